@@ -15,7 +15,8 @@ extern crate serde_derive;
 
 use docopt::Docopt;
 
-use rustls::{OwnedTrustAnchor, RootCertStore};
+use rustls::client::{ServerName, Tls12ClientSessionValue, Tls13ClientSessionValue};
+use rustls::{NamedGroup, OwnedTrustAnchor, RootCertStore};
 
 const CLIENT: mio::Token = mio::Token(0);
 
@@ -195,10 +196,32 @@ impl io::Read for TlsClient {
 /// Note that the contents of such a file are extremely sensitive.
 /// Don't write this stuff to disk in production code.
 struct PersistCache {
-    cache: Mutex<collections::HashMap<Vec<u8>, Vec<u8>>>,
+    cache: Mutex<collections::HashMap<String, ServerSessions>>,
     filename: Option<String>,
 }
 
+/// Everything cached for one server.
+#[derive(Default)]
+struct ServerSessions {
+    kx_hint: Option<NamedGroup>,
+    tls12: Option<Tls12ClientSessionValue>,
+    tls13: collections::VecDeque<Tls13ClientSessionValue>,
+}
+
+/// The kinds of item in a cache file.
+const ITEM_KX_HINT: u8 = 0;
+const ITEM_TLS12_SESSION: u8 = 1;
+const ITEM_TLS13_TICKET: u8 = 2;
+
+/// The key for `server_name`: the name, as given to `ServerName::try_from`.
+fn server_key(server_name: &ServerName) -> String {
+    match server_name {
+        ServerName::DnsName(name) => name.as_ref().to_string(),
+        ServerName::IpAddress(addr) => addr.to_string(),
+        _ => unreachable!("unknown ServerName"),
+    }
+}
+
 impl PersistCache {
     /// Make a new cache.  If filename is Some, load the cache
     /// from it and flush changes back to that file.
@@ -213,9 +236,35 @@ impl PersistCache {
         cache
     }
 
+    /// Apply `edit` to what is cached for `server_name`, then save.
+    fn edit<T>(&self, server_name: &ServerName, edit: impl FnOnce(&mut ServerSessions) -> T) -> T {
+        let result = edit(
+            self.cache
+                .lock()
+                .unwrap()
+                .entry(server_key(server_name))
+                .or_default(),
+        );
+        self.save();
+        result
+    }
+
+    /// Read what is cached for `server_name`.
+    fn get<T>(
+        &self,
+        server_name: &ServerName,
+        get: impl FnOnce(&ServerSessions) -> T,
+    ) -> Option<T> {
+        self.cache
+            .lock()
+            .unwrap()
+            .get(&server_key(server_name))
+            .map(get)
+    }
+
     /// If we have a filename, save the cache contents to it.
     fn save(&self) {
-        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::base::{PayloadU16, PayloadU24};
         use rustls::internal::msgs::codec::Codec;
 
         if self.filename.is_none() {
@@ -225,20 +274,33 @@ impl PersistCache {
         let mut file =
             fs::File::create(self.filename.as_ref().unwrap()).expect("cannot open cache file");
 
-        for (key, val) in self.cache.lock().unwrap().iter() {
-            let mut item = Vec::new();
-            let key_pl = PayloadU16::new(key.clone());
-            let val_pl = PayloadU16::new(val.clone());
-            key_pl.encode(&mut item);
-            val_pl.encode(&mut item);
-            file.write_all(&item).unwrap();
+        for (server, sessions) in self.cache.lock().unwrap().iter() {
+            let mut items = Vec::new();
+            if let Some(group) = sessions.kx_hint {
+                items.push((ITEM_KX_HINT, group.get_encoding()));
+            }
+            if let Some(session) = &sessions.tls12 {
+                items.push((ITEM_TLS12_SESSION, session.get_encoding()));
+            }
+            for ticket in &sessions.tls13 {
+                items.push((ITEM_TLS13_TICKET, ticket.get_encoding()));
+            }
+
+            for (kind, value) in items {
+                let mut item = Vec::new();
+                PayloadU16::new(server.as_bytes().to_vec()).encode(&mut item);
+                kind.encode(&mut item);
+                PayloadU24::new(value).encode(&mut item);
+                file.write_all(&item).unwrap();
+            }
         }
     }
 
     /// We have a filename, so replace the cache contents from it.
     fn load(&self) {
-        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::base::{PayloadU16, PayloadU24};
         use rustls::internal::msgs::codec::{Codec, Reader};
+        use rustls::internal::msgs::persist::ClientSessionValue;
 
         let mut file = match fs::File::open(self.filename.as_ref().unwrap()) {
             Ok(f) => f,
@@ -252,31 +314,56 @@ impl PersistCache {
         let mut rd = Reader::init(&data);
 
         while rd.any_left() {
-            let key_pl = PayloadU16::read(&mut rd).unwrap();
-            let val_pl = PayloadU16::read(&mut rd).unwrap();
-            cache.insert(key_pl.0, val_pl.0);
+            let server = String::from_utf8(PayloadU16::read(&mut rd).unwrap().0).unwrap();
+            let kind = u8::read(&mut rd).unwrap();
+            let value = PayloadU24::read(&mut rd).unwrap();
+            let mut value = Reader::init(&value.0);
+            let sessions = cache.entry(server).or_default();
+
+            if kind == ITEM_KX_HINT {
+                sessions.kx_hint = NamedGroup::read(&mut value);
+                continue;
+            }
+
+            // Sessions for suites which are no longer supported are dropped.
+            let suite = rustls::CipherSuite::read(&mut value).unwrap();
+            match ClientSessionValue::read(&mut value, suite, rustls::ALL_CIPHER_SUITES) {
+                Some(ClientSessionValue::Tls12(session)) if kind == ITEM_TLS12_SESSION => {
+                    sessions.tls12 = Some(session);
+                }
+                Some(ClientSessionValue::Tls13(ticket)) if kind == ITEM_TLS13_TICKET => {
+                    sessions.tls13.push_back(ticket);
+                }
+                _ => {}
+            }
         }
     }
 }
 
 impl rustls::client::StoresClientSessions for PersistCache {
-    /// put: insert into in-memory cache, and perhaps persist to disk.
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.cache
-            .lock()
-            .unwrap()
-            .insert(key, value);
-        self.save();
-        true
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
+        self.edit(server_name, |sessions| sessions.kx_hint = Some(group));
     }
 
-    /// get: from in-memory cache
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache
-            .lock()
-            .unwrap()
-            .get(key)
-            .cloned()
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
+        self.get(server_name, |sessions| sessions.kx_hint)?
+    }
+
+    fn set_tls12_session(&self, server_name: &ServerName, value: Tls12ClientSessionValue) {
+        self.edit(server_name, |sessions| sessions.tls12 = Some(value));
+    }
+
+    fn tls12_session(&self, server_name: &ServerName) -> Option<Tls12ClientSessionValue> {
+        self.get(server_name, |sessions| sessions.tls12.clone())?
+    }
+
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue) {
+        self.edit(server_name, |sessions| sessions.tls13.push_back(value));
+    }
+
+    /// take_tls13_ticket: each ticket is used once, so this also saves.
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
+        self.edit(server_name, |sessions| sessions.tls13.pop_front())
     }
 }
 
@@ -308,6 +395,11 @@ Options:
     --proto PROTOCOL    Send ALPN extension containing PROTOCOL.
                         May be used multiple times to offer several protocols.
     --cache CACHE       Save session cache to file CACHE.
+    --cache-dir DIR     Save session cache to directory DIR, which
+                        may be shared by several processes.
+    --cache-key KEY     Authenticate the sessions in --cache-dir DIR
+                        with the key in file KEY, which must hold at
+                        least 32 random bytes and not be kept in DIR.
     --no-tickets        Disable session ticket support.
     --no-sni            Disable server name indication support.
     --insecure          Disable certificate verification.
@@ -328,6 +420,8 @@ struct Args {
     flag_max_frag_size: Option<usize>,
     flag_cafile: Option<String>,
     flag_cache: Option<String>,
+    flag_cache_dir: Option<String>,
+    flag_cache_key: Option<String>,
     flag_no_tickets: bool,
     flag_no_sni: bool,
     flag_insecure: bool,
@@ -531,7 +625,18 @@ fn make_config(args: &Args) -> Arc<rustls::ClientConfig> {
         config.enable_sni = false;
     }
 
-    config.session_storage = Arc::new(PersistCache::new(&args.flag_cache));
+    config.session_storage = match &args.flag_cache_dir {
+        Some(dir) => {
+            let key_file = args
+                .flag_cache_key
+                .as_ref()
+                .expect("--cache-dir needs --cache-key");
+            let key = fs::read(key_file).expect("cannot read cache key file");
+            rustls::client::ClientSessionFileCache::new(dir, &key)
+                .expect("cannot open cache directory")
+        }
+        None => Arc::new(PersistCache::new(&args.flag_cache)),
+    };
 
     config.alpn_protocols = args
         .flag_proto
@@ -8,11 +8,11 @@ use base64::prelude::{Engine, BASE64_STANDARD};
 use env_logger;
 use rustls;
 
-use rustls::internal::msgs::codec::{Codec, Reader};
+use rustls::internal::msgs::codec::Codec;
 use rustls::internal::msgs::persist;
 use rustls::quic::{self, ClientQuicExt, QuicExt, ServerQuicExt};
 use rustls::server::ClientHello;
-use rustls::{CipherSuite, ProtocolVersion};
+use rustls::ProtocolVersion;
 use rustls::{ClientConnection, Connection, ServerConnection, Side};
 
 use std::env;
@@ -321,11 +321,19 @@ fn lookup_scheme(scheme: u16) -> rustls::SignatureScheme {
     }
 }
 
-fn lookup_kx_group(group: u16) -> &'static rustls::SupportedKxGroup {
+fn lookup_kx_group(group: u16) -> &'static dyn rustls::SupportedKxGroup {
     match group {
         0x001d => &rustls::kx_group::X25519,
         0x0017 => &rustls::kx_group::SECP256R1,
         0x0018 => &rustls::kx_group::SECP384R1,
+        #[cfg(feature = "ffdhe")]
+        0x0100 => &rustls::kx_group::FFDHE2048,
+        #[cfg(feature = "ffdhe")]
+        0x0101 => &rustls::kx_group::FFDHE3072,
+        #[cfg(feature = "ffdhe")]
+        0x0102 => &rustls::kx_group::FFDHE4096,
+        #[cfg(feature = "kyber")]
+        0x6399 => &rustls::kx_group::X25519_KYBER768_DRAFT00,
         _ => {
             println_err!("Unsupported kx group {:04x}", group);
             process::exit(BOGO_NACK);
@@ -478,34 +486,45 @@ impl ClientCacheWithoutKxHints {
 }
 
 impl rustls::client::StoresClientSessions for ClientCacheWithoutKxHints {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        if key.len() > 2 && key[0] == b'k' && key[1] == b'x' {
-            return true;
-        }
+    fn set_kx_hint(&self, _: &rustls::ServerName, _: rustls::NamedGroup) {}
 
-        let mut reader = Reader::init(&value[2..]);
-        let csv = CipherSuite::read_bytes(&value[..2])
-            .and_then(|suite| {
-                persist::ClientSessionValue::read(&mut reader, suite, &rustls::ALL_CIPHER_SUITES)
-            })
-            .unwrap();
+    fn kx_hint(&self, _: &rustls::ServerName) -> Option<rustls::NamedGroup> {
+        None
+    }
 
-        let value = match csv {
-            persist::ClientSessionValue::Tls13(mut tls13) => {
-                tls13.common.rewind_epoch(self.delay);
-                tls13.get_encoding()
-            }
-            persist::ClientSessionValue::Tls12(mut tls12) => {
-                tls12.common.rewind_epoch(self.delay);
-                tls12.get_encoding()
-            }
-        };
+    fn set_tls12_session(
+        &self,
+        server_name: &rustls::ServerName,
+        mut value: rustls::client::Tls12ClientSessionValue,
+    ) {
+        value.common.rewind_epoch(self.delay);
+        self.storage
+            .set_tls12_session(server_name, value);
+    }
 
-        self.storage.put(key, value)
+    fn tls12_session(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls12ClientSessionValue> {
+        self.storage.tls12_session(server_name)
     }
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.storage.get(key)
+    fn insert_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+        mut value: rustls::client::Tls13ClientSessionValue,
+    ) {
+        value.common.rewind_epoch(self.delay);
+        self.storage
+            .insert_tls13_ticket(server_name, value);
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls13ClientSessionValue> {
+        self.storage
+            .take_tls13_ticket(server_name)
     }
 }
 
@@ -1,6 +1,6 @@
+use crate::crypto::{CryptoProvider, SupportedKxGroup};
 use crate::error::Error;
-use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
-use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
+use crate::suites::SupportedCipherSuite;
 use crate::versions;
 
 use std::fmt;
@@ -76,6 +76,9 @@ use std::marker::PhantomData;
 /// The types used here fit together like this:
 ///
 /// 1. Call [`ClientConfig::builder()`] or [`ServerConfig::builder()`] to initialize a builder.
+///    These use the default [*ring*-based cryptography provider](crate::crypto::ring::RING);
+///    to use another, call [`ClientConfig::builder_with_provider()`] or
+///    [`ServerConfig::builder_with_provider()`] instead.
 /// 1. You must make a decision on which cipher suites to use, typically
 ///    by calling [`ConfigBuilder<S, WantsCipherSuites>::with_safe_default_cipher_suites()`].
 /// 2. Now you must make a decision
@@ -91,6 +94,8 @@ use std::marker::PhantomData;
 /// [`ClientConfig`]: crate::ClientConfig
 /// [`ClientConfig::builder()`]: crate::ClientConfig::builder()
 /// [`ServerConfig::builder()`]: crate::ServerConfig::builder()
+/// [`ClientConfig::builder_with_provider()`]: crate::ClientConfig::builder_with_provider()
+/// [`ServerConfig::builder_with_provider()`]: crate::ServerConfig::builder_with_provider()
 /// [`ConfigBuilder<ClientConfig, WantsVerifier>`]: struct.ConfigBuilder.html#impl-3
 /// [`ConfigBuilder<ServerConfig, WantsVerifier>`]: struct.ConfigBuilder.html#impl-6
 #[derive(Clone)]
@@ -116,23 +121,30 @@ impl<Side: ConfigSide, State: fmt::Debug> fmt::Debug for ConfigBuilder<Side, Sta
 ///
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
-pub struct WantsCipherSuites(pub(crate) ());
+pub struct WantsCipherSuites(pub(crate) &'static dyn CryptoProvider);
 
 impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
     /// Start side-specific config with defaults for underlying cryptography.
     ///
-    /// If used, this will enable all safe supported cipher suites ([`DEFAULT_CIPHER_SUITES`]), all
-    /// safe supported key exchange groups ([`ALL_KX_GROUPS`]) and all safe supported protocol
+    /// If used, this will enable all safe supported cipher suites and key exchange groups
+    /// offered by the cryptography provider (for the default provider, these are
+    /// [`DEFAULT_CIPHER_SUITES`] and [`ALL_KX_GROUPS`]), and all safe supported protocol
     /// versions ([`DEFAULT_VERSIONS`]).
     ///
     /// These are safe defaults, useful for 99% of applications.
     ///
+    /// [`DEFAULT_CIPHER_SUITES`]: crate::DEFAULT_CIPHER_SUITES
+    /// [`ALL_KX_GROUPS`]: crate::ALL_KX_GROUPS
     /// [`DEFAULT_VERSIONS`]: versions::DEFAULT_VERSIONS
     pub fn with_safe_defaults(self) -> ConfigBuilder<S, WantsVerifier> {
+        let provider = self.state.0;
         ConfigBuilder {
             state: WantsVerifier {
-                cipher_suites: DEFAULT_CIPHER_SUITES.to_vec(),
-                kx_groups: ALL_KX_GROUPS.to_vec(),
+                provider,
+                cipher_suites: provider
+                    .default_cipher_suites()
+                    .to_vec(),
+                kx_groups: provider.default_kx_groups().to_vec(),
                 versions: versions::EnabledVersions::new(versions::DEFAULT_VERSIONS),
             },
             side: self.side,
@@ -146,19 +158,24 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
     ) -> ConfigBuilder<S, WantsKxGroups> {
         ConfigBuilder {
             state: WantsKxGroups {
+                provider: self.state.0,
                 cipher_suites: cipher_suites.to_vec(),
             },
             side: self.side,
         }
     }
 
-    /// Choose the default set of cipher suites ([`DEFAULT_CIPHER_SUITES`]).
+    /// Choose the cryptography provider's default set of cipher suites
+    /// ([`DEFAULT_CIPHER_SUITES`] for the default provider).
     ///
     /// Note that this default provides only high-quality suites: there is no need
     /// to filter out low-, export- or NULL-strength cipher suites: rustls does not
     /// implement these.
+    ///
+    /// [`DEFAULT_CIPHER_SUITES`]: crate::DEFAULT_CIPHER_SUITES
     pub fn with_safe_default_cipher_suites(self) -> ConfigBuilder<S, WantsKxGroups> {
-        self.with_cipher_suites(DEFAULT_CIPHER_SUITES)
+        let default_suites = self.state.0.default_cipher_suites();
+        self.with_cipher_suites(default_suites)
     }
 }
 
@@ -167,6 +184,7 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
 pub struct WantsKxGroups {
+    provider: &'static dyn CryptoProvider,
     cipher_suites: Vec<SupportedCipherSuite>,
 }
 
@@ -174,10 +192,11 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsKxGroups> {
     /// Choose a specific set of key exchange groups.
     pub fn with_kx_groups(
         self,
-        kx_groups: &[&'static SupportedKxGroup],
+        kx_groups: &[&'static dyn SupportedKxGroup],
     ) -> ConfigBuilder<S, WantsVersions> {
         ConfigBuilder {
             state: WantsVersions {
+                provider: self.state.provider,
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: kx_groups.to_vec(),
             },
@@ -185,11 +204,15 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsKxGroups> {
         }
     }
 
-    /// Choose the default set of key exchange groups ([`ALL_KX_GROUPS`]).
+    /// Choose the cryptography provider's default set of key exchange groups
+    /// ([`ALL_KX_GROUPS`] for the default provider).
     ///
     /// This is a safe default: rustls doesn't implement any poor-quality groups.
+    ///
+    /// [`ALL_KX_GROUPS`]: crate::ALL_KX_GROUPS
     pub fn with_safe_default_kx_groups(self) -> ConfigBuilder<S, WantsVersions> {
-        self.with_kx_groups(&ALL_KX_GROUPS)
+        let default_groups = self.state.provider.default_kx_groups();
+        self.with_kx_groups(default_groups)
     }
 }
 
@@ -198,8 +221,9 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsKxGroups> {
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
 pub struct WantsVersions {
+    provider: &'static dyn CryptoProvider,
     cipher_suites: Vec<SupportedCipherSuite>,
-    kx_groups: Vec<&'static SupportedKxGroup>,
+    kx_groups: Vec<&'static dyn SupportedKxGroup>,
 }
 
 impl<S: ConfigSide> ConfigBuilder<S, WantsVersions> {
@@ -233,6 +257,7 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsVersions> {
 
         Ok(ConfigBuilder {
             state: WantsVerifier {
+                provider: self.state.provider,
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: versions::EnabledVersions::new(versions),
@@ -247,8 +272,9 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsVersions> {
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
 pub struct WantsVerifier {
+    pub(crate) provider: &'static dyn CryptoProvider,
     pub(crate) cipher_suites: Vec<SupportedCipherSuite>,
-    pub(crate) kx_groups: Vec<&'static SupportedKxGroup>,
+    pub(crate) kx_groups: Vec<&'static dyn SupportedKxGroup>,
     pub(crate) versions: versions::EnabledVersions,
 }
 
@@ -1,9 +1,8 @@
+use crate::crypto::aead::NONCE_LEN;
 use crate::error::Error;
 use crate::msgs::codec;
 use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
 
-use ring::{aead, hkdf};
-
 /// Objects with this trait can decrypt TLS messages.
 pub trait MessageDecrypter: Send + Sync {
     /// Perform the decryption over the concerned TLS message.
@@ -30,18 +29,13 @@ impl dyn MessageDecrypter {
 
 /// A write or read IV.
 #[derive(Default)]
-pub(crate) struct Iv(pub(crate) [u8; ring::aead::NONCE_LEN]);
+pub(crate) struct Iv(pub(crate) [u8; NONCE_LEN]);
 
 impl Iv {
-    #[cfg(feature = "tls12")]
-    fn new(value: [u8; ring::aead::NONCE_LEN]) -> Self {
-        Self(value)
-    }
-
     #[cfg(feature = "tls12")]
     pub(crate) fn copy(value: &[u8]) -> Self {
-        debug_assert_eq!(value.len(), ring::aead::NONCE_LEN);
-        let mut iv = Self::new(Default::default());
+        debug_assert_eq!(value.len(), NONCE_LEN);
+        let mut iv = Self::default();
         iv.0.copy_from_slice(value);
         iv
     }
@@ -52,24 +46,8 @@ impl Iv {
     }
 }
 
-pub(crate) struct IvLen;
-
-impl hkdf::KeyType for IvLen {
-    fn len(&self) -> usize {
-        aead::NONCE_LEN
-    }
-}
-
-impl From<hkdf::Okm<'_, IvLen>> for Iv {
-    fn from(okm: hkdf::Okm<IvLen>) -> Self {
-        let mut r = Self(Default::default());
-        okm.fill(&mut r.0[..]).unwrap();
-        r
-    }
-}
-
-pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
-    let mut nonce = [0u8; ring::aead::NONCE_LEN];
+pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
     codec::put_u64(seq, &mut nonce[4..]);
 
     nonce
@@ -79,7 +57,7 @@ pub(crate) fn make_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
             *nonce ^= *iv;
         });
 
-    aead::Nonce::assume_unique_for_key(nonce)
+    nonce
 }
 
 /// A `MessageEncrypter` which doesn't work.
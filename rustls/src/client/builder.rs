@@ -2,11 +2,14 @@ use crate::anchors;
 use crate::builder::{ConfigBuilder, WantsVerifier};
 use crate::client::handy;
 use crate::client::{ClientConfig, ResolvesClientCert};
+use crate::compress;
+use crate::crypto::{CryptoProvider, SupportedKxGroup};
+use crate::ct::{CertificateTransparencyPolicy, CtLogList, CtRequirements};
 use crate::error::Error;
 use crate::key;
-use crate::kx::SupportedKxGroup;
+use crate::msgs::enums::PSKKeyExchangeMode;
 use crate::suites::SupportedCipherSuite;
-use crate::verify::{self, CertificateTransparencyPolicy};
+use crate::verify::{self, OcspPolicy};
 use crate::versions;
 use crate::NoKeyLog;
 
@@ -22,10 +25,12 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
     ) -> ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
         ConfigBuilder {
             state: WantsTransparencyPolicyOrClientCert {
+                provider: self.state.provider,
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
                 root_store,
+                ocsp_policy: OcspPolicy::default(),
             },
             side: PhantomData::default(),
         }
@@ -39,6 +44,7 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
     ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
         ConfigBuilder {
             state: WantsClientCert {
+                provider: self.state.provider,
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
@@ -52,19 +58,30 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
 /// A config builder state where the caller needs to supply a certificate transparency policy or
 /// client certificate resolver.
 ///
-/// In this state, the caller can optionally enable certificate transparency, or ignore CT and
-/// invoke one of the methods related to client certificates (as in the [`WantsClientCert`] state).
+/// In this state, the caller can optionally enable certificate transparency or choose how stapled
+/// OCSP responses are checked, or ignore these and invoke one of the methods related to client
+/// certificates (as in the [`WantsClientCert`] state).
 ///
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
 pub struct WantsTransparencyPolicyOrClientCert {
+    provider: &'static dyn CryptoProvider,
     cipher_suites: Vec<SupportedCipherSuite>,
-    kx_groups: Vec<&'static SupportedKxGroup>,
+    kx_groups: Vec<&'static dyn SupportedKxGroup>,
     versions: versions::EnabledVersions,
     root_store: anchors::RootCertStore,
+    ocsp_policy: OcspPolicy,
 }
 
 impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
+    /// Choose how OCSP responses stapled by the server are checked.
+    ///
+    /// By default they are ignored; see [`OcspPolicy`].
+    pub fn with_ocsp_policy(mut self, ocsp_policy: OcspPolicy) -> Self {
+        self.state.ocsp_policy = ocsp_policy;
+        self
+    }
+
     /// Set Certificate Transparency logs to use for server certificate validation.
     ///
     /// Because Certificate Transparency logs are sharded on a per-year basis and can be trusted or
@@ -83,6 +100,23 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
         )))
     }
 
+    /// Require server certificates to meet `requirements` for Certificate
+    /// Transparency, using the logs in `logs`.
+    ///
+    /// Like [`Self::with_certificate_transparency_logs`], this stops
+    /// checking server certificates after `validation_deadline`.
+    pub fn with_certificate_transparency_policy(
+        self,
+        logs: CtLogList,
+        requirements: CtRequirements,
+        validation_deadline: SystemTime,
+    ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        self.with_logs(Some(
+            CertificateTransparencyPolicy::from_log_list(logs, validation_deadline)
+                .with_requirements(requirements),
+        ))
+    }
+
     /// Sets a single certificate chain and matching private key for use
     /// in client authentication.
     ///
@@ -99,6 +133,20 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
             .with_single_cert(cert_chain, key_der)
     }
 
+    /// Sets a private key for use in client authentication with a raw
+    /// public key (RFC7250) rather than a certificate chain.
+    ///
+    /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
+    ///
+    /// This function fails if `key_der` is invalid.
+    pub fn with_client_raw_public_key(
+        self,
+        key_der: key::PrivateKey,
+    ) -> Result<ClientConfig, Error> {
+        self.with_logs(None)
+            .with_client_raw_public_key(key_der)
+    }
+
     /// Do not support client auth.
     pub fn with_no_client_auth(self) -> ClientConfig {
         self.with_logs(None)
@@ -120,13 +168,14 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
     ) -> ConfigBuilder<ClientConfig, WantsClientCert> {
         ConfigBuilder {
             state: WantsClientCert {
+                provider: self.state.provider,
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
-                verifier: Arc::new(verify::WebPkiVerifier::new(
-                    self.state.root_store,
-                    ct_policy,
-                )),
+                verifier: Arc::new(
+                    verify::WebPkiVerifier::new(self.state.root_store, ct_policy)
+                        .with_ocsp_policy(self.state.ocsp_policy),
+                ),
             },
             side: PhantomData,
         }
@@ -139,8 +188,9 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
 pub struct WantsClientCert {
+    provider: &'static dyn CryptoProvider,
     cipher_suites: Vec<SupportedCipherSuite>,
-    kx_groups: Vec<&'static SupportedKxGroup>,
+    kx_groups: Vec<&'static dyn SupportedKxGroup>,
     versions: versions::EnabledVersions,
     verifier: Arc<dyn verify::ServerCertVerifier>,
 }
@@ -158,7 +208,23 @@ impl ConfigBuilder<ClientConfig, WantsClientCert> {
         cert_chain: Vec<key::Certificate>,
         key_der: key::PrivateKey,
     ) -> Result<ClientConfig, Error> {
-        let resolver = handy::AlwaysResolvesClientCert::new(cert_chain, &key_der)?;
+        let resolver =
+            handy::AlwaysResolvesClientCert::new(self.state.provider, cert_chain, &key_der)?;
+        Ok(self.with_client_cert_resolver(Arc::new(resolver)))
+    }
+
+    /// Sets a private key for use in client authentication with a raw
+    /// public key (RFC7250) rather than a certificate chain.
+    ///
+    /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
+    ///
+    /// This function fails if `key_der` is invalid.
+    pub fn with_client_raw_public_key(
+        self,
+        key_der: key::PrivateKey,
+    ) -> Result<ClientConfig, Error> {
+        let resolver =
+            handy::AlwaysResolvesClientCert::new_raw_public_key(self.state.provider, &key_der)?;
         Ok(self.with_client_cert_resolver(Arc::new(resolver)))
     }
 
@@ -173,20 +239,32 @@ impl ConfigBuilder<ClientConfig, WantsClientCert> {
         client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
     ) -> ClientConfig {
         ClientConfig {
+            provider: self.state.provider,
             cipher_suites: self.state.cipher_suites,
             kx_groups: self.state.kx_groups,
             alpn_protocols: Vec::new(),
             session_storage: handy::ClientSessionMemoryCache::new(256),
             max_fragment_size: None,
+            record_size_limit: None,
+            max_fragment_length: None,
             client_auth_cert_resolver,
             enable_tickets: true,
             versions: self.state.versions,
             enable_sni: true,
+            send_certificate_authorities: false,
+            enable_delegated_credentials: false,
             verifier: self.state.verifier,
             key_log: Arc::new(NoKeyLog {}),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
             enable_early_data: false,
+            ech_config: None,
+            cert_decompressors: compress::default_cert_decompressors().to_vec(),
+            cert_compressors: compress::default_cert_compressors().to_vec(),
+            server_raw_key_verifier: None,
+            external_psks: Vec::new(),
+            psk_kx_modes: vec![PSKKeyExchangeMode::PSK_DHE_KE],
+            defer_cert_verification: false,
         }
     }
 }
@@ -1,21 +1,27 @@
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
+use crate::compress;
 use crate::conn::{CommonState, ConnectionCommon, Protocol, Side};
+use crate::crypto::{self, CryptoProvider, SupportedKxGroup};
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::error::Error;
-use crate::kx::SupportedKxGroup;
 #[cfg(feature = "logging")]
 use crate::log::trace;
 #[cfg(feature = "quic")]
 use crate::msgs::enums::AlertDescription;
+use crate::msgs::enums::{MaxFragmentLength, NamedGroup, PSKKeyExchangeMode};
 use crate::msgs::handshake::ClientExtension;
+#[cfg(feature = "tls12")]
+use crate::msgs::persist::Tls12ClientSessionValue;
+use crate::msgs::persist::Tls13ClientSessionValue;
 use crate::sign;
 use crate::suites::SupportedCipherSuite;
-use crate::verify;
+use crate::verify::{self, ServerRawKeyVerifier};
 use crate::versions;
 #[cfg(feature = "secret_extraction")]
 use crate::ExtractedSecrets;
-use crate::KeyLog;
+use crate::{ExternalPsk, KeyLog};
 
+use super::ech::{EchConfig, EchStatus};
 use super::hs;
 #[cfg(feature = "quic")]
 use crate::quic;
@@ -27,25 +33,50 @@ use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 use std::{fmt, io, mem};
 
-/// A trait for the ability to store client session data.
-/// The keys and values are opaque.
+/// A trait for the ability to store client session data, so that
+/// later connections to the same server can resume.
 ///
-/// Both the keys and values should be treated as
-/// **highly sensitive data**, containing enough key material
-/// to break all security of the corresponding session.
+/// Sessions should be treated as **highly sensitive data**,
+/// containing enough key material to break all security
+/// of the corresponding session.
 ///
-/// `put` is a mutating operation; this isn't expressed
+/// TLS1.2 sessions and TLS1.3 tickets are stored separately.
+/// A TLS1.2 session may be resumed any number of times, and a
+/// newer one replaces it.  A server may issue several TLS1.3
+/// tickets, and each must be used at most once: reusing a ticket
+/// lets observers link the connections that used it, and defeats
+/// the server's anti-replay protection for early data.
+///
+/// The setters are mutating operations; this isn't expressed
 /// in the type system to allow implementations freedom in
 /// how to achieve interior mutability.  `Mutex` is a common
 /// choice.
 pub trait StoresClientSessions: Send + Sync {
-    /// Stores a new `value` for `key`.  Returns `true`
-    /// if the value was stored.
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool;
+    /// Remembers which key exchange group `server_name` chose, so
+    /// that a key share for it can be sent in the next client hello.
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup);
+
+    /// Returns the group last stored by `set_kx_hint`, if any.
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup>;
+
+    /// Stores a TLS1.2 session for `server_name`, replacing any
+    /// existing one.
+    #[cfg(feature = "tls12")]
+    fn set_tls12_session(&self, server_name: &ServerName, value: Tls12ClientSessionValue);
+
+    /// Returns the TLS1.2 session for `server_name`, if any.  The
+    /// session stays stored.
+    #[cfg(feature = "tls12")]
+    fn tls12_session(&self, server_name: &ServerName) -> Option<Tls12ClientSessionValue>;
 
-    /// Returns the latest value for `key`.  Returns `None`
-    /// if there's no such value.
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    /// Adds a TLS1.3 ticket for `server_name` to those already stored.
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue);
+
+    /// Removes and returns a TLS1.3 ticket for `server_name`, if any.
+    ///
+    /// Implementations need not check expiry: rustls discards tickets
+    /// which are past their `ticket_lifetime`.
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue>;
 }
 
 /// A trait for the ability to choose a certificate chain and
@@ -70,6 +101,16 @@ pub trait ResolvesClientCert: Send + Sync {
 
     /// Return true if any certificates at all are available.
     fn has_certs(&self) -> bool;
+
+    /// Return true if this resolver authenticates with raw public keys
+    /// (RFC7250) rather than certificate chains.
+    ///
+    /// If so, raw public keys are offered to TLS1.3 servers, and `resolve`
+    /// should return keys made by [`sign::CertifiedKey::new_raw_public_key`].
+    /// The default is false.
+    fn uses_raw_public_keys(&self) -> bool {
+        false
+    }
 }
 
 /// Common configuration for (typically) all connections made by
@@ -83,6 +124,8 @@ pub trait ResolvesClientCert: Send + Sync {
 /// # Defaults
 ///
 /// * [`ClientConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
+/// * [`ClientConfig::record_size_limit`]: the default is `None`: the peer may send full-sized records.
+/// * [`ClientConfig::max_fragment_length`]: the default is `None`: no fragment length is requested.
 /// * [`ClientConfig::session_storage`]: the default stores 256 sessions in memory.
 /// * [`ClientConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
 /// * [`ClientConfig::key_log`]: key material is not logged.
@@ -91,12 +134,15 @@ pub struct ClientConfig {
     /// List of ciphersuites, in preference order.
     pub(super) cipher_suites: Vec<SupportedCipherSuite>,
 
+    /// Source of cryptographic primitives and randomness.
+    pub(crate) provider: &'static dyn CryptoProvider,
+
     /// List of supported key exchange algorithms, in preference order -- the
     /// first element is the highest priority.
     ///
     /// The first element in this list is the _default key share algorithm_,
     /// and in TLS1.3 a key share for it is sent in the client hello.
-    pub(super) kx_groups: Vec<&'static SupportedKxGroup>,
+    pub(super) kx_groups: Vec<&'static dyn SupportedKxGroup>,
 
     /// Which ALPN protocols we include in our client hello.
     /// If empty, no ALPN extension is sent.
@@ -114,6 +160,28 @@ pub struct ClientConfig {
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
     pub max_fragment_size: Option<usize>,
 
+    /// The largest record we are prepared to receive, advertised to the peer using the
+    /// `record_size_limit` extension (RFC8449).  This counts the plaintext of a protected
+    /// record, plus the inner content type in TLS1.3.  It is not used for QUIC or DTLS.
+    ///
+    /// If None, we don't send the extension.
+    /// Peers that exceed the limit are rejected with [`Error::PeerExceededRecordSizeLimit`].
+    ///
+    /// Values must be between 64 and 16385; others are reported as errors from
+    /// ClientConnection::new.
+    pub record_size_limit: Option<usize>,
+
+    /// A fragment length to request from the server using the `max_fragment_length`
+    /// extension (RFC6066).  Once the server accepts it, records in both directions are
+    /// limited to this length.  If None, we don't send the extension.
+    ///
+    /// Prefer `record_size_limit`: servers that support both will ignore this field if
+    /// that is also set.  This is not used for QUIC or DTLS.
+    ///
+    /// `MaxFragmentLength::Unknown` values are reported as [`Error::BadMaxFragmentSize`]
+    /// from ClientConnection::new.
+    pub max_fragment_length: Option<MaxFragmentLength>,
+
     /// How to decide what client auth certificate/keys to use.
     pub client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
 
@@ -134,6 +202,23 @@ pub struct ClientConfig {
     /// The default is true.
     pub enable_sni: bool,
 
+    /// Whether to send the TLS1.3 `certificate_authorities` extension,
+    /// listing the trust anchors from `ServerCertVerifier::root_hint_subjects`.
+    /// Servers can use this to choose between certificate chains.
+    ///
+    /// This makes the client hello larger, and reveals which roots are
+    /// trusted to anyone observing the connection.  The default is false.
+    pub send_certificate_authorities: bool,
+
+    /// Whether to accept delegated credentials (RFC9345) from TLS1.3
+    /// servers, so they can authenticate with a short-lived key rather
+    /// than the key of their certificate.
+    ///
+    /// Delegated credentials are checked by
+    /// `ServerCertVerifier::verify_delegated_credential`.  The default
+    /// is false.
+    pub enable_delegated_credentials: bool,
+
     /// How to verify the server certificate chain.
     pub(super) verifier: Arc<dyn verify::ServerCertVerifier>,
 
@@ -151,6 +236,71 @@ pub struct ClientConfig {
     ///
     /// The default is false.
     pub enable_early_data: bool,
+
+    /// Offer Encrypted Client Hello using this configuration, hiding
+    /// the server name and other sensitive extensions from observers.
+    ///
+    /// ECH is only offered when TLS1.3 is enabled.  If the server
+    /// rejects it the handshake fails with
+    /// [`Error::EncryptedClientHelloRejected`] after authenticating
+    /// the server under the configuration's public name.
+    ///
+    /// The default is `None`.
+    pub ech_config: Option<EchConfig>,
+
+    /// Certificate compression algorithms we can decompress, in
+    /// preference order.  These are offered to the server, and the
+    /// server may then send its certificate compressed.
+    ///
+    /// The default is [`compress::default_cert_decompressors()`].
+    pub cert_decompressors: Vec<&'static dyn compress::CertDecompressor>,
+
+    /// Certificate compression algorithms we can use to compress our
+    /// client certificate, in preference order.
+    ///
+    /// The default is [`compress::default_cert_compressors()`].
+    pub cert_compressors: Vec<&'static dyn compress::CertCompressor>,
+
+    /// Accept servers which authenticate with a raw public key (RFC7250)
+    /// instead of a certificate chain, deciding whether to trust them
+    /// using this.
+    ///
+    /// Raw public keys are only offered when TLS1.3 is enabled.  Servers
+    /// which send certificate chains are still verified as usual.
+    ///
+    /// The default is `None`.
+    pub server_raw_key_verifier: Option<Arc<dyn ServerRawKeyVerifier>>,
+
+    /// Externally provisioned pre-shared keys to offer in TLS1.3
+    /// handshakes, in preference order.  These are offered after any
+    /// resumption ticket.
+    ///
+    /// A PSK is only offered if a TLS1.3 cipher suite with a matching
+    /// hash is enabled.  If the server selects one, it does not
+    /// authenticate itself with a certificate.
+    ///
+    /// The default is empty.
+    pub external_psks: Vec<Arc<ExternalPsk>>,
+
+    /// The PSK key exchange modes we offer alongside a resumption ticket
+    /// or external PSK.  `PSK_KE` connections do not have forward secrecy.
+    ///
+    /// The default is `[PSK_DHE_KE]`.
+    pub psk_kx_modes: Vec<PSKKeyExchangeMode>,
+
+    /// Don't verify the server's certificate chain with
+    /// `ServerCertVerifier::verify_server_cert`.  Instead, the handshake
+    /// is suspended after the server's `Certificate` message while the
+    /// application verifies it: see [`CommonState::pending_cert_verification`].
+    ///
+    /// This allows the verification to consult a slow service, without
+    /// blocking the thread processing the handshake.  The verifier still
+    /// checks the server's signatures, and raw public keys are still
+    /// verified by `server_raw_key_verifier`.  This does not apply to DTLS
+    /// connections.
+    ///
+    /// The default is false.
+    pub defer_cert_verification: bool,
 }
 
 impl fmt::Debug for ClientConfig {
@@ -158,9 +308,26 @@ impl fmt::Debug for ClientConfig {
         f.debug_struct("ClientConfig")
             .field("alpn_protocols", &self.alpn_protocols)
             .field("max_fragment_size", &self.max_fragment_size)
+            .field("record_size_limit", &self.record_size_limit)
+            .field("max_fragment_length", &self.max_fragment_length)
             .field("enable_tickets", &self.enable_tickets)
             .field("enable_sni", &self.enable_sni)
+            .field(
+                "send_certificate_authorities",
+                &self.send_certificate_authorities,
+            )
+            .field(
+                "enable_delegated_credentials",
+                &self.enable_delegated_credentials,
+            )
             .field("enable_early_data", &self.enable_early_data)
+            .field("ech_config", &self.ech_config)
+            .field("cert_decompressors", &self.cert_decompressors)
+            .field("cert_compressors", &self.cert_compressors)
+            .field("server_raw_key_verifier", &self.server_raw_key_verifier)
+            .field("external_psks", &self.external_psks)
+            .field("psk_kx_modes", &self.psk_kx_modes)
+            .field("defer_cert_verification", &self.defer_cert_verification)
             .finish_non_exhaustive()
     }
 }
@@ -168,10 +335,22 @@ impl fmt::Debug for ClientConfig {
 impl ClientConfig {
     /// Create a builder to build up the client configuration.
     ///
+    /// This uses the default, *ring*-based, cryptography provider.
+    ///
     /// For more information, see the [`ConfigBuilder`] documentation.
     pub fn builder() -> ConfigBuilder<Self, WantsCipherSuites> {
+        Self::builder_with_provider(crypto::ring::RING)
+    }
+
+    /// Create a builder to build up the client configuration, using
+    /// `provider` for all cryptography.
+    ///
+    /// For more information, see the [`ConfigBuilder`] documentation.
+    pub fn builder_with_provider(
+        provider: &'static dyn CryptoProvider,
+    ) -> ConfigBuilder<Self, WantsCipherSuites> {
         ConfigBuilder {
-            state: WantsCipherSuites(()),
+            state: WantsCipherSuites(provider),
             side: PhantomData::default(),
         }
     }
@@ -248,11 +427,6 @@ impl ServerName {
 
     /// Return a prefix-free, unique encoding for the name.
     pub(crate) fn encode(&self) -> Vec<u8> {
-        enum UniqueTypeCode {
-            DnsName = 0x01,
-            IpAddr = 0x02,
-        }
-
         match self {
             Self::DnsName(dns_name) => {
                 let bytes = dns_name.0.as_ref();
@@ -277,6 +451,31 @@ impl ServerName {
             }
         }
     }
+
+    /// Parse the output of [`Self::encode()`].
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let (code, name) = match bytes {
+            [code, len, name @ ..] if usize::from(*len) == name.len() => {
+                (*code, std::str::from_utf8(name).ok()?)
+            }
+            _ => return None,
+        };
+
+        match code {
+            c if c == UniqueTypeCode::DnsName as u8 => webpki::DnsNameRef::try_from_ascii_str(name)
+                .ok()
+                .map(|dns| Self::DnsName(verify::DnsName(dns.into()))),
+            c if c == UniqueTypeCode::IpAddr as u8 => name.parse().ok().map(Self::IpAddress),
+            _ => None,
+        }
+    }
+}
+
+/// The first byte of [`ServerName::encode()`]'s output, saying which
+/// kind of name follows.
+enum UniqueTypeCode {
+    DnsName = 0x01,
+    IpAddr = 0x02,
 }
 
 /// Attempt to make a ServerName from a string by parsing
@@ -472,6 +671,11 @@ impl ClientConnection {
     ) -> Result<Self, Error> {
         let mut common_state = CommonState::new(Side::Client);
         common_state.set_max_fragment_size(config.max_fragment_size)?;
+        common_state.set_record_size_limit(config.record_size_limit)?;
+        if let Some(len) = config.max_fragment_length {
+            len.fragment_len()
+                .ok_or(Error::BadMaxFragmentSize)?;
+        }
         common_state.protocol = proto;
         #[cfg(feature = "secret_extraction")]
         {
@@ -490,6 +694,14 @@ impl ClientConnection {
         Ok(Self { inner })
     }
 
+    #[cfg(feature = "dtls")]
+    pub(crate) fn new_dtls(
+        config: Arc<ClientConfig>,
+        name: ServerName,
+    ) -> Result<ConnectionCommon<ClientConnectionData>, Error> {
+        Ok(Self::new_inner(config, name, Vec::new(), Protocol::Dtls)?.inner)
+    }
+
     /// Returns an `io::Write` implementer you can write bytes to
     /// to send TLS1.3 early data (a.k.a. "0-RTT data") to the server.
     ///
@@ -537,6 +749,14 @@ impl ClientConnection {
             })
     }
 
+    /// Returns the outcome of Encrypted Client Hello for this connection.
+    ///
+    /// This is only meaningful once the server's first flight has been
+    /// processed.
+    pub fn ech_status(&self) -> EchStatus {
+        self.inner.data.ech_status
+    }
+
     /// Extract secrets, so they can be used when configuring kTLS, for example.
     #[cfg(feature = "secret_extraction")]
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
@@ -581,6 +801,8 @@ impl From<ClientConnection> for crate::Connection {
 pub struct ClientConnectionData {
     pub(super) early_data: EarlyData,
     pub(super) resumption_ciphersuite: Option<SupportedCipherSuite>,
+    pub(super) ech_status: EchStatus,
+    pub(super) ech_retry_configs: Option<Vec<u8>>,
 }
 
 impl ClientConnectionData {
@@ -588,6 +810,8 @@ impl ClientConnectionData {
         Self {
             early_data: EarlyData::new(),
             resumption_ciphersuite: None,
+            ech_status: EchStatus::NotOffered,
+            ech_retry_configs: None,
         }
     }
 }
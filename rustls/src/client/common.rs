@@ -1,11 +1,11 @@
 use super::ResolvesClientCert;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::enums::ExtensionType;
+use crate::msgs::enums::{CertificateType, ExtensionType};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SCTList;
 use crate::msgs::handshake::ServerExtension;
-use crate::{sign, DistinguishedNames, SignatureScheme};
+use crate::{sign, DistinguishedNames, ExternalPsk, SignatureScheme};
 
 use std::sync::Arc;
 
@@ -40,12 +40,14 @@ impl ServerCertDetails {
 
 pub(super) struct ClientHelloDetails {
     pub(super) sent_extensions: Vec<ExtensionType>,
+    pub(super) offered_external_psks: Vec<Arc<ExternalPsk>>,
 }
 
 impl ClientHelloDetails {
     pub(super) fn new() -> Self {
         Self {
             sent_extensions: Vec::new(),
+            offered_external_psks: Vec::new(),
         }
     }
 
@@ -88,6 +90,7 @@ impl ClientAuthDetails {
         resolver: &dyn ResolvesClientCert,
         canames: Option<&DistinguishedNames>,
         sigschemes: &[SignatureScheme],
+        cert_type: CertificateType,
         auth_context_tls13: Option<Vec<u8>>,
     ) -> Self {
         let acceptable_issuers = canames
@@ -97,7 +100,10 @@ impl ClientAuthDetails {
             .map(|p| p.0.as_slice())
             .collect::<Vec<&[u8]>>();
 
-        if let Some(certkey) = resolver.resolve(&acceptable_issuers, sigschemes) {
+        if let Some(certkey) = resolver
+            .resolve(&acceptable_issuers, sigschemes)
+            .filter(|certkey| certkey.cert_type == cert_type)
+        {
             if let Some(signer) = certkey.key.choose_scheme(sigschemes) {
                 debug!("Attempting client auth");
                 return Self::Verify {
@@ -0,0 +1,477 @@
+use crate::crypto::CryptoProvider;
+use crate::ech::{self, CONFIRMATION_LEN, HPKE_INFO_PREFIX};
+use crate::enums::ProtocolVersion;
+use crate::error::Error;
+use crate::hash_hs::HandshakeHashBuffer;
+use crate::hpke;
+#[cfg(feature = "logging")]
+use crate::log::debug;
+use crate::msgs::base::{Payload, PayloadU16};
+use crate::msgs::codec::Codec;
+use crate::msgs::enums::HandshakeType;
+use crate::msgs::handshake::{ClientExtension, ClientHelloPayload, ConvertServerNameList};
+use crate::msgs::handshake::{EchConfigContents, EchConfigList, EchConfigPayload};
+use crate::msgs::handshake::{EncryptedClientHello, EncryptedClientHelloOuter};
+use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+use crate::msgs::handshake::{HelloRetryExtension, HelloRetryRequest};
+use crate::msgs::handshake::{HpkeSymmetricCipherSuite, Random, SessionID};
+use crate::msgs::message::{Message, MessagePayload};
+use crate::suites::SupportedCipherSuite;
+use crate::tls13::Tls13CipherSuite;
+
+use super::common::ClientHelloDetails;
+use super::ServerName;
+
+use ring::constant_time;
+
+use std::convert::TryFrom;
+use std::mem;
+
+/// A server's Encrypted Client Hello configuration, as published
+/// in its DNS HTTPS record.
+#[derive(Clone, Debug)]
+pub struct EchConfig {
+    contents: EchConfigContents,
+    encoded: Vec<u8>,
+}
+
+impl EchConfig {
+    /// Select a usable configuration from a TLS-encoded `ECHConfigList`.
+    ///
+    /// The first configuration in the list that we support is chosen.
+    /// An error is returned if the list is malformed or contains no
+    /// configuration we can use.
+    pub fn new(ech_config_list: &[u8]) -> Result<Self, Error> {
+        let configs = EchConfigList::read_bytes(ech_config_list)
+            .ok_or_else(|| Error::General("invalid ECHConfigList".into()))?;
+
+        configs
+            .into_iter()
+            .find_map(|config| match config {
+                EchConfigPayload::V18(contents) => Self::from_contents(contents),
+                EchConfigPayload::Unknown { .. } => None,
+            })
+            .ok_or_else(|| Error::General("no supported ECH configuration".into()))
+    }
+
+    fn from_contents(contents: EchConfigContents) -> Option<Self> {
+        let key_config = &contents.key_config;
+        if !hpke::SUPPORTED_KEMS.contains(&key_config.kem_id) || contents.has_mandatory_extension()
+        {
+            return None;
+        }
+
+        public_name_of(&contents)?;
+
+        if !key_config
+            .symmetric_cipher_suites
+            .iter()
+            .any(hpke::is_supported_suite)
+        {
+            return None;
+        }
+
+        let encoded = EchConfigPayload::V18(contents.clone()).get_encoding();
+        Some(Self { contents, encoded })
+    }
+
+    /// The first of the configuration's HPKE cipher suites that `provider`
+    /// implements.
+    fn suite_for(&self, provider: &dyn CryptoProvider) -> Option<HpkeSymmetricCipherSuite> {
+        self.contents
+            .key_config
+            .symmetric_cipher_suites
+            .iter()
+            .find(|suite| hpke::provider_supports_suite(provider, suite))
+            .copied()
+    }
+}
+
+fn public_name_of(contents: &EchConfigContents) -> Option<ServerName> {
+    let name = std::str::from_utf8(&contents.public_name.0).ok()?;
+    match ServerName::try_from(name).ok()? {
+        ServerName::DnsName(dns_name) => Some(ServerName::DnsName(dns_name)),
+        _ => None,
+    }
+}
+
+/// The outcome of offering Encrypted Client Hello.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EchStatus {
+    /// ECH was not configured, or could not be offered.
+    NotOffered,
+    /// ECH was offered but the server has not yet responded.
+    Offered,
+    /// The server accepted ECH, and the handshake is with the
+    /// inner ClientHello.
+    Accepted,
+    /// The server rejected ECH.  The connection will fail once the
+    /// server has been authenticated under the public name.
+    Rejected,
+}
+
+/// Everything needed to send the inner ClientHello encrypted inside
+/// the outer one, and to later tell which one the server used.
+pub(super) struct EchState {
+    config: EchConfig,
+    pub(super) public_name: ServerName,
+    suite: HpkeSymmetricCipherSuite,
+    context: hpke::Context,
+    /// The encapsulated key: sent in the first ClientHello only.
+    enc: Vec<u8>,
+    pub(super) inner_random: Random,
+    pub(super) inner_transcript: HandshakeHashBuffer,
+    pub(super) inner_hello: ClientHelloDetails,
+    hrr_accepted: Option<bool>,
+}
+
+impl EchState {
+    pub(super) fn new(
+        provider: &dyn CryptoProvider,
+        config: &EchConfig,
+        client_auth_enabled: bool,
+    ) -> Result<Self, Error> {
+        let suite = config
+            .suite_for(provider)
+            .ok_or_else(|| {
+                Error::General("no ECH cipher suite is supported by the crypto provider".into())
+            })?;
+        let info = [HPKE_INFO_PREFIX, &config.encoded].concat();
+        let (enc, context) = hpke::setup_base_sender(
+            provider,
+            config.contents.key_config.kem_id,
+            &suite,
+            &config.contents.key_config.public_key.0,
+            &info,
+        )?;
+
+        let mut inner_transcript = HandshakeHashBuffer::new();
+        if client_auth_enabled {
+            inner_transcript.set_client_auth_enabled();
+        }
+
+        Ok(Self {
+            public_name: public_name_of(&config.contents)
+                .expect("public name validated when config was selected"),
+            config: config.clone(),
+            suite,
+            context,
+            enc,
+            inner_random: Random::new(provider)?,
+            inner_transcript,
+            inner_hello: ClientHelloDetails::new(),
+            hrr_accepted: None,
+        })
+    }
+
+    /// Produce the ClientHelloOuter carrying `inner`, recording `inner`
+    /// in the inner transcript.
+    ///
+    /// The outer hello offers `outer_versions` and the public name, and
+    /// otherwise repeats `inner`'s extensions, less those that could
+    /// identify a resumed session.
+    pub(super) fn seal_outer(
+        &mut self,
+        inner: &ClientHelloPayload,
+        outer_random: Random,
+        outer_versions: Vec<ProtocolVersion>,
+    ) -> Result<ClientHelloPayload, Error> {
+        self.inner_transcript
+            .add_message(&Message {
+                version: ProtocolVersion::TLSv1_3,
+                payload: MessagePayload::handshake(HandshakeMessagePayload {
+                    typ: HandshakeType::ClientHello,
+                    payload: HandshakePayload::ClientHello(inner.clone()),
+                }),
+            });
+
+        let encoded_inner = self.encode_inner(inner);
+
+        let mut extensions = Vec::with_capacity(inner.extensions.len());
+        for ext in &inner.extensions {
+            match ext {
+                ClientExtension::SupportedVersions(_) => {
+                    extensions.push(ClientExtension::SupportedVersions(outer_versions.clone()));
+                }
+                ClientExtension::ServerName(_)
+                | ClientExtension::PresharedKey(_)
+                | ClientExtension::EarlyData
+                | ClientExtension::EncryptedClientHello(_) => {}
+                _ => extensions.push(ext.clone()),
+            }
+        }
+
+        if let ServerName::DnsName(public_name) = &self.public_name {
+            extensions.push(ClientExtension::make_sni(public_name.0.as_ref()));
+        }
+
+        let mut outer_ech = EncryptedClientHelloOuter {
+            cipher_suite: self.suite,
+            config_id: self
+                .config
+                .contents
+                .key_config
+                .config_id,
+            enc: PayloadU16::new(mem::take(&mut self.enc)),
+            payload: PayloadU16::new(vec![0u8; encoded_inner.len() + self.context.tag_len()]),
+        };
+
+        let mut outer = ClientHelloPayload {
+            client_version: inner.client_version,
+            random: outer_random,
+            session_id: inner.session_id,
+            cipher_suites: inner.cipher_suites.clone(),
+            compression_methods: inner.compression_methods.clone(),
+            extensions,
+        };
+
+        // The AAD is the outer hello with the payload zeroed.
+        outer
+            .extensions
+            .push(ClientExtension::EncryptedClientHello(
+                EncryptedClientHello::Outer(outer_ech.clone()),
+            ));
+        let aad = outer.get_encoding();
+        outer_ech.payload = PayloadU16::new(
+            self.context
+                .seal(&aad, &encoded_inner)?,
+        );
+
+        outer.extensions.pop();
+        outer
+            .extensions
+            .push(ClientExtension::EncryptedClientHello(
+                EncryptedClientHello::Outer(outer_ech),
+            ));
+        Ok(outer)
+    }
+
+    /// Encode `inner` as an `EncodedClientHelloInner`: without the
+    /// session id (the server copies it from the outer hello), and
+    /// padded to hide the length of the server name.
+    fn encode_inner(&self, inner: &ClientHelloPayload) -> Vec<u8> {
+        let mut inner = inner.clone();
+        inner.session_id = SessionID::empty();
+        let mut encoded = inner.get_encoding();
+
+        let max_name_len = self.config.contents.maximum_name_length as usize;
+        let mut padding_len = match inner
+            .get_sni_extension()
+            .and_then(ConvertServerNameList::get_single_hostname)
+        {
+            Some(name) => max_name_len.saturating_sub(<&str>::from(name).len()),
+            None => max_name_len + 9,
+        };
+        padding_len += 31 - ((encoded.len() + padding_len + 31) % 32);
+        encoded.resize(encoded.len() + padding_len, 0);
+        encoded
+    }
+
+    /// Process a HelloRetryRequest, noting whether it confirms that the
+    /// server accepted ECH and updating the inner transcript.
+    pub(super) fn process_hrr(
+        &mut self,
+        cs: SupportedCipherSuite,
+        hrr: &HelloRetryRequest,
+        m: &Message,
+    ) {
+        let transcript = mem::replace(&mut self.inner_transcript, HandshakeHashBuffer::new())
+            .start_hash(cs.hash_algorithm())
+            .into_hrr_buffer();
+
+        let accepted = match (cs.tls13(), hrr.get_ech_confirmation()) {
+            (Some(suite), Some(confirmation)) if confirmation.len() == CONFIRMATION_LEN => {
+                let mut zeroed = hrr.clone();
+                for ext in zeroed.extensions.iter_mut() {
+                    if let HelloRetryExtension::EncryptedClientHello(payload) = ext {
+                        *payload = Payload::new(vec![0u8; CONFIRMATION_LEN]);
+                    }
+                }
+
+                let encoded = HandshakeMessagePayload {
+                    typ: HandshakeType::HelloRetryRequest,
+                    payload: HandshakePayload::HelloRetryRequest(zeroed),
+                }
+                .get_encoding();
+
+                let hash = transcript.get_hash_given(cs.hash_algorithm(), &encoded);
+                let expected = ech::confirmation(
+                    suite,
+                    &self.inner_random,
+                    ech::HRR_CONFIRMATION_LABEL,
+                    &hash,
+                );
+                constant_time::verify_slices_are_equal(&expected.0, confirmation).is_ok()
+            }
+            _ => false,
+        };
+
+        debug!("ECH accepted in HelloRetryRequest: {}", accepted);
+        self.hrr_accepted = Some(accepted);
+        self.inner_transcript = transcript;
+        self.inner_transcript.add_message(m);
+    }
+
+    /// What the HelloRetryRequest said about ECH acceptance, if there was one.
+    pub(super) fn hrr_accepted(&self) -> Option<bool> {
+        self.hrr_accepted
+    }
+
+    /// Returns true if the ServerHello `m` confirms that the server
+    /// accepted ECH.
+    pub(super) fn confirm_server_hello(
+        &self,
+        suite: &'static Tls13CipherSuite,
+        m: &Message,
+    ) -> bool {
+        let mut encoded = match &m.payload {
+            MessagePayload::Handshake { encoded, .. } => encoded.0.clone(),
+            _ => return false,
+        };
+
+        // Handshake header (4 bytes), legacy_version (2 bytes), then the random.
+        const RANDOM_START: usize = 4 + 2;
+        let confirmation_start = RANDOM_START + 32 - CONFIRMATION_LEN;
+        if encoded.len() < RANDOM_START + 32 {
+            return false;
+        }
+        let received = encoded[confirmation_start..RANDOM_START + 32].to_vec();
+        for byte in &mut encoded[confirmation_start..RANDOM_START + 32] {
+            *byte = 0;
+        }
+
+        let hash = self
+            .inner_transcript
+            .get_hash_given(suite.hash_algorithm(), &encoded);
+        let expected = ech::confirmation(
+            suite,
+            &self.inner_random,
+            ech::SERVER_HELLO_CONFIRMATION_LABEL,
+            &hash,
+        );
+        constant_time::verify_slices_are_equal(&expected.0, &received).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msgs::base::PayloadU8;
+    use crate::msgs::enums::{EchVersion, HpkeAead, HpkeKdf, HpkeKem};
+    use crate::msgs::handshake::HpkeKeyConfig;
+
+    fn contents(kem_id: HpkeKem, public_name: &[u8], extensions: Vec<u8>) -> EchConfigContents {
+        EchConfigContents {
+            key_config: HpkeKeyConfig {
+                config_id: 7,
+                kem_id,
+                public_key: PayloadU16::new(vec![0x42; 32]),
+                symmetric_cipher_suites: vec![
+                    HpkeSymmetricCipherSuite {
+                        kdf_id: HpkeKdf::Unknown(0x99),
+                        aead_id: HpkeAead::AES_128_GCM,
+                    },
+                    HpkeSymmetricCipherSuite {
+                        kdf_id: HpkeKdf::HKDF_SHA256,
+                        aead_id: HpkeAead::CHACHA20_POLY_1305,
+                    },
+                ],
+            },
+            maximum_name_length: 32,
+            public_name: PayloadU8::new(public_name.to_vec()),
+            extensions: PayloadU16::new(extensions),
+        }
+    }
+
+    fn encode_list(configs: Vec<EchConfigPayload>) -> Vec<u8> {
+        let list: EchConfigList = configs;
+        list.get_encoding()
+    }
+
+    #[test]
+    fn selects_first_supported_config() {
+        let list = encode_list(vec![
+            EchConfigPayload::Unknown {
+                version: EchVersion::Unknown(0xfe0a),
+                contents: PayloadU16::new(vec![1, 2, 3]),
+            },
+            EchConfigPayload::V18(contents(
+                HpkeKem::DHKEM_P256_HKDF_SHA256,
+                b"public.example",
+                vec![],
+            )),
+            EchConfigPayload::V18(contents(
+                HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                b"public.example",
+                vec![0x80, 0x01, 0x00, 0x00],
+            )),
+            EchConfigPayload::V18(contents(
+                HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                b"not a name",
+                vec![],
+            )),
+            EchConfigPayload::V18(contents(
+                HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                b"public.example",
+                vec![0x00, 0x01, 0x00, 0x00],
+            )),
+        ]);
+
+        let config = EchConfig::new(&list).unwrap();
+        assert_eq!(config.contents.key_config.config_id, 7);
+        assert_eq!(config.contents.extensions.0, vec![0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(
+            config.suite_for(crate::crypto::ring::RING),
+            Some(HpkeSymmetricCipherSuite {
+                kdf_id: HpkeKdf::HKDF_SHA256,
+                aead_id: HpkeAead::CHACHA20_POLY_1305,
+            })
+        );
+        assert!(list.ends_with(&config.encoded));
+    }
+
+    #[test]
+    fn rejects_unusable_lists() {
+        assert!(EchConfig::new(&[]).is_err());
+        assert!(EchConfig::new(&[0x00, 0x03, 0xfe, 0x0d]).is_err());
+
+        let list = encode_list(vec![EchConfigPayload::V18(contents(
+            HpkeKem::DHKEM_X448_HKDF_SHA512,
+            b"public.example",
+            vec![],
+        ))]);
+        assert!(EchConfig::new(&list).is_err());
+    }
+
+    #[test]
+    fn inner_hello_is_padded() {
+        let list = encode_list(vec![EchConfigPayload::V18(contents(
+            HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            b"public.example",
+            vec![],
+        ))]);
+        let state = EchState::new(
+            crate::crypto::ring::RING,
+            &EchConfig::new(&list).unwrap(),
+            false,
+        )
+        .unwrap();
+
+        let mut lengths = Vec::new();
+        for name in ["a.example", "a-much-longer-name.example"] {
+            let dns_name = webpki::DnsNameRef::try_from_ascii_str(name).unwrap();
+            let hello = ClientHelloPayload {
+                client_version: ProtocolVersion::TLSv1_2,
+                random: Random([0; 32]),
+                session_id: SessionID::random(crate::crypto::ring::RING).unwrap(),
+                cipher_suites: vec![],
+                compression_methods: vec![],
+                extensions: vec![ClientExtension::make_sni(dns_name)],
+            };
+            let encoded = state.encode_inner(&hello);
+            assert_eq!(encoded.len() % 32, 0);
+            lengths.push(encoded.len());
+        }
+        assert_eq!(lengths[0], lengths[1]);
+    }
+}
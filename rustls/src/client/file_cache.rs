@@ -0,0 +1,798 @@
+use crate::client::{self, ServerName};
+use crate::crypto::{self, hmac, CryptoProvider};
+use crate::enums::CipherSuite;
+#[cfg(feature = "logging")]
+use crate::log::{debug, warn};
+use crate::msgs::codec::{u24, Codec, Reader};
+use crate::msgs::enums::{HashAlgorithm, NamedGroup};
+#[cfg(feature = "tls12")]
+use crate::msgs::persist::Tls12ClientSessionValue;
+use crate::msgs::persist::{ClientSessionValue, Tls13ClientSessionValue};
+use crate::suites::ALL_CIPHER_SUITES;
+
+use super::handy::ServerData;
+
+use ring::constant_time;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Starts every file, identifying its format.
+const MAGIC: &[u8] = b"rustls client sessions\n";
+
+/// The format version.  Files with any other version are ignored.
+const VERSION: u8 = 2;
+
+/// The shortest key accepted by [`ClientSessionFileCache::new()`].
+const MIN_KEY_LEN: usize = 32;
+
+/// Distinguishes the HMAC naming a server's file from those
+/// authenticating files, which start with `MAGIC`.
+const FILE_NAME_LABEL: &[u8] = b"file name\0";
+
+/// How long to wait for another process to release a lock.
+const LOCK_ATTEMPTS: u32 = 100;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A lock file older than this was left by a process which stopped
+/// while holding it.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(10);
+
+/// An implementer of `StoresClientSessions` that keeps sessions in
+/// a directory, so that they survive the process.
+///
+/// There is one file for each server.  Changes are made while holding
+/// a lock file, and files are replaced atomically, so the directory can
+/// be shared by several processes: each TLS1.3 ticket is still used at
+/// most once.  Each file holds a format version and an HMAC of its
+/// contents, under a key which the application keeps outside the
+/// directory; files which are corrupt, in another version or made with
+/// another key are ignored and later replaced.  File names are HMACs of
+/// the server names, so do not reveal them.
+///
+/// Expired sessions are removed whenever a server's file is changed,
+/// and by [`ClientSessionFileCache::prune()`].
+///
+/// These files contain enough key material to break all security of
+/// the sessions in them.  On Unix, the directory and files are created
+/// accessible only by their owner, and an existing directory which
+/// others can access is refused.
+///
+/// Failures to read or write files are logged, and otherwise treated
+/// as if nothing was stored.
+pub struct ClientSessionFileCache {
+    dir: PathBuf,
+    key: Box<dyn hmac::Key>,
+}
+
+impl ClientSessionFileCache {
+    /// Make a new ClientSessionFileCache, storing sessions in `dir`
+    /// and authenticating them with `key`.  `dir` is created if it does
+    /// not exist.
+    ///
+    /// `key` must be at least 32 random bytes, and the same for every
+    /// process sharing `dir`.  Store it somewhere other than `dir`: anyone
+    /// who has it and can write to `dir` can make this client resume
+    /// sessions of their choosing.
+    ///
+    /// On Unix, this fails if `dir` is accessible by anyone other than
+    /// its owner, whether or not it was just created.
+    ///
+    /// This uses the default, *ring*-based, cryptography provider.
+    pub fn new(dir: impl AsRef<Path>, key: &[u8]) -> io::Result<Arc<Self>> {
+        Self::new_with_provider(crypto::ring::RING, dir, key)
+    }
+
+    /// Like [`Self::new()`], but computing HMAC-SHA256 with `provider`.
+    ///
+    /// This fails if none of `provider`'s TLS1.3 cipher suites uses SHA-256.
+    pub fn new_with_provider(
+        provider: &'static dyn CryptoProvider,
+        dir: impl AsRef<Path>,
+        key: &[u8],
+    ) -> io::Result<Arc<Self>> {
+        if key.len() < MIN_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("session cache key must be at least {} bytes", MIN_KEY_LEN),
+            ));
+        }
+        let hmac = crypto::find_hmac(provider, HashAlgorithm::SHA256).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "session cache needs the provider to support HMAC-SHA256",
+            )
+        })?;
+
+        let dir = dir.as_ref().to_path_buf();
+
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(&dir)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&dir)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "session cache directory {:?} is accessible by other users (mode {:o})",
+                        dir,
+                        mode & 0o777
+                    ),
+                ));
+            }
+        }
+
+        Ok(Arc::new(Self {
+            dir,
+            key: hmac.with_key(key),
+        }))
+    }
+
+    /// Remove expired sessions from every file in the directory, and
+    /// any files which are left with nothing in them or cannot be read.
+    pub fn prune(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(name) if is_data_file_name(name) => {}
+                _ => continue,
+            }
+
+            let path = entry.path();
+            let _lock = Lock::acquire(path.with_extension("lock"))?;
+            match read_file(&path).and_then(|bytes| decode(&*self.key, &bytes)) {
+                Some((server_name, data)) if self.data_path(&server_name) == path => {
+                    self.save(&server_name, data)?
+                }
+                _ => remove_file(&path)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply `edit` to what is stored for `server_name`, while holding
+    /// its lock.
+    fn edit<T>(
+        &self,
+        server_name: &ServerName,
+        edit: impl FnOnce(&mut ServerData) -> T,
+    ) -> Option<T> {
+        let result = Lock::acquire(self.lock_path(server_name)).and_then(|_lock| {
+            let mut data = self
+                .load(server_name)
+                .unwrap_or_default();
+            let result = edit(&mut data);
+            self.save(server_name, data)?;
+            Ok(result)
+        });
+
+        match result {
+            Ok(result) => Some(result),
+            #[allow(unused_variables)]
+            Err(e) => {
+                warn!("Cannot update session cache in {:?}: {}", self.dir, e);
+                None
+            }
+        }
+    }
+
+    fn load(&self, server_name: &ServerName) -> Option<ServerData> {
+        let bytes = read_file(&self.data_path(server_name))?;
+        match decode(&*self.key, &bytes) {
+            Some((stored_name, data)) if &stored_name == server_name => Some(data),
+            _ => {
+                debug!(
+                    "Ignoring unreadable session cache file for {:?}",
+                    server_name
+                );
+                None
+            }
+        }
+    }
+
+    /// Write `data` for `server_name`, after removing expired sessions.
+    /// The caller must hold the lock.
+    fn save(&self, server_name: &ServerName, mut data: ServerData) -> io::Result<()> {
+        let path = self.data_path(server_name);
+        data.remove_expired();
+        if data.is_empty() {
+            return remove_file(&path);
+        }
+
+        let tmp_path = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options
+            .write(true)
+            .create(true)
+            .truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(&encode(&*self.key, server_name, &data))?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn data_path(&self, server_name: &ServerName) -> PathBuf {
+        let tag = self
+            .key
+            .sign(&[FILE_NAME_LABEL, &server_name.encode()]);
+        let name = tag
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        self.dir.join(name)
+    }
+
+    fn lock_path(&self, server_name: &ServerName) -> PathBuf {
+        self.data_path(server_name)
+            .with_extension("lock")
+    }
+}
+
+impl client::StoresClientSessions for ClientSessionFileCache {
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
+        self.edit(server_name, |data| data.kx_hint = Some(group));
+    }
+
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
+        self.load(server_name)?.kx_hint
+    }
+
+    #[cfg(feature = "tls12")]
+    fn set_tls12_session(&self, server_name: &ServerName, value: Tls12ClientSessionValue) {
+        self.edit(server_name, |data| data.tls12 = Some(value));
+    }
+
+    #[cfg(feature = "tls12")]
+    fn tls12_session(&self, server_name: &ServerName) -> Option<Tls12ClientSessionValue> {
+        self.load(server_name)?.tls12
+    }
+
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue) {
+        self.edit(server_name, |data| data.insert_tls13_ticket(value));
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
+        self.edit(server_name, ServerData::take_tls13_ticket)?
+    }
+}
+
+/// A lock file, which is removed when this is dropped.
+///
+/// The file holds a token unique to this acquisition.  It is only ever
+/// removed by first renaming it aside and checking the token, so a lock
+/// which was taken over after going stale is not removed by its previous
+/// holder, and a stale lock is not broken twice.
+struct Lock {
+    path: PathBuf,
+    token: Vec<u8>,
+}
+
+impl Lock {
+    fn acquire(path: PathBuf) -> io::Result<Self> {
+        let token = new_lock_token();
+        for _ in 0..LOCK_ATTEMPTS {
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(mut file) => {
+                    if let Err(e) = file.write_all(&token) {
+                        let _ = fs::remove_file(&path);
+                        return Err(e);
+                    }
+                    return Ok(Self { path, token });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+
+            // Read the holder's token before checking the age, so that
+            // only the lock which was found to be stale can be removed.
+            let holder = fs::read(&path).ok();
+            let stale = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| {
+                    SystemTime::now()
+                        .duration_since(modified)
+                        .ok()
+                })
+                .map_or(false, |age| age > STALE_LOCK_AGE);
+            match holder {
+                Some(holder) if stale => {
+                    debug!("Removing stale lock {:?}", path);
+                    remove_lock(&path, &holder, &token)?;
+                }
+                _ => thread::sleep(LOCK_RETRY_INTERVAL),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out waiting for {:?}", path),
+        ))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = remove_lock(&self.path, &self.token, &self.token);
+    }
+}
+
+/// Make a token which no other lock holder, in this process or
+/// another, will use.
+fn new_lock_token() -> Vec<u8> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos());
+    format!(
+        "{}-{}-{}",
+        process::id(),
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+    .into_bytes()
+}
+
+/// Remove the lock file at `path` if it holds `token`.  `remover` is
+/// the token of whoever is removing it, and names the file it is moved
+/// aside to while its token is checked.
+///
+/// If the lock turns out to have been taken over by someone else, it is
+/// put back, unless yet another lock has been taken in the meantime.
+fn remove_lock(path: &Path, token: &[u8], remover: &[u8]) -> io::Result<()> {
+    let aside = path.with_extension(format!("lock.{}", String::from_utf8_lossy(remover)));
+    match fs::rename(path, &aside) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    if fs::read(&aside)? != token {
+        match fs::hard_link(&aside, path) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                let _ = fs::remove_file(&aside);
+                return Err(e);
+            }
+            _ => {}
+        }
+    }
+    fs::remove_file(&aside)
+}
+
+fn is_data_file_name(name: &str) -> bool {
+    name.len() == 64
+        && name
+            .bytes()
+            .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Read the file at `path`, which need not exist.
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    match fs::read(path) {
+        Ok(bytes) => Some(bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        #[allow(unused_variables)]
+        Err(e) => {
+            warn!("Cannot read session cache file {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Remove the file at `path`, which need not exist.
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The file format is `MAGIC`, `VERSION`, then the HMAC-SHA256 of those
+/// and the body, then the body:
+///
+/// ```text
+/// opaque server_name<1..2^16-1>;
+/// optional<NamedGroup> kx_hint;
+/// optional<opaque<1..2^24-1>> tls12_session;
+/// opaque tls13_tickets<0..2^24-1>;  /* each as opaque<1..2^24-1> */
+/// ```
+///
+/// where `optional<T>` is a presence byte followed by `T` if it is 1.
+/// Sessions are encoded the same way whichever crate features are
+/// enabled, and a TLS1.2 session is skipped by a reader without TLS1.2
+/// support.
+fn encode(key: &dyn hmac::Key, server_name: &ServerName, data: &ServerData) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_u16_prefixed(&server_name.encode(), &mut body);
+
+    match data.kx_hint {
+        Some(group) => {
+            1u8.encode(&mut body);
+            group.encode(&mut body);
+        }
+        None => 0u8.encode(&mut body),
+    }
+
+    #[cfg(feature = "tls12")]
+    let tls12 = data
+        .tls12
+        .as_ref()
+        .map(Tls12ClientSessionValue::get_encoding);
+    #[cfg(not(feature = "tls12"))]
+    let tls12: Option<Vec<u8>> = None;
+    match tls12 {
+        Some(session) => {
+            1u8.encode(&mut body);
+            encode_u24_prefixed(&session, &mut body);
+        }
+        None => 0u8.encode(&mut body),
+    }
+
+    let mut tickets = Vec::new();
+    for ticket in &data.tls13 {
+        encode_u24_prefixed(&ticket.get_encoding(), &mut tickets);
+    }
+    encode_u24_prefixed(&tickets, &mut body);
+
+    let mut bytes = MAGIC.to_vec();
+    VERSION.encode(&mut bytes);
+    bytes.extend_from_slice(
+        key.sign(&[MAGIC, &[VERSION], &body])
+            .as_ref(),
+    );
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+fn decode(key: &dyn hmac::Key, bytes: &[u8]) -> Option<(ServerName, ServerData)> {
+    let mut r = Reader::init(bytes);
+    if r.take(MAGIC.len())? != MAGIC || u8::read(&mut r)? != VERSION {
+        return None;
+    }
+    let expected_tag = r.take(key.tag_len())?;
+    let body = r.rest();
+    let tag = key.sign(&[MAGIC, &[VERSION], body]);
+    if constant_time::verify_slices_are_equal(tag.as_ref(), expected_tag).is_err() {
+        return None;
+    }
+
+    let mut r = Reader::init(body);
+    let server_name = ServerName::decode(read_u16_prefixed(&mut r)?)?;
+    let mut data = ServerData::default();
+
+    if u8::read(&mut r)? == 1 {
+        data.kx_hint = Some(NamedGroup::read(&mut r)?);
+    }
+
+    if u8::read(&mut r)? == 1 {
+        let _session = read_u24_prefixed(&mut r)?;
+        #[cfg(feature = "tls12")]
+        if let Some(ClientSessionValue::Tls12(session)) = decode_value(_session) {
+            data.tls12 = Some(session);
+        }
+    }
+
+    let mut tickets = Reader::init(read_u24_prefixed(&mut r)?);
+    while tickets.any_left() {
+        // Tickets that cannot be decoded, for example because their
+        // cipher suite is not supported, are skipped.
+        if let Some(ClientSessionValue::Tls13(ticket)) =
+            decode_value(read_u24_prefixed(&mut tickets)?)
+        {
+            data.tls13.push_back(ticket);
+        }
+    }
+
+    match r.any_left() {
+        true => None,
+        false => Some((server_name, data)),
+    }
+}
+
+fn decode_value(bytes: &[u8]) -> Option<ClientSessionValue> {
+    let mut r = Reader::init(bytes);
+    let suite = CipherSuite::read(&mut r)?;
+    ClientSessionValue::read(&mut r, suite, ALL_CIPHER_SUITES)
+}
+
+fn encode_u16_prefixed(bytes: &[u8], out: &mut Vec<u8>) {
+    (bytes.len() as u16).encode(out);
+    out.extend_from_slice(bytes);
+}
+
+fn read_u16_prefixed<'a>(r: &mut Reader<'a>) -> Option<&'a [u8]> {
+    let len = u16::read(r)?;
+    r.take(usize::from(len))
+}
+
+fn encode_u24_prefixed(bytes: &[u8], out: &mut Vec<u8>) {
+    u24(bytes.len() as u32).encode(out);
+    out.extend_from_slice(bytes);
+}
+
+fn read_u24_prefixed<'a>(r: &mut Reader<'a>) -> Option<&'a [u8]> {
+    let len = u24::read(r)?;
+    r.take(usize::from(len))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::StoresClientSessions;
+    use crate::ticketer::TimeBase;
+    use crate::tls13::TLS13_AES_128_GCM_SHA256;
+
+    use std::env;
+    use std::process;
+
+    const KEY: &[u8] = &[0x42; MIN_KEY_LEN];
+
+    /// A directory which is removed when this is dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("rustls-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn server_name(name: &str) -> ServerName {
+        name.try_into().unwrap()
+    }
+
+    fn tls13_ticket(ticket: u8) -> Tls13ClientSessionValue {
+        Tls13ClientSessionValue::new(
+            TLS13_AES_128_GCM_SHA256
+                .tls13()
+                .unwrap(),
+            vec![ticket],
+            vec![1, 2, 3],
+            vec![],
+            TimeBase::now().unwrap(),
+            3600,
+            0,
+            0,
+        )
+    }
+
+    fn data_files(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                is_data_file_name(
+                    entry
+                        .as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_str()
+                        .unwrap(),
+                )
+            })
+            .count()
+    }
+
+    #[test]
+    fn test_clientsessionfilecache_persists_across_instances() {
+        let dir = TempDir::new("persists");
+        let name = server_name("example.com");
+
+        let first = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        first.set_kx_hint(&name, NamedGroup::X25519);
+        first.insert_tls13_ticket(&name, tls13_ticket(1));
+        first.insert_tls13_ticket(&name, tls13_ticket(2));
+        first.insert_tls13_ticket(&server_name("1.2.3.4"), tls13_ticket(3));
+
+        let second = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        assert_eq!(second.kx_hint(&name), Some(NamedGroup::X25519));
+        assert_eq!(
+            second
+                .take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[2]
+        );
+
+        // Each ticket is only used once, whichever instance takes it.
+        let ticket = first.take_tls13_ticket(&name).unwrap();
+        assert_eq!(ticket.ticket(), &[1]);
+        assert_eq!(ticket.secret(), &[1, 2, 3]);
+        assert!(second
+            .take_tls13_ticket(&name)
+            .is_none());
+        assert_eq!(
+            second
+                .take_tls13_ticket(&server_name("1.2.3.4"))
+                .unwrap()
+                .ticket(),
+            &[3]
+        );
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_clientsessionfilecache_keeps_tls12_session() {
+        use crate::msgs::handshake::SessionID;
+        use crate::suites::SupportedCipherSuite;
+
+        let suite = match crate::tls12::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 {
+            SupportedCipherSuite::Tls12(suite) => suite,
+            _ => unreachable!(),
+        };
+        let session = Tls12ClientSessionValue::new(
+            suite,
+            SessionID::empty(),
+            vec![4],
+            vec![5; 48],
+            vec![],
+            TimeBase::now().unwrap(),
+            0,
+            true,
+        );
+
+        let dir = TempDir::new("tls12");
+        let name = server_name("example.com");
+        let cache = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        cache.set_tls12_session(&name, session);
+
+        let cache = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        for _ in 0..2 {
+            let session = cache.tls12_session(&name).unwrap();
+            assert_eq!(session.ticket(), &[4]);
+            assert_eq!(session.secret(), &[5; 48]);
+            assert!(session.extended_ms());
+        }
+    }
+
+    #[test]
+    fn test_clientsessionfilecache_ignores_damaged_files() {
+        let dir = TempDir::new("damaged");
+        let name = server_name("example.com");
+        let cache = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        cache.set_kx_hint(&name, NamedGroup::X25519);
+
+        let path = cache.data_path(&name);
+        let good = fs::read(&path).unwrap();
+
+        let mut corrupt = good.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut other_version = good.clone();
+        other_version[MAGIC.len()] += 1;
+
+        for bad in [corrupt, other_version, good[..good.len() - 1].to_vec()] {
+            fs::write(&path, bad).unwrap();
+            assert_eq!(cache.kx_hint(&name), None);
+        }
+
+        // A damaged file is replaced by the next change.
+        cache.insert_tls13_ticket(&name, tls13_ticket(1));
+        assert_eq!(
+            cache
+                .take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[1]
+        );
+    }
+
+    #[test]
+    fn test_clientsessionfilecache_ignores_files_made_with_another_key() {
+        let dir = TempDir::new("other-key");
+        let name = server_name("example.com");
+        let other = ClientSessionFileCache::new(&dir.0, &[0x43; MIN_KEY_LEN]).unwrap();
+        other.set_kx_hint(&name, NamedGroup::X25519);
+        other.insert_tls13_ticket(&name, tls13_ticket(1));
+
+        // The other key names the file differently, so planting it where
+        // this key looks for it is not enough either.
+        let cache = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        fs::copy(other.data_path(&name), cache.data_path(&name)).unwrap();
+        assert_eq!(cache.kx_hint(&name), None);
+        assert!(cache.take_tls13_ticket(&name).is_none());
+    }
+
+    #[test]
+    fn test_clientsessionfilecache_refuses_short_key() {
+        let dir = TempDir::new("short-key");
+        let err = ClientSessionFileCache::new(&dir.0, &KEY[1..])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clientsessionfilecache_refuses_shared_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("shared");
+        fs::create_dir(&dir.0).unwrap();
+        fs::set_permissions(&dir.0, fs::Permissions::from_mode(0o755)).unwrap();
+        let err = ClientSessionFileCache::new(&dir.0, KEY)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        fs::set_permissions(&dir.0, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(ClientSessionFileCache::new(&dir.0, KEY).is_ok());
+    }
+
+    #[test]
+    fn test_clientsessionfilecache_lock_is_only_removed_by_its_holder() {
+        let dir = TempDir::new("lock");
+        ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+        let path = dir.0.join("test.lock");
+
+        // A lock taken over by someone else is left for them.
+        let lock = Lock::acquire(path.clone()).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, b"other").unwrap();
+        drop(lock);
+        assert_eq!(fs::read(&path).unwrap(), b"other");
+
+        // Breaking a stale lock only removes the lock which was seen.
+        remove_lock(&path, b"stale", b"breaker").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"other");
+        remove_lock(&path, b"other", b"breaker").unwrap();
+        assert!(!path.exists());
+
+        let lock = Lock::acquire(path.clone()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), lock.token);
+        drop(lock);
+        assert!(!path.exists());
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_clientsessionfilecache_prunes_expired_sessions() {
+        let dir = TempDir::new("prunes");
+        let cache = ClientSessionFileCache::new(&dir.0, KEY).unwrap();
+
+        let mut expired = tls13_ticket(1);
+        expired.common.rewind_epoch(3601);
+        cache.insert_tls13_ticket(&server_name("a.example.com"), expired);
+        cache.insert_tls13_ticket(&server_name("b.example.com"), tls13_ticket(2));
+        fs::write(dir.0.join("0".repeat(64)), b"junk").unwrap();
+
+        // Saving drops the expired ticket, so nothing is kept for a.example.com.
+        assert_eq!(data_files(&dir.0), 2);
+
+        let mut expired = tls13_ticket(3);
+        expired.common.rewind_epoch(3601);
+        let name = server_name("b.example.com");
+        let mut data = cache.load(&name).unwrap();
+        data.tls13.push_back(expired);
+        fs::write(cache.data_path(&name), encode(&*cache.key, &name, &data)).unwrap();
+
+        cache.prune().unwrap();
+        assert_eq!(data_files(&dir.0), 1);
+        assert_eq!(cache.load(&name).unwrap().tls13.len(), 1);
+    }
+}
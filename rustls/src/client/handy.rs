@@ -1,58 +1,172 @@
 use crate::client;
+use crate::crypto::CryptoProvider;
 use crate::enums::SignatureScheme;
 use crate::error::Error;
 use crate::key;
 use crate::limited_cache;
+use crate::msgs::enums::{CertificateType, NamedGroup};
+#[cfg(feature = "tls12")]
+use crate::msgs::persist::Tls12ClientSessionValue;
+use crate::msgs::persist::Tls13ClientSessionValue;
 use crate::sign;
+use crate::ticketer::TimeBase;
 
+use super::ServerName;
+
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 
 /// An implementer of `StoresClientSessions` which does nothing.
 pub struct NoClientSessionStorage {}
 
 impl client::StoresClientSessions for NoClientSessionStorage {
-    fn put(&self, _key: Vec<u8>, _value: Vec<u8>) -> bool {
-        false
+    fn set_kx_hint(&self, _server_name: &ServerName, _group: NamedGroup) {}
+
+    fn kx_hint(&self, _server_name: &ServerName) -> Option<NamedGroup> {
+        None
+    }
+
+    #[cfg(feature = "tls12")]
+    fn set_tls12_session(&self, _server_name: &ServerName, _value: Tls12ClientSessionValue) {}
+
+    #[cfg(feature = "tls12")]
+    fn tls12_session(&self, _server_name: &ServerName) -> Option<Tls12ClientSessionValue> {
+        None
     }
 
-    fn get(&self, _key: &[u8]) -> Option<Vec<u8>> {
+    fn insert_tls13_ticket(&self, _server_name: &ServerName, _value: Tls13ClientSessionValue) {}
+
+    fn take_tls13_ticket(&self, _server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
         None
     }
 }
 
+/// How many TLS1.3 tickets are kept for each server.  Servers
+/// typically issue one or two per connection.
+const MAX_TLS13_TICKETS_PER_SERVER: usize = 8;
+
+/// Everything stored for one server.
+#[derive(Default)]
+pub(super) struct ServerData {
+    pub(super) kx_hint: Option<NamedGroup>,
+
+    #[cfg(feature = "tls12")]
+    pub(super) tls12: Option<Tls12ClientSessionValue>,
+
+    // first item is the oldest ticket
+    pub(super) tls13: VecDeque<Tls13ClientSessionValue>,
+}
+
+impl ServerData {
+    pub(super) fn insert_tls13_ticket(&mut self, value: Tls13ClientSessionValue) {
+        if self.tls13.len() == MAX_TLS13_TICKETS_PER_SERVER {
+            self.tls13.pop_front();
+        }
+        self.tls13.push_back(value);
+    }
+
+    /// Remove and return the newest ticket which has not expired.
+    pub(super) fn take_tls13_ticket(&mut self) -> Option<Tls13ClientSessionValue> {
+        self.remove_expired();
+        self.tls13.pop_back()
+    }
+
+    pub(super) fn remove_expired(&mut self) {
+        let now = match TimeBase::now() {
+            Ok(now) => now,
+            Err(_) => return,
+        };
+
+        #[cfg(feature = "tls12")]
+        if let Some(true) = self
+            .tls12
+            .as_ref()
+            .map(|session| session.has_expired(now))
+        {
+            self.tls12 = None;
+        }
+        self.tls13
+            .retain(|ticket| !ticket.has_expired(now));
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        #[cfg(feature = "tls12")]
+        if self.tls12.is_some() {
+            return false;
+        }
+        self.kx_hint.is_none() && self.tls13.is_empty()
+    }
+}
+
 /// An implementer of `StoresClientSessions` that stores everything
-/// in memory.  It enforces a limit on the number of entries
-/// to bound memory usage.
+/// in memory.
+///
+/// Up to eight TLS1.3 tickets are kept for each server, and the
+/// newest unexpired one is used first.  It enforces a limit on the
+/// number of servers to bound memory usage.
 pub struct ClientSessionMemoryCache {
-    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, Vec<u8>>>,
+    servers: Mutex<limited_cache::LimitedCache<ServerName, ServerData>>,
 }
 
 impl ClientSessionMemoryCache {
     /// Make a new ClientSessionMemoryCache.  `size` is the
-    /// maximum number of stored sessions.
+    /// maximum number of servers for which sessions are stored.
     pub fn new(size: usize) -> Arc<Self> {
         debug_assert!(size > 0);
         Arc::new(Self {
-            cache: Mutex::new(limited_cache::LimitedCache::new(size)),
+            servers: Mutex::new(limited_cache::LimitedCache::new(size)),
         })
     }
 }
 
 impl client::StoresClientSessions for ClientSessionMemoryCache {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.cache
+    fn set_kx_hint(&self, server_name: &ServerName, group: NamedGroup) {
+        self.servers
             .lock()
             .unwrap()
-            .insert(key, value);
-        true
+            .get_or_insert_default_and_edit(server_name.clone(), |data| data.kx_hint = Some(group));
+    }
+
+    fn kx_hint(&self, server_name: &ServerName) -> Option<NamedGroup> {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(server_name)
+            .and_then(|data| data.kx_hint)
+    }
+
+    #[cfg(feature = "tls12")]
+    fn set_tls12_session(&self, server_name: &ServerName, value: Tls12ClientSessionValue) {
+        self.servers
+            .lock()
+            .unwrap()
+            .get_or_insert_default_and_edit(server_name.clone(), |data| data.tls12 = Some(value));
+    }
+
+    #[cfg(feature = "tls12")]
+    fn tls12_session(&self, server_name: &ServerName) -> Option<Tls12ClientSessionValue> {
+        self.servers
+            .lock()
+            .unwrap()
+            .get(server_name)
+            .and_then(|data| data.tls12.clone())
     }
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache
+    fn insert_tls13_ticket(&self, server_name: &ServerName, value: Tls13ClientSessionValue) {
+        self.servers
             .lock()
             .unwrap()
-            .get(key)
-            .cloned()
+            .get_or_insert_default_and_edit(server_name.clone(), |data| {
+                data.insert_tls13_ticket(value)
+            });
+    }
+
+    fn take_tls13_ticket(&self, server_name: &ServerName) -> Option<Tls13ClientSessionValue> {
+        self.servers
+            .lock()
+            .unwrap()
+            .get_mut(server_name)?
+            .take_tls13_ticket()
     }
 }
 
@@ -76,13 +190,23 @@ pub(super) struct AlwaysResolvesClientCert(Arc<sign::CertifiedKey>);
 
 impl AlwaysResolvesClientCert {
     pub(super) fn new(
+        provider: &dyn CryptoProvider,
         chain: Vec<key::Certificate>,
         priv_key: &key::PrivateKey,
     ) -> Result<Self, Error> {
-        let key = sign::any_supported_type(priv_key)
-            .map_err(|_| Error::General("invalid private key".into()))?;
+        let key = provider.load_private_key(priv_key)?;
         Ok(Self(Arc::new(sign::CertifiedKey::new(chain, key))))
     }
+
+    pub(super) fn new_raw_public_key(
+        provider: &dyn CryptoProvider,
+        priv_key: &key::PrivateKey,
+    ) -> Result<Self, Error> {
+        let key = provider.load_private_key(priv_key)?;
+        let certkey = sign::CertifiedKey::from_signing_key(key)
+            .map_err(|_| Error::General("cannot get public key".into()))?;
+        Ok(Self(Arc::new(certkey)))
+    }
 }
 
 impl client::ResolvesClientCert for AlwaysResolvesClientCert {
@@ -97,64 +221,184 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
     fn has_certs(&self) -> bool {
         true
     }
+
+    fn uses_raw_public_keys(&self) -> bool {
+        self.0.cert_type == CertificateType::RawPublicKey
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::client::StoresClientSessions;
+    #[cfg(feature = "tls12")]
+    use crate::suites::SupportedCipherSuite;
+    use crate::tls13::TLS13_AES_128_GCM_SHA256;
+
+    fn server_name(name: &str) -> ServerName {
+        name.try_into().unwrap()
+    }
+
+    fn tls13_ticket(ticket: u8) -> Tls13ClientSessionValue {
+        Tls13ClientSessionValue::new(
+            TLS13_AES_128_GCM_SHA256
+                .tls13()
+                .unwrap(),
+            vec![ticket],
+            vec![],
+            vec![],
+            TimeBase::now().unwrap(),
+            3600,
+            0,
+            0,
+        )
+    }
+
+    #[cfg(feature = "tls12")]
+    fn tls12_session(ticket: u8) -> Tls12ClientSessionValue {
+        let suite = match crate::tls12::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 {
+            SupportedCipherSuite::Tls12(suite) => suite,
+            _ => unreachable!(),
+        };
+        Tls12ClientSessionValue::new(
+            suite,
+            crate::msgs::handshake::SessionID::empty(),
+            vec![ticket],
+            vec![],
+            vec![],
+            TimeBase::now().unwrap(),
+            0,
+            true,
+        )
+    }
 
     #[test]
-    fn test_noclientsessionstorage_drops_put() {
+    fn test_noclientsessionstorage_drops_everything() {
         let c = NoClientSessionStorage {};
-        assert!(!c.put(vec![0x01], vec![0x02]));
+        let name = server_name("example.com");
+
+        c.set_kx_hint(&name, NamedGroup::X25519);
+        c.insert_tls13_ticket(&name, tls13_ticket(1));
+        assert_eq!(c.kx_hint(&name), None);
+        assert!(c.take_tls13_ticket(&name).is_none());
+
+        #[cfg(feature = "tls12")]
+        {
+            c.set_tls12_session(&name, tls12_session(2));
+            assert!(c.tls12_session(&name).is_none());
+        }
     }
 
     #[test]
-    fn test_noclientsessionstorage_denies_gets() {
-        let c = NoClientSessionStorage {};
-        c.put(vec![0x01], vec![0x02]);
-        assert_eq!(c.get(&[]), None);
-        assert_eq!(c.get(&[0x01]), None);
-        assert_eq!(c.get(&[0x02]), None);
+    fn test_clientsessionmemorycache_persists_kx_hint() {
+        let c = ClientSessionMemoryCache::new(4);
+        let name = server_name("example.com");
+        assert_eq!(c.kx_hint(&name), None);
+
+        c.set_kx_hint(&name, NamedGroup::X25519);
+        assert_eq!(c.kx_hint(&name), Some(NamedGroup::X25519));
+        assert_eq!(c.kx_hint(&name), Some(NamedGroup::X25519));
+
+        c.set_kx_hint(&name, NamedGroup::secp384r1);
+        assert_eq!(c.kx_hint(&name), Some(NamedGroup::secp384r1));
+        assert_eq!(c.kx_hint(&server_name("example.org")), None);
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_clientsessionmemorycache_keeps_tls12_session() {
+        let c = ClientSessionMemoryCache::new(4);
+        let name = server_name("example.com");
+
+        c.set_tls12_session(&name, tls12_session(1));
+        assert_eq!(c.tls12_session(&name).unwrap().ticket(), &[1]);
+        assert_eq!(c.tls12_session(&name).unwrap().ticket(), &[1]);
+
+        c.set_tls12_session(&name, tls12_session(2));
+        assert_eq!(c.tls12_session(&name).unwrap().ticket(), &[2]);
+
+        // TLS1.2 sessions and TLS1.3 tickets are kept apart.
+        assert!(c.take_tls13_ticket(&name).is_none());
+        c.insert_tls13_ticket(&name, tls13_ticket(3));
+        assert_eq!(c.tls12_session(&name).unwrap().ticket(), &[2]);
     }
 
     #[test]
-    fn test_clientsessionmemorycache_accepts_put() {
+    fn test_clientsessionmemorycache_takes_each_tls13_ticket_once() {
         let c = ClientSessionMemoryCache::new(4);
-        assert!(c.put(vec![0x01], vec![0x02]));
+        let name = server_name("example.com");
+
+        c.insert_tls13_ticket(&name, tls13_ticket(1));
+        c.insert_tls13_ticket(&name, tls13_ticket(2));
+        c.insert_tls13_ticket(&server_name("example.org"), tls13_ticket(3));
+
+        assert_eq!(
+            c.take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[2]
+        );
+        assert_eq!(
+            c.take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[1]
+        );
+        assert!(c.take_tls13_ticket(&name).is_none());
     }
 
     #[test]
-    fn test_clientsessionmemorycache_persists_put() {
+    fn test_clientsessionmemorycache_skips_expired_tls13_tickets() {
         let c = ClientSessionMemoryCache::new(4);
-        assert!(c.put(vec![0x01], vec![0x02]));
-        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
-        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        let name = server_name("example.com");
+
+        let mut expired = tls13_ticket(1);
+        expired.common.rewind_epoch(3601);
+        c.insert_tls13_ticket(&name, tls13_ticket(2));
+        c.insert_tls13_ticket(&name, expired);
+
+        assert_eq!(
+            c.take_tls13_ticket(&name)
+                .unwrap()
+                .ticket(),
+            &[2]
+        );
+        assert!(c.take_tls13_ticket(&name).is_none());
     }
 
     #[test]
-    fn test_clientsessionmemorycache_overwrites_put() {
+    fn test_clientsessionmemorycache_limits_tls13_tickets_per_server() {
         let c = ClientSessionMemoryCache::new(4);
-        assert!(c.put(vec![0x01], vec![0x02]));
-        assert!(c.put(vec![0x01], vec![0x04]));
-        assert_eq!(c.get(&[0x01]), Some(vec![0x04]));
+        let name = server_name("example.com");
+
+        for i in 0..10 {
+            c.insert_tls13_ticket(&name, tls13_ticket(i));
+        }
+
+        for i in (2..10).rev() {
+            assert_eq!(
+                c.take_tls13_ticket(&name)
+                    .unwrap()
+                    .ticket(),
+                &[i]
+            );
+        }
+        assert!(c.take_tls13_ticket(&name).is_none());
     }
 
     #[test]
     fn test_clientsessionmemorycache_drops_to_maintain_size_invariant() {
         let c = ClientSessionMemoryCache::new(2);
-        assert!(c.put(vec![0x01], vec![0x02]));
-        assert!(c.put(vec![0x03], vec![0x04]));
-        assert!(c.put(vec![0x05], vec![0x06]));
-        assert!(c.put(vec![0x07], vec![0x08]));
-        assert!(c.put(vec![0x09], vec![0x0a]));
-
-        let count = c.get(&[0x01]).iter().count()
-            + c.get(&[0x03]).iter().count()
-            + c.get(&[0x05]).iter().count()
-            + c.get(&[0x07]).iter().count()
-            + c.get(&[0x09]).iter().count();
+        let names = ["a.com", "b.com", "c.com", "d.com", "e.com"];
+
+        for name in &names {
+            c.set_kx_hint(&server_name(name), NamedGroup::X25519);
+        }
+
+        let count = names
+            .iter()
+            .filter(|name| c.kx_hint(&server_name(name)).is_some())
+            .count();
 
         assert!(count < 5);
     }
@@ -2,19 +2,17 @@
 use crate::bs_debug;
 use crate::check::inappropriate_handshake_message;
 use crate::conn::{CommonState, ConnectionRandoms, State};
+use crate::crypto::{self, ActiveKeyExchange};
 use crate::enums::{CipherSuite, ProtocolVersion};
 use crate::error::Error;
 use crate::hash_hs::HandshakeHashBuffer;
-use crate::kx;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
 use crate::msgs::base::Payload;
-#[cfg(feature = "quic")]
-use crate::msgs::base::PayloadU16;
-use crate::msgs::codec::{Codec, Reader};
-use crate::msgs::enums::{AlertDescription, Compression, ContentType};
-use crate::msgs::enums::{ECPointFormat, PSKKeyExchangeMode};
+use crate::msgs::enums::ECPointFormat;
+use crate::msgs::enums::{AlertDescription, CertificateType, Compression, ContentType};
 use crate::msgs::enums::{ExtensionType, HandshakeType};
+use crate::msgs::handshake::EncryptedClientHello;
 use crate::msgs::handshake::{CertificateStatusRequest, ClientSessionTicket, SCTList};
 use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
 use crate::msgs::handshake::{ClientHelloPayload, HandshakeMessagePayload, HandshakePayload};
@@ -26,12 +24,14 @@ use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::ticketer::TimeBase;
 use crate::tls13::key_schedule::KeyScheduleEarly;
+use crate::verify;
 use crate::SupportedCipherSuite;
 
 #[cfg(feature = "tls12")]
 use super::tls12;
 use crate::client::client_conn::ClientConnectionData;
 use crate::client::common::ClientHelloDetails;
+use crate::client::ech::{EchState, EchStatus};
 use crate::client::{tls13, ClientConfig, ServerName};
 
 use std::sync::Arc;
@@ -45,39 +45,54 @@ fn find_session(
     config: &ClientConfig,
     #[cfg(feature = "quic")] cx: &mut ClientContext<'_>,
 ) -> Option<persist::Retrieved<persist::ClientSessionValue>> {
-    let key = persist::ClientSessionKey::session_for_server_name(server_name);
-    let key_buf = key.get_encoding();
-
-    let value = config
-        .session_storage
-        .get(&key_buf)
-        .or_else(|| {
-            debug!("No cached session for {:?}", server_name);
-            None
-        })?;
-
-    #[allow(unused_mut)]
-    let mut reader = Reader::init(&value[2..]);
-    #[allow(clippy::bind_instead_of_map)] // https://github.com/rust-lang/rust-clippy/issues/8082
-    CipherSuite::read_bytes(&value[..2])
-        .and_then(|suite| {
-            persist::ClientSessionValue::read(&mut reader, suite, &config.cipher_suites)
-        })
-        .and_then(|resuming| {
-            let retrieved = persist::Retrieved::new(resuming, TimeBase::now().ok()?);
-            match retrieved.has_expired() {
-                false => Some(retrieved),
-                true => None,
-            }
-        })
-        .and_then(|resuming| {
-            #[cfg(feature = "quic")]
-            if cx.common.is_quic() {
-                let params = PayloadU16::read(&mut reader)?;
-                cx.common.quic.params = Some(params.0);
-            }
-            Some(resuming)
-        })
+    // Each TLS1.3 ticket is used at most once, so prefer those: a
+    // TLS1.2 session remains available for the next connection.
+    let found = if config.supports_version(ProtocolVersion::TLSv1_3) {
+        config
+            .session_storage
+            .take_tls13_ticket(server_name)
+            .map(persist::ClientSessionValue::Tls13)
+    } else {
+        None
+    };
+
+    #[cfg(feature = "tls12")]
+    let found = found.or_else(|| {
+        if !config.supports_version(ProtocolVersion::TLSv1_2) {
+            return None;
+        }
+        config
+            .session_storage
+            .tls12_session(server_name)
+            .map(persist::ClientSessionValue::Tls12)
+    });
+
+    let found = found.or_else(|| {
+        debug!("No cached session for {:?}", server_name);
+        None
+    })?;
+
+    if !config
+        .cipher_suites
+        .iter()
+        .any(|suite| suite.suite() == found.suite())
+    {
+        debug!("Cached session uses a cipher suite which is not enabled");
+        return None;
+    }
+
+    let retrieved = persist::Retrieved::new(found, TimeBase::now().ok()?);
+    if retrieved.has_expired() {
+        debug!("Cached session for {:?} has expired", server_name);
+        return None;
+    }
+
+    #[cfg(feature = "quic")]
+    if cx.common.is_quic() {
+        cx.common.quic.params = Some(retrieved.tls13()?.quic_params());
+    }
+
+    Some(retrieved)
 }
 
 pub(super) fn start_handshake(
@@ -87,15 +102,27 @@ pub(super) fn start_handshake(
     cx: &mut ClientContext<'_>,
 ) -> NextStateOrError {
     let mut transcript_buffer = HandshakeHashBuffer::new();
-    if config
+    let client_auth_enabled = config
         .client_auth_cert_resolver
-        .has_certs()
-    {
+        .has_certs();
+    if client_auth_enabled {
         transcript_buffer.set_client_auth_enabled();
     }
 
     let support_tls13 = config.supports_version(ProtocolVersion::TLSv1_3);
 
+    let ech_state = match (&config.ech_config, support_tls13) {
+        (Some(ech_config), true) => {
+            cx.data.ech_status = EchStatus::Offered;
+            Some(EchState::new(
+                config.provider,
+                ech_config,
+                client_auth_enabled,
+            )?)
+        }
+        _ => None,
+    };
+
     let mut session_id: Option<SessionID> = None;
     let mut resuming_session = find_session(
         &server_name,
@@ -117,7 +144,7 @@ pub(super) fn start_handshake(
             // we're  doing an abbreviated handshake.  See section 3.4 in
             // RFC5077.
             if !inner.ticket().is_empty() {
-                inner.session_id = SessionID::random()?;
+                inner.session_id = SessionID::random(config.provider)?;
             }
             session_id = Some(inner.session_id);
         }
@@ -129,15 +156,16 @@ pub(super) fn start_handshake(
 
     // https://tools.ietf.org/html/rfc8446#appendix-D.4
     // https://tools.ietf.org/html/draft-ietf-quic-tls-34#section-8.4
-    if session_id.is_none() && !cx.common.is_quic() {
-        session_id = Some(SessionID::random()?);
+    // https://www.rfc-editor.org/rfc/rfc9147#section-5.3
+    if session_id.is_none() && !cx.common.is_quic() && !cx.common.is_dtls() {
+        session_id = Some(SessionID::random(config.provider)?);
     }
 
-    let random = Random::new()?;
+    let random = Random::new(config.provider)?;
     let hello_details = ClientHelloDetails::new();
     let sent_tls13_fake_ccs = false;
     let may_send_sct_list = config.verifier.request_scts();
-    Ok(emit_client_hello_for_retry(
+    emit_client_hello_for_retry(
         config,
         cx,
         resuming_session,
@@ -153,7 +181,8 @@ pub(super) fn start_handshake(
         extra_exts,
         may_send_sct_list,
         None,
-    ))
+        ech_state,
+    )
 }
 
 struct ExpectServerHello {
@@ -165,10 +194,11 @@ struct ExpectServerHello {
     transcript_buffer: HandshakeHashBuffer,
     early_key_schedule: Option<KeyScheduleEarly>,
     hello: ClientHelloDetails,
-    offered_key_share: Option<kx::KeyExchange>,
+    offered_key_share: Option<Box<dyn ActiveKeyExchange>>,
     session_id: SessionID,
     sent_tls13_fake_ccs: bool,
     suite: Option<SupportedCipherSuite>,
+    ech_state: Option<EchState>,
 }
 
 struct ExpectServerHelloOrHelloRetryRequest {
@@ -188,11 +218,12 @@ fn emit_client_hello_for_retry(
     session_id: Option<SessionID>,
     retryreq: Option<&HelloRetryRequest>,
     server_name: ServerName,
-    key_share: Option<kx::KeyExchange>,
+    key_share: Option<Box<dyn ActiveKeyExchange>>,
     extra_exts: Vec<ClientExtension>,
     may_send_sct_list: bool,
     suite: Option<SupportedCipherSuite>,
-) -> NextState {
+    mut ech_state: Option<EchState>,
+) -> NextStateOrError {
     // Do we have a SessionID or ticket cached for this host?
     let (ticket, resume_version) = if let Some(resuming) = &resuming_session {
         match &resuming.value {
@@ -208,12 +239,17 @@ fn emit_client_hello_for_retry(
         (Vec::new(), ProtocolVersion::Unknown(0))
     };
 
-    let support_tls12 = config.supports_version(ProtocolVersion::TLSv1_2) && !cx.common.is_quic();
+    let support_tls12 = config.supports_version(ProtocolVersion::TLSv1_2)
+        && !cx.common.is_quic()
+        && !cx.common.is_dtls();
     let support_tls13 = config.supports_version(ProtocolVersion::TLSv1_3);
 
     let mut supported_versions = Vec::new();
     if support_tls13 {
-        supported_versions.push(ProtocolVersion::TLSv1_3);
+        supported_versions.push(
+            cx.common
+                .wire_version(ProtocolVersion::TLSv1_3),
+        );
     }
 
     if support_tls12 {
@@ -223,14 +259,24 @@ fn emit_client_hello_for_retry(
     // should be unreachable thanks to config builder
     assert!(!supported_versions.is_empty());
 
+    // The inner hello of ECH is TLS1.3-only; the outer one offers everything.
+    let offered_versions = match ech_state {
+        Some(_) => vec![ProtocolVersion::TLSv1_3],
+        None => supported_versions.clone(),
+    };
+
     let mut exts = vec![
-        ClientExtension::SupportedVersions(supported_versions),
+        ClientExtension::SupportedVersions(offered_versions),
         ClientExtension::ECPointFormats(ECPointFormatList::supported()),
         ClientExtension::NamedGroups(
             config
                 .kx_groups
                 .iter()
-                .map(|skxg| skxg.name)
+                .filter(|skxg| {
+                    (support_tls13 && skxg.usable_for_version(ProtocolVersion::TLSv1_3))
+                        || (support_tls12 && skxg.usable_for_version(ProtocolVersion::TLSv1_2))
+                })
+                .map(|skxg| skxg.name())
                 .collect(),
         ),
         ClientExtension::SignatureAlgorithms(
@@ -252,7 +298,7 @@ fn emit_client_hello_for_retry(
 
     if let Some(key_share) = &key_share {
         debug_assert!(support_tls13);
-        let key_share = KeyShareEntry::new(key_share.group(), key_share.pubkey.as_ref());
+        let key_share = KeyShareEntry::new(key_share.group(), key_share.pub_key());
         exts.push(ClientExtension::KeyShare(vec![key_share]));
     }
 
@@ -260,11 +306,16 @@ fn emit_client_hello_for_retry(
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
-    if support_tls13 && config.enable_tickets {
-        // We could support PSK_KE here too. Such connections don't
-        // have forward secrecy, and are similar to TLS1.2 resumption.
-        let psk_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE];
-        exts.push(ClientExtension::PresharedKeyModes(psk_modes));
+    let offer_psks = support_tls13 && !config.psk_kx_modes.is_empty();
+    let external_psks = match offer_psks {
+        true => tls13::usable_external_psks(&config, suite),
+        false => Vec::new(),
+    };
+
+    if offer_psks && (config.enable_tickets || !external_psks.is_empty()) {
+        exts.push(ClientExtension::PresharedKeyModes(
+            config.psk_kx_modes.clone(),
+        ));
     }
 
     if !config.alpn_protocols.is_empty() {
@@ -277,10 +328,73 @@ fn emit_client_hello_for_retry(
         )));
     }
 
+    if support_tls13 && !config.cert_decompressors.is_empty() {
+        exts.push(ClientExtension::CertificateCompressionAlgorithms(
+            config
+                .cert_decompressors
+                .iter()
+                .map(|dec| dec.algorithm())
+                .collect(),
+        ));
+    }
+
+    if support_tls13 && config.server_raw_key_verifier.is_some() {
+        exts.push(ClientExtension::ServerCertificateTypes(vec![
+            CertificateType::RawPublicKey,
+            CertificateType::X509,
+        ]));
+    }
+
+    if support_tls13
+        && config
+            .client_auth_cert_resolver
+            .uses_raw_public_keys()
+    {
+        exts.push(ClientExtension::ClientCertificateTypes(vec![
+            CertificateType::RawPublicKey,
+        ]));
+    }
+
+    if support_tls13 && config.send_certificate_authorities {
+        match config.verifier.root_hint_subjects() {
+            Some(subjects) if !subjects.is_empty() => {
+                exts.push(ClientExtension::AuthorityNames(subjects));
+            }
+            _ => {}
+        }
+    }
+
+    if support_tls13 && config.enable_delegated_credentials {
+        exts.push(ClientExtension::DelegatedCredential(
+            verify::RAW_KEY_SCHEMES.to_vec(),
+        ));
+    }
+
+    if support_tls13 && tls13::offers_post_handshake_auth(&config, cx.common) {
+        exts.push(ClientExtension::PostHandshakeAuth);
+    }
+
+    if !cx.common.is_quic() && !cx.common.is_dtls() {
+        if let Some(limit) = config.record_size_limit {
+            exts.push(ClientExtension::RecordSizeLimit(limit as u16));
+        }
+
+        if let Some(len) = config.max_fragment_length {
+            exts.push(ClientExtension::MaxFragmentLength(len));
+        }
+    }
+
     // Extra extensions must be placed before the PSK extension
     exts.extend(extra_exts.iter().cloned());
 
-    let fill_in_binder = if support_tls13
+    if ech_state.is_some() {
+        exts.push(ClientExtension::EncryptedClientHello(
+            EncryptedClientHello::Inner,
+        ));
+    }
+
+    let mut psk_offer = None;
+    let fill_in_binder = if offer_psks
         && config.enable_tickets
         && resume_version == ProtocolVersion::TLSv1_3
         && !ticket.is_empty()
@@ -298,14 +412,15 @@ fn emit_client_hello_for_retry(
                 _ => None,
             })
             .map(|resuming| {
-                tls13::prepare_resumption(
+                psk_offer = Some(tls13::prepare_resumption(
                     &config,
                     cx,
                     ticket,
                     &resuming,
                     &mut exts,
-                    retryreq.is_some(),
-                );
+                    // Early data is not offered alongside ECH.
+                    retryreq.is_some() || ech_state.is_some(),
+                ));
                 resuming
             })
     } else if config.enable_tickets {
@@ -322,16 +437,27 @@ fn emit_client_hello_for_retry(
         None
     };
 
+    // Finally, and only for TLS1.3, offer our ticket and external PSKs.
+    // This must go last.
+    if let Some(psk_offer) = tls13::prepare_external_psks(&external_psks, psk_offer) {
+        exts.push(ClientExtension::PresharedKey(psk_offer));
+    }
+
     // Note what extensions we sent.
     hello.sent_extensions = exts
         .iter()
         .map(ClientExtension::get_type)
         .collect();
+    hello.offered_external_psks = external_psks
+        .iter()
+        .map(|(psk, _)| Arc::clone(psk))
+        .collect();
 
     let session_id = session_id.unwrap_or_else(SessionID::empty);
     let mut cipher_suites: Vec<_> = config
         .cipher_suites
         .iter()
+        .filter(|cs| cs.usable_for_kx_groups(&config.kx_groups))
         .map(|cs| cs.suite())
         .collect();
     // We don't do renegotiation at all, in fact.
@@ -340,8 +466,13 @@ fn emit_client_hello_for_retry(
     let mut chp = HandshakeMessagePayload {
         typ: HandshakeType::ClientHello,
         payload: HandshakePayload::ClientHello(ClientHelloPayload {
-            client_version: ProtocolVersion::TLSv1_2,
-            random,
+            client_version: cx
+                .common
+                .wire_version(ProtocolVersion::TLSv1_2),
+            random: match &ech_state {
+                Some(ech) => ech.inner_random,
+                None => random,
+            },
             session_id,
             cipher_suites,
             compression_methods: vec![Compression::Null],
@@ -349,13 +480,39 @@ fn emit_client_hello_for_retry(
         }),
     };
 
-    let early_key_schedule = if let Some(resuming) = fill_in_binder {
-        let schedule = tls13::fill_in_psk_binder(&resuming, &transcript_buffer, &mut chp);
-        Some((resuming.suite(), schedule))
+    let early_key_schedule = if fill_in_binder.is_some() || !external_psks.is_empty() {
+        // With ECH, only the inner hello offers PSKs.
+        let binder_transcript = match &ech_state {
+            Some(ech) => &ech.inner_transcript,
+            None => &transcript_buffer,
+        };
+        let resuming = fill_in_binder.as_deref().copied();
+        let schedule = tls13::fill_in_psk_binders(
+            cx.common.protocol,
+            resuming,
+            &external_psks,
+            binder_transcript,
+            &mut chp,
+        );
+        resuming
+            .zip(schedule)
+            .map(|(resuming, schedule)| (resuming.suite(), schedule))
     } else {
         None
     };
 
+    if let (Some(ech), HandshakePayload::ClientHello(inner)) = (&mut ech_state, &chp.payload) {
+        let outer = ech.seal_outer(inner, random, supported_versions)?;
+        ech.inner_hello.sent_extensions = hello.sent_extensions;
+        ech.inner_hello.offered_external_psks = std::mem::take(&mut hello.offered_external_psks);
+        hello.sent_extensions = outer
+            .extensions
+            .iter()
+            .map(ClientExtension::get_type)
+            .collect();
+        chp.payload = HandshakePayload::ClientHello(outer);
+    }
+
     let ch = Message {
         // "This value MUST be set to 0x0303 for all records generated
         //  by a TLS 1.3 implementation other than an initial ClientHello
@@ -410,13 +567,14 @@ fn emit_client_hello_for_retry(
         session_id,
         sent_tls13_fake_ccs,
         suite,
+        ech_state,
     };
 
-    if support_tls13 && retryreq.is_none() {
+    Ok(if support_tls13 && retryreq.is_none() {
         Box::new(ExpectServerHelloOrHelloRetryRequest { next, extra_exts })
     } else {
         Box::new(next)
-    }
+    })
 }
 
 pub(super) fn process_alpn_protocol(
@@ -459,6 +617,33 @@ pub(super) fn process_alpn_protocol(
     Ok(())
 }
 
+/// Apply the server's answer to our `record_size_limit` or
+/// `max_fragment_length` offer.
+pub(super) fn process_fragment_limits(
+    common: &mut CommonState,
+    config: &ClientConfig,
+    exts: &impl HasServerExtensions,
+) -> Result<(), Error> {
+    match (exts.get_record_size_limit(), exts.get_max_fragment_length()) {
+        // RFC8449: "A client MUST treat receipt of both max_fragment_length and
+        // record_size_limit as a fatal error"
+        (Some(_), Some(_)) => {
+            Err(common.illegal_param("server sent both record_size_limit and max_fragment_length"))
+        }
+        (Some(limit), None) => common
+            .negotiate_record_size_limit(limit)
+            .map(|_| ()),
+        (None, Some(len)) => match len.fragment_len() {
+            Some(max_fragment_len) if Some(len) == config.max_fragment_length => {
+                common.apply_max_fragment_length(max_fragment_len);
+                Ok(())
+            }
+            _ => Err(common.illegal_param("server varied max_fragment_length")),
+        },
+        (None, None) => Ok(()),
+    }
+}
+
 pub(super) fn sct_list_is_invalid(scts: &SCTList) -> bool {
     scts.is_empty() || scts.iter().any(|sct| sct.0.is_empty())
 }
@@ -472,17 +657,20 @@ impl State<ClientConnectionData> for ExpectServerHello {
         use crate::ProtocolVersion::{TLSv1_2, TLSv1_3};
         let tls13_supported = self.config.supports_version(TLSv1_3);
 
-        let server_version = if server_hello.legacy_version == TLSv1_2 {
+        let server_version = if server_hello.legacy_version == cx.common.wire_version(TLSv1_2) {
             server_hello
                 .get_supported_versions()
                 .unwrap_or(server_hello.legacy_version)
         } else {
             server_hello.legacy_version
         };
+        let server_version = cx
+            .common
+            .version_from_wire(server_version);
 
         let version = match server_version {
             TLSv1_3 if tls13_supported => TLSv1_3,
-            TLSv1_2 if self.config.supports_version(TLSv1_2) => {
+            TLSv1_2 if self.config.supports_version(TLSv1_2) && !cx.common.is_dtls() => {
                 if cx.data.early_data.is_enabled() && cx.common.early_traffic {
                     // The client must fail with a dedicated error code if the server
                     // responds with TLS 1.2 when offering 0-RTT.
@@ -527,6 +715,44 @@ impl State<ClientConnectionData> for ExpectServerHello {
             ));
         }
 
+        // Work out whether the server used our inner or outer hello: this decides
+        // which hello the rest of the handshake is checked against.
+        if let Some(ech) = self.ech_state.take() {
+            let accepted = match (
+                version,
+                self.config
+                    .find_cipher_suite(server_hello.cipher_suite),
+            ) {
+                (TLSv1_3, Some(SupportedCipherSuite::Tls13(suite))) => {
+                    ech.confirm_server_hello(suite, &m)
+                }
+                _ => false,
+            };
+
+            if matches!(ech.hrr_accepted(), Some(hrr_accepted) if hrr_accepted != accepted) {
+                return Err(cx
+                    .common
+                    .illegal_param("server changed ECH acceptance after hrr"));
+            }
+
+            if accepted {
+                debug!("ECH accepted");
+                cx.data.ech_status = EchStatus::Accepted;
+                self.transcript_buffer = ech.inner_transcript;
+                self.random = ech.inner_random;
+                self.hello = ech.inner_hello;
+            } else {
+                debug!(
+                    "ECH rejected; authenticating server as {:?}",
+                    ech.public_name
+                );
+                cx.data.ech_status = EchStatus::Rejected;
+                self.server_name = ech.public_name;
+                self.resuming_session = None;
+                self.early_key_schedule = None;
+            }
+        }
+
         let allowed_unsolicited = [ExtensionType::RenegotiationInfo];
         if self
             .hello
@@ -541,9 +767,10 @@ impl State<ClientConnectionData> for ExpectServerHello {
 
         cx.common.negotiated_version = Some(version);
 
-        // Extract ALPN protocol
+        // Extract ALPN protocol, and apply any record size limit
         if !cx.common.is_tls13() {
             process_alpn_protocol(cx.common, &self.config, server_hello.get_alpn_protocol())?;
+            process_fragment_limits(cx.common, &self.config, server_hello)?;
         }
 
         // If ECPointFormats extension is supplied by the server, it must contain
@@ -710,7 +937,10 @@ impl ExpectServerHelloOrHelloRetryRequest {
         }
 
         // Or asks us to talk a protocol we didn't offer, or doesn't support HRR at all.
-        match hrr.get_supported_versions() {
+        match hrr
+            .get_supported_versions()
+            .map(|version| cx.common.version_from_wire(version))
+        {
             Some(ProtocolVersion::TLSv1_3) => {
                 cx.common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
             }
@@ -738,6 +968,20 @@ impl ExpectServerHelloOrHelloRetryRequest {
         // HRR selects the ciphersuite.
         cx.common.suite = Some(cs);
 
+        // Only a server we offered ECH to may answer it.
+        let mut ech_state = self.next.ech_state;
+        match &mut ech_state {
+            Some(ech) => ech.process_hrr(cs, hrr, &m),
+            None if hrr.get_ech_confirmation().is_some() => {
+                cx.common
+                    .send_fatal_alert(AlertDescription::UnsupportedExtension);
+                return Err(Error::PeerMisbehavedError(
+                    "server sent unsolicited ECH extension in hrr".to_string(),
+                ));
+            }
+            None => {}
+        }
+
         // This is the draft19 change where the transcript became a tree
         let transcript = self
             .next
@@ -758,17 +1002,17 @@ impl ExpectServerHelloOrHelloRetryRequest {
 
         let key_share = match req_group {
             Some(group) if group != offered_key_share.group() => {
-                let group = kx::KeyExchange::choose(group, &self.next.config.kx_groups)
-                    .ok_or_else(|| {
+                let group =
+                    crypto::find_kx_group(group, &self.next.config.kx_groups).ok_or_else(|| {
                         cx.common
                             .illegal_param("server requested hrr with bad group")
                     })?;
-                kx::KeyExchange::start(group).ok_or(Error::FailedToGetRandomBytes)?
+                group.start()?
             }
             _ => offered_key_share,
         };
 
-        Ok(emit_client_hello_for_retry(
+        emit_client_hello_for_retry(
             self.next.config,
             cx,
             self.next.resuming_session,
@@ -784,7 +1028,8 @@ impl ExpectServerHelloOrHelloRetryRequest {
             self.extra_exts,
             may_send_sct_list,
             Some(cs),
-        ))
+            ech_state,
+        )
     }
 }
 
@@ -826,6 +1071,9 @@ pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Err
         Error::PeerMisbehavedError(_) => {
             common.send_fatal_alert(AlertDescription::IllegalParameter);
         }
+        Error::CertificateRevoked => {
+            common.send_fatal_alert(AlertDescription::CertificateRevoked);
+        }
         _ => {
             common.send_fatal_alert(AlertDescription::BadCertificate);
         }
@@ -833,3 +1081,13 @@ pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Err
 
     err
 }
+
+/// Abort a handshake in which the server rejected ECH.  This must only be
+/// done once the server has been authenticated.
+pub(super) fn abort_ech_rejected(cx: &mut ClientContext<'_>) -> Error {
+    cx.common
+        .send_fatal_alert(AlertDescription::EncryptedClientHelloRequired);
+    Error::EncryptedClientHelloRejected {
+        retry_configs: cx.data.ech_retry_configs.take(),
+    }
+}
@@ -1,18 +1,20 @@
 use crate::check::{inappropriate_handshake_message, inappropriate_message};
-use crate::conn::{CommonState, ConnectionRandoms, Side, State};
+use crate::conn::{CommonState, ConnectionRandoms, ExpectCertVerification, Side, State};
+use crate::crypto::{FfdheGroup, SupportedKxGroup};
 use crate::enums::ProtocolVersion;
 use crate::error::Error;
 use crate::hash_hs::HandshakeHash;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::base::{Payload, PayloadU8};
+use crate::msgs::base::{Payload, PayloadU16, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::AlertDescription;
-use crate::msgs::enums::{ContentType, HandshakeType};
+use crate::msgs::enums::{CertificateType, ContentType, HandshakeType};
 use crate::msgs::handshake::{
     CertificatePayload, DecomposedSignatureScheme, DigitallySignedStruct, HandshakeMessagePayload,
-    HandshakePayload, NewSessionTicketPayload, SCTList, ServerECDHParams, SessionID,
+    HandshakePayload, KeyExchangeAlgorithm, NewSessionTicketPayload, SCTList, ServerDHParams,
+    ServerECDHParams, ServerKeyExchangePayload, SessionID,
 };
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
@@ -22,15 +24,15 @@ use crate::suites::PartiallyExtractedSecrets;
 use crate::suites::SupportedCipherSuite;
 use crate::ticketer::TimeBase;
 use crate::tls12::{self, ConnectionSecrets, Tls12CipherSuite};
-use crate::{kx, verify};
+use crate::{crypto, verify};
 
 use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
 use crate::client::common::ClientAuthDetails;
 use crate::client::common::ServerCertDetails;
+use crate::client::ech::EchStatus;
 use crate::client::{hs, ClientConfig, ServerName};
 
-use ring::agreement::PublicKey;
 use ring::constant_time;
 
 use std::sync::Arc;
@@ -208,11 +210,7 @@ struct ExpectCertificate {
 }
 
 impl State<ClientConnectionData> for ExpectCertificate {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
         let server_cert_chain = require_handshake_msg_move!(
             m,
@@ -220,8 +218,11 @@ impl State<ClientConnectionData> for ExpectCertificate {
             HandshakePayload::Certificate
         )?;
 
-        if self.may_send_cert_status {
-            Ok(Box::new(ExpectCertificateStatusOrServerKx {
+        let deferred = (self.config.defer_cert_verification && !server_cert_chain.is_empty())
+            .then(|| server_cert_chain.clone());
+
+        let next: Box<dyn State<ClientConnectionData>> = if self.may_send_cert_status {
+            Box::new(ExpectCertificateStatusOrServerKx {
                 config: self.config,
                 resuming_session: self.resuming_session,
                 session_id: self.session_id,
@@ -233,12 +234,12 @@ impl State<ClientConnectionData> for ExpectCertificate {
                 server_cert_sct_list: self.server_cert_sct_list,
                 server_cert_chain,
                 must_issue_new_ticket: self.must_issue_new_ticket,
-            }))
+            })
         } else {
             let server_cert =
                 ServerCertDetails::new(server_cert_chain, vec![], self.server_cert_sct_list);
 
-            Ok(Box::new(ExpectServerKx {
+            Box::new(ExpectServerKx {
                 config: self.config,
                 resuming_session: self.resuming_session,
                 session_id: self.session_id,
@@ -249,8 +250,15 @@ impl State<ClientConnectionData> for ExpectCertificate {
                 suite: self.suite,
                 server_cert,
                 must_issue_new_ticket: self.must_issue_new_ticket,
-            }))
-        }
+            })
+        };
+
+        Ok(match deferred {
+            Some(cert_chain) => {
+                ExpectCertVerification::new(cx.common, cert_chain, next, hs::send_cert_error_alert)
+            }
+            None => next,
+        })
     }
 }
 
@@ -404,23 +412,29 @@ impl State<ClientConnectionData> for ExpectServerKx {
         )?;
         self.transcript.add_message(&m);
 
-        let ecdhe = opaque_kx
+        let decode_error = |common: &mut CommonState| {
+            common.send_fatal_alert(AlertDescription::DecodeError);
+            Error::CorruptMessagePayload(ContentType::Handshake)
+        };
+        let skx = opaque_kx
             .unwrap_given_kxa(&self.suite.kx)
-            .ok_or_else(|| {
-                cx.common
-                    .send_fatal_alert(AlertDescription::DecodeError);
-                Error::CorruptMessagePayload(ContentType::Handshake)
-            })?;
+            .ok_or_else(|| decode_error(cx.common))?;
 
         // Save the signature and signed parameters for later verification.
         let mut kx_params = Vec::new();
-        ecdhe.params.encode(&mut kx_params);
-        let server_kx = ServerKxDetails::new(kx_params, ecdhe.dss);
-
-        #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
-        {
-            debug!("ECDHE curve is {:?}", ecdhe.params.curve_params);
-        }
+        let server_kx = match skx {
+            ServerKeyExchangePayload::ECDHE(ecdhe) => {
+                debug!("ECDHE curve is {:?}", ecdhe.params.curve_params);
+                ecdhe.params.encode(&mut kx_params);
+                ServerKxDetails::new(kx_params, ecdhe.dss)
+            }
+            ServerKeyExchangePayload::DHE(dhe) => {
+                debug!("DHE prime is {} bytes", dhe.params.dh_p.0.len());
+                dhe.params.encode(&mut kx_params);
+                ServerKxDetails::new(kx_params, dhe.dss)
+            }
+            ServerKeyExchangePayload::Unknown(_) => return Err(decode_error(cx.common)),
+        };
 
         Ok(Box::new(ExpectServerDoneOrCertReq {
             config: self.config,
@@ -455,10 +469,17 @@ fn emit_certificate(
     common.send_msg(cert, false);
 }
 
-fn emit_clientkx(transcript: &mut HandshakeHash, common: &mut CommonState, pubkey: &PublicKey) {
+fn emit_clientkx(
+    transcript: &mut HandshakeHash,
+    common: &mut CommonState,
+    kxa: KeyExchangeAlgorithm,
+    pubkey: &[u8],
+) {
     let mut buf = Vec::new();
-    let ecpoint = PayloadU8::new(Vec::from(pubkey.as_ref()));
-    ecpoint.encode(&mut buf);
+    match kxa {
+        KeyExchangeAlgorithm::DHE => PayloadU16::new(Vec::from(pubkey)).encode(&mut buf),
+        _ => PayloadU8::new(Vec::from(pubkey)).encode(&mut buf),
+    }
     let pubkey = Payload::new(buf);
 
     let ckx = Message {
@@ -529,6 +550,29 @@ fn emit_finished(
     common.send_msg(f, true);
 }
 
+/// Find the group among `groups` whose parameters exactly match `params`.
+fn find_ffdhe_group(
+    params: &ServerDHParams,
+    groups: &[&'static dyn SupportedKxGroup],
+) -> Option<&'static dyn SupportedKxGroup> {
+    let received = FfdheGroup {
+        p: strip_leading_zeros(&params.dh_p.0),
+        g: strip_leading_zeros(&params.dh_g.0),
+    };
+    groups
+        .iter()
+        .find(|group| group.ffdhe_group() == Some(received))
+        .copied()
+}
+
+fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let first = bytes
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(bytes.len());
+    &bytes[first..]
+}
+
 struct ServerKxDetails {
     kx_params: Vec<u8>,
     kx_sig: DigitallySignedStruct,
@@ -623,11 +667,7 @@ struct ExpectCertificateRequest {
 }
 
 impl State<ClientConnectionData> for ExpectCertificateRequest {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         let certreq = require_handshake_msg!(
             m,
             HandshakeType::CertificateRequest,
@@ -643,14 +683,22 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
         // is entirely duplicated in certreq.sigschemes.
 
         const NO_CONTEXT: Option<Vec<u8>> = None; // TLS 1.2 doesn't use a context.
-        let client_auth = ClientAuthDetails::resolve(
-            self.config
-                .client_auth_cert_resolver
-                .as_ref(),
-            Some(&certreq.canames),
-            &certreq.sigschemes,
-            NO_CONTEXT,
-        );
+        let client_auth = match cx.data.ech_status {
+            // We are talking to the public name, not the server we meant to
+            // reach: sending our certificate would reveal who we are.
+            EchStatus::Rejected => ClientAuthDetails::Empty {
+                auth_context_tls13: NO_CONTEXT,
+            },
+            _ => ClientAuthDetails::resolve(
+                self.config
+                    .client_auth_cert_resolver
+                    .as_ref(),
+                Some(&certreq.canames),
+                &certreq.sigschemes,
+                CertificateType::X509,
+                NO_CONTEXT,
+            ),
+        };
 
         Ok(Box::new(ExpectServerDone {
             config: self.config,
@@ -732,19 +780,22 @@ impl State<ClientConnectionData> for ExpectServerDone {
             .cert_chain
             .split_first()
             .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = st
-            .config
-            .verifier
-            .verify_server_cert(
-                end_entity,
-                intermediates,
-                &st.server_name,
-                &mut st.server_cert.scts(),
-                &st.server_cert.ocsp_response,
-                now,
-            )
-            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        let cert_verified = if st.config.defer_cert_verification {
+            // The application accepted the chain when we received it.
+            verify::ServerCertVerified::assertion()
+        } else {
+            st.config
+                .verifier
+                .verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    &st.server_name,
+                    &mut st.server_cert.scts(),
+                    &st.server_cert.ocsp_response,
+                    std::time::SystemTime::now(),
+                )
+                .map_err(|err| hs::send_cert_error_alert(cx.common, err))?
+        };
 
         // 3.
         // Build up the contents of the signed message.
@@ -784,18 +835,45 @@ impl State<ClientConnectionData> for ExpectServerDone {
         }
 
         // 5a.
-        let ecdh_params =
-            tls12::decode_ecdh_params::<ServerECDHParams>(cx.common, &st.server_kx.kx_params)?;
-        let group =
-            kx::KeyExchange::choose(ecdh_params.curve_params.named_group, &st.config.kx_groups)
+        let (kx, peer_pub_key) = match suite.kx {
+            KeyExchangeAlgorithm::DHE => {
+                let dh_params =
+                    tls12::decode_kx_params::<ServerDHParams>(cx.common, &st.server_kx.kx_params)?;
+                // Only accept the standard groups we were configured with:
+                // this rules out small and maliciously-chosen primes.
+                let group =
+                    find_ffdhe_group(&dh_params, &st.config.kx_groups).ok_or_else(|| {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::InsufficientSecurity);
+                        Error::PeerIncompatibleError(
+                            "server chose a non-standard DHE group".to_string(),
+                        )
+                    })?;
+                (group.start()?, dh_params.dh_ys.0)
+            }
+            _ => {
+                let ecdh_params = tls12::decode_kx_params::<ServerECDHParams>(
+                    cx.common,
+                    &st.server_kx.kx_params,
+                )?;
+                let group = crypto::find_kx_group(
+                    ecdh_params.curve_params.named_group,
+                    &st.config.kx_groups,
+                )
+                .filter(|group| {
+                    group.usable_for_version(ProtocolVersion::TLSv1_2)
+                        && group.name().key_exchange_algorithm() == KeyExchangeAlgorithm::ECDHE
+                })
                 .ok_or_else(|| {
                     Error::PeerMisbehavedError("peer chose an unsupported group".to_string())
                 })?;
-        let kx = kx::KeyExchange::start(group).ok_or(Error::FailedToGetRandomBytes)?;
+                (group.start()?, ecdh_params.public.0)
+            }
+        };
 
         // 5b.
         let mut transcript = st.transcript;
-        emit_clientkx(&mut transcript, cx.common, &kx.pubkey);
+        emit_clientkx(&mut transcript, cx.common, suite.kx, kx.pub_key());
         // nb. EMS handshake hash only runs up to ClientKeyExchange.
         let ems_seed = st
             .using_ems
@@ -810,13 +888,8 @@ impl State<ClientConnectionData> for ExpectServerDone {
         emit_ccs(cx.common);
 
         // 5e. Now commit secrets.
-        let secrets = ConnectionSecrets::from_key_exchange(
-            kx,
-            &ecdh_params.public.0,
-            ems_seed,
-            st.randoms,
-            suite,
-        )?;
+        let secrets =
+            ConnectionSecrets::from_key_exchange(kx, &peer_pub_key, ems_seed, st.randoms, suite)?;
 
         st.config.key_log.log(
             "CLIENT_RANDOM",
@@ -1001,7 +1074,6 @@ impl ExpectFinished {
             }
         };
 
-        let key = persist::ClientSessionKey::session_for_server_name(&self.server_name);
         let value = persist::Tls12ClientSessionValue::new(
             self.secrets.suite(),
             self.session_id,
@@ -1016,16 +1088,10 @@ impl ExpectFinished {
             self.using_ems,
         );
 
-        let worked = self
-            .config
+        self.config
             .session_storage
-            .put(key.get_encoding(), value.get_encoding());
-
-        if worked {
-            debug!("Session saved");
-        } else {
-            debug!("Session not saved");
-        }
+            .set_tls12_session(&self.server_name, value);
+        debug!("Session saved");
     }
 }
 
@@ -1052,6 +1118,10 @@ impl State<ClientConnectionData> for ExpectFinished {
                 })
                 .map(|_| verify::FinishedMessageVerified::assertion())?;
 
+        if cx.data.ech_status == EchStatus::Rejected {
+            return Err(hs::abort_ech_rejected(cx));
+        }
+
         // Hash this message too.
         st.transcript.add_message(&m);
 
@@ -1,27 +1,30 @@
 use crate::check::inappropriate_handshake_message;
+use crate::compress::{self, CertCompressor};
 #[cfg(feature = "secret_extraction")]
 use crate::conn::Side;
-use crate::conn::{CommonState, ConnectionRandoms, State};
+use crate::conn::{CommonState, ConnectionRandoms, ExpectCertVerification, Protocol, State};
+use crate::crypto::{self, hmac, ActiveKeyExchange};
 use crate::enums::{ProtocolVersion, SignatureScheme};
 use crate::error::Error;
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
-use crate::kx;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
 use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::KeyUpdateRequest;
-use crate::msgs::enums::{AlertDescription, NamedGroup};
+use crate::msgs::enums::{AlertDescription, CertificateType, NamedGroup};
 use crate::msgs::enums::{ContentType, ExtensionType, HandshakeType};
+use crate::msgs::enums::{KeyUpdateRequest, PSKKeyExchangeMode};
+use crate::msgs::handshake::CertificateRequestPayloadTLS13;
 use crate::msgs::handshake::ClientExtension;
+use crate::msgs::handshake::DelegatedCredential;
 use crate::msgs::handshake::DigitallySignedStruct;
 use crate::msgs::handshake::EncryptedExtensions;
 use crate::msgs::handshake::NewSessionTicketPayloadTLS13;
 use crate::msgs::handshake::{CertificateEntry, CertificatePayloadTLS13};
 use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{HasServerExtensions, ServerHelloPayload};
-use crate::msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer};
+use crate::msgs::handshake::{PresharedKeyBinder, PresharedKeyIdentity, PresharedKeyOffer};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 #[cfg(feature = "secret_extraction")]
@@ -31,15 +34,14 @@ use crate::tls13::key_schedule::{
 };
 use crate::tls13::Tls13CipherSuite;
 use crate::verify;
-#[cfg(feature = "quic")]
-use crate::{conn::Protocol, msgs::base::PayloadU16};
-use crate::{sign, KeyLog};
+use crate::{sign, Certificate, ExternalPsk, KeyLog, SubjectPublicKeyInfo, SupportedCipherSuite};
 
 use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
 use crate::client::common::ServerCertDetails;
 use crate::client::common::{ClientAuthDetails, ClientHelloDetails};
-use crate::client::{hs, ClientConfig, ServerName, StoresClientSessions};
+use crate::client::ech::EchStatus;
+use crate::client::{hs, ClientConfig, ServerName};
 
 use crate::ticketer::TimeBase;
 use ring::constant_time;
@@ -74,75 +76,115 @@ pub(super) fn handle_server_hello(
     transcript: HandshakeHash,
     early_key_schedule: Option<KeyScheduleEarly>,
     hello: ClientHelloDetails,
-    our_key_share: kx::KeyExchange,
+    our_key_share: Box<dyn ActiveKeyExchange>,
     mut sent_tls13_fake_ccs: bool,
 ) -> hs::NextStateOrError {
     validate_server_hello(cx.common, server_hello)?;
 
-    let their_key_share = server_hello
-        .get_key_share()
-        .ok_or_else(|| {
+    let selected_psk = server_hello.get_psk_index();
+    let their_key_share = match server_hello.get_key_share() {
+        Some(their_key_share) => Some(their_key_share),
+        // In psk_ke mode the server does not send a key share.
+        None if selected_psk.is_some()
+            && config
+                .psk_kx_modes
+                .contains(&PSKKeyExchangeMode::PSK_KE) =>
+        {
+            None
+        }
+        None => {
             cx.common
                 .send_fatal_alert(AlertDescription::MissingExtension);
-            Error::PeerMisbehavedError("missing key share".to_string())
-        })?;
+            return Err(Error::PeerMisbehavedError("missing key share".to_string()));
+        }
+    };
 
-    if our_key_share.group() != their_key_share.group {
-        return Err(cx
-            .common
-            .illegal_param("wrong group for key share"));
+    if let Some(their_key_share) = their_key_share {
+        if our_key_share.group() != their_key_share.group {
+            return Err(cx
+                .common
+                .illegal_param("wrong group for key share"));
+        }
     }
 
-    let key_schedule_pre_handshake = if let (Some(selected_psk), Some(early_key_schedule)) =
-        (server_hello.get_psk_index(), early_key_schedule)
-    {
-        if let Some(ref resuming) = resuming_session {
-            let resuming_suite = match suite.can_resume_from(resuming.suite()) {
-                Some(resuming) => resuming,
-                None => {
+    let offered_ticket = early_key_schedule.is_some();
+    let mut using_external_psk = false;
+    let key_schedule_pre_handshake =
+        if let (Some(0), Some(early_key_schedule)) = (selected_psk, early_key_schedule) {
+            if let Some(ref resuming) = resuming_session {
+                let resuming_suite = match suite.can_resume_from(resuming.suite()) {
+                    Some(resuming) => resuming,
+                    None => {
+                        return Err(cx
+                            .common
+                            .illegal_param("server resuming incompatible suite"));
+                    }
+                };
+
+                // If the server varies the suite here, we will have encrypted early data with
+                // the wrong suite.
+                if cx.data.early_data.is_enabled() && resuming_suite != suite {
                     return Err(cx
                         .common
-                        .illegal_param("server resuming incompatible suite"));
+                        .illegal_param("server varied suite with early data"));
                 }
-            };
 
-            // If the server varies the suite here, we will have encrypted early data with
-            // the wrong suite.
-            if cx.data.early_data.is_enabled() && resuming_suite != suite {
-                return Err(cx
-                    .common
-                    .illegal_param("server varied suite with early data"));
+                debug!("Resuming using PSK");
+                // The key schedule has been initialized and set in fill_in_psk_binder()
+            } else {
+                return Err(Error::PeerMisbehavedError(
+                    "server selected unoffered psk".to_string(),
+                ));
             }
-
-            if selected_psk != 0 {
+            KeySchedulePreHandshake::from(early_key_schedule)
+        } else if let Some(selected_psk) = selected_psk {
+            // Our external PSKs follow the resumption ticket, if we offered one.
+            let psk = (selected_psk as usize)
+                .checked_sub(offered_ticket as usize)
+                .and_then(|i| hello.offered_external_psks.get(i))
+                .ok_or_else(|| {
+                    cx.common
+                        .illegal_param("server selected invalid psk")
+                })?;
+
+            if psk.hash_algorithm().algorithm() != suite.hash_algorithm().algorithm() {
                 return Err(cx
                     .common
-                    .illegal_param("server selected invalid psk"));
+                    .illegal_param("server selected psk with incompatible suite"));
             }
 
-            debug!("Resuming using PSK");
-            // The key schedule has been initialized and set in fill_in_psk_binder()
+            debug!("Using external PSK");
+            // Early data is only ever sent under a resumption ticket.
+            cx.data.early_data.rejected();
+            cx.common.early_traffic = false;
+            resuming_session.take();
+            using_external_psk = true;
+            KeySchedulePreHandshake::from(KeyScheduleEarly::new(
+                cx.common.protocol,
+                suite,
+                psk.secret(),
+            ))
         } else {
-            return Err(Error::PeerMisbehavedError(
-                "server selected unoffered psk".to_string(),
-            ));
-        }
-        KeySchedulePreHandshake::from(early_key_schedule)
-    } else {
-        debug!("Not resuming");
-        // Discard the early data key schedule.
-        cx.data.early_data.rejected();
-        cx.common.early_traffic = false;
-        resuming_session.take();
-        KeySchedulePreHandshake::new(suite)
-    };
+            debug!("Not resuming");
+            // Discard the early data key schedule.
+            cx.data.early_data.rejected();
+            cx.common.early_traffic = false;
+            resuming_session.take();
+            KeySchedulePreHandshake::new(cx.common.protocol, suite)
+        };
 
-    let key_schedule = our_key_share.complete(&their_key_share.payload.0, |secret| {
-        Ok(key_schedule_pre_handshake.into_handshake(secret))
-    })?;
+    let key_schedule = match their_key_share {
+        Some(their_key_share) => {
+            let shared_secret = our_key_share.complete(&their_key_share.payload.0)?;
+            let key_schedule =
+                key_schedule_pre_handshake.into_handshake(shared_secret.secret_bytes());
 
-    // Remember what KX group the server liked for next time.
-    save_kx_hint(&config, &server_name, their_key_share.group);
+            // Remember what KX group the server liked for next time.
+            save_kx_hint(&config, &server_name, their_key_share.group);
+            key_schedule
+        }
+        None => key_schedule_pre_handshake.into_handshake_without_kx(),
+    };
 
     // If we change keying when a subsequent handshake message is being joined,
     // the two halves will have different record layer protections.  Disallow this.
@@ -169,6 +211,7 @@ pub(super) fn handle_server_hello(
         transcript,
         key_schedule,
         hello,
+        using_external_psk,
     }))
 }
 
@@ -191,59 +234,132 @@ fn validate_server_hello(
 pub(super) fn initial_key_share(
     config: &ClientConfig,
     server_name: &ServerName,
-) -> Result<kx::KeyExchange, Error> {
-    let key = persist::ClientSessionKey::hint_for_server_name(server_name);
-    let key_buf = key.get_encoding();
-
-    let maybe_value = config.session_storage.get(&key_buf);
-
-    let group = maybe_value
-        .and_then(|enc| NamedGroup::read_bytes(&enc))
-        .and_then(|group| kx::KeyExchange::choose(group, &config.kx_groups))
+) -> Result<Box<dyn ActiveKeyExchange>, Error> {
+    let group = config
+        .session_storage
+        .kx_hint(server_name)
+        .and_then(|group| crypto::find_kx_group(group, &config.kx_groups))
         .unwrap_or_else(|| {
             config
                 .kx_groups
                 .first()
+                .copied()
                 .expect("No kx groups configured")
         });
 
-    kx::KeyExchange::start(group).ok_or(Error::FailedToGetRandomBytes)
+    Ok(group.start()?)
 }
 
 fn save_kx_hint(config: &ClientConfig, server_name: &ServerName, group: NamedGroup) {
-    let key = persist::ClientSessionKey::hint_for_server_name(server_name);
-
     config
         .session_storage
-        .put(key.get_encoding(), group.get_encoding());
+        .set_kx_hint(server_name, group);
 }
 
 /// This implements the horrifying TLS1.3 hack where PSK binders have a
 /// data dependency on the message they are contained within.
-pub(super) fn fill_in_psk_binder(
-    resuming: &persist::Tls13ClientSessionValue,
+///
+/// Binders are computed for the resumption ticket (if any) followed by
+/// each of `external_psks`, matching the order they were offered in.
+/// Returns the key schedule for resuming with the ticket.
+pub(super) fn fill_in_psk_binders(
+    protocol: Protocol,
+    resuming: Option<&persist::Tls13ClientSessionValue>,
+    external_psks: &[(Arc<ExternalPsk>, &'static Tls13CipherSuite)],
     transcript: &HandshakeHashBuffer,
     hmp: &mut HandshakeMessagePayload,
-) -> KeyScheduleEarly {
-    // We need to know the hash function of the suite we're trying to resume into.
-    let suite = resuming.suite();
-    let suite_hash = suite.hash_algorithm();
-
+) -> Option<KeyScheduleEarly> {
     // The binder is calculated over the clienthello, but doesn't include itself or its
     // length, or the length of its container.
     let binder_plaintext = hmp.get_encoding_for_binder_signing();
-    let handshake_hash = transcript.get_hash_given(suite_hash, &binder_plaintext);
+    let mut binders = Vec::new();
 
     // Run a fake key_schedule to simulate what the server will do if it chooses
-    // to resume.
-    let key_schedule = KeyScheduleEarly::new(suite, resuming.secret());
-    let real_binder = key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
+    // to resume.  We need to know the hash function of the suite we're trying
+    // to resume into.
+    let resumption_key_schedule = resuming.map(|resuming| {
+        let suite = resuming.suite();
+        let handshake_hash = transcript.get_hash_given(suite.hash_algorithm(), &binder_plaintext);
+        let key_schedule = KeyScheduleEarly::new(protocol, suite, resuming.secret());
+        binders.push(
+            key_schedule
+                .resumption_psk_binder_key_and_sign_verify_data(&handshake_hash)
+                .as_ref()
+                .to_vec(),
+        );
+        key_schedule
+    });
+
+    for (psk, suite) in external_psks {
+        let handshake_hash = transcript.get_hash_given(psk.hash_algorithm(), &binder_plaintext);
+        let key_schedule = KeyScheduleEarly::new(protocol, suite, psk.secret());
+        binders.push(
+            key_schedule
+                .external_psk_binder_key_and_sign_verify_data(&handshake_hash)
+                .as_ref()
+                .to_vec(),
+        );
+    }
 
     if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
-        ch.set_psk_binder(real_binder.as_ref());
+        ch.set_psk_binders(binders);
     };
 
-    key_schedule
+    resumption_key_schedule
+}
+
+/// Our external PSKs which can be offered, each with a TLS1.3 suite
+/// sharing its hash.  Once the server has chosen `suite` (in a
+/// HelloRetryRequest) only PSKs usable with it remain.
+pub(super) fn usable_external_psks(
+    config: &ClientConfig,
+    suite: Option<SupportedCipherSuite>,
+) -> Vec<(Arc<ExternalPsk>, &'static Tls13CipherSuite)> {
+    let suite = suite.and_then(|suite| suite.tls13());
+    config
+        .external_psks
+        .iter()
+        .filter_map(|psk| {
+            let psk_suite = match suite {
+                Some(suite) => suite,
+                None => config
+                    .cipher_suites
+                    .iter()
+                    .filter_map(|suite| suite.tls13())
+                    .find(|suite| {
+                        suite.hash_algorithm().algorithm() == psk.hash_algorithm().algorithm()
+                    })?,
+            };
+
+            (psk_suite.hash_algorithm().algorithm() == psk.hash_algorithm().algorithm())
+                .then(|| (Arc::clone(psk), psk_suite))
+        })
+        .collect()
+}
+
+/// Add identities and empty binders for `external_psks` to `offer`, after
+/// any resumption ticket it already contains.
+pub(super) fn prepare_external_psks(
+    external_psks: &[(Arc<ExternalPsk>, &'static Tls13CipherSuite)],
+    mut offer: Option<PresharedKeyOffer>,
+) -> Option<PresharedKeyOffer> {
+    for (psk, _) in external_psks {
+        // External PSKs have no age: obfuscated_ticket_age is zero.
+        let identity = PresharedKeyIdentity::new(psk.identity().to_vec(), 0);
+        let binder = vec![0u8; psk.hash_algorithm().output_len()];
+
+        match &mut offer {
+            Some(offer) => {
+                offer.identities.push(identity);
+                offer
+                    .binders
+                    .push(PresharedKeyBinder::new(binder));
+            }
+            None => offer = Some(PresharedKeyOffer::new(identity, binder)),
+        }
+    }
+
+    offer
 }
 
 pub(super) fn prepare_resumption(
@@ -253,35 +369,31 @@ pub(super) fn prepare_resumption(
     resuming_session: &persist::Retrieved<&persist::Tls13ClientSessionValue>,
     exts: &mut Vec<ClientExtension>,
     doing_retry: bool,
-) {
+) -> PresharedKeyOffer {
     let resuming_suite = resuming_session.suite();
     cx.common.suite = Some(resuming_suite.into());
     cx.data.resumption_ciphersuite = Some(resuming_suite.into());
     // The EarlyData extension MUST be supplied together with the
-    // PreSharedKey extension.
+    // PreSharedKey extension.  DTLS connections do not send early data.
     let max_early_data_size = resuming_session.max_early_data_size();
-    if config.enable_early_data && max_early_data_size > 0 && !doing_retry {
+    if config.enable_early_data && max_early_data_size > 0 && !doing_retry && !cx.common.is_dtls() {
         cx.data
             .early_data
             .enable(max_early_data_size as usize);
         exts.push(ClientExtension::EarlyData);
     }
 
-    // Finally, and only for TLS1.3 with a ticket resumption, include a binder
-    // for our ticket.  This must go last.
-    //
-    // Include an empty binder. It gets filled in below because it depends on
-    // the message it's contained in (!!!).
+    // Include an empty binder for our ticket. It gets filled in later because
+    // it depends on the message it's contained in (!!!).
     let obfuscated_ticket_age = resuming_session.obfuscated_ticket_age();
 
     let binder_len = resuming_suite
         .hash_algorithm()
-        .output_len;
+        .output_len();
     let binder = vec![0u8; binder_len];
 
     let psk_identity = PresharedKeyIdentity::new(ticket, obfuscated_ticket_age);
-    let psk_ext = PresharedKeyOffer::new(psk_identity, binder);
-    exts.push(ClientExtension::PresharedKey(psk_ext));
+    PresharedKeyOffer::new(psk_identity, binder)
 }
 
 pub(super) fn derive_early_traffic_secret(
@@ -310,7 +422,7 @@ pub(super) fn derive_early_traffic_secret(
 }
 
 pub(super) fn emit_fake_ccs(sent_tls13_fake_ccs: &mut bool, common: &mut CommonState) {
-    if common.is_quic() {
+    if common.is_quic() || common.is_dtls() {
         return;
     }
 
@@ -356,6 +468,32 @@ fn validate_encrypted_extensions(
     Ok(())
 }
 
+/// Find which kinds of certificate the server chose for itself and
+/// for us (see RFC7250).  Both default to X.509.
+fn negotiated_cert_types(
+    common: &mut CommonState,
+    exts: &EncryptedExtensions,
+) -> Result<(CertificateType, CertificateType), Error> {
+    // We know from `validate_encrypted_extensions` that we offered these
+    // extensions if they are present: for the server we offer both types,
+    // and for the client only raw public keys.
+    let server_cert_type = match exts.get_server_certificate_type() {
+        None | Some(CertificateType::X509) => CertificateType::X509,
+        Some(CertificateType::RawPublicKey) => CertificateType::RawPublicKey,
+        Some(_) => return Err(common.illegal_param("server chose unoffered certificate type")),
+    };
+
+    let client_cert_type = match exts.get_client_certificate_type() {
+        None => CertificateType::X509,
+        Some(CertificateType::RawPublicKey) => CertificateType::RawPublicKey,
+        Some(_) => {
+            return Err(common.illegal_param("server chose unoffered client certificate type"))
+        }
+    };
+
+    Ok((server_cert_type, client_cert_type))
+}
+
 struct ExpectEncryptedExtensions {
     config: Arc<ClientConfig>,
     resuming_session: Option<persist::Tls13ClientSessionValue>,
@@ -365,6 +503,7 @@ struct ExpectEncryptedExtensions {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     hello: ClientHelloDetails,
+    using_external_psk: bool,
 }
 
 impl State<ClientConnectionData> for ExpectEncryptedExtensions {
@@ -379,6 +518,14 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
 
         validate_encrypted_extensions(cx.common, &self.hello, exts)?;
         hs::process_alpn_protocol(cx.common, &self.config, exts.get_alpn_protocol())?;
+        hs::process_fragment_limits(cx.common, &self.config, exts)?;
+        let (server_cert_type, client_cert_type) = negotiated_cert_types(cx.common, exts)?;
+
+        if cx.data.ech_status == EchStatus::Rejected {
+            cx.data.ech_retry_configs = exts
+                .get_ech_retry_configs()
+                .map(Codec::get_encoding);
+        }
 
         #[cfg(feature = "quic")]
         {
@@ -430,14 +577,30 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 client_auth: None,
+                cert_compressor: None,
+                cert_verified,
+                sig_verified,
+            }))
+        } else if exts.early_data_extension_offered() {
+            let msg = "server sent early data extension without resumption".to_string();
+            Err(Error::PeerMisbehavedError(msg))
+        } else if self.using_external_psk {
+            // The server proved knowledge of the PSK in place of a certificate.
+            let cert_verified = verify::ServerCertVerified::assertion();
+            let sig_verified = verify::HandshakeSignatureValid::assertion();
+            Ok(Box::new(ExpectFinished {
+                config: self.config,
+                server_name: self.server_name,
+                randoms: self.randoms,
+                suite: self.suite,
+                transcript: self.transcript,
+                key_schedule: self.key_schedule,
+                client_auth: None,
+                cert_compressor: None,
                 cert_verified,
                 sig_verified,
             }))
         } else {
-            if exts.early_data_extension_offered() {
-                let msg = "server sent early data extension without resumption".to_string();
-                return Err(Error::PeerMisbehavedError(msg));
-            }
             Ok(Box::new(ExpectCertificateOrCertReq {
                 config: self.config,
                 server_name: self.server_name,
@@ -446,6 +609,8 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.hello.server_may_send_sct_list(),
+                server_cert_type,
+                client_cert_type,
             }))
         }
     }
@@ -459,6 +624,8 @@ struct ExpectCertificateOrCertReq {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    server_cert_type: CertificateType,
+    client_cert_type: CertificateType,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
@@ -471,6 +638,14 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                         ..
                     },
                 ..
+            }
+            | MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CompressedCertificate(..),
+                        ..
+                    },
+                ..
             } => Box::new(ExpectCertificate {
                 config: self.config,
                 server_name: self.server_name,
@@ -479,7 +654,9 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.may_send_sct_list,
+                server_cert_type: self.server_cert_type,
                 client_auth: None,
+                cert_compressor: None,
             })
             .handle(cx, m),
             MessagePayload::Handshake {
@@ -497,6 +674,8 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.may_send_sct_list,
+                server_cert_type: self.server_cert_type,
+                client_cert_type: self.client_cert_type,
             })
             .handle(cx, m),
             payload => Err(inappropriate_handshake_message(
@@ -504,6 +683,7 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 &[ContentType::Handshake],
                 &[
                     HandshakeType::Certificate,
+                    HandshakeType::CompressedCertificate,
                     HandshakeType::CertificateRequest,
                 ],
             )),
@@ -522,6 +702,8 @@ struct ExpectCertificateRequest {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    server_cert_type: CertificateType,
+    client_cert_type: CertificateType,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateRequest {
@@ -545,16 +727,7 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             return Err(Error::CorruptMessagePayload(ContentType::Handshake));
         }
 
-        let tls13_sign_schemes = sign::supported_sign_tls13();
-        let no_sigschemes = Vec::new();
-        let compat_sigschemes = certreq
-            .get_sigalgs_extension()
-            .unwrap_or(&no_sigschemes)
-            .iter()
-            .cloned()
-            .filter(|scheme| tls13_sign_schemes.contains(scheme))
-            .collect::<Vec<SignatureScheme>>();
-
+        let compat_sigschemes = compatible_sigschemes(certreq);
         if compat_sigschemes.is_empty() {
             cx.common
                 .send_fatal_alert(AlertDescription::HandshakeFailure);
@@ -569,9 +742,16 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
                 .as_ref(),
             certreq.get_authorities_extension(),
             &compat_sigschemes,
+            self.client_cert_type,
             Some(certreq.context.0.clone()),
         );
 
+        let cert_compressor = certreq
+            .get_certificate_compression_extension()
+            .and_then(|offered| {
+                compress::choose_compressor(&self.config.cert_compressors, offered)
+            });
+
         Ok(Box::new(ExpectCertificate {
             config: self.config,
             server_name: self.server_name,
@@ -580,11 +760,37 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             may_send_sct_list: self.may_send_sct_list,
+            server_cert_type: self.server_cert_type,
             client_auth: Some(client_auth),
+            cert_compressor,
         }))
     }
 }
 
+fn compatible_sigschemes(certreq: &CertificateRequestPayloadTLS13) -> Vec<SignatureScheme> {
+    let tls13_sign_schemes = sign::supported_sign_tls13();
+    certreq
+        .get_sigalgs_extension()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .cloned()
+        .filter(|scheme| tls13_sign_schemes.contains(scheme))
+        .collect()
+}
+
+/// Whether we offer to authenticate ourselves after the handshake,
+/// should the server ask.
+///
+/// This is only possible with X.509 certificates, and QUIC forbids it.
+pub(super) fn offers_post_handshake_auth(config: &ClientConfig, common: &CommonState) -> bool {
+    let resolver = &config.client_auth_cert_resolver;
+    !common.is_quic()
+        && !common.is_dtls()
+        && resolver.has_certs()
+        && !resolver.uses_raw_public_keys()
+}
+
 struct ExpectCertificate {
     config: Arc<ClientConfig>,
     server_name: ServerName,
@@ -593,16 +799,36 @@ struct ExpectCertificate {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    server_cert_type: CertificateType,
     client_auth: Option<ClientAuthDetails>,
+    cert_compressor: Option<&'static dyn CertCompressor>,
 }
 
 impl State<ClientConnectionData> for ExpectCertificate {
     fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
-        let cert_chain = require_handshake_msg!(
-            m,
-            HandshakeType::Certificate,
-            HandshakePayload::CertificateTLS13
-        )?;
+        let decompressed;
+        let cert_chain = match &m.payload {
+            MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CompressedCertificate(compressed),
+                        ..
+                    },
+                ..
+            } => {
+                decompressed = compress::decompress_certificate(
+                    cx.common,
+                    &self.config.cert_decompressors,
+                    compressed,
+                )?;
+                &decompressed
+            }
+            _ => require_handshake_msg!(
+                m,
+                HandshakeType::Certificate,
+                HandshakePayload::CertificateTLS13
+            )?,
+        };
         self.transcript.add_message(&m);
 
         // This is only non-empty for client auth.
@@ -624,6 +850,22 @@ impl State<ClientConnectionData> for ExpectCertificate {
             ));
         }
 
+        if self.server_cert_type == CertificateType::RawPublicKey && cert_chain.entries.len() > 1 {
+            return Err(cx
+                .common
+                .illegal_param("server sent more than one raw public key"));
+        }
+
+        let delegated_credential = cert_chain.get_end_entity_delegated_credential();
+        if delegated_credential.is_some()
+            && (!self.config.enable_delegated_credentials
+                || self.server_cert_type != CertificateType::X509)
+        {
+            return Err(Error::PeerMisbehavedError(
+                "server sent unsolicited delegated credential".to_string(),
+            ));
+        }
+
         let server_cert = ServerCertDetails::new(
             cert_chain.convert(),
             cert_chain.get_end_entity_ocsp(),
@@ -642,7 +884,20 @@ impl State<ClientConnectionData> for ExpectCertificate {
             }
         }
 
-        Ok(Box::new(ExpectCertificateVerify {
+        // The application verifies the chain itself, instead of
+        // `config.verifier`, unless a raw public key goes to
+        // `config.server_raw_key_verifier`.
+        let raw_key_verified = self.server_cert_type == CertificateType::RawPublicKey
+            && self
+                .config
+                .server_raw_key_verifier
+                .is_some();
+        let cert_verification_deferred = self.config.defer_cert_verification
+            && !cx.common.is_dtls()
+            && !raw_key_verified
+            && !server_cert.cert_chain.is_empty();
+
+        let next = Box::new(ExpectCertificateVerify {
             config: self.config,
             server_name: self.server_name,
             randoms: self.randoms,
@@ -650,8 +905,23 @@ impl State<ClientConnectionData> for ExpectCertificate {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             server_cert,
+            server_cert_type: self.server_cert_type,
+            cert_verification_deferred,
+            delegated_credential,
             client_auth: self.client_auth,
-        }))
+            cert_compressor: self.cert_compressor,
+        });
+
+        if cert_verification_deferred {
+            let cert_chain = next.server_cert.cert_chain.clone();
+            return Ok(ExpectCertVerification::new(
+                cx.common,
+                cert_chain,
+                next,
+                hs::send_cert_error_alert,
+            ));
+        }
+        Ok(next)
     }
 }
 
@@ -664,7 +934,11 @@ struct ExpectCertificateVerify {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     server_cert: ServerCertDetails,
+    server_cert_type: CertificateType,
+    cert_verification_deferred: bool,
+    delegated_credential: Option<DelegatedCredential>,
     client_auth: Option<ClientAuthDetails>,
+    cert_compressor: Option<&'static dyn CertCompressor>,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateVerify {
@@ -684,30 +958,66 @@ impl State<ClientConnectionData> for ExpectCertificateVerify {
             .split_first()
             .ok_or(Error::NoCertificatesPresented)?;
         let now = std::time::SystemTime::now();
-        let cert_verified = self
-            .config
-            .verifier
-            .verify_server_cert(
-                end_entity,
-                intermediates,
-                &self.server_name,
-                &mut self.server_cert.scts(),
-                &self.server_cert.ocsp_response,
-                now,
-            )
-            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
-
-        // 2. Verify their signature on the handshake.
         let handshake_hash = self.transcript.get_current_hash();
-        let sig_verified = self
-            .config
-            .verifier
-            .verify_tls13_signature(
-                &verify::construct_tls13_server_verify_message(&handshake_hash),
-                &self.server_cert.cert_chain[0],
-                cert_verify,
-            )
-            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        let verify_message = verify::construct_tls13_server_verify_message(&handshake_hash);
+
+        let (cert_verified, sig_verified) =
+            match (self.server_cert_type, &self.config.server_raw_key_verifier) {
+                (CertificateType::RawPublicKey, Some(raw_key_verifier)) => {
+                    // The end-entity "certificate" is the server's SubjectPublicKeyInfo.
+                    let spki = SubjectPublicKeyInfo(end_entity.0.clone());
+                    raw_key_verifier
+                        .verify_server_raw_key(&spki, &self.server_name, now)
+                        .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+
+                    // 2. Verify their signature on the handshake.
+                    let sig_verified = verify::verify_tls13_raw_key(
+                        self.config.provider,
+                        &verify_message,
+                        spki.as_ref(),
+                        cert_verify,
+                    )
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                    (verify::ServerCertVerified::assertion(), sig_verified)
+                }
+                _ => {
+                    let cert_verified = if self.cert_verification_deferred {
+                        // The application has already accepted the chain.
+                        verify::ServerCertVerified::assertion()
+                    } else {
+                        self.config
+                            .verifier
+                            .verify_server_cert(
+                                end_entity,
+                                intermediates,
+                                &self.server_name,
+                                &mut self.server_cert.scts(),
+                                &self.server_cert.ocsp_response,
+                                now,
+                            )
+                            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?
+                    };
+
+                    // 2. Verify their signature on the handshake, possibly made
+                    //    by a delegated key.
+                    let sig_verified = match &self.delegated_credential {
+                        Some(dc) => verify_with_delegated_credential(
+                            &self.config,
+                            end_entity,
+                            dc,
+                            &verify_message,
+                            cert_verify,
+                            now,
+                        ),
+                        None => self
+                            .config
+                            .verifier
+                            .verify_tls13_signature(&verify_message, end_entity, cert_verify),
+                    }
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                    (cert_verified, sig_verified)
+                }
+            };
 
         cx.common.peer_certificates = Some(self.server_cert.cert_chain);
         self.transcript.add_message(&m);
@@ -720,16 +1030,50 @@ impl State<ClientConnectionData> for ExpectCertificateVerify {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             client_auth: self.client_auth,
+            cert_compressor: self.cert_compressor,
             cert_verified,
             sig_verified,
         }))
     }
 }
 
+/// Verify the delegated credential `dc` sent with `end_entity`, and then
+/// the server's `CertificateVerify` signature using the delegated key.
+fn verify_with_delegated_credential(
+    config: &ClientConfig,
+    end_entity: &Certificate,
+    dc: &DelegatedCredential,
+    message: &[u8],
+    cert_verify: &DigitallySignedStruct,
+    now: std::time::SystemTime,
+) -> Result<verify::HandshakeSignatureValid, Error> {
+    verify::check_delegated_credential_time(end_entity, &dc.cred, now)?;
+
+    let scheme = dc.cred.dc_cert_verify_algorithm;
+    if !verify::RAW_KEY_SCHEMES.contains(&scheme) || cert_verify.scheme != scheme {
+        return Err(Error::PeerMisbehavedError(
+            "server used wrong scheme for delegated credential".to_string(),
+        ));
+    }
+
+    let dc_message =
+        verify::construct_delegated_credential_message(end_entity, &dc.cred, dc.algorithm);
+    config
+        .verifier
+        .verify_delegated_credential(
+            &dc_message,
+            end_entity,
+            &DigitallySignedStruct::new(dc.algorithm, dc.signature.0.clone()),
+        )?;
+
+    verify::verify_tls13_raw_key(config.provider, message, &dc.cred.public_key.0, cert_verify)
+}
+
 fn emit_certificate_tls13(
     transcript: &mut HandshakeHash,
     certkey: Option<&CertifiedKey>,
     auth_context: Option<Vec<u8>>,
+    compressor: Option<&dyn CertCompressor>,
     common: &mut CommonState,
 ) {
     let context = auth_context.unwrap_or_default();
@@ -747,12 +1091,20 @@ fn emit_certificate_tls13(
         }
     }
 
-    let m = Message {
-        version: ProtocolVersion::TLSv1_3,
-        payload: MessagePayload::handshake(HandshakeMessagePayload {
+    let payload = match compressor.and_then(|c| compress::compress_certificate(c, &cert_payload)) {
+        Some(compressed) => HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(compressed),
+        },
+        None => HandshakeMessagePayload {
             typ: HandshakeType::Certificate,
             payload: HandshakePayload::CertificateTLS13(cert_payload),
-        }),
+        },
+    };
+
+    let m = Message {
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::handshake(payload),
     };
     transcript.add_message(&m);
     common.send_msg(m, true);
@@ -782,9 +1134,33 @@ fn emit_certverify_tls13(
     Ok(())
 }
 
+fn emit_client_auth_tls13(
+    transcript: &mut HandshakeHash,
+    client_auth: ClientAuthDetails,
+    compressor: Option<&dyn CertCompressor>,
+    common: &mut CommonState,
+) -> Result<(), Error> {
+    match client_auth {
+        ClientAuthDetails::Empty {
+            auth_context_tls13: auth_context,
+        } => {
+            emit_certificate_tls13(transcript, None, auth_context, compressor, common);
+        }
+        ClientAuthDetails::Verify {
+            certkey,
+            signer,
+            auth_context_tls13: auth_context,
+        } => {
+            emit_certificate_tls13(transcript, Some(&certkey), auth_context, compressor, common);
+            emit_certverify_tls13(transcript, signer.as_ref(), common)?;
+        }
+    }
+    Ok(())
+}
+
 fn emit_finished_tls13(
     transcript: &mut HandshakeHash,
-    verify_data: ring::hmac::Tag,
+    verify_data: hmac::Tag,
     common: &mut CommonState,
 ) {
     let verify_data_payload = Payload::new(verify_data.as_ref());
@@ -826,6 +1202,7 @@ struct ExpectFinished {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     client_auth: Option<ClientAuthDetails>,
+    cert_compressor: Option<&'static dyn CertCompressor>,
     cert_verified: verify::ServerCertVerified,
     sig_verified: verify::HandshakeSignatureValid,
 }
@@ -849,6 +1226,12 @@ impl State<ClientConnectionData> for ExpectFinished {
             })
             .map(|_| verify::FinishedMessageVerified::assertion())?;
 
+        // The server authenticated itself under the ECH public name: now we can
+        // safely tell it we won't continue.
+        if cx.data.ech_status == EchStatus::Rejected {
+            return Err(hs::abort_ech_rejected(cx));
+        }
+
         st.transcript.add_message(&m);
 
         let hash_after_handshake = st.transcript.get_current_hash();
@@ -865,26 +1248,12 @@ impl State<ClientConnectionData> for ExpectFinished {
         /* Send our authentication/finished messages.  These are still encrypted
          * with our handshake keys. */
         if let Some(client_auth) = st.client_auth {
-            match client_auth {
-                ClientAuthDetails::Empty {
-                    auth_context_tls13: auth_context,
-                } => {
-                    emit_certificate_tls13(&mut st.transcript, None, auth_context, cx.common);
-                }
-                ClientAuthDetails::Verify {
-                    certkey,
-                    signer,
-                    auth_context_tls13: auth_context,
-                } => {
-                    emit_certificate_tls13(
-                        &mut st.transcript,
-                        Some(&certkey),
-                        auth_context,
-                        cx.common,
-                    );
-                    emit_certverify_tls13(&mut st.transcript, signer.as_ref(), cx.common)?;
-                }
-            }
+            emit_client_auth_tls13(
+                &mut st.transcript,
+                client_auth,
+                st.cert_compressor,
+                cx.common,
+            )?;
         }
 
         let (key_schedule_pre_finished, verify_data) = st
@@ -904,7 +1273,8 @@ impl State<ClientConnectionData> for ExpectFinished {
         cx.common.start_traffic();
 
         let st = ExpectTraffic {
-            session_storage: Arc::clone(&st.config.session_storage),
+            post_handshake_auth_offered: offers_post_handshake_auth(&st.config, cx.common),
+            config: st.config,
             server_name: st.server_name,
             suite: st.suite,
             transcript: st.transcript,
@@ -928,12 +1298,13 @@ impl State<ClientConnectionData> for ExpectFinished {
 // In this state we can be sent tickets, key updates,
 // and application data.
 struct ExpectTraffic {
-    session_storage: Arc<dyn StoresClientSessions>,
+    config: Arc<ClientConfig>,
     server_name: ServerName,
     suite: &'static Tls13CipherSuite,
     transcript: HandshakeHash,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    post_handshake_auth_offered: bool,
     _cert_verified: verify::ServerCertVerified,
     _sig_verified: verify::HandshakeSignatureValid,
     _fin_verified: verify::FinishedMessageVerified,
@@ -968,7 +1339,13 @@ impl ExpectTraffic {
             }
         };
 
-        let value = persist::Tls13ClientSessionValue::new(
+        if nst.lifetime == 0 {
+            debug!("Ticket not saved: server gave it a lifetime of zero");
+            return Ok(());
+        }
+
+        #[allow(unused_mut)]
+        let mut value = persist::Tls13ClientSessionValue::new(
             self.suite,
             nst.ticket.0.clone(),
             secret,
@@ -992,26 +1369,71 @@ impl ExpectTraffic {
             }
         }
 
-        let key = persist::ClientSessionKey::session_for_server_name(&self.server_name);
-        #[allow(unused_mut)]
-        let mut ticket = value.get_encoding();
-
         #[cfg(feature = "quic")]
         if let (Protocol::Quic, Some(ref quic_params)) =
             (cx.common.protocol, &cx.common.quic.params)
         {
-            PayloadU16::encode_slice(quic_params, &mut ticket);
+            value.set_quic_params(quic_params);
         }
 
-        let worked = self
+        self.config
             .session_storage
-            .put(key.get_encoding(), ticket);
+            .insert_tls13_ticket(&self.server_name, value);
+        debug!("Ticket saved");
+        Ok(())
+    }
 
-        if worked {
-            debug!("Ticket saved");
-        } else {
-            debug!("Ticket not saved");
+    fn handle_certificate_request(
+        &mut self,
+        common: &mut CommonState,
+        m: &Message,
+        certreq: &CertificateRequestPayloadTLS13,
+    ) -> Result<(), Error> {
+        if !self.post_handshake_auth_offered {
+            common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            return Err(Error::PeerMisbehavedError(
+                "server sent post-handshake CertificateRequest without our offer".into(),
+            ));
         }
+
+        // Mustn't be interleaved with other handshake messages.
+        common.check_aligned_handshake()?;
+        debug!("Got post-handshake CertificateRequest {:?}", certreq);
+
+        let compat_sigschemes = compatible_sigschemes(certreq);
+        if compat_sigschemes.is_empty() {
+            common.send_fatal_alert(AlertDescription::HandshakeFailure);
+            return Err(Error::PeerIncompatibleError(
+                "server sent bad certreq schemes".to_string(),
+            ));
+        }
+
+        let client_auth = ClientAuthDetails::resolve(
+            self.config
+                .client_auth_cert_resolver
+                .as_ref(),
+            certreq.get_authorities_extension(),
+            &compat_sigschemes,
+            CertificateType::X509,
+            Some(certreq.context.0.clone()),
+        );
+
+        let cert_compressor = certreq
+            .get_certificate_compression_extension()
+            .and_then(|offered| {
+                compress::choose_compressor(&self.config.cert_compressors, offered)
+            });
+
+        // Each post-handshake exchange continues from the transcript as it
+        // was at the end of the handshake.
+        let mut transcript = self.transcript.clone();
+        transcript.add_message(m);
+        emit_client_auth_tls13(&mut transcript, client_auth, cert_compressor, common)?;
+
+        let verify_data = self
+            .key_schedule
+            .sign_post_handshake_client_finish(&transcript.get_current_hash());
+        emit_finished_tls13(&mut transcript, verify_data, common);
         Ok(())
     }
 
@@ -1030,6 +1452,13 @@ impl ExpectTraffic {
             }
         }
 
+        if common.is_dtls() {
+            common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            let msg = "KeyUpdate received in DTLS connection".to_string();
+            warn!("{}", msg);
+            return Err(Error::PeerMisbehavedError(msg));
+        }
+
         // Mustn't be interleaved with other handshake messages.
         common.check_aligned_handshake()?;
 
@@ -1073,11 +1502,23 @@ impl State<ClientConnectionData> for ExpectTraffic {
                     },
                 ..
             } => self.handle_key_update(cx.common, key_update)?,
+            MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CertificateRequestTLS13(ref certreq),
+                        ..
+                    },
+                ..
+            } => self.handle_certificate_request(cx.common, &m, certreq)?,
             payload => {
                 return Err(inappropriate_handshake_message(
                     &payload,
                     &[ContentType::ApplicationData, ContentType::Handshake],
-                    &[HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate],
+                    &[
+                        HandshakeType::NewSessionTicket,
+                        HandshakeType::KeyUpdate,
+                        HandshakeType::CertificateRequest,
+                    ],
                 ));
             }
         }
@@ -1096,13 +1537,31 @@ impl State<ClientConnectionData> for ExpectTraffic {
     }
 
     fn perhaps_write_key_update(&mut self, common: &mut CommonState) {
-        if self.want_write_key_update {
+        if self.want_write_key_update || common.record_layer.wants_key_update() {
             self.want_write_key_update = false;
             self.key_schedule
                 .update_encrypter_and_notify(common);
         }
     }
 
+    fn refresh_traffic_keys(
+        &mut self,
+        common: &mut CommonState,
+        request_peer: bool,
+    ) -> Result<(), Error> {
+        // This also answers any outstanding request from the peer.
+        self.want_write_key_update = false;
+        match request_peer {
+            true => self
+                .key_schedule
+                .update_encrypter_and_request(common),
+            false => self
+                .key_schedule
+                .update_encrypter_and_notify(common),
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "secret_extraction")]
     fn extract_secrets(&self) -> Result<PartiallyExtractedSecrets, Error> {
         self.key_schedule
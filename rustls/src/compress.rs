@@ -0,0 +1,418 @@
+//! Certificate compression and decompression support
+//!
+//! This crate supports compression and decompression everywhere
+//! certificates are used, in accordance with [RFC8879][rfc8879].
+//!
+//! Note that this is only supported for TLS1.3 connections.
+//!
+//! # Getting started
+//!
+//! Build this crate with the `zlib` feature to get an implementation of
+//! zlib compression and decompression, via the `flate2` crate.  This is
+//! then used by default by `ClientConfig` and `ServerConfig`.
+//!
+//! To provide your own compression or decompression implementation, implement
+//! [`CertCompressor`] and/or [`CertDecompressor`], and add them to the
+//! `cert_compressors` and/or `cert_decompressors` fields of `ClientConfig`
+//! and `ServerConfig`.
+//!
+//! # Security
+//!
+//! Decompression is bounded: the peer declares the uncompressed length up
+//! front, and that length is checked against [`CERTIFICATE_MAX_SIZE_LIMIT`]
+//! before any output buffer is allocated.  A decompressor is handed an
+//! output buffer of exactly the declared size, and must fail if the input
+//! would decompress to anything else.
+//!
+//! [rfc8879]: https://www.rfc-editor.org/rfc/rfc8879.html
+
+use crate::conn::CommonState;
+use crate::enums::CertificateCompressionAlgorithm;
+use crate::error::Error;
+#[cfg(feature = "logging")]
+use crate::log::{debug, trace, warn};
+use crate::msgs::base::PayloadU24;
+use crate::msgs::codec::{self, Codec};
+use crate::msgs::enums::AlertDescription;
+use crate::msgs::handshake::{CertificatePayloadTLS13, CompressedCertificatePayload};
+
+use std::fmt::Debug;
+
+/// The maximum uncompressed size of a certificate message we accept.
+///
+/// Compressed certificates which claim to be larger than this are
+/// rejected without attempting decompression.
+pub const CERTIFICATE_MAX_SIZE_LIMIT: usize = 0x1_0000;
+
+/// Returns the supported `CertDecompressor` implementations enabled
+/// by crate features.
+pub fn default_cert_decompressors() -> &'static [&'static dyn CertDecompressor] {
+    &[
+        #[cfg(feature = "zlib")]
+        ZLIB_DECOMPRESSOR,
+    ]
+}
+
+/// An available certificate decompression algorithm.
+pub trait CertDecompressor: Debug + Send + Sync {
+    /// Decompress `input`, writing the result to `output`.
+    ///
+    /// `output` is sized to match the declared length of the decompressed data.
+    ///
+    /// `Err(DecompressionFailed)` should be returned if decompression produces more, or fewer
+    /// bytes than fit in `output`, or if the `input` is in any way malformed.
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed>;
+
+    /// Which algorithm this decompressor handles.
+    fn algorithm(&self) -> CertificateCompressionAlgorithm;
+}
+
+/// Returns the supported `CertCompressor` implementations enabled
+/// by crate features.
+pub fn default_cert_compressors() -> &'static [&'static dyn CertCompressor] {
+    &[
+        #[cfg(feature = "zlib")]
+        ZLIB_COMPRESSOR,
+    ]
+}
+
+/// An available certificate compression algorithm.
+pub trait CertCompressor: Debug + Send + Sync {
+    /// Compress `input`, returning the result.
+    ///
+    /// `Err(CompressionFailed)` may be returned to fail the compression; in
+    /// that case the certificate is sent uncompressed.
+    fn compress(&self, input: Vec<u8>) -> Result<Vec<u8>, CompressionFailed>;
+
+    /// Which algorithm this compressor handles.
+    fn algorithm(&self) -> CertificateCompressionAlgorithm;
+}
+
+/// A failure indication from [`CertDecompressor::decompress`].
+#[derive(Debug)]
+pub struct DecompressionFailed;
+
+/// A failure indication from [`CertCompressor::compress`].
+#[derive(Debug)]
+pub struct CompressionFailed;
+
+#[cfg(feature = "zlib")]
+mod feat_zlib {
+    use super::*;
+    use flate2::{Decompress, FlushDecompress, Status};
+    use std::io::Write;
+
+    /// A certificate decompressor for the Zlib algorithm using the `flate2` crate.
+    pub const ZLIB_DECOMPRESSOR: &dyn CertDecompressor = &ZlibDecompressor;
+
+    #[derive(Debug)]
+    struct ZlibDecompressor;
+
+    impl CertDecompressor for ZlibDecompressor {
+        fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed> {
+            let mut z = Decompress::new(true);
+            match z.decompress(input, output, FlushDecompress::Finish) {
+                Ok(Status::StreamEnd)
+                    if z.total_in() as usize == input.len()
+                        && z.total_out() as usize == output.len() =>
+                {
+                    Ok(())
+                }
+                _ => Err(DecompressionFailed),
+            }
+        }
+
+        fn algorithm(&self) -> CertificateCompressionAlgorithm {
+            CertificateCompressionAlgorithm::Zlib
+        }
+    }
+
+    /// A certificate compressor for the Zlib algorithm using the `flate2` crate.
+    pub const ZLIB_COMPRESSOR: &dyn CertCompressor = &ZlibCompressor;
+
+    #[derive(Debug)]
+    struct ZlibCompressor;
+
+    impl CertCompressor for ZlibCompressor {
+        fn compress(&self, input: Vec<u8>) -> Result<Vec<u8>, CompressionFailed> {
+            let mut z = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+            z.write_all(&input)
+                .map_err(|_| CompressionFailed)?;
+            z.finish()
+                .map_err(|_| CompressionFailed)
+        }
+
+        fn algorithm(&self) -> CertificateCompressionAlgorithm {
+            CertificateCompressionAlgorithm::Zlib
+        }
+    }
+}
+
+#[cfg(feature = "zlib")]
+pub use feat_zlib::{ZLIB_COMPRESSOR, ZLIB_DECOMPRESSOR};
+
+/// Choose a compressor for the peer's list of `offered` algorithms, in our
+/// order of preference.
+pub(crate) fn choose_compressor(
+    compressors: &[&'static dyn CertCompressor],
+    offered: &[CertificateCompressionAlgorithm],
+) -> Option<&'static dyn CertCompressor> {
+    compressors
+        .iter()
+        .find(|c| offered.contains(&c.algorithm()))
+        .copied()
+}
+
+/// Compress `cert`, returning `None` if that was not possible (or not
+/// worthwhile).
+pub(crate) fn compress_certificate(
+    compressor: &dyn CertCompressor,
+    cert: &CertificatePayloadTLS13,
+) -> Option<CompressedCertificatePayload> {
+    let uncompressed = cert.get_encoding();
+    let uncompressed_len = uncompressed.len();
+    if uncompressed_len > CERTIFICATE_MAX_SIZE_LIMIT {
+        return None;
+    }
+
+    match compressor.compress(uncompressed) {
+        Ok(compressed) if compressed.len() < uncompressed_len => {
+            Some(CompressedCertificatePayload {
+                alg: compressor.algorithm(),
+                uncompressed_len: uncompressed_len as u32,
+                compressed: PayloadU24(compressed),
+            })
+        }
+        Ok(_) => {
+            debug!("Certificate compression was not worthwhile");
+            None
+        }
+        Err(CompressionFailed) => {
+            warn!(
+                "Certificate compression with {:?} failed",
+                compressor.algorithm()
+            );
+            None
+        }
+    }
+}
+
+/// Decompress the peer's `CompressedCertificate` message, using one of the
+/// `decompressors` we offered.
+pub(crate) fn decompress_certificate(
+    common: &mut CommonState,
+    decompressors: &[&'static dyn CertDecompressor],
+    compressed: &CompressedCertificatePayload,
+) -> Result<CertificatePayloadTLS13, Error> {
+    let decompressor = match decompressors
+        .iter()
+        .find(|d| d.algorithm() == compressed.alg)
+    {
+        Some(d) => d,
+        None => {
+            return Err(
+                common.illegal_param("peer chose unoffered certificate compression algorithm")
+            );
+        }
+    };
+
+    let uncompressed_len = compressed.uncompressed_len as usize;
+    if uncompressed_len == 0 || uncompressed_len > CERTIFICATE_MAX_SIZE_LIMIT {
+        return Err(bad_certificate(
+            common,
+            "compressed certificate has unacceptable length",
+        ));
+    }
+
+    let mut buffer = vec![0u8; uncompressed_len];
+    if decompressor
+        .decompress(&compressed.compressed.0, &mut buffer)
+        .is_err()
+    {
+        return Err(bad_certificate(common, "certificate decompression failed"));
+    }
+
+    let mut rd = codec::Reader::init(&buffer);
+    match CertificatePayloadTLS13::read(&mut rd) {
+        Some(cert) if !rd.any_left() => {
+            trace!(
+                "Decompressed {:?} certificate from {} to {} bytes",
+                compressed.alg,
+                compressed.compressed.0.len(),
+                uncompressed_len
+            );
+            Ok(cert)
+        }
+        _ => Err(bad_certificate(
+            common,
+            "invalid certificate after decompression",
+        )),
+    }
+}
+
+fn bad_certificate(common: &mut CommonState, why: &str) -> Error {
+    common.send_fatal_alert(AlertDescription::BadCertificate);
+    Error::PeerMisbehavedError(why.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conn::Side;
+    use crate::key::Certificate;
+    use crate::msgs::handshake::CertificateEntry;
+
+    #[derive(Debug)]
+    struct Copying;
+
+    impl CertDecompressor for Copying {
+        fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed> {
+            if input.len() != output.len() {
+                return Err(DecompressionFailed);
+            }
+            output.copy_from_slice(input);
+            Ok(())
+        }
+
+        fn algorithm(&self) -> CertificateCompressionAlgorithm {
+            CertificateCompressionAlgorithm::Unknown(0xffff)
+        }
+    }
+
+    #[derive(Debug)]
+    struct NeverCalled;
+
+    impl CertDecompressor for NeverCalled {
+        fn decompress(&self, _: &[u8], _: &mut [u8]) -> Result<(), DecompressionFailed> {
+            panic!("decompressor must not be called");
+        }
+
+        fn algorithm(&self) -> CertificateCompressionAlgorithm {
+            CertificateCompressionAlgorithm::Unknown(0xffff)
+        }
+    }
+
+    fn copied(payload: Vec<u8>) -> CompressedCertificatePayload {
+        CompressedCertificatePayload {
+            alg: CertificateCompressionAlgorithm::Unknown(0xffff),
+            uncompressed_len: payload.len() as u32,
+            compressed: PayloadU24(payload),
+        }
+    }
+
+    #[test]
+    fn decompress_certificate_round_trip() {
+        let cert =
+            CertificatePayloadTLS13::new(vec![CertificateEntry::new(Certificate(vec![1, 2, 3]))]);
+        let mut common = CommonState::new(Side::Client);
+        let got =
+            decompress_certificate(&mut common, &[&Copying], &copied(cert.get_encoding())).unwrap();
+        assert_eq!(got.get_encoding(), cert.get_encoding());
+    }
+
+    #[test]
+    fn decompress_certificate_refuses_oversized_length() {
+        let mut common = CommonState::new(Side::Client);
+        let mut bomb = copied(vec![0x42; 16]);
+        bomb.uncompressed_len = CERTIFICATE_MAX_SIZE_LIMIT as u32 + 1;
+        assert!(decompress_certificate(&mut common, &[&NeverCalled], &bomb).is_err());
+
+        let mut common = CommonState::new(Side::Client);
+        let mut empty = copied(vec![0x42; 16]);
+        empty.uncompressed_len = 0;
+        assert!(decompress_certificate(&mut common, &[&NeverCalled], &empty).is_err());
+    }
+
+    #[test]
+    fn decompress_certificate_refuses_unoffered_algorithm() {
+        let mut common = CommonState::new(Side::Server);
+        let mut payload = copied(vec![0; 4]);
+        payload.alg = CertificateCompressionAlgorithm::Zlib;
+        assert!(decompress_certificate(&mut common, &[&NeverCalled], &payload).is_err());
+    }
+
+    #[test]
+    fn decompress_certificate_refuses_trailing_data() {
+        let mut encoding = CertificatePayloadTLS13::new(vec![]).get_encoding();
+        encoding.push(0);
+        let mut common = CommonState::new(Side::Client);
+        assert!(decompress_certificate(&mut common, &[&Copying], &copied(encoding)).is_err());
+    }
+
+    #[derive(Debug)]
+    struct Identity;
+
+    impl CertCompressor for Identity {
+        fn compress(&self, input: Vec<u8>) -> Result<Vec<u8>, CompressionFailed> {
+            Ok(input)
+        }
+
+        fn algorithm(&self) -> CertificateCompressionAlgorithm {
+            CertificateCompressionAlgorithm::Unknown(0xffff)
+        }
+    }
+
+    #[test]
+    fn incompressible_certificate_is_sent_uncompressed() {
+        let cert = CertificatePayloadTLS13::new(vec![]);
+        assert!(compress_certificate(&Identity, &cert).is_none());
+    }
+
+    #[test]
+    fn choose_compressor_uses_our_preference() {
+        let first: &'static dyn CertCompressor = &Identity;
+        let offered = [
+            CertificateCompressionAlgorithm::Zlib,
+            CertificateCompressionAlgorithm::Unknown(0xffff),
+        ];
+        assert_eq!(
+            choose_compressor(&[first], &offered).map(|c| c.algorithm()),
+            Some(CertificateCompressionAlgorithm::Unknown(0xffff))
+        );
+        assert!(choose_compressor(&[first], &offered[..1]).is_none());
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn zlib_round_trip() {
+        let input = vec![0x42u8; 1000];
+        let compressed = ZLIB_COMPRESSOR
+            .compress(input.clone())
+            .unwrap();
+        assert!(compressed.len() < input.len());
+
+        let mut output = vec![0u8; input.len()];
+        ZLIB_DECOMPRESSOR
+            .decompress(&compressed, &mut output)
+            .unwrap();
+        assert_eq!(input, output);
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn zlib_rejects_length_mismatch() {
+        let input = vec![0x42u8; 1000];
+        let compressed = ZLIB_COMPRESSOR
+            .compress(input.clone())
+            .unwrap();
+
+        let mut short = vec![0u8; input.len() - 1];
+        assert!(ZLIB_DECOMPRESSOR
+            .decompress(&compressed, &mut short)
+            .is_err());
+
+        let mut long = vec![0u8; input.len() + 1];
+        assert!(ZLIB_DECOMPRESSOR
+            .decompress(&compressed, &mut long)
+            .is_err());
+    }
+
+    #[cfg(feature = "zlib")]
+    #[test]
+    fn zlib_rejects_garbage() {
+        let mut output = vec![0u8; 16];
+        assert!(ZLIB_DECOMPRESSOR
+            .decompress(&[1, 2, 3, 4], &mut output)
+            .is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::check::inappropriate_message;
 use crate::enums::ProtocolVersion;
 use crate::error::Error;
 use crate::key;
@@ -8,7 +9,9 @@ use crate::msgs::base::Payload;
 use crate::msgs::deframer::{Deframed, MessageDeframer};
 use crate::msgs::enums::HandshakeType;
 use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType};
-use crate::msgs::fragmenter::MessageFragmenter;
+use crate::msgs::fragmenter::{
+    MessageFragmenter, MAX_FRAGMENT_LEN, MAX_RECORD_SIZE_LIMIT, MIN_RECORD_SIZE_LIMIT,
+};
 use crate::msgs::handshake::Random;
 use crate::msgs::message::{
     BorrowedPlainMessage, Message, MessagePayload, OpaqueMessage, PlainMessage,
@@ -21,8 +24,10 @@ use crate::suites::SupportedCipherSuite;
 use crate::suites::{ExtractedSecrets, PartiallyExtractedSecrets};
 #[cfg(feature = "tls12")]
 use crate::tls12::ConnectionSecrets;
+#[cfg(feature = "dtls")]
+use crate::tls13::Tls13CipherSuite;
 use crate::vecbuf::ChunkVecBuffer;
-#[cfg(feature = "quic")]
+#[cfg(any(feature = "quic", feature = "dtls"))]
 use std::collections::VecDeque;
 
 use std::fmt::Debug;
@@ -76,6 +81,20 @@ impl Connection {
         }
     }
 
+    /// Continue the handshake with the application's verification of the
+    /// peer's certificates.
+    ///
+    /// See [`ConnectionCommon::complete_cert_verification()`] for more information.
+    pub fn complete_cert_verification(
+        &mut self,
+        verified: Result<(), Error>,
+    ) -> Result<IoState, Error> {
+        match self {
+            Self::Client(conn) => conn.complete_cert_verification(verified),
+            Self::Server(conn) => conn.complete_cert_verification(verified),
+        }
+    }
+
     /// Derives key material from the agreed connection secrets.
     ///
     /// See [`ConnectionCommon::export_keying_material()`] for more information.
@@ -91,6 +110,16 @@ impl Connection {
         }
     }
 
+    /// Update the keys we send with, optionally asking the peer to do the same.
+    ///
+    /// See [`ConnectionCommon::refresh_traffic_keys()`] for more information.
+    pub fn refresh_traffic_keys(&mut self, request_peer: bool) -> Result<(), Error> {
+        match self {
+            Self::Client(conn) => conn.refresh_traffic_keys(request_peer),
+            Self::Server(conn) => conn.refresh_traffic_keys(request_peer),
+        }
+    }
+
     /// Extract secrets, to set up kTLS for example
     #[cfg(feature = "secret_extraction")]
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
@@ -369,6 +398,8 @@ pub(crate) enum Protocol {
     Tcp,
     #[cfg(feature = "quic")]
     Quic,
+    #[cfg(feature = "dtls")]
+    Dtls,
 }
 
 #[derive(Debug)]
@@ -626,7 +657,11 @@ impl<Data> ConnectionCommon<Data> {
             }
         };
 
-        while let Some(msg) = self.deframe()? {
+        while !state.is_suspended() {
+            let msg = match self.deframe()? {
+                Some(msg) => msg,
+                None => break,
+            };
             match self.process_msg(msg, state) {
                 Ok(new) => state = new,
                 Err(e) => {
@@ -642,6 +677,14 @@ impl<Data> ConnectionCommon<Data> {
 
     /// Pull a message out of the deframer and send any messages that need to be sent as a result.
     fn deframe(&mut self) -> Result<Option<PlainMessage>, Error> {
+        self.message_deframer
+            .set_record_size_limit(
+                self.common_state
+                    .received_record_size_limit,
+            );
+        self.message_deframer
+            .set_max_fragment_len(self.common_state.max_fragment_len);
+
         match self
             .message_deframer
             .pop(&mut self.common_state.record_layer)
@@ -684,6 +727,11 @@ impl<Data> ConnectionCommon<Data> {
                     .send_fatal_alert(AlertDescription::RecordOverflow);
                 Err(Error::PeerSentOversizedRecord)
             }
+            Err(Error::PeerExceededRecordSizeLimit) => {
+                self.common_state
+                    .send_fatal_alert(AlertDescription::RecordOverflow);
+                Err(Error::PeerExceededRecordSizeLimit)
+            }
             Err(Error::DecryptError) => {
                 self.common_state
                     .send_fatal_alert(AlertDescription::BadRecordMac);
@@ -693,12 +741,77 @@ impl<Data> ConnectionCommon<Data> {
         }
     }
 
-    pub(crate) fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
-        if let Ok(st) = &mut self.state {
-            st.perhaps_write_key_update(&mut self.common_state);
+    /// Continue the handshake with the application's verification of the
+    /// peer's certificates, given by [`CommonState::pending_cert_verification`].
+    /// An error rejects them: it ends the handshake, and is returned.
+    ///
+    /// Any messages received meanwhile are then processed, as by
+    /// [`ConnectionCommon::process_new_packets`], whose result is returned.
+    /// This fails without affecting the connection if no verification is
+    /// pending.
+    pub fn complete_cert_verification(
+        &mut self,
+        verified: Result<(), Error>,
+    ) -> Result<IoState, Error> {
+        if self
+            .common_state
+            .pending_cert_verification
+            .take()
+            .is_none()
+        {
+            return Err(Error::General(
+                "no certificate verification is pending".into(),
+            ));
+        }
+
+        self.common_state.cert_verification = Some(verified);
+        self.resume()
+    }
+
+    /// Continue a suspended handshake, then process any messages received
+    /// while it was suspended.
+    pub(crate) fn resume(&mut self) -> Result<IoState, Error> {
+        let state = match mem::replace(&mut self.state, Err(Error::HandshakeNotComplete)) {
+            Ok(state) => state,
+            Err(e) => {
+                self.state = Err(e.clone());
+                return Err(e);
+            }
+        };
+
+        match state.resume(&mut Context {
+            common: &mut self.common_state,
+            data: &mut self.data,
+        }) {
+            Ok(new) => self.state = Ok(new),
+            Err(e) => {
+                self.state = Err(e.clone());
+                return Err(e);
+            }
         }
+
+        self.process_new_packets()
+    }
+
+    pub(crate) fn request_client_certificate(&mut self) -> Result<(), Error> {
+        let st = self
+            .state
+            .as_mut()
+            .map_err(|e| e.clone())?;
+        st.request_client_certificate(&mut Context {
+            common: &mut self.common_state,
+            data: &mut self.data,
+        })
+    }
+
+    pub(crate) fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
+        let mut state = self.state.as_mut().ok();
         self.common_state
-            .send_some_plaintext(buf)
+            .send_some_plaintext(buf, &mut |common| {
+                if let Some(st) = &mut state {
+                    st.perhaps_write_key_update(common);
+                }
+            })
     }
 
     /// Read TLS content from `rd` into the internal buffer.
@@ -760,6 +873,36 @@ impl<Data> ConnectionCommon<Data> {
         }
     }
 
+    /// Send a TLS1.3 `KeyUpdate` message, so that data we send from now
+    /// on is encrypted with new keys.
+    ///
+    /// If `request_peer` is true, the peer is also asked to update the keys
+    /// it sends with.  It does so before the next data it sends.
+    ///
+    /// Keys are also updated automatically before the cipher suite's
+    /// confidentiality limit is reached, so this is not needed to keep
+    /// long-lived connections secure.
+    ///
+    /// This function fails if called prior to the handshake completing,
+    /// and for TLS1.2, QUIC and DTLS connections which do not have
+    /// `KeyUpdate` messages.
+    pub fn refresh_traffic_keys(&mut self, request_peer: bool) -> Result<(), Error> {
+        if self.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if !self.is_tls13() || self.is_quic() || self.is_dtls() {
+            return Err(Error::General(
+                "KeyUpdate is only possible with TLS1.3 over TCP".into(),
+            ));
+        }
+
+        match self.state.as_mut() {
+            Ok(st) => st.refresh_traffic_keys(&mut self.common_state, request_peer),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
     /// Extract secrets, so they can be used when configuring kTLS, for example.
     #[cfg(feature = "secret_extraction")]
     pub fn extract_secrets(self) -> Result<ExtractedSecrets, Error> {
@@ -788,6 +931,43 @@ impl<Data> ConnectionCommon<Data> {
     }
 }
 
+#[cfg(feature = "dtls")]
+impl<Data> ConnectionCommon<Data> {
+    /// Process a complete handshake message, reassembled from DTLS
+    /// fragments and given in its TLS encoding.
+    pub(crate) fn read_dtls_hs(&mut self, message: &[u8]) -> Result<(), Error> {
+        self.message_deframer
+            .push(ProtocolVersion::TLSv1_3, message)?;
+        self.process_new_packets()?;
+        Ok(())
+    }
+
+    pub(crate) fn read_dtls_alert(&mut self, alert: &AlertMessagePayload) -> Result<(), Error> {
+        self.dtls_error()?;
+        let result = self.common_state.process_alert(alert);
+        if let Err(e) = &result {
+            self.state = Err(e.clone());
+        }
+        result
+    }
+
+    /// Returns the error which ended this connection, if any.
+    pub(crate) fn dtls_error(&self) -> Result<(), Error> {
+        match &self.state {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// End the connection because of a problem found by the DTLS record
+    /// layer.
+    pub(crate) fn fail_dtls(&mut self, desc: AlertDescription, err: Error) -> Error {
+        self.common_state.send_fatal_alert(desc);
+        self.state = Err(err.clone());
+        err
+    }
+}
+
 impl<T> Deref for ConnectionCommon<T> {
     type Target = CommonState;
 
@@ -819,7 +999,20 @@ pub struct CommonState {
     has_seen_eof: bool,
     received_middlebox_ccs: u8,
     pub(crate) peer_certificates: Option<Vec<key::Certificate>>,
+    /// The peer's certificates, while the handshake waits for the
+    /// application to verify them.
+    pending_cert_verification: Option<Vec<key::Certificate>>,
+    /// The application's verdict on `pending_cert_verification`.
+    cert_verification: Option<Result<(), Error>>,
     message_fragmenter: MessageFragmenter,
+    /// The `record_size_limit` we advertise, if configured.
+    record_size_limit: Option<usize>,
+    /// The largest plaintext accepted in a protected record, once
+    /// a `record_size_limit` has been negotiated.
+    received_record_size_limit: Option<usize>,
+    /// The negotiated `max_fragment_length`, which applies to records
+    /// in both directions.
+    max_fragment_len: Option<usize>,
     received_plaintext: ChunkVecBuffer,
     sendable_plaintext: ChunkVecBuffer,
     pub(crate) sendable_tls: ChunkVecBuffer,
@@ -828,6 +1021,8 @@ pub struct CommonState {
     pub(crate) protocol: Protocol,
     #[cfg(feature = "quic")]
     pub(crate) quic: Quic,
+    #[cfg(feature = "dtls")]
+    pub(crate) dtls: Dtls,
     #[cfg(feature = "secret_extraction")]
     pub(crate) enable_secret_extraction: bool,
 }
@@ -849,7 +1044,12 @@ impl CommonState {
             has_seen_eof: false,
             received_middlebox_ccs: 0,
             peer_certificates: None,
+            pending_cert_verification: None,
+            cert_verification: None,
             message_fragmenter: MessageFragmenter::default(),
+            record_size_limit: None,
+            received_record_size_limit: None,
+            max_fragment_len: None,
             received_plaintext: ChunkVecBuffer::new(Some(DEFAULT_RECEIVED_PLAINTEXT_LIMIT)),
             sendable_plaintext: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
             sendable_tls: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
@@ -857,6 +1057,8 @@ impl CommonState {
             protocol: Protocol::Tcp,
             #[cfg(feature = "quic")]
             quic: Quic::new(),
+            #[cfg(feature = "dtls")]
+            dtls: Dtls::new(),
             #[cfg(feature = "secret_extraction")]
             enable_secret_extraction: false,
         }
@@ -891,11 +1093,29 @@ impl CommonState {
     /// For servers, this is the certificate chain of the client,
     /// if client authentication was completed.
     ///
+    /// If the peer authenticated with a raw public key (RFC7250), this
+    /// has one item: its DER-encoded `SubjectPublicKeyInfo`.
+    ///
     /// The return value is None until this value is available.
     pub fn peer_certificates(&self) -> Option<&[key::Certificate]> {
         self.peer_certificates.as_deref()
     }
 
+    /// The peer's certificate chain, while the handshake waits for the
+    /// application to verify it.  The end-entity certificate is first.
+    ///
+    /// This is only ever `Some` if `defer_cert_verification` is set in
+    /// the [`ClientConfig`] or [`ServerConfig`].  No more received TLS
+    /// messages are processed until the verification is completed with
+    /// [`ConnectionCommon::complete_cert_verification`].
+    ///
+    /// [`ClientConfig`]: crate::ClientConfig
+    /// [`ServerConfig`]: crate::ServerConfig
+    pub fn pending_cert_verification(&self) -> Option<&[key::Certificate]> {
+        self.pending_cert_verification
+            .as_deref()
+    }
+
     /// Retrieves the protocol agreed with the peer via ALPN.
     ///
     /// A return value of `None` after handshake completion
@@ -962,8 +1182,16 @@ impl CommonState {
     ///
     /// If internal buffers are too small, this function will not accept
     /// all the data.
-    pub(crate) fn send_some_plaintext(&mut self, data: &[u8]) -> usize {
-        self.send_plain(data, Limit::Yes)
+    ///
+    /// `update_keys` is called before each record is encrypted, so that
+    /// the keys can be updated before they reach their confidentiality
+    /// limit, however much data is sent at once.
+    pub(crate) fn send_some_plaintext(
+        &mut self,
+        data: &[u8],
+        update_keys: &mut dyn FnMut(&mut Self),
+    ) -> usize {
+        self.send_plain(data, Limit::Yes, update_keys)
     }
 
     pub(crate) fn send_early_plaintext(&mut self, data: &[u8]) -> usize {
@@ -975,7 +1203,8 @@ impl CommonState {
             return 0;
         }
 
-        self.send_appdata_encrypt(data, Limit::Yes)
+        // Early data keys are never updated.
+        self.send_appdata_encrypt(data, Limit::Yes, &mut |_| {})
     }
 
     // Changing the keys must not span any fragmented handshake
@@ -1010,7 +1239,14 @@ impl CommonState {
     }
 
     /// Like send_msg_encrypt, but operate on an appdata directly.
-    fn send_appdata_encrypt(&mut self, payload: &[u8], limit: Limit) -> usize {
+    ///
+    /// `update_keys` is called before each fragment is encrypted.
+    fn send_appdata_encrypt(
+        &mut self,
+        payload: &[u8],
+        limit: Limit,
+        update_keys: &mut dyn FnMut(&mut Self),
+    ) -> usize {
         // Here, the limit on sendable_tls applies to encrypted data,
         // but we're respecting it for plaintext data -- so we'll
         // be out by whatever the cipher+record overhead is.  That's a
@@ -1028,6 +1264,7 @@ impl CommonState {
             &payload[..len],
         );
         for m in iter {
+            update_keys(self);
             self.send_single_fragment(m);
         }
 
@@ -1070,7 +1307,12 @@ impl CommonState {
     ///
     /// Returns the number of bytes written from `data`: this might
     /// be less than `data.len()` if buffer limits were exceeded.
-    fn send_plain(&mut self, data: &[u8], limit: Limit) -> usize {
+    fn send_plain(
+        &mut self,
+        data: &[u8],
+        limit: Limit,
+        update_keys: &mut dyn FnMut(&mut Self),
+    ) -> usize {
         if !self.may_send_application_data {
             // If we haven't completed handshaking, buffer
             // plaintext to send once we do.
@@ -1092,7 +1334,7 @@ impl CommonState {
             return 0;
         }
 
-        self.send_appdata_encrypt(data, limit)
+        self.send_appdata_encrypt(data, limit, update_keys)
     }
 
     pub(crate) fn start_outgoing_traffic(&mut self) {
@@ -1156,8 +1398,10 @@ impl CommonState {
             return;
         }
 
+        // This data was buffered during the handshake, so is sent with
+        // fresh keys: there is no need to update them.
         while let Some(buf) = self.sendable_plaintext.pop() {
-            self.send_plain(&buf, Limit::No);
+            self.send_plain(&buf, Limit::No, &mut |_| {});
         }
     }
 
@@ -1187,6 +1431,27 @@ impl CommonState {
                 return;
             }
         }
+        #[cfg(feature = "dtls")]
+        {
+            if let Protocol::Dtls = self.protocol {
+                let typ = match m.payload {
+                    MessagePayload::Alert(_) => ContentType::Alert,
+                    _ => {
+                        debug_assert!(
+                            matches!(m.payload, MessagePayload::Handshake { .. }),
+                            "DTLS1.3 has no ChangeCipherSpec messages"
+                        );
+                        ContentType::Handshake
+                    }
+                };
+                let mut bytes = Vec::new();
+                m.payload.encode(&mut bytes);
+                self.dtls
+                    .queue
+                    .push_back((self.dtls.write_epoch, typ, bytes));
+                return;
+            }
+        }
         if !must_encrypt {
             let msg = &m.into();
             let iter = self
@@ -1278,6 +1543,46 @@ impl CommonState {
             .set_max_fragment_size(new)
     }
 
+    pub(crate) fn set_record_size_limit(&mut self, limit: Option<usize>) -> Result<(), Error> {
+        self.record_size_limit = match limit {
+            Some(MIN_RECORD_SIZE_LIMIT..=MAX_RECORD_SIZE_LIMIT) | None => limit,
+            _ => return Err(Error::BadRecordSizeLimit),
+        };
+        Ok(())
+    }
+
+    /// Apply the `record_size_limit` sent by the peer, and start
+    /// enforcing our own.
+    ///
+    /// Returns the limit we advertise, which is the protocol maximum if
+    /// none was configured.  Must be called once the protocol version is
+    /// known, as in TLS1.3 the limit includes the inner content type.
+    pub(crate) fn negotiate_record_size_limit(&mut self, peer_limit: u16) -> Result<u16, Error> {
+        let peer_limit = usize::from(peer_limit);
+        if peer_limit < MIN_RECORD_SIZE_LIMIT {
+            return Err(self.illegal_param("record_size_limit too small"));
+        }
+
+        let overhead = if self.is_tls13() { 1 } else { 0 };
+        let max = MAX_FRAGMENT_LEN + overhead;
+        self.message_fragmenter
+            .reduce_max_fragment_len(peer_limit.min(max) - overhead);
+
+        let our_limit = self
+            .record_size_limit
+            .map_or(max, |limit| limit.min(max));
+        self.received_record_size_limit = Some(our_limit - overhead);
+        Ok(our_limit as u16)
+    }
+
+    /// Apply a negotiated `max_fragment_length` to the records we send
+    /// and receive.
+    pub(crate) fn apply_max_fragment_length(&mut self, max_fragment_len: usize) {
+        self.message_fragmenter
+            .reduce_max_fragment_len(max_fragment_len);
+        self.max_fragment_len = Some(max_fragment_len);
+    }
+
     pub(crate) fn get_alpn_protocol(&self) -> Option<&[u8]> {
         self.alpn_protocol
             .as_ref()
@@ -1318,6 +1623,49 @@ impl CommonState {
         #[cfg(not(feature = "quic"))]
         false
     }
+
+    #[cfg(feature = "dtls")]
+    pub(crate) fn has_received_close_notify(&self) -> bool {
+        self.has_received_close_notify
+    }
+
+    pub(crate) fn is_dtls(&self) -> bool {
+        #[cfg(feature = "dtls")]
+        {
+            self.protocol == Protocol::Dtls
+        }
+        #[cfg(not(feature = "dtls"))]
+        false
+    }
+
+    /// The version number `version` goes by on the wire: DTLS numbers
+    /// its versions differently from TLS.
+    pub(crate) fn wire_version(&self, version: ProtocolVersion) -> ProtocolVersion {
+        #[cfg(feature = "dtls")]
+        if self.is_dtls() {
+            return match version {
+                ProtocolVersion::TLSv1_2 => ProtocolVersion::DTLSv1_2,
+                ProtocolVersion::TLSv1_3 => ProtocolVersion::DTLSv1_3,
+                _ => version,
+            };
+        }
+        version
+    }
+
+    /// The inverse of `wire_version`.  For DTLS, TLS version numbers
+    /// are mapped to `ProtocolVersion::Unknown` so they are never
+    /// mistaken for a supported version.
+    pub(crate) fn version_from_wire(&self, version: ProtocolVersion) -> ProtocolVersion {
+        #[cfg(feature = "dtls")]
+        if self.is_dtls() {
+            return match version {
+                ProtocolVersion::DTLSv1_2 => ProtocolVersion::TLSv1_2,
+                ProtocolVersion::DTLSv1_3 => ProtocolVersion::TLSv1_3,
+                _ => ProtocolVersion::Unknown(version.get_u16()),
+            };
+        }
+        version
+    }
 }
 
 pub(crate) trait State<Data>: Send + Sync {
@@ -1342,6 +1690,32 @@ pub(crate) trait State<Data>: Send + Sync {
     }
 
     fn perhaps_write_key_update(&mut self, _cx: &mut CommonState) {}
+
+    fn refresh_traffic_keys(
+        &mut self,
+        _common: &mut CommonState,
+        _request_peer: bool,
+    ) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
+
+    /// Start post-handshake client authentication; only meaningful for
+    /// TLS1.3 servers.
+    fn request_client_certificate(&mut self, _cx: &mut Context<'_, Data>) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
+
+    /// Whether the handshake is waiting for the application, in which
+    /// case no more messages are processed until `resume` is called.
+    fn is_suspended(&self) -> bool {
+        false
+    }
+
+    /// Continue a suspended handshake, once the application has supplied
+    /// what it was waiting for.
+    fn resume(self: Box<Self>, _cx: &mut Context<'_, Data>) -> Result<Box<dyn State<Data>>, Error> {
+        Err(Error::General("handshake is not suspended".into()))
+    }
 }
 
 pub(crate) struct Context<'a, Data> {
@@ -1349,13 +1723,59 @@ pub(crate) struct Context<'a, Data> {
     pub(crate) data: &'a mut Data,
 }
 
+/// The handshake is suspended until the application has verified the
+/// peer's certificates, after which it continues with `next`.
+pub(crate) struct ExpectCertVerification<Data> {
+    next: Box<dyn State<Data>>,
+    send_cert_error_alert: fn(&mut CommonState, Error) -> Error,
+}
+
+impl<Data: 'static> ExpectCertVerification<Data> {
+    /// Ask the application to verify `cert_chain`.  If it rejects them,
+    /// `send_cert_error_alert` tells the peer why.
+    pub(crate) fn new(
+        common: &mut CommonState,
+        cert_chain: Vec<key::Certificate>,
+        next: Box<dyn State<Data>>,
+        send_cert_error_alert: fn(&mut CommonState, Error) -> Error,
+    ) -> Box<dyn State<Data>> {
+        common.pending_cert_verification = Some(cert_chain);
+        Box::new(Self {
+            next,
+            send_cert_error_alert,
+        })
+    }
+}
+
+impl<Data: 'static> State<Data> for ExpectCertVerification<Data> {
+    fn handle(
+        self: Box<Self>,
+        _cx: &mut Context<'_, Data>,
+        m: Message,
+    ) -> Result<Box<dyn State<Data>>, Error> {
+        Err(inappropriate_message(&m.payload, &[]))
+    }
+
+    fn is_suspended(&self) -> bool {
+        true
+    }
+
+    fn resume(self: Box<Self>, cx: &mut Context<'_, Data>) -> Result<Box<dyn State<Data>>, Error> {
+        match cx.common.cert_verification.take() {
+            Some(Ok(())) => Ok(self.next),
+            Some(Err(err)) => Err((self.send_cert_error_alert)(cx.common, err)),
+            None => Err(Error::HandshakeNotComplete),
+        }
+    }
+}
+
 #[cfg(feature = "quic")]
 pub(crate) struct Quic {
     /// QUIC transport parameters received from the peer during the handshake
     pub(crate) params: Option<Vec<u8>>,
     pub(crate) alert: Option<AlertDescription>,
     pub(crate) hs_queue: VecDeque<(bool, Vec<u8>)>,
-    pub(crate) early_secret: Option<ring::hkdf::Prk>,
+    pub(crate) early_secret: Option<crate::crypto::hkdf::Prk>,
     pub(crate) hs_secrets: Option<quic::Secrets>,
     pub(crate) traffic_secrets: Option<quic::Secrets>,
     /// Whether keys derived from traffic_secrets have been passed to the QUIC implementation
@@ -1377,6 +1797,62 @@ impl Quic {
     }
 }
 
+#[cfg(feature = "dtls")]
+pub(crate) struct Dtls {
+    /// Handshake and alert messages to send, with the epoch whose keys
+    /// protect them
+    pub(crate) queue: VecDeque<(u64, ContentType, Vec<u8>)>,
+    /// Epoch of the newest keys we send with
+    pub(crate) write_epoch: u64,
+    /// Epoch of the newest keys the peer sends with
+    pub(crate) read_epoch: u64,
+    /// Secrets from the key schedule not yet taken by the record layer
+    pub(crate) write_secrets: Vec<(u64, &'static Tls13CipherSuite, crate::crypto::hkdf::Prk)>,
+    pub(crate) read_secrets: Vec<(u64, &'static Tls13CipherSuite, crate::crypto::hkdf::Prk)>,
+}
+
+#[cfg(feature = "dtls")]
+impl Dtls {
+    fn new() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            write_epoch: 0,
+            read_epoch: 0,
+            write_secrets: Vec::new(),
+            read_secrets: Vec::new(),
+        }
+    }
+
+    pub(crate) fn set_write_secret(
+        &mut self,
+        suite: &'static Tls13CipherSuite,
+        secret: crate::crypto::hkdf::Prk,
+    ) {
+        self.write_epoch = Self::next_epoch(self.write_epoch);
+        self.write_secrets
+            .push((self.write_epoch, suite, secret));
+    }
+
+    pub(crate) fn set_read_secret(
+        &mut self,
+        suite: &'static Tls13CipherSuite,
+        secret: crate::crypto::hkdf::Prk,
+    ) {
+        self.read_epoch = Self::next_epoch(self.read_epoch);
+        self.read_secrets
+            .push((self.read_epoch, suite, secret));
+    }
+
+    /// Epoch 1 is reserved for early data, which DTLS connections never
+    /// send; the handshake keys are epoch 2 and each later key follows.
+    fn next_epoch(epoch: u64) -> u64 {
+        match epoch {
+            0 => 2,
+            n => n + 1,
+        }
+    }
+}
+
 /// Side of the connection.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Side {
@@ -0,0 +1,243 @@
+use crate::error::Error;
+use crate::x509::{self, Issuer, ParsedCert};
+
+use ring::io::der;
+
+/// A certificate revocation list (CRL), as described in RFC5280 section 5.
+///
+/// CRLs are given to [`AllowAnyAuthenticatedClient`] and
+/// [`AllowAnyAnonymousOrAuthenticatedClient`] to reject client certificates
+/// (or intermediates) that their issuer has revoked.
+///
+/// Only complete CRLs for certificates issued by the CRL's issuer are
+/// supported: a CRL with critical extensions (such as a delta CRL, or an
+/// indirect CRL) is refused.
+///
+/// [`AllowAnyAuthenticatedClient`]: crate::server::AllowAnyAuthenticatedClient
+/// [`AllowAnyAnonymousOrAuthenticatedClient`]: crate::server::AllowAnyAnonymousOrAuthenticatedClient
+#[derive(Clone, Debug)]
+pub struct CertRevocationList {
+    /// The complete `TBSCertList`, which is the signed data.
+    tbs: Vec<u8>,
+    signature_alg: Vec<u8>,
+    signature: Vec<u8>,
+    /// The contents of the issuer `Name`.
+    issuer: Vec<u8>,
+    next_update: Option<u64>,
+    /// Serial numbers of the revoked certificates, sorted.
+    revoked: Vec<Vec<u8>>,
+}
+
+impl CertRevocationList {
+    /// Parse a DER-encoded `CertificateList`.
+    ///
+    /// The signature is not checked here, because the issuer is not yet
+    /// known: that happens each time the CRL is used.
+    pub fn new(der: &[u8]) -> Result<Self, Error> {
+        Self::parse(der).ok_or_else(|| {
+            Error::General("invalid or unsupported certificate revocation list".to_string())
+        })
+    }
+
+    fn parse(crl: &[u8]) -> Option<Self> {
+        let crl = match x509::read_der(crl, der::Tag::Sequence)? {
+            (crl, []) => crl,
+            _ => return None,
+        };
+        let (tbs, rest) = x509::read_der_element(crl, der::Tag::Sequence)?;
+        let (signature_alg, rest) = x509::read_der(rest, der::Tag::Sequence)?;
+        let signature = match x509::read_der(rest, der::Tag::BitString)? {
+            ([0, signature @ ..], []) => signature,
+            _ => return None,
+        };
+
+        let (fields, _) = x509::read_der(tbs, der::Tag::Sequence)?;
+        // version is optional.
+        let fields = match x509::read_der(fields, der::Tag::Integer) {
+            Some((_, rest)) => rest,
+            None => fields,
+        };
+        let (_signature, fields) = x509::read_der(fields, der::Tag::Sequence)?;
+        let (issuer, fields) = x509::read_der(fields, der::Tag::Sequence)?;
+        let (_this_update, fields) = x509::read_time(fields)?;
+        let (next_update, fields) = match x509::read_time(fields) {
+            Some((next_update, rest)) => (Some(next_update), rest),
+            None => (None, fields),
+        };
+
+        let mut revoked = Vec::new();
+        let fields = match x509::read_der(fields, der::Tag::Sequence) {
+            Some((mut entries, rest)) => {
+                while !entries.is_empty() {
+                    let (entry, rest) = x509::read_der(entries, der::Tag::Sequence)?;
+                    entries = rest;
+
+                    let (serial, entry) = x509::read_der(entry, der::Tag::Integer)?;
+                    let (_revocation_date, entry) = x509::read_time(entry)?;
+                    if !entry.is_empty() && has_critical_extension(entry)? {
+                        return None;
+                    }
+                    revoked.push(serial.to_vec());
+                }
+                rest
+            }
+            None => fields,
+        };
+
+        match x509::read_der(fields, der::Tag::ContextSpecificConstructed0) {
+            Some((extensions, [])) => {
+                if has_critical_extension(extensions)? {
+                    return None;
+                }
+            }
+            Some(_) => return None,
+            None if fields.is_empty() => {}
+            None => return None,
+        }
+
+        revoked.sort_unstable();
+
+        Some(Self {
+            tbs: tbs.to_vec(),
+            signature_alg: signature_alg.to_vec(),
+            signature: signature.to_vec(),
+            issuer: issuer.to_vec(),
+            next_update,
+            revoked,
+        })
+    }
+
+    /// Check `cert`, issued by `issuer`, against this CRL at `now` (in
+    /// seconds since the UNIX epoch).
+    ///
+    /// This does nothing if this CRL is from a different issuer.
+    pub(crate) fn check(&self, cert: &ParsedCert, issuer: &Issuer, now: u64) -> Result<(), Error> {
+        if self.issuer != cert.issuer {
+            return Ok(());
+        }
+
+        if !x509::verify_signature(issuer.spki, &self.signature_alg, &self.tbs, &self.signature) {
+            return Err(Error::InvalidCertificateData(
+                "certificate revocation list has invalid signature".to_string(),
+            ));
+        }
+
+        if self
+            .next_update
+            .map_or(false, |next_update| next_update <= now)
+        {
+            return Err(Error::InvalidCertificateData(
+                "certificate revocation list is expired".to_string(),
+            ));
+        }
+
+        match self
+            .revoked
+            .binary_search_by(|serial| serial.as_slice().cmp(cert.serial))
+        {
+            Ok(_) => Err(Error::CertificateRevoked),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// Return whether the DER `Extensions` SEQUENCE at the front of `input`
+/// contains a critical extension.
+fn has_critical_extension(input: &[u8]) -> Option<bool> {
+    let (mut extensions, _) = x509::read_der(input, der::Tag::Sequence)?;
+
+    while !extensions.is_empty() {
+        let (extension, rest) = x509::read_der(extensions, der::Tag::Sequence)?;
+        extensions = rest;
+
+        let (_id, extension) = x509::read_der(extension, der::Tag::OID)?;
+        if let Some(([0xff], _)) = x509::read_der(extension, der::Tag::Boolean) {
+            return Some(true);
+        }
+    }
+
+    Some(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// thisUpdate of the test CRL, plus a day.
+    const NOW: u64 = 1_792_207_621 + 86_400;
+
+    fn crl() -> CertRevocationList {
+        CertRevocationList::new(include_bytes!("testdata/crl-inter.der")).unwrap()
+    }
+
+    fn check(crl: &CertRevocationList, cert: &[u8], now: u64) -> Result<(), Error> {
+        let cert = ParsedCert::parse(cert).unwrap();
+        let issuer = ParsedCert::parse(include_bytes!("testdata/ocsp-issuer.der")).unwrap();
+        let issuer = Issuer {
+            subject: issuer.subject,
+            spki: issuer.spki,
+        };
+        crl.check(&cert, &issuer, now)
+    }
+
+    #[test]
+    fn reports_revoked_certificate() {
+        assert_eq!(
+            check(&crl(), include_bytes!("testdata/ocsp-end.der"), NOW),
+            Err(Error::CertificateRevoked)
+        );
+    }
+
+    #[test]
+    fn ignores_other_certificates() {
+        // The intermediate has a different issuer, so the CRL does not apply to it.
+        assert_eq!(
+            check(&crl(), include_bytes!("testdata/ocsp-issuer.der"), NOW),
+            Ok(())
+        );
+
+        // Revoked certificates are identified by serial number.
+        let mut other_serial = crl();
+        other_serial.revoked = vec![vec![0x01, 0xc9]];
+        assert_eq!(
+            check(&other_serial, include_bytes!("testdata/ocsp-end.der"), NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn checks_signature() {
+        let mut crl = crl();
+        let last = crl.signature.len() - 1;
+        crl.signature[last] ^= 1;
+        assert_eq!(
+            check(&crl, include_bytes!("testdata/ocsp-end.der"), NOW),
+            Err(Error::InvalidCertificateData(
+                "certificate revocation list has invalid signature".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn checks_expiry() {
+        let next_update = crl().next_update.unwrap();
+        assert_eq!(
+            check(&crl(), include_bytes!("testdata/ocsp-end.der"), next_update),
+            Err(Error::InvalidCertificateData(
+                "certificate revocation list is expired".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let der = include_bytes!("testdata/crl-inter.der");
+        assert!(CertRevocationList::new(&der[..der.len() - 1]).is_err());
+
+        let mut trailing = der.to_vec();
+        trailing.push(0);
+        assert!(CertRevocationList::new(&trailing).is_err());
+
+        assert!(CertRevocationList::new(&[]).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::error::Error;
+
+use std::fmt::Debug;
+
+/// The length of the nonce used by every AEAD in TLS.
+pub const NONCE_LEN: usize = 12;
+
+/// Describes a single AEAD algorithm, such as AES-128-GCM.
+pub trait Algorithm: Send + Sync + Debug {
+    /// The length of a key, in bytes.
+    fn key_len(&self) -> usize;
+
+    /// The length of the authentication tag, in bytes.
+    fn tag_len(&self) -> usize;
+
+    /// Prepare `key` for use.  `key` is [`Algorithm::key_len()`] bytes long.
+    fn new_key(&self, key: &[u8]) -> Result<Box<dyn Key>, Error>;
+
+    /// Prepare `key` for QUIC header protection, or for DTLS1.3 record
+    /// number encryption, using the block cipher underlying this AEAD.
+    ///
+    /// `key` is [`Algorithm::key_len()`] bytes long.
+    fn new_header_protection_key(&self, key: &[u8]) -> Result<Box<dyn HeaderProtectionKey>, Error>;
+}
+
+/// A key for an AEAD [`Algorithm`], ready for use.
+pub trait Key: Send + Sync {
+    /// Encrypt `in_out` in place, authenticating it along with `aad`,
+    /// and return the authentication tag.
+    fn seal_in_place_separate_tag(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<Tag, Error>;
+
+    /// Authenticate and decrypt `in_out` in place.  `in_out` is the
+    /// ciphertext followed by the tag, and `aad` is the associated data.
+    ///
+    /// Returns the length of the plaintext, which is at the start of
+    /// `in_out`.  Authentication failures must be reported as
+    /// [`Error::DecryptError`].
+    fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<usize, Error>;
+}
+
+/// A key for masking packet headers, as QUIC and DTLS1.3 do.
+pub trait HeaderProtectionKey: Send + Sync {
+    /// The number of bytes of ciphertext sampled to make a mask.
+    fn sample_len(&self) -> usize;
+
+    /// Compute the mask for `sample`, which is
+    /// [`HeaderProtectionKey::sample_len()`] bytes long.
+    fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5], Error>;
+}
+
+/// An AEAD authentication tag, stored as a value.
+#[derive(Clone)]
+pub struct Tag {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Tag {
+    /// Build a tag by copying a byte slice of no more than
+    /// [`Tag::MAX_LEN`] bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut tag = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= Self::MAX_LEN);
+        tag.buf[..bytes.len()].copy_from_slice(bytes);
+        tag
+    }
+
+    /// The longest tag supported.
+    pub const MAX_LEN: usize = 16;
+}
+
+impl AsRef<[u8]> for Tag {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
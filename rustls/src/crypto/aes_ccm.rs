@@ -0,0 +1,115 @@
+use crate::crypto;
+use crate::crypto::aead::{Tag, NONCE_LEN};
+use crate::error::Error;
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use ccm::aead::generic_array::typenum::Unsigned;
+use ccm::aead::generic_array::GenericArray;
+use ccm::aead::AeadInPlace;
+use ccm::consts::{U12, U16, U8};
+use ccm::Ccm;
+
+/// AES-128 in counter with CBC-MAC mode, with a 16-byte tag.
+pub static AES_128_CCM: AesCcm = AesCcm { tag_len: 16 };
+
+/// AES-128 in counter with CBC-MAC mode, with an 8-byte tag.
+pub static AES_128_CCM_8: AesCcm = AesCcm { tag_len: 8 };
+
+/// AES-128-CCM, implemented by the RustCrypto `aes` and `ccm` crates.
+///
+/// Header protection uses AES-128 directly, as RFC9001 section 5.4.3
+/// requires for the AES-based suites.
+#[derive(Debug)]
+pub struct AesCcm {
+    tag_len: usize,
+}
+
+impl crypto::aead::Algorithm for AesCcm {
+    fn key_len(&self) -> usize {
+        16
+    }
+
+    fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+
+    fn new_key(&self, key: &[u8]) -> Result<Box<dyn crypto::aead::Key>, Error> {
+        let invalid = |_| Error::General("invalid AEAD key".into());
+        Ok(match self.tag_len {
+            16 => Box::new(CcmKey(
+                Ccm::<Aes128, U16, U12>::new_from_slice(key).map_err(invalid)?,
+            )),
+            _ => Box::new(CcmKey(
+                Ccm::<Aes128, U8, U12>::new_from_slice(key).map_err(invalid)?,
+            )),
+        })
+    }
+
+    fn new_header_protection_key(
+        &self,
+        key: &[u8],
+    ) -> Result<Box<dyn crypto::aead::HeaderProtectionKey>, Error> {
+        let key = Aes128::new_from_slice(key)
+            .map_err(|_| Error::General("invalid header protection key".into()))?;
+        Ok(Box::new(HeaderProtectionKey(key)))
+    }
+}
+
+struct CcmKey<A>(A);
+
+impl<A: AeadInPlace + Send + Sync> crypto::aead::Key for CcmKey<A> {
+    fn seal_in_place_separate_tag(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<Tag, Error> {
+        self.0
+            .encrypt_in_place_detached(GenericArray::from_slice(&nonce), aad, in_out)
+            .map(|tag| Tag::new(&tag))
+            .map_err(|_| Error::General("encrypt failed".to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let plain_len = in_out
+            .len()
+            .checked_sub(A::TagSize::USIZE)
+            .ok_or(Error::DecryptError)?;
+        let (plain, tag) = in_out.split_at_mut(plain_len);
+        self.0
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                aad,
+                plain,
+                GenericArray::from_slice(tag),
+            )
+            .map(|_| plain_len)
+            .map_err(|_| Error::DecryptError)
+    }
+}
+
+struct HeaderProtectionKey(Aes128);
+
+impl crypto::aead::HeaderProtectionKey for HeaderProtectionKey {
+    fn sample_len(&self) -> usize {
+        16
+    }
+
+    fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5], Error> {
+        if sample.len() != self.sample_len() {
+            return Err(Error::General("sample of invalid length".into()));
+        }
+
+        let mut block = *GenericArray::from_slice(sample);
+        self.0.encrypt_block(&mut block);
+        let mut mask = [0u8; 5];
+        mask.copy_from_slice(&block[..5]);
+        Ok(mask)
+    }
+}
@@ -0,0 +1,377 @@
+//! Finite-field Diffie-Hellman groups from RFC7919.
+//!
+//! The modular exponentiation is done by `crypto-bigint`, whose
+//! Montgomery arithmetic takes time independent of the secret exponent.
+
+use crate::crypto::ring::RING;
+use crate::crypto::{
+    ActiveKeyExchange, CryptoProvider, FfdheGroup, GetRandomFailed, SharedSecret, SupportedKxGroup,
+};
+use crate::error::Error;
+use crate::msgs::enums::NamedGroup;
+
+use crypto_bigint::modular::runtime_mod::{DynResidue, DynResidueParams};
+use crypto_bigint::{Uint, U2048, U3072, U4096, U512};
+
+use std::fmt;
+
+/// A finite-field Diffie-Hellman group from RFC7919.
+///
+/// These can be used with TLS1.3, and with the TLS1.2 DHE cipher suites.
+/// They are much slower than the elliptic curve groups, so are only
+/// worth enabling for peers that support nothing else.
+///
+/// Private exponents are generated with the random number generator of a
+/// [`CryptoProvider`]: *ring*'s for the statics here, or another one given
+/// to [`FfdheKxGroup::with_provider`].
+pub struct FfdheKxGroup {
+    name: NamedGroup,
+    p: &'static [u8],
+    /// Length of our private exponents.  RFC7919 section 5.2 recommends
+    /// at least twice the group's security level in bits.
+    exponent_len: usize,
+    provider: &'static dyn CryptoProvider,
+}
+
+impl FfdheKxGroup {
+    /// Returns this group, generating private exponents with
+    /// `provider.fill_random()`.
+    pub fn with_provider(&self, provider: &'static dyn CryptoProvider) -> Self {
+        Self {
+            name: self.name,
+            p: self.p,
+            exponent_len: self.exponent_len,
+            provider,
+        }
+    }
+}
+
+/// Ephemeral DH with the 2048-bit group of RFC7919.
+pub static FFDHE2048: FfdheKxGroup = FfdheKxGroup {
+    name: NamedGroup::FFDHE2048,
+    p: &FFDHE2048_P,
+    exponent_len: 32,
+    provider: RING,
+};
+
+/// Ephemeral DH with the 3072-bit group of RFC7919.
+pub static FFDHE3072: FfdheKxGroup = FfdheKxGroup {
+    name: NamedGroup::FFDHE3072,
+    p: &FFDHE3072_P,
+    exponent_len: 40,
+    provider: RING,
+};
+
+/// Ephemeral DH with the 4096-bit group of RFC7919.
+pub static FFDHE4096: FfdheKxGroup = FfdheKxGroup {
+    name: NamedGroup::FFDHE4096,
+    p: &FFDHE4096_P,
+    exponent_len: 48,
+    provider: RING,
+};
+
+/// All the RFC7919 groups use a generator of 2.
+static FFDHE_G: [u8; 1] = [2];
+
+impl SupportedKxGroup for FfdheKxGroup {
+    fn start(&self) -> Result<Box<dyn ActiveKeyExchange>, GetRandomFailed> {
+        let mut exponent = vec![0u8; self.exponent_len];
+        self.provider
+            .fill_random(&mut exponent)?;
+        // The exponent must be nonzero; setting the top bit also fixes
+        // its length.
+        exponent[0] |= 0x80;
+
+        let pub_key = modpow(self.p, &FFDHE_G, &exponent).expect("generator is in range");
+
+        Ok(Box::new(FfdheKeyExchange {
+            name: self.name,
+            p: self.p,
+            exponent,
+            pub_key,
+        }))
+    }
+
+    fn name(&self) -> NamedGroup {
+        self.name
+    }
+
+    fn ffdhe_group(&self) -> Option<FfdheGroup<'static>> {
+        Some(FfdheGroup {
+            p: self.p,
+            g: &FFDHE_G,
+        })
+    }
+}
+
+impl fmt::Debug for FfdheKxGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name.fmt(f)
+    }
+}
+
+struct FfdheKeyExchange {
+    name: NamedGroup,
+    p: &'static [u8],
+    exponent: Vec<u8>,
+    pub_key: Vec<u8>,
+}
+
+impl ActiveKeyExchange for FfdheKeyExchange {
+    fn complete(self: Box<Self>, peer: &[u8]) -> Result<SharedSecret, Error> {
+        // As required by TLS1.3, the secret is left-padded to the length
+        // of the prime.
+        modpow(self.p, peer, &self.exponent)
+            .map(SharedSecret::from)
+            .ok_or_else(|| Error::PeerMisbehavedError("key agreement failed".to_string()))
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        &self.pub_key
+    }
+
+    fn group(&self) -> NamedGroup {
+        self.name
+    }
+}
+
+impl Drop for FfdheKeyExchange {
+    fn drop(&mut self) {
+        self.exponent
+            .iter_mut()
+            .for_each(|b| *b = 0);
+    }
+}
+
+/// Compute `base ^ exponent mod p`, as a big-endian value the length of `p`.
+///
+/// This returns `None` if `base` is not in the range (1, p - 1), which is
+/// what RFC7919 and RFC8446 require of a peer's public value, or if `p` is
+/// not the size of one of the RFC7919 primes.
+fn modpow(p: &[u8], base: &[u8], exponent: &[u8]) -> Option<Vec<u8>> {
+    match p.len() {
+        256 => modpow_sized::<{ U2048::LIMBS }>(p, base, exponent),
+        384 => modpow_sized::<{ U3072::LIMBS }>(p, base, exponent),
+        512 => modpow_sized::<{ U4096::LIMBS }>(p, base, exponent),
+        _ => None,
+    }
+}
+
+fn modpow_sized<const LIMBS: usize>(p: &[u8], base: &[u8], exponent: &[u8]) -> Option<Vec<u8>> {
+    if base.is_empty() || base.len() > p.len() || exponent.len() > U512::BYTES {
+        return None;
+    }
+
+    let base = Uint::<LIMBS>::from_be_slice(&left_pad(base, p.len()));
+    let p = Uint::<LIMBS>::from_be_slice(p);
+    if base <= Uint::ONE || base >= p.wrapping_sub(&Uint::ONE) {
+        return None;
+    }
+
+    let mut padded_exponent = left_pad(exponent, U512::BYTES);
+    let exponent_bits = exponent.len() * 8;
+    let result = DynResidue::new(&base, DynResidueParams::new(&p))
+        .pow_bounded_exp(&U512::from_be_slice(&padded_exponent), exponent_bits)
+        .retrieve();
+    padded_exponent
+        .iter_mut()
+        .for_each(|b| *b = 0);
+
+    Some(
+        result
+            .as_words()
+            .iter()
+            .rev()
+            .flat_map(|word| word.to_be_bytes())
+            .collect(),
+    )
+}
+
+fn left_pad(bytes: &[u8], len: usize) -> Vec<u8> {
+    let mut padded = vec![0u8; len - bytes.len()];
+    padded.extend_from_slice(bytes);
+    padded
+}
+
+/// The 2048-bit prime from RFC7919 appendix A.
+static FFDHE2048_P: [u8; 256] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xad, 0xf8, 0x54, 0x58, 0xa2, 0xbb, 0x4a, 0x9a,
+    0xaf, 0xdc, 0x56, 0x20, 0x27, 0x3d, 0x3c, 0xf1, 0xd8, 0xb9, 0xc5, 0x83, 0xce, 0x2d, 0x36, 0x95,
+    0xa9, 0xe1, 0x36, 0x41, 0x14, 0x64, 0x33, 0xfb, 0xcc, 0x93, 0x9d, 0xce, 0x24, 0x9b, 0x3e, 0xf9,
+    0x7d, 0x2f, 0xe3, 0x63, 0x63, 0x0c, 0x75, 0xd8, 0xf6, 0x81, 0xb2, 0x02, 0xae, 0xc4, 0x61, 0x7a,
+    0xd3, 0xdf, 0x1e, 0xd5, 0xd5, 0xfd, 0x65, 0x61, 0x24, 0x33, 0xf5, 0x1f, 0x5f, 0x06, 0x6e, 0xd0,
+    0x85, 0x63, 0x65, 0x55, 0x3d, 0xed, 0x1a, 0xf3, 0xb5, 0x57, 0x13, 0x5e, 0x7f, 0x57, 0xc9, 0x35,
+    0x98, 0x4f, 0x0c, 0x70, 0xe0, 0xe6, 0x8b, 0x77, 0xe2, 0xa6, 0x89, 0xda, 0xf3, 0xef, 0xe8, 0x72,
+    0x1d, 0xf1, 0x58, 0xa1, 0x36, 0xad, 0xe7, 0x35, 0x30, 0xac, 0xca, 0x4f, 0x48, 0x3a, 0x79, 0x7a,
+    0xbc, 0x0a, 0xb1, 0x82, 0xb3, 0x24, 0xfb, 0x61, 0xd1, 0x08, 0xa9, 0x4b, 0xb2, 0xc8, 0xe3, 0xfb,
+    0xb9, 0x6a, 0xda, 0xb7, 0x60, 0xd7, 0xf4, 0x68, 0x1d, 0x4f, 0x42, 0xa3, 0xde, 0x39, 0x4d, 0xf4,
+    0xae, 0x56, 0xed, 0xe7, 0x63, 0x72, 0xbb, 0x19, 0x0b, 0x07, 0xa7, 0xc8, 0xee, 0x0a, 0x6d, 0x70,
+    0x9e, 0x02, 0xfc, 0xe1, 0xcd, 0xf7, 0xe2, 0xec, 0xc0, 0x34, 0x04, 0xcd, 0x28, 0x34, 0x2f, 0x61,
+    0x91, 0x72, 0xfe, 0x9c, 0xe9, 0x85, 0x83, 0xff, 0x8e, 0x4f, 0x12, 0x32, 0xee, 0xf2, 0x81, 0x83,
+    0xc3, 0xfe, 0x3b, 0x1b, 0x4c, 0x6f, 0xad, 0x73, 0x3b, 0xb5, 0xfc, 0xbc, 0x2e, 0xc2, 0x20, 0x05,
+    0xc5, 0x8e, 0xf1, 0x83, 0x7d, 0x16, 0x83, 0xb2, 0xc6, 0xf3, 0x4a, 0x26, 0xc1, 0xb2, 0xef, 0xfa,
+    0x88, 0x6b, 0x42, 0x38, 0x61, 0x28, 0x5c, 0x97, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// The 3072-bit prime from RFC7919 appendix A.
+static FFDHE3072_P: [u8; 384] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xad, 0xf8, 0x54, 0x58, 0xa2, 0xbb, 0x4a, 0x9a,
+    0xaf, 0xdc, 0x56, 0x20, 0x27, 0x3d, 0x3c, 0xf1, 0xd8, 0xb9, 0xc5, 0x83, 0xce, 0x2d, 0x36, 0x95,
+    0xa9, 0xe1, 0x36, 0x41, 0x14, 0x64, 0x33, 0xfb, 0xcc, 0x93, 0x9d, 0xce, 0x24, 0x9b, 0x3e, 0xf9,
+    0x7d, 0x2f, 0xe3, 0x63, 0x63, 0x0c, 0x75, 0xd8, 0xf6, 0x81, 0xb2, 0x02, 0xae, 0xc4, 0x61, 0x7a,
+    0xd3, 0xdf, 0x1e, 0xd5, 0xd5, 0xfd, 0x65, 0x61, 0x24, 0x33, 0xf5, 0x1f, 0x5f, 0x06, 0x6e, 0xd0,
+    0x85, 0x63, 0x65, 0x55, 0x3d, 0xed, 0x1a, 0xf3, 0xb5, 0x57, 0x13, 0x5e, 0x7f, 0x57, 0xc9, 0x35,
+    0x98, 0x4f, 0x0c, 0x70, 0xe0, 0xe6, 0x8b, 0x77, 0xe2, 0xa6, 0x89, 0xda, 0xf3, 0xef, 0xe8, 0x72,
+    0x1d, 0xf1, 0x58, 0xa1, 0x36, 0xad, 0xe7, 0x35, 0x30, 0xac, 0xca, 0x4f, 0x48, 0x3a, 0x79, 0x7a,
+    0xbc, 0x0a, 0xb1, 0x82, 0xb3, 0x24, 0xfb, 0x61, 0xd1, 0x08, 0xa9, 0x4b, 0xb2, 0xc8, 0xe3, 0xfb,
+    0xb9, 0x6a, 0xda, 0xb7, 0x60, 0xd7, 0xf4, 0x68, 0x1d, 0x4f, 0x42, 0xa3, 0xde, 0x39, 0x4d, 0xf4,
+    0xae, 0x56, 0xed, 0xe7, 0x63, 0x72, 0xbb, 0x19, 0x0b, 0x07, 0xa7, 0xc8, 0xee, 0x0a, 0x6d, 0x70,
+    0x9e, 0x02, 0xfc, 0xe1, 0xcd, 0xf7, 0xe2, 0xec, 0xc0, 0x34, 0x04, 0xcd, 0x28, 0x34, 0x2f, 0x61,
+    0x91, 0x72, 0xfe, 0x9c, 0xe9, 0x85, 0x83, 0xff, 0x8e, 0x4f, 0x12, 0x32, 0xee, 0xf2, 0x81, 0x83,
+    0xc3, 0xfe, 0x3b, 0x1b, 0x4c, 0x6f, 0xad, 0x73, 0x3b, 0xb5, 0xfc, 0xbc, 0x2e, 0xc2, 0x20, 0x05,
+    0xc5, 0x8e, 0xf1, 0x83, 0x7d, 0x16, 0x83, 0xb2, 0xc6, 0xf3, 0x4a, 0x26, 0xc1, 0xb2, 0xef, 0xfa,
+    0x88, 0x6b, 0x42, 0x38, 0x61, 0x1f, 0xcf, 0xdc, 0xde, 0x35, 0x5b, 0x3b, 0x65, 0x19, 0x03, 0x5b,
+    0xbc, 0x34, 0xf4, 0xde, 0xf9, 0x9c, 0x02, 0x38, 0x61, 0xb4, 0x6f, 0xc9, 0xd6, 0xe6, 0xc9, 0x07,
+    0x7a, 0xd9, 0x1d, 0x26, 0x91, 0xf7, 0xf7, 0xee, 0x59, 0x8c, 0xb0, 0xfa, 0xc1, 0x86, 0xd9, 0x1c,
+    0xae, 0xfe, 0x13, 0x09, 0x85, 0x13, 0x92, 0x70, 0xb4, 0x13, 0x0c, 0x93, 0xbc, 0x43, 0x79, 0x44,
+    0xf4, 0xfd, 0x44, 0x52, 0xe2, 0xd7, 0x4d, 0xd3, 0x64, 0xf2, 0xe2, 0x1e, 0x71, 0xf5, 0x4b, 0xff,
+    0x5c, 0xae, 0x82, 0xab, 0x9c, 0x9d, 0xf6, 0x9e, 0xe8, 0x6d, 0x2b, 0xc5, 0x22, 0x36, 0x3a, 0x0d,
+    0xab, 0xc5, 0x21, 0x97, 0x9b, 0x0d, 0xea, 0xda, 0x1d, 0xbf, 0x9a, 0x42, 0xd5, 0xc4, 0x48, 0x4e,
+    0x0a, 0xbc, 0xd0, 0x6b, 0xfa, 0x53, 0xdd, 0xef, 0x3c, 0x1b, 0x20, 0xee, 0x3f, 0xd5, 0x9d, 0x7c,
+    0x25, 0xe4, 0x1d, 0x2b, 0x66, 0xc6, 0x2e, 0x37, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// The 4096-bit prime from RFC7919 appendix A.
+static FFDHE4096_P: [u8; 512] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xad, 0xf8, 0x54, 0x58, 0xa2, 0xbb, 0x4a, 0x9a,
+    0xaf, 0xdc, 0x56, 0x20, 0x27, 0x3d, 0x3c, 0xf1, 0xd8, 0xb9, 0xc5, 0x83, 0xce, 0x2d, 0x36, 0x95,
+    0xa9, 0xe1, 0x36, 0x41, 0x14, 0x64, 0x33, 0xfb, 0xcc, 0x93, 0x9d, 0xce, 0x24, 0x9b, 0x3e, 0xf9,
+    0x7d, 0x2f, 0xe3, 0x63, 0x63, 0x0c, 0x75, 0xd8, 0xf6, 0x81, 0xb2, 0x02, 0xae, 0xc4, 0x61, 0x7a,
+    0xd3, 0xdf, 0x1e, 0xd5, 0xd5, 0xfd, 0x65, 0x61, 0x24, 0x33, 0xf5, 0x1f, 0x5f, 0x06, 0x6e, 0xd0,
+    0x85, 0x63, 0x65, 0x55, 0x3d, 0xed, 0x1a, 0xf3, 0xb5, 0x57, 0x13, 0x5e, 0x7f, 0x57, 0xc9, 0x35,
+    0x98, 0x4f, 0x0c, 0x70, 0xe0, 0xe6, 0x8b, 0x77, 0xe2, 0xa6, 0x89, 0xda, 0xf3, 0xef, 0xe8, 0x72,
+    0x1d, 0xf1, 0x58, 0xa1, 0x36, 0xad, 0xe7, 0x35, 0x30, 0xac, 0xca, 0x4f, 0x48, 0x3a, 0x79, 0x7a,
+    0xbc, 0x0a, 0xb1, 0x82, 0xb3, 0x24, 0xfb, 0x61, 0xd1, 0x08, 0xa9, 0x4b, 0xb2, 0xc8, 0xe3, 0xfb,
+    0xb9, 0x6a, 0xda, 0xb7, 0x60, 0xd7, 0xf4, 0x68, 0x1d, 0x4f, 0x42, 0xa3, 0xde, 0x39, 0x4d, 0xf4,
+    0xae, 0x56, 0xed, 0xe7, 0x63, 0x72, 0xbb, 0x19, 0x0b, 0x07, 0xa7, 0xc8, 0xee, 0x0a, 0x6d, 0x70,
+    0x9e, 0x02, 0xfc, 0xe1, 0xcd, 0xf7, 0xe2, 0xec, 0xc0, 0x34, 0x04, 0xcd, 0x28, 0x34, 0x2f, 0x61,
+    0x91, 0x72, 0xfe, 0x9c, 0xe9, 0x85, 0x83, 0xff, 0x8e, 0x4f, 0x12, 0x32, 0xee, 0xf2, 0x81, 0x83,
+    0xc3, 0xfe, 0x3b, 0x1b, 0x4c, 0x6f, 0xad, 0x73, 0x3b, 0xb5, 0xfc, 0xbc, 0x2e, 0xc2, 0x20, 0x05,
+    0xc5, 0x8e, 0xf1, 0x83, 0x7d, 0x16, 0x83, 0xb2, 0xc6, 0xf3, 0x4a, 0x26, 0xc1, 0xb2, 0xef, 0xfa,
+    0x88, 0x6b, 0x42, 0x38, 0x61, 0x1f, 0xcf, 0xdc, 0xde, 0x35, 0x5b, 0x3b, 0x65, 0x19, 0x03, 0x5b,
+    0xbc, 0x34, 0xf4, 0xde, 0xf9, 0x9c, 0x02, 0x38, 0x61, 0xb4, 0x6f, 0xc9, 0xd6, 0xe6, 0xc9, 0x07,
+    0x7a, 0xd9, 0x1d, 0x26, 0x91, 0xf7, 0xf7, 0xee, 0x59, 0x8c, 0xb0, 0xfa, 0xc1, 0x86, 0xd9, 0x1c,
+    0xae, 0xfe, 0x13, 0x09, 0x85, 0x13, 0x92, 0x70, 0xb4, 0x13, 0x0c, 0x93, 0xbc, 0x43, 0x79, 0x44,
+    0xf4, 0xfd, 0x44, 0x52, 0xe2, 0xd7, 0x4d, 0xd3, 0x64, 0xf2, 0xe2, 0x1e, 0x71, 0xf5, 0x4b, 0xff,
+    0x5c, 0xae, 0x82, 0xab, 0x9c, 0x9d, 0xf6, 0x9e, 0xe8, 0x6d, 0x2b, 0xc5, 0x22, 0x36, 0x3a, 0x0d,
+    0xab, 0xc5, 0x21, 0x97, 0x9b, 0x0d, 0xea, 0xda, 0x1d, 0xbf, 0x9a, 0x42, 0xd5, 0xc4, 0x48, 0x4e,
+    0x0a, 0xbc, 0xd0, 0x6b, 0xfa, 0x53, 0xdd, 0xef, 0x3c, 0x1b, 0x20, 0xee, 0x3f, 0xd5, 0x9d, 0x7c,
+    0x25, 0xe4, 0x1d, 0x2b, 0x66, 0x9e, 0x1e, 0xf1, 0x6e, 0x6f, 0x52, 0xc3, 0x16, 0x4d, 0xf4, 0xfb,
+    0x79, 0x30, 0xe9, 0xe4, 0xe5, 0x88, 0x57, 0xb6, 0xac, 0x7d, 0x5f, 0x42, 0xd6, 0x9f, 0x6d, 0x18,
+    0x77, 0x63, 0xcf, 0x1d, 0x55, 0x03, 0x40, 0x04, 0x87, 0xf5, 0x5b, 0xa5, 0x7e, 0x31, 0xcc, 0x7a,
+    0x71, 0x35, 0xc8, 0x86, 0xef, 0xb4, 0x31, 0x8a, 0xed, 0x6a, 0x1e, 0x01, 0x2d, 0x9e, 0x68, 0x32,
+    0xa9, 0x07, 0x60, 0x0a, 0x91, 0x81, 0x30, 0xc4, 0x6d, 0xc7, 0x78, 0xf9, 0x71, 0xad, 0x00, 0x38,
+    0x09, 0x29, 0x99, 0xa3, 0x33, 0xcb, 0x8b, 0x7a, 0x1a, 0x1d, 0xb9, 0x3d, 0x71, 0x40, 0x00, 0x3c,
+    0x2a, 0x4e, 0xce, 0xa9, 0xf9, 0x8d, 0x0a, 0xcc, 0x0a, 0x82, 0x91, 0xcd, 0xce, 0xc9, 0x7d, 0xcf,
+    0x8e, 0xc9, 0xb5, 0x5a, 0x7f, 0x88, 0xa4, 0x6b, 0x4d, 0xb5, 0xa8, 0x51, 0xf4, 0x41, 0x82, 0xe1,
+    0xc6, 0x8a, 0x00, 0x7e, 0x5e, 0x65, 0x5f, 0x6a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_out_of_range_public_values() {
+        let a = [0x5au8; 32];
+        assert!(modpow(&FFDHE2048_P, &[], &a).is_none());
+        assert!(modpow(&FFDHE2048_P, &[0], &a).is_none());
+        assert!(modpow(&FFDHE2048_P, &[1], &a).is_none());
+        assert!(modpow(&FFDHE2048_P, &[2], &a).is_some());
+
+        let mut p_minus_1 = FFDHE2048_P.to_vec();
+        p_minus_1[255] -= 1;
+        assert!(modpow(&FFDHE2048_P, &p_minus_1, &a).is_none());
+        p_minus_1[255] -= 1;
+        assert!(modpow(&FFDHE2048_P, &p_minus_1, &a).is_some());
+        assert!(modpow(&FFDHE2048_P, &FFDHE2048_P, &a).is_none());
+        assert!(modpow(&FFDHE2048_P, &[1u8; 257], &a).is_none());
+    }
+
+    #[test]
+    fn small_exponents() {
+        let mut expected = vec![0u8; 256];
+        expected[255] = 1;
+        assert_eq!(modpow(&FFDHE2048_P, &FFDHE_G, &[]).unwrap(), expected);
+        expected[255] = 32;
+        assert_eq!(modpow(&FFDHE2048_P, &FFDHE_G, &[5]).unwrap(), expected);
+        assert_eq!(
+            modpow(&FFDHE2048_P, &FFDHE_G, &[0, 0, 5]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn diffie_hellman_agrees() {
+        for group in [&FFDHE2048, &FFDHE3072, &FFDHE4096] {
+            let a = group.start().unwrap();
+            let b = group.start().unwrap();
+            assert_eq!(a.pub_key().len(), group.p.len());
+
+            let (a_pub_key, b_pub_key) = (a.pub_key().to_vec(), b.pub_key().to_vec());
+            let za = a.complete(&b_pub_key).unwrap();
+            let zb = b.complete(&a_pub_key).unwrap();
+            assert_eq!(za.secret_bytes(), zb.secret_bytes());
+            assert_eq!(za.secret_bytes().len(), group.p.len());
+        }
+    }
+
+    #[test]
+    fn exponent_comes_from_provider() {
+        #[derive(Debug)]
+        struct FixedRandom;
+
+        impl CryptoProvider for FixedRandom {
+            fn fill_random(&self, buf: &mut [u8]) -> Result<(), GetRandomFailed> {
+                buf.iter_mut().for_each(|b| *b = 0x5a);
+                Ok(())
+            }
+
+            fn default_cipher_suites(&self) -> &'static [crate::SupportedCipherSuite] {
+                RING.default_cipher_suites()
+            }
+
+            fn default_kx_groups(&self) -> &'static [&'static dyn SupportedKxGroup] {
+                RING.default_kx_groups()
+            }
+
+            fn verify_signature(
+                &self,
+                scheme: crate::SignatureScheme,
+                spki: &[u8],
+                message: &[u8],
+                signature: &[u8],
+            ) -> Result<(), Error> {
+                RING.verify_signature(scheme, spki, message, signature)
+            }
+
+            fn load_private_key(
+                &self,
+                key_der: &crate::PrivateKey,
+            ) -> Result<std::sync::Arc<dyn crate::sign::SigningKey>, Error> {
+                RING.load_private_key(key_der)
+            }
+
+            fn ticket_aead(&self) -> &'static dyn crate::crypto::aead::Algorithm {
+                RING.ticket_aead()
+            }
+        }
+
+        let group = FFDHE2048.with_provider(&FixedRandom);
+        let mut exponent = [0x5au8; 32];
+        exponent[0] |= 0x80;
+        let expected = modpow(&FFDHE2048_P, &FFDHE_G, &exponent).unwrap();
+        assert_eq!(group.start().unwrap().pub_key(), &expected[..]);
+        assert_eq!(group.name(), NamedGroup::FFDHE2048);
+    }
+}
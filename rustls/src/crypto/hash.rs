@@ -0,0 +1,69 @@
+use crate::msgs::enums::HashAlgorithm;
+
+use std::fmt::Debug;
+
+/// The largest hash output supported, in bytes.
+pub const MAX_LEN: usize = 64;
+
+/// Describes a single cryptographic hash function.
+///
+/// This interface can do both one-shot and incremental hashing, using
+/// [`Hash::hash()`] and [`Hash::start()`] respectively.
+pub trait Hash: Send + Sync + Debug {
+    /// Start an incremental hash computation.
+    fn start(&self) -> Box<dyn Context>;
+
+    /// Return the output of this hash function with input `data`.
+    fn hash(&self, data: &[u8]) -> Output;
+
+    /// The length in bytes of this hash function's output.
+    fn output_len(&self) -> usize;
+
+    /// Which hash function this is, eg, `HashAlgorithm::SHA256`.
+    fn algorithm(&self) -> HashAlgorithm;
+}
+
+/// How to incrementally compute a hash.
+pub trait Context: Send + Sync {
+    /// Finish the computation, returning the resulting output.
+    ///
+    /// The computation remains valid, and more data can be added later with
+    /// [`Context::update()`].
+    fn fork_finish(&self) -> Output;
+
+    /// Fork the computation, producing another context that has the
+    /// same prefix as this one.
+    fn fork(&self) -> Box<dyn Context>;
+
+    /// Terminate and finish the computation, returning the resulting output.
+    fn finish(self: Box<Self>) -> Output;
+
+    /// Add `data` to computation.
+    fn update(&mut self, data: &[u8]);
+}
+
+/// A hash output, stored as a value.
+#[derive(Clone)]
+pub struct Output {
+    buf: [u8; MAX_LEN],
+    used: usize,
+}
+
+impl Output {
+    /// Build a `hash::Output` from a slice of no more than [`MAX_LEN`] bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut output = Self {
+            buf: [0u8; MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= MAX_LEN);
+        output.buf[..bytes.len()].copy_from_slice(bytes);
+        output
+    }
+}
+
+impl AsRef<[u8]> for Output {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
@@ -0,0 +1,166 @@
+//! HKDF (RFC5869), built upon a provider's HMAC.
+
+use crate::crypto::hmac::{self, Hmac};
+
+use std::fmt;
+
+/// A pseudorandom key, as produced by HKDF-Extract.
+///
+/// TLS1.3 traffic secrets are also used as pseudorandom keys.
+#[derive(Clone)]
+pub(crate) struct Prk {
+    hmac: &'static dyn Hmac,
+    key: hmac::Tag,
+}
+
+impl Prk {
+    /// HKDF-Extract: produce a pseudorandom key from `ikm` and `salt`.
+    pub(crate) fn extract(hmac: &'static dyn Hmac, salt: &[u8], ikm: &[u8]) -> Self {
+        Self {
+            hmac,
+            key: hmac.with_key(salt).sign(&[ikm]),
+        }
+    }
+
+    /// Use `secret` directly as a pseudorandom key.
+    ///
+    /// `secret` must be the output length of the hash function.
+    pub(crate) fn new_less_safe(hmac: &'static dyn Hmac, secret: &[u8]) -> Self {
+        Self {
+            hmac,
+            key: hmac::Tag::new(secret),
+        }
+    }
+
+    /// HKDF-Expand: fill `out` with output keying material, using the
+    /// concatenation of `info` as the info string.
+    ///
+    /// Fails if `out` is longer than 255 times the hash output length.
+    pub(crate) fn expand(&self, info: &[&[u8]], out: &mut [u8]) -> Result<(), OutputLengthError> {
+        let hash_len = self.hmac.hash_output_len();
+        if out.len() > 255 * hash_len {
+            return Err(OutputLengthError);
+        }
+
+        let key = self.hmac.with_key(self.key.as_ref());
+        let mut previous: Option<hmac::Tag> = None;
+        for (i, chunk) in out.chunks_mut(hash_len).enumerate() {
+            // Cannot overflow: there are at most 255 chunks.
+            let counter = [i as u8 + 1];
+            let mut input = Vec::with_capacity(info.len() + 2);
+            if let Some(previous) = &previous {
+                input.push(previous.as_ref());
+            }
+            input.extend_from_slice(info);
+            input.push(&counter);
+
+            let block = key.sign(&input);
+            chunk.copy_from_slice(&block.as_ref()[..chunk.len()]);
+            previous = Some(block);
+        }
+
+        Ok(())
+    }
+
+    /// HKDF-Expand a new pseudorandom key of the hash output length.
+    pub(crate) fn expand_prk(&self, info: &[&[u8]]) -> Self {
+        let mut secret = [0u8; hmac::Tag::MAX_LEN];
+        let secret = &mut secret[..self.hmac.hash_output_len()];
+        // Cannot fail: the output is one block long.
+        self.expand(info, secret).unwrap();
+        Self::new_less_safe(self.hmac, secret)
+    }
+
+    /// The HMAC this key is used with.
+    pub(crate) fn hmac(&self) -> &'static dyn Hmac {
+        self.hmac
+    }
+
+    #[cfg(test)]
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+}
+
+impl fmt::Debug for Prk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Prk")
+            .field("hmac", &self.hmac)
+            .finish_non_exhaustive()
+    }
+}
+
+/// HKDF-Expand was asked for more output than it can produce.
+#[derive(Debug)]
+pub(crate) struct OutputLengthError;
+
+#[cfg(test)]
+mod tests {
+    use super::Prk;
+    use crate::crypto::ring::hmac::HMAC_SHA256;
+
+    #[test]
+    fn rfc5869_test_case_1() {
+        let ikm = [0x0b; 22];
+        let salt = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+        ];
+        let info = [0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9];
+
+        let prk = Prk::extract(&HMAC_SHA256, &salt, &ikm);
+        assert_eq!(
+            prk.as_bytes(),
+            &[
+                0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf, 0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b,
+                0xba, 0x63, 0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31, 0x22, 0xec, 0x84, 0x4a,
+                0xd7, 0xc2, 0xb3, 0xe5,
+            ]
+        );
+
+        let mut okm = [0u8; 42];
+        prk.expand(&[&info[..5], &info[5..]], &mut okm)
+            .unwrap();
+        assert_eq!(
+            okm.to_vec(),
+            vec![
+                0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+                0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+                0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+            ]
+        );
+    }
+
+    #[test]
+    fn rfc5869_test_case_3() {
+        let prk = Prk::extract(&HMAC_SHA256, &[], &[0x0b; 22]);
+        assert_eq!(
+            prk.as_bytes(),
+            &[
+                0x19, 0xef, 0x24, 0xa3, 0x2c, 0x71, 0x7b, 0x16, 0x7f, 0x33, 0xa9, 0x1d, 0x6f, 0x64,
+                0x8b, 0xdf, 0x96, 0x59, 0x67, 0x76, 0xaf, 0xdb, 0x63, 0x77, 0xac, 0x43, 0x4c, 0x1c,
+                0x29, 0x3c, 0xcb, 0x04,
+            ]
+        );
+
+        let mut okm = [0u8; 42];
+        prk.expand(&[], &mut okm).unwrap();
+        assert_eq!(
+            okm.to_vec(),
+            vec![
+                0x8d, 0xa4, 0xe7, 0x75, 0xa5, 0x63, 0xc1, 0x8f, 0x71, 0x5f, 0x80, 0x2a, 0x06, 0x3c,
+                0x5a, 0x31, 0xb8, 0xa1, 0x1f, 0x5c, 0x5e, 0xe1, 0x87, 0x9e, 0xc3, 0x45, 0x4e, 0x5f,
+                0x3c, 0x73, 0x8d, 0x2d, 0x9d, 0x20, 0x13, 0x95, 0xfa, 0xa4, 0xb6, 0x1a, 0x96, 0xc8,
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_refuses_overlong_output() {
+        let prk = Prk::extract(&HMAC_SHA256, &[], b"secret");
+        let mut okm = vec![0u8; 255 * 32 + 1];
+        assert!(prk.expand(&[], &mut okm).is_err());
+        assert!(prk
+            .expand(&[], &mut okm[..255 * 32])
+            .is_ok());
+    }
+}
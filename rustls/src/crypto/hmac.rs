@@ -0,0 +1,70 @@
+use crate::crypto::hash;
+
+use std::fmt::Debug;
+
+/// A concrete HMAC implementation, for a single cryptographic hash function.
+///
+/// You should have one object that implements this trait for HMAC-SHA256,
+/// another for HMAC-SHA384, etc.  TLS1.3 uses HKDF built upon these, and
+/// TLS1.2 uses them in its PRF.
+pub trait Hmac: Send + Sync + Debug {
+    /// Prepare to use `key` as a HMAC key.
+    fn with_key(&self, key: &[u8]) -> Box<dyn Key>;
+
+    /// The hash function this HMAC is built upon.
+    fn hash(&self) -> &'static dyn hash::Hash;
+
+    /// The length in bytes of this HMAC's output.
+    fn hash_output_len(&self) -> usize {
+        self.hash().output_len()
+    }
+}
+
+/// A HMAC tag, stored as a value.
+#[derive(Clone)]
+pub struct Tag {
+    buf: [u8; Self::MAX_LEN],
+    used: usize,
+}
+
+impl Tag {
+    /// Build a tag by copying a byte slice.
+    ///
+    /// The slice can be up to [`Tag::MAX_LEN`] bytes in length.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut tag = Self {
+            buf: [0u8; Self::MAX_LEN],
+            used: bytes.len(),
+        };
+        debug_assert!(bytes.len() <= Self::MAX_LEN);
+        tag.buf[..bytes.len()].copy_from_slice(bytes);
+        tag
+    }
+
+    /// Maximum supported HMAC tag size: supports up to SHA512.
+    pub const MAX_LEN: usize = hash::MAX_LEN;
+}
+
+impl Drop for Tag {
+    fn drop(&mut self) {
+        self.buf.iter_mut().for_each(|b| *b = 0);
+    }
+}
+
+impl AsRef<[u8]> for Tag {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.used]
+    }
+}
+
+/// A HMAC key that is ready for use.
+///
+/// The algorithm used is implicit in the `Hmac` object that produced the key.
+pub trait Key: Send + Sync {
+    /// Calculates a tag over the concatenation of the slices in `data`.
+    fn sign(&self, data: &[&[u8]]) -> Tag;
+
+    /// Returns the length of the tag returned by a computation using
+    /// this key.
+    fn tag_len(&self) -> usize;
+}
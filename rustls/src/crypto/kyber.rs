@@ -0,0 +1,258 @@
+//! The hybrid X25519 and Kyber768 post-quantum group.
+//!
+//! Kyber768 is done by `pqcrypto-kyber`, which wraps PQClean's packaging
+//! of the reference implementation of the round 3 Kyber submission.
+
+use crate::crypto::ring::kx::X25519;
+use crate::crypto::{
+    ActiveKeyExchange, CompletedKeyExchange, GetRandomFailed, SharedSecret, SupportedKxGroup,
+};
+use crate::enums::ProtocolVersion;
+use crate::error::Error;
+use crate::msgs::enums::NamedGroup;
+
+use pqcrypto_kyber::kyber768;
+use pqcrypto_traits::kem::{Ciphertext as _, PublicKey as _, SharedSecret as _};
+
+use std::fmt;
+
+/// A hybrid of X25519 and the Kyber768 post-quantum KEM.
+///
+/// This is `X25519Kyber768Draft00` from draft-tls-westerbaan-xyber768d00,
+/// using the round 3 version of Kyber.  The shared secret is only
+/// compromised if both X25519 and Kyber768 are broken.
+///
+/// This group is only usable with TLS1.3.  The client's key share is
+/// much larger than for the classical groups, so offer it alongside
+/// [`X25519`] rather than instead of it:
+///
+/// ```
+/// # use rustls::kx_group;
+/// let config = rustls::ClientConfig::builder()
+///     .with_safe_default_cipher_suites()
+///     .with_kx_groups(&[&kx_group::X25519_KYBER768_DRAFT00, &kx_group::X25519])
+///     .with_safe_default_protocol_versions()
+///     .unwrap();
+/// ```
+///
+/// [`X25519`]: crate::kx_group::X25519
+pub static X25519_KYBER768_DRAFT00: X25519Kyber768 = X25519Kyber768;
+
+/// The type of [`X25519_KYBER768_DRAFT00`].
+pub struct X25519Kyber768;
+
+const X25519_LEN: usize = 32;
+
+impl SupportedKxGroup for X25519Kyber768 {
+    fn start(&self) -> Result<Box<dyn ActiveKeyExchange>, GetRandomFailed> {
+        let x25519 = X25519.start()?;
+        let (kyber_pub_key, kyber_key) = kyber768::keypair();
+
+        let mut pub_key = Vec::with_capacity(X25519_LEN + kyber768::public_key_bytes());
+        pub_key.extend_from_slice(x25519.pub_key());
+        pub_key.extend_from_slice(kyber_pub_key.as_bytes());
+
+        Ok(Box::new(HybridKeyExchange {
+            x25519,
+            kyber_key,
+            pub_key,
+        }))
+    }
+
+    fn start_and_complete(&self, peer_pub_key: &[u8]) -> Result<CompletedKeyExchange, Error> {
+        if peer_pub_key.len() != X25519_LEN + kyber768::public_key_bytes() {
+            return Err(key_agreement_failed());
+        }
+        let (x25519_peer, kyber_peer) = peer_pub_key.split_at(X25519_LEN);
+        let kyber_peer =
+            kyber768::PublicKey::from_bytes(kyber_peer).map_err(|_| key_agreement_failed())?;
+
+        let x25519 = X25519.start()?;
+        let mut pub_key = Vec::with_capacity(X25519_LEN + kyber768::ciphertext_bytes());
+        pub_key.extend_from_slice(x25519.pub_key());
+        let x25519_secret = x25519.complete(x25519_peer)?;
+
+        let (kyber_secret, ciphertext) = kyber768::encapsulate(&kyber_peer);
+        pub_key.extend_from_slice(ciphertext.as_bytes());
+
+        Ok(CompletedKeyExchange {
+            group: self.name(),
+            pub_key,
+            secret: combine(&x25519_secret, kyber_secret.as_bytes()),
+        })
+    }
+
+    fn name(&self) -> NamedGroup {
+        NamedGroup::X25519Kyber768Draft00
+    }
+
+    fn usable_for_version(&self, version: ProtocolVersion) -> bool {
+        version == ProtocolVersion::TLSv1_3
+    }
+}
+
+impl fmt::Debug for X25519Kyber768 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// The client side of an in-progress [`X25519Kyber768`] exchange.
+struct HybridKeyExchange {
+    x25519: Box<dyn ActiveKeyExchange>,
+    kyber_key: kyber768::SecretKey,
+    pub_key: Vec<u8>,
+}
+
+impl ActiveKeyExchange for HybridKeyExchange {
+    fn complete(self: Box<Self>, peer: &[u8]) -> Result<SharedSecret, Error> {
+        if peer.len() != X25519_LEN + kyber768::ciphertext_bytes() {
+            return Err(key_agreement_failed());
+        }
+        let (x25519_peer, ciphertext) = peer.split_at(X25519_LEN);
+        let ciphertext =
+            kyber768::Ciphertext::from_bytes(ciphertext).map_err(|_| key_agreement_failed())?;
+
+        let x25519_secret = self.x25519.complete(x25519_peer)?;
+        let kyber_secret = kyber768::decapsulate(&ciphertext, &self.kyber_key);
+        Ok(combine(&x25519_secret, kyber_secret.as_bytes()))
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        &self.pub_key
+    }
+
+    fn group(&self) -> NamedGroup {
+        NamedGroup::X25519Kyber768Draft00
+    }
+}
+
+/// The hybrid shared secret is the concatenation of the X25519 and
+/// Kyber768 secrets, in that order.
+fn combine(x25519: &SharedSecret, kyber: &[u8]) -> SharedSecret {
+    let mut secret = Vec::with_capacity(X25519_LEN + kyber.len());
+    secret.extend_from_slice(x25519.secret_bytes());
+    secret.extend_from_slice(kyber);
+    SharedSecret::from(secret)
+}
+
+fn key_agreement_failed() -> Error {
+    Error::PeerMisbehavedError("key agreement failed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pqcrypto_traits::kem::SecretKey as _;
+
+    /// The first known-answer test for Kyber768 from the round 3
+    /// submission.  These are the values PQClean checks its packaging
+    /// against, and this file is byte-for-byte what its `nistkat` test
+    /// prints.
+    const KAT: &str = include_str!("../testdata/kyber768-kat.rsp");
+
+    /// The `nistkat-sha256` of Kyber768 in PQClean's `META.yml`.
+    const KAT_SHA256: &str = "c7e76b4b30c786b5b70c152a446e7832c1cb42b3816ec048dbeaf7041211b310";
+
+    fn kat(name: &str) -> Vec<u8> {
+        let hex = KAT
+            .lines()
+            .find_map(|line| {
+                line.strip_prefix(name)?
+                    .strip_prefix(" = ")
+            })
+            .unwrap();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// A client exchange using the known-answer secret key.
+    fn kat_client() -> HybridKeyExchange {
+        HybridKeyExchange {
+            x25519: X25519.start().unwrap(),
+            kyber_key: kyber768::SecretKey::from_bytes(&kat("sk")).unwrap(),
+            pub_key: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn kat_file_is_intact() {
+        let digest = ring::digest::digest(&ring::digest::SHA256, KAT.as_bytes());
+        let hex = digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        assert_eq!(hex, KAT_SHA256);
+    }
+
+    #[test]
+    fn client_decapsulates_known_answer() {
+        let client = kat_client();
+        let client_x25519 = client.x25519.pub_key().to_vec();
+
+        let server_x25519 = X25519.start().unwrap();
+        let mut share = server_x25519.pub_key().to_vec();
+        share.extend_from_slice(&kat("ct"));
+
+        let secret = Box::new(client)
+            .complete(&share)
+            .unwrap();
+        let x25519_secret = server_x25519
+            .complete(&client_x25519)
+            .unwrap();
+
+        let (x25519, kyber) = secret
+            .secret_bytes()
+            .split_at(X25519_LEN);
+        assert_eq!(x25519, x25519_secret.secret_bytes());
+        assert_eq!(kyber, &kat("ss")[..]);
+    }
+
+    #[test]
+    fn server_encapsulates_to_known_answer_key() {
+        let client = kat_client();
+        let mut client_share = client.x25519.pub_key().to_vec();
+        client_share.extend_from_slice(&kat("pk"));
+
+        let completed = X25519_KYBER768_DRAFT00
+            .start_and_complete(&client_share)
+            .unwrap();
+        assert_eq!(completed.group, NamedGroup::X25519Kyber768Draft00);
+        assert_eq!(
+            completed.pub_key.len(),
+            X25519_LEN + kyber768::ciphertext_bytes()
+        );
+
+        let secret = Box::new(client)
+            .complete(&completed.pub_key)
+            .unwrap();
+        assert_eq!(secret.secret_bytes(), completed.secret.secret_bytes());
+        assert_eq!(secret.secret_bytes().len(), X25519_LEN + 32);
+    }
+
+    #[test]
+    fn rejects_shares_of_the_wrong_length() {
+        let client = X25519_KYBER768_DRAFT00.start().unwrap();
+        assert_eq!(
+            client.pub_key().len(),
+            X25519_LEN + kyber768::public_key_bytes()
+        );
+        assert!(X25519_KYBER768_DRAFT00
+            .start_and_complete(&client.pub_key()[1..])
+            .is_err());
+
+        let mut share = vec![9; X25519_LEN];
+        share.extend_from_slice(&kat("ct"));
+        share.pop();
+        assert!(client.complete(&share).is_err());
+    }
+
+    #[test]
+    fn only_usable_for_tls13() {
+        assert!(X25519_KYBER768_DRAFT00.usable_for_version(ProtocolVersion::TLSv1_3));
+        assert!(!X25519_KYBER768_DRAFT00.usable_for_version(ProtocolVersion::TLSv1_2));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::enums::{ProtocolVersion, SignatureScheme};
+use crate::error::Error;
+use crate::key;
+use crate::msgs::enums::{HashAlgorithm, NamedGroup};
+use crate::sign::SigningKey;
+use crate::suites::SupportedCipherSuite;
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+pub use crate::rand::GetRandomFailed;
+
+/// Authenticated encryption with associated data.
+pub mod aead;
+
+/// Cryptographic hash functions.
+pub mod hash;
+
+/// HMAC, and HKDF built upon it.
+pub mod hmac;
+
+/// AES-128-CCM, for the cipher suites that use it.
+#[cfg(feature = "aes_ccm")]
+pub mod aes_ccm;
+
+/// Finite-field Diffie-Hellman groups from RFC7919.
+#[cfg(feature = "ffdhe")]
+pub mod ffdhe;
+
+/// The hybrid X25519 and Kyber768 post-quantum group.
+#[cfg(feature = "kyber")]
+pub mod kyber;
+
+pub(crate) mod hkdf;
+
+/// The default provider, built on *ring*.
+pub mod ring;
+
+/// The source of all cryptography used by a `ClientConfig` or `ServerConfig`.
+///
+/// A provider supplies randomness, key exchange, signature verification and
+/// private key loading directly.  The symmetric primitives (AEADs, hashes
+/// and HMAC) are reached through the cipher suites it offers: see
+/// [`Tls13CipherSuite`] and [`Tls12CipherSuite`], whose fields may be filled
+/// in with the provider's own implementations of the traits in this module.
+///
+/// [`ring::RING`] is the default, and is what `ClientConfig::builder()` and
+/// `ServerConfig::builder()` use.  Pass another provider to
+/// `ClientConfig::builder_with_provider()` or
+/// `ServerConfig::builder_with_provider()` to use it for the handshake and
+/// record layer.  [`Ticketer::new_with_provider()`],
+/// [`TicketKey::new_with_provider()`], for QUIC,
+/// `quic::Keys::initial_with_provider()` and, with the `ffdhe` feature,
+/// `ffdhe::FfdheKxGroup::with_provider()` do the same for session tickets,
+/// initial packet keys and finite-field private exponents.
+///
+/// *ring* remains a dependency however, and some parts of the crate
+/// always use it, whichever provider is configured:
+///
+/// - certificate validation, which is done by `webpki`, along with the
+///   OCSP, CRL and certificate transparency checks layered on it.  Supply
+///   a custom `ServerCertVerifier` or `ClientCertVerifier` to avoid these.
+/// - the signing keys in [`sign`](crate::sign), such as those made by
+///   `sign::any_supported_type()`.  These are what [`ring::RING`] returns
+///   from [`CryptoProvider::load_private_key()`]; another provider returns
+///   its own.
+/// - the server's long-lived encrypted client hello key, `EchKeyPair`,
+///   which is generated with *ring*'s random numbers and used with
+///   `x25519-dalek`.  The rest of HPKE uses the provider: the client's
+///   ephemeral key comes from its X25519 group, and the HMAC and AEAD
+///   from its TLS1.3 cipher suites.
+///
+/// [`Ticketer::new_with_provider()`]: crate::Ticketer::new_with_provider
+/// [`TicketKey::new_with_provider()`]: crate::TicketKey::new_with_provider
+/// [`Tls13CipherSuite`]: crate::Tls13CipherSuite
+/// [`Tls12CipherSuite`]: crate::Tls12CipherSuite
+pub trait CryptoProvider: Send + Sync + Debug + 'static {
+    /// Fill the given buffer with cryptographically secure random data.
+    fn fill_random(&self, buf: &mut [u8]) -> Result<(), GetRandomFailed>;
+
+    /// The cipher suites used by `with_safe_default_cipher_suites()`, in
+    /// preference order.
+    fn default_cipher_suites(&self) -> &'static [SupportedCipherSuite];
+
+    /// The key exchange groups used by `with_safe_default_kx_groups()`, in
+    /// preference order.
+    fn default_kx_groups(&self) -> &'static [&'static dyn SupportedKxGroup];
+
+    /// Verify `signature` over `message`, made using `scheme` by the key in
+    /// the DER-encoded `SubjectPublicKeyInfo` `spki`.
+    ///
+    /// This is used for peers authenticating with raw public keys.
+    fn verify_signature(
+        &self,
+        scheme: SignatureScheme,
+        spki: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Error>;
+
+    /// Load a DER-encoded private key, auto-detecting its type and encoding.
+    ///
+    /// This is used by `with_single_cert()` and friends.
+    fn load_private_key(&self, key_der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, Error>;
+
+    /// The AEAD used to encrypt session tickets by [`Ticketer`] and
+    /// [`TicketKey`].  It must take a 32-byte key.
+    ///
+    /// [`Ticketer`]: crate::Ticketer
+    /// [`TicketKey`]: crate::TicketKey
+    fn ticket_aead(&self) -> &'static dyn aead::Algorithm;
+}
+
+/// A supported key exchange group.
+///
+/// This type carries both configuration and implementation.  Specifically,
+/// it has a TLS-level name expressed using the [`NamedGroup`] enum, and
+/// a function which produces an [`ActiveKeyExchange`].
+///
+/// Groups built on a key encapsulation mechanism (KEM) fit this shape
+/// too: the client's [`start()`](Self::start) generates a KEM key pair and
+/// sends the public key, the server's
+/// [`start_and_complete()`](Self::start_and_complete) encapsulates to it and
+/// replies with the ciphertext, and the client's
+/// [`ActiveKeyExchange::complete()`] decapsulates that.
+pub trait SupportedKxGroup: Send + Sync + Debug {
+    /// Start a key exchange, as the client.
+    ///
+    /// This generates an ephemeral key pair, which is consumed by the
+    /// returned [`ActiveKeyExchange`].
+    fn start(&self) -> Result<Box<dyn ActiveKeyExchange>, GetRandomFailed>;
+
+    /// Perform a whole key exchange in one step, as the server, given the
+    /// client's key share.
+    ///
+    /// The default implementation is correct for Diffie-Hellman style
+    /// groups, where both sides send a public key.  KEM-based groups must
+    /// override it to encapsulate to `peer_pub_key`.
+    fn start_and_complete(&self, peer_pub_key: &[u8]) -> Result<CompletedKeyExchange, Error> {
+        let kx = self.start()?;
+        Ok(CompletedKeyExchange {
+            group: kx.group(),
+            pub_key: kx.pub_key().to_vec(),
+            secret: kx.complete(peer_pub_key)?,
+        })
+    }
+
+    /// The IANA "TLS Supported Groups" name of the group.
+    fn name(&self) -> NamedGroup;
+
+    /// Whether this group may be used with the given protocol version.
+    ///
+    /// Groups that cannot be expressed in a TLS1.2 `ServerKeyExchange`,
+    /// such as KEM-based ones, return false for `TLSv1_2`.
+    fn usable_for_version(&self, _version: ProtocolVersion) -> bool {
+        true
+    }
+
+    /// For a finite-field Diffie-Hellman group, its parameters.
+    ///
+    /// TLS1.2 DHE cipher suites send these in the `ServerKeyExchange`
+    /// message, and the client uses them to recognise which of its groups
+    /// the server chose.
+    fn ffdhe_group(&self) -> Option<FfdheGroup<'static>> {
+        None
+    }
+}
+
+/// The parameters of a finite-field Diffie-Hellman group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FfdheGroup<'a> {
+    /// The prime modulus, big-endian.
+    pub p: &'a [u8],
+
+    /// The generator, big-endian.
+    pub g: &'a [u8],
+}
+
+/// The result from [`SupportedKxGroup::start_and_complete`].
+pub struct CompletedKeyExchange {
+    /// The group used.
+    pub group: NamedGroup,
+
+    /// Our key share: a public key, or a KEM ciphertext.
+    pub pub_key: Vec<u8>,
+
+    /// The shared secret.
+    pub secret: SharedSecret,
+}
+
+/// An in-progress key exchange, originating from a [`SupportedKxGroup`].
+pub trait ActiveKeyExchange: Send + Sync {
+    /// Completes the key exchange, given the peer's public key (or, for a
+    /// KEM-based group, the server's ciphertext).
+    ///
+    /// The shared secret is returned as a [`SharedSecret`] which can be
+    /// constructed from a `&[u8]`.  Invalid peer keys should produce
+    /// an error, which aborts the handshake.
+    fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Result<SharedSecret, Error>;
+
+    /// Our public key, in the encoding used by TLS key shares.
+    fn pub_key(&self) -> &[u8];
+
+    /// The group this key exchange is using.
+    fn group(&self) -> NamedGroup;
+}
+
+/// The result from [`ActiveKeyExchange::complete`].
+pub struct SharedSecret(Vec<u8>);
+
+impl SharedSecret {
+    /// The shared secret.
+    pub fn secret_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.0.iter_mut().for_each(|b| *b = 0);
+    }
+}
+
+impl From<&[u8]> for SharedSecret {
+    fn from(source: &[u8]) -> Self {
+        Self(source.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for SharedSecret {
+    fn from(source: Vec<u8>) -> Self {
+        Self(source)
+    }
+}
+
+/// Choose a group by name from a list of supported groups.
+pub(crate) fn find_kx_group(
+    name: NamedGroup,
+    supported: &[&'static dyn SupportedKxGroup],
+) -> Option<&'static dyn SupportedKxGroup> {
+    supported
+        .iter()
+        .find(|skxg| skxg.name() == name)
+        .copied()
+}
+
+/// Find `provider`'s HMAC over `hash`, from the first of its TLS1.3 cipher
+/// suites that uses that hash.
+pub(crate) fn find_hmac(
+    provider: &dyn CryptoProvider,
+    hash: HashAlgorithm,
+) -> Option<&'static dyn hmac::Hmac> {
+    provider
+        .default_cipher_suites()
+        .iter()
+        .filter_map(|scs| scs.tls13())
+        .map(|suite| suite.hmac)
+        .find(|hmac| hmac.hash().algorithm() == hash)
+}
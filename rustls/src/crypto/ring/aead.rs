@@ -0,0 +1,105 @@
+use crate::crypto;
+use crate::crypto::aead::{Tag, NONCE_LEN};
+use crate::error::Error;
+
+use ring::aead;
+
+/// An AEAD algorithm implemented by *ring*.
+#[derive(Debug)]
+pub struct Aead {
+    algorithm: &'static aead::Algorithm,
+    header_protection: &'static aead::quic::Algorithm,
+}
+
+/// AES-128 in Galois counter mode.
+pub static AES_128_GCM: Aead = Aead {
+    algorithm: &aead::AES_128_GCM,
+    header_protection: &aead::quic::AES_128,
+};
+
+/// AES-256 in Galois counter mode.
+pub static AES_256_GCM: Aead = Aead {
+    algorithm: &aead::AES_256_GCM,
+    header_protection: &aead::quic::AES_256,
+};
+
+/// ChaCha20 for confidentiality with Poly1305 for authenticity.
+pub static CHACHA20_POLY1305: Aead = Aead {
+    algorithm: &aead::CHACHA20_POLY1305,
+    header_protection: &aead::quic::CHACHA20,
+};
+
+impl crypto::aead::Algorithm for Aead {
+    fn key_len(&self) -> usize {
+        self.algorithm.key_len()
+    }
+
+    fn tag_len(&self) -> usize {
+        self.algorithm.tag_len()
+    }
+
+    fn new_key(&self, key: &[u8]) -> Result<Box<dyn crypto::aead::Key>, Error> {
+        let key = aead::UnboundKey::new(self.algorithm, key)
+            .map_err(|_| Error::General("invalid AEAD key".into()))?;
+        Ok(Box::new(AeadKey(aead::LessSafeKey::new(key))))
+    }
+
+    fn new_header_protection_key(
+        &self,
+        key: &[u8],
+    ) -> Result<Box<dyn crypto::aead::HeaderProtectionKey>, Error> {
+        let key = aead::quic::HeaderProtectionKey::new(self.header_protection, key)
+            .map_err(|_| Error::General("invalid header protection key".into()))?;
+        Ok(Box::new(HeaderProtectionKey(key)))
+    }
+}
+
+struct AeadKey(aead::LessSafeKey);
+
+impl crypto::aead::Key for AeadKey {
+    fn seal_in_place_separate_tag(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<Tag, Error> {
+        self.0
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(aad),
+                in_out,
+            )
+            .map(|tag| Tag::new(tag.as_ref()))
+            .map_err(|_| Error::General("encrypt failed".to_string()))
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: [u8; NONCE_LEN],
+        aad: &[u8],
+        in_out: &mut [u8],
+    ) -> Result<usize, Error> {
+        self.0
+            .open_in_place(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(aad),
+                in_out,
+            )
+            .map(|plain| plain.len())
+            .map_err(|_| Error::DecryptError)
+    }
+}
+
+struct HeaderProtectionKey(aead::quic::HeaderProtectionKey);
+
+impl crypto::aead::HeaderProtectionKey for HeaderProtectionKey {
+    fn sample_len(&self) -> usize {
+        self.0.algorithm().sample_len()
+    }
+
+    fn new_mask(&self, sample: &[u8]) -> Result<[u8; 5], Error> {
+        self.0
+            .new_mask(sample)
+            .map_err(|_| Error::General("sample of invalid length".into()))
+    }
+}
@@ -0,0 +1,59 @@
+use crate::crypto;
+use crate::msgs::enums::HashAlgorithm;
+
+use ring::digest;
+
+/// A hash function implemented by *ring*.
+#[derive(Debug)]
+pub struct Hash(&'static digest::Algorithm, HashAlgorithm);
+
+/// SHA-256.
+pub static SHA256: Hash = Hash(&digest::SHA256, HashAlgorithm::SHA256);
+
+/// SHA-384.
+pub static SHA384: Hash = Hash(&digest::SHA384, HashAlgorithm::SHA384);
+
+/// SHA-512.
+pub static SHA512: Hash = Hash(&digest::SHA512, HashAlgorithm::SHA512);
+
+impl crypto::hash::Hash for Hash {
+    fn start(&self) -> Box<dyn crypto::hash::Context> {
+        Box::new(Context(digest::Context::new(self.0)))
+    }
+
+    fn hash(&self, data: &[u8]) -> crypto::hash::Output {
+        convert(digest::digest(self.0, data))
+    }
+
+    fn output_len(&self) -> usize {
+        self.0.output_len
+    }
+
+    fn algorithm(&self) -> HashAlgorithm {
+        self.1
+    }
+}
+
+struct Context(digest::Context);
+
+impl crypto::hash::Context for Context {
+    fn fork_finish(&self) -> crypto::hash::Output {
+        convert(self.0.clone().finish())
+    }
+
+    fn fork(&self) -> Box<dyn crypto::hash::Context> {
+        Box::new(Self(self.0.clone()))
+    }
+
+    fn finish(self: Box<Self>) -> crypto::hash::Output {
+        convert(self.0.finish())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+}
+
+fn convert(val: digest::Digest) -> crypto::hash::Output {
+    crypto::hash::Output::new(val.as_ref())
+}
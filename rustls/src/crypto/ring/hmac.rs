@@ -0,0 +1,46 @@
+use crate::crypto;
+use crate::crypto::ring::hash;
+
+use ring::hmac;
+
+/// HMAC implemented by *ring*.
+#[derive(Debug)]
+pub struct Hmac(hmac::Algorithm, &'static hash::Hash);
+
+/// HMAC using SHA-256.
+pub static HMAC_SHA256: Hmac = Hmac(hmac::HMAC_SHA256, &hash::SHA256);
+
+/// HMAC using SHA-384.
+pub static HMAC_SHA384: Hmac = Hmac(hmac::HMAC_SHA384, &hash::SHA384);
+
+/// HMAC using SHA-512.
+pub static HMAC_SHA512: Hmac = Hmac(hmac::HMAC_SHA512, &hash::SHA512);
+
+impl crypto::hmac::Hmac for Hmac {
+    fn with_key(&self, key: &[u8]) -> Box<dyn crypto::hmac::Key> {
+        Box::new(Key(hmac::Key::new(self.0, key)))
+    }
+
+    fn hash(&self) -> &'static dyn crypto::hash::Hash {
+        self.1
+    }
+}
+
+struct Key(hmac::Key);
+
+impl crypto::hmac::Key for Key {
+    fn sign(&self, data: &[&[u8]]) -> crypto::hmac::Tag {
+        let mut ctx = hmac::Context::with_key(&self.0);
+        for d in data {
+            ctx.update(d);
+        }
+        crypto::hmac::Tag::new(ctx.sign().as_ref())
+    }
+
+    fn tag_len(&self) -> usize {
+        self.0
+            .algorithm()
+            .digest_algorithm()
+            .output_len
+    }
+}
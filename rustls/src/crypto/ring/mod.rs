@@ -0,0 +1,102 @@
+use crate::crypto::{self, CryptoProvider, GetRandomFailed, SupportedKxGroup};
+use crate::enums::SignatureScheme;
+use crate::error::Error;
+use crate::key;
+use crate::sign::{self, SigningKey};
+use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
+use crate::x509;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature;
+
+use std::sync::Arc;
+
+/// AEAD algorithms implemented with *ring*.
+pub mod aead;
+
+/// Hash functions implemented with *ring*.
+pub mod hash;
+
+/// HMAC implemented with *ring*.
+pub mod hmac;
+
+/// Key exchange groups implemented with *ring*.
+pub mod kx;
+
+/// The default [`CryptoProvider`], backed by *ring*.
+pub static RING: &dyn CryptoProvider = &Ring;
+
+/// A [`CryptoProvider`] backed by *ring*.
+///
+/// Use [`RING`] to refer to it.
+#[derive(Debug)]
+pub struct Ring;
+
+impl CryptoProvider for Ring {
+    fn fill_random(&self, buf: &mut [u8]) -> Result<(), GetRandomFailed> {
+        SystemRandom::new()
+            .fill(buf)
+            .map_err(|_| GetRandomFailed)
+    }
+
+    fn default_cipher_suites(&self) -> &'static [SupportedCipherSuite] {
+        DEFAULT_CIPHER_SUITES
+    }
+
+    fn default_kx_groups(&self) -> &'static [&'static dyn SupportedKxGroup] {
+        &kx::ALL_KX_GROUPS
+    }
+
+    fn verify_signature(
+        &self,
+        scheme: SignatureScheme,
+        spki: &[u8],
+        message: &[u8],
+        sig: &[u8],
+    ) -> Result<(), Error> {
+        let (alg_id, public_key) =
+            x509::split_spki(spki).ok_or(Error::InvalidCertificateEncoding)?;
+
+        let (expected_alg_id, alg): (_, &dyn signature::VerificationAlgorithm) = match scheme {
+            SignatureScheme::ECDSA_NISTP256_SHA256 => {
+                (x509::ALG_ID_ECDSA_P256, &signature::ECDSA_P256_SHA256_ASN1)
+            }
+            SignatureScheme::ECDSA_NISTP384_SHA384 => {
+                (x509::ALG_ID_ECDSA_P384, &signature::ECDSA_P384_SHA384_ASN1)
+            }
+            SignatureScheme::ED25519 => (x509::ALG_ID_ED25519, &signature::ED25519),
+            SignatureScheme::RSA_PSS_SHA256 => (
+                x509::ALG_ID_RSA_ENCRYPTION,
+                &signature::RSA_PSS_2048_8192_SHA256,
+            ),
+            SignatureScheme::RSA_PSS_SHA384 => (
+                x509::ALG_ID_RSA_ENCRYPTION,
+                &signature::RSA_PSS_2048_8192_SHA384,
+            ),
+            SignatureScheme::RSA_PSS_SHA512 => (
+                x509::ALG_ID_RSA_ENCRYPTION,
+                &signature::RSA_PSS_2048_8192_SHA512,
+            ),
+            _ => {
+                let error_msg = format!("received unsupported sig scheme {:?}", scheme);
+                return Err(Error::PeerMisbehavedError(error_msg));
+            }
+        };
+
+        if alg_id != expected_alg_id {
+            return Err(Error::InvalidCertificateSignatureType);
+        }
+
+        signature::UnparsedPublicKey::new(alg, public_key)
+            .verify(message, sig)
+            .map_err(|_| Error::InvalidCertificateSignature)
+    }
+
+    fn load_private_key(&self, key_der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, Error> {
+        sign::any_supported_type(key_der).map_err(|_| Error::General("invalid private key".into()))
+    }
+
+    fn ticket_aead(&self) -> &'static dyn crypto::aead::Algorithm {
+        &aead::CHACHA20_POLY1305
+    }
+}
@@ -0,0 +1,906 @@
+// Certificate Transparency (RFC6962) policy enforcement.
+
+use crate::error::Error;
+#[cfg(feature = "ct-log-list")]
+use crate::json;
+use crate::key::Certificate;
+#[cfg(feature = "logging")]
+use crate::log::{debug, warn};
+use crate::msgs::codec::{u24, Codec, Reader};
+use crate::msgs::handshake::SCTList;
+use crate::ocsp;
+use crate::x509::{self, Issuer, ParsedCert};
+
+use ring::digest;
+use ring::io::der;
+use ring::signature;
+
+use std::time::{Duration, SystemTime};
+
+/// OID of the certificate `SignedCertificateTimestampList` extension (RFC6962).
+const OID_SCT_LIST: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02];
+
+const SCT_V1: u8 = 0;
+const SIGNATURE_TYPE_TIMESTAMP: u8 = 0;
+const ENTRY_TYPE_PRECERT: u16 = 1;
+
+const ECDSA_SHA256: u16 = 0x0403;
+const ECDSA_SHA384: u16 = 0x0503;
+const RSA_PKCS1_SHA256: u16 = 0x0401;
+const RSA_PKCS1_SHA384: u16 = 0x0501;
+
+/// A list of Certificate Transparency logs, and who operates them.
+///
+/// With the `ct-log-list` feature, this is normally loaded with
+/// [`CtLogList::from_json`] from a log list published in the JSON format
+/// used by Chrome, such as
+/// <https://www.gstatic.com/ct/log_list/v3/log_list.json>.
+#[derive(Clone, Debug)]
+pub struct CtLogList {
+    logs: Vec<CtLog>,
+}
+
+impl CtLogList {
+    /// Make a list of the logs in `logs`.
+    ///
+    /// These logs are all treated as usable, for certificates expiring at
+    /// any time.
+    pub fn new(logs: &[&sct::Log]) -> Self {
+        Self {
+            logs: logs
+                .iter()
+                .map(|log| CtLog {
+                    description: log.description.to_string(),
+                    operator: log.operated_by.to_string(),
+                    key: log.key.to_vec(),
+                    id: log.id,
+                    state: LogState::Usable,
+                    interval: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Parse a log list in the JSON format described by
+    /// <https://www.gstatic.com/ct/log_list/v3/log_list_schema.json>.
+    ///
+    /// The `logs` and `tiled_logs` of each operator are read.  A log's
+    /// `state` and `temporal_interval` decide which SCTs from it are
+    /// accepted; see [`CtRequirements`].  A log without a `state` is
+    /// treated as pending.
+    ///
+    /// The signature over the list, if any, is not checked: the caller
+    /// is responsible for obtaining it from a trustworthy source.
+    #[cfg(feature = "ct-log-list")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "ct-log-list")))]
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let invalid = || Error::General("invalid Certificate Transparency log list".to_string());
+        let list: json::LogList = serde_json::from_str(json).map_err(|_| invalid())?;
+
+        let mut logs = Vec::new();
+        for operator in &list.operators {
+            for log in operator
+                .logs
+                .iter()
+                .chain(&operator.tiled_logs)
+            {
+                logs.push(CtLog::from_json(log, &operator.name).ok_or_else(invalid)?);
+            }
+        }
+
+        Ok(Self { logs })
+    }
+
+    /// The number of logs in the list.
+    pub fn len(&self) -> usize {
+        self.logs.len()
+    }
+
+    /// Returns true if the list contains no logs.
+    pub fn is_empty(&self) -> bool {
+        self.logs.is_empty()
+    }
+
+    /// These logs, as the `sct` crate describes them.
+    fn sct_logs(&self) -> Vec<sct::Log<'_>> {
+        self.logs
+            .iter()
+            .map(|log| sct::Log {
+                description: &log.description,
+                url: "",
+                operated_by: &log.operator,
+                key: &log.key,
+                id: log.id,
+                max_merge_delay: 0,
+            })
+            .collect()
+    }
+
+    /// Verify the encoded SCT `sct`, delivered for the certificate `cert`,
+    /// at `now` (in milliseconds since the UNIX epoch).  `sct_logs` are
+    /// these logs, from [`Self::sct_logs`].
+    ///
+    /// Returns the log which issued it and its timestamp.
+    fn verify_delivered(
+        &self,
+        sct_logs: &[&sct::Log],
+        cert: &[u8],
+        sct: &[u8],
+        now: u64,
+    ) -> Result<(&CtLog, u64), sct::Error> {
+        let index = sct::verify_sct(cert, sct, now, sct_logs)?;
+        let sct = Sct::parse(sct)?;
+        Ok((&self.logs[index], sct.timestamp))
+    }
+
+    /// Verify the encoded SCT `sct`, embedded in a certificate, at `now`.
+    ///
+    /// An embedded SCT signs the certificate's precertificate -- its
+    /// `TBSCertificate` without the SCT list, `tbs`, and the hash of its
+    /// issuer's key.  The `sct` crate only verifies SCTs which sign a
+    /// whole certificate, so this is done here.
+    fn verify_embedded(
+        &self,
+        sct: &[u8],
+        issuer_key_hash: &[u8],
+        tbs: &[u8],
+        now: u64,
+    ) -> Result<(&CtLog, u64), sct::Error> {
+        let sct = Sct::parse(sct)?;
+        let log = self
+            .logs
+            .iter()
+            .find(|log| log.id == sct.log_id)
+            .ok_or(sct::Error::UnknownLog)?;
+
+        let alg: &dyn signature::VerificationAlgorithm = match sct.signature_alg {
+            ECDSA_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+            ECDSA_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+            RSA_PKCS1_SHA256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+            RSA_PKCS1_SHA384 => &signature::RSA_PKCS1_2048_8192_SHA384,
+            _ => return Err(sct::Error::InvalidSignature),
+        };
+
+        let mut message = vec![SCT_V1, SIGNATURE_TYPE_TIMESTAMP];
+        sct.timestamp.encode(&mut message);
+        ENTRY_TYPE_PRECERT.encode(&mut message);
+        message.extend_from_slice(issuer_key_hash);
+        u24(tbs.len() as u32).encode(&mut message);
+        message.extend_from_slice(tbs);
+        (sct.extensions.len() as u16).encode(&mut message);
+        message.extend_from_slice(sct.extensions);
+
+        signature::UnparsedPublicKey::new(alg, &log.key)
+            .verify(&message, sct.signature)
+            .map_err(|_| sct::Error::InvalidSignature)?;
+
+        if sct.timestamp > now {
+            return Err(sct::Error::TimestampInFuture);
+        }
+
+        Ok((log, sct.timestamp))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CtLog {
+    #[cfg_attr(not(feature = "logging"), allow(dead_code))]
+    description: String,
+    operator: String,
+    /// The public key, without its `SubjectPublicKeyInfo` wrapping.
+    key: Vec<u8>,
+    /// SHA-256 of the log's `SubjectPublicKeyInfo`.
+    id: [u8; 32],
+    state: LogState,
+    /// The log only accepts certificates expiring in this range
+    /// (start inclusive, end exclusive; in milliseconds since the UNIX
+    /// epoch).
+    interval: Option<(u64, u64)>,
+}
+
+impl CtLog {
+    #[cfg(feature = "ct-log-list")]
+    fn from_json(log: &json::Log, operator: &str) -> Option<Self> {
+        let spki = base64_decode(&log.key)?;
+        let id = base64_decode(&log.log_id)?;
+        if id != digest::digest(&digest::SHA256, &spki).as_ref() {
+            return None;
+        }
+        let (_, key) = x509::split_spki(&spki)?;
+
+        let state = match &log.state {
+            Some(state) => LogState::from_json(state)?,
+            None => LogState::Pending,
+        };
+
+        let interval = match &log.temporal_interval {
+            Some(interval) => Some((
+                parse_time(&interval.start_inclusive)?,
+                parse_time(&interval.end_exclusive)?,
+            )),
+            None => None,
+        };
+
+        Some(Self {
+            description: log.description.clone(),
+            operator: operator.to_string(),
+            key: key.to_vec(),
+            id: id.try_into().ok()?,
+            state,
+            interval,
+        })
+    }
+
+    /// Return true if an SCT from this log, with the given `timestamp`,
+    /// counts for a certificate expiring at `not_after`.
+    fn accepts(&self, timestamp: u64, embedded: bool, not_after: u64) -> bool {
+        let state_ok = match self.state {
+            LogState::Qualified | LogState::Usable | LogState::ReadOnly => true,
+            LogState::Retired(at) => embedded && timestamp < at,
+            LogState::Pending | LogState::Rejected => false,
+        };
+
+        state_ok
+            && self
+                .interval
+                .map_or(true, |(start, end)| start <= not_after && not_after < end)
+    }
+}
+
+/// The lifecycle state of a log.  Times are in milliseconds since the
+/// UNIX epoch.
+#[cfg_attr(not(feature = "ct-log-list"), allow(dead_code))]
+#[derive(Clone, Copy, Debug)]
+enum LogState {
+    Pending,
+    Qualified,
+    Usable,
+    ReadOnly,
+    Retired(u64),
+    Rejected,
+}
+
+impl LogState {
+    #[cfg(feature = "ct-log-list")]
+    fn from_json(state: &json::State) -> Option<Self> {
+        Some(match state {
+            json::State::Pending {} => Self::Pending,
+            json::State::Qualified {} => Self::Qualified,
+            json::State::Usable {} => Self::Usable,
+            json::State::ReadOnly {} => Self::ReadOnly,
+            json::State::Retired(retired) => Self::Retired(parse_time(&retired.timestamp)?),
+            json::State::Rejected {} => Self::Rejected,
+        })
+    }
+}
+
+/// Requirements for the SCTs of a server certificate; see
+/// [`ConfigBuilder::with_certificate_transparency_policy`].
+///
+/// An SCT only counts if it was issued by a log from the [`CtLogList`]
+/// which is `qualified`, `usable` or `readonly` -- or `retired`, if the
+/// SCT is embedded in the certificate and predates the log's retirement --
+/// and whose temporal interval (if any) includes the certificate's expiry.
+/// Several SCTs from the same log count once.
+///
+/// A certificate complies if either the SCTs embedded in it, or the SCTs
+/// delivered in the TLS handshake or a stapled OCSP response, meet these
+/// requirements.  The default requirements are those of Chrome's CT policy.
+///
+/// [`ConfigBuilder::with_certificate_transparency_policy`]: crate::ConfigBuilder::with_certificate_transparency_policy
+#[derive(Clone, Debug)]
+pub struct CtRequirements {
+    /// How many distinct log operators must have issued SCTs.
+    pub min_operators: usize,
+
+    /// How many distinct logs must have issued SCTs delivered in the TLS
+    /// handshake or a stapled OCSP response.
+    pub min_delivered_scts: usize,
+
+    /// How many distinct logs must have issued SCTs embedded in the
+    /// certificate, depending on its lifetime.
+    ///
+    /// Each entry gives the number required for a certificate whose
+    /// lifetime is at most the entry's duration; the first matching entry
+    /// applies.  If none match, embedded SCTs never suffice.
+    pub min_embedded_scts: Vec<(Duration, usize)>,
+}
+
+impl Default for CtRequirements {
+    fn default() -> Self {
+        Self {
+            min_operators: 2,
+            min_delivered_scts: 2,
+            min_embedded_scts: vec![
+                (Duration::from_secs(180 * 24 * 60 * 60), 2),
+                (Duration::MAX, 3),
+            ],
+        }
+    }
+}
+
+/// Policy for enforcing Certificate Transparency.
+///
+/// SCTs are taken from the TLS handshake, from a stapled OCSP response and
+/// from the server certificate itself.  By default, enforcement is
+/// opportunistic: a certificate is accepted if any of its SCTs verifies,
+/// or if it has none.  [`CertificateTransparencyPolicy::with_requirements`]
+/// makes the policy enforce [`CtRequirements`] instead.
+///
+/// Because Certificate Transparency logs are sharded on a per-year basis and can be trusted or
+/// distrusted relatively quickly, rustls stores a validation deadline. Server certificates will
+/// be validated against the configured CT logs until the deadline expires. After the deadline,
+/// certificates will no longer be validated, and a warning message will be logged. The deadline
+/// may vary depending on how often you deploy builds with updated dependencies.
+#[allow(unreachable_pub)]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+#[derive(Clone, Debug)]
+pub struct CertificateTransparencyPolicy {
+    logs: CtLogList,
+    requirements: Option<CtRequirements>,
+    validation_deadline: SystemTime,
+}
+
+impl CertificateTransparencyPolicy {
+    /// Create a new policy.
+    #[allow(unreachable_pub)]
+    pub fn new(
+        logs: &'static [&'static sct::Log<'static>],
+        validation_deadline: SystemTime,
+    ) -> Self {
+        Self::from_log_list(CtLogList::new(logs), validation_deadline)
+    }
+
+    /// Create a new policy, using the logs in `logs`.
+    #[allow(unreachable_pub)]
+    pub fn from_log_list(logs: CtLogList, validation_deadline: SystemTime) -> Self {
+        Self {
+            logs,
+            requirements: None,
+            validation_deadline,
+        }
+    }
+
+    /// Require server certificates to meet `requirements`.
+    #[allow(unreachable_pub)]
+    pub fn with_requirements(mut self, requirements: CtRequirements) -> Self {
+        self.requirements = Some(requirements);
+        self
+    }
+
+    /// Check the SCTs for `end_entity`, which was issued by `issuer` (if
+    /// known: SCTs embedded in the certificate or in `ocsp_response` can
+    /// only be verified if it is).
+    pub(crate) fn verify(
+        &self,
+        end_entity: &Certificate,
+        issuer: Option<&Issuer>,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        if self.logs.is_empty() {
+            return Ok(());
+        } else if self
+            .validation_deadline
+            .duration_since(now)
+            .is_err()
+        {
+            warn!("certificate transparency logs have expired, validation disabled");
+            return Ok(());
+        }
+
+        let cert = ParsedCert::parse(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+        let (not_before, not_after) = match (cert.not_before(), cert.not_after()) {
+            (Some(not_before), Some(not_after)) => (not_before, not_after),
+            _ => return Err(Error::InvalidCertificateEncoding),
+        };
+
+        let mut found = FoundScts {
+            not_after: not_after.saturating_mul(1000),
+            now: unix_time_millis(now)?,
+            delivered: Vec::new(),
+            embedded: Vec::new(),
+            last_error: None,
+        };
+
+        let sct_logs = self.logs.sct_logs();
+        let sct_logs = sct_logs.iter().collect::<Vec<_>>();
+        for sct in scts {
+            let verified = self
+                .logs
+                .verify_delivered(&sct_logs, &end_entity.0, sct, found.now);
+            found.add(verified, false)?;
+        }
+
+        if let Some(issuer) = issuer {
+            if let Some(list) = ocsp::find_scts(ocsp_response, &cert, issuer, found.now / 1000) {
+                for sct in parse_sct_list(list)?.iter() {
+                    let verified =
+                        self.logs
+                            .verify_delivered(&sct_logs, &end_entity.0, &sct.0, found.now);
+                    found.add(verified, false)?;
+                }
+            }
+
+            if let Some(value) = cert.find_extension(OID_SCT_LIST) {
+                let list = match x509::read_der(value, der::Tag::OctetString) {
+                    Some((list, [])) => list,
+                    _ => return Err(Error::InvalidSct(sct::Error::MalformedSct)),
+                };
+                let tbs = cert
+                    .tbs_without_extension(OID_SCT_LIST)
+                    .ok_or(Error::InvalidCertificateEncoding)?;
+                let mut issuer_spki = issuer.spki.to_vec();
+                x509::wrap_in_sequence(&mut issuer_spki);
+                let issuer_key_hash = digest::digest(&digest::SHA256, &issuer_spki);
+
+                for sct in parse_sct_list(list)?.iter() {
+                    let verified = self.logs.verify_embedded(
+                        &sct.0,
+                        issuer_key_hash.as_ref(),
+                        &tbs,
+                        found.now,
+                    );
+                    found.add(verified, true)?;
+                }
+            }
+        }
+
+        let requirements = match &self.requirements {
+            Some(requirements) => requirements,
+            None => {
+                /* If we were supplied with some logs, and some SCTs,
+                 * but couldn't verify any of them, fail the handshake. */
+                return match found.last_error {
+                    Some(err) if found.delivered.is_empty() && found.embedded.is_empty() => {
+                        warn!("No valid SCTs provided");
+                        Err(Error::InvalidSct(err))
+                    }
+                    _ => Ok(()),
+                };
+            }
+        };
+
+        let lifetime = Duration::from_secs(not_after.saturating_sub(not_before));
+        let min_embedded = requirements
+            .min_embedded_scts
+            .iter()
+            .find(|(max_lifetime, _)| lifetime <= *max_lifetime)
+            .map(|(_, min)| *min);
+
+        if min_embedded.map_or(false, |min| {
+            meets(&found.embedded, min, requirements.min_operators)
+        }) || meets(
+            &found.delivered,
+            requirements.min_delivered_scts,
+            requirements.min_operators,
+        ) {
+            return Ok(());
+        }
+
+        warn!("Certificate does not comply with CT policy");
+        Err(Error::InvalidCertificateData(
+            "certificate does not comply with Certificate Transparency policy".to_string(),
+        ))
+    }
+}
+
+/// The logs which issued valid SCTs for a certificate.
+struct FoundScts<'a> {
+    /// Times are in milliseconds since the UNIX epoch.
+    not_after: u64,
+    now: u64,
+    delivered: Vec<&'a CtLog>,
+    embedded: Vec<&'a CtLog>,
+    /// Why the last ignored SCT was ignored.
+    last_error: Option<sct::Error>,
+}
+
+impl<'a> FoundScts<'a> {
+    /// Count an SCT, given the result of verifying it.
+    fn add(
+        &mut self,
+        verified: Result<(&'a CtLog, u64), sct::Error>,
+        embedded: bool,
+    ) -> Result<(), Error> {
+        #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+        match verified {
+            Ok((log, timestamp)) if log.accepts(timestamp, embedded, self.not_after) => {
+                debug!(
+                    "Valid SCT signed by {} on {}",
+                    log.operator, log.description
+                );
+                match embedded {
+                    true => self.embedded.push(log),
+                    false => self.delivered.push(log),
+                }
+            }
+            Ok((log, _)) => {
+                debug!("SCT ignored because {} does not qualify", log.description);
+                self.last_error = Some(sct::Error::UnknownLog);
+            }
+            Err(e) if e.should_be_fatal() => return Err(Error::InvalidSct(e)),
+            Err(e) => {
+                debug!("SCT ignored because {:?}", e);
+                self.last_error = Some(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Return true if `logs` includes at least `min_logs` distinct logs, run
+/// by at least `min_operators` distinct operators.
+fn meets(logs: &[&CtLog], min_logs: usize, min_operators: usize) -> bool {
+    let mut ids = logs
+        .iter()
+        .map(|log| log.id)
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut operators = logs
+        .iter()
+        .map(|log| log.operator.as_str())
+        .collect::<Vec<_>>();
+    operators.sort_unstable();
+    operators.dedup();
+
+    ids.len() >= min_logs && operators.len() >= min_operators
+}
+
+fn parse_sct_list(list: &[u8]) -> Result<SCTList, Error> {
+    SCTList::read_bytes(list).ok_or(Error::InvalidSct(sct::Error::MalformedSct))
+}
+
+/// The fields of an encoded `SignedCertificateTimestamp`.
+struct Sct<'a> {
+    log_id: &'a [u8],
+    timestamp: u64,
+    extensions: &'a [u8],
+    signature_alg: u16,
+    signature: &'a [u8],
+}
+
+impl<'a> Sct<'a> {
+    fn parse(sct: &'a [u8]) -> Result<Self, sct::Error> {
+        let mut r = Reader::init(sct);
+        match u8::read(&mut r) {
+            Some(SCT_V1) => {}
+            Some(_) => return Err(sct::Error::UnsupportedSctVersion),
+            None => return Err(sct::Error::MalformedSct),
+        }
+
+        match Self::read(&mut r) {
+            Some(sct) if !r.any_left() => Ok(sct),
+            _ => Err(sct::Error::MalformedSct),
+        }
+    }
+
+    fn read(r: &mut Reader<'a>) -> Option<Self> {
+        let log_id = r.take(32)?;
+        let timestamp = u64::read(r)?;
+        let len = u16::read(r)?;
+        let extensions = r.take(len as usize)?;
+        let signature_alg = u16::read(r)?;
+        let len = u16::read(r)?;
+        let signature = r.take(len as usize)?;
+
+        Some(Self {
+            log_id,
+            timestamp,
+            extensions,
+            signature_alg,
+            signature,
+        })
+    }
+}
+
+fn unix_time_millis(now: SystemTime) -> Result<u64, Error> {
+    now.duration_since(std::time::UNIX_EPOCH)
+        .map(|dur| dur.as_secs())
+        .map_err(|_| Error::FailedToGetCurrentTime)
+        .and_then(|secs| {
+            secs.checked_mul(1000)
+                .ok_or(Error::FailedToGetCurrentTime)
+        })
+}
+
+/// Decode padded base64, with the standard alphabet (RFC4648 section 4).
+#[cfg(feature = "ct-log-list")]
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD
+        .decode(input)
+        .ok()
+}
+
+/// Parse an RFC3339 time into milliseconds since the UNIX epoch.
+#[cfg(feature = "ct-log-list")]
+fn parse_time(time: &str) -> Option<u64> {
+    humantime::parse_rfc3339(time)
+        .ok()
+        .and_then(|time| unix_time_millis(time).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Timestamp of the test SCTs (2025-01-01T00:05:00Z), in milliseconds.
+    const SCT_TIME: u64 = 1_735_689_900_000;
+
+    fn now() -> SystemTime {
+        std::time::UNIX_EPOCH + Duration::from_millis(SCT_TIME) + Duration::from_secs(2 * 86_400)
+    }
+
+    /// The logs in `testdata/ct-log-list.json`.
+    fn log_list() -> CtLogList {
+        use base64::Engine;
+
+        let log = |description: &str, operator: &str, spki: &str, state| {
+            let spki = base64::engine::general_purpose::STANDARD
+                .decode(spki)
+                .unwrap();
+            CtLog {
+                description: description.to_string(),
+                operator: operator.to_string(),
+                key: x509::split_spki(&spki)
+                    .unwrap()
+                    .1
+                    .to_vec(),
+                id: digest::digest(&digest::SHA256, &spki)
+                    .as_ref()
+                    .try_into()
+                    .unwrap(),
+                state,
+                interval: Some((1_735_689_600_000, 1_767_225_600_000)),
+            }
+        };
+
+        CtLogList {
+            logs: vec![
+                log(
+                    "Alpha 2025",
+                    "Alpha",
+                    "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEn3W5v8kP+1QXsM3MEWq+P21197bTQFR4Yt0NcuMw3wnxZ//ScmNeBveUDoCKQChole6B3MW64QCFSwCFgZqxtA==",
+                    LogState::Usable,
+                ),
+                log(
+                    "Alpha Tiled 2025",
+                    "Alpha",
+                    "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEZ05T07wRYjRbfkHjyTCuOqYASa/+Ygsv8VOQog+MqHRS19a1hK2RjjCQrHbBFRQzP61GUe6drR2B8DfckfdcCw==",
+                    LogState::Qualified,
+                ),
+                log(
+                    "Beta 2025",
+                    "Beta",
+                    "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEYdcsPwXj+G3xgnAxlMK+XshFQ/uOFbpflRLsRZTwPWwNosDKcobmxrzDDiJAxRIJEJcHli27YB+hK2m+sdXeBg==",
+                    LogState::Usable,
+                ),
+            ],
+        }
+    }
+
+    fn enforcing(logs: CtLogList, requirements: CtRequirements) -> CertificateTransparencyPolicy {
+        CertificateTransparencyPolicy::from_log_list(logs, now() + Duration::from_secs(86_400))
+            .with_requirements(requirements)
+    }
+
+    fn beta(logs: &mut CtLogList) -> &mut CtLog {
+        logs.logs
+            .iter_mut()
+            .find(|log| log.operator == "Beta")
+            .unwrap()
+    }
+
+    fn check(
+        policy: &CertificateTransparencyPolicy,
+        scts: &[&[u8]],
+        ocsp_response: &[u8],
+    ) -> Result<(), Error> {
+        let cert = Certificate(include_bytes!("testdata/ct-end.der").to_vec());
+        let issuer = ParsedCert::parse(include_bytes!("testdata/ct-issuer.der")).unwrap();
+        let issuer = Issuer {
+            subject: issuer.subject,
+            spki: issuer.spki,
+        };
+        policy.verify(
+            &cert,
+            Some(&issuer),
+            &mut scts.iter().copied(),
+            ocsp_response,
+            now(),
+        )
+    }
+
+    fn not_compliant() -> Result<(), Error> {
+        Err(Error::InvalidCertificateData(
+            "certificate does not comply with Certificate Transparency policy".to_string(),
+        ))
+    }
+
+    /// The SCTs stapled in the test OCSP response, which are also valid
+    /// when delivered in the TLS handshake.
+    fn delivered_scts() -> Vec<Vec<u8>> {
+        let cert = ParsedCert::parse(include_bytes!("testdata/ct-end.der")).unwrap();
+        let issuer = ParsedCert::parse(include_bytes!("testdata/ct-issuer.der")).unwrap();
+        let issuer = Issuer {
+            subject: issuer.subject,
+            spki: issuer.spki,
+        };
+        let list = ocsp::find_scts(
+            include_bytes!("testdata/ct-ocsp.der"),
+            &cert,
+            &issuer,
+            SCT_TIME / 1000,
+        )
+        .unwrap();
+        parse_sct_list(list)
+            .unwrap()
+            .into_iter()
+            .map(|sct| sct.0)
+            .collect()
+    }
+
+    #[cfg(feature = "ct-log-list")]
+    #[test]
+    fn parses_log_list() {
+        let logs = CtLogList::from_json(include_str!("testdata/ct-log-list.json")).unwrap();
+        assert_eq!(format!("{:?}", logs), format!("{:?}", log_list()));
+    }
+
+    #[cfg(feature = "ct-log-list")]
+    #[test]
+    fn rejects_invalid_log_list() {
+        let json = include_str!("testdata/ct-log-list.json");
+
+        for invalid in [
+            json.replacen("\"usable\"", "\"unheard-of\"", 1),
+            json.replacen("9m1YCHrF", "9m1YCHrG", 1),
+            json.replacen("MFkwEwYH", "MFkwEwY!", 1),
+            json.replacen("2026-01-01T00:00:00Z", "2026-01-01", 1),
+            json.replacen("\"operators\"", "\"operator\"", 1),
+            json[1..].to_string(),
+        ] {
+            assert!(CtLogList::from_json(&invalid).is_err());
+        }
+
+        // A log without a state is pending, and other fields are optional.
+        let minimal = json.replacen("\"state\"", "\"old_state\"", 1);
+        let logs = CtLogList::from_json(&minimal).unwrap();
+        assert!(matches!(logs.logs[0].state, LogState::Pending));
+        assert_eq!(logs.logs[0].key, log_list().logs[0].key);
+        assert!(CtLogList::from_json(r#"{"operators": []}"#)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn accepts_embedded_scts() {
+        let policy = enforcing(log_list(), CtRequirements::default());
+        assert_eq!(check(&policy, &[], &[]), Ok(()));
+
+        let stricter = CtRequirements {
+            min_embedded_scts: vec![(Duration::from_secs(86_400), 1)],
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&enforcing(log_list(), stricter), &[], &[]),
+            not_compliant()
+        );
+
+        let stricter = CtRequirements {
+            min_operators: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            check(&enforcing(log_list(), stricter), &[], &[]),
+            not_compliant()
+        );
+    }
+
+    #[test]
+    fn counts_log_operators() {
+        let mut logs = log_list();
+        beta(&mut logs).state = LogState::Pending;
+
+        // Both remaining SCTs are from logs operated by Alpha.
+        let policy = enforcing(logs.clone(), CtRequirements::default());
+        assert_eq!(check(&policy, &[], &[]), not_compliant());
+
+        let policy = enforcing(
+            logs,
+            CtRequirements {
+                min_operators: 1,
+                ..Default::default()
+            },
+        );
+        assert_eq!(check(&policy, &[], &[]), Ok(()));
+    }
+
+    #[test]
+    fn counts_retired_logs_for_earlier_embedded_scts() {
+        let mut logs = log_list();
+        beta(&mut logs).state = LogState::Retired(SCT_TIME + 1);
+        let policy = enforcing(logs.clone(), CtRequirements::default());
+        assert_eq!(check(&policy, &[], &[]), Ok(()));
+
+        beta(&mut logs).state = LogState::Retired(SCT_TIME);
+        let policy = enforcing(logs, CtRequirements::default());
+        assert_eq!(check(&policy, &[], &[]), not_compliant());
+    }
+
+    #[test]
+    fn checks_temporal_interval() {
+        // The certificate expires on 2025-04-01.
+        let mut logs = log_list();
+        beta(&mut logs).interval = Some((0, 1_743_465_600_000));
+        let policy = enforcing(logs, CtRequirements::default());
+        assert_eq!(check(&policy, &[], &[]), not_compliant());
+    }
+
+    #[test]
+    fn accepts_delivered_scts() {
+        let delivered_only = CtRequirements {
+            min_embedded_scts: vec![],
+            ..Default::default()
+        };
+        let policy = enforcing(log_list(), delivered_only);
+        assert_eq!(check(&policy, &[], &[]), not_compliant());
+
+        assert_eq!(
+            check(&policy, &[], include_bytes!("testdata/ct-ocsp.der")),
+            Ok(())
+        );
+
+        let scts = delivered_scts();
+        assert_eq!(check(&policy, &[&scts[0], &scts[1]], &[]), Ok(()));
+        assert_eq!(check(&policy, &[&scts[0], &scts[0]], &[]), not_compliant());
+    }
+
+    #[test]
+    fn rejects_invalid_sct() {
+        let policy = enforcing(log_list(), CtRequirements::default());
+        let mut sct = delivered_scts().remove(0);
+        *sct.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            check(&policy, &[&sct], &[]),
+            Err(Error::InvalidSct(sct::Error::InvalidSignature))
+        );
+
+        let too_late = CertificateTransparencyPolicy::from_log_list(
+            log_list(),
+            now() - Duration::from_secs(1),
+        );
+        assert_eq!(check(&too_late, &[&sct], &[]), Ok(()));
+    }
+
+    #[test]
+    fn opportunistic_policy_needs_one_known_log() {
+        let mut logs = log_list();
+        let policy = CertificateTransparencyPolicy::from_log_list(
+            logs.clone(),
+            now() + Duration::from_secs(1),
+        );
+        assert_eq!(check(&policy, &[], &[]), Ok(()));
+
+        logs.logs
+            .retain(|log| log.operator == "Beta");
+        let policy = CertificateTransparencyPolicy::from_log_list(
+            logs.clone(),
+            now() + Duration::from_secs(1),
+        );
+        assert_eq!(check(&policy, &[], &[]), Ok(()));
+
+        beta(&mut logs).id = [0; 32];
+        let policy =
+            CertificateTransparencyPolicy::from_log_list(logs, now() + Duration::from_secs(1));
+        assert_eq!(
+            check(&policy, &[], &[]),
+            Err(Error::InvalidSct(sct::Error::UnknownLog))
+        );
+    }
+}
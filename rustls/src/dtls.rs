@@ -0,0 +1,1353 @@
+//! DTLS1.3 ([RFC9147]) protects data sent over an unreliable datagram
+//! transport, such as UDP.
+//!
+//! The handshake is the TLS1.3 one, run by the same state machines as a
+//! [`crate::ClientConnection`] or [`crate::ServerConnection`] and configured
+//! with the same [`ClientConfig`] and [`ServerConfig`].  What differs is the
+//! record layer, which is implemented here: handshake messages are
+//! fragmented to fit in datagrams, acknowledged by the peer and
+//! retransmitted if lost; records carry their epoch and an encrypted
+//! sequence number in the unified header; and each application data write
+//! is sent in a record of its own.
+//!
+//! Like the rest of rustls, this does no IO.  Datagrams received from the
+//! peer are given to [`read_datagram`], and datagrams to send are taken
+//! from [`write_datagram`].  The caller also supplies the time: [`timeout`]
+//! says when [`handle_timeout`] should next be called, so that
+//! unacknowledged handshake messages are retransmitted.
+//!
+//! Not supported: early data, connection IDs, `KeyUpdate` and post-handshake
+//! client authentication.
+//!
+//! [RFC9147]: https://www.rfc-editor.org/rfc/rfc9147
+//! [`read_datagram`]: crate::dtls::ConnectionCommon::read_datagram
+//! [`write_datagram`]: crate::dtls::ConnectionCommon::write_datagram
+//! [`timeout`]: crate::dtls::ConnectionCommon::timeout
+//! [`handle_timeout`]: crate::dtls::ConnectionCommon::handle_timeout
+
+use crate::cipher::{make_nonce, Iv};
+use crate::client::{ClientConfig, ClientConnectionData, ServerName};
+use crate::conn;
+use crate::crypto::aead;
+use crate::crypto::hkdf::Prk;
+use crate::error::Error;
+use crate::key;
+#[cfg(feature = "logging")]
+use crate::log::{debug, trace};
+use crate::msgs::alert::AlertMessagePayload;
+use crate::msgs::codec::{self, Codec, Reader};
+use crate::msgs::enums::{AlertDescription, ContentType};
+use crate::server::{ServerConfig, ServerConnectionData};
+use crate::suites::SupportedCipherSuite;
+use crate::tls13::key_schedule::dtls_hkdf_expand;
+use crate::tls13::Tls13CipherSuite;
+use crate::ProtocolVersion;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A DTLS1.3 client connection.
+pub struct ClientConnection {
+    inner: ConnectionCommon<ClientConnectionData>,
+}
+
+impl ClientConnection {
+    /// Make a new DTLS client connection to the server `name`.
+    ///
+    /// `config` must support TLS1.3, and must not use Encrypted Client
+    /// Hello.  The ClientHello is ready to send straight away, from
+    /// [`ConnectionCommon::write_datagram`].
+    pub fn new(config: Arc<ClientConfig>, name: ServerName) -> Result<Self, Error> {
+        if !config.supports_version(ProtocolVersion::TLSv1_3) {
+            return Err(Error::General(
+                "TLS 1.3 support is required for DTLS".into(),
+            ));
+        }
+
+        if config.ech_config.is_some() {
+            return Err(Error::General(
+                "Encrypted Client Hello is not supported for DTLS".into(),
+            ));
+        }
+
+        let conn = crate::ClientConnection::new_dtls(config, name)?;
+        Ok(Self {
+            inner: ConnectionCommon::new(conn),
+        })
+    }
+}
+
+impl fmt::Debug for ClientConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("dtls::ClientConnection")
+            .finish()
+    }
+}
+
+impl Deref for ClientConnection {
+    type Target = ConnectionCommon<ClientConnectionData>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for ClientConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+/// A DTLS1.3 server connection.
+///
+/// A server has one of these per client, so it must route each datagram
+/// it receives to the right connection; typically by the peer's address.
+pub struct ServerConnection {
+    inner: ConnectionCommon<ServerConnectionData>,
+}
+
+impl ServerConnection {
+    /// Make a new DTLS server connection.
+    ///
+    /// `config` must support TLS1.3.
+    pub fn new(config: Arc<ServerConfig>) -> Result<Self, Error> {
+        if !config.supports_version(ProtocolVersion::TLSv1_3) {
+            return Err(Error::General(
+                "TLS 1.3 support is required for DTLS".into(),
+            ));
+        }
+
+        let conn = crate::ServerConnection::new_dtls(config)?;
+        Ok(Self {
+            inner: ConnectionCommon::new(conn),
+        })
+    }
+}
+
+impl fmt::Debug for ServerConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("dtls::ServerConnection")
+            .finish()
+    }
+}
+
+impl Deref for ServerConnection {
+    type Target = ConnectionCommon<ServerConnectionData>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for ServerConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+/// Interface shared by DTLS client and server connections.
+pub struct ConnectionCommon<Data> {
+    conn: conn::ConnectionCommon<Data>,
+    records: RecordLayer,
+}
+
+impl<Data> ConnectionCommon<Data> {
+    fn new(conn: conn::ConnectionCommon<Data>) -> Self {
+        let mut new = Self {
+            conn,
+            records: RecordLayer::new(),
+        };
+        new.records.take_messages(&mut new.conn);
+        new
+    }
+
+    /// Process a datagram received from the peer.
+    ///
+    /// Records which cannot be authenticated are discarded, as are
+    /// duplicates.  Errors are fatal to the connection: call
+    /// [`Self::write_datagram`] to send any alert queued as a result, and
+    /// then stop using the connection.
+    pub fn read_datagram(&mut self, datagram: &[u8]) -> Result<(), Error> {
+        self.conn.dtls_error()?;
+        let result = self
+            .records
+            .read_datagram(&mut self.conn, datagram);
+        self.records
+            .take_messages(&mut self.conn);
+        result
+    }
+
+    /// Returns the next datagram to send to the peer, or `None` if there
+    /// is nothing to send.
+    ///
+    /// Call this until it returns `None` after each call to
+    /// [`Self::read_datagram`], [`Self::handle_timeout`] or
+    /// [`Self::send_application_data`].
+    pub fn write_datagram(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.records
+            .write_datagram(&self.conn, now)
+    }
+
+    /// Returns when [`Self::handle_timeout`] should next be called, if a
+    /// retransmission is pending.
+    pub fn timeout(&self) -> Option<Instant> {
+        self.records.retransmit.deadline
+    }
+
+    /// Retransmit handshake messages the peer has not acknowledged, if
+    /// [`Self::timeout`] has passed.
+    ///
+    /// The timeout doubles with each retransmission, from one second up
+    /// to a minute.
+    pub fn handle_timeout(&mut self, now: Instant) {
+        self.records.handle_timeout(now);
+    }
+
+    /// Queue `data` to be sent to the peer in a record of its own.
+    ///
+    /// Like the datagrams carrying it, the record may be lost or
+    /// reordered.  Data given before the handshake completes is sent once
+    /// it does.  This fails if `data` is too large to fit in a datagram;
+    /// see [`Self::max_application_data_len`].
+    pub fn send_application_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.conn.dtls_error()?;
+        if data.len() > self.max_application_data_len() {
+            return Err(Error::General(
+                "application data too large for a datagram".into(),
+            ));
+        }
+
+        self.records
+            .application_data
+            .push_back(data.to_vec());
+        Ok(())
+    }
+
+    /// Returns the next record of application data received from the
+    /// peer, if any.
+    pub fn received_application_data(&mut self) -> Option<Vec<u8>> {
+        self.records.received.pop_front()
+    }
+
+    /// The most application data [`Self::send_application_data`] accepts
+    /// in one call.
+    pub fn max_application_data_len(&self) -> usize {
+        self.records.mtu - MAX_CIPHERTEXT_OVERHEAD
+    }
+
+    /// Sets the largest datagram this connection sends.
+    ///
+    /// The default, 1200 bytes, avoids IP fragmentation on most paths.
+    /// Values below 256 are treated as 256.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.records.mtu = mtu.max(MIN_MTU);
+    }
+
+    /// Returns true if the handshake is not yet complete.
+    pub fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    /// Returns true once the peer has sent a `close_notify` alert.
+    pub fn peer_has_closed(&self) -> bool {
+        self.conn.has_received_close_notify()
+    }
+
+    /// Retrieves the certificate chain used by the peer to authenticate.
+    ///
+    /// See [`crate::CommonState::peer_certificates`].
+    pub fn peer_certificates(&self) -> Option<&[key::Certificate]> {
+        self.conn.peer_certificates()
+    }
+
+    /// Retrieves the protocol agreed with the peer via ALPN.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    /// Retrieves the ciphersuite agreed with the peer.
+    pub fn negotiated_cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        self.conn.negotiated_cipher_suite()
+    }
+
+    /// Derives key material from the agreed connection secrets.
+    ///
+    /// See [`crate::ConnectionCommon::export_keying_material`].
+    pub fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        self.conn
+            .export_keying_material(output, label, context)
+    }
+
+    /// Queues a `close_notify` alert, to be sent by the next call to
+    /// [`Self::write_datagram`].
+    pub fn send_close_notify(&mut self) {
+        self.conn.send_close_notify();
+        self.records
+            .take_messages(&mut self.conn);
+    }
+}
+
+/// The DTLS record layer and its handshake message reliability.
+struct RecordLayer {
+    mtu: usize,
+    write_epochs: Vec<WriteEpoch>,
+    read_epochs: Vec<ReadEpoch>,
+
+    /// Our current flight of handshake messages, until it is acknowledged.
+    flight: Vec<OutgoingMessage>,
+    /// Which fragment of `flight` each record we sent carried.
+    sent: Vec<SentRecord>,
+    next_send_seq: u16,
+    retransmit: Retransmit,
+
+    /// Handshake messages being reassembled, by `message_seq`.
+    incoming: BTreeMap<u16, IncomingMessage>,
+    next_receive_seq: u16,
+    /// Encrypted records from epochs we don't have the keys for yet.
+    deferred: Vec<Vec<u8>>,
+
+    /// Records of handshake messages to acknowledge, as (epoch, sequence number).
+    acks: Vec<(u64, u64)>,
+    alerts: VecDeque<(u64, Vec<u8>)>,
+    application_data: VecDeque<Vec<u8>>,
+    received: VecDeque<Vec<u8>>,
+}
+
+impl RecordLayer {
+    fn new() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            write_epochs: vec![WriteEpoch {
+                epoch: 0,
+                keys: None,
+                next_seq: 0,
+            }],
+            read_epochs: vec![ReadEpoch {
+                epoch: 0,
+                keys: None,
+                window: ReplayWindow::default(),
+            }],
+            flight: Vec::new(),
+            sent: Vec::new(),
+            next_send_seq: 0,
+            retransmit: Retransmit::new(),
+            incoming: BTreeMap::new(),
+            next_receive_seq: 0,
+            deferred: Vec::new(),
+            acks: Vec::new(),
+            alerts: VecDeque::new(),
+            application_data: VecDeque::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Take new keys and messages to send from `conn`.
+    ///
+    /// Returns true if there are new keys for reading.
+    fn take_messages<Data>(&mut self, conn: &mut conn::ConnectionCommon<Data>) -> bool {
+        for (epoch, suite, secret) in conn.dtls.write_secrets.drain(..) {
+            self.write_epochs.push(WriteEpoch {
+                epoch,
+                keys: Some(RecordKeys::new(suite, &secret)),
+                next_seq: 0,
+            });
+        }
+
+        let new_read_keys = !conn.dtls.read_secrets.is_empty();
+        for (epoch, suite, secret) in conn.dtls.read_secrets.drain(..) {
+            self.read_epochs.push(ReadEpoch {
+                epoch,
+                keys: Some(RecordKeys::new(suite, &secret)),
+                window: ReplayWindow::default(),
+            });
+        }
+
+        let mut new_flight = false;
+        while let Some((epoch, typ, message)) = conn.dtls.queue.pop_front() {
+            if typ == ContentType::Alert {
+                self.alerts.push_back((epoch, message));
+                continue;
+            }
+
+            if !new_flight {
+                // A new flight implicitly acknowledges everything the peer sent
+                // before it, and replaces our previous flight.
+                new_flight = true;
+                self.end_flight();
+                self.acks.clear();
+            }
+
+            let max_fragment = self.mtu - record_overhead(epoch) - HANDSHAKE_HEADER_LEN;
+            let body_len = message.len() - TLS_HANDSHAKE_HEADER_LEN;
+            let mut fragments = Vec::new();
+            let mut offset = 0;
+            loop {
+                let len = Ord::min(max_fragment, body_len - offset);
+                fragments.push(Fragment {
+                    offset,
+                    len,
+                    acked: false,
+                    due: true,
+                });
+                offset += len;
+                if offset >= body_len {
+                    break;
+                }
+            }
+
+            self.flight.push(OutgoingMessage {
+                epoch,
+                message_seq: self.next_send_seq,
+                message,
+                fragments,
+            });
+            self.next_send_seq = self.next_send_seq.wrapping_add(1);
+        }
+
+        new_read_keys
+    }
+
+    fn end_flight(&mut self) {
+        self.flight.clear();
+        self.sent.clear();
+        self.retransmit = Retransmit::new();
+    }
+
+    fn handle_timeout(&mut self, now: Instant) {
+        match self.retransmit.deadline {
+            Some(deadline) if deadline <= now => {}
+            _ => return,
+        }
+
+        debug!("Retransmitting unacknowledged handshake messages");
+        self.retransmit.deadline = None;
+        self.retransmit.timeout = Ord::min(self.retransmit.timeout * 2, MAX_RETRANSMIT_TIMEOUT);
+        self.resend_flight();
+    }
+
+    fn resend_flight(&mut self) {
+        for message in self.flight.iter_mut() {
+            for fragment in message.fragments.iter_mut() {
+                fragment.due = !fragment.acked;
+            }
+        }
+    }
+
+    fn read_datagram<Data>(
+        &mut self,
+        conn: &mut conn::ConnectionCommon<Data>,
+        mut datagram: &[u8],
+    ) -> Result<(), Error> {
+        while !datagram.is_empty() {
+            let len = match record_len(datagram) {
+                Some(len) => len,
+                // Not a record we understand: the rest of the datagram
+                // can't be parsed either.
+                None => break,
+            };
+
+            let (record, rest) = datagram.split_at(len);
+            datagram = rest;
+            self.read_record(conn, record)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_record<Data>(
+        &mut self,
+        conn: &mut conn::ConnectionCommon<Data>,
+        record: &[u8],
+    ) -> Result<(), Error> {
+        let (epoch, seq, typ, payload) = match record[0] {
+            first if first & UNIFIED_HEADER_MASK == UNIFIED_HEADER_BITS => {
+                match self.open_record(record) {
+                    Opened::Record(epoch, seq, typ, payload) => (epoch, seq, typ, payload),
+                    Opened::Deferred => {
+                        if self.deferred.len() < MAX_DEFERRED_RECORDS {
+                            self.deferred.push(record.to_vec());
+                        }
+                        return Ok(());
+                    }
+                    Opened::Discarded => return Ok(()),
+                }
+            }
+            // Once handshake keys are installed, the peer sends nothing in
+            // the clear, so a plaintext record can only be stale or forged.
+            _ if conn.dtls.read_epoch > 0 => return Ok(()),
+            _ => match self.read_plaintext_record(record) {
+                Some((seq, typ, payload)) => (0, seq, typ, payload),
+                None => return Ok(()),
+            },
+        };
+
+        match typ {
+            ContentType::Handshake if epoch != 1 => self.read_handshake(conn, epoch, seq, &payload),
+            ContentType::Alert => {
+                let alert = match AlertMessagePayload::read_bytes(&payload) {
+                    Some(alert) => alert,
+                    None => {
+                        return Err(conn.fail_dtls(
+                            AlertDescription::DecodeError,
+                            Error::CorruptMessagePayload(ContentType::Alert),
+                        ))
+                    }
+                };
+                conn.read_dtls_alert(&alert)
+            }
+            ContentType::Ack if epoch >= HANDSHAKE_EPOCH => match read_ack(&payload) {
+                Some(acked) => {
+                    self.read_ack(&acked);
+                    Ok(())
+                }
+                None => Err(conn.fail_dtls(
+                    AlertDescription::DecodeError,
+                    Error::CorruptMessagePayload(ContentType::Ack),
+                )),
+            },
+            ContentType::ApplicationData if epoch > HANDSHAKE_EPOCH => {
+                self.received.push_back(payload);
+                Ok(())
+            }
+            _ => Err(conn.fail_dtls(
+                AlertDescription::UnexpectedMessage,
+                Error::InappropriateMessage {
+                    expect_types: vec![ContentType::Handshake],
+                    got_type: typ,
+                },
+            )),
+        }
+    }
+
+    /// Parses a `DTLSPlaintext` record, returning its sequence number,
+    /// type and payload.
+    fn read_plaintext_record(&mut self, record: &[u8]) -> Option<(u64, ContentType, Vec<u8>)> {
+        let mut r = Reader::init(record);
+        let typ = ContentType::read(&mut r)?;
+        let _version = ProtocolVersion::read(&mut r)?;
+        let epoch = u16::read(&mut r)?;
+        let seq = read_u48(&mut r)?;
+        let len = u16::read(&mut r)? as usize;
+        let payload = r.take(len)?;
+
+        // Only the first flight of each side is unprotected.
+        if epoch != 0 || !matches!(typ, ContentType::Handshake | ContentType::Alert) {
+            return None;
+        }
+
+        let window = &mut self.read_epochs[0].window;
+        if !window.is_fresh(seq) {
+            return None;
+        }
+        window.mark(seq);
+
+        Some((seq, typ, payload.to_vec()))
+    }
+
+    /// Decrypts a `DTLSCiphertext` record.
+    fn open_record(&mut self, record: &[u8]) -> Opened {
+        let first = record[0];
+        if first & CID_BIT != 0 {
+            // We never negotiate connection IDs.
+            return Opened::Discarded;
+        }
+
+        let seq_len = match first & SEQ_16_BIT {
+            0 => 1,
+            _ => 2,
+        };
+        let header_len = 1
+            + seq_len
+            + match first & LENGTH_BIT {
+                0 => 0,
+                _ => 2,
+            };
+        if record.len() < header_len + SN_MASK_SAMPLE_LEN {
+            return Opened::Discarded;
+        }
+
+        let epoch_bits = u64::from(first & EPOCH_BITS);
+        let read = match self
+            .read_epochs
+            .iter_mut()
+            .rev()
+            .find(|read| read.keys.is_some() && read.epoch & 3 == epoch_bits)
+        {
+            Some(read) => read,
+            None => return Opened::Deferred,
+        };
+        let keys = read.keys.as_ref().unwrap();
+
+        let (header, ciphertext) = record.split_at(header_len);
+        let mut header = header.to_vec();
+        let mask = match keys
+            .sn_key
+            .new_mask(&ciphertext[..SN_MASK_SAMPLE_LEN])
+        {
+            Ok(mask) => mask,
+            Err(_) => return Opened::Discarded,
+        };
+        for (byte, mask) in header[1..1 + seq_len]
+            .iter_mut()
+            .zip(mask.iter())
+        {
+            *byte ^= mask;
+        }
+
+        let low_seq = match seq_len {
+            1 => u64::from(header[1]),
+            _ => u64::from(u16::from_be_bytes([header[1], header[2]])),
+        };
+        let seq = reconstruct_seq(read.window.next, low_seq, seq_len as u32 * 8);
+        if !read.window.is_fresh(seq) {
+            return Opened::Discarded;
+        }
+
+        let mut payload = ciphertext.to_vec();
+        let plain_len =
+            match keys
+                .key
+                .open_in_place(make_nonce(&keys.iv, seq), &header, &mut payload)
+            {
+                Ok(plain_len) => plain_len,
+                Err(_) => {
+                    trace!("Discarding record which failed to decrypt");
+                    return Opened::Discarded;
+                }
+            };
+        payload.truncate(plain_len);
+        read.window.mark(seq);
+
+        // Strip padding to find the real content type.
+        let typ = loop {
+            match payload.pop() {
+                Some(0) => {}
+                Some(typ) => break ContentType::from(typ),
+                None => return Opened::Discarded,
+            }
+        };
+
+        Opened::Record(read.epoch, seq, typ, payload)
+    }
+
+    fn read_handshake<Data>(
+        &mut self,
+        conn: &mut conn::ConnectionCommon<Data>,
+        epoch: u64,
+        seq: u64,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        if epoch >= HANDSHAKE_EPOCH {
+            self.acks.push((epoch, seq));
+        }
+
+        let mut r = Reader::init(payload);
+        while r.any_left() {
+            let fragment = match HandshakeFragment::read(&mut r) {
+                Some(fragment) => fragment,
+                None => {
+                    return Err(conn.fail_dtls(
+                        AlertDescription::DecodeError,
+                        Error::CorruptMessagePayload(ContentType::Handshake),
+                    ))
+                }
+            };
+
+            let ahead = fragment
+                .message_seq
+                .wrapping_sub(self.next_receive_seq);
+            if ahead >= MAX_MESSAGES_AHEAD {
+                // A retransmission of something we have processed: the
+                // peer has not seen our response, so resend it.
+                trace!(
+                    "Received duplicate handshake message {}",
+                    fragment.message_seq
+                );
+                self.resend_flight();
+                continue;
+            }
+
+            let message = self
+                .incoming
+                .entry(fragment.message_seq)
+                .or_insert_with(|| IncomingMessage::new(&fragment, epoch));
+            if !message.add(&fragment, epoch) {
+                return Err(conn.fail_dtls(
+                    AlertDescription::IllegalParameter,
+                    Error::PeerMisbehavedError("inconsistent handshake fragments".into()),
+                ));
+            }
+        }
+
+        while self
+            .incoming
+            .get(&self.next_receive_seq)
+            .map_or(false, IncomingMessage::is_complete)
+        {
+            let message = self
+                .incoming
+                .remove(&self.next_receive_seq)
+                .unwrap();
+            self.next_receive_seq = self.next_receive_seq.wrapping_add(1);
+
+            if message.epoch != conn.dtls.read_epoch {
+                return Err(conn.fail_dtls(
+                    AlertDescription::UnexpectedMessage,
+                    Error::PeerMisbehavedError("handshake message in wrong epoch".into()),
+                ));
+            }
+
+            // Everything we sent so far must have been received for the
+            // peer to send this.
+            self.end_flight();
+
+            conn.read_dtls_hs(&message.encode())?;
+            if self.take_messages(conn) {
+                for record in mem::take(&mut self.deferred) {
+                    self.read_record(conn, &record)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_ack(&mut self, acked: &[(u64, u64)]) {
+        for (epoch, seq) in acked {
+            for sent in self
+                .sent
+                .iter()
+                .filter(|sent| sent.epoch == *epoch && sent.seq == *seq)
+            {
+                self.flight[sent.message].fragments[sent.fragment].acked = true;
+            }
+        }
+
+        let all_acked = self.flight.iter().all(|message| {
+            message
+                .fragments
+                .iter()
+                .all(|fragment| fragment.acked)
+        });
+        if all_acked {
+            self.end_flight();
+        }
+    }
+
+    fn write_datagram<Data>(
+        &mut self,
+        conn: &conn::ConnectionCommon<Data>,
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        let mut out = Vec::new();
+
+        if !self.acks.is_empty() {
+            let max = (self.mtu - MAX_CIPHERTEXT_OVERHEAD - 2) / 16;
+            let count = Ord::min(max, self.acks.len());
+            let acks = self
+                .acks
+                .drain(..count)
+                .collect::<Vec<_>>();
+            let mut payload = Vec::new();
+            ((acks.len() * 16) as u16).encode(&mut payload);
+            for (epoch, seq) in acks {
+                epoch.encode(&mut payload);
+                seq.encode(&mut payload);
+            }
+
+            let epoch = conn.dtls.write_epoch;
+            if epoch >= HANDSHAKE_EPOCH {
+                self.seal(epoch, ContentType::Ack, &payload, &mut out);
+            }
+        }
+
+        while let Some((epoch, alert)) = self.alerts.pop_front() {
+            self.seal(epoch, ContentType::Alert, &alert, &mut out);
+        }
+
+        let mut wrote_handshake = false;
+        for index in 0..self.flight.len() {
+            for fragment_index in 0..self.flight[index].fragments.len() {
+                let message = &self.flight[index];
+                let fragment = &message.fragments[fragment_index];
+                if !fragment.due {
+                    continue;
+                }
+
+                let len = record_overhead(message.epoch) + HANDSHAKE_HEADER_LEN + fragment.len;
+                if out.len() + len > self.mtu {
+                    break;
+                }
+
+                let epoch = message.epoch;
+                let payload = message.encode_fragment(fragment);
+                let seq = self.seal(epoch, ContentType::Handshake, &payload, &mut out);
+                self.flight[index].fragments[fragment_index].due = false;
+                self.sent.push(SentRecord {
+                    epoch,
+                    seq,
+                    message: index,
+                    fragment: fragment_index,
+                });
+                wrote_handshake = true;
+            }
+        }
+
+        if wrote_handshake && self.retransmit.deadline.is_none() {
+            self.retransmit.deadline = Some(now + self.retransmit.timeout);
+        }
+
+        if conn.may_send_application_data {
+            let epoch = conn.dtls.write_epoch;
+            while let Some(data) = self.application_data.front() {
+                if out.len() + MAX_CIPHERTEXT_OVERHEAD + data.len() > self.mtu {
+                    break;
+                }
+
+                let data = self
+                    .application_data
+                    .pop_front()
+                    .unwrap();
+                self.seal(epoch, ContentType::ApplicationData, &data, &mut out);
+            }
+        }
+
+        match out.is_empty() {
+            true => None,
+            false => Some(out),
+        }
+    }
+
+    /// Append a record of `payload` protected for `epoch` to `out`,
+    /// returning its sequence number.
+    fn seal(&mut self, epoch: u64, typ: ContentType, payload: &[u8], out: &mut Vec<u8>) -> u64 {
+        let write = self
+            .write_epochs
+            .iter_mut()
+            .find(|write| write.epoch == epoch)
+            .expect("no keys for epoch");
+        let seq = write.next_seq;
+        write.next_seq += 1;
+
+        let keys = match &write.keys {
+            Some(keys) => keys,
+            None => {
+                typ.encode(out);
+                ProtocolVersion::DTLSv1_2.encode(out);
+                0u16.encode(out);
+                out.extend_from_slice(&seq.to_be_bytes()[2..]);
+                (payload.len() as u16).encode(out);
+                out.extend_from_slice(payload);
+                return seq;
+            }
+        };
+
+        let tag_len = keys.tag_len;
+        let start = out.len();
+        out.push(UNIFIED_HEADER_BITS | SEQ_16_BIT | LENGTH_BIT | (epoch & 3) as u8);
+        (seq as u16).encode(out);
+        ((payload.len() + 1 + tag_len) as u16).encode(out);
+        let header_end = out.len();
+        out.extend_from_slice(payload);
+        typ.encode(out);
+
+        let mut header = [0u8; UNIFIED_HEADER_LEN];
+        header.copy_from_slice(&out[start..header_end]);
+        let tag = keys
+            .key
+            .seal_in_place_separate_tag(make_nonce(&keys.iv, seq), &header, &mut out[header_end..])
+            .expect("record too large");
+        out.extend_from_slice(tag.as_ref());
+
+        let mask = keys
+            .sn_key
+            .new_mask(&out[header_end..header_end + SN_MASK_SAMPLE_LEN])
+            .expect("sample too short");
+        out[start + 1] ^= mask[0];
+        out[start + 2] ^= mask[1];
+        seq
+    }
+}
+
+struct WriteEpoch {
+    epoch: u64,
+    /// `None` for epoch 0, which is unprotected.
+    keys: Option<RecordKeys>,
+    next_seq: u64,
+}
+
+struct ReadEpoch {
+    epoch: u64,
+    keys: Option<RecordKeys>,
+    window: ReplayWindow,
+}
+
+struct RecordKeys {
+    key: Box<dyn aead::Key>,
+    tag_len: usize,
+    iv: Iv,
+    /// Protects record sequence numbers (RFC9147 section 4.2.3)
+    sn_key: Box<dyn aead::HeaderProtectionKey>,
+}
+
+impl RecordKeys {
+    fn new(suite: &'static Tls13CipherSuite, secret: &Prk) -> Self {
+        let alg = suite.common.aead_algorithm;
+        let mut key = vec![0u8; alg.key_len()];
+        dtls_hkdf_expand(secret, b"key", &[], &mut key);
+        let mut iv = Iv::default();
+        dtls_hkdf_expand(secret, b"iv", &[], &mut iv.0);
+        let mut sn_key = vec![0u8; alg.key_len()];
+        dtls_hkdf_expand(secret, b"sn", &[], &mut sn_key);
+
+        Self {
+            key: alg.new_key(&key).unwrap(),
+            tag_len: alg.tag_len(),
+            iv,
+            sn_key: alg
+                .new_header_protection_key(&sn_key)
+                .unwrap(),
+        }
+    }
+}
+
+enum Opened {
+    Record(u64, u64, ContentType, Vec<u8>),
+    /// From an epoch whose keys we don't have yet.
+    Deferred,
+    Discarded,
+}
+
+/// Which of the records received in an epoch we have seen, to discard
+/// replays.
+#[derive(Default)]
+struct ReplayWindow {
+    /// One more than the highest sequence number received.
+    next: u64,
+    /// Bit `n` is set if `next - 1 - n` was received.
+    received: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, seq: u64) -> bool {
+        if seq >= self.next {
+            return true;
+        }
+
+        let age = self.next - 1 - seq;
+        age < 64 && self.received & (1 << age) == 0
+    }
+
+    fn mark(&mut self, seq: u64) {
+        if seq >= self.next {
+            let shift = seq + 1 - self.next;
+            self.received = match shift {
+                0..=63 => self.received << shift,
+                _ => 0,
+            } | 1;
+            self.next = seq + 1;
+        } else {
+            self.received |= 1 << (self.next - 1 - seq);
+        }
+    }
+}
+
+/// Recovers a sequence number from its low `bits` bits, choosing the
+/// value closest to `next` (RFC9147 section 4.2.2).
+fn reconstruct_seq(next: u64, low: u64, bits: u32) -> u64 {
+    let span = 1u64 << bits;
+    let candidate = (next & !(span - 1)) | low;
+    if candidate + span / 2 <= next {
+        candidate + span
+    } else if candidate > next + span / 2 && candidate >= span {
+        candidate - span
+    } else {
+        candidate
+    }
+}
+
+/// Returns the length of the record at the start of `datagram`.
+fn record_len(datagram: &[u8]) -> Option<usize> {
+    let first = *datagram.first()?;
+    if first & UNIFIED_HEADER_MASK == UNIFIED_HEADER_BITS {
+        let mut header_len = 1 + match first & SEQ_16_BIT {
+            0 => 1,
+            _ => 2,
+        };
+        if first & CID_BIT != 0 {
+            return None;
+        }
+        if first & LENGTH_BIT == 0 {
+            return Some(datagram.len());
+        }
+
+        let len = codec::decode_u16(datagram.get(header_len..header_len + 2)?)? as usize;
+        header_len += 2;
+        return Some(header_len + len).filter(|len| *len <= datagram.len());
+    }
+
+    let len = codec::decode_u16(datagram.get(PLAINTEXT_HEADER_LEN - 2..PLAINTEXT_HEADER_LEN)?)?;
+    Some(PLAINTEXT_HEADER_LEN + len as usize).filter(|len| *len <= datagram.len())
+}
+
+fn read_ack(payload: &[u8]) -> Option<Vec<(u64, u64)>> {
+    let mut r = Reader::init(payload);
+    let len = u16::read(&mut r)? as usize;
+    let mut sub = r.sub(len)?;
+    let mut acked = Vec::new();
+    while sub.any_left() {
+        acked.push((u64::read(&mut sub)?, u64::read(&mut sub)?));
+    }
+
+    match r.any_left() {
+        true => None,
+        false => Some(acked),
+    }
+}
+
+fn read_u48(r: &mut Reader) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    bytes[2..].copy_from_slice(r.take(6)?);
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// The most bytes a record adds to its payload, in `epoch`.
+fn record_overhead(epoch: u64) -> usize {
+    match epoch {
+        0 => PLAINTEXT_HEADER_LEN,
+        _ => MAX_CIPHERTEXT_OVERHEAD,
+    }
+}
+
+struct OutgoingMessage {
+    epoch: u64,
+    message_seq: u16,
+    /// The message in its TLS encoding, with a four byte header.
+    message: Vec<u8>,
+    fragments: Vec<Fragment>,
+}
+
+impl OutgoingMessage {
+    fn encode_fragment(&self, fragment: &Fragment) -> Vec<u8> {
+        let body = &self.message[TLS_HANDSHAKE_HEADER_LEN..];
+        let mut out = Vec::with_capacity(HANDSHAKE_HEADER_LEN + fragment.len);
+        out.extend_from_slice(&self.message[..TLS_HANDSHAKE_HEADER_LEN]);
+        self.message_seq.encode(&mut out);
+        codec::u24(fragment.offset as u32).encode(&mut out);
+        codec::u24(fragment.len as u32).encode(&mut out);
+        out.extend_from_slice(&body[fragment.offset..fragment.offset + fragment.len]);
+        out
+    }
+}
+
+struct Fragment {
+    offset: usize,
+    len: usize,
+    acked: bool,
+    /// Whether this fragment should be sent by the next `write_datagram`.
+    due: bool,
+}
+
+struct SentRecord {
+    epoch: u64,
+    seq: u64,
+    message: usize,
+    fragment: usize,
+}
+
+struct HandshakeFragment<'a> {
+    typ: u8,
+    length: usize,
+    message_seq: u16,
+    offset: usize,
+    body: &'a [u8],
+}
+
+impl<'a> HandshakeFragment<'a> {
+    fn read(r: &mut Reader<'a>) -> Option<Self> {
+        let typ = u8::read(r)?;
+        let length = usize::from(codec::u24::read(r)?);
+        let message_seq = u16::read(r)?;
+        let offset = usize::from(codec::u24::read(r)?);
+        let fragment_len = usize::from(codec::u24::read(r)?);
+        let body = r.take(fragment_len)?;
+        if length > MAX_HANDSHAKE_SIZE || offset + fragment_len > length {
+            return None;
+        }
+
+        Some(Self {
+            typ,
+            length,
+            message_seq,
+            offset,
+            body,
+        })
+    }
+}
+
+struct IncomingMessage {
+    typ: u8,
+    epoch: u64,
+    body: Vec<u8>,
+    /// Byte ranges of `body` received so far, sorted and disjoint.
+    received: Vec<(usize, usize)>,
+}
+
+impl IncomingMessage {
+    fn new(fragment: &HandshakeFragment, epoch: u64) -> Self {
+        Self {
+            typ: fragment.typ,
+            epoch,
+            body: vec![0u8; fragment.length],
+            received: Vec::new(),
+        }
+    }
+
+    /// Adds `fragment`, returning false if it contradicts earlier fragments.
+    fn add(&mut self, fragment: &HandshakeFragment, epoch: u64) -> bool {
+        if fragment.typ != self.typ || fragment.length != self.body.len() || epoch != self.epoch {
+            return false;
+        }
+
+        let (start, end) = (fragment.offset, fragment.offset + fragment.body.len());
+        self.body[start..end].copy_from_slice(fragment.body);
+
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = Ord::max(last.1, end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+        true
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.received.as_slice() {
+            [(0, end)] => *end == self.body.len(),
+            [] => self.body.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// The message in its TLS encoding.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(TLS_HANDSHAKE_HEADER_LEN + self.body.len());
+        self.typ.encode(&mut out);
+        codec::u24(self.body.len() as u32).encode(&mut out);
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+struct Retransmit {
+    timeout: Duration,
+    /// When to retransmit; `None` until our flight is sent.
+    deadline: Option<Instant>,
+}
+
+impl Retransmit {
+    fn new() -> Self {
+        Self {
+            timeout: INITIAL_RETRANSMIT_TIMEOUT,
+            deadline: None,
+        }
+    }
+}
+
+const DEFAULT_MTU: usize = 1200;
+const MIN_MTU: usize = 256;
+
+const INITIAL_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Epoch 2 carries the encrypted part of the handshake; later epochs carry
+/// application data.
+const HANDSHAKE_EPOCH: u64 = 2;
+
+/// The DTLSCiphertext unified header starts with the bits 001CSLEE.
+const UNIFIED_HEADER_MASK: u8 = 0b1110_0000;
+const UNIFIED_HEADER_BITS: u8 = 0b0010_0000;
+const CID_BIT: u8 = 0b0001_0000;
+const SEQ_16_BIT: u8 = 0b0000_1000;
+const LENGTH_BIT: u8 = 0b0000_0100;
+const EPOCH_BITS: u8 = 0b0000_0011;
+
+/// The unified header we send: first byte, 16-bit sequence number and length.
+const UNIFIED_HEADER_LEN: usize = 5;
+/// Header, inner content type and AEAD tag.
+const MAX_CIPHERTEXT_OVERHEAD: usize = UNIFIED_HEADER_LEN + 1 + 16;
+const PLAINTEXT_HEADER_LEN: usize = 13;
+const SN_MASK_SAMPLE_LEN: usize = 16;
+
+/// msg_type, length, message_seq, fragment_offset and fragment_length.
+const HANDSHAKE_HEADER_LEN: usize = 12;
+const TLS_HANDSHAKE_HEADER_LEN: usize = 4;
+const MAX_HANDSHAKE_SIZE: usize = 0xffff;
+/// How far ahead of the next expected handshake message we buffer.
+const MAX_MESSAGES_AHEAD: u16 = 16;
+const MAX_DEFERRED_RECORDS: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.is_fresh(0));
+        window.mark(0);
+        assert!(!window.is_fresh(0));
+
+        window.mark(5);
+        assert!(window.is_fresh(3));
+        window.mark(3);
+        assert!(!window.is_fresh(3));
+        assert!(!window.is_fresh(5));
+        assert!(window.is_fresh(6));
+
+        window.mark(100);
+        assert!(!window.is_fresh(5));
+        assert!(window.is_fresh(99));
+    }
+
+    #[test]
+    fn sequence_number_reconstruction() {
+        assert_eq!(reconstruct_seq(0, 0, 16), 0);
+        assert_eq!(reconstruct_seq(5, 3, 16), 3);
+        assert_eq!(reconstruct_seq(0xffff, 0x0001, 16), 0x1_0001);
+        assert_eq!(reconstruct_seq(0x1_0002, 0xfffe, 16), 0xfffe);
+        assert_eq!(reconstruct_seq(0x1ff, 0x02, 8), 0x202);
+    }
+
+    #[test]
+    fn ack_encoding() {
+        let mut payload = Vec::new();
+        32u16.encode(&mut payload);
+        for n in [2u64, 7, 3, 0] {
+            n.encode(&mut payload);
+        }
+        assert_eq!(read_ack(&payload), Some(vec![(2, 7), (3, 0)]));
+
+        payload.push(0);
+        assert_eq!(read_ack(&payload), None);
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let secret = Prk::extract(&crate::crypto::ring::hmac::HMAC_SHA256, b"salt", b"secret");
+        let suite = crate::tls13::TLS13_AES_128_GCM_SHA256_INTERNAL;
+
+        let mut sender = RecordLayer::new();
+        sender.write_epochs.push(WriteEpoch {
+            epoch: 2,
+            keys: Some(RecordKeys::new(suite, &secret)),
+            next_seq: 0,
+        });
+        let mut receiver = RecordLayer::new();
+        receiver.read_epochs.push(ReadEpoch {
+            epoch: 2,
+            keys: Some(RecordKeys::new(suite, &secret)),
+            window: ReplayWindow::default(),
+        });
+
+        let mut datagram = Vec::new();
+        sender.seal(0, ContentType::Handshake, b"hello", &mut datagram);
+        sender.seal(2, ContentType::ApplicationData, b"world", &mut datagram);
+        sender.seal(2, ContentType::ApplicationData, b"again", &mut datagram);
+
+        // The plaintext record, then two in the unified header format.
+        let first = record_len(&datagram).unwrap();
+        assert_eq!(first, PLAINTEXT_HEADER_LEN + 5);
+        assert_eq!(datagram[first], 0x2e);
+
+        let (plaintext, rest) = datagram.split_at(first);
+        assert_eq!(
+            receiver.read_plaintext_record(plaintext),
+            Some((0, ContentType::Handshake, b"hello".to_vec()))
+        );
+        let (one, two) = rest.split_at(record_len(rest).unwrap());
+        assert_eq!(record_len(two), Some(two.len()));
+
+        // The sequence number is encrypted.
+        assert_ne!(&two[1..3], &[0, 1]);
+
+        for (record, seq, data) in [(two, 1, b"again"), (one, 0, b"world")] {
+            match receiver.open_record(record) {
+                Opened::Record(epoch, got_seq, typ, payload) => {
+                    assert_eq!(epoch, 2);
+                    assert_eq!(got_seq, seq);
+                    assert_eq!(typ, ContentType::ApplicationData);
+                    assert_eq!(payload, data);
+                }
+                _ => panic!("record not opened"),
+            }
+        }
+
+        // Replays are discarded.
+        assert!(matches!(receiver.open_record(one), Opened::Discarded));
+
+        // Records for unknown epochs wait for their keys.
+        let mut future = one.to_vec();
+        future[0] |= 1;
+        assert!(matches!(receiver.open_record(&future), Opened::Deferred));
+    }
+
+    #[test]
+    fn handshake_reassembly() {
+        let body = (0..100u8).collect::<Vec<_>>();
+        let mut message = vec![2, 0, 0, 100];
+        message.extend_from_slice(&body);
+        let outgoing = OutgoingMessage {
+            epoch: 0,
+            message_seq: 1,
+            message: message.clone(),
+            fragments: Vec::new(),
+        };
+
+        let fragments = [(60, 40), (0, 30), (20, 50)]
+            .iter()
+            .map(|&(offset, len)| {
+                outgoing.encode_fragment(&Fragment {
+                    offset,
+                    len,
+                    acked: false,
+                    due: true,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let read = |bytes: &[u8]| {
+            let mut r = Reader::init(bytes);
+            let fragment = HandshakeFragment::read(&mut r).unwrap();
+            assert!(!r.any_left());
+            (fragment.offset, fragment.body.len(), fragment.message_seq)
+        };
+        assert_eq!(read(&fragments[0]), (60, 40, 1));
+
+        let mut r = Reader::init(&fragments[0]);
+        let mut incoming = IncomingMessage::new(&HandshakeFragment::read(&mut r).unwrap(), 0);
+        for (i, bytes) in fragments.iter().enumerate() {
+            let mut r = Reader::init(bytes);
+            assert!(incoming.add(&HandshakeFragment::read(&mut r).unwrap(), 0));
+            assert_eq!(incoming.is_complete(), i == 2);
+        }
+        assert_eq!(incoming.encode(), message);
+
+        // A fragment from another epoch is refused.
+        let mut r = Reader::init(&fragments[1]);
+        assert!(!incoming.add(&HandshakeFragment::read(&mut r).unwrap(), 2));
+    }
+}
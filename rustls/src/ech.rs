@@ -0,0 +1,36 @@
+//! Parts of Encrypted Client Hello shared by clients and servers.
+
+use crate::crypto::hash;
+use crate::crypto::hkdf::Prk;
+use crate::msgs::base::PayloadU8;
+use crate::msgs::handshake::Random;
+use crate::tls13::key_schedule::hkdf_expand;
+use crate::tls13::Tls13CipherSuite;
+
+/// Prefix of the HPKE `info` parameter; the ECHConfig follows.
+pub(crate) const HPKE_INFO_PREFIX: &[u8] = b"tls ech\0";
+
+/// Length of the acceptance signal in the server's random or HRR.
+pub(crate) const CONFIRMATION_LEN: usize = 8;
+
+pub(crate) const SERVER_HELLO_CONFIRMATION_LABEL: &[u8] = b"ech accept confirmation";
+pub(crate) const HRR_CONFIRMATION_LABEL: &[u8] = b"hrr ech accept confirmation";
+
+/// Compute the acceptance signal over `hash`, a transcript hash of the
+/// inner handshake with the signal itself zeroed.
+pub(crate) fn confirmation(
+    suite: &'static Tls13CipherSuite,
+    inner_random: &Random,
+    label: &[u8],
+    hash: &hash::Output,
+) -> PayloadU8 {
+    let zeroes = [0u8; hash::MAX_LEN];
+    let prk = Prk::extract(
+        suite.hmac,
+        &zeroes[..suite.hash_algorithm().output_len()],
+        &inner_random.0,
+    );
+    let mut out = vec![0u8; CONFIRMATION_LEN];
+    hkdf_expand(&prk, label, hash.as_ref(), &mut out);
+    PayloadU8::new(out)
+}
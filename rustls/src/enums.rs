@@ -429,3 +429,16 @@ enum_builder! {
         ED448 => 0x0808
     }
 }
+
+enum_builder! {
+    /// The `CertificateCompressionAlgorithm` TLS protocol enum, from RFC 8879.
+    /// Values in this enum are taken from the IANA registry.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: CertificateCompressionAlgorithm;
+    EnumVal{
+        Zlib => 0x0001,
+        Brotli => 0x0002,
+        Zstd => 0x0003
+    }
+}
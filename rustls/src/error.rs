@@ -72,6 +72,9 @@ pub enum Error {
     /// We received a certificate which includes invalid data.
     InvalidCertificateData(String),
 
+    /// We received a certificate which has been revoked by its issuer.
+    CertificateRevoked,
+
     /// The presented SCT(s) were invalid.
     InvalidSct(sct::Error),
 
@@ -91,12 +94,31 @@ pub enum Error {
     /// The peer sent an oversized record/fragment.
     PeerSentOversizedRecord,
 
+    /// The peer sent a protected record larger than the
+    /// `record_size_limit` we advertised to it.
+    PeerExceededRecordSizeLimit,
+
     /// An incoming connection did not support any known application protocol.
     NoApplicationProtocol,
 
     /// The `max_fragment_size` value supplied in configuration was too small,
-    /// or too large.
+    /// or too large, or the `max_fragment_length` was not one defined by RFC6066.
     BadMaxFragmentSize,
+
+    /// The `record_size_limit` value supplied in configuration was too small,
+    /// or too large.
+    BadRecordSizeLimit,
+
+    /// The server rejected our Encrypted Client Hello offer.  The
+    /// connection was authenticated under the ECH configuration's
+    /// public name, then closed.
+    ///
+    /// `retry_configs` holds the encoded `ECHConfigList` the server
+    /// provided, if any; retrying with one of these may succeed.
+    EncryptedClientHelloRejected {
+        /// The server's replacement ECH configurations
+        retry_configs: Option<Vec<u8>>,
+    },
 }
 
 fn join<T: fmt::Debug>(items: &[T]) -> String {
@@ -146,12 +168,19 @@ impl fmt::Display for Error {
             Self::InvalidCertificateData(ref reason) => {
                 write!(f, "invalid peer certificate contents: {}", reason)
             }
+            Self::CertificateRevoked => write!(f, "peer certificate has been revoked"),
             Self::CorruptMessage => write!(f, "received corrupt message"),
             Self::NoCertificatesPresented => write!(f, "peer sent no certificates"),
             Self::UnsupportedNameType => write!(f, "presented server name type wasn't supported"),
             Self::DecryptError => write!(f, "cannot decrypt peer's message"),
             Self::EncryptError => write!(f, "cannot encrypt message"),
             Self::PeerSentOversizedRecord => write!(f, "peer sent excess record size"),
+            Self::PeerExceededRecordSizeLimit => {
+                write!(
+                    f,
+                    "peer sent record larger than the negotiated record_size_limit"
+                )
+            }
             Self::HandshakeNotComplete => write!(f, "handshake not complete"),
             Self::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             Self::InvalidSct(ref err) => write!(f, "invalid certificate timestamp: {:?}", err),
@@ -160,6 +189,12 @@ impl fmt::Display for Error {
             Self::BadMaxFragmentSize => {
                 write!(f, "the supplied max_fragment_size was too small or large")
             }
+            Self::BadRecordSizeLimit => {
+                write!(f, "the supplied record_size_limit was too small or large")
+            }
+            Self::EncryptedClientHelloRejected { .. } => {
+                write!(f, "server rejected encrypted client hello")
+            }
             Self::General(ref err) => write!(f, "unexpected error: {}", err),
         }
     }
@@ -209,14 +244,20 @@ mod tests {
             Error::InvalidCertificateSignatureType,
             Error::InvalidCertificateSignature,
             Error::InvalidCertificateData("Data".into()),
+            Error::CertificateRevoked,
             Error::InvalidSct(sct::Error::MalformedSct),
             Error::General("undocumented error".to_string()),
             Error::FailedToGetCurrentTime,
             Error::FailedToGetRandomBytes,
             Error::HandshakeNotComplete,
             Error::PeerSentOversizedRecord,
+            Error::PeerExceededRecordSizeLimit,
             Error::NoApplicationProtocol,
             Error::BadMaxFragmentSize,
+            Error::BadRecordSizeLimit,
+            Error::EncryptedClientHelloRejected {
+                retry_configs: Some(vec![]),
+            },
         ];
 
         for err in all {
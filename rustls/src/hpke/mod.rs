@@ -0,0 +1,600 @@
+//! Hybrid Public Key Encryption (RFC9180), base mode only.
+//!
+//! This supports exactly the parts needed by Encrypted Client Hello:
+//! the DHKEM(X25519, HKDF-SHA256) KEM, the HKDF-SHA2 KDFs and the
+//! AES-GCM and ChaCha20-Poly1305 AEADs.
+//!
+//! The KDF's HMAC and the AEAD are taken from the TLS1.3 cipher suites
+//! of the connection's [`CryptoProvider`], so a KDF or AEAD is only
+//! available if one of those suites uses it.  Senders also use the
+//! provider's X25519 key exchange.  Receivers hold a long-lived private
+//! key, which *ring* cannot do, so they need the `ech_server` feature: it
+//! uses the `x25519-dalek` crate.
+
+use crate::crypto::aead::{self, NONCE_LEN};
+use crate::crypto::hkdf::Prk;
+use crate::crypto::hmac::Hmac;
+use crate::crypto::{self, ActiveKeyExchange, CryptoProvider};
+use crate::error::Error;
+use crate::msgs::enums::{HashAlgorithm, HpkeAead, HpkeKdf, HpkeKem, NamedGroup};
+use crate::msgs::handshake::HpkeSymmetricCipherSuite;
+use crate::suites::BulkAlgorithm;
+use crate::tls13::Tls13CipherSuite;
+
+const VERSION_LABEL: &[u8] = b"HPKE-v1";
+const MODE_BASE: u8 = 0x00;
+
+/// The length of an X25519 public key, and of its shared secrets.
+const X25519_LEN: usize = 32;
+
+/// The KEMs we support.
+pub(crate) const SUPPORTED_KEMS: &[HpkeKem] = &[HpkeKem::DHKEM_X25519_HKDF_SHA256];
+
+/// Returns true if this KDF and AEAD combination is one we know, so could
+/// use with a provider that implements it.
+pub(crate) fn is_supported_suite(suite: &HpkeSymmetricCipherSuite) -> bool {
+    kdf_hash(suite.kdf_id).is_some() && aead_bulk(suite.aead_id).is_some()
+}
+
+/// Returns true if `provider` implements this KDF and AEAD combination.
+pub(crate) fn provider_supports_suite(
+    provider: &dyn CryptoProvider,
+    suite: &HpkeSymmetricCipherSuite,
+) -> bool {
+    kdf_hmac(provider, suite.kdf_id).is_some() && aead_algorithm(provider, suite.aead_id).is_some()
+}
+
+fn kdf_hash(kdf: HpkeKdf) -> Option<HashAlgorithm> {
+    match kdf {
+        HpkeKdf::HKDF_SHA256 => Some(HashAlgorithm::SHA256),
+        HpkeKdf::HKDF_SHA384 => Some(HashAlgorithm::SHA384),
+        HpkeKdf::HKDF_SHA512 => Some(HashAlgorithm::SHA512),
+        _ => None,
+    }
+}
+
+fn aead_bulk(aead: HpkeAead) -> Option<BulkAlgorithm> {
+    match aead {
+        HpkeAead::AES_128_GCM => Some(BulkAlgorithm::Aes128Gcm),
+        HpkeAead::AES_256_GCM => Some(BulkAlgorithm::Aes256Gcm),
+        HpkeAead::CHACHA20_POLY_1305 => Some(BulkAlgorithm::Chacha20Poly1305),
+        _ => None,
+    }
+}
+
+fn tls13_suites(provider: &dyn CryptoProvider) -> impl Iterator<Item = &'static Tls13CipherSuite> {
+    provider
+        .default_cipher_suites()
+        .iter()
+        .filter_map(|scs| scs.tls13())
+}
+
+fn kdf_hmac(provider: &dyn CryptoProvider, kdf: HpkeKdf) -> Option<&'static dyn Hmac> {
+    crypto::find_hmac(provider, kdf_hash(kdf)?)
+}
+
+/// The AEAD for `aead`, from the first of `provider`'s TLS1.3 suites that
+/// uses it.
+fn aead_algorithm(
+    provider: &dyn CryptoProvider,
+    aead: HpkeAead,
+) -> Option<&'static dyn aead::Algorithm> {
+    let bulk = aead_bulk(aead)?;
+    tls13_suites(provider)
+        .map(|suite| &suite.common)
+        .find(|common| common.bulk == bulk)
+        .map(|common| common.aead_algorithm)
+}
+
+/// An X25519 key pair, as used by an HPKE receiver.
+#[cfg(feature = "ech_server")]
+#[derive(Clone)]
+pub(crate) struct HpkeKeyPair {
+    private: x25519_dalek::StaticSecret,
+    pub(crate) public: [u8; X25519_LEN],
+}
+
+#[cfg(feature = "ech_server")]
+impl HpkeKeyPair {
+    /// Generate a fresh key pair.
+    pub(crate) fn generate() -> Result<Self, Error> {
+        let mut private = [0u8; X25519_LEN];
+        crypto::ring::RING.fill_random(&mut private)?;
+        Ok(Self::from_private(private))
+    }
+
+    /// Rebuild a key pair from a serialized private key.
+    pub(crate) fn from_private_bytes(bytes: &[u8]) -> Option<Self> {
+        let private: [u8; X25519_LEN] = bytes.try_into().ok()?;
+        Some(Self::from_private(private))
+    }
+
+    fn from_private(private: [u8; X25519_LEN]) -> Self {
+        let private = x25519_dalek::StaticSecret::from(private);
+        Self {
+            public: x25519_dalek::PublicKey::from(&private).to_bytes(),
+            private,
+        }
+    }
+
+    pub(crate) fn private_bytes(&self) -> &[u8] {
+        self.private.as_bytes()
+    }
+
+    /// Compute the X25519 shared secret with the peer's `public` key.
+    ///
+    /// Returns `None` if the peer supplied a point of small order.
+    fn agree(&self, public: &[u8]) -> Option<crypto::SharedSecret> {
+        let public: [u8; X25519_LEN] = public.try_into().ok()?;
+        let shared = self
+            .private
+            .diffie_hellman(&x25519_dalek::PublicKey::from(public));
+        match shared.was_contributory() {
+            true => Some(crypto::SharedSecret::from(&shared.as_bytes()[..])),
+            false => None,
+        }
+    }
+}
+
+/// The `LabeledExtract` and `LabeledExpand` functions, for a given `suite_id`.
+struct Labeled<'a> {
+    hmac: &'static dyn Hmac,
+    suite_id: &'a [u8],
+}
+
+impl Labeled<'_> {
+    fn extract(&self, salt: &[u8], label: &[u8], ikm: &[u8]) -> Prk {
+        let labeled_ikm = [VERSION_LABEL, self.suite_id, label, ikm].concat();
+        Prk::extract(self.hmac, salt, &labeled_ikm)
+    }
+
+    /// As `extract`, but returning the PRK's bytes.  HKDF-Extract is just
+    /// HMAC keyed with the salt: an empty salt is equivalent to the
+    /// all-zero one RFC5869 specifies, as HMAC pads keys with zeroes.
+    fn extract_bytes(&self, salt: &[u8], label: &[u8], ikm: &[u8]) -> Vec<u8> {
+        let labeled_ikm = [VERSION_LABEL, self.suite_id, label, ikm].concat();
+        self.hmac
+            .with_key(salt)
+            .sign(&[&labeled_ikm])
+            .as_ref()
+            .to_vec()
+    }
+
+    fn expand(&self, prk: &Prk, label: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+        let len_bytes = (len as u16).to_be_bytes();
+        let parts = [&len_bytes[..], VERSION_LABEL, self.suite_id, label, info];
+        let mut out = vec![0u8; len];
+        // Cannot fail: every length we ask for is far below the limit.
+        prk.expand(&parts, &mut out).unwrap();
+        out
+    }
+}
+
+fn kem_suite_id(kem: HpkeKem) -> Vec<u8> {
+    [&b"KEM"[..], &kem.get_u16().to_be_bytes()].concat()
+}
+
+fn hpke_suite_id(kem: HpkeKem, suite: &HpkeSymmetricCipherSuite) -> Vec<u8> {
+    [
+        &b"HPKE"[..],
+        &kem.get_u16().to_be_bytes(),
+        &suite.kdf_id.get_u16().to_be_bytes(),
+        &suite.aead_id.get_u16().to_be_bytes(),
+    ]
+    .concat()
+}
+
+/// DHKEM's `ExtractAndExpand`.
+fn kem_shared_secret(
+    provider: &dyn CryptoProvider,
+    dh: &[u8],
+    kem_context: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let hmac = kdf_hmac(provider, HpkeKdf::HKDF_SHA256)
+        .ok_or_else(|| Error::General("HPKE needs the provider to support SHA-256".into()))?;
+    let suite_id = kem_suite_id(HpkeKem::DHKEM_X25519_HKDF_SHA256);
+    let labeled = Labeled {
+        hmac,
+        suite_id: &suite_id,
+    };
+    let eae_prk = labeled.extract(&[], b"eae_prk", dh);
+    Ok(labeled.expand(&eae_prk, b"shared_secret", kem_context, 32))
+}
+
+fn key_schedule(
+    provider: &dyn CryptoProvider,
+    kem: HpkeKem,
+    suite: &HpkeSymmetricCipherSuite,
+    shared_secret: &[u8],
+    info: &[u8],
+) -> Result<Context, Error> {
+    let (hmac, aead_alg) = match (
+        kdf_hmac(provider, suite.kdf_id),
+        aead_algorithm(provider, suite.aead_id),
+    ) {
+        (Some(hmac), Some(aead_alg)) => (hmac, aead_alg),
+        _ => return Err(Error::General("unsupported HPKE cipher suite".into())),
+    };
+
+    let suite_id = hpke_suite_id(kem, suite);
+    let labeled = Labeled {
+        hmac,
+        suite_id: &suite_id,
+    };
+
+    let psk_id_hash = labeled.extract_bytes(&[], b"psk_id_hash", &[]);
+    let info_hash = labeled.extract_bytes(&[], b"info_hash", info);
+    let context = [&[MODE_BASE][..], &psk_id_hash, &info_hash].concat();
+
+    let secret = labeled.extract(shared_secret, b"secret", &[]);
+    let key = labeled.expand(&secret, b"key", &context, aead_alg.key_len());
+    let base_nonce = labeled.expand(&secret, b"base_nonce", &context, NONCE_LEN);
+
+    Ok(Context {
+        key: aead_alg.new_key(&key)?,
+        tag_len: aead_alg.tag_len(),
+        base_nonce: base_nonce.try_into().unwrap(),
+        seq: 0,
+    })
+}
+
+/// Set up an HPKE sender context in base mode, encapsulating to `public_key`.
+///
+/// The ephemeral key pair comes from `provider`'s X25519 group.
+/// Returns the encapsulated key `enc` and the context.
+pub(crate) fn setup_base_sender(
+    provider: &dyn CryptoProvider,
+    kem: HpkeKem,
+    suite: &HpkeSymmetricCipherSuite,
+    public_key: &[u8],
+    info: &[u8],
+) -> Result<(Vec<u8>, Context), Error> {
+    let group = crypto::find_kx_group(NamedGroup::X25519, provider.default_kx_groups())
+        .ok_or_else(|| Error::General("HPKE needs the provider to support X25519".into()))?;
+    setup_base_sender_with_ephemeral(provider, kem, suite, public_key, info, group.start()?)
+}
+
+fn setup_base_sender_with_ephemeral(
+    provider: &dyn CryptoProvider,
+    kem: HpkeKem,
+    suite: &HpkeSymmetricCipherSuite,
+    public_key: &[u8],
+    info: &[u8],
+    ephemeral: Box<dyn ActiveKeyExchange>,
+) -> Result<(Vec<u8>, Context), Error> {
+    if kem != HpkeKem::DHKEM_X25519_HKDF_SHA256 || public_key.len() != X25519_LEN {
+        return Err(Error::General("unsupported HPKE KEM".into()));
+    }
+
+    let enc = ephemeral.pub_key().to_vec();
+    let dh = ephemeral
+        .complete(public_key)
+        .ok()
+        .filter(|dh| !is_all_zero(dh.secret_bytes()))
+        .ok_or_else(|| Error::General("HPKE encapsulation failed".into()))?;
+    let kem_context = [&enc[..], public_key].concat();
+    let shared_secret = kem_shared_secret(provider, dh.secret_bytes(), &kem_context)?;
+
+    Ok((
+        enc,
+        key_schedule(provider, kem, suite, &shared_secret, info)?,
+    ))
+}
+
+/// Set up an HPKE receiver context in base mode, decapsulating `enc`
+/// with `key_pair`.
+#[cfg(feature = "ech_server")]
+pub(crate) fn setup_base_receiver(
+    provider: &dyn CryptoProvider,
+    kem: HpkeKem,
+    suite: &HpkeSymmetricCipherSuite,
+    enc: &[u8],
+    key_pair: &HpkeKeyPair,
+    info: &[u8],
+) -> Result<Context, Error> {
+    if kem != HpkeKem::DHKEM_X25519_HKDF_SHA256 {
+        return Err(Error::General("unsupported HPKE KEM".into()));
+    }
+
+    let dh = key_pair
+        .agree(enc)
+        .ok_or(Error::DecryptError)?;
+    let kem_context = [enc, &key_pair.public[..]].concat();
+    let shared_secret = kem_shared_secret(provider, dh.secret_bytes(), &kem_context)?;
+
+    key_schedule(provider, kem, suite, &shared_secret, info)
+}
+
+/// An X25519 output of all zeroes means the peer's key had small order.
+fn is_all_zero(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, b| acc | b) == 0
+}
+
+/// An HPKE encryption context.  The same type serves senders and receivers.
+pub(crate) struct Context {
+    key: Box<dyn aead::Key>,
+    tag_len: usize,
+    base_nonce: [u8; NONCE_LEN],
+    seq: u64,
+}
+
+impl Context {
+    fn next_nonce(&mut self) -> Result<[u8; NONCE_LEN], Error> {
+        let mut nonce = self.base_nonce;
+        for (n, s) in nonce[NONCE_LEN - 8..]
+            .iter_mut()
+            .zip(self.seq.to_be_bytes().iter())
+        {
+            *n ^= s;
+        }
+        self.seq = self
+            .seq
+            .checked_add(1)
+            .ok_or(Error::EncryptError)?;
+        Ok(nonce)
+    }
+
+    pub(crate) fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce()?;
+        let mut buf = plaintext.to_vec();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, aad, &mut buf)
+            .map_err(|_| Error::EncryptError)?;
+        buf.extend_from_slice(tag.as_ref());
+        Ok(buf)
+    }
+
+    #[cfg(feature = "ech_server")]
+    pub(crate) fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce()?;
+        let mut buf = ciphertext.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, aad, &mut buf)
+            .map_err(|_| Error::DecryptError)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    pub(crate) fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crypto::ring::RING;
+
+    #[cfg(feature = "ech_server")]
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn suite(kdf_id: HpkeKdf, aead_id: HpkeAead) -> HpkeSymmetricCipherSuite {
+        HpkeSymmetricCipherSuite { kdf_id, aead_id }
+    }
+
+    /// A sender's ephemeral key, fixed so as to reproduce test vectors.
+    #[cfg(feature = "ech_server")]
+    struct FixedEphemeral(HpkeKeyPair);
+
+    #[cfg(feature = "ech_server")]
+    impl ActiveKeyExchange for FixedEphemeral {
+        fn complete(self: Box<Self>, peer_pub_key: &[u8]) -> Result<crypto::SharedSecret, Error> {
+            self.0
+                .agree(peer_pub_key)
+                .ok_or(Error::DecryptError)
+        }
+
+        fn pub_key(&self) -> &[u8] {
+            &self.0.public
+        }
+
+        fn group(&self) -> NamedGroup {
+            NamedGroup::X25519
+        }
+    }
+
+    #[cfg(feature = "ech_server")]
+    #[test]
+    fn rfc9180_a1_base_vector() {
+        // RFC9180 appendix A.1.1: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM
+        let info = unhex("4f6465206f6e2061204772656369616e2055726e");
+        let sk_e = unhex("52c4a758a802cd8b936eceea314432798d5baf2d7e9235dc084ab1b9cfa2f736");
+        let pk_r = unhex("3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d");
+        let sk_r = unhex("4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8");
+        let expect_enc = unhex("37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431");
+
+        let ephemeral = HpkeKeyPair::from_private_bytes(&sk_e).unwrap();
+        let receiver = HpkeKeyPair::from_private_bytes(&sk_r).unwrap();
+        assert_eq!(&receiver.public[..], &pk_r[..]);
+
+        let suite = suite(HpkeKdf::HKDF_SHA256, HpkeAead::AES_128_GCM);
+        let (enc, mut sender) = setup_base_sender_with_ephemeral(
+            RING,
+            HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            &suite,
+            &pk_r,
+            &info,
+            Box::new(FixedEphemeral(ephemeral)),
+        )
+        .unwrap();
+        assert_eq!(enc, expect_enc);
+
+        let pt = unhex("4265617574792069732074727574682c20747275746820626561757479");
+        let aad = unhex("436f756e742d30");
+        let expect_ct = unhex(
+            "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a9\
+             6d8770ac83d07bea87e13c512a",
+        );
+        assert_eq!(sender.seal(&aad, &pt).unwrap(), expect_ct);
+
+        let mut receiver = setup_base_receiver(
+            RING,
+            HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            &suite,
+            &enc,
+            &receiver,
+            &info,
+        )
+        .unwrap();
+        assert_eq!(receiver.open(&aad, &expect_ct).unwrap(), pt);
+    }
+
+    #[cfg(feature = "ech_server")]
+    #[test]
+    fn round_trip_all_suites() {
+        let receiver = HpkeKeyPair::generate().unwrap();
+        for kdf in [HpkeKdf::HKDF_SHA256, HpkeKdf::HKDF_SHA384] {
+            for aead in [
+                HpkeAead::AES_128_GCM,
+                HpkeAead::AES_256_GCM,
+                HpkeAead::CHACHA20_POLY_1305,
+            ] {
+                let suite = suite(kdf, aead);
+                assert!(provider_supports_suite(RING, &suite));
+                let (enc, mut sender) = setup_base_sender(
+                    RING,
+                    HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                    &suite,
+                    &receiver.public,
+                    b"info",
+                )
+                .unwrap();
+                let mut opener = setup_base_receiver(
+                    RING,
+                    HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                    &suite,
+                    &enc,
+                    &receiver,
+                    b"info",
+                )
+                .unwrap();
+
+                for i in 0..3u8 {
+                    let ct = sender.seal(&[i], b"hello").unwrap();
+                    assert_eq!(ct.len(), 5 + sender.tag_len());
+                    assert_eq!(opener.open(&[i], &ct).unwrap(), b"hello");
+                }
+
+                let ct = sender.seal(b"aad", b"hello").unwrap();
+                assert_eq!(opener.open(b"wrong", &ct), Err(Error::DecryptError));
+            }
+        }
+    }
+
+    /// *ring*, offering only `TLS13_AES_128_GCM_SHA256`.
+    #[derive(Debug)]
+    struct Aes128GcmOnly;
+
+    static AES_128_GCM_ONLY: &[crate::SupportedCipherSuite] =
+        &[crate::tls13::TLS13_AES_128_GCM_SHA256];
+
+    impl CryptoProvider for Aes128GcmOnly {
+        fn fill_random(&self, buf: &mut [u8]) -> Result<(), crypto::GetRandomFailed> {
+            RING.fill_random(buf)
+        }
+
+        fn default_cipher_suites(&self) -> &'static [crate::SupportedCipherSuite] {
+            AES_128_GCM_ONLY
+        }
+
+        fn default_kx_groups(&self) -> &'static [&'static dyn crypto::SupportedKxGroup] {
+            RING.default_kx_groups()
+        }
+
+        fn verify_signature(
+            &self,
+            scheme: crate::SignatureScheme,
+            spki: &[u8],
+            message: &[u8],
+            signature: &[u8],
+        ) -> Result<(), Error> {
+            RING.verify_signature(scheme, spki, message, signature)
+        }
+
+        fn load_private_key(
+            &self,
+            key_der: &crate::PrivateKey,
+        ) -> Result<std::sync::Arc<dyn crate::sign::SigningKey>, Error> {
+            RING.load_private_key(key_der)
+        }
+
+        fn ticket_aead(&self) -> &'static dyn aead::Algorithm {
+            RING.ticket_aead()
+        }
+    }
+
+    #[test]
+    fn suites_come_from_provider() {
+        let aes_128_gcm = suite(HpkeKdf::HKDF_SHA256, HpkeAead::AES_128_GCM);
+        let sha384 = suite(HpkeKdf::HKDF_SHA384, HpkeAead::AES_128_GCM);
+        let sha512 = suite(HpkeKdf::HKDF_SHA512, HpkeAead::AES_128_GCM);
+        let chacha = suite(HpkeKdf::HKDF_SHA256, HpkeAead::CHACHA20_POLY_1305);
+
+        assert!(provider_supports_suite(&Aes128GcmOnly, &aes_128_gcm));
+        assert!(!provider_supports_suite(&Aes128GcmOnly, &sha384));
+        assert!(!provider_supports_suite(&Aes128GcmOnly, &chacha));
+        assert!(provider_supports_suite(RING, &sha384));
+        assert!(provider_supports_suite(RING, &chacha));
+
+        // No TLS1.3 suite uses SHA-512.
+        assert!(is_supported_suite(&sha512));
+        assert!(!provider_supports_suite(RING, &sha512));
+
+        let public_key = [9; X25519_LEN];
+        assert!(setup_base_sender(
+            &Aes128GcmOnly,
+            HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            &aes_128_gcm,
+            &public_key,
+            b"",
+        )
+        .is_ok());
+        assert!(setup_base_sender(
+            &Aes128GcmOnly,
+            HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            &chacha,
+            &public_key,
+            b"",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn unsupported_parameters() {
+        assert!(!is_supported_suite(&suite(
+            HpkeKdf::Unknown(9),
+            HpkeAead::AES_128_GCM
+        )));
+        assert!(!is_supported_suite(&suite(
+            HpkeKdf::HKDF_SHA256,
+            HpkeAead::EXPORT_ONLY
+        )));
+
+        let aes_128_gcm = suite(HpkeKdf::HKDF_SHA256, HpkeAead::AES_128_GCM);
+        assert!(setup_base_sender(
+            RING,
+            HpkeKem::DHKEM_P256_HKDF_SHA256,
+            &aes_128_gcm,
+            &[9; X25519_LEN],
+            b"",
+        )
+        .is_err());
+
+        // A small-order point.
+        assert!(setup_base_sender(
+            RING,
+            HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            &aes_128_gcm,
+            &[0; X25519_LEN],
+            b"",
+        )
+        .is_err());
+    }
+}
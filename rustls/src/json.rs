@@ -0,0 +1,56 @@
+// The parts of the Certificate Transparency log list JSON schema
+// (<https://www.gstatic.com/ct/log_list/v3/log_list_schema.json>) which
+// rustls uses.  Other members are ignored.
+
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LogList {
+    pub(crate) operators: Vec<Operator>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Operator {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) logs: Vec<Log>,
+    #[serde(default)]
+    pub(crate) tiled_logs: Vec<Log>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Log {
+    #[serde(default)]
+    pub(crate) description: String,
+    /// Base64 of the log's `SubjectPublicKeyInfo`.
+    pub(crate) key: String,
+    /// Base64 of the SHA-256 hash of `key`.
+    pub(crate) log_id: String,
+    pub(crate) state: Option<State>,
+    pub(crate) temporal_interval: Option<TemporalInterval>,
+}
+
+/// A log's state.  Of the times at which logs entered their states,
+/// only the time of retirement is used.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum State {
+    Pending {},
+    Qualified {},
+    Usable {},
+    ReadOnly {},
+    Retired(Timestamp),
+    Rejected {},
+}
+
+/// RFC3339 times.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Timestamp {
+    pub(crate) timestamp: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TemporalInterval {
+    pub(crate) start_inclusive: String,
+    pub(crate) end_exclusive: String,
+}
@@ -1,3 +1,5 @@
+use crate::crypto::hash;
+
 use std::fmt;
 
 /// This type contains a private key by value.
@@ -38,9 +40,83 @@ impl fmt::Debug for Certificate {
     }
 }
 
+/// This type contains a single public key by value, as used for
+/// raw public key authentication (RFC7250).
+///
+/// The public key must be a DER-encoded X.509 `SubjectPublicKeyInfo`.
+#[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct SubjectPublicKeyInfo(pub Vec<u8>);
+
+impl AsRef<[u8]> for SubjectPublicKeyInfo {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SubjectPublicKeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use super::bs_debug::BsDebug;
+        f.debug_tuple("SubjectPublicKeyInfo")
+            .field(&BsDebug(&self.0))
+            .finish()
+    }
+}
+
+/// An externally provisioned TLS1.3 pre-shared key (RFC8446 section 2.2).
+///
+/// This is a secret agreed out-of-band between client and server,
+/// named by `identity`, and usable only with cipher suites whose hash
+/// is `hash`.
+#[derive(Clone)]
+pub struct ExternalPsk {
+    identity: Vec<u8>,
+    secret: Vec<u8>,
+    hash: &'static dyn hash::Hash,
+}
+
+impl ExternalPsk {
+    /// Make a new external PSK with the given `identity`, `secret` and
+    /// associated hash algorithm.
+    pub fn new(
+        identity: impl Into<Vec<u8>>,
+        secret: impl Into<Vec<u8>>,
+        hash: &'static dyn hash::Hash,
+    ) -> Self {
+        Self {
+            identity: identity.into(),
+            secret: secret.into(),
+            hash,
+        }
+    }
+
+    /// The identity by which this PSK is named on the wire.
+    pub fn identity(&self) -> &[u8] {
+        &self.identity
+    }
+
+    /// The hash algorithm this PSK is bound to.
+    pub fn hash_algorithm(&self) -> &'static dyn hash::Hash {
+        self.hash
+    }
+
+    pub(crate) fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+}
+
+impl fmt::Debug for ExternalPsk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use super::bs_debug::BsDebug;
+        f.debug_struct("ExternalPsk")
+            .field("identity", &BsDebug(&self.identity))
+            .field("hash", &self.hash.algorithm())
+            .finish_non_exhaustive()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::Certificate;
+    use super::{Certificate, ExternalPsk};
 
     #[test]
     fn certificate_debug() {
@@ -49,4 +125,17 @@ mod test {
             format!("{:?}", Certificate(b"ab".to_vec()))
         );
     }
+
+    #[test]
+    fn external_psk_debug_hides_secret() {
+        let psk = ExternalPsk::new(
+            b"id".to_vec(),
+            b"secret".to_vec(),
+            &crate::crypto::ring::hash::SHA256,
+        );
+        assert_eq!(
+            "ExternalPsk { identity: b\"id\", hash: SHA256, .. }",
+            format!("{:?}", psk)
+        );
+    }
 }
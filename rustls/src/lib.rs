@@ -9,7 +9,10 @@
 //! * ECDSA, Ed25519 or RSA server authentication by clients.
 //! * ECDSA, Ed25519 or RSA server authentication by servers.
 //! * Forward secrecy using ECDHE; with curve25519, nistp256 or nistp384 curves.
+//! * Optional forward secrecy using finite-field DHE, with the RFC7919 groups (`ffdhe` feature).
+//! * Optional hybrid post-quantum key exchange with X25519 and Kyber768, for TLS1.3 (`kyber` feature).
 //! * AES128-GCM and AES256-GCM bulk encryption, with safe nonces.
+//! * Optional AES128-CCM bulk encryption, for peers with AES-CCM hardware (`aes_ccm` feature).
 //! * ChaCha20-Poly1305 bulk encryption ([RFC7905](https://tools.ietf.org/html/rfc7905)).
 //! * ALPN support.
 //! * SNI support.
@@ -56,11 +59,14 @@
 //!
 //! ### Platform support
 //!
-//! Rustls uses [`ring`](https://crates.io/crates/ring) for implementing the
+//! By default, rustls uses [`ring`](https://crates.io/crates/ring) for implementing the
 //! cryptography in TLS. As a result, rustls only runs on platforms
 //! [supported by `ring`](https://github.com/briansmith/ring#online-automated-testing).
 //! At the time of writing this means x86, x86-64, armv7, and aarch64.
 //!
+//! Other implementations of the TLS cryptography can be plugged in with a
+//! [`CryptoProvider`]; see [`ClientConfig::builder_with_provider()`].
+//!
 //! ## Design Overview
 //! ### Rustls does not take care of network IO
 //! It doesn't make or accept TCP connections, or do DNS, or read or write files.
@@ -247,6 +253,10 @@
 //!   details of these.  You will only need this if you're writing a QUIC
 //!   implementation.
 //!
+//! - `dtls`: this feature adds DTLS1.3 (RFC9147) client and server
+//!   connections, for protecting data sent over datagram transports
+//!   such as UDP.  See the `dtls` module for details.
+//!
 //! - `tls12`: enables support for TLS version 1.2. This feature is in the default
 //!   set. Note that, due to the additive nature of Cargo features and because it
 //!   is enabled by default, other crates in your dependency graph could re-enable
@@ -256,6 +266,34 @@
 //! - `read_buf`: When building with Rust Nightly, adds support for the unstable
 //!   `std::io::ReadBuf` and related APIs. This reduces costs from initializing
 //!   buffers. Will do nothing on non-Nightly releases.
+//!
+//! - `zlib`: uses the `flate2` crate to compress and decompress certificates
+//!   with zlib, as described in RFC8879.  See the [`compress`] module for
+//!   details.
+//!
+//! - `ech_server`: adds server-side Encrypted Client Hello.  ECH servers
+//!   hold a long-lived X25519 private key, which *ring* cannot use, so
+//!   this feature uses the `x25519-dalek` crate for it.  See
+//!   `server::EchKeyPair`.  Clients can offer ECH without this feature.
+//!
+//! - `aes_ccm`: adds the AES-128-CCM cipher suites, such as
+//!   `cipher_suite::TLS13_AES_128_CCM_SHA256`.  *ring* doesn't implement
+//!   CCM, so this feature uses the `aes` and `ccm` crates for it.  These
+//!   suites are in [`ALL_CIPHER_SUITES`] but not [`DEFAULT_CIPHER_SUITES`].
+//!
+//! - `ffdhe`: adds the RFC7919 finite-field Diffie-Hellman groups, such as
+//!   `kx_group::FFDHE2048`, for use with TLS1.3 and with the TLS1.2 DHE
+//!   cipher suites.  *ring* does not provide these, so this feature uses
+//!   the `crypto-bigint` crate for their arithmetic.
+//!
+//! - `kyber`: adds `kx_group::X25519_KYBER768_DRAFT00`, a hybrid of X25519
+//!   and the Kyber768 post-quantum KEM, for use with TLS1.3.  *ring* does
+//!   not provide Kyber, so this feature uses the `pqcrypto-kyber` crate,
+//!   which wraps the reference implementation.
+//!
+//! - `ct-log-list`: adds `client::CtLogList::from_json()`, which reads
+//!   Certificate Transparency log lists in the JSON format published for
+//!   Chrome.  This uses the `serde_json`, `base64` and `humantime` crates.
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code, unused_must_use)]
@@ -318,9 +356,16 @@ mod msgs;
 mod anchors;
 mod cipher;
 mod conn;
+mod crl;
+mod ct;
+mod ech;
 mod error;
 mod hash_hs;
+mod hpke;
+#[cfg(feature = "ct-log-list")]
+mod json;
 mod limited_cache;
+mod ocsp;
 mod rand;
 mod record_layer;
 mod stream;
@@ -340,7 +385,6 @@ mod enums;
 mod key;
 mod key_log;
 mod key_log_file;
-mod kx;
 mod suites;
 mod ticketer;
 mod versions;
@@ -370,14 +414,18 @@ pub use crate::builder::{
 pub use crate::conn::{
     CommonState, Connection, ConnectionCommon, IoState, Reader, Side, SideData, Writer,
 };
-pub use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
+pub use crate::crypto::ring::kx::ALL_KX_GROUPS;
+pub use crate::crypto::{CryptoProvider, SupportedKxGroup};
+pub use crate::enums::{
+    CertificateCompressionAlgorithm, CipherSuite, ProtocolVersion, SignatureScheme,
+};
 pub use crate::error::Error;
-pub use crate::key::{Certificate, PrivateKey};
+pub use crate::key::{Certificate, ExternalPsk, PrivateKey, SubjectPublicKeyInfo};
 pub use crate::key_log::{KeyLog, NoKeyLog};
 pub use crate::key_log_file::KeyLogFile;
-pub use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
 pub use crate::msgs::enums::{
-    AlertDescription, ContentType, HandshakeType, NamedGroup, SignatureAlgorithm,
+    AlertDescription, CertificateType, ContentType, HandshakeType, HashAlgorithm,
+    MaxFragmentLength, NamedGroup, PSKKeyExchangeMode, SignatureAlgorithm,
 };
 pub use crate::msgs::handshake::{DigitallySignedStruct, DistinguishedNames};
 pub use crate::stream::{Stream, StreamOwned};
@@ -386,10 +434,11 @@ pub use crate::suites::{
 };
 #[cfg(feature = "secret_extraction")]
 pub use crate::suites::{ConnectionTrafficSecrets, ExtractedSecrets};
-pub use crate::ticketer::Ticketer;
+pub use crate::ticketer::{SharedTicketer, TicketKey, Ticketer};
 #[cfg(feature = "tls12")]
 pub use crate::tls12::Tls12CipherSuite;
 pub use crate::tls13::Tls13CipherSuite;
+pub use crate::verify::TrustedRawKeys;
 pub use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS};
 
 /// Items for use in a client.
@@ -397,6 +446,8 @@ pub mod client {
     pub(super) mod builder;
     mod client_conn;
     mod common;
+    mod ech;
+    mod file_cache;
     pub(super) mod handy;
     mod hs;
     #[cfg(feature = "tls12")]
@@ -411,12 +462,23 @@ pub mod client {
     pub use client_conn::ServerName;
     pub use client_conn::StoresClientSessions;
     pub use client_conn::{ClientConfig, ClientConnection, ClientConnectionData, WriteEarlyData};
+    pub use ech::{EchConfig, EchStatus};
+    pub use file_cache::ClientSessionFileCache;
     pub use handy::{ClientSessionMemoryCache, NoClientSessionStorage};
 
+    #[cfg(feature = "tls12")]
+    pub use crate::msgs::persist::Tls12ClientSessionValue;
+    pub use crate::msgs::persist::Tls13ClientSessionValue;
+
+    pub use crate::ct::{CtLogList, CtRequirements};
+    pub use crate::verify::ServerRawKeyVerifier;
+    pub use crate::verify::{OcspMode, OcspPolicy};
+
+    #[cfg(feature = "dangerous_configuration")]
+    pub use crate::ct::CertificateTransparencyPolicy;
     #[cfg(feature = "dangerous_configuration")]
     pub use crate::verify::{
-        CertificateTransparencyPolicy, HandshakeSignatureValid, ServerCertVerified,
-        ServerCertVerifier, WebPkiVerifier,
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
     };
     #[cfg(feature = "dangerous_configuration")]
     pub use client_conn::danger::DangerousClientConfig;
@@ -428,6 +490,8 @@ pub use client::{ClientConfig, ClientConnection, ServerName};
 pub mod server {
     pub(crate) mod builder;
     mod common;
+    #[cfg(feature = "ech_server")]
+    mod ech;
     pub(crate) mod handy;
     mod hs;
     mod server_conn;
@@ -435,19 +499,25 @@ pub mod server {
     mod tls12;
     mod tls13;
 
+    pub use crate::crl::CertRevocationList;
     pub use crate::verify::{
-        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientRawKeyVerifier,
+        NoClientAuth,
     };
     pub use builder::WantsServerCert;
+    #[cfg(feature = "ech_server")]
+    pub use ech::EchKeyPair;
     pub use handy::ResolvesServerCertUsingSni;
     pub use handy::{NoServerSessionStorage, ServerSessionMemoryCache};
+    pub use server_conn::PostHandshakeAuthStatus;
     #[cfg(feature = "quic")]
     pub use server_conn::ServerQuicExt;
     pub use server_conn::StoresServerSessions;
     pub use server_conn::{
         Accepted, Acceptor, ReadEarlyData, ServerConfig, ServerConnection, ServerConnectionData,
     };
-    pub use server_conn::{ClientHello, ProducesTickets, ResolvesServerCert};
+    pub use server_conn::{ClientHello, ProducesTickets, ResolvesServerCert, ResolvesServerPsk};
+    pub use server_conn::{SessionLookup, SigningKeySource, SigningRequest};
 
     #[cfg(feature = "dangerous_configuration")]
     pub use crate::verify::{ClientCertVerified, ClientCertVerifier, DnsName};
@@ -461,6 +531,14 @@ pub use server::{ServerConfig, ServerConnection};
 pub mod cipher_suite {
     pub use crate::suites::CipherSuiteCommon;
     #[cfg(feature = "tls12")]
+    pub use crate::tls12::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256;
+    #[cfg(feature = "tls12")]
+    pub use crate::tls12::TLS_DHE_RSA_WITH_AES_256_GCM_SHA384;
+    #[cfg(all(feature = "tls12", feature = "aes_ccm"))]
+    pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_CCM;
+    #[cfg(all(feature = "tls12", feature = "aes_ccm"))]
+    pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8;
+    #[cfg(feature = "tls12")]
     pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256;
     #[cfg(feature = "tls12")]
     pub use crate::tls12::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384;
@@ -472,6 +550,8 @@ pub mod cipher_suite {
     pub use crate::tls12::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384;
     #[cfg(feature = "tls12")]
     pub use crate::tls12::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256;
+    #[cfg(feature = "aes_ccm")]
+    pub use crate::tls13::TLS13_AES_128_CCM_SHA256;
     pub use crate::tls13::TLS13_AES_128_GCM_SHA256;
     pub use crate::tls13::TLS13_AES_256_GCM_SHA384;
     pub use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256;
@@ -490,19 +570,33 @@ pub mod version {
 ///
 /// ALL_KX_GROUPS is provided as an array of all of these values.
 pub mod kx_group {
-    pub use crate::kx::SECP256R1;
-    pub use crate::kx::SECP384R1;
-    pub use crate::kx::X25519;
+    #[cfg(feature = "ffdhe")]
+    pub use crate::crypto::ffdhe::{FFDHE2048, FFDHE3072, FFDHE4096};
+    #[cfg(feature = "kyber")]
+    pub use crate::crypto::kyber::X25519_KYBER768_DRAFT00;
+    pub use crate::crypto::ring::kx::SECP256R1;
+    pub use crate::crypto::ring::kx::SECP384R1;
+    pub use crate::crypto::ring::kx::X25519;
 }
 
 /// Message signing interfaces and implementations.
 pub mod sign;
 
+/// Cryptography providers, and the primitives they implement.
+pub mod crypto;
+
+pub mod compress;
+
 #[cfg(feature = "quic")]
 #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
 /// APIs for implementing QUIC TLS
 pub mod quic;
 
+#[cfg(feature = "dtls")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dtls")))]
+/// APIs for using rustls over datagram transports with DTLS1.3
+pub mod dtls;
+
 /// This is the rustls manual.
 pub mod manual;
 
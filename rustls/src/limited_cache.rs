@@ -54,6 +54,30 @@ where
         }
     }
 
+    /// Apply `edit` to the value for `k`, first inserting a default
+    /// value if there is none.
+    pub(crate) fn get_or_insert_default_and_edit(&mut self, k: K, edit: impl FnOnce(&mut V))
+    where
+        V: Default,
+    {
+        match self.map.get_mut(&k) {
+            Some(value) => edit(value),
+            None => {
+                let mut value = V::default();
+                edit(&mut value);
+                self.insert(k, value);
+            }
+        }
+    }
+
+    pub(crate) fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.map.get_mut(k)
+    }
+
     pub(crate) fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
     where
         K: Borrow<Q>,
@@ -95,6 +119,19 @@ mod test {
         assert_eq!(t.get("abc"), Some(&2));
     }
 
+    #[test]
+    fn test_edits_or_inserts_default_item() {
+        let mut t = Test::new(3);
+        t.get_or_insert_default_and_edit("abc".into(), |v| *v += 1);
+        assert_eq!(t.get("abc"), Some(&1));
+        t.get_or_insert_default_and_edit("abc".into(), |v| *v += 1);
+        assert_eq!(t.get("abc"), Some(&2));
+
+        *t.get_mut("abc").unwrap() = 5;
+        assert_eq!(t.get("abc"), Some(&5));
+        assert_eq!(t.get_mut("def"), None);
+    }
+
     #[test]
     fn test_evicts_oldest_item() {
         let mut t = Test::new(3);
@@ -13,7 +13,7 @@ use crate::ProtocolVersion;
 /// This deframer works to reconstruct TLS messages from a stream of arbitrary-sized reads.
 ///
 /// It buffers incoming data into a `Vec` through `read()`, and returns messages through `pop()`.
-/// QUIC connections will call `push()` to append handshake payload data directly.
+/// QUIC and DTLS connections will call `push()` to append handshake payload data directly.
 #[derive(Default)]
 pub struct MessageDeframer {
     /// Set to true if the peer is not talking TLS, but some other
@@ -31,9 +31,27 @@ pub struct MessageDeframer {
 
     /// What size prefix of `buf` is used.
     used: usize,
+
+    /// The largest plaintext we accept in a protected record, if we
+    /// negotiated a `record_size_limit` with the peer.
+    record_size_limit: Option<usize>,
+
+    /// The largest plaintext we accept in any record, if we negotiated
+    /// a `max_fragment_length` with the peer.
+    max_fragment_len: Option<usize>,
 }
 
 impl MessageDeframer {
+    /// Reject protected records whose plaintext is longer than `max_len`.
+    pub fn set_record_size_limit(&mut self, max_len: Option<usize>) {
+        self.record_size_limit = max_len;
+    }
+
+    /// Reject any record whose plaintext is longer than `max_len`.
+    pub fn set_max_fragment_len(&mut self, max_len: Option<usize>) {
+        self.max_fragment_len = max_len;
+    }
+
     /// Return any decrypted messages that the deframer has been able to parse.
     ///
     /// Returns an `Error` if the deframer failed to parse some message contents or if decryption
@@ -94,6 +112,7 @@ impl MessageDeframer {
             }
 
             // Decrypt the encrypted message (if necessary).
+            let protected = record_layer.is_decrypting();
             let msg = match record_layer.decrypt_incoming(m) {
                 Ok(Some(decrypted)) => {
                     let Decrypted {
@@ -116,6 +135,20 @@ impl MessageDeframer {
                 Err(e) => return Err(e),
             };
 
+            match self.record_size_limit {
+                Some(limit) if protected && msg.payload.0.len() > limit => {
+                    return Err(Error::PeerExceededRecordSizeLimit);
+                }
+                _ => {}
+            }
+
+            match self.max_fragment_len {
+                Some(max) if msg.payload.0.len() > max => {
+                    return Err(Error::PeerSentOversizedRecord);
+                }
+                _ => {}
+            }
+
             if self.joining_hs.is_some() && msg.typ != ContentType::Handshake {
                 // "Handshake messages MUST NOT be interleaved with other record
                 // types.  That is, if a handshake message is split over two or more
@@ -178,7 +211,7 @@ impl MessageDeframer {
     }
 
     /// Allow pushing handshake messages directly into the buffer.
-    #[cfg(feature = "quic")]
+    #[cfg(any(feature = "quic", feature = "dtls"))]
     pub fn push(&mut self, version: ProtocolVersion, payload: &[u8]) -> Result<(), Error> {
         if self.used > 0 && self.joining_hs.is_none() {
             return Err(Error::General(
@@ -188,7 +221,15 @@ impl MessageDeframer {
             return Err(Error::General(err.into()));
         }
 
+        // `prepare_read()` only makes room for one read's worth of data.
         let end = self.used + payload.len();
+        if end > self.buf.len() {
+            if end > MAX_HANDSHAKE_SIZE as usize + HEADER_SIZE {
+                return Err(Error::General("message buffer full".into()));
+            }
+            self.buf.resize(end, 0);
+        }
+
         self.append_hs(version, payload, end, true)?;
         self.used = end;
         Ok(())
@@ -413,7 +454,8 @@ const INTERLEAVED_ERROR: &str = "";
 #[cfg(test)]
 mod tests {
     use super::MessageDeframer;
-    use crate::msgs::message::{Message, OpaqueMessage};
+    use crate::cipher::MessageDecrypter;
+    use crate::msgs::message::{Message, OpaqueMessage, PlainMessage};
     use crate::record_layer::RecordLayer;
     use crate::{ContentType, Error};
 
@@ -699,4 +741,62 @@ mod tests {
         );
         assert!(input_bytes(&mut d, &message).is_err());
     }
+
+    struct PassthroughDecrypter;
+
+    impl MessageDecrypter for PassthroughDecrypter {
+        fn decrypt(&self, m: OpaqueMessage, _: u64) -> Result<PlainMessage, Error> {
+            Ok(m.into_plain_message())
+        }
+    }
+
+    fn application_data(len: usize) -> Vec<u8> {
+        let mut message = vec![0x17, 0x03, 0x03];
+        message.extend((len as u16).to_be_bytes());
+        message.extend(vec![0; len]);
+        message
+    }
+
+    #[test]
+    fn test_record_size_limit() {
+        let mut d = MessageDeframer::default();
+        d.set_record_size_limit(Some(64));
+        let mut rl = RecordLayer::new();
+
+        // unprotected records are not subject to the limit
+        input_bytes(&mut d, &application_data(65)).unwrap();
+        assert_eq!(
+            d.pop(&mut rl)
+                .unwrap()
+                .unwrap()
+                .message
+                .payload
+                .0
+                .len(),
+            65
+        );
+
+        rl.set_message_decrypter(Box::new(PassthroughDecrypter));
+        input_bytes(&mut d, &application_data(64)).unwrap();
+        assert!(d.pop(&mut rl).unwrap().is_some());
+
+        input_bytes(&mut d, &application_data(65)).unwrap();
+        assert_eq!(
+            d.pop(&mut rl).unwrap_err(),
+            Error::PeerExceededRecordSizeLimit
+        );
+    }
+
+    #[test]
+    fn test_max_fragment_len() {
+        let mut d = MessageDeframer::default();
+        d.set_max_fragment_len(Some(512));
+        let mut rl = RecordLayer::new();
+
+        input_bytes(&mut d, &application_data(512)).unwrap();
+        assert!(d.pop(&mut rl).unwrap().is_some());
+
+        input_bytes(&mut d, &application_data(513)).unwrap();
+        assert_eq!(d.pop(&mut rl).unwrap_err(), Error::PeerSentOversizedRecord);
+    }
 }
@@ -80,7 +80,8 @@ enum_builder! {
         Alert => 0x15,
         Handshake => 0x16,
         ApplicationData => 0x17,
-        Heartbeat => 0x18
+        Heartbeat => 0x18,
+        Ack => 0x1a
     }
 }
 
@@ -109,6 +110,7 @@ enum_builder! {
         CertificateURL => 0x15,
         CertificateStatus => 0x16,
         KeyUpdate => 0x18,
+        CompressedCertificate => 0x19,
         MessageHash => 0xfe
     }
 }
@@ -165,7 +167,8 @@ enum_builder! {
         BadCertificateHashValue => 0x72,
         UnknownPSKIdentity => 0x73,
         CertificateRequired => 0x74,
-        NoApplicationProtocol => 0x78
+        NoApplicationProtocol => 0x78,
+        EncryptedClientHelloRequired => 0x79
     }
 }
 
@@ -206,8 +209,13 @@ enum_builder! {
         Heartbeat => 0x000f,
         ALProtocolNegotiation => 0x0010,
         SCT => 0x0012,
+        ClientCertificateType => 0x0013,
+        ServerCertificateType => 0x0014,
         Padding => 0x0015,
         ExtendedMasterSecret => 0x0017,
+        CompressCertificate => 0x001b,
+        RecordSizeLimit => 0x001c,
+        DelegatedCredential => 0x0022,
         SessionTicket => 0x0023,
         PreSharedKey => 0x0029,
         EarlyData => 0x002a,
@@ -223,6 +231,8 @@ enum_builder! {
         TransportParameters => 0x0039,
         NextProtocolNegotiation => 0x3374,
         ChannelId => 0x754f,
+        EncryptedClientHelloOuterExtensions => 0xfd00,
+        EncryptedClientHello => 0xfe0d,
         RenegotiationInfo => 0xff01,
         TransportParametersDraft => 0xffa5
     }
@@ -297,7 +307,8 @@ enum_builder! {
         FFDHE3072 => 0x0101,
         FFDHE4096 => 0x0102,
         FFDHE6144 => 0x0103,
-        FFDHE8192 => 0x0104
+        FFDHE8192 => 0x0104,
+        X25519Kyber768Draft00 => 0x6399
     }
 }
 
@@ -351,6 +362,34 @@ enum_builder! {
     }
 }
 
+enum_builder! {
+    /// The `MaxFragmentLength` TLS protocol enum.  Values in this enum are taken
+    /// from the various RFCs covering TLS, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U8
+    EnumName: MaxFragmentLength;
+    EnumVal{
+        Len512 => 0x01,
+        Len1024 => 0x02,
+        Len2048 => 0x03,
+        Len4096 => 0x04
+    }
+}
+
+impl MaxFragmentLength {
+    /// The largest record plaintext this value allows, or `None` for
+    /// unknown values.
+    pub(crate) fn fragment_len(&self) -> Option<usize> {
+        match *self {
+            Self::Len512 => Some(512),
+            Self::Len1024 => Some(1024),
+            Self::Len2048 => Some(2048),
+            Self::Len4096 => Some(4096),
+            Self::Unknown(_) => None,
+        }
+    }
+}
+
 enum_builder! {
     /// The `KeyUpdateRequest` TLS protocol enum.  Values in this enum are taken
     /// from the various RFCs covering TLS, and are listed by IANA.
@@ -373,3 +412,80 @@ enum_builder! {
         OCSP => 0x01
     }
 }
+
+enum_builder! {
+    /// The `CertificateType` TLS protocol enum.  Values in this enum are taken
+    /// from the various RFCs covering TLS, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U8
+    EnumName: CertificateType;
+    EnumVal{
+        X509 => 0x00,
+        RawPublicKey => 0x02
+    }
+}
+
+enum_builder! {
+    /// The `EchVersion` enum.  Values in this enum are taken from
+    /// draft-ietf-tls-esni.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: EchVersion;
+    EnumVal{
+        V18 => 0xfe0d
+    }
+}
+
+enum_builder! {
+    /// The `EchClientHelloType` enum.  Values in this enum are taken from
+    /// draft-ietf-tls-esni.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U8
+    EnumName: EchClientHelloType;
+    EnumVal{
+        ClientHelloOuter => 0x00,
+        ClientHelloInner => 0x01
+    }
+}
+
+enum_builder! {
+    /// The `HpkeKem` enum.  Values in this enum are taken from RFC9180,
+    /// and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: HpkeKem;
+    EnumVal{
+        DHKEM_P256_HKDF_SHA256 => 0x0010,
+        DHKEM_P384_HKDF_SHA384 => 0x0011,
+        DHKEM_P521_HKDF_SHA512 => 0x0012,
+        DHKEM_X25519_HKDF_SHA256 => 0x0020,
+        DHKEM_X448_HKDF_SHA512 => 0x0021
+    }
+}
+
+enum_builder! {
+    /// The `HpkeKdf` enum.  Values in this enum are taken from RFC9180,
+    /// and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: HpkeKdf;
+    EnumVal{
+        HKDF_SHA256 => 0x0001,
+        HKDF_SHA384 => 0x0002,
+        HKDF_SHA512 => 0x0003
+    }
+}
+
+enum_builder! {
+    /// The `HpkeAead` enum.  Values in this enum are taken from RFC9180,
+    /// and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: HpkeAead;
+    EnumVal{
+        AES_128_GCM => 0x0001,
+        AES_256_GCM => 0x0002,
+        CHACHA20_POLY_1305 => 0x0003,
+        EXPORT_ONLY => 0xffff
+    }
+}
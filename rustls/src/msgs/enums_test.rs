@@ -52,12 +52,12 @@ fn test_enums() {
         ClientCertificateType::ECDSAFixedECDH,
     );
     test_enum8::<Compression>(Compression::Null, Compression::LSZ);
-    test_enum8::<ContentType>(ContentType::ChangeCipherSpec, ContentType::Heartbeat);
+    test_enum8::<ContentType>(ContentType::ChangeCipherSpec, ContentType::Ack);
     test_enum8::<HandshakeType>(HandshakeType::HelloRequest, HandshakeType::MessageHash);
     test_enum8::<AlertLevel>(AlertLevel::Warning, AlertLevel::Fatal);
     test_enum8::<AlertDescription>(
         AlertDescription::CloseNotify,
-        AlertDescription::NoApplicationProtocol,
+        AlertDescription::EncryptedClientHelloRequired,
     );
     test_enum8::<HeartbeatMessageType>(
         HeartbeatMessageType::Request,
@@ -80,9 +80,20 @@ fn test_enums() {
     );
     test_enum8::<ECCurveType>(ECCurveType::ExplicitPrime, ECCurveType::NamedCurve);
     test_enum8::<PSKKeyExchangeMode>(PSKKeyExchangeMode::PSK_KE, PSKKeyExchangeMode::PSK_DHE_KE);
+    test_enum8::<MaxFragmentLength>(MaxFragmentLength::Len512, MaxFragmentLength::Len4096);
     test_enum8::<KeyUpdateRequest>(
         KeyUpdateRequest::UpdateNotRequested,
         KeyUpdateRequest::UpdateRequested,
     );
     test_enum8::<CertificateStatusType>(CertificateStatusType::OCSP, CertificateStatusType::OCSP);
+    test_enum8::<CertificateType>(CertificateType::X509, CertificateType::RawPublicKey);
+    test_enum8::<EchClientHelloType>(
+        EchClientHelloType::ClientHelloOuter,
+        EchClientHelloType::ClientHelloInner,
+    );
+    test_enum16::<HpkeKem>(
+        HpkeKem::DHKEM_P256_HKDF_SHA256,
+        HpkeKem::DHKEM_X448_HKDF_SHA512,
+    );
+    test_enum16::<HpkeKdf>(HpkeKdf::HKDF_SHA256, HpkeKdf::HKDF_SHA512);
 }
@@ -6,6 +6,12 @@ pub const MAX_FRAGMENT_LEN: usize = 16384;
 pub const PACKET_OVERHEAD: usize = 1 + 2 + 2;
 pub const MAX_FRAGMENT_SIZE: usize = MAX_FRAGMENT_LEN + PACKET_OVERHEAD;
 
+/// The smallest `record_size_limit` a peer may advertise (RFC8449 section 4).
+pub const MIN_RECORD_SIZE_LIMIT: usize = 64;
+/// The largest useful `record_size_limit`: a full fragment plus the
+/// TLS1.3 inner content type.
+pub const MAX_RECORD_SIZE_LIMIT: usize = MAX_FRAGMENT_LEN + 1;
+
 pub struct MessageFragmenter {
     max_frag: usize,
 }
@@ -63,6 +69,14 @@ impl MessageFragmenter {
         };
         Ok(())
     }
+
+    /// Further restrict the size of produced fragments to a limit negotiated
+    /// with the peer, excluding any protocol overhead.
+    ///
+    /// This never raises the existing limit.
+    pub fn reduce_max_fragment_len(&mut self, max_fragment_len: usize) {
+        self.max_frag = self.max_frag.min(max_fragment_len);
+    }
 }
 
 #[cfg(test)]
@@ -159,4 +173,22 @@ mod tests {
             b"\x01\x02\x03\x04\x05\x06\x07\x08",
         );
     }
+
+    #[test]
+    fn negotiated_limit_only_lowers_max_frag() {
+        let m = PlainMessage {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(vec![0u8; 100]),
+        };
+
+        let mut frag = MessageFragmenter::default();
+        frag.reduce_max_fragment_len(63);
+        assert_eq!(frag.fragment_message(&m).count(), 2);
+
+        frag.set_max_fragment_size(Some(32))
+            .unwrap();
+        frag.reduce_max_fragment_len(63);
+        assert_eq!(frag.fragment_message(&m).count(), 4);
+    }
 }
@@ -1,15 +1,18 @@
 #![allow(non_camel_case_types)]
-use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
+use crate::crypto::{CryptoProvider, GetRandomFailed};
+use crate::enums::{
+    CertificateCompressionAlgorithm, CipherSuite, ProtocolVersion, SignatureScheme,
+};
 use crate::key;
 use crate::msgs::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
 use crate::msgs::codec;
 use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{
-    CertificateStatusType, ClientCertificateType, Compression, ECCurveType, ECPointFormat,
-    ExtensionType, HandshakeType, HashAlgorithm, KeyUpdateRequest, NamedGroup, PSKKeyExchangeMode,
-    ServerNameType, SignatureAlgorithm,
+    CertificateStatusType, CertificateType, ClientCertificateType, Compression, ECCurveType,
+    ECPointFormat, EchClientHelloType, EchVersion, ExtensionType, HandshakeType, HashAlgorithm,
+    HpkeAead, HpkeKdf, HpkeKem, KeyUpdateRequest, MaxFragmentLength, NamedGroup,
+    PSKKeyExchangeMode, ServerNameType, SignatureAlgorithm,
 };
-use crate::rand;
 
 #[cfg(feature = "logging")]
 use crate::log::warn;
@@ -83,9 +86,9 @@ impl Codec for Random {
 }
 
 impl Random {
-    pub fn new() -> Result<Self, rand::GetRandomFailed> {
+    pub fn new(provider: &dyn CryptoProvider) -> Result<Self, GetRandomFailed> {
         let mut data = [0u8; 32];
-        rand::fill_random(&mut data)?;
+        provider.fill_random(&mut data)?;
         Ok(Self(data))
     }
 
@@ -151,9 +154,9 @@ impl Codec for SessionID {
 }
 
 impl SessionID {
-    pub fn random() -> Result<Self, rand::GetRandomFailed> {
+    pub fn random(provider: &dyn CryptoProvider) -> Result<Self, GetRandomFailed> {
         let mut data = [0u8; 32];
-        rand::fill_random(&mut data)?;
+        provider.fill_random(&mut data)?;
         Ok(Self { data, len: 32 })
     }
 
@@ -539,6 +542,207 @@ impl CertificateStatusRequest {
     }
 }
 
+// --- draft-ietf-tls-esni Encrypted Client Hello ---
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HpkeSymmetricCipherSuite {
+    pub kdf_id: HpkeKdf,
+    pub aead_id: HpkeAead,
+}
+
+impl Codec for HpkeSymmetricCipherSuite {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.kdf_id.encode(bytes);
+        self.aead_id.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            kdf_id: HpkeKdf::read(r)?,
+            aead_id: HpkeAead::read(r)?,
+        })
+    }
+}
+
+declare_u16_vec!(HpkeSymmetricCipherSuites, HpkeSymmetricCipherSuite);
+
+#[derive(Clone, Debug)]
+pub struct HpkeKeyConfig {
+    pub config_id: u8,
+    pub kem_id: HpkeKem,
+    pub public_key: PayloadU16,
+    pub symmetric_cipher_suites: HpkeSymmetricCipherSuites,
+}
+
+impl Codec for HpkeKeyConfig {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.config_id.encode(bytes);
+        self.kem_id.encode(bytes);
+        self.public_key.encode(bytes);
+        self.symmetric_cipher_suites
+            .encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            config_id: u8::read(r)?,
+            kem_id: HpkeKem::read(r)?,
+            public_key: PayloadU16::read(r)?,
+            symmetric_cipher_suites: HpkeSymmetricCipherSuites::read(r)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EchConfigContents {
+    pub key_config: HpkeKeyConfig,
+    pub maximum_name_length: u8,
+    pub public_name: PayloadU8,
+    pub extensions: PayloadU16,
+}
+
+impl EchConfigContents {
+    /// Returns true if any extension is marked mandatory (its type has the
+    /// high bit set).  We don't understand any ECHConfig extensions, so such
+    /// configurations must be skipped.
+    pub fn has_mandatory_extension(&self) -> bool {
+        let mut r = Reader::init(&self.extensions.0);
+        while r.any_left() {
+            let typ = match u16::read(&mut r) {
+                Some(typ) => typ,
+                None => return true,
+            };
+            if typ & 0x8000 != 0 || PayloadU16::read(&mut r).is_none() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Codec for EchConfigContents {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.key_config.encode(bytes);
+        self.maximum_name_length.encode(bytes);
+        self.public_name.encode(bytes);
+        self.extensions.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            key_config: HpkeKeyConfig::read(r)?,
+            maximum_name_length: u8::read(r)?,
+            public_name: PayloadU8::read(r)?,
+            extensions: PayloadU16::read(r)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EchConfigPayload {
+    V18(EchConfigContents),
+    Unknown {
+        version: EchVersion,
+        contents: PayloadU16,
+    },
+}
+
+impl Codec for EchConfigPayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            Self::V18(ref c) => {
+                EchVersion::V18.encode(bytes);
+                let inner = c.get_encoding();
+                (inner.len() as u16).encode(bytes);
+                bytes.extend_from_slice(&inner);
+            }
+            Self::Unknown {
+                ref version,
+                ref contents,
+            } => {
+                version.encode(bytes);
+                contents.encode(bytes);
+            }
+        }
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let version = EchVersion::read(r)?;
+        let length = u16::read(r)? as usize;
+        let mut sub = r.sub(length)?;
+
+        let config = match version {
+            EchVersion::V18 => Self::V18(EchConfigContents::read(&mut sub)?),
+            _ => Self::Unknown {
+                version,
+                contents: PayloadU16::new(sub.rest().to_vec()),
+            },
+        };
+
+        if sub.any_left() {
+            None
+        } else {
+            Some(config)
+        }
+    }
+}
+
+declare_u16_vec!(EchConfigList, EchConfigPayload);
+declare_u8_vec!(EchOuterExtensions, ExtensionType);
+
+#[derive(Clone, Debug)]
+pub struct EncryptedClientHelloOuter {
+    pub cipher_suite: HpkeSymmetricCipherSuite,
+    pub config_id: u8,
+    pub enc: PayloadU16,
+    pub payload: PayloadU16,
+}
+
+impl Codec for EncryptedClientHelloOuter {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.cipher_suite.encode(bytes);
+        self.config_id.encode(bytes);
+        self.enc.encode(bytes);
+        self.payload.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            cipher_suite: HpkeSymmetricCipherSuite::read(r)?,
+            config_id: u8::read(r)?,
+            enc: PayloadU16::read(r)?,
+            payload: PayloadU16::read(r)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EncryptedClientHello {
+    Outer(EncryptedClientHelloOuter),
+    Inner,
+}
+
+impl Codec for EncryptedClientHello {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            Self::Outer(ref outer) => {
+                EchClientHelloType::ClientHelloOuter.encode(bytes);
+                outer.encode(bytes);
+            }
+            Self::Inner => EchClientHelloType::ClientHelloInner.encode(bytes),
+        }
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        match EchClientHelloType::read(r)? {
+            EchClientHelloType::ClientHelloOuter => {
+                Some(Self::Outer(EncryptedClientHelloOuter::read(r)?))
+            }
+            EchClientHelloType::ClientHelloInner => Some(Self::Inner),
+            _ => None,
+        }
+    }
+}
+
 // ---
 // SCTs
 
@@ -549,6 +753,11 @@ pub type SCTList = VecU16OfPayloadU16;
 declare_u8_vec!(PSKKeyExchangeModes, PSKKeyExchangeMode);
 declare_u16_vec!(KeyShareEntries, KeyShareEntry);
 declare_u8_vec!(ProtocolVersions, ProtocolVersion);
+declare_u8_vec!(
+    CertificateCompressionAlgorithms,
+    CertificateCompressionAlgorithm
+);
+declare_u8_vec!(CertificateTypes, CertificateType);
 
 #[derive(Clone, Debug)]
 pub enum ClientExtension {
@@ -569,6 +778,15 @@ pub enum ClientExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    EncryptedClientHello(EncryptedClientHello),
+    CertificateCompressionAlgorithms(CertificateCompressionAlgorithms),
+    ClientCertificateTypes(CertificateTypes),
+    ServerCertificateTypes(CertificateTypes),
+    PostHandshakeAuth,
+    RecordSizeLimit(u16),
+    MaxFragmentLength(MaxFragmentLength),
+    AuthorityNames(DistinguishedNames),
+    DelegatedCredential(SupportedSignatureSchemes),
     Unknown(UnknownExtension),
 }
 
@@ -592,6 +810,15 @@ impl ClientExtension {
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::EncryptedClientHello(_) => ExtensionType::EncryptedClientHello,
+            Self::CertificateCompressionAlgorithms(_) => ExtensionType::CompressCertificate,
+            Self::ClientCertificateTypes(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateTypes(_) => ExtensionType::ServerCertificateType,
+            Self::PostHandshakeAuth => ExtensionType::PostHandshakeAuth,
+            Self::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            Self::MaxFragmentLength(_) => ExtensionType::MaxFragmentLength,
+            Self::AuthorityNames(_) => ExtensionType::CertificateAuthorities,
+            Self::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -610,7 +837,8 @@ impl Codec for ClientExtension {
             Self::SessionTicket(ClientSessionTicket::Request)
             | Self::ExtendedMasterSecretRequest
             | Self::SignedCertificateTimestampRequest
-            | Self::EarlyData => {}
+            | Self::EarlyData
+            | Self::PostHandshakeAuth => {}
             Self::SessionTicket(ClientSessionTicket::Offer(ref r)) => r.encode(&mut sub),
             Self::Protocols(ref r) => r.encode(&mut sub),
             Self::SupportedVersions(ref r) => r.encode(&mut sub),
@@ -622,6 +850,15 @@ impl Codec for ClientExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r)
             }
+            Self::EncryptedClientHello(ref r) => r.encode(&mut sub),
+            Self::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
+            Self::ClientCertificateTypes(ref r) | Self::ServerCertificateTypes(ref r) => {
+                r.encode(&mut sub)
+            }
+            Self::RecordSizeLimit(r) => r.encode(&mut sub),
+            Self::MaxFragmentLength(r) => r.encode(&mut sub),
+            Self::AuthorityNames(ref r) => r.encode(&mut sub),
+            Self::DelegatedCredential(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -677,6 +914,37 @@ impl Codec for ClientExtension {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData if !sub.any_left() => Self::EarlyData,
+            ExtensionType::PostHandshakeAuth if !sub.any_left() => Self::PostHandshakeAuth,
+            ExtensionType::EncryptedClientHello => {
+                Self::EncryptedClientHello(EncryptedClientHello::read(&mut sub)?)
+            }
+            ExtensionType::CompressCertificate => {
+                let algs = CertificateCompressionAlgorithms::read(&mut sub)?;
+                if algs.is_empty() {
+                    return None;
+                }
+                Self::CertificateCompressionAlgorithms(algs)
+            }
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateTypes(CertificateTypes::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateTypes(CertificateTypes::read(&mut sub)?)
+            }
+            ExtensionType::RecordSizeLimit => Self::RecordSizeLimit(u16::read(&mut sub)?),
+            ExtensionType::MaxFragmentLength => {
+                Self::MaxFragmentLength(MaxFragmentLength::read(&mut sub)?)
+            }
+            ExtensionType::CertificateAuthorities => {
+                let cas = DistinguishedNames::read(&mut sub)?;
+                if cas.is_empty() {
+                    return None;
+                }
+                Self::AuthorityNames(cas)
+            }
+            ExtensionType::DelegatedCredential => {
+                Self::DelegatedCredential(SupportedSignatureSchemes::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -737,6 +1005,11 @@ pub enum ServerExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    EncryptedClientHello(EchConfigList),
+    ClientCertificateType(CertificateType),
+    ServerCertificateType(CertificateType),
+    RecordSizeLimit(u16),
+    MaxFragmentLength(MaxFragmentLength),
     Unknown(UnknownExtension),
 }
 
@@ -757,6 +1030,11 @@ impl ServerExtension {
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::EncryptedClientHello(_) => ExtensionType::EncryptedClientHello,
+            Self::ClientCertificateType(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateType(_) => ExtensionType::ServerCertificateType,
+            Self::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            Self::MaxFragmentLength(_) => ExtensionType::MaxFragmentLength,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -783,6 +1061,12 @@ impl Codec for ServerExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r)
             }
+            Self::EncryptedClientHello(ref r) => r.encode(&mut sub),
+            Self::ClientCertificateType(ref r) | Self::ServerCertificateType(ref r) => {
+                r.encode(&mut sub)
+            }
+            Self::RecordSizeLimit(r) => r.encode(&mut sub),
+            Self::MaxFragmentLength(r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -821,6 +1105,19 @@ impl Codec for ServerExtension {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData => Self::EarlyData,
+            ExtensionType::EncryptedClientHello => {
+                Self::EncryptedClientHello(EchConfigList::read(&mut sub)?)
+            }
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateType(CertificateType::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateType(CertificateType::read(&mut sub)?)
+            }
+            ExtensionType::RecordSizeLimit => Self::RecordSizeLimit(u16::read(&mut sub)?),
+            ExtensionType::MaxFragmentLength => {
+                Self::MaxFragmentLength(MaxFragmentLength::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -848,7 +1145,14 @@ impl ServerExtension {
     }
 }
 
-#[derive(Debug)]
+fn is_dtls_version(version: ProtocolVersion) -> bool {
+    matches!(
+        version,
+        ProtocolVersion::DTLSv1_0 | ProtocolVersion::DTLSv1_2 | ProtocolVersion::DTLSv1_3
+    )
+}
+
+#[derive(Clone, Debug)]
 pub struct ClientHelloPayload {
     pub client_version: ProtocolVersion,
     pub random: Random,
@@ -863,6 +1167,10 @@ impl Codec for ClientHelloPayload {
         self.client_version.encode(bytes);
         self.random.encode(bytes);
         self.session_id.encode(bytes);
+        if self.is_dtls() {
+            // legacy_cookie, which is always empty in DTLS1.3
+            PayloadU8::empty().encode(bytes);
+        }
         codec::encode_vec_u16(bytes, &self.cipher_suites);
         codec::encode_vec_u8(bytes, &self.compression_methods);
 
@@ -872,10 +1180,18 @@ impl Codec for ClientHelloPayload {
     }
 
     fn read(r: &mut Reader) -> Option<Self> {
+        let client_version = ProtocolVersion::read(r)?;
+        let random = Random::read(r)?;
+        let session_id = SessionID::read(r)?;
+        if is_dtls_version(client_version) && !PayloadU8::read(r)?.0.is_empty() {
+            // "A DTLS 1.3-only client MUST set the legacy_cookie field to zero length."
+            return None;
+        }
+
         let mut ret = Self {
-            client_version: ProtocolVersion::read(r)?,
-            random: Random::read(r)?,
-            session_id: SessionID::read(r)?,
+            client_version,
+            random,
+            session_id,
             cipher_suites: codec::read_vec_u16::<CipherSuite>(r)?,
             compression_methods: codec::read_vec_u8::<Compression>(r)?,
             extensions: Vec::new(),
@@ -894,6 +1210,10 @@ impl Codec for ClientHelloPayload {
 }
 
 impl ClientHelloPayload {
+    fn is_dtls(&self) -> bool {
+        is_dtls_version(self.client_version)
+    }
+
     /// Returns true if there is more than one extension of a given
     /// type.
     pub fn has_duplicate_extension(&self) -> bool {
@@ -1032,10 +1352,12 @@ impl ClientHelloPayload {
             .unwrap_or(false)
     }
 
-    pub fn set_psk_binder(&mut self, binder: impl Into<Vec<u8>>) {
+    pub fn set_psk_binders(&mut self, binders: Vec<Vec<u8>>) {
         let last_extension = self.extensions.last_mut();
         if let Some(ClientExtension::PresharedKey(ref mut offer)) = last_extension {
-            offer.binders[0] = PresharedKeyBinder::new(binder.into());
+            for (slot, binder) in offer.binders.iter_mut().zip(binders) {
+                *slot = PresharedKeyBinder::new(binder);
+            }
         }
     }
 
@@ -1048,13 +1370,85 @@ impl ClientHelloPayload {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    pub fn post_handshake_auth_offered(&self) -> bool {
+        self.find_extension(ExtensionType::PostHandshakeAuth)
+            .is_some()
+    }
+
+    pub fn get_certificate_compression_extension(
+        &self,
+    ) -> Option<&CertificateCompressionAlgorithms> {
+        let ext = self.find_extension(ExtensionType::CompressCertificate)?;
+        match *ext {
+            ClientExtension::CertificateCompressionAlgorithms(ref algs) => Some(algs),
+            _ => None,
+        }
+    }
+
+    pub fn get_client_certificate_types_extension(&self) -> Option<&CertificateTypes> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ClientExtension::ClientCertificateTypes(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_server_certificate_types_extension(&self) -> Option<&CertificateTypes> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ClientExtension::ServerCertificateTypes(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_record_size_limit_extension(&self) -> Option<u16> {
+        let ext = self.find_extension(ExtensionType::RecordSizeLimit)?;
+        match *ext {
+            ClientExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    pub fn get_max_fragment_length_extension(&self) -> Option<MaxFragmentLength> {
+        let ext = self.find_extension(ExtensionType::MaxFragmentLength)?;
+        match *ext {
+            ClientExtension::MaxFragmentLength(len) => Some(len),
+            _ => None,
+        }
+    }
+
+    pub fn get_certificate_authorities_extension(&self) -> Option<&DistinguishedNames> {
+        let ext = self.find_extension(ExtensionType::CertificateAuthorities)?;
+        match *ext {
+            ClientExtension::AuthorityNames(ref names) => Some(names),
+            _ => None,
+        }
+    }
+
+    pub fn get_delegated_credential_extension(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = self.find_extension(ExtensionType::DelegatedCredential)?;
+        match *ext {
+            ClientExtension::DelegatedCredential(ref schemes) => Some(schemes),
+            _ => None,
+        }
+    }
+
+    pub fn get_ech_extension(&self) -> Option<&EncryptedClientHello> {
+        let ext = self.find_extension(ExtensionType::EncryptedClientHello)?;
+        match *ext {
+            ClientExtension::EncryptedClientHello(ref ech) => Some(ech),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum HelloRetryExtension {
     KeyShare(NamedGroup),
     Cookie(PayloadU16),
     SupportedVersions(ProtocolVersion),
+    EncryptedClientHello(Payload),
     Unknown(UnknownExtension),
 }
 
@@ -1064,6 +1458,7 @@ impl HelloRetryExtension {
             Self::KeyShare(_) => ExtensionType::KeyShare,
             Self::Cookie(_) => ExtensionType::Cookie,
             Self::SupportedVersions(_) => ExtensionType::SupportedVersions,
+            Self::EncryptedClientHello(_) => ExtensionType::EncryptedClientHello,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -1078,6 +1473,7 @@ impl Codec for HelloRetryExtension {
             Self::KeyShare(ref r) => r.encode(&mut sub),
             Self::Cookie(ref r) => r.encode(&mut sub),
             Self::SupportedVersions(ref r) => r.encode(&mut sub),
+            Self::EncryptedClientHello(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1096,6 +1492,9 @@ impl Codec for HelloRetryExtension {
             ExtensionType::SupportedVersions => {
                 Self::SupportedVersions(ProtocolVersion::read(&mut sub)?)
             }
+            ExtensionType::EncryptedClientHello => {
+                Self::EncryptedClientHello(Payload::read(&mut sub))
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1107,7 +1506,7 @@ impl Codec for HelloRetryExtension {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct HelloRetryRequest {
     pub legacy_version: ProtocolVersion,
     pub session_id: SessionID,
@@ -1166,6 +1565,7 @@ impl HelloRetryRequest {
             ext.get_type() != ExtensionType::KeyShare
                 && ext.get_type() != ExtensionType::SupportedVersions
                 && ext.get_type() != ExtensionType::Cookie
+                && ext.get_type() != ExtensionType::EncryptedClientHello
         })
     }
 
@@ -1198,6 +1598,14 @@ impl HelloRetryRequest {
             _ => None,
         }
     }
+
+    pub fn get_ech_confirmation(&self) -> Option<&[u8]> {
+        let ext = self.find_extension(ExtensionType::EncryptedClientHello)?;
+        match *ext {
+            HelloRetryExtension::EncryptedClientHello(ref confirmation) => Some(&confirmation.0),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1331,6 +1739,7 @@ impl Codec for CertificatePayload {
 pub enum CertificateExtension {
     CertificateStatus(CertificateStatus),
     SignedCertificateTimestamp(SCTList),
+    DelegatedCredential(DelegatedCredential),
     Unknown(UnknownExtension),
 }
 
@@ -1339,6 +1748,7 @@ impl CertificateExtension {
         match *self {
             Self::CertificateStatus(_) => ExtensionType::StatusRequest,
             Self::SignedCertificateTimestamp(_) => ExtensionType::SCT,
+            Self::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -1361,6 +1771,13 @@ impl CertificateExtension {
             _ => None,
         }
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredential> {
+        match *self {
+            Self::DelegatedCredential(ref dc) => Some(dc),
+            _ => None,
+        }
+    }
 }
 
 impl Codec for CertificateExtension {
@@ -1371,6 +1788,7 @@ impl Codec for CertificateExtension {
         match *self {
             Self::CertificateStatus(ref r) => r.encode(&mut sub),
             Self::SignedCertificateTimestamp(ref r) => r.encode(&mut sub),
+            Self::DelegatedCredential(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1392,6 +1810,9 @@ impl Codec for CertificateExtension {
                 let scts = SCTList::read(&mut sub)?;
                 Self::SignedCertificateTimestamp(scts)
             }
+            ExtensionType::DelegatedCredential => {
+                Self::DelegatedCredential(DelegatedCredential::read(&mut sub)?)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1405,6 +1826,72 @@ impl Codec for CertificateExtension {
 
 declare_u16_vec!(CertificateExtensions, CertificateExtension);
 
+/// The `Credential` structure from RFC9345.
+#[derive(Clone, Debug)]
+pub struct Credential {
+    /// Validity period, in seconds after the certificate's `notBefore`.
+    pub valid_time: u32,
+    pub dc_cert_verify_algorithm: SignatureScheme,
+    /// DER-encoded `SubjectPublicKeyInfo` of the delegated key.
+    pub public_key: PayloadU24,
+}
+
+impl Codec for Credential {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.valid_time.encode(bytes);
+        self.dc_cert_verify_algorithm
+            .encode(bytes);
+        self.public_key.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let valid_time = u32::read(r)?;
+        let dc_cert_verify_algorithm = SignatureScheme::read(r)?;
+        let public_key = PayloadU24::read(r)?;
+        if public_key.0.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            valid_time,
+            dc_cert_verify_algorithm,
+            public_key,
+        })
+    }
+}
+
+/// The `DelegatedCredential` structure from RFC9345: a `Credential`
+/// signed by the key of the end-entity certificate.
+#[derive(Clone, Debug)]
+pub struct DelegatedCredential {
+    pub cred: Credential,
+    pub algorithm: SignatureScheme,
+    pub signature: PayloadU16,
+}
+
+impl Codec for DelegatedCredential {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.cred.encode(bytes);
+        self.algorithm.encode(bytes);
+        self.signature.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let cred = Credential::read(r)?;
+        let algorithm = SignatureScheme::read(r)?;
+        let signature = PayloadU16::read(r)?;
+        if signature.0.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            cred,
+            algorithm,
+            signature,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct CertificateEntry {
     pub cert: key::Certificate,
@@ -1450,7 +1937,9 @@ impl CertificateEntry {
 
     pub fn has_unknown_extension(&self) -> bool {
         self.exts.iter().any(|ext| {
-            ext.get_type() != ExtensionType::StatusRequest && ext.get_type() != ExtensionType::SCT
+            ext.get_type() != ExtensionType::StatusRequest
+                && ext.get_type() != ExtensionType::SCT
+                && ext.get_type() != ExtensionType::DelegatedCredential
         })
     }
 
@@ -1467,6 +1956,13 @@ impl CertificateEntry {
             .find(|ext| ext.get_type() == ExtensionType::SCT)
             .and_then(CertificateExtension::get_sct_list)
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredential> {
+        self.exts
+            .iter()
+            .find(|ext| ext.get_type() == ExtensionType::DelegatedCredential)
+            .and_then(CertificateExtension::get_delegated_credential)
+    }
 }
 
 #[derive(Debug)]
@@ -1542,6 +2038,13 @@ impl CertificatePayloadTLS13 {
             .cloned()
     }
 
+    pub fn get_end_entity_delegated_credential(&self) -> Option<DelegatedCredential> {
+        self.entries
+            .first()
+            .and_then(CertificateEntry::get_delegated_credential)
+            .cloned()
+    }
+
     pub fn convert(&self) -> CertificatePayload {
         let mut ret = Vec::new();
         for entry in &self.entries {
@@ -1551,7 +2054,7 @@ impl CertificatePayloadTLS13 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KeyExchangeAlgorithm {
     BulkOnly,
     DH,
@@ -1561,6 +2064,21 @@ pub enum KeyExchangeAlgorithm {
     ECDHE,
 }
 
+impl NamedGroup {
+    /// The TLS1.2 key exchange algorithm that uses this group.
+    #[cfg(feature = "tls12")]
+    pub(crate) fn key_exchange_algorithm(self) -> KeyExchangeAlgorithm {
+        match self {
+            Self::FFDHE2048
+            | Self::FFDHE3072
+            | Self::FFDHE4096
+            | Self::FFDHE6144
+            | Self::FFDHE8192 => KeyExchangeAlgorithm::DHE,
+            _ => KeyExchangeAlgorithm::ECDHE,
+        }
+    }
+}
+
 // We don't support arbitrary curves.  It's a terrible
 // idea and unnecessary attack surface.  Please,
 // get a grip.
@@ -1679,6 +2197,55 @@ impl Codec for ServerECDHParams {
     }
 }
 
+#[derive(Debug)]
+pub struct ClientDHParams {
+    pub public: PayloadU16,
+}
+
+impl Codec for ClientDHParams {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.public.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let pb = PayloadU16::read(r)?;
+        Some(Self { public: pb })
+    }
+}
+
+#[derive(Debug)]
+pub struct ServerDHParams {
+    pub dh_p: PayloadU16,
+    pub dh_g: PayloadU16,
+    pub dh_ys: PayloadU16,
+}
+
+impl ServerDHParams {
+    pub fn new(p: &[u8], g: &[u8], pubkey: &[u8]) -> Self {
+        Self {
+            dh_p: PayloadU16::new(p.to_vec()),
+            dh_g: PayloadU16::new(g.to_vec()),
+            dh_ys: PayloadU16::new(pubkey.to_vec()),
+        }
+    }
+}
+
+impl Codec for ServerDHParams {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.dh_p.encode(bytes);
+        self.dh_g.encode(bytes);
+        self.dh_ys.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let dh_p = PayloadU16::read(r)?;
+        let dh_g = PayloadU16::read(r)?;
+        let dh_ys = PayloadU16::read(r)?;
+
+        Some(Self { dh_p, dh_g, dh_ys })
+    }
+}
+
 #[derive(Debug)]
 pub struct ECDHEServerKeyExchange {
     pub params: ServerECDHParams,
@@ -1699,9 +2266,30 @@ impl Codec for ECDHEServerKeyExchange {
     }
 }
 
+#[derive(Debug)]
+pub struct DHEServerKeyExchange {
+    pub params: ServerDHParams,
+    pub dss: DigitallySignedStruct,
+}
+
+impl Codec for DHEServerKeyExchange {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.params.encode(bytes);
+        self.dss.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        let params = ServerDHParams::read(r)?;
+        let dss = DigitallySignedStruct::read(r)?;
+
+        Some(Self { params, dss })
+    }
+}
+
 #[derive(Debug)]
 pub enum ServerKeyExchangePayload {
     ECDHE(ECDHEServerKeyExchange),
+    DHE(DHEServerKeyExchange),
     Unknown(Payload),
 }
 
@@ -1709,6 +2297,7 @@ impl Codec for ServerKeyExchangePayload {
     fn encode(&self, bytes: &mut Vec<u8>) {
         match *self {
             Self::ECDHE(ref x) => x.encode(bytes),
+            Self::DHE(ref x) => x.encode(bytes),
             Self::Unknown(ref x) => x.encode(bytes),
         }
     }
@@ -1721,12 +2310,17 @@ impl Codec for ServerKeyExchangePayload {
 }
 
 impl ServerKeyExchangePayload {
-    pub fn unwrap_given_kxa(&self, kxa: &KeyExchangeAlgorithm) -> Option<ECDHEServerKeyExchange> {
+    /// Fully parse an `Unknown` payload, now that we know the
+    /// `KeyExchangeAlgorithm`.
+    pub fn unwrap_given_kxa(&self, kxa: &KeyExchangeAlgorithm) -> Option<Self> {
         if let Self::Unknown(ref unk) = *self {
             let mut rd = Reader::init(&unk.0);
 
             let result = match *kxa {
-                KeyExchangeAlgorithm::ECDHE => ECDHEServerKeyExchange::read(&mut rd),
+                KeyExchangeAlgorithm::ECDHE => {
+                    ECDHEServerKeyExchange::read(&mut rd).map(Self::ECDHE)
+                }
+                KeyExchangeAlgorithm::DHE => DHEServerKeyExchange::read(&mut rd).map(Self::DHE),
                 _ => None,
             };
 
@@ -1791,6 +2385,46 @@ pub trait HasServerExtensions {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    fn get_ech_retry_configs(&self) -> Option<&EchConfigList> {
+        let ext = self.find_extension(ExtensionType::EncryptedClientHello)?;
+        match *ext {
+            ServerExtension::EncryptedClientHello(ref configs) => Some(configs),
+            _ => None,
+        }
+    }
+
+    fn get_client_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ServerExtension::ClientCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    fn get_server_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ServerExtension::ServerCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = self.find_extension(ExtensionType::RecordSizeLimit)?;
+        match *ext {
+            ServerExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    fn get_max_fragment_length(&self) -> Option<MaxFragmentLength> {
+        let ext = self.find_extension(ExtensionType::MaxFragmentLength)?;
+        match *ext {
+            ServerExtension::MaxFragmentLength(len) => Some(len),
+            _ => None,
+        }
+    }
 }
 
 impl HasServerExtensions for EncryptedExtensions {
@@ -1852,6 +2486,7 @@ impl Codec for CertificateRequestPayload {
 pub enum CertReqExtension {
     SignatureAlgorithms(SupportedSignatureSchemes),
     AuthorityNames(DistinguishedNames),
+    CertificateCompressionAlgorithms(CertificateCompressionAlgorithms),
     Unknown(UnknownExtension),
 }
 
@@ -1860,6 +2495,7 @@ impl CertReqExtension {
         match *self {
             Self::SignatureAlgorithms(_) => ExtensionType::SignatureAlgorithms,
             Self::AuthorityNames(_) => ExtensionType::CertificateAuthorities,
+            Self::CertificateCompressionAlgorithms(_) => ExtensionType::CompressCertificate,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -1873,6 +2509,7 @@ impl Codec for CertReqExtension {
         match *self {
             Self::SignatureAlgorithms(ref r) => r.encode(&mut sub),
             Self::AuthorityNames(ref r) => r.encode(&mut sub),
+            Self::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1897,6 +2534,13 @@ impl Codec for CertReqExtension {
                 let cas = DistinguishedNames::read(&mut sub)?;
                 Self::AuthorityNames(cas)
             }
+            ExtensionType::CompressCertificate => {
+                let algs = CertificateCompressionAlgorithms::read(&mut sub)?;
+                if algs.is_empty() {
+                    return None;
+                }
+                Self::CertificateCompressionAlgorithms(algs)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1955,6 +2599,40 @@ impl CertificateRequestPayloadTLS13 {
             _ => None,
         }
     }
+
+    pub fn get_certificate_compression_extension(
+        &self,
+    ) -> Option<&CertificateCompressionAlgorithms> {
+        let ext = self.find_extension(ExtensionType::CompressCertificate)?;
+        match *ext {
+            CertReqExtension::CertificateCompressionAlgorithms(ref algs) => Some(algs),
+            _ => None,
+        }
+    }
+}
+
+// -- CompressedCertificate (RFC8879) --
+#[derive(Debug)]
+pub struct CompressedCertificatePayload {
+    pub alg: CertificateCompressionAlgorithm,
+    pub uncompressed_len: u32,
+    pub compressed: PayloadU24,
+}
+
+impl Codec for CompressedCertificatePayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.alg.encode(bytes);
+        codec::u24(self.uncompressed_len).encode(bytes);
+        self.compressed.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            alg: CertificateCompressionAlgorithm::read(r)?,
+            uncompressed_len: codec::u24::read(r)?.0,
+            compressed: PayloadU24::read(r)?,
+        })
+    }
 }
 
 // -- NewSessionTicket --
@@ -2162,6 +2840,7 @@ pub enum HandshakePayload {
     HelloRetryRequest(HelloRetryRequest),
     Certificate(CertificatePayload),
     CertificateTLS13(CertificatePayloadTLS13),
+    CompressedCertificate(CompressedCertificatePayload),
     ServerKeyExchange(ServerKeyExchangePayload),
     CertificateRequest(CertificateRequestPayload),
     CertificateRequestTLS13(CertificateRequestPayloadTLS13),
@@ -2189,6 +2868,7 @@ impl HandshakePayload {
             HelloRetryRequest(ref x) => x.encode(bytes),
             Certificate(ref x) => x.encode(bytes),
             CertificateTLS13(ref x) => x.encode(bytes),
+            CompressedCertificate(ref x) => x.encode(bytes),
             ServerKeyExchange(ref x) => x.encode(bytes),
             ClientKeyExchange(ref x) => x.encode(bytes),
             CertificateRequest(ref x) => x.encode(bytes),
@@ -2312,6 +2992,9 @@ impl HandshakeMessagePayload {
                 HandshakePayload::EndOfEarlyData
             }
             HandshakeType::Finished => HandshakePayload::Finished(Payload::read(&mut sub)),
+            HandshakeType::CompressedCertificate => HandshakePayload::CompressedCertificate(
+                CompressedCertificatePayload::read(&mut sub)?,
+            ),
             HandshakeType::CertificateStatus => {
                 HandshakePayload::CertificateStatus(CertificateStatus::read(&mut sub)?)
             }
@@ -2340,6 +3023,13 @@ impl HandshakeMessagePayload {
         }
     }
 
+    pub fn build_key_update_request() -> Self {
+        Self {
+            typ: HandshakeType::KeyUpdate,
+            payload: HandshakePayload::KeyUpdate(KeyUpdateRequest::UpdateRequested),
+        }
+    }
+
     pub fn get_encoding_for_binder_signing(&self) -> Vec<u8> {
         let mut ret = self.get_encoding();
 
@@ -1,23 +1,27 @@
-use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
+use crate::enums::{
+    CertificateCompressionAlgorithm, CipherSuite, ProtocolVersion, SignatureScheme,
+};
 use crate::key::Certificate;
 use crate::msgs::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
 use crate::msgs::codec::{put_u16, Codec, Reader};
 use crate::msgs::enums::{
-    ClientCertificateType, Compression, ECCurveType, ExtensionType, HandshakeType, HashAlgorithm,
-    KeyUpdateRequest, NamedGroup, PSKKeyExchangeMode, ServerNameType, SignatureAlgorithm,
+    CertificateType, ClientCertificateType, Compression, ECCurveType, ExtensionType, HandshakeType,
+    HashAlgorithm, KeyUpdateRequest, MaxFragmentLength, NamedGroup, PSKKeyExchangeMode,
+    ServerNameType, SignatureAlgorithm,
 };
 use crate::msgs::handshake::{
     CertReqExtension, CertificateEntry, CertificateExtension, CertificatePayloadTLS13,
     CertificateRequestPayload, CertificateRequestPayloadTLS13, CertificateStatus,
     CertificateStatusRequest, ClientExtension, ClientHelloPayload, ClientSessionTicket,
-    ConvertProtocolNameList, ConvertServerNameList, DecomposedSignatureScheme,
-    DigitallySignedStruct, ECDHEServerKeyExchange, ECParameters, ECPointFormatList,
-    EncryptedExtensions, HandshakeMessagePayload, HandshakePayload, HasServerExtensions,
-    HelloRetryExtension, HelloRetryRequest, KeyShareEntry, NewSessionTicketExtension,
+    CompressedCertificatePayload, ConvertProtocolNameList, ConvertServerNameList, Credential,
+    DHEServerKeyExchange, DecomposedSignatureScheme, DelegatedCredential, DigitallySignedStruct,
+    ECDHEServerKeyExchange, ECParameters, ECPointFormatList, EncryptedExtensions,
+    HandshakeMessagePayload, HandshakePayload, HasServerExtensions, HelloRetryExtension,
+    HelloRetryRequest, KeyExchangeAlgorithm, KeyShareEntry, NewSessionTicketExtension,
     NewSessionTicketPayload, NewSessionTicketPayloadTLS13, PresharedKeyBinder,
-    PresharedKeyIdentity, PresharedKeyOffer, Random, ServerECDHParams, ServerExtension,
-    ServerHelloPayload, ServerKeyExchangePayload, SessionID, SupportedPointFormats,
-    UnknownExtension,
+    PresharedKeyIdentity, PresharedKeyOffer, Random, ServerDHParams, ServerECDHParams,
+    ServerExtension, ServerHelloPayload, ServerKeyExchangePayload, SessionID,
+    SupportedPointFormats, UnknownExtension,
 };
 use webpki::DnsNameRef;
 
@@ -424,6 +428,19 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
             ClientExtension::SignedCertificateTimestampRequest,
             ClientExtension::TransportParameters(vec![1, 2, 3]),
+            ClientExtension::CertificateCompressionAlgorithms(vec![
+                CertificateCompressionAlgorithm::Zlib,
+            ]),
+            ClientExtension::ClientCertificateTypes(vec![CertificateType::RawPublicKey]),
+            ClientExtension::ServerCertificateTypes(vec![
+                CertificateType::RawPublicKey,
+                CertificateType::X509,
+            ]),
+            ClientExtension::PostHandshakeAuth,
+            ClientExtension::RecordSizeLimit(512),
+            ClientExtension::MaxFragmentLength(MaxFragmentLength::Len1024),
+            ClientExtension::AuthorityNames(vec![PayloadU16(vec![1, 2, 3])]),
+            ClientExtension::DelegatedCredential(vec![SignatureScheme::ED25519]),
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -607,6 +624,38 @@ fn client_get_psk_modes() {
     });
 }
 
+#[test]
+fn client_get_certificate_authorities_extension() {
+    test_client_extension_getter(ExtensionType::CertificateAuthorities, |chp| {
+        chp.get_certificate_authorities_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_delegated_credential_extension() {
+    test_client_extension_getter(ExtensionType::DelegatedCredential, |chp| {
+        chp.get_delegated_credential_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_max_fragment_length_extension() {
+    test_client_extension_getter(ExtensionType::MaxFragmentLength, |chp| {
+        chp.get_max_fragment_length_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_record_size_limit_extension() {
+    test_client_extension_getter(ExtensionType::RecordSizeLimit, |chp| {
+        chp.get_record_size_limit_extension()
+            .is_some()
+    });
+}
+
 #[test]
 fn test_truncated_helloretry_extension_is_detected() {
     let hrr = get_sample_helloretryrequest();
@@ -753,6 +802,20 @@ fn server_get_supported_versions() {
     });
 }
 
+#[test]
+fn server_get_max_fragment_length() {
+    test_server_extension_getter(ExtensionType::MaxFragmentLength, |shp| {
+        shp.get_max_fragment_length().is_some()
+    });
+}
+
+#[test]
+fn server_get_record_size_limit() {
+    test_server_extension_getter(ExtensionType::RecordSizeLimit, |shp| {
+        shp.get_record_size_limit().is_some()
+    });
+}
+
 fn test_cert_extension_getter(typ: ExtensionType, getter: fn(&CertificateEntry) -> bool) {
     let mut ce = get_sample_certificatepayloadtls13()
         .entries
@@ -784,6 +847,39 @@ fn certentry_get_scts() {
     test_cert_extension_getter(ExtensionType::SCT, |ce| ce.get_scts().is_some());
 }
 
+#[test]
+fn certentry_get_delegated_credential() {
+    test_cert_extension_getter(ExtensionType::DelegatedCredential, |ce| {
+        ce.get_delegated_credential().is_some()
+    });
+}
+
+#[test]
+fn refuses_delegated_credential_with_empty_fields() {
+    let dc = get_sample_delegated_credential();
+    assert!(DelegatedCredential::read_bytes(&dc.get_encoding()).is_some());
+
+    let mut no_key = dc.clone();
+    no_key.cred.public_key = PayloadU24(vec![]);
+    assert!(DelegatedCredential::read_bytes(&no_key.get_encoding()).is_none());
+
+    let mut no_signature = dc;
+    no_signature.signature = PayloadU16(vec![]);
+    assert!(DelegatedCredential::read_bytes(&no_signature.get_encoding()).is_none());
+}
+
+fn get_sample_delegated_credential() -> DelegatedCredential {
+    DelegatedCredential {
+        cred: Credential {
+            valid_time: 3600,
+            dc_cert_verify_algorithm: SignatureScheme::ED25519,
+            public_key: PayloadU24(vec![1, 2, 3]),
+        },
+        algorithm: SignatureScheme::ECDSA_NISTP256_SHA256,
+        signature: PayloadU16(vec![4, 5, 6]),
+    }
+}
+
 fn get_sample_serverhellopayload() -> ServerHelloPayload {
     ServerHelloPayload {
         legacy_version: ProtocolVersion::TLSv1_2,
@@ -804,6 +900,10 @@ fn get_sample_serverhellopayload() -> ServerHelloPayload {
             ServerExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
             ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_2),
             ServerExtension::TransportParameters(vec![1, 2, 3]),
+            ServerExtension::ClientCertificateType(CertificateType::RawPublicKey),
+            ServerExtension::ServerCertificateType(CertificateType::X509),
+            ServerExtension::RecordSizeLimit(16385),
+            ServerExtension::MaxFragmentLength(MaxFragmentLength::Len1024),
             ServerExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -849,6 +949,7 @@ fn get_sample_certificatepayloadtls13() -> CertificatePayloadTLS13 {
                     ocsp_response: PayloadU24(vec![1, 2, 3]),
                 }),
                 CertificateExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
+                CertificateExtension::DelegatedCredential(get_sample_delegated_credential()),
                 CertificateExtension::Unknown(UnknownExtension {
                     typ: ExtensionType::Unknown(12345),
                     payload: Payload(vec![1, 2, 3]),
@@ -871,6 +972,40 @@ fn get_sample_serverkeyexchangepayload_ecdhe() -> ServerKeyExchangePayload {
     })
 }
 
+fn get_sample_serverkeyexchangepayload_dhe() -> ServerKeyExchangePayload {
+    ServerKeyExchangePayload::DHE(DHEServerKeyExchange {
+        params: ServerDHParams::new(&[0xff, 0xfb], &[2], &[1, 2, 3]),
+        dss: DigitallySignedStruct::new(SignatureScheme::RSA_PSS_SHA256, vec![1, 2, 3]),
+    })
+}
+
+#[test]
+fn serverkeyexchange_is_parsed_given_kxa() {
+    for (sample, kxa) in [
+        (
+            get_sample_serverkeyexchangepayload_ecdhe(),
+            KeyExchangeAlgorithm::ECDHE,
+        ),
+        (
+            get_sample_serverkeyexchangepayload_dhe(),
+            KeyExchangeAlgorithm::DHE,
+        ),
+    ] {
+        let opaque = ServerKeyExchangePayload::read_bytes(&sample.get_encoding()).unwrap();
+        let parsed = opaque.unwrap_given_kxa(&kxa).unwrap();
+        assert_eq!(parsed.get_encoding(), sample.get_encoding());
+    }
+
+    // Parsing with the wrong algorithm fails.
+    let opaque = ServerKeyExchangePayload::read_bytes(
+        &get_sample_serverkeyexchangepayload_dhe().get_encoding(),
+    )
+    .unwrap();
+    assert!(opaque
+        .unwrap_given_kxa(&KeyExchangeAlgorithm::ECDHE)
+        .is_none());
+}
+
 fn get_sample_serverkeyexchangepayload_unknown() -> ServerKeyExchangePayload {
     ServerKeyExchangePayload::Unknown(Payload(vec![1, 2, 3]))
 }
@@ -889,6 +1024,9 @@ fn get_sample_certificaterequestpayloadtls13() -> CertificateRequestPayloadTLS13
         extensions: vec![
             CertReqExtension::SignatureAlgorithms(vec![SignatureScheme::ECDSA_NISTP256_SHA256]),
             CertReqExtension::AuthorityNames(vec![PayloadU16(vec![1, 2, 3])]),
+            CertReqExtension::CertificateCompressionAlgorithms(vec![
+                CertificateCompressionAlgorithm::Zstd,
+            ]),
             CertReqExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -955,6 +1093,10 @@ fn get_all_tls12_handshake_payloads() -> Vec<HandshakeMessagePayload> {
                 get_sample_serverkeyexchangepayload_ecdhe(),
             ),
         },
+        HandshakeMessagePayload {
+            typ: HandshakeType::ServerKeyExchange,
+            payload: HandshakePayload::ServerKeyExchange(get_sample_serverkeyexchangepayload_dhe()),
+        },
         HandshakeMessagePayload {
             typ: HandshakeType::ServerKeyExchange,
             payload: HandshakePayload::ServerKeyExchange(
@@ -1077,12 +1219,24 @@ fn get_all_tls13_handshake_payloads() -> Vec<HandshakeMessagePayload> {
             typ: HandshakeType::Certificate,
             payload: HandshakePayload::CertificateTLS13(get_sample_certificatepayloadtls13()),
         },
+        HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(CompressedCertificatePayload {
+                alg: CertificateCompressionAlgorithm::Zlib,
+                uncompressed_len: 123,
+                compressed: PayloadU24(vec![1, 2, 3]),
+            }),
+        },
         HandshakeMessagePayload {
             typ: HandshakeType::ServerKeyExchange,
             payload: HandshakePayload::ServerKeyExchange(
                 get_sample_serverkeyexchangepayload_ecdhe(),
             ),
         },
+        HandshakeMessagePayload {
+            typ: HandshakeType::ServerKeyExchange,
+            payload: HandshakePayload::ServerKeyExchange(get_sample_serverkeyexchangepayload_dhe()),
+        },
         HandshakeMessagePayload {
             typ: HandshakeType::ServerKeyExchange,
             payload: HandshakePayload::ServerKeyExchange(
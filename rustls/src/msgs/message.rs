@@ -223,6 +223,18 @@ impl Message {
         }
     }
 
+    /// Copy this message back into plaintext form, from which it can be
+    /// decoded again.
+    pub fn to_plain(&self) -> PlainMessage {
+        let mut buf = Vec::new();
+        self.payload.encode(&mut buf);
+        PlainMessage {
+            typ: self.payload.content_type(),
+            version: self.version,
+            payload: Payload(buf),
+        }
+    }
+
     pub fn build_alert(level: AlertLevel, desc: AlertDescription) -> Self {
         Self {
             version: ProtocolVersion::TLSv1_2,
@@ -239,6 +251,13 @@ impl Message {
             payload: MessagePayload::handshake(HandshakeMessagePayload::build_key_update_notify()),
         }
     }
+
+    pub fn build_key_update_request() -> Self {
+        Self {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::handshake(HandshakeMessagePayload::build_key_update_request()),
+        }
+    }
 }
 
 /// Parses a plaintext message into a well-typed [`Message`].
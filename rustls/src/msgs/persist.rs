@@ -81,6 +81,14 @@ impl ClientSessionValue {
         }
     }
 
+    pub fn suite(&self) -> CipherSuite {
+        match self {
+            Self::Tls13(inner) => inner.suite().common.suite,
+            #[cfg(feature = "tls12")]
+            Self::Tls12(inner) => inner.suite().common.suite,
+        }
+    }
+
     fn common(&self) -> &ClientSessionCommon {
         match self {
             Self::Tls13(inner) => &inner.common,
@@ -138,12 +146,9 @@ impl Retrieved<ClientSessionValue> {
     }
 
     pub fn has_expired(&self) -> bool {
-        let common = self.value.common();
-        common.lifetime_secs != 0
-            && common
-                .epoch
-                .saturating_add(u64::from(common.lifetime_secs))
-                < self.retrieved_at.as_secs()
+        self.value
+            .common()
+            .has_expired(self.retrieved_at)
     }
 }
 
@@ -155,12 +160,17 @@ impl<T> std::ops::Deref for Retrieved<T> {
     }
 }
 
-#[derive(Debug)]
+/// A TLS1.3 ticket, along with what is needed to resume the session it
+/// was issued for.  Each ticket should only be used once.
+#[derive(Clone, Debug)]
 pub struct Tls13ClientSessionValue {
     suite: &'static Tls13CipherSuite,
     age_add: u32,
     max_early_data_size: u32,
     pub common: ClientSessionCommon,
+    /// Always encoded, so the encoding does not depend on the `quic`
+    /// feature.  Empty unless this is a QUIC connection's ticket.
+    quic_params: PayloadU16,
 }
 
 impl Tls13ClientSessionValue {
@@ -185,6 +195,7 @@ impl Tls13ClientSessionValue {
                 lifetime_secs,
                 server_cert_chain,
             ),
+            quic_params: PayloadU16(Vec::new()),
         }
     }
 
@@ -198,6 +209,7 @@ impl Tls13ClientSessionValue {
             age_add: u32::read(r)?,
             max_early_data_size: u32::read(r)?,
             common: ClientSessionCommon::read(r)?,
+            quic_params: PayloadU16::read(r)?,
         })
     }
 
@@ -214,6 +226,7 @@ impl Tls13ClientSessionValue {
         self.max_early_data_size
             .encode(&mut bytes);
         self.common.encode(&mut bytes);
+        self.quic_params.encode(&mut bytes);
         bytes
     }
 
@@ -221,6 +234,18 @@ impl Tls13ClientSessionValue {
         self.max_early_data_size
     }
 
+    /// The QUIC transport parameters the server sent in the connection
+    /// this ticket was issued on.
+    #[cfg(feature = "quic")]
+    pub fn quic_params(&self) -> Vec<u8> {
+        self.quic_params.0.clone()
+    }
+
+    #[cfg(feature = "quic")]
+    pub fn set_quic_params(&mut self, quic_params: &[u8]) {
+        self.quic_params = PayloadU16(quic_params.to_vec());
+    }
+
     pub fn suite(&self) -> &'static Tls13CipherSuite {
         self.suite
     }
@@ -234,8 +259,10 @@ impl std::ops::Deref for Tls13ClientSessionValue {
     }
 }
 
+/// A TLS1.2 session, identified by a session ID or ticket.  Unlike TLS1.3
+/// tickets, these can be resumed more than once.
 #[cfg(feature = "tls12")]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Tls12ClientSessionValue {
     suite: &'static Tls12CipherSuite,
     pub session_id: SessionID,
@@ -319,7 +346,7 @@ impl std::ops::Deref for Tls12ClientSessionValue {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ClientSessionCommon {
     ticket: PayloadU16,
     secret: PayloadU8,
@@ -381,6 +408,16 @@ impl ClientSessionCommon {
         self.ticket.0.as_ref()
     }
 
+    /// Whether the lifetime the server gave this session has passed at
+    /// `time_now`.  A lifetime of zero means the server did not give one.
+    pub fn has_expired(&self, time_now: TimeBase) -> bool {
+        self.lifetime_secs != 0
+            && self
+                .epoch
+                .saturating_add(u64::from(self.lifetime_secs))
+                < time_now.as_secs()
+    }
+
     /// Test only: wind back epoch by delta seconds.
     pub fn rewind_epoch(&mut self, delta: u32) {
         self.epoch -= delta as u64;
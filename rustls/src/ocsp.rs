@@ -0,0 +1,421 @@
+// Verification of stapled OCSP responses (RFC6960).
+
+use crate::x509::{self, Issuer, ParsedCert};
+
+use ring::digest;
+use ring::io::der;
+
+use std::fmt;
+
+/// OID of `id-pkix-ocsp-basic`.
+const OID_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+/// OID of `id-kp-OCSPSigning`.
+const OID_OCSP_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09];
+
+/// OID of the OCSP `SignedCertificateTimestampList` extension (RFC6962).
+const OID_SCT_LIST: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x05];
+
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+/// `CertStatus` tags.
+const STATUS_GOOD: u8 = 0x80;
+const STATUS_REVOKED: u8 = 0xa1;
+const STATUS_UNKNOWN: u8 = 0x82;
+
+/// Ways in which an OCSP response can fail to vouch for a certificate.
+#[derive(Debug, PartialEq)]
+pub(crate) enum OcspError {
+    /// The response could not be parsed.
+    Malformed,
+    /// The responder did not return a response (`responseStatus` was
+    /// not `successful`).
+    Unsuccessful,
+    /// The response was not signed by the issuer, or by a responder
+    /// authorized by the issuer.
+    BadSignature,
+    /// The response does not cover the certificate.
+    NoMatchingResponse,
+    /// The response's `thisUpdate` is in the future.
+    NotYetValid,
+    /// The response's `nextUpdate` has passed.
+    Expired,
+    /// The responder does not know about the certificate.
+    UnknownStatus,
+    /// The certificate is revoked.
+    Revoked,
+    /// The issuer of the certificate could not be found, so the response
+    /// cannot be verified.
+    UnknownIssuer,
+}
+
+impl fmt::Display for OcspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::Malformed => "malformed OCSP response",
+            Self::Unsuccessful => "unsuccessful OCSP response",
+            Self::BadSignature => "OCSP response has invalid signature",
+            Self::NoMatchingResponse => "OCSP response does not cover certificate",
+            Self::NotYetValid => "OCSP response is not yet valid",
+            Self::Expired => "OCSP response is expired",
+            Self::UnknownStatus => "OCSP responder does not know certificate",
+            Self::Revoked => "certificate revoked",
+            Self::UnknownIssuer => "issuer of certificate not found",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Verify the DER-encoded `OCSPResponse` `response` for `end_entity`,
+/// issued by `issuer`, at `now` (in seconds since the UNIX epoch).
+///
+/// Returns `Ok(())` only if the response is authentic, current, covers
+/// `end_entity` and says it is good.
+pub(crate) fn verify_response(
+    response: &[u8],
+    end_entity: &ParsedCert,
+    issuer: &Issuer,
+    now: u64,
+) -> Result<(), OcspError> {
+    let basic = parse_response(response)?;
+
+    if !signed_by_issuer(&basic, issuer, now) {
+        return Err(OcspError::BadSignature);
+    }
+
+    let single = find_single_response(basic.tbs, end_entity, issuer)?;
+    let (status_tag, _, rest) = x509::read_any_der(single).ok_or(OcspError::Malformed)?;
+    let (this_update, rest) = read_generalized_time(rest)?;
+    let next_update = match x509::read_der(rest, der::Tag::ContextSpecificConstructed0) {
+        Some((next_update, _)) => Some(read_generalized_time(next_update)?.0),
+        None => None,
+    };
+
+    if this_update > now {
+        return Err(OcspError::NotYetValid);
+    }
+    if next_update.map_or(false, |next_update| next_update <= now) {
+        return Err(OcspError::Expired);
+    }
+
+    match status_tag {
+        STATUS_GOOD => Ok(()),
+        STATUS_REVOKED => Err(OcspError::Revoked),
+        STATUS_UNKNOWN => Err(OcspError::UnknownStatus),
+        _ => Err(OcspError::Malformed),
+    }
+}
+
+/// Return the contents of the `SignedCertificateTimestampList` extension
+/// (RFC6962 section 3.3) in the `SingleResponse` for `end_entity` in the
+/// DER-encoded `OCSPResponse` `response`.
+///
+/// The response must be authentic, but unlike `verify_response` it need
+/// not be current, nor say the certificate is good.
+pub(crate) fn find_scts<'a>(
+    response: &'a [u8],
+    end_entity: &ParsedCert,
+    issuer: &Issuer,
+    now: u64,
+) -> Option<&'a [u8]> {
+    let basic = parse_response(response).ok()?;
+    if !signed_by_issuer(&basic, issuer, now) {
+        return None;
+    }
+
+    // Skip certStatus, thisUpdate and nextUpdate.
+    let single = find_single_response(basic.tbs, end_entity, issuer).ok()?;
+    let (_, _, rest) = x509::read_any_der(single)?;
+    let (_, _, rest) = x509::read_any_der(rest)?;
+    let rest = match x509::read_der(rest, der::Tag::ContextSpecificConstructed0) {
+        Some((_, rest)) => rest,
+        None => rest,
+    };
+
+    let (extensions, _) = x509::read_der(rest, der::Tag::ContextSpecificConstructed1)?;
+    let (extensions, _) = x509::read_der(extensions, der::Tag::Sequence)?;
+    match x509::find_extension(extensions, OID_SCT_LIST)
+        .and_then(|value| x509::read_der(value, der::Tag::OctetString))
+    {
+        Some((scts, [])) => Some(scts),
+        _ => None,
+    }
+}
+
+/// The parts of a `BasicOCSPResponse`.
+struct BasicResponse<'a> {
+    /// The complete `tbsResponseData`, which is the signed data.
+    tbs: &'a [u8],
+    signature_alg: &'a [u8],
+    signature: &'a [u8],
+    /// The contents of the `certs` SEQUENCE, or empty.
+    certs: &'a [u8],
+}
+
+fn parse_response(response: &[u8]) -> Result<BasicResponse<'_>, OcspError> {
+    let (response, _) = x509::read_der(response, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+    let rest = match x509::read_any_der(response) {
+        Some((ENUMERATED, [0], rest)) => rest,
+        Some((ENUMERATED, _, _)) => return Err(OcspError::Unsuccessful),
+        _ => return Err(OcspError::Malformed),
+    };
+
+    let (response_bytes, _) = x509::read_der(rest, der::Tag::ContextSpecificConstructed0)
+        .and_then(|(bytes, _)| x509::read_der(bytes, der::Tag::Sequence))
+        .ok_or(OcspError::Malformed)?;
+    let (response_type, rest) =
+        x509::read_der(response_bytes, der::Tag::OID).ok_or(OcspError::Malformed)?;
+    if response_type != OID_OCSP_BASIC {
+        return Err(OcspError::Malformed);
+    }
+    let (basic, _) = x509::read_der(rest, der::Tag::OctetString)
+        .and_then(|(basic, _)| x509::read_der(basic, der::Tag::Sequence))
+        .ok_or(OcspError::Malformed)?;
+
+    let (tbs, rest) =
+        x509::read_der_element(basic, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+    let (signature_alg, rest) =
+        x509::read_der(rest, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+    let (signature, rest) = match x509::read_der(rest, der::Tag::BitString) {
+        Some(([0, signature @ ..], rest)) => (signature, rest),
+        _ => return Err(OcspError::Malformed),
+    };
+    let certs = match x509::read_der(rest, der::Tag::ContextSpecificConstructed0) {
+        Some((certs, _)) => {
+            x509::read_der(certs, der::Tag::Sequence)
+                .ok_or(OcspError::Malformed)?
+                .0
+        }
+        None => &[],
+    };
+
+    Ok(BasicResponse {
+        tbs,
+        signature_alg,
+        signature,
+        certs,
+    })
+}
+
+/// Return true if `basic` is signed by `issuer`, or by a responder
+/// certificate included in `basic` that `issuer` authorized for OCSP
+/// signing (RFC6960 section 4.2.2.2).
+fn signed_by_issuer(basic: &BasicResponse, issuer: &Issuer, now: u64) -> bool {
+    if x509::verify_signature(issuer.spki, basic.signature_alg, basic.tbs, basic.signature) {
+        return true;
+    }
+
+    let mut certs = basic.certs;
+    while let Some((cert, rest)) = x509::read_der_element(certs, der::Tag::Sequence) {
+        certs = rest;
+
+        let responder = match ParsedCert::parse(cert) {
+            Some(responder) => responder,
+            None => continue,
+        };
+
+        let authorized = responder.has_extended_key_usage(OID_OCSP_SIGNING)
+            && responder
+                .not_before()
+                .map_or(false, |not_before| not_before <= now)
+            && responder
+                .not_after()
+                .map_or(false, |not_after| now <= not_after)
+            && responder.is_issued_by(issuer);
+
+        if authorized
+            && x509::verify_signature(
+                responder.spki,
+                basic.signature_alg,
+                basic.tbs,
+                basic.signature,
+            )
+        {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Find the `SingleResponse` in the `tbsResponseData` `tbs` whose `CertID`
+/// identifies `end_entity`, and return what follows its `CertID`.
+fn find_single_response<'a>(
+    tbs: &'a [u8],
+    end_entity: &ParsedCert,
+    issuer: &Issuer,
+) -> Result<&'a [u8], OcspError> {
+    let (tbs, _) = x509::read_der(tbs, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+    // version is optional.
+    let tbs = match x509::read_der(tbs, der::Tag::ContextSpecificConstructed0) {
+        Some((_, rest)) => rest,
+        None => tbs,
+    };
+    // Skip responderID and producedAt.
+    let (_, _, rest) = x509::read_any_der(tbs).ok_or(OcspError::Malformed)?;
+    let (_, _, rest) = x509::read_any_der(rest).ok_or(OcspError::Malformed)?;
+    let (mut responses, _) =
+        x509::read_der(rest, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+
+    let mut issuer_name = issuer.subject.to_vec();
+    x509::wrap_in_sequence(&mut issuer_name);
+    let mut issuer_spki = issuer.spki.to_vec();
+    x509::wrap_in_sequence(&mut issuer_spki);
+    let (_, issuer_key) = x509::split_spki(&issuer_spki).ok_or(OcspError::Malformed)?;
+
+    while !responses.is_empty() {
+        let (single, rest) =
+            x509::read_der(responses, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+        responses = rest;
+
+        let (cert_id, status) =
+            x509::read_der(single, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+        let (hash_alg, cert_id) =
+            x509::read_der(cert_id, der::Tag::Sequence).ok_or(OcspError::Malformed)?;
+        let (name_hash, cert_id) =
+            x509::read_der(cert_id, der::Tag::OctetString).ok_or(OcspError::Malformed)?;
+        let (key_hash, cert_id) =
+            x509::read_der(cert_id, der::Tag::OctetString).ok_or(OcspError::Malformed)?;
+        let (serial, _) = x509::read_der(cert_id, der::Tag::Integer).ok_or(OcspError::Malformed)?;
+
+        let hash_alg = match x509::read_der(hash_alg, der::Tag::OID) {
+            Some((oid, _)) if oid == OID_SHA1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            Some((oid, _)) if oid == OID_SHA256 => &digest::SHA256,
+            Some((oid, _)) if oid == OID_SHA384 => &digest::SHA384,
+            Some((oid, _)) if oid == OID_SHA512 => &digest::SHA512,
+            _ => continue,
+        };
+
+        if serial == end_entity.serial
+            && name_hash == digest::digest(hash_alg, &issuer_name).as_ref()
+            && key_hash == digest::digest(hash_alg, issuer_key).as_ref()
+        {
+            return Ok(status);
+        }
+    }
+
+    Err(OcspError::NoMatchingResponse)
+}
+
+/// The DER tag for `ENUMERATED`, which ring's `der::Tag` lacks.
+const ENUMERATED: u8 = 0x0a;
+
+fn read_generalized_time(input: &[u8]) -> Result<(u64, &[u8]), OcspError> {
+    match x509::read_any_der(input) {
+        Some((tag, _, _)) if tag == der::Tag::GeneralizedTime as u8 => {
+            x509::read_time(input).ok_or(OcspError::Malformed)
+        }
+        _ => Err(OcspError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// thisUpdate of the test responses, plus a day.
+    const NOW: u64 = 1_792_207_113 + 86_400;
+
+    fn check(response: &[u8], now: u64) -> Result<(), OcspError> {
+        let end_entity = ParsedCert::parse(include_bytes!("testdata/ocsp-end.der")).unwrap();
+        let issuer = ParsedCert::parse(include_bytes!("testdata/ocsp-issuer.der")).unwrap();
+        let issuer = Issuer {
+            subject: issuer.subject,
+            spki: issuer.spki,
+        };
+        verify_response(response, &end_entity, &issuer, now)
+    }
+
+    #[test]
+    fn accepts_good_response() {
+        assert_eq!(check(include_bytes!("testdata/ocsp-good.der"), NOW), Ok(()));
+        assert_eq!(
+            check(include_bytes!("testdata/ocsp-sha256.der"), NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn accepts_authorized_responder() {
+        assert_eq!(
+            check(include_bytes!("testdata/ocsp-responder.der"), NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_unauthorized_responder() {
+        assert_eq!(
+            check(include_bytes!("testdata/ocsp-unauthorized.der"), NOW),
+            Err(OcspError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn reports_revoked() {
+        assert_eq!(
+            check(include_bytes!("testdata/ocsp-revoked.der"), NOW),
+            Err(OcspError::Revoked)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_response() {
+        let mut response = include_bytes!("testdata/ocsp-good.der").to_vec();
+        let last = response.len() - 1;
+        response[last] ^= 1;
+        assert_eq!(check(&response, NOW), Err(OcspError::BadSignature));
+    }
+
+    #[test]
+    fn checks_freshness() {
+        let response = include_bytes!("testdata/ocsp-good.der");
+        assert_eq!(
+            check(response, NOW - 2 * 86_400),
+            Err(OcspError::NotYetValid)
+        );
+        assert_eq!(check(response, NOW + 7 * 86_400), Err(OcspError::Expired));
+    }
+
+    #[test]
+    fn rejects_response_for_other_cert() {
+        // The issuer certificate has a different serial number.
+        let other = ParsedCert::parse(include_bytes!("testdata/ocsp-issuer.der")).unwrap();
+        let issuer = Issuer {
+            subject: other.subject,
+            spki: other.spki,
+        };
+        assert_eq!(
+            verify_response(
+                include_bytes!("testdata/ocsp-good.der"),
+                &other,
+                &issuer,
+                NOW
+            ),
+            Err(OcspError::NoMatchingResponse)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_response() {
+        assert_eq!(check(&[], NOW), Err(OcspError::Malformed));
+        assert_eq!(
+            check(&[0x30, 0x03, 0x0a, 0x01, 0x00], NOW),
+            Err(OcspError::Malformed)
+        );
+        // responseStatus tryLater, with no responseBytes.
+        assert_eq!(
+            check(&[0x30, 0x03, 0x0a, 0x01, 0x03], NOW),
+            Err(OcspError::Unsuccessful)
+        );
+
+        let response = include_bytes!("testdata/ocsp-good.der");
+        assert_eq!(
+            check(&response[..response.len() - 10], NOW),
+            Err(OcspError::Malformed)
+        );
+    }
+}
@@ -1,24 +1,25 @@
 /// This module contains optional APIs for implementing QUIC TLS.
-use crate::cipher::{Iv, IvLen};
+use crate::cipher::Iv;
 pub use crate::client::ClientQuicExt;
 use crate::conn::{CommonState, Side};
+use crate::crypto::aead::{self, NONCE_LEN};
+use crate::crypto::hkdf::Prk;
+use crate::crypto::CryptoProvider;
+use crate::enums::CipherSuite;
 use crate::error::Error;
 use crate::msgs::enums::AlertDescription;
 pub use crate::server::ServerQuicExt;
-use crate::suites::BulkAlgorithm;
-use crate::tls13::key_schedule::hkdf_expand;
+use crate::tls13::key_schedule::{hkdf_expand, hkdf_expand_prk};
 use crate::tls13::{Tls13CipherSuite, TLS13_AES_128_GCM_SHA256_INTERNAL};
 use std::fmt::Debug;
 
-use ring::{aead, hkdf};
-
 /// Secrets used to encrypt/decrypt traffic
 #[derive(Clone, Debug)]
 pub struct Secrets {
     /// Secret used to encrypt packets transmitted by the client
-    client: hkdf::Prk,
+    client: Prk,
     /// Secret used to encrypt packets transmitted by the server
-    server: hkdf::Prk,
+    server: Prk,
     /// Cipher suite used with these secrets
     suite: &'static Tls13CipherSuite,
     side: Side,
@@ -26,8 +27,8 @@ pub struct Secrets {
 
 impl Secrets {
     pub(crate) fn new(
-        client: hkdf::Prk,
-        server: hkdf::Prk,
+        client: Prk,
+        server: Prk,
         suite: &'static Tls13CipherSuite,
         side: Side,
     ) -> Self {
@@ -47,12 +48,11 @@ impl Secrets {
     }
 
     fn update(&mut self) {
-        let hkdf_alg = self.suite.hkdf_algorithm;
-        self.client = hkdf_expand(&self.client, hkdf_alg, b"quic ku", &[]);
-        self.server = hkdf_expand(&self.server, hkdf_alg, b"quic ku", &[]);
+        self.client = hkdf_expand_prk(&self.client, b"quic ku", &[]);
+        self.server = hkdf_expand_prk(&self.server, b"quic ku", &[]);
     }
 
-    fn local_remote(&self) -> (&hkdf::Prk, &hkdf::Prk) {
+    fn local_remote(&self) -> (&Prk, &Prk) {
         match self.side {
             Side::Client => (&self.client, &self.server),
             Side::Server => (&self.server, &self.client),
@@ -99,7 +99,7 @@ pub struct DirectionalKeys {
 }
 
 impl DirectionalKeys {
-    pub(crate) fn new(suite: &'static Tls13CipherSuite, secret: &hkdf::Prk) -> Self {
+    pub(crate) fn new(suite: &'static Tls13CipherSuite, secret: &Prk) -> Self {
         Self {
             header: HeaderProtectionKey::new(suite, secret),
             packet: PacketKey::new(suite, secret),
@@ -108,17 +108,17 @@ impl DirectionalKeys {
 }
 
 /// A QUIC header protection key
-pub struct HeaderProtectionKey(aead::quic::HeaderProtectionKey);
+pub struct HeaderProtectionKey(Box<dyn aead::HeaderProtectionKey>);
 
 impl HeaderProtectionKey {
-    fn new(suite: &'static Tls13CipherSuite, secret: &hkdf::Prk) -> Self {
-        let alg = match suite.common.bulk {
-            BulkAlgorithm::Aes128Gcm => &aead::quic::AES_128,
-            BulkAlgorithm::Aes256Gcm => &aead::quic::AES_256,
-            BulkAlgorithm::Chacha20Poly1305 => &aead::quic::CHACHA20,
-        };
-
-        Self(hkdf_expand(secret, alg, b"quic hp", &[]))
+    fn new(suite: &'static Tls13CipherSuite, secret: &Prk) -> Self {
+        let alg = suite.common.aead_algorithm;
+        let mut key = vec![0u8; alg.key_len()];
+        hkdf_expand(secret, b"quic hp", &[], &mut key);
+        Self(
+            alg.new_header_protection_key(&key)
+                .unwrap(),
+        )
     }
 
     /// Adds QUIC Header Protection.
@@ -191,10 +191,7 @@ impl HeaderProtectionKey {
     ) -> Result<(), Error> {
         // This implements [Header Protection Application] almost verbatim.
 
-        let mask = self
-            .0
-            .new_mask(sample)
-            .map_err(|_| Error::General("sample of invalid length".into()))?;
+        let mask = self.0.new_mask(sample)?;
 
         // The `unwrap()` will not panic because `new_mask` returns a
         // non-empty result.
@@ -238,14 +235,14 @@ impl HeaderProtectionKey {
     /// Expected sample length for the key's algorithm
     #[inline]
     pub fn sample_len(&self) -> usize {
-        self.0.algorithm().sample_len()
+        self.0.sample_len()
     }
 }
 
 /// Keys to encrypt or decrypt the payload of a packet
 pub struct PacketKey {
     /// Encrypts or decrypts a packet's payload
-    key: aead::LessSafeKey,
+    key: Box<dyn aead::Key>,
     /// Computes unique nonces for each packet
     iv: Iv,
     /// The cipher suite used for this packet key
@@ -253,15 +250,15 @@ pub struct PacketKey {
 }
 
 impl PacketKey {
-    fn new(suite: &'static Tls13CipherSuite, secret: &hkdf::Prk) -> Self {
+    fn new(suite: &'static Tls13CipherSuite, secret: &Prk) -> Self {
+        let alg = suite.common.aead_algorithm;
+        let mut key = vec![0u8; alg.key_len()];
+        hkdf_expand(secret, b"quic key", &[], &mut key);
+        let mut iv = Iv::default();
+        hkdf_expand(secret, b"quic iv", &[], &mut iv.0);
         Self {
-            key: aead::LessSafeKey::new(hkdf_expand(
-                secret,
-                suite.common.aead_algorithm,
-                b"quic key",
-                &[],
-            )),
-            iv: hkdf_expand(secret, IvLen, b"quic iv", &[]),
+            key: alg.new_key(&key).unwrap(),
+            iv,
             suite,
         }
     }
@@ -279,11 +276,10 @@ impl PacketKey {
         header: &[u8],
         payload: &mut [u8],
     ) -> Result<Tag, Error> {
-        let aad = aead::Aad::from(header);
         let nonce = nonce_for(packet_number, &self.iv);
         let tag = self
             .key
-            .seal_in_place_separate_tag(nonce, aad, payload)
+            .seal_in_place_separate_tag(nonce, header, payload)
             .map_err(|_| Error::EncryptError)?;
         Ok(Tag(tag))
     }
@@ -301,14 +297,11 @@ impl PacketKey {
         header: &[u8],
         payload: &'a mut [u8],
     ) -> Result<&'a [u8], Error> {
-        let payload_len = payload.len();
-        let aad = aead::Aad::from(header);
         let nonce = nonce_for(packet_number, &self.iv);
-        self.key
-            .open_in_place(nonce, aad, payload)
+        let plain_len = self
+            .key
+            .open_in_place(nonce, header, payload)
             .map_err(|_| Error::DecryptError)?;
-
-        let plain_len = payload_len - self.key.algorithm().tag_len();
         Ok(&payload[..plain_len])
     }
 
@@ -331,7 +324,10 @@ impl PacketKey {
     /// Tag length for the underlying AEAD algorithm
     #[inline]
     pub fn tag_len(&self) -> usize {
-        self.key.algorithm().tag_len()
+        self.suite
+            .common
+            .aead_algorithm
+            .tag_len()
     }
 }
 
@@ -374,15 +370,55 @@ pub struct Keys {
 impl Keys {
     /// Construct keys for use with initial packets
     pub fn initial(version: Version, client_dst_connection_id: &[u8], side: Side) -> Self {
+        Self::initial_with_suite(
+            version,
+            TLS13_AES_128_GCM_SHA256_INTERNAL,
+            client_dst_connection_id,
+            side,
+        )
+    }
+
+    /// Like [`Keys::initial`], but using `provider`'s implementation of
+    /// TLS13_AES_128_GCM_SHA256, which initial packets always use.
+    ///
+    /// This fails if `provider` does not offer that cipher suite.
+    pub fn initial_with_provider(
+        version: Version,
+        provider: &dyn CryptoProvider,
+        client_dst_connection_id: &[u8],
+        side: Side,
+    ) -> Result<Self, Error> {
+        let suite = provider
+            .default_cipher_suites()
+            .iter()
+            .find(|scs| scs.suite() == CipherSuite::TLS13_AES_128_GCM_SHA256)
+            .and_then(|scs| scs.tls13())
+            .ok_or_else(|| {
+                Error::General("QUIC initial keys need TLS13_AES_128_GCM_SHA256".into())
+            })?;
+        Ok(Self::initial_with_suite(
+            version,
+            suite,
+            client_dst_connection_id,
+            side,
+        ))
+    }
+
+    fn initial_with_suite(
+        version: Version,
+        suite: &'static Tls13CipherSuite,
+        client_dst_connection_id: &[u8],
+        side: Side,
+    ) -> Self {
         const CLIENT_LABEL: &[u8] = b"client in";
         const SERVER_LABEL: &[u8] = b"server in";
         let salt = version.initial_salt();
-        let hs_secret = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(client_dst_connection_id);
+        let hs_secret = Prk::extract(suite.hmac, salt, client_dst_connection_id);
 
         let secrets = Secrets {
-            client: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, CLIENT_LABEL, &[]),
-            server: hkdf_expand(&hs_secret, hkdf::HKDF_SHA256, SERVER_LABEL, &[]),
-            suite: TLS13_AES_128_GCM_SHA256_INTERNAL,
+            client: hkdf_expand_prk(&hs_secret, CLIENT_LABEL, &[]),
+            server: hkdf_expand_prk(&hs_secret, SERVER_LABEL, &[]),
+            suite,
             side,
         };
         Self::new(&secrets)
@@ -459,13 +495,13 @@ pub enum KeyChange {
 }
 
 /// Compute the nonce to use for encrypting or decrypting `packet_number`
-fn nonce_for(packet_number: u64, iv: &Iv) -> ring::aead::Nonce {
-    let mut out = [0; aead::NONCE_LEN];
+fn nonce_for(packet_number: u64, iv: &Iv) -> [u8; NONCE_LEN] {
+    let mut out = [0; NONCE_LEN];
     out[4..].copy_from_slice(&packet_number.to_be_bytes());
     for (out, inp) in out.iter_mut().zip(iv.0.iter()) {
         *out ^= inp;
     }
-    aead::Nonce::assume_unique_for_key(out)
+    out
 }
 
 /// QUIC protocol version
@@ -500,6 +536,7 @@ impl Version {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::crypto::ring;
 
     #[test]
     fn short_packet_header_protection() {
@@ -512,7 +549,7 @@ mod test {
             0x0f, 0x21, 0x63, 0x2b,
         ];
 
-        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, SECRET);
+        let secret = Prk::new_less_safe(&ring::hmac::HMAC_SHA256, SECRET);
         use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
         let hpk = HeaderProtectionKey::new(TLS13_CHACHA20_POLY1305_SHA256_INTERNAL, &secret);
         let packet = PacketKey::new(TLS13_CHACHA20_POLY1305_SHA256_INTERNAL, &secret);
@@ -554,34 +591,48 @@ mod test {
         assert_eq!(plain, &PLAIN[4..]);
     }
 
+    #[cfg(feature = "aes_ccm")]
+    #[test]
+    fn aes_ccm_header_protection_uses_aes() {
+        // RFC9001 section 5.4.3: AES-CCM suites mask headers with AES, so
+        // with the same secret they agree with AES-GCM ones.
+        let secret = Prk::new_less_safe(&ring::hmac::HMAC_SHA256, &[0x5a; 32]);
+        let ccm = crate::tls13::TLS13_AES_128_CCM_SHA256
+            .tls13()
+            .unwrap();
+        let ccm = HeaderProtectionKey::new(ccm, &secret);
+        let gcm = HeaderProtectionKey::new(TLS13_AES_128_GCM_SHA256_INTERNAL, &secret);
+        assert_eq!(ccm.sample_len(), gcm.sample_len());
+
+        let sample = [0x17; 16];
+        let (mut ccm_first, mut ccm_pn) = (0xc3, [1, 2, 3, 4]);
+        let (mut gcm_first, mut gcm_pn) = (0xc3, [1, 2, 3, 4]);
+        ccm.encrypt_in_place(&sample, &mut ccm_first, &mut ccm_pn)
+            .unwrap();
+        gcm.encrypt_in_place(&sample, &mut gcm_first, &mut gcm_pn)
+            .unwrap();
+        assert_eq!((ccm_first, ccm_pn), (gcm_first, gcm_pn));
+        assert_ne!(ccm_pn, [1, 2, 3, 4]);
+    }
+
     #[test]
     fn key_update_test_vector() {
-        fn equal_prk(x: &hkdf::Prk, y: &hkdf::Prk) -> bool {
-            let mut x_data = [0; 16];
-            let mut y_data = [0; 16];
-            let x_okm = x
-                .expand(&[b"info"], &aead::quic::AES_128)
-                .unwrap();
-            x_okm.fill(&mut x_data[..]).unwrap();
-            let y_okm = y
-                .expand(&[b"info"], &aead::quic::AES_128)
-                .unwrap();
-            y_okm.fill(&mut y_data[..]).unwrap();
-            x_data == y_data
+        fn equal_prk(x: &Prk, y: &Prk) -> bool {
+            x.as_bytes() == y.as_bytes()
         }
 
         let mut secrets = Secrets {
             // Constant dummy values for reproducibility
-            client: hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
+            client: Prk::new_less_safe(
+                &ring::hmac::HMAC_SHA256,
                 &[
                     0xb8, 0x76, 0x77, 0x08, 0xf8, 0x77, 0x23, 0x58, 0xa6, 0xea, 0x9f, 0xc4, 0x3e,
                     0x4a, 0xdd, 0x2c, 0x96, 0x1b, 0x3f, 0x52, 0x87, 0xa6, 0xd1, 0x46, 0x7e, 0xe0,
                     0xae, 0xab, 0x33, 0x72, 0x4d, 0xbf,
                 ],
             ),
-            server: hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
+            server: Prk::new_less_safe(
+                &ring::hmac::HMAC_SHA256,
                 &[
                     0x42, 0xdc, 0x97, 0x21, 0x40, 0xe0, 0xf2, 0xe3, 0x98, 0x45, 0xb7, 0x67, 0x61,
                     0x34, 0x39, 0xdc, 0x67, 0x58, 0xca, 0x43, 0x25, 0x9b, 0x87, 0x85, 0x06, 0x82,
@@ -595,8 +646,8 @@ mod test {
 
         assert!(equal_prk(
             &secrets.client,
-            &hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
+            &Prk::new_less_safe(
+                &ring::hmac::HMAC_SHA256,
                 &[
                     0x42, 0xca, 0xc8, 0xc9, 0x1c, 0xd5, 0xeb, 0x40, 0x68, 0x2e, 0x43, 0x2e, 0xdf,
                     0x2d, 0x2b, 0xe9, 0xf4, 0x1a, 0x52, 0xca, 0x6b, 0x22, 0xd8, 0xe6, 0xcd, 0xb1,
@@ -606,8 +657,8 @@ mod test {
         ));
         assert!(equal_prk(
             &secrets.server,
-            &hkdf::Prk::new_less_safe(
-                hkdf::HKDF_SHA256,
+            &Prk::new_less_safe(
+                &ring::hmac::HMAC_SHA256,
                 &[
                     0xeb, 0x7f, 0x5e, 0x2a, 0x12, 0x3f, 0x40, 0x7d, 0xb4, 0x99, 0xe3, 0x61, 0xca,
                     0xe5, 0x90, 0xd4, 0xd9, 0x92, 0xe1, 0x4b, 0x7a, 0xce, 0x3, 0xc2, 0x44, 0xe0,
@@ -29,6 +29,10 @@ pub struct RecordLayer {
     encrypt_state: DirectionState,
     decrypt_state: DirectionState,
 
+    // How many messages we are willing to encrypt with one key,
+    // before asking for it to be updated.
+    confidentiality_limit: u64,
+
     // Message encrypted with other keys may be encountered, so failures
     // should be swallowed by the caller.  This struct tracks the amount
     // of message size this is allowed for.
@@ -45,6 +49,7 @@ impl RecordLayer {
             read_seq: 0,
             encrypt_state: DirectionState::Invalid,
             decrypt_state: DirectionState::Invalid,
+            confidentiality_limit: u64::MAX,
             trial_decryption_len: None,
         }
     }
@@ -53,6 +58,10 @@ impl RecordLayer {
         self.encrypt_state == DirectionState::Active
     }
 
+    pub(crate) fn is_decrypting(&self) -> bool {
+        self.decrypt_state == DirectionState::Active
+    }
+
     #[cfg(feature = "secret_extraction")]
     pub(crate) fn write_seq(&self) -> u64 {
         self.write_seq
@@ -144,6 +153,24 @@ impl RecordLayer {
         self.write_seq == SEQ_SOFT_LIMIT
     }
 
+    /// Limit how many messages are encrypted with one key, for
+    /// [`RecordLayer::wants_key_update`].
+    pub(crate) fn set_confidentiality_limit(&mut self, limit: u64) {
+        self.confidentiality_limit = limit;
+    }
+
+    /// Return true if we have encrypted enough messages with our
+    /// encryption key that it should be updated.
+    ///
+    /// This leaves room for the `KeyUpdate` message, which is the last
+    /// one encrypted with the old key.
+    pub(crate) fn wants_key_update(&self) -> bool {
+        self.write_seq
+            >= self
+                .confidentiality_limit
+                .saturating_sub(1)
+    }
+
     /// Return true if we outright refuse to do anything with the
     /// encryption key.
     pub(crate) fn encrypt_exhausted(&self) -> bool {
@@ -219,3 +246,44 @@ pub struct Decrypted {
     /// The decrypted message.
     pub plaintext: PlainMessage,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enums::ProtocolVersion;
+    use crate::msgs::enums::ContentType;
+
+    struct PassThroughEncrypter;
+
+    impl MessageEncrypter for PassThroughEncrypter {
+        fn encrypt(&self, m: BorrowedPlainMessage, _seq: u64) -> Result<OpaqueMessage, Error> {
+            Ok(m.to_unencrypted_opaque())
+        }
+    }
+
+    fn encrypt_one(record_layer: &mut RecordLayer) {
+        record_layer.encrypt_outgoing(BorrowedPlainMessage {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_3,
+            payload: b"hello",
+        });
+    }
+
+    #[test]
+    fn wants_key_update_at_confidentiality_limit() {
+        let mut record_layer = RecordLayer::new();
+        record_layer.set_message_encrypter(Box::new(PassThroughEncrypter));
+        assert!(!record_layer.wants_key_update());
+
+        // The third message under this key must be the KeyUpdate.
+        record_layer.set_confidentiality_limit(3);
+        encrypt_one(&mut record_layer);
+        assert!(!record_layer.wants_key_update());
+        encrypt_one(&mut record_layer);
+        assert!(record_layer.wants_key_update());
+
+        // A new key starts counting again.
+        record_layer.set_message_encrypter(Box::new(PassThroughEncrypter));
+        assert!(!record_layer.wants_key_update());
+    }
+}
@@ -1,7 +1,9 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::compress;
+use crate::crypto::{CryptoProvider, SupportedKxGroup};
 use crate::error::Error;
 use crate::key;
-use crate::kx::SupportedKxGroup;
+use crate::msgs::enums::PSKKeyExchangeMode;
 use crate::server::handy;
 use crate::server::{ResolvesServerCert, ServerConfig};
 use crate::suites::SupportedCipherSuite;
@@ -20,6 +22,7 @@ impl ConfigBuilder<ServerConfig, WantsVerifier> {
     ) -> ConfigBuilder<ServerConfig, WantsServerCert> {
         ConfigBuilder {
             state: WantsServerCert {
+                provider: self.state.provider,
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
@@ -41,8 +44,9 @@ impl ConfigBuilder<ServerConfig, WantsVerifier> {
 /// For more information, see the [`ConfigBuilder`] documentation.
 #[derive(Clone, Debug)]
 pub struct WantsServerCert {
+    provider: &'static dyn CryptoProvider,
     cipher_suites: Vec<SupportedCipherSuite>,
-    kx_groups: Vec<&'static SupportedKxGroup>,
+    kx_groups: Vec<&'static dyn SupportedKxGroup>,
     versions: versions::EnabledVersions,
     verifier: Arc<dyn verify::ClientCertVerifier>,
 }
@@ -66,7 +70,7 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
         cert_chain: Vec<key::Certificate>,
         key_der: key::PrivateKey,
     ) -> Result<ServerConfig, Error> {
-        let resolver = handy::AlwaysResolvesChain::new(cert_chain, &key_der)?;
+        let resolver = handy::AlwaysResolvesChain::new(self.state.provider, cert_chain, &key_der)?;
         Ok(self.with_cert_resolver(Arc::new(resolver)))
     }
 
@@ -88,20 +92,43 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
         ocsp: Vec<u8>,
         scts: Vec<u8>,
     ) -> Result<ServerConfig, Error> {
+        let resolver = handy::AlwaysResolvesChain::new_with_extras(
+            self.state.provider,
+            cert_chain,
+            &key_der,
+            ocsp,
+            scts,
+        )?;
+        Ok(self.with_cert_resolver(Arc::new(resolver)))
+    }
+
+    /// Sets a private key to authenticate with as a raw public key
+    /// (RFC7250), rather than a certificate chain.  This key is used for
+    /// all subsequent connections.
+    ///
+    /// Raw public keys can only be used with TLS1.3 clients which offer
+    /// to accept them: handshakes with other clients fail.
+    ///
+    /// `key_der` is a DER-encoded RSA, ECDSA, or Ed25519 private key.
+    ///
+    /// This function fails if `key_der` is invalid.
+    pub fn with_raw_public_key(self, key_der: key::PrivateKey) -> Result<ServerConfig, Error> {
         let resolver =
-            handy::AlwaysResolvesChain::new_with_extras(cert_chain, &key_der, ocsp, scts)?;
+            handy::AlwaysResolvesChain::new_raw_public_key(self.state.provider, &key_der)?;
         Ok(self.with_cert_resolver(Arc::new(resolver)))
     }
 
     /// Sets a custom [`ResolvesServerCert`].
     pub fn with_cert_resolver(self, cert_resolver: Arc<dyn ResolvesServerCert>) -> ServerConfig {
         ServerConfig {
+            provider: self.state.provider,
             cipher_suites: self.state.cipher_suites,
             kx_groups: self.state.kx_groups,
             verifier: self.state.verifier,
             cert_resolver,
             ignore_client_order: false,
             max_fragment_size: None,
+            record_size_limit: None,
             session_storage: handy::ServerSessionMemoryCache::new(256),
             ticketer: Arc::new(handy::NeverProducesTickets {}),
             alpn_protocols: Vec::new(),
@@ -111,6 +138,17 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
             enable_secret_extraction: false,
             max_early_data_size: 0,
             send_half_rtt_data: false,
+            #[cfg(feature = "ech_server")]
+            ech_keys: Vec::new(),
+            cert_compressors: compress::default_cert_compressors().to_vec(),
+            cert_decompressors: compress::default_cert_decompressors().to_vec(),
+            client_raw_key_verifier: None,
+            psk_resolver: None,
+            psk_kx_modes: vec![PSKKeyExchangeMode::PSK_DHE_KE],
+            defer_client_auth: false,
+            defer_session_lookups: false,
+            defer_signing: false,
+            defer_cert_verification: false,
         }
     }
 }
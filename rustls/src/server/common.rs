@@ -1,3 +1,4 @@
+use crate::msgs::enums::CertificateType;
 use crate::{key, sign};
 
 /// ActiveCertifiedKey wraps CertifiedKey and tracks OSCP and SCT state
@@ -23,12 +24,28 @@ impl<'a> ActiveCertifiedKey<'a> {
         &self.key.cert
     }
 
+    /// What `get_cert` contains
+    #[inline]
+    pub(super) fn get_cert_type(&self) -> CertificateType {
+        self.key.cert_type
+    }
+
     /// Get the signing key
     #[inline]
     pub(super) fn get_key(&self) -> &dyn sign::SigningKey {
         &*self.key.key
     }
 
+    /// Get the delegated credential, if one is usable with the
+    /// certificate type.
+    #[inline]
+    pub(super) fn get_delegated_credential(&self) -> Option<&sign::DelegatedCredential> {
+        match self.key.cert_type {
+            CertificateType::X509 => self.key.delegated_credential.as_ref(),
+            _ => None,
+        }
+    }
+
     #[inline]
     pub(super) fn get_ocsp(&self) -> Option<&[u8]> {
         self.ocsp
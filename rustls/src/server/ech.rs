@@ -0,0 +1,444 @@
+use crate::crypto::CryptoProvider;
+use crate::ech::HPKE_INFO_PREFIX;
+use crate::enums::CipherSuite;
+use crate::enums::ProtocolVersion;
+use crate::error::Error;
+use crate::hpke::{self, HpkeKeyPair};
+use crate::msgs::base::{PayloadU16, PayloadU8};
+use crate::msgs::codec::{self, Codec, Reader};
+use crate::msgs::enums::{Compression, ExtensionType};
+use crate::msgs::enums::{HpkeAead, HpkeKdf, HpkeKem};
+use crate::msgs::handshake::{ClientExtension, ClientHelloPayload, EchOuterExtensions, SessionID};
+use crate::msgs::handshake::{EchConfigContents, EchConfigList, EchConfigPayload};
+use crate::msgs::handshake::{EncryptedClientHello, EncryptedClientHelloOuter};
+use crate::msgs::handshake::{HpkeKeyConfig, HpkeSymmetricCipherSuite, Random};
+
+use std::fmt;
+
+/// An Encrypted Client Hello configuration published by this server,
+/// together with the HPKE private key clients encrypt to.
+///
+/// Clients learn the configurations from the DNS HTTPS record of each
+/// name served behind the public name; see
+/// [`ServerConfig::ech_config_list()`](super::ServerConfig::ech_config_list).
+#[derive(Clone)]
+pub struct EchKeyPair {
+    key_pair: HpkeKeyPair,
+    contents: EchConfigContents,
+    encoded: Vec<u8>,
+}
+
+impl EchKeyPair {
+    /// Generate a fresh X25519 key pair and an `ECHConfig` for it,
+    /// identified by `config_id` and naming `public_name` as the
+    /// client-facing server.
+    ///
+    /// The configuration offers HKDF-SHA256 with AES-128-GCM and with
+    /// ChaCha20-Poly1305.
+    pub fn generate(config_id: u8, public_name: &str) -> Result<Self, Error> {
+        if webpki::DnsNameRef::try_from_ascii_str(public_name).is_err() {
+            return Err(Error::General("invalid ECH public name".into()));
+        }
+
+        let key_pair = HpkeKeyPair::generate()?;
+        let contents = EchConfigContents {
+            key_config: HpkeKeyConfig {
+                config_id,
+                kem_id: HpkeKem::DHKEM_X25519_HKDF_SHA256,
+                public_key: PayloadU16::new(key_pair.public.to_vec()),
+                symmetric_cipher_suites: vec![
+                    HpkeSymmetricCipherSuite {
+                        kdf_id: HpkeKdf::HKDF_SHA256,
+                        aead_id: HpkeAead::AES_128_GCM,
+                    },
+                    HpkeSymmetricCipherSuite {
+                        kdf_id: HpkeKdf::HKDF_SHA256,
+                        aead_id: HpkeAead::CHACHA20_POLY_1305,
+                    },
+                ],
+            },
+            maximum_name_length: 0,
+            public_name: PayloadU8::new(public_name.as_bytes().to_vec()),
+            extensions: PayloadU16::empty(),
+        };
+
+        Ok(Self::from_parts(key_pair, contents))
+    }
+
+    /// Load a key pair from its 32-byte X25519 `private_key` and the
+    /// TLS-encoded `ECHConfig` that publishes the matching public key.
+    pub fn new(private_key: &[u8], ech_config: &[u8]) -> Result<Self, Error> {
+        let key_pair = HpkeKeyPair::from_private_bytes(private_key)
+            .ok_or_else(|| Error::General("invalid ECH private key".into()))?;
+
+        let contents = match EchConfigPayload::read_bytes(ech_config) {
+            Some(EchConfigPayload::V18(contents)) => contents,
+            _ => return Err(Error::General("invalid ECHConfig".into())),
+        };
+
+        if contents.key_config.kem_id != HpkeKem::DHKEM_X25519_HKDF_SHA256
+            || contents.key_config.public_key.0 != key_pair.public
+        {
+            return Err(Error::General(
+                "ECHConfig does not match private key".into(),
+            ));
+        }
+
+        Ok(Self::from_parts(key_pair, contents))
+    }
+
+    fn from_parts(key_pair: HpkeKeyPair, contents: EchConfigContents) -> Self {
+        Self {
+            encoded: EchConfigPayload::V18(contents.clone()).get_encoding(),
+            key_pair,
+            contents,
+        }
+    }
+
+    /// The X25519 private key, to be stored alongside [`Self::ech_config()`].
+    pub fn private_key(&self) -> &[u8] {
+        self.key_pair.private_bytes()
+    }
+
+    /// The TLS-encoded `ECHConfig`.
+    pub fn ech_config(&self) -> &[u8] {
+        &self.encoded
+    }
+
+    /// Try to open `offer` with this key pair, using `provider`'s HPKE
+    /// cipher suite primitives.
+    fn open(
+        &self,
+        provider: &dyn CryptoProvider,
+        offer: &EncryptedClientHelloOuter,
+        aad: &[u8],
+    ) -> Option<(hpke::Context, Vec<u8>)> {
+        let key_config = &self.contents.key_config;
+        if offer.config_id != key_config.config_id
+            || !key_config
+                .symmetric_cipher_suites
+                .contains(&offer.cipher_suite)
+            || !hpke::provider_supports_suite(provider, &offer.cipher_suite)
+        {
+            return None;
+        }
+
+        let info = [HPKE_INFO_PREFIX, &self.encoded].concat();
+        let mut context = hpke::setup_base_receiver(
+            provider,
+            key_config.kem_id,
+            &offer.cipher_suite,
+            &offer.enc.0,
+            &self.key_pair,
+            &info,
+        )
+        .ok()?;
+        let encoded_inner = context
+            .open(aad, &offer.payload.0)
+            .ok()?;
+        Some((context, encoded_inner))
+    }
+}
+
+impl fmt::Debug for EchKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EchKeyPair")
+            .field("config_id", &self.contents.key_config.config_id)
+            .field(
+                "public_name",
+                &String::from_utf8_lossy(&self.contents.public_name.0),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+/// The `ECHConfigList` naming each of `keys`.
+pub(super) fn ech_config_list(keys: &[EchKeyPair]) -> EchConfigList {
+    keys.iter()
+        .map(|key| EchConfigPayload::V18(key.contents.clone()))
+        .collect()
+}
+
+/// What became of a client's offer of Encrypted Client Hello.
+pub(super) enum EchState {
+    /// We could not decrypt the offer: the handshake continues with the
+    /// outer hello, and we tell the client our current configurations.
+    Rejected,
+    /// We decrypted the inner hello, and the handshake continues with it.
+    Accepted(Box<EchAccepted>),
+}
+
+/// The HPKE context an accepted offer was opened with.  If we send a
+/// HelloRetryRequest, the second ClientHello is opened with it too.
+pub(super) struct EchAccepted {
+    context: hpke::Context,
+    config_id: u8,
+    cipher_suite: HpkeSymmetricCipherSuite,
+}
+
+impl EchAccepted {
+    /// Try to open `offer`, from the first ClientHello `outer`, with
+    /// each of `keys` in turn.  Returns the `EncodedClientHelloInner`
+    /// if one succeeds.
+    pub(super) fn open_first(
+        provider: &dyn CryptoProvider,
+        keys: &[EchKeyPair],
+        outer: &ClientHelloPayload,
+        offer: &EncryptedClientHelloOuter,
+    ) -> Option<(Self, Vec<u8>)> {
+        let aad = aad(outer, offer);
+        keys.iter().find_map(|key| {
+            let (context, encoded_inner) = key.open(provider, offer, &aad)?;
+            Some((
+                Self {
+                    context,
+                    config_id: offer.config_id,
+                    cipher_suite: offer.cipher_suite,
+                },
+                encoded_inner,
+            ))
+        })
+    }
+
+    /// Open `offer` from the second ClientHello `outer`.  This must use
+    /// the same configuration as the first, and carry no `enc`.
+    pub(super) fn open_second(
+        &mut self,
+        outer: &ClientHelloPayload,
+        offer: &EncryptedClientHelloOuter,
+    ) -> Option<Vec<u8>> {
+        if offer.config_id != self.config_id
+            || offer.cipher_suite != self.cipher_suite
+            || !offer.enc.0.is_empty()
+        {
+            return None;
+        }
+
+        self.context
+            .open(&aad(outer, offer), &offer.payload.0)
+            .ok()
+    }
+}
+
+/// The AAD is the outer hello with the ECH payload zeroed.
+fn aad(outer: &ClientHelloPayload, offer: &EncryptedClientHelloOuter) -> Vec<u8> {
+    let mut zeroed = offer.clone();
+    zeroed.payload = PayloadU16::new(vec![0u8; offer.payload.0.len()]);
+
+    let mut outer = outer.clone();
+    for ext in outer.extensions.iter_mut() {
+        if let ClientExtension::EncryptedClientHello(EncryptedClientHello::Outer(_)) = ext {
+            *ext =
+                ClientExtension::EncryptedClientHello(EncryptedClientHello::Outer(zeroed.clone()));
+        }
+    }
+    outer.get_encoding()
+}
+
+/// Decode an `EncodedClientHelloInner`, restoring the session id and
+/// any extensions the client elided by reference to `outer`.
+pub(super) fn decode_inner(
+    encoded: &[u8],
+    outer: &ClientHelloPayload,
+) -> Result<ClientHelloPayload, &'static str> {
+    let mut r = Reader::init(encoded);
+    let mut inner = read_inner(&mut r).ok_or("invalid EncodedClientHelloInner")?;
+    if r.rest().iter().any(|byte| *byte != 0) {
+        return Err("EncodedClientHelloInner has non-zero padding");
+    }
+
+    inner.session_id = outer.session_id;
+    inner.extensions = decompress_extensions(inner.extensions, outer)?;
+
+    if inner.has_duplicate_extension() {
+        return Err("ClientHelloInner has duplicate extensions");
+    }
+
+    match inner.get_ech_extension() {
+        Some(EncryptedClientHello::Inner) => {}
+        _ => return Err("ClientHelloInner lacks inner ECH extension"),
+    }
+
+    match inner.get_versions_extension() {
+        Some(versions)
+            if !versions
+                .iter()
+                .any(|v| v.get_u16() <= ProtocolVersion::TLSv1_2.get_u16()) => {}
+        _ => return Err("ClientHelloInner offers TLS1.2 or below"),
+    }
+
+    Ok(inner)
+}
+
+/// As `ClientHelloPayload::read`, but leaving any padding unread.
+fn read_inner(r: &mut Reader) -> Option<ClientHelloPayload> {
+    Some(ClientHelloPayload {
+        client_version: ProtocolVersion::read(r)?,
+        random: Random::read(r)?,
+        session_id: SessionID::read(r)?,
+        cipher_suites: codec::read_vec_u16::<CipherSuite>(r)?,
+        compression_methods: codec::read_vec_u8::<Compression>(r)?,
+        extensions: codec::read_vec_u16::<ClientExtension>(r)?,
+    })
+}
+
+/// Replace any `ech_outer_extensions` extension with the outer
+/// extensions it names, which must appear in that order in `outer`.
+fn decompress_extensions(
+    extensions: Vec<ClientExtension>,
+    outer: &ClientHelloPayload,
+) -> Result<Vec<ClientExtension>, &'static str> {
+    let mut result = Vec::with_capacity(extensions.len());
+    let mut outer_exts = outer.extensions.iter();
+
+    for ext in extensions {
+        let references = match &ext {
+            ClientExtension::Unknown(unknown)
+                if unknown.typ == ExtensionType::EncryptedClientHelloOuterExtensions =>
+            {
+                EchOuterExtensions::read_bytes(&unknown.payload.0)
+                    .ok_or("invalid ech_outer_extensions")?
+            }
+            _ => {
+                result.push(ext);
+                continue;
+            }
+        };
+
+        for typ in references {
+            if typ == ExtensionType::EncryptedClientHello {
+                return Err("ech_outer_extensions references ECH");
+            }
+
+            let found = outer_exts
+                .find(|outer_ext| outer_ext.get_type() == typ)
+                .ok_or("ech_outer_extensions references missing extension")?;
+            result.push(found.clone());
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msgs::base::Payload;
+    use crate::msgs::handshake::UnknownExtension;
+
+    fn hello(extensions: Vec<ClientExtension>) -> ClientHelloPayload {
+        ClientHelloPayload {
+            client_version: ProtocolVersion::TLSv1_2,
+            random: Random([1; 32]),
+            session_id: SessionID::empty(),
+            cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+            compression_methods: vec![Compression::Null],
+            extensions,
+        }
+    }
+
+    fn inner_extensions() -> Vec<ClientExtension> {
+        vec![
+            ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
+            ClientExtension::EncryptedClientHello(EncryptedClientHello::Inner),
+        ]
+    }
+
+    #[test]
+    fn key_pair_round_trips() {
+        let key = EchKeyPair::generate(3, "public.example").unwrap();
+        let loaded = EchKeyPair::new(key.private_key(), key.ech_config()).unwrap();
+        assert_eq!(loaded.ech_config(), key.ech_config());
+
+        let other = EchKeyPair::generate(3, "public.example").unwrap();
+        assert!(EchKeyPair::new(other.private_key(), key.ech_config()).is_err());
+        assert!(EchKeyPair::new(&[0; 31], key.ech_config()).is_err());
+        assert!(EchKeyPair::generate(3, "not a name").is_err());
+    }
+
+    #[test]
+    fn decodes_padded_inner() {
+        let mut outer = hello(vec![]);
+        outer.session_id = SessionID::random(crate::crypto::ring::RING).unwrap();
+
+        let mut encoded = hello(inner_extensions()).get_encoding();
+        encoded.extend_from_slice(&[0; 17]);
+        let inner = decode_inner(&encoded, &outer).unwrap();
+        assert_eq!(inner.session_id, outer.session_id);
+
+        *encoded.last_mut().unwrap() = 1;
+        assert!(decode_inner(&encoded, &outer).is_err());
+    }
+
+    #[test]
+    fn rejects_inner_without_marker_or_offering_tls12() {
+        let outer = hello(vec![]);
+
+        let no_marker = hello(vec![ClientExtension::SupportedVersions(vec![
+            ProtocolVersion::TLSv1_3,
+        ])]);
+        assert!(decode_inner(&no_marker.get_encoding(), &outer).is_err());
+
+        let tls12 = hello(vec![
+            ClientExtension::SupportedVersions(vec![
+                ProtocolVersion::TLSv1_3,
+                ProtocolVersion::TLSv1_2,
+            ]),
+            ClientExtension::EncryptedClientHello(EncryptedClientHello::Inner),
+        ]);
+        assert!(decode_inner(&tls12.get_encoding(), &outer).is_err());
+    }
+
+    #[test]
+    fn decompresses_outer_extensions() {
+        let outer = hello(vec![
+            ClientExtension::ExtendedMasterSecretRequest,
+            ClientExtension::EarlyData,
+            ClientExtension::SignedCertificateTimestampRequest,
+        ]);
+
+        let reference = |types: Vec<ExtensionType>| {
+            ClientExtension::Unknown(UnknownExtension {
+                typ: ExtensionType::EncryptedClientHelloOuterExtensions,
+                payload: Payload::new(types.get_encoding()),
+            })
+        };
+
+        let mut extensions = inner_extensions();
+        extensions.insert(
+            1,
+            reference(vec![
+                ExtensionType::ExtendedMasterSecret,
+                ExtensionType::SCT,
+            ]),
+        );
+        let inner = decode_inner(&hello(extensions).get_encoding(), &outer).unwrap();
+        let types = inner
+            .extensions
+            .iter()
+            .map(ClientExtension::get_type)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                ExtensionType::SupportedVersions,
+                ExtensionType::ExtendedMasterSecret,
+                ExtensionType::SCT,
+                ExtensionType::EncryptedClientHello,
+            ]
+        );
+
+        // Out of order
+        let mut extensions = inner_extensions();
+        extensions.push(reference(vec![
+            ExtensionType::SCT,
+            ExtensionType::ExtendedMasterSecret,
+        ]));
+        assert!(decode_inner(&hello(extensions).get_encoding(), &outer).is_err());
+
+        // Referencing ECH itself
+        let mut extensions = inner_extensions();
+        extensions.push(reference(vec![ExtensionType::EncryptedClientHello]));
+        assert!(decode_inner(&hello(extensions).get_encoding(), &outer).is_err());
+    }
+}
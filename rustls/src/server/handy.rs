@@ -1,3 +1,4 @@
+use crate::crypto::CryptoProvider;
 use crate::error::Error;
 use crate::key;
 use crate::limited_cache;
@@ -95,25 +96,38 @@ impl AlwaysResolvesChain {
     /// Creates an `AlwaysResolvesChain`, auto-detecting the underlying private
     /// key type and encoding.
     pub(super) fn new(
+        provider: &dyn CryptoProvider,
         chain: Vec<key::Certificate>,
         priv_key: &key::PrivateKey,
     ) -> Result<Self, Error> {
-        let key = sign::any_supported_type(priv_key)
-            .map_err(|_| Error::General("invalid private key".into()))?;
+        let key = provider.load_private_key(priv_key)?;
         Ok(Self(Arc::new(sign::CertifiedKey::new(chain, key))))
     }
 
+    /// Creates an `AlwaysResolvesChain` which authenticates with the
+    /// raw public key of `priv_key`.
+    pub(super) fn new_raw_public_key(
+        provider: &dyn CryptoProvider,
+        priv_key: &key::PrivateKey,
+    ) -> Result<Self, Error> {
+        let key = provider.load_private_key(priv_key)?;
+        let certkey = sign::CertifiedKey::from_signing_key(key)
+            .map_err(|_| Error::General("cannot get public key".into()))?;
+        Ok(Self(Arc::new(certkey)))
+    }
+
     /// Creates an `AlwaysResolvesChain`, auto-detecting the underlying private
     /// key type and encoding.
     ///
     /// If non-empty, the given OCSP response and SCTs are attached.
     pub(super) fn new_with_extras(
+        provider: &dyn CryptoProvider,
         chain: Vec<key::Certificate>,
         priv_key: &key::PrivateKey,
         ocsp: Vec<u8>,
         scts: Vec<u8>,
     ) -> Result<Self, Error> {
-        let mut r = Self::new(chain, priv_key)?;
+        let mut r = Self::new(provider, chain, priv_key)?;
 
         {
             let cert = Arc::make_mut(&mut r.0);
@@ -261,7 +275,7 @@ mod test {
     fn test_resolvesservercertusingsni_requires_sni() {
         let rscsni = ResolvesServerCertUsingSni::new();
         assert!(rscsni
-            .resolve(ClientHello::new(&None, &[], None, &[]))
+            .resolve(ClientHello::new(&None, &[], None, &[], None, None))
             .is_none());
     }
 
@@ -272,7 +286,7 @@ mod test {
             .unwrap()
             .to_owned();
         assert!(rscsni
-            .resolve(ClientHello::new(&Some(name), &[], None, &[]))
+            .resolve(ClientHello::new(&Some(name), &[], None, &[], None, None))
             .is_none());
     }
 }
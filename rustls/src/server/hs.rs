@@ -6,22 +6,25 @@ use crate::error::Error;
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::enums::HandshakeType;
 use crate::msgs::enums::{AlertDescription, Compression, ExtensionType};
+use crate::msgs::enums::{CertificateType, HandshakeType};
 #[cfg(feature = "tls12")]
 use crate::msgs::handshake::SessionID;
 use crate::msgs::handshake::{ClientHelloPayload, Random, ServerExtension};
 use crate::msgs::handshake::{ConvertProtocolNameList, ConvertServerNameList, HandshakePayload};
+#[cfg(feature = "ech_server")]
+use crate::msgs::handshake::{EncryptedClientHello, HandshakeMessagePayload};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::server::{ClientHello, ServerConfig};
 use crate::suites;
 use crate::SupportedCipherSuite;
 
+#[cfg(feature = "ech_server")]
+use super::ech::{self, EchAccepted, EchState};
 use super::server_conn::ServerConnectionData;
 #[cfg(feature = "tls12")]
 use super::tls12;
-use crate::server::common::ActiveCertifiedKey;
 use crate::server::tls13;
 
 use std::sync::Arc;
@@ -35,6 +38,20 @@ pub(super) fn incompatible(common: &mut CommonState, why: &str) -> Error {
     Error::PeerIncompatibleError(why.to_string())
 }
 
+/// Send the alert for a client certificate the verifier rejected with `err`.
+pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Error {
+    match err {
+        Error::CertificateRevoked => {
+            common.send_fatal_alert(AlertDescription::CertificateRevoked);
+        }
+        _ => {
+            incompatible(common, "certificate invalid");
+        }
+    }
+
+    err
+}
+
 fn bad_version(common: &mut CommonState, why: &str) -> Error {
     common.send_fatal_alert(AlertDescription::ProtocolVersion);
     Error::PeerIncompatibleError(why.to_string())
@@ -188,6 +205,30 @@ impl ExtensionProcessing {
             sct_list.take();
         }
 
+        // Record size limit (RFC8449), which supersedes max fragment length (RFC6066)
+        if !cx.common.is_quic() && !cx.common.is_dtls() {
+            if let Some(limit) = hello.get_record_size_limit_extension() {
+                let ours = cx
+                    .common
+                    .negotiate_record_size_limit(limit)?;
+                self.exts
+                    .push(ServerExtension::RecordSizeLimit(ours));
+            } else if let Some(len) = hello.get_max_fragment_length_extension() {
+                let max_fragment_len = match len.fragment_len() {
+                    Some(max_fragment_len) => max_fragment_len,
+                    None => {
+                        return Err(cx
+                            .common
+                            .illegal_param("client sent unknown max_fragment_length"));
+                    }
+                };
+                cx.common
+                    .apply_max_fragment_length(max_fragment_len);
+                self.exts
+                    .push(ServerExtension::MaxFragmentLength(len));
+            }
+        }
+
         self.exts.extend(extra_exts);
 
         Ok(())
@@ -245,13 +286,15 @@ pub(super) struct ExpectClientHello {
     pub(super) using_ems: bool,
     pub(super) done_retry: bool,
     pub(super) send_ticket: bool,
+    #[cfg(feature = "ech_server")]
+    pub(super) ech: Option<EchState>,
 }
 
 impl ExpectClientHello {
     pub(super) fn new(config: Arc<ServerConfig>, extra_exts: Vec<ServerExtension>) -> Self {
         let mut transcript_buffer = HandshakeHashBuffer::new();
 
-        if config.verifier.offer_client_auth() {
+        if config.offer_client_auth_in_handshake() {
             transcript_buffer.set_client_auth_enabled();
         }
 
@@ -265,9 +308,97 @@ impl ExpectClientHello {
             using_ems: false,
             done_retry: false,
             send_ticket: false,
+            #[cfg(feature = "ech_server")]
+            ech: None,
         }
     }
 
+    /// If the client offered Encrypted Client Hello, try to decrypt the
+    /// inner `ClientHello` carried by `m`.  If we can, it is returned and
+    /// the handshake continues with it instead of `m`.
+    #[cfg(feature = "ech_server")]
+    pub(super) fn open_ech(
+        &mut self,
+        m: &Message,
+        cx: &mut ServerContext<'_>,
+    ) -> Result<Option<Message>, Error> {
+        let outer =
+            require_handshake_msg!(m, HandshakeType::ClientHello, HandshakePayload::ClientHello)?;
+        let offer = match outer.get_ech_extension() {
+            Some(EncryptedClientHello::Outer(offer)) => Some(offer),
+            _ => None,
+        };
+
+        let encoded_inner = match (&mut self.ech, offer) {
+            (None, Some(offer)) if !self.done_retry => {
+                if self.config.ech_keys.is_empty()
+                    || !self
+                        .config
+                        .supports_version(ProtocolVersion::TLSv1_3)
+                {
+                    return Ok(None);
+                }
+
+                match EchAccepted::open_first(
+                    self.config.provider,
+                    &self.config.ech_keys,
+                    outer,
+                    offer,
+                ) {
+                    Some((accepted, encoded_inner)) => {
+                        self.ech = Some(EchState::Accepted(Box::new(accepted)));
+                        encoded_inner
+                    }
+                    None => {
+                        debug!("Rejecting ECH: could not decrypt inner ClientHello");
+                        self.ech = Some(EchState::Rejected);
+                        return Ok(None);
+                    }
+                }
+            }
+            (Some(EchState::Accepted(accepted)), Some(offer)) => accepted
+                .open_second(outer, offer)
+                .ok_or_else(|| {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::DecryptError);
+                    Error::PeerMisbehavedError(
+                        "could not decrypt second inner ClientHello".to_string(),
+                    )
+                })?,
+            (Some(EchState::Accepted(_)), None) => {
+                cx.common
+                    .send_fatal_alert(AlertDescription::MissingExtension);
+                return Err(Error::PeerMisbehavedError(
+                    "client did not offer ECH in second ClientHello".to_string(),
+                ));
+            }
+            _ => return Ok(None),
+        };
+
+        let inner =
+            ech::decode_inner(&encoded_inner, outer).map_err(|why| cx.common.illegal_param(why))?;
+        trace!("decrypted inner ClientHello {:?}", inner);
+        cx.data.ech_accepted = true;
+
+        Ok(Some(Message {
+            version: m.version,
+            payload: MessagePayload::handshake(HandshakeMessagePayload {
+                typ: HandshakeType::ClientHello,
+                payload: HandshakePayload::ClientHello(inner),
+            }),
+        }))
+    }
+
+    /// Without the `ech_server` feature, ECH offers are ignored.
+    #[cfg(not(feature = "ech_server"))]
+    pub(super) fn open_ech(
+        &mut self,
+        _m: &Message,
+        _cx: &mut ServerContext<'_>,
+    ) -> Result<Option<Message>, Error> {
+        Ok(None)
+    }
+
     /// Continues handling of a `ClientHello` message once config and certificate are available.
     pub(super) fn with_certified_key(
         self,
@@ -286,8 +417,17 @@ impl ExpectClientHello {
         // Are we doing TLS1.3?
         let maybe_versions_ext = client_hello.get_versions_extension();
         let version = if let Some(versions) = maybe_versions_ext {
-            if versions.contains(&ProtocolVersion::TLSv1_3) && tls13_enabled {
+            if versions.contains(
+                &cx.common
+                    .wire_version(ProtocolVersion::TLSv1_3),
+            ) && tls13_enabled
+            {
                 ProtocolVersion::TLSv1_3
+            } else if cx.common.is_dtls() {
+                return Err(bad_version(
+                    cx.common,
+                    "Expecting DTLS connection, but client does not support DTLSv1_3",
+                ));
             } else if !versions.contains(&ProtocolVersion::TLSv1_2) || !tls12_enabled {
                 return Err(bad_version(cx.common, "TLS1.2 not offered/enabled"));
             } else if cx.common.is_quic() {
@@ -298,6 +438,11 @@ impl ExpectClientHello {
             } else {
                 ProtocolVersion::TLSv1_2
             }
+        } else if cx.common.is_dtls() {
+            return Err(bad_version(
+                cx.common,
+                "Expecting DTLS connection, but client does not support DTLSv1_3",
+            ));
         } else if client_hello.client_version.get_u16() < ProtocolVersion::TLSv1_2.get_u16() {
             return Err(bad_version(cx.common, "Client does not support TLSv1_2"));
         } else if !tls12_enabled && tls13_enabled {
@@ -343,6 +488,10 @@ impl ExpectClientHello {
                 &sig_schemes,
                 client_hello.get_alpn_extension(),
                 &client_hello.cipher_suites,
+                client_hello
+                    .get_server_certificate_types_extension()
+                    .map(|types| types.as_slice()),
+                client_hello.get_certificate_authorities_extension(),
             );
 
             let certkey = self
@@ -356,16 +505,56 @@ impl ExpectClientHello {
                 Error::General("no server certificate chain resolved".to_string())
             })?
         };
-        let certkey = ActiveCertifiedKey::from_certified_key(&certkey);
+
+        // Raw public keys (RFC7250) are only supported in TLS1.3.
+        if certkey.cert_type == CertificateType::RawPublicKey && version != ProtocolVersion::TLSv1_3
+        {
+            return Err(incompatible(
+                cx.common,
+                "raw public key resolved for non-TLS1.3 handshake",
+            ));
+        }
 
         // Reduce our supported ciphersuites by the certificate.
         // (no-op for TLS1.3)
         let suitable_suites =
-            suites::reduce_given_sigalg(&self.config.cipher_suites, certkey.get_key().algorithm());
+            suites::reduce_given_sigalg(&self.config.cipher_suites, certkey.key.algorithm());
 
         // And version
         let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
 
+        // And, for TLS1.2, whether we can agree a group for the suite's key
+        // exchange.  If that leaves nothing the client offered, carry on
+        // without it, so the failure is reported as the lack of a group.
+        #[cfg(feature = "tls12")]
+        let suitable_suites = {
+            let client_groups = client_hello
+                .get_namedgroups_extension()
+                .map(|groups| groups.as_slice())
+                .unwrap_or_default();
+            let kx_suites = suitable_suites
+                .iter()
+                .copied()
+                .filter(|suite| match suite {
+                    SupportedCipherSuite::Tls12(suite) => {
+                        tls12::choose_kx_group(&self.config.kx_groups, suite, client_groups)
+                            .is_some()
+                    }
+                    SupportedCipherSuite::Tls13(_) => true,
+                })
+                .collect::<Vec<_>>();
+            let offered = |suite: &SupportedCipherSuite| {
+                client_hello
+                    .cipher_suites
+                    .contains(&suite.suite())
+            };
+            if kx_suites.iter().any(offered) {
+                kx_suites
+            } else {
+                suitable_suites
+            }
+        };
+
         let suite = if self.config.ignore_client_order {
             suites::choose_ciphersuite_preferring_server(
                 &client_hello.cipher_suites,
@@ -386,7 +575,11 @@ impl ExpectClientHello {
         let starting_hash = suite.hash_algorithm();
         let transcript = match self.transcript {
             HandshakeHashOrBuffer::Buffer(inner) => inner.start_hash(starting_hash),
-            HandshakeHashOrBuffer::Hash(inner) if inner.algorithm() == starting_hash => inner,
+            HandshakeHashOrBuffer::Hash(inner)
+                if inner.algorithm().algorithm() == starting_hash.algorithm() =>
+            {
+                inner
+            }
             _ => {
                 return Err(cx
                     .common
@@ -395,7 +588,8 @@ impl ExpectClientHello {
         };
 
         // Save their Random.
-        let randoms = ConnectionRandoms::new(client_hello.random, Random::new()?);
+        let randoms =
+            ConnectionRandoms::new(client_hello.random, Random::new(self.config.provider)?);
         match suite {
             SupportedCipherSuite::Tls13(suite) => tls13::CompleteClientHelloHandling {
                 config: self.config,
@@ -405,8 +599,10 @@ impl ExpectClientHello {
                 done_retry: self.done_retry,
                 send_ticket: self.send_ticket,
                 extra_exts: self.extra_exts,
+                #[cfg(feature = "ech_server")]
+                ech: self.ech,
             }
-            .handle_client_hello(cx, certkey, m, client_hello, sig_schemes),
+            .handle_client_hello(cx, &certkey, m, client_hello, sig_schemes, None),
             #[cfg(feature = "tls12")]
             SupportedCipherSuite::Tls12(suite) => tls12::CompleteClientHelloHandling {
                 config: self.config,
@@ -420,7 +616,7 @@ impl ExpectClientHello {
             }
             .handle_client_hello(
                 cx,
-                certkey,
+                &certkey,
                 m,
                 client_hello,
                 sig_schemes,
@@ -431,7 +627,8 @@ impl ExpectClientHello {
 }
 
 impl State<ServerConnectionData> for ExpectClientHello {
-    fn handle(self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> NextStateOrError {
+        let m = self.open_ech(&m, cx)?.unwrap_or(m);
         let (client_hello, sig_schemes) =
             process_client_hello(&m, self.done_retry, cx.common, cx.data)?;
         self.with_certified_key(sig_schemes, client_hello, &m, cx)
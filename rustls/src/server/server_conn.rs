@@ -1,25 +1,34 @@
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::conn::{CommonState, ConnectionCommon, Side, State};
+use crate::compress;
+#[cfg(any(feature = "quic", feature = "dtls"))]
+use crate::conn::Protocol;
+use crate::conn::{CommonState, ConnectionCommon, IoState, Side, State};
+use crate::crypto::{self, CryptoProvider, SupportedKxGroup};
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::error::Error;
-use crate::kx::SupportedKxGroup;
+use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::base::{Payload, PayloadU8};
+#[cfg(feature = "ech_server")]
+use crate::msgs::codec::Codec;
 #[cfg(feature = "quic")]
 use crate::msgs::enums::AlertDescription;
-use crate::msgs::handshake::{ClientHelloPayload, ServerExtension};
+use crate::msgs::enums::{CertificateType, PSKKeyExchangeMode};
+use crate::msgs::handshake::{ClientHelloPayload, DistinguishedNames, ServerExtension};
 use crate::msgs::message::Message;
+#[cfg(feature = "quic")]
+use crate::quic;
 use crate::sign;
 use crate::suites::SupportedCipherSuite;
 use crate::vecbuf::ChunkVecBuffer;
-use crate::verify;
+use crate::verify::{self, ClientRawKeyVerifier};
 #[cfg(feature = "secret_extraction")]
 use crate::ExtractedSecrets;
-use crate::KeyLog;
-#[cfg(feature = "quic")]
-use crate::{conn::Protocol, quic};
+use crate::{ExternalPsk, KeyLog};
 
+#[cfg(feature = "ech_server")]
+use super::ech::{self, EchKeyPair};
 use super::hs;
 
 use std::marker::PhantomData;
@@ -65,6 +74,65 @@ pub trait StoresServerSessions: Send + Sync {
     fn can_cache(&self) -> bool;
 }
 
+/// A lookup in a session store which a server handshake is waiting for.
+///
+/// See [`ServerConfig::defer_session_lookups`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionLookup {
+    /// Find the value stored against this key, like
+    /// [`StoresServerSessions::get`].
+    Get(Vec<u8>),
+    /// Find the value stored against this key and delete it, like
+    /// [`StoresServerSessions::take`].
+    Take(Vec<u8>),
+}
+
+/// A signature which a server handshake is waiting for.
+///
+/// See [`ServerConfig::defer_signing`].
+#[derive(Clone, Debug)]
+pub struct SigningRequest {
+    pub(super) scheme: SignatureScheme,
+    pub(super) message: Vec<u8>,
+    pub(super) cert_chain: Vec<key::Certificate>,
+    pub(super) key_source: SigningKeySource,
+}
+
+impl SigningRequest {
+    /// The scheme to sign with, chosen by [`sign::SigningKey::choose_scheme`].
+    pub fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+
+    /// The message to sign.
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// The certificate chain resolved for this handshake, end-entity
+    /// certificate first.
+    pub fn cert_chain(&self) -> &[key::Certificate] {
+        &self.cert_chain
+    }
+
+    /// Which key must make the signature.
+    pub fn key_source(&self) -> &SigningKeySource {
+        &self.key_source
+    }
+}
+
+/// Which private key a [`SigningRequest`] is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SigningKeySource {
+    /// The key of the end-entity certificate, the first in
+    /// [`SigningRequest::cert_chain`].
+    Certificate,
+    /// The key of the delegated credential sent with the certificate.
+    /// This is the credential's encoding, as from
+    /// [`sign::DelegatedCredential::encoded`].
+    DelegatedCredential(Vec<u8>),
+}
+
 /// A trait for the ability to encrypt and decrypt tickets.
 pub trait ProducesTickets: Send + Sync {
     /// Returns true if this implementation will encrypt/decrypt
@@ -108,12 +176,24 @@ pub trait ResolvesServerCert: Send + Sync {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>>;
 }
 
+/// How to find an externally provisioned pre-shared key offered by
+/// a TLS1.3 client.
+pub trait ResolvesServerPsk: Send + Sync {
+    /// Return the PSK named `identity`, or `None` if it is unknown.
+    ///
+    /// A PSK whose hash does not match the negotiated cipher suite
+    /// is ignored.
+    fn resolve(&self, identity: &[u8]) -> Option<Arc<ExternalPsk>>;
+}
+
 /// A struct representing the received Client Hello
 pub struct ClientHello<'a> {
     server_name: &'a Option<webpki::DnsName>,
     signature_schemes: &'a [SignatureScheme],
     alpn: Option<&'a Vec<PayloadU8>>,
     cipher_suites: &'a [CipherSuite],
+    server_cert_types: Option<&'a [CertificateType]>,
+    certificate_authorities: Option<&'a DistinguishedNames>,
 }
 
 impl<'a> ClientHello<'a> {
@@ -123,17 +203,23 @@ impl<'a> ClientHello<'a> {
         signature_schemes: &'a [SignatureScheme],
         alpn: Option<&'a Vec<PayloadU8>>,
         cipher_suites: &'a [CipherSuite],
+        server_cert_types: Option<&'a [CertificateType]>,
+        certificate_authorities: Option<&'a DistinguishedNames>,
     ) -> Self {
         trace!("sni {:?}", server_name);
         trace!("sig schemes {:?}", signature_schemes);
         trace!("alpn protocols {:?}", alpn);
         trace!("cipher suites {:?}", cipher_suites);
+        trace!("server certificate types {:?}", server_cert_types);
+        trace!("certificate authorities {:?}", certificate_authorities);
 
         ClientHello {
             server_name,
             signature_schemes,
             alpn,
             cipher_suites,
+            server_cert_types,
+            certificate_authorities,
         }
     }
 
@@ -182,6 +268,28 @@ impl<'a> ClientHello<'a> {
     pub fn cipher_suites(&self) -> &[CipherSuite] {
         self.cipher_suites
     }
+
+    /// Get the kinds of server credential the client accepts, in
+    /// preference order (see RFC7250).
+    ///
+    /// Returns `None` if the client did not include a server_certificate_type
+    /// extension, in which case only X.509 certificate chains may be used.
+    /// A [`sign::CertifiedKey`] made with
+    /// [`sign::CertifiedKey::new_raw_public_key`] should only be returned
+    /// if this includes [`CertificateType::RawPublicKey`].
+    pub fn server_certificate_types(&self) -> Option<&[CertificateType]> {
+        self.server_cert_types
+    }
+
+    /// Get the distinguished names of the trust anchors the client accepts,
+    /// from the TLS1.3 `certificate_authorities` extension.
+    ///
+    /// Returns `None` if the client did not include this extension.  A
+    /// [`ResolvesServerCert`] can use this to pick a certificate chain that
+    /// leads to one of these roots.
+    pub fn certificate_authorities(&self) -> Option<&'a DistinguishedNames> {
+        self.certificate_authorities
+    }
 }
 
 /// Common configuration for a set of server sessions.
@@ -194,6 +302,7 @@ impl<'a> ClientHello<'a> {
 /// # Defaults
 ///
 /// * [`ServerConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
+/// * [`ServerConfig::record_size_limit`]: the default is `None`: the peer may send full-sized records.
 /// * [`ServerConfig::session_storage`]: the default stores 256 sessions in memory.
 /// * [`ServerConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
 /// * [`ServerConfig::key_log`]: key material is not logged.
@@ -202,11 +311,14 @@ pub struct ServerConfig {
     /// List of ciphersuites, in preference order.
     pub(super) cipher_suites: Vec<SupportedCipherSuite>,
 
+    /// Source of cryptographic primitives and randomness.
+    pub(crate) provider: &'static dyn CryptoProvider,
+
     /// List of supported key exchange groups.
     ///
     /// The first is the highest priority: they will be
     /// offered to the client in this order.
-    pub(super) kx_groups: Vec<&'static SupportedKxGroup>,
+    pub(super) kx_groups: Vec<&'static dyn SupportedKxGroup>,
 
     /// Ignore the client's ciphersuite order. Instead,
     /// choose the top ciphersuite in the server list
@@ -222,6 +334,17 @@ pub struct ServerConfig {
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
     pub max_fragment_size: Option<usize>,
 
+    /// The largest record we are prepared to receive, advertised to the peer using the
+    /// `record_size_limit` extension (RFC8449).  This counts the plaintext of a protected
+    /// record, plus the inner content type in TLS1.3.  It is not used for QUIC or DTLS.
+    ///
+    /// The extension is only sent if the client sent it.  If None, we advertise the protocol maximum.
+    /// Peers that exceed the limit are rejected with [`Error::PeerExceededRecordSizeLimit`].
+    ///
+    /// Values must be between 64 and 16385; others are reported as errors from
+    /// ServerConnection::new.
+    pub record_size_limit: Option<usize>,
+
     /// How to store client sessions.
     pub session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
 
@@ -284,16 +407,130 @@ pub struct ServerConfig {
     /// sent by the server comes after receiving and validating the client's
     /// handshake up to the `Finished` message.  This is the safest option.
     pub send_half_rtt_data: bool,
+
+    /// Key pairs for Encrypted Client Hello, if this server accepts it.
+    ///
+    /// A client's encrypted inner `ClientHello` is decrypted with the
+    /// key pair its offer names, and the handshake then proceeds with
+    /// the inner hello.  If it cannot be decrypted, the handshake proceeds
+    /// with the outer hello and the client is sent the current
+    /// configurations to retry with.
+    ///
+    /// The default is empty, meaning ECH offers are ignored.
+    #[cfg(feature = "ech_server")]
+    pub ech_keys: Vec<EchKeyPair>,
+
+    /// Certificate compression algorithms we can use to compress our
+    /// certificate, in preference order.  The first one the client
+    /// also supports is used.
+    ///
+    /// The default is [`compress::default_cert_compressors()`].
+    pub cert_compressors: Vec<&'static dyn compress::CertCompressor>,
+
+    /// Certificate compression algorithms we can decompress client
+    /// certificates with.  These are offered to the client when
+    /// requesting client authentication.
+    ///
+    /// The default is [`compress::default_cert_decompressors()`].
+    pub cert_decompressors: Vec<&'static dyn compress::CertDecompressor>,
+
+    /// Accept clients which authenticate with a raw public key (RFC7250)
+    /// instead of a certificate chain, deciding whether to trust them
+    /// using this.
+    ///
+    /// This is only possible in TLS1.3.  When a client offers a raw
+    /// public key, it is requested regardless of `verifier`.
+    ///
+    /// The default is `None`.
+    pub client_raw_key_verifier: Option<Arc<dyn ClientRawKeyVerifier>>,
+
+    /// How to look up externally provisioned pre-shared keys offered
+    /// by clients.  A client which completes the handshake with one of
+    /// these is authenticated by it, and we send no certificate.
+    ///
+    /// This is only possible in TLS1.3.
+    ///
+    /// The default is `None`.
+    pub psk_resolver: Option<Arc<dyn ResolvesServerPsk>>,
+
+    /// The PSK key exchange modes we accept, in preference order.
+    /// These apply to both resumption and external PSKs.  `PSK_KE`
+    /// connections do not have forward secrecy.
+    ///
+    /// The default is `[PSK_DHE_KE]`.
+    pub psk_kx_modes: Vec<PSKKeyExchangeMode>,
+
+    /// Don't ask for a client certificate during the handshake, even if
+    /// the client certificate verifier offers client authentication.
+    ///
+    /// Client certificates are then only requested through
+    /// [`ServerConnection::request_client_certificate`], which is
+    /// possible for TLS1.3 clients that support it.
+    ///
+    /// The default is false.
+    pub defer_client_auth: bool,
+
+    /// Don't look up sessions in `session_storage` during the handshake.
+    /// Instead, the handshake is suspended while the application does the
+    /// lookup: see [`ServerConnection::pending_session_lookup`].
+    ///
+    /// This allows sessions to be kept in a store which is slow to reach,
+    /// without blocking the thread processing the handshake.  Sessions are
+    /// still saved with [`StoresServerSessions::put`], which such a store
+    /// may do in the background.  Stateless resumption with `ticketer` is
+    /// not affected, and nor are DTLS connections.
+    ///
+    /// The default is false.
+    pub defer_session_lookups: bool,
+
+    /// Don't sign with the [`sign::SigningKey`] chosen by `cert_resolver`
+    /// (or its delegated credential's key).  Instead, the handshake is
+    /// suspended while the application makes the signature: see
+    /// [`ServerConnection::pending_signature`].
+    ///
+    /// This allows the private key to be held by a remote signing
+    /// service, without blocking the thread processing the handshake.
+    /// The key's [`sign::SigningKey::choose_scheme`] is still used to
+    /// choose the signature scheme, but the [`sign::Signer`] it returns
+    /// is not used.  This does not apply to DTLS connections.
+    ///
+    /// The default is false.
+    pub defer_signing: bool,
+
+    /// Don't verify client certificate chains with
+    /// `ClientCertVerifier::verify_client_cert`.  Instead, the handshake
+    /// is suspended after the client's `Certificate` message while the
+    /// application verifies it: see [`CommonState::pending_cert_verification`].
+    ///
+    /// `verifier` still decides whether client authentication is offered
+    /// or mandatory, and checks the client's signature.  Raw public keys,
+    /// post-handshake authentication and DTLS connections are not affected.
+    ///
+    /// The default is false.
+    pub defer_cert_verification: bool,
 }
 
 impl fmt::Debug for ServerConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("ServerConfig")
+        let mut debug = f.debug_struct("ServerConfig");
+        debug
             .field("ignore_client_order", &self.ignore_client_order)
             .field("max_fragment_size", &self.max_fragment_size)
+            .field("record_size_limit", &self.record_size_limit)
             .field("alpn_protocols", &self.alpn_protocols)
             .field("max_early_data_size", &self.max_early_data_size)
-            .field("send_half_rtt_data", &self.send_half_rtt_data)
+            .field("send_half_rtt_data", &self.send_half_rtt_data);
+        #[cfg(feature = "ech_server")]
+        debug.field("ech_keys", &self.ech_keys);
+        debug
+            .field("cert_compressors", &self.cert_compressors)
+            .field("cert_decompressors", &self.cert_decompressors)
+            .field("client_raw_key_verifier", &self.client_raw_key_verifier)
+            .field("psk_kx_modes", &self.psk_kx_modes)
+            .field("defer_client_auth", &self.defer_client_auth)
+            .field("defer_session_lookups", &self.defer_session_lookups)
+            .field("defer_signing", &self.defer_signing)
+            .field("defer_cert_verification", &self.defer_cert_verification)
             .finish_non_exhaustive()
     }
 }
@@ -301,10 +538,22 @@ impl fmt::Debug for ServerConfig {
 impl ServerConfig {
     /// Create builder to build up the server configuration.
     ///
+    /// This uses the default, *ring*-based, cryptography provider.
+    ///
     /// For more information, see the [`ConfigBuilder`] documentation.
     pub fn builder() -> ConfigBuilder<Self, WantsCipherSuites> {
+        Self::builder_with_provider(crypto::ring::RING)
+    }
+
+    /// Create builder to build up the server configuration, using
+    /// `provider` for all cryptography.
+    ///
+    /// For more information, see the [`ConfigBuilder`] documentation.
+    pub fn builder_with_provider(
+        provider: &'static dyn CryptoProvider,
+    ) -> ConfigBuilder<Self, WantsCipherSuites> {
         ConfigBuilder {
-            state: WantsCipherSuites(()),
+            state: WantsCipherSuites(provider),
             side: PhantomData::default(),
         }
     }
@@ -319,6 +568,20 @@ impl ServerConfig {
                 .iter()
                 .any(|cs| cs.version().version == v)
     }
+
+    /// Whether we ask for a client certificate during the handshake.
+    pub(crate) fn offer_client_auth_in_handshake(&self) -> bool {
+        !self.defer_client_auth && self.verifier.offer_client_auth()
+    }
+
+    /// The TLS-encoded `ECHConfigList` for [`ServerConfig::ech_keys`].
+    ///
+    /// This is what should be published in DNS, and is what clients
+    /// whose offer we could not decrypt are sent to retry with.
+    #[cfg(feature = "ech_server")]
+    pub fn ech_config_list(&self) -> Vec<u8> {
+        ech::ech_config_list(&self.ech_keys).get_encoding()
+    }
 }
 
 /// Allows reading of early data in resumed TLS1.3 connections.
@@ -368,6 +631,7 @@ impl ServerConnection {
     ) -> Result<Self, Error> {
         let mut common = CommonState::new(Side::Server);
         common.set_max_fragment_size(config.max_fragment_size)?;
+        common.set_record_size_limit(config.record_size_limit)?;
         #[cfg(feature = "secret_extraction")]
         {
             common.enable_secret_extraction = config.enable_secret_extraction;
@@ -381,6 +645,15 @@ impl ServerConnection {
         })
     }
 
+    #[cfg(feature = "dtls")]
+    pub(crate) fn new_dtls(
+        config: Arc<ServerConfig>,
+    ) -> Result<ConnectionCommon<ServerConnectionData>, Error> {
+        let mut new = Self::from_config(config, vec![])?;
+        new.inner.common_state.protocol = Protocol::Dtls;
+        Ok(new.inner)
+    }
+
     /// Retrieves the SNI hostname, if any, used to select the certificate and
     /// private key.
     ///
@@ -401,6 +674,15 @@ impl ServerConnection {
         self.inner.data.get_sni_str()
     }
 
+    /// Returns true if the client offered Encrypted Client Hello and we
+    /// accepted it.
+    ///
+    /// In that case the handshake is with the client's inner `ClientHello`,
+    /// and [`ServerConnection::sni_hostname`] is the name from that.
+    pub fn ech_accepted(&self) -> bool {
+        self.inner.data.ech_accepted
+    }
+
     /// Application-controlled portion of the resumption ticket supplied by the client, if any.
     ///
     /// Recovered from the prior session's `set_resumption_data`. Integrity is guaranteed by rustls.
@@ -414,6 +696,145 @@ impl ServerConnection {
             .map(|x| &x[..])
     }
 
+    /// The identity of the external pre-shared key the client
+    /// authenticated with, if any.
+    ///
+    /// This is `None` until the handshake has selected a PSK, and for
+    /// handshakes using certificates or resumption.
+    pub fn external_psk_identity(&self) -> Option<&[u8]> {
+        self.inner
+            .data
+            .external_psk
+            .as_ref()
+            .map(|psk| psk.identity())
+    }
+
+    /// Ask the client to authenticate itself with a certificate, after
+    /// the handshake has completed (RFC8446 section 4.6.2).
+    ///
+    /// The client's certificate is checked by the client certificate
+    /// verifier in the `ServerConfig`.  Progress can be followed with
+    /// [`ServerConnection::post_handshake_auth_status`]; once that is
+    /// [`PostHandshakeAuthStatus::Verified`],
+    /// [`CommonState::peer_certificates`] returns the client's
+    /// certificate chain.
+    ///
+    /// This fails with [`Error::HandshakeNotComplete`] if called during the
+    /// handshake, and with [`Error::PeerIncompatibleError`] if the connection
+    /// does not use TLS1.3 or the client did not offer post-handshake
+    /// authentication.  Only one request may be outstanding at a time.
+    ///
+    /// Set [`ServerConfig::defer_client_auth`] to avoid also asking for a
+    /// certificate during the handshake.
+    ///
+    /// The `CertificateRequest` message is written out along with other
+    /// TLS data, so call [`CommonState::write_tls`] afterwards.
+    pub fn request_client_certificate(&mut self) -> Result<(), Error> {
+        if self.inner.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if !self.inner.is_tls13()
+            || !self
+                .inner
+                .data
+                .post_handshake_auth_offered
+        {
+            return Err(Error::PeerIncompatibleError(
+                "client does not support post-handshake authentication".into(),
+            ));
+        }
+
+        if self.inner.data.post_handshake_auth == PostHandshakeAuthStatus::Pending {
+            return Err(Error::General(
+                "post-handshake authentication already in progress".into(),
+            ));
+        }
+
+        self.inner.request_client_certificate()
+    }
+
+    /// Where post-handshake client authentication has got to.
+    ///
+    /// See [`ServerConnection::request_client_certificate`].
+    pub fn post_handshake_auth_status(&self) -> PostHandshakeAuthStatus {
+        self.inner.data.post_handshake_auth
+    }
+
+    /// The session lookup the handshake is waiting for, if any.
+    ///
+    /// This is only ever `Some` if [`ServerConfig::defer_session_lookups`]
+    /// is set.  Until the lookup is completed with
+    /// [`ServerConnection::complete_session_lookup`], received TLS
+    /// messages are kept but not processed, so the blocking helpers
+    /// [`ConnectionCommon::complete_io`] and [`crate::Stream`] must not be
+    /// used.
+    pub fn pending_session_lookup(&self) -> Option<&SessionLookup> {
+        self.inner
+            .data
+            .pending_session_lookup
+            .as_ref()
+    }
+
+    /// Continue the handshake with `value`, the result of the pending
+    /// session lookup: the value stored against its key, if any.
+    ///
+    /// Any messages received meanwhile are then processed, as by
+    /// [`ConnectionCommon::process_new_packets`], whose result is returned.
+    /// This fails without affecting the connection if no lookup is pending.
+    pub fn complete_session_lookup(&mut self, value: Option<Vec<u8>>) -> Result<IoState, Error> {
+        if self
+            .inner
+            .data
+            .pending_session_lookup
+            .take()
+            .is_none()
+        {
+            return Err(Error::General("no session lookup is pending".into()));
+        }
+
+        self.inner.data.session_lookup_result = value;
+        self.inner.resume()
+    }
+
+    /// The signature the handshake is waiting for, if any.
+    ///
+    /// This is only ever `Some` if [`ServerConfig::defer_signing`] is set.
+    /// As with [`ServerConnection::pending_session_lookup`], received TLS
+    /// messages are not processed until the signature is supplied with
+    /// [`ServerConnection::complete_signature`].
+    pub fn pending_signature(&self) -> Option<&SigningRequest> {
+        self.inner
+            .data
+            .pending_signature
+            .as_ref()
+    }
+
+    /// Continue the handshake with the result of signing the pending
+    /// [`SigningRequest`].  An error ends the handshake, and is returned.
+    ///
+    /// Any messages received meanwhile are then processed, as by
+    /// [`ConnectionCommon::process_new_packets`], whose result is returned.
+    /// This fails without affecting the connection if no signature is
+    /// pending.
+    pub fn complete_signature(
+        &mut self,
+        signature: Result<Vec<u8>, Error>,
+    ) -> Result<IoState, Error> {
+        if self
+            .inner
+            .data
+            .pending_signature
+            .take()
+            .is_none()
+        {
+            return Err(Error::General("no signature is pending".into()));
+        }
+
+        self.inner.data.signature = Some(signature);
+        self.inner.resume()
+    }
+
     /// Set the resumption data to embed in future resumption tickets supplied to the client.
     ///
     /// Defaults to the empty byte string. Must be less than 2^15 bytes to allow room for other
@@ -607,6 +1028,10 @@ pub struct Accepted {
 
 impl Accepted {
     /// Get the [`ClientHello`] for this connection.
+    ///
+    /// If the client offered Encrypted Client Hello, this describes the
+    /// outer `ClientHello`.  Whether the inner one can be decrypted depends
+    /// on the [`ServerConfig`] given to [`Accepted::into_connection()`].
     pub fn client_hello(&self) -> ClientHello<'_> {
         let payload = Self::client_hello_payload(&self.message);
         ClientHello::new(
@@ -614,6 +1039,10 @@ impl Accepted {
             &self.sig_schemes,
             payload.get_alpn_extension(),
             &payload.cipher_suites,
+            payload
+                .get_server_certificate_types_extension()
+                .map(|types| types.as_slice()),
+            payload.get_certificate_authorities_extension(),
         )
     }
 
@@ -626,6 +1055,9 @@ impl Accepted {
         self.connection
             .common_state
             .set_max_fragment_size(config.max_fragment_size)?;
+        self.connection
+            .common_state
+            .set_record_size_limit(config.record_size_limit)?;
 
         #[cfg(feature = "secret_extraction")]
         {
@@ -634,16 +1066,26 @@ impl Accepted {
                 .enable_secret_extraction = config.enable_secret_extraction;
         }
 
-        let state = hs::ExpectClientHello::new(config, Vec::new());
+        let mut state = hs::ExpectClientHello::new(config, Vec::new());
         let mut cx = hs::ServerContext {
             common: &mut self.connection.common_state,
             data: &mut self.connection.data,
         };
 
+        // The SNI seen by `accept()` was the outer one.
+        let (message, sig_schemes) = match state.open_ech(&self.message, &mut cx)? {
+            Some(inner) => {
+                cx.data.sni = None;
+                let (_, sig_schemes) = hs::process_client_hello(&inner, false, cx.common, cx.data)?;
+                (inner, sig_schemes)
+            }
+            None => (self.message, self.sig_schemes),
+        };
+
         let new = state.with_certified_key(
-            self.sig_schemes,
-            Self::client_hello_payload(&self.message),
-            &self.message,
+            sig_schemes,
+            Self::client_hello_payload(&message),
+            &message,
             &mut cx,
         )?;
 
@@ -755,6 +1197,28 @@ fn test_read_buf_in_new_state() {
     );
 }
 
+/// The progress of post-handshake client authentication.
+///
+/// See [`ServerConnection::request_client_certificate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostHandshakeAuthStatus {
+    /// No certificate has been requested after the handshake.
+    NotRequested,
+    /// We sent a `CertificateRequest` and are waiting for the client's
+    /// response.
+    Pending,
+    /// The client presented a certificate which we verified.
+    Verified,
+    /// The client responded without a certificate.
+    Declined,
+}
+
+impl Default for PostHandshakeAuthStatus {
+    fn default() -> Self {
+        Self::NotRequested
+    }
+}
+
 /// State associated with a server connection.
 #[derive(Default)]
 pub struct ServerConnectionData {
@@ -762,9 +1226,25 @@ pub struct ServerConnectionData {
     pub(super) received_resumption_data: Option<Vec<u8>>,
     pub(super) resumption_data: Vec<u8>,
     pub(super) early_data: EarlyDataState,
+    pub(super) ech_accepted: bool,
+    pub(super) external_psk: Option<Arc<ExternalPsk>>,
+    pub(super) post_handshake_auth_offered: bool,
+    pub(super) post_handshake_auth: PostHandshakeAuthStatus,
+    pub(super) pending_session_lookup: Option<SessionLookup>,
+    pub(super) session_lookup_result: Option<Vec<u8>>,
+    pub(super) pending_signature: Option<SigningRequest>,
+    pub(super) signature: Option<Result<Vec<u8>, Error>>,
 }
 
 impl ServerConnectionData {
+    /// Take the result of the application's signing, for a handshake
+    /// resuming from `pending_signature`.
+    pub(super) fn take_signature(&mut self) -> Result<Vec<u8>, Error> {
+        self.signature
+            .take()
+            .unwrap_or(Err(Error::HandshakeNotComplete))
+    }
+
     pub(super) fn get_sni_str(&self) -> Option<&str> {
         self.sni.as_ref().map(AsRef::as_ref)
     }
@@ -1,5 +1,6 @@
 use crate::check::inappropriate_message;
-use crate::conn::{CommonState, ConnectionRandoms, Side, State};
+use crate::conn::{CommonState, ConnectionRandoms, ExpectCertVerification, Side, State};
+use crate::crypto::{ActiveKeyExchange, SupportedKxGroup};
 use crate::enums::ProtocolVersion;
 use crate::error::Error;
 use crate::hash_hs::HandshakeHash;
@@ -9,42 +10,77 @@ use crate::log::{debug, trace};
 use crate::msgs::base::Payload;
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
+use crate::msgs::enums::NamedGroup;
 use crate::msgs::enums::{AlertDescription, ContentType, HandshakeType};
-use crate::msgs::handshake::{ClientECDHParams, HandshakeMessagePayload, HandshakePayload};
+use crate::msgs::handshake::{ClientDHParams, ClientECDHParams, KeyExchangeAlgorithm};
+use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{NewSessionTicketPayload, SessionID};
-use crate::msgs::message::{Message, MessagePayload};
+use crate::msgs::message::{Message, MessagePayload, PlainMessage};
 use crate::msgs::persist;
 #[cfg(feature = "secret_extraction")]
 use crate::suites::PartiallyExtractedSecrets;
 use crate::tls12::{self, ConnectionSecrets, Tls12CipherSuite};
-use crate::{kx, ticketer, verify};
+use crate::{ticketer, verify};
 
 use super::common::ActiveCertifiedKey;
 use super::hs::{self, ServerContext};
 use super::server_conn::{ProducesTickets, ServerConfig, ServerConnectionData};
+use super::server_conn::{SessionLookup, SigningKeySource, SigningRequest};
 
 use ring::constant_time;
 
+use std::mem;
 use std::sync::Arc;
 
 pub(super) use client_hello::CompleteClientHelloHandling;
 
+/// Choose a group for `suite`'s key exchange from those we support and
+/// those the client offered.
+///
+/// Clients predating RFC7919 don't list finite-field groups at all, but
+/// may still offer DHE suites.  For them we use our preferred
+/// finite-field group (RFC7919 section 4).
+pub(super) fn choose_kx_group(
+    kx_groups: &[&'static dyn SupportedKxGroup],
+    suite: &Tls12CipherSuite,
+    client_groups: &[NamedGroup],
+) -> Option<&'static dyn SupportedKxGroup> {
+    let mut usable = kx_groups
+        .iter()
+        .copied()
+        .filter(|group| suite.usable_for_kx_group(*group));
+
+    let client_lists_ffdhe = client_groups
+        .iter()
+        .any(|group| group.key_exchange_algorithm() == KeyExchangeAlgorithm::DHE);
+    if suite.kx == KeyExchangeAlgorithm::DHE && !client_lists_ffdhe {
+        return usable.next();
+    }
+
+    usable.find(|group| client_groups.contains(&group.name()))
+}
+
 mod client_hello {
     use crate::enums::SignatureScheme;
     use crate::msgs::enums::ECPointFormat;
     use crate::msgs::enums::{ClientCertificateType, Compression};
     use crate::msgs::handshake::{CertificateRequestPayload, ClientSessionTicket, Random};
     use crate::msgs::handshake::{
-        CertificateStatus, DigitallySignedStruct, ECDHEServerKeyExchange,
+        CertificateStatus, DHEServerKeyExchange, DigitallySignedStruct, ECDHEServerKeyExchange,
     };
     use crate::msgs::handshake::{ClientExtension, SessionID};
     use crate::msgs::handshake::{ClientHelloPayload, ServerHelloPayload};
-    use crate::msgs::handshake::{ECPointFormatList, ServerECDHParams, SupportedPointFormats};
+    use crate::msgs::handshake::{ServerDHParams, ServerECDHParams};
     use crate::msgs::handshake::{ServerExtension, ServerKeyExchangePayload};
     use crate::sign;
 
     use super::*;
 
+    enum ServerKxParams {
+        Dh(ServerDHParams),
+        Ecdh(ServerECDHParams),
+    }
+
     pub(in crate::server) struct CompleteClientHelloHandling {
         pub(in crate::server) config: Arc<ServerConfig>,
         pub(in crate::server) transcript: HandshakeHash,
@@ -60,35 +96,40 @@ mod client_hello {
         pub(in crate::server) fn handle_client_hello(
             mut self,
             cx: &mut ServerContext<'_>,
-            server_key: ActiveCertifiedKey,
+            server_key: &Arc<sign::CertifiedKey>,
             chm: &Message,
             client_hello: &ClientHelloPayload,
             sigschemes_ext: Vec<SignatureScheme>,
             tls13_enabled: bool,
         ) -> hs::NextStateOrError {
             // -- TLS1.2 only from hereon in --
-            self.transcript.add_message(chm);
-
             if client_hello.ems_support_offered() {
                 self.using_ems = true;
             }
 
-            let groups_ext = client_hello
-                .get_namedgroups_extension()
-                .ok_or_else(|| hs::incompatible(cx.common, "client didn't describe groups"))?;
-            let ecpoints_ext = client_hello
-                .get_ecpoints_extension()
-                .ok_or_else(|| hs::incompatible(cx.common, "client didn't describe ec points"))?;
-
+            // Clients offering only DHE suites may omit these extensions.
+            let groups_ext = client_hello.get_namedgroups_extension();
             trace!("namedgroups {:?}", groups_ext);
-            trace!("ecpoints {:?}", ecpoints_ext);
 
-            if !ecpoints_ext.contains(&ECPointFormat::Uncompressed) {
-                cx.common
-                    .send_fatal_alert(AlertDescription::IllegalParameter);
-                return Err(Error::PeerIncompatibleError(
-                    "client didn't support uncompressed ec points".to_string(),
-                ));
+            if self.suite.kx == KeyExchangeAlgorithm::ECDHE {
+                if groups_ext.is_none() {
+                    return Err(hs::incompatible(cx.common, "client didn't describe groups"));
+                }
+                let ecpoints_ext = client_hello
+                    .get_ecpoints_extension()
+                    .ok_or_else(|| {
+                        hs::incompatible(cx.common, "client didn't describe ec points")
+                    })?;
+
+                trace!("ecpoints {:?}", ecpoints_ext);
+
+                if !ecpoints_ext.contains(&ECPointFormat::Uncompressed) {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::IllegalParameter);
+                    return Err(Error::PeerIncompatibleError(
+                        "client didn't support uncompressed ec points".to_string(),
+                    ));
+                }
             }
 
             // -- If TLS1.3 is enabled, signal the downgrade in the server random
@@ -108,35 +149,71 @@ mod client_hello {
             // which is passed to start_resumption and concludes
             // our handling of the ClientHello.
             //
-            let mut ticket_received = false;
-            let resume_data = client_hello
+            let ticket = client_hello
                 .get_ticket_extension()
                 .and_then(|ticket_ext| match ticket_ext {
                     ClientExtension::SessionTicket(ClientSessionTicket::Offer(ticket)) => {
                         Some(ticket)
                     }
                     _ => None,
-                })
-                .and_then(|ticket| {
-                    ticket_received = true;
+                });
+            let ticket_received = ticket.is_some();
+
+            let stored = match ticket {
+                Some(ticket) => {
                     debug!("Ticket received");
                     let data = self.config.ticketer.decrypt(&ticket.0);
                     if data.is_none() {
                         debug!("Ticket didn't decrypt");
                     }
                     data
-                })
-                .or_else(|| {
-                    // Perhaps resume?  If we received a ticket, the sessionid
-                    // does not correspond to a real session.
-                    if client_hello.session_id.is_empty() || ticket_received {
-                        return None;
-                    }
+                }
+                // Perhaps resume?  If we received a ticket, the sessionid
+                // does not correspond to a real session.
+                None if client_hello.session_id.is_empty() => None,
+                None if self.config.defer_session_lookups && !cx.common.is_dtls() => {
+                    cx.data.pending_session_lookup =
+                        Some(SessionLookup::Get(client_hello.session_id.get_encoding()));
+                    return Ok(Box::new(ExpectSessionLookup {
+                        handling: self,
+                        server_key: Arc::clone(server_key),
+                        client_hello: chm.to_plain(),
+                        sigschemes_ext,
+                    }));
+                }
+                None => self
+                    .config
+                    .session_storage
+                    .get(&client_hello.session_id.get_encoding()),
+            };
 
-                    self.config
-                        .session_storage
-                        .get(&client_hello.session_id.get_encoding())
-                })
+            self.resume_or_start_full_handshake(
+                cx,
+                server_key,
+                chm,
+                client_hello,
+                sigschemes_ext,
+                ticket_received,
+                stored,
+            )
+        }
+
+        /// Continue with `stored`, the session the client asked to resume
+        /// if we have it.
+        #[allow(clippy::too_many_arguments)]
+        fn resume_or_start_full_handshake(
+            mut self,
+            cx: &mut ServerContext<'_>,
+            server_key: &Arc<sign::CertifiedKey>,
+            chm: &Message,
+            client_hello: &ClientHelloPayload,
+            sigschemes_ext: Vec<SignatureScheme>,
+            ticket_received: bool,
+            stored: Option<Vec<u8>>,
+        ) -> hs::NextStateOrError {
+            self.transcript.add_message(chm);
+
+            let resume_data = stored
                 .and_then(|x| persist::ServerSessionValue::read_bytes(&x))
                 .filter(|resumedata| {
                     hs::can_resume(self.suite.into(), &cx.data.sni, self.using_ems, resumedata)
@@ -146,6 +223,9 @@ mod client_hello {
                 return self.start_resumption(cx, client_hello, &client_hello.session_id, data);
             }
 
+            let groups_ext = client_hello.get_namedgroups_extension();
+            let server_key = ActiveCertifiedKey::from_certified_key(server_key);
+
             // Now we have chosen a ciphersuite, we can make kx decisions.
             let sigschemes = self
                 .suite
@@ -155,21 +235,14 @@ mod client_hello {
                 return Err(hs::incompatible(cx.common, "no overlapping sigschemes"));
             }
 
-            let group = self
-                .config
-                .kx_groups
-                .iter()
-                .find(|skxg| groups_ext.contains(&skxg.name))
-                .cloned()
-                .ok_or_else(|| hs::incompatible(cx.common, "no supported group"))?;
-
-            let ecpoint = ECPointFormatList::supported()
-                .iter()
-                .find(|format| ecpoints_ext.contains(format))
-                .cloned()
-                .ok_or_else(|| hs::incompatible(cx.common, "no supported point format"))?;
-
-            debug_assert_eq!(ecpoint, ECPointFormat::Uncompressed);
+            let group = choose_kx_group(
+                &self.config.kx_groups,
+                self.suite,
+                groups_ext
+                    .map(|groups| groups.as_slice())
+                    .unwrap_or_default(),
+            )
+            .ok_or_else(|| hs::incompatible(cx.common, "no supported group"))?;
 
             let (mut ocsp_response, mut sct_list) =
                 (server_key.get_ocsp(), server_key.get_sct_list());
@@ -178,7 +251,7 @@ mod client_hello {
             if !self.config.session_storage.can_cache() {
                 self.session_id = SessionID::empty();
             } else if self.session_id.is_empty() && !ticket_received {
-                self.session_id = SessionID::random()?;
+                self.session_id = SessionID::random(self.config.provider)?;
             }
 
             self.send_ticket = emit_server_hello(
@@ -193,20 +266,51 @@ mod client_hello {
                 client_hello,
                 None,
                 &self.randoms,
-                self.extra_exts,
+                mem::take(&mut self.extra_exts),
             )?;
             emit_certificate(&mut self.transcript, cx.common, server_key.get_cert());
             if let Some(ocsp_response) = ocsp_response {
                 emit_cert_status(&mut self.transcript, cx.common, ocsp_response);
             }
-            let server_kx = emit_server_kx(
+
+            let (server_kx, params) = start_server_kx(group)?;
+            let message = server_kx_message(&params, &self.randoms);
+            let signer = server_key
+                .get_key()
+                .choose_scheme(&sigschemes)
+                .ok_or_else(|| Error::General("incompatible signing key".to_string()))?;
+
+            if self.config.defer_signing {
+                cx.data.pending_signature = Some(SigningRequest {
+                    scheme: signer.scheme(),
+                    message,
+                    cert_chain: server_key.get_cert().to_vec(),
+                    key_source: SigningKeySource::Certificate,
+                });
+                return Ok(Box::new(ExpectSignature {
+                    handling: self,
+                    server_kx,
+                    params,
+                    scheme: signer.scheme(),
+                }));
+            }
+
+            let signature = signer.sign(&message)?;
+            emit_server_kx(
                 &mut self.transcript,
                 cx.common,
-                sigschemes,
-                group,
-                server_key.get_key(),
-                &self.randoms,
-            )?;
+                params,
+                DigitallySignedStruct::new(signer.scheme(), signature),
+            );
+            self.finish_server_hello(cx, server_kx)
+        }
+
+        /// Send the rest of our first flight, after `ServerKeyExchange`.
+        fn finish_server_hello(
+            mut self,
+            cx: &mut ServerContext<'_>,
+            server_kx: Box<dyn ActiveKeyExchange>,
+        ) -> hs::NextStateOrError {
             let doing_client_auth = emit_certificate_req(&self.config, &mut self.transcript, cx)?;
             emit_server_hello_done(&mut self.transcript, cx.common);
 
@@ -308,6 +412,89 @@ mod client_hello {
         }
     }
 
+    /// The handshake is suspended until the application looks up the
+    /// session the client asked to resume.
+    struct ExpectSessionLookup {
+        handling: CompleteClientHelloHandling,
+        server_key: Arc<sign::CertifiedKey>,
+        client_hello: PlainMessage,
+        sigschemes_ext: Vec<SignatureScheme>,
+    }
+
+    impl State<ServerConnectionData> for ExpectSessionLookup {
+        fn handle(
+            self: Box<Self>,
+            _cx: &mut ServerContext<'_>,
+            m: Message,
+        ) -> hs::NextStateOrError {
+            Err(inappropriate_message(&m.payload, &[]))
+        }
+
+        fn is_suspended(&self) -> bool {
+            true
+        }
+
+        fn resume(self: Box<Self>, cx: &mut ServerContext<'_>) -> hs::NextStateOrError {
+            let Self {
+                handling,
+                server_key,
+                client_hello,
+                sigschemes_ext,
+            } = *self;
+            let chm = Message::try_from(client_hello)?;
+            let client_hello = require_handshake_msg!(
+                chm,
+                HandshakeType::ClientHello,
+                HandshakePayload::ClientHello
+            )?;
+            let stored = cx.data.session_lookup_result.take();
+            handling.resume_or_start_full_handshake(
+                cx,
+                &server_key,
+                &chm,
+                client_hello,
+                sigschemes_ext,
+                false,
+                stored,
+            )
+        }
+    }
+
+    /// The handshake is suspended until the application supplies the
+    /// signature for our `ServerKeyExchange` message.
+    struct ExpectSignature {
+        handling: CompleteClientHelloHandling,
+        server_kx: Box<dyn ActiveKeyExchange>,
+        params: ServerKxParams,
+        scheme: SignatureScheme,
+    }
+
+    impl State<ServerConnectionData> for ExpectSignature {
+        fn handle(
+            self: Box<Self>,
+            _cx: &mut ServerContext<'_>,
+            m: Message,
+        ) -> hs::NextStateOrError {
+            Err(inappropriate_message(&m.payload, &[]))
+        }
+
+        fn is_suspended(&self) -> bool {
+            true
+        }
+
+        fn resume(self: Box<Self>, cx: &mut ServerContext<'_>) -> hs::NextStateOrError {
+            let mut handling = self.handling;
+            let signature = cx.data.take_signature()?;
+            emit_server_kx(
+                &mut handling.transcript,
+                cx.common,
+                self.params,
+                DigitallySignedStruct::new(self.scheme, signature),
+            );
+            handling.finish_server_hello(cx, self.server_kx)
+        }
+    }
+
     fn emit_server_hello(
         config: &ServerConfig,
         transcript: &mut HandshakeHash,
@@ -387,32 +574,45 @@ mod client_hello {
         common.send_msg(c, false);
     }
 
-    fn emit_server_kx(
-        transcript: &mut HandshakeHash,
-        common: &mut CommonState,
-        sigschemes: Vec<SignatureScheme>,
-        skxg: &'static kx::SupportedKxGroup,
-        signing_key: &dyn sign::SigningKey,
-        randoms: &ConnectionRandoms,
-    ) -> Result<kx::KeyExchange, Error> {
-        let kx = kx::KeyExchange::start(skxg).ok_or(Error::FailedToGetRandomBytes)?;
-        let secdh = ServerECDHParams::new(skxg.name, kx.pubkey.as_ref());
+    /// Start our side of the key exchange, returning it along with the
+    /// parameters to send.
+    fn start_server_kx(
+        skxg: &'static dyn SupportedKxGroup,
+    ) -> Result<(Box<dyn ActiveKeyExchange>, ServerKxParams), Error> {
+        let kx = skxg.start()?;
+        let params = match skxg.ffdhe_group() {
+            Some(ffdhe) => ServerKxParams::Dh(ServerDHParams::new(ffdhe.p, ffdhe.g, kx.pub_key())),
+            None => ServerKxParams::Ecdh(ServerECDHParams::new(skxg.name(), kx.pub_key())),
+        };
+        Ok((kx, params))
+    }
 
+    /// The message signed in `ServerKeyExchange`.
+    fn server_kx_message(params: &ServerKxParams, randoms: &ConnectionRandoms) -> Vec<u8> {
         let mut msg = Vec::new();
         msg.extend(randoms.client);
         msg.extend(randoms.server);
-        secdh.encode(&mut msg);
-
-        let signer = signing_key
-            .choose_scheme(&sigschemes)
-            .ok_or_else(|| Error::General("incompatible signing key".to_string()))?;
-        let sigscheme = signer.scheme();
-        let sig = signer.sign(&msg)?;
+        match params {
+            ServerKxParams::Dh(params) => params.encode(&mut msg),
+            ServerKxParams::Ecdh(params) => params.encode(&mut msg),
+        }
+        msg
+    }
 
-        let skx = ServerKeyExchangePayload::ECDHE(ECDHEServerKeyExchange {
-            params: secdh,
-            dss: DigitallySignedStruct::new(sigscheme, sig),
-        });
+    fn emit_server_kx(
+        transcript: &mut HandshakeHash,
+        common: &mut CommonState,
+        params: ServerKxParams,
+        dss: DigitallySignedStruct,
+    ) {
+        let skx = match params {
+            ServerKxParams::Dh(params) => {
+                ServerKeyExchangePayload::DHE(DHEServerKeyExchange { params, dss })
+            }
+            ServerKxParams::Ecdh(params) => {
+                ServerKeyExchangePayload::ECDHE(ECDHEServerKeyExchange { params, dss })
+            }
+        };
 
         let m = Message {
             version: ProtocolVersion::TLSv1_2,
@@ -424,7 +624,6 @@ mod client_hello {
 
         transcript.add_message(&m);
         common.send_msg(m, false);
-        Ok(kx)
     }
 
     fn emit_certificate_req(
@@ -434,7 +633,7 @@ mod client_hello {
     ) -> Result<bool, Error> {
         let client_auth = &config.verifier;
 
-        if !client_auth.offer_client_auth() {
+        if !config.offer_client_auth_in_handshake() {
             return Ok(false);
         }
 
@@ -494,7 +693,7 @@ struct ExpectCertificate {
     session_id: SessionID,
     suite: &'static Tls12CipherSuite,
     using_ems: bool,
-    server_kx: kx::KeyExchange,
+    server_kx: Box<dyn ActiveKeyExchange>,
     send_ticket: bool,
 }
 
@@ -521,6 +720,7 @@ impl State<ServerConnectionData> for ExpectCertificate {
 
         trace!("certs {:?}", cert_chain);
 
+        let mut deferred = None;
         let client_cert = match cert_chain.split_first() {
             None if mandatory => {
                 cx.common
@@ -532,21 +732,22 @@ impl State<ServerConnectionData> for ExpectCertificate {
                 self.transcript.abandon_client_auth();
                 None
             }
+            Some(_) if self.config.defer_cert_verification => {
+                deferred = Some(cert_chain.clone());
+                Some(cert_chain)
+            }
             Some((end_entity, intermediates)) => {
                 let now = std::time::SystemTime::now();
                 self.config
                     .verifier
                     .verify_client_cert(end_entity, intermediates, now)
-                    .map_err(|err| {
-                        hs::incompatible(cx.common, "certificate invalid");
-                        err
-                    })?;
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
 
                 Some(cert_chain)
             }
         };
 
-        Ok(Box::new(ExpectClientKx {
+        let next = Box::new(ExpectClientKx {
             config: self.config,
             transcript: self.transcript,
             randoms: self.randoms,
@@ -556,7 +757,14 @@ impl State<ServerConnectionData> for ExpectCertificate {
             server_kx: self.server_kx,
             client_cert,
             send_ticket: self.send_ticket,
-        }))
+        });
+
+        Ok(match deferred {
+            Some(cert_chain) => {
+                ExpectCertVerification::new(cx.common, cert_chain, next, hs::send_cert_error_alert)
+            }
+            None => next,
+        })
     }
 }
 
@@ -568,7 +776,7 @@ struct ExpectClientKx {
     session_id: SessionID,
     suite: &'static Tls12CipherSuite,
     using_ems: bool,
-    server_kx: kx::KeyExchange,
+    server_kx: Box<dyn ActiveKeyExchange>,
     client_cert: Option<Vec<Certificate>>,
     send_ticket: bool,
 }
@@ -587,11 +795,21 @@ impl State<ServerConnectionData> for ExpectClientKx {
 
         // Complete key agreement, and set up encryption with the
         // resulting premaster secret.
-        let peer_kx_params =
-            tls12::decode_ecdh_params::<ClientECDHParams>(cx.common, &client_kx.0)?;
+        let peer_pub_key = match self.suite.kx {
+            KeyExchangeAlgorithm::DHE => {
+                tls12::decode_kx_params::<ClientDHParams>(cx.common, &client_kx.0)?
+                    .public
+                    .0
+            }
+            _ => {
+                tls12::decode_kx_params::<ClientECDHParams>(cx.common, &client_kx.0)?
+                    .public
+                    .0
+            }
+        };
         let secrets = ConnectionSecrets::from_key_exchange(
             self.server_kx,
-            &peer_kx_params.public.0,
+            &peer_pub_key,
             ems_seed,
             self.randoms,
             self.suite,
@@ -1,20 +1,24 @@
-use crate::check::inappropriate_handshake_message;
+use crate::check::{inappropriate_handshake_message, inappropriate_message};
+use crate::compress;
 #[cfg(feature = "secret_extraction")]
 use crate::conn::Side;
-use crate::conn::{CommonState, ConnectionRandoms, State};
+use crate::conn::{CommonState, ConnectionRandoms, ExpectCertVerification, Protocol, State};
+use crate::ech;
 use crate::enums::ProtocolVersion;
 use crate::error::Error;
 use crate::hash_hs::HandshakeHash;
-use crate::key::Certificate;
+use crate::key::{Certificate, ExternalPsk, SubjectPublicKeyInfo};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
+use crate::msgs::base::PayloadU8;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::{AlertDescription, KeyUpdateRequest};
+use crate::msgs::enums::{AlertDescription, CertificateType, KeyUpdateRequest};
 use crate::msgs::enums::{ContentType, HandshakeType};
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::handshake::HandshakePayload;
+use crate::msgs::handshake::{CertReqExtension, CertificateRequestPayloadTLS13};
 use crate::msgs::handshake::{NewSessionTicketExtension, NewSessionTicketPayloadTLS13};
-use crate::msgs::message::{Message, MessagePayload};
+use crate::msgs::message::{Message, MessagePayload, PlainMessage};
 use crate::msgs::persist;
 use crate::rand;
 use crate::server::ServerConfig;
@@ -24,12 +28,14 @@ use crate::ticketer;
 use crate::tls13::key_schedule::{KeyScheduleTraffic, KeyScheduleTrafficWithClientFinishedPending};
 use crate::tls13::Tls13CipherSuite;
 use crate::verify;
-#[cfg(feature = "quic")]
-use crate::{check::inappropriate_message, conn::Protocol};
 
+#[cfg(feature = "ech_server")]
+use super::ech::{ech_config_list, EchState};
 use super::hs::{self, HandshakeHashOrBuffer, ServerContext};
-use super::server_conn::ServerConnectionData;
+use super::server_conn::{PostHandshakeAuthStatus, ServerConnectionData};
+use super::server_conn::{SessionLookup, SigningKeySource, SigningRequest};
 
+use std::mem;
 use std::sync::Arc;
 
 use ring::constant_time;
@@ -37,19 +43,19 @@ use ring::constant_time;
 pub(super) use client_hello::CompleteClientHelloHandling;
 
 mod client_hello {
+    use crate::compress::CertCompressor;
+    use crate::crypto;
     use crate::enums::SignatureScheme;
-    use crate::kx;
-    use crate::msgs::base::{Payload, PayloadU8};
+    use crate::msgs::base::Payload;
     use crate::msgs::ccs::ChangeCipherSpecPayload;
     use crate::msgs::enums::NamedGroup;
     use crate::msgs::enums::{Compression, PSKKeyExchangeMode};
-    use crate::msgs::handshake::CertReqExtension;
     use crate::msgs::handshake::CertificateEntry;
     use crate::msgs::handshake::CertificateExtension;
     use crate::msgs::handshake::CertificatePayloadTLS13;
-    use crate::msgs::handshake::CertificateRequestPayloadTLS13;
     use crate::msgs::handshake::CertificateStatus;
     use crate::msgs::handshake::ClientHelloPayload;
+    use crate::msgs::handshake::DelegatedCredential;
     use crate::msgs::handshake::DigitallySignedStruct;
     use crate::msgs::handshake::HelloRetryExtension;
     use crate::msgs::handshake::HelloRetryRequest;
@@ -81,6 +87,8 @@ mod client_hello {
         pub(in crate::server) done_retry: bool,
         pub(in crate::server) send_ticket: bool,
         pub(in crate::server) extra_exts: Vec<ServerExtension>,
+        #[cfg(feature = "ech_server")]
+        pub(in crate::server) ech: Option<EchState>,
     }
 
     fn max_early_data_size(configured: u32) -> usize {
@@ -99,12 +107,65 @@ mod client_hello {
     }
 
     impl CompleteClientHelloHandling {
+        /// Choose the kinds of certificate used by us and by the client
+        /// (see RFC7250), returning the client's.
+        fn negotiate_cert_types(
+            &mut self,
+            common: &mut CommonState,
+            client_hello: &ClientHelloPayload,
+            server_cert_type: CertificateType,
+        ) -> Result<CertificateType, Error> {
+            match client_hello.get_server_certificate_types_extension() {
+                Some(offered) if offered.contains(&server_cert_type) => {
+                    self.extra_exts
+                        .push(ServerExtension::ServerCertificateType(server_cert_type));
+                }
+                None if server_cert_type == CertificateType::X509 => {}
+                _ => {
+                    common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+                    return Err(Error::PeerIncompatibleError(
+                        "client does not accept our certificate type".to_string(),
+                    ));
+                }
+            }
+
+            let offered = match client_hello.get_client_certificate_types_extension() {
+                Some(offered) => offered,
+                None => return Ok(CertificateType::X509),
+            };
+
+            let client_cert_type = if offered.contains(&CertificateType::RawPublicKey)
+                && self
+                    .config
+                    .client_raw_key_verifier
+                    .is_some()
+            {
+                CertificateType::RawPublicKey
+            } else if offered.contains(&CertificateType::X509)
+                && self
+                    .config
+                    .offer_client_auth_in_handshake()
+            {
+                CertificateType::X509
+            } else {
+                // We can't use anything the client offered: leave it to
+                // `verifier` to decide whether the client must authenticate.
+                return Ok(CertificateType::X509);
+            };
+
+            self.extra_exts
+                .push(ServerExtension::ClientCertificateType(client_cert_type));
+            Ok(client_cert_type)
+        }
+
         fn check_binder(
             &self,
+            protocol: Protocol,
             suite: &'static Tls13CipherSuite,
             client_hello: &Message,
             psk: &[u8],
             binder: &[u8],
+            external: bool,
         ) -> bool {
             let binder_plaintext = match &client_hello.payload {
                 MessagePayload::Handshake { parsed, .. } => {
@@ -117,37 +178,104 @@ mod client_hello {
                 .transcript
                 .get_hash_given(&binder_plaintext);
 
-            let key_schedule = KeyScheduleEarly::new(suite, psk);
-            let real_binder =
-                key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
+            let key_schedule = KeyScheduleEarly::new(protocol, suite, psk);
+            let real_binder = match external {
+                true => key_schedule.external_psk_binder_key_and_sign_verify_data(&handshake_hash),
+                false => {
+                    key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash)
+                }
+            };
 
             constant_time::verify_slices_are_equal(real_binder.as_ref(), binder).is_ok()
         }
 
-        fn attempt_tls13_ticket_decryption(
-            &mut self,
-            ticket: &[u8],
-        ) -> Option<persist::ServerSessionValue> {
-            if self.config.ticketer.enabled() {
-                self.config
-                    .ticketer
-                    .decrypt(ticket)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
-            } else {
-                self.config
-                    .session_storage
-                    .take(ticket)
-                    .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
+        fn resolve_external_psk(&self, identity: &[u8]) -> Option<Arc<ExternalPsk>> {
+            self.config
+                .psk_resolver
+                .as_ref()?
+                .resolve(identity)
+                .filter(|psk| {
+                    psk.hash_algorithm().algorithm() == self.suite.hash_algorithm().algorithm()
+                })
+        }
+
+        /// We don't have a suitable key share.  Choose a suitable group and
+        /// send a HelloRetryRequest.
+        fn retry_or_fail(
+            mut self,
+            cx: &mut ServerContext<'_>,
+            chm: &Message,
+            groups_ext: &[NamedGroup],
+            early_data_requested: bool,
+        ) -> hs::NextStateOrError {
+            let retry_group_maybe = self
+                .config
+                .kx_groups
+                .iter()
+                .find(|group| groups_ext.contains(&group.name()))
+                .cloned();
+
+            self.transcript.add_message(chm);
+
+            if let Some(group) = retry_group_maybe {
+                if self.done_retry {
+                    return Err(cx
+                        .common
+                        .illegal_param("did not follow retry request"));
+                }
+
+                emit_hello_retry_request(
+                    &mut self.transcript,
+                    self.suite,
+                    cx,
+                    group.name(),
+                    &self.randoms,
+                );
+                emit_fake_ccs(cx.common);
+
+                let skip_early_data = max_early_data_size(self.config.max_early_data_size);
+
+                let next = Box::new(hs::ExpectClientHello {
+                    config: self.config,
+                    transcript: HandshakeHashOrBuffer::Hash(self.transcript),
+                    #[cfg(feature = "tls12")]
+                    session_id: SessionID::empty(),
+                    #[cfg(feature = "tls12")]
+                    using_ems: false,
+                    done_retry: true,
+                    send_ticket: self.send_ticket,
+                    extra_exts: self.extra_exts,
+                    #[cfg(feature = "ech_server")]
+                    ech: self.ech,
+                });
+
+                return if early_data_requested {
+                    Ok(Box::new(ExpectAndSkipRejectedEarlyData {
+                        skip_data_left: skip_early_data,
+                        next,
+                    }))
+                } else {
+                    Ok(next)
+                };
             }
+
+            Err(hs::incompatible(
+                cx.common,
+                "no kx group overlap with client",
+            ))
         }
 
+        /// Handle `client_hello`.  If the handshake was suspended to look
+        /// up the session for one of the client's PSK identities,
+        /// `deferred_lookup` is that identity's index and the result.
         pub(in crate::server) fn handle_client_hello(
             mut self,
             cx: &mut ServerContext<'_>,
-            server_key: ActiveCertifiedKey,
+            server_key: &Arc<sign::CertifiedKey>,
             chm: &Message,
             client_hello: &ClientHelloPayload,
             mut sigschemes_ext: Vec<SignatureScheme>,
+            mut deferred_lookup: Option<(usize, Option<Vec<u8>>)>,
         ) -> hs::NextStateOrError {
             if client_hello.compression_methods.len() != 1 {
                 return Err(cx
@@ -173,6 +301,9 @@ mod client_hello {
             }
 
             let early_data_requested = client_hello.early_data_extension_offered();
+            cx.data.post_handshake_auth_offered = client_hello.post_handshake_auth_offered()
+                && !cx.common.is_quic()
+                && !cx.common.is_dtls();
 
             // EarlyData extension is illegal in second ClientHello
             if self.done_retry && early_data_requested {
@@ -189,71 +320,36 @@ mod client_hello {
                 .find_map(|group| {
                     shares_ext
                         .iter()
-                        .find(|share| share.group == group.name)
+                        .find(|share| share.group == group.name())
                 });
 
-            let chosen_share = match chosen_share {
-                Some(s) => s,
-                None => {
-                    // We don't have a suitable key share.  Choose a suitable group and
-                    // send a HelloRetryRequest.
-                    let retry_group_maybe = self
-                        .config
-                        .kx_groups
-                        .iter()
-                        .find(|group| groups_ext.contains(&group.name))
-                        .cloned();
-
-                    self.transcript.add_message(chm);
-
-                    if let Some(group) = retry_group_maybe {
-                        if self.done_retry {
-                            return Err(cx
-                                .common
-                                .illegal_param("did not follow retry request"));
-                        }
-
-                        emit_hello_retry_request(
-                            &mut self.transcript,
-                            self.suite,
-                            cx.common,
-                            group.name,
-                        );
-                        emit_fake_ccs(cx.common);
-
-                        let skip_early_data = max_early_data_size(self.config.max_early_data_size);
-
-                        let next = Box::new(hs::ExpectClientHello {
-                            config: self.config,
-                            transcript: HandshakeHashOrBuffer::Hash(self.transcript),
-                            #[cfg(feature = "tls12")]
-                            session_id: SessionID::empty(),
-                            #[cfg(feature = "tls12")]
-                            using_ems: false,
-                            done_retry: true,
-                            send_ticket: self.send_ticket,
-                            extra_exts: self.extra_exts,
-                        });
-
-                        return if early_data_requested {
-                            Ok(Box::new(ExpectAndSkipRejectedEarlyData {
-                                skip_data_left: skip_early_data,
-                                next,
-                            }))
-                        } else {
-                            Ok(next)
-                        };
-                    }
+            // Without a suitable key share we need a HelloRetryRequest, unless
+            // the client allows psk_ke.
+            let psk_ke_possible = client_hello.get_psk().is_some()
+                && client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_KE)
+                && self
+                    .config
+                    .psk_kx_modes
+                    .contains(&PSKKeyExchangeMode::PSK_KE);
+            if chosen_share.is_none() && !psk_ke_possible {
+                return self.retry_or_fail(cx, chm, groups_ext, early_data_requested);
+            }
 
-                    return Err(hs::incompatible(
-                        cx.common,
-                        "no kx group overlap with client",
-                    ));
-                }
-            };
+            // Our most preferred PSK key exchange mode the client also offered.
+            // psk_dhe_ke needs a key share.
+            let psk_mode = self
+                .config
+                .psk_kx_modes
+                .iter()
+                .copied()
+                .find(|mode| {
+                    client_hello.psk_mode_offered(*mode)
+                        && (chosen_share.is_some() || *mode == PSKKeyExchangeMode::PSK_KE)
+                });
 
             let mut chosen_psk_index = None;
             let mut resumedata = None;
+            let mut external_psk = None;
             let time_now = ticketer::TimeBase::now()?;
 
             if let Some(psk_offer) = client_hello.get_psk() {
@@ -273,57 +369,121 @@ mod client_hello {
                         .illegal_param("psk extension mismatched ids/binders"));
                 }
 
+                if psk_mode.is_none() {
+                    debug!("Client unwilling to use PSKs with our key exchange modes");
+                }
+
                 for (i, psk_id) in psk_offer.identities.iter().enumerate() {
-                    let resume = match self
-                        .attempt_tls13_ticket_decryption(&psk_id.identity.0)
+                    if psk_mode.is_none() {
+                        break;
+                    }
+
+                    let plain = match deferred_lookup {
+                        // These were tried before the handshake was suspended.
+                        Some((index, _)) if i < index => continue,
+                        Some((index, ref mut stored)) if i == index => stored.take(),
+                        _ if self.config.ticketer.enabled() => self
+                            .config
+                            .ticketer
+                            .decrypt(&psk_id.identity.0),
+                        _ if self.config.defer_session_lookups && !cx.common.is_dtls() => {
+                            cx.data.pending_session_lookup =
+                                Some(SessionLookup::Take(psk_id.identity.0.clone()));
+                            return Ok(Box::new(ExpectSessionLookup {
+                                handling: self,
+                                server_key: Arc::clone(server_key),
+                                client_hello: chm.to_plain(),
+                                sigschemes_ext,
+                                psk_index: i,
+                            }));
+                        }
+                        _ => self
+                            .config
+                            .session_storage
+                            .take(&psk_id.identity.0),
+                    };
+
+                    if let Some(resume) = plain
+                        .and_then(|plain| persist::ServerSessionValue::read_bytes(&plain))
                         .map(|resumedata| {
                             resumedata.set_freshness(psk_id.obfuscated_ticket_age, time_now)
                         })
                         .filter(|resumedata| {
                             hs::can_resume(self.suite.into(), &cx.data.sni, false, resumedata)
-                        }) {
-                        Some(resume) => resume,
-                        None => continue,
-                    };
+                        })
+                    {
+                        if !self.check_binder(
+                            cx.common.protocol,
+                            self.suite,
+                            chm,
+                            &resume.master_secret.0,
+                            &psk_offer.binders[i].0,
+                            false,
+                        ) {
+                            cx.common
+                                .send_fatal_alert(AlertDescription::DecryptError);
+                            return Err(Error::PeerMisbehavedError(
+                                "client sent wrong binder".to_string(),
+                            ));
+                        }
 
-                    if !self.check_binder(
-                        self.suite,
-                        chm,
-                        &resume.master_secret.0,
-                        &psk_offer.binders[i].0,
-                    ) {
-                        cx.common
-                            .send_fatal_alert(AlertDescription::DecryptError);
-                        return Err(Error::PeerMisbehavedError(
-                            "client sent wrong binder".to_string(),
-                        ));
+                        chosen_psk_index = Some(i);
+                        resumedata = Some(resume);
+                        break;
                     }
 
-                    chosen_psk_index = Some(i);
-                    resumedata = Some(resume);
-                    break;
+                    if let Some(psk) = self.resolve_external_psk(&psk_id.identity.0) {
+                        if !self.check_binder(
+                            cx.common.protocol,
+                            self.suite,
+                            chm,
+                            psk.secret(),
+                            &psk_offer.binders[i].0,
+                            true,
+                        ) {
+                            cx.common
+                                .send_fatal_alert(AlertDescription::DecryptError);
+                            return Err(Error::PeerMisbehavedError(
+                                "client sent wrong binder".to_string(),
+                            ));
+                        }
+
+                        chosen_psk_index = Some(i);
+                        external_psk = Some(psk);
+                        break;
+                    }
                 }
             }
 
-            if !client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_DHE_KE) {
-                debug!("Client unwilling to resume, DHE_KE not offered");
-                self.send_ticket = false;
-                chosen_psk_index = None;
-                resumedata = None;
-            } else {
-                self.send_ticket = true;
-            }
+            self.send_ticket = self
+                .config
+                .psk_kx_modes
+                .iter()
+                .any(|mode| client_hello.psk_mode_offered(*mode));
+
+            let chosen_share = match (chosen_share, chosen_psk_index.and(psk_mode)) {
+                (_, Some(PSKKeyExchangeMode::PSK_KE)) => None,
+                (Some(share), _) => Some(share),
+                (None, _) => {
+                    return self.retry_or_fail(cx, chm, groups_ext, early_data_requested);
+                }
+            };
 
             if let Some(ref resume) = resumedata {
                 cx.data.received_resumption_data = Some(resume.application_data.0.clone());
                 cx.common.peer_certificates = resume.client_cert_chain.clone();
             }
 
-            let full_handshake = resumedata.is_none();
+            if external_psk.is_some() {
+                debug!("Client authenticated with external PSK");
+            }
+
+            let full_handshake = resumedata.is_none() && external_psk.is_none();
+            let server_key = ActiveCertifiedKey::from_certified_key(server_key);
             self.transcript.add_message(chm);
             let key_schedule = emit_server_hello(
                 &mut self.transcript,
-                &self.randoms,
+                &mut self.randoms,
                 self.suite,
                 cx,
                 &client_hello.session_id,
@@ -332,12 +492,28 @@ mod client_hello {
                 resumedata
                     .as_ref()
                     .map(|x| &x.master_secret.0[..]),
+                external_psk.as_deref(),
                 &self.config,
             )?;
+            cx.data.external_psk = external_psk;
             if !self.done_retry {
                 emit_fake_ccs(cx.common);
             }
 
+            #[cfg(feature = "ech_server")]
+            if let Some(EchState::Rejected) = self.ech {
+                self.extra_exts
+                    .push(ServerExtension::EncryptedClientHello(ech_config_list(
+                        &self.config.ech_keys,
+                    )));
+            }
+
+            let client_cert_type = if full_handshake {
+                self.negotiate_cert_types(cx.common, client_hello, server_key.get_cert_type())?
+            } else {
+                CertificateType::X509
+            };
+
             let (mut ocsp_response, mut sct_list) =
                 (server_key.get_ocsp(), server_key.get_sct_list());
             let doing_early_data = emit_encrypted_extensions(
@@ -348,31 +524,103 @@ mod client_hello {
                 &mut sct_list,
                 client_hello,
                 resumedata.as_ref(),
-                self.extra_exts,
+                mem::take(&mut self.extra_exts),
                 &self.config,
             )?;
 
             let doing_client_auth = if full_handshake {
-                let client_auth =
-                    emit_certificate_req_tls13(&mut self.transcript, cx, &self.config)?;
+                let client_auth = emit_certificate_req_tls13(
+                    &mut self.transcript,
+                    cx,
+                    &self.config,
+                    client_cert_type,
+                )?;
+                let compressor = client_hello
+                    .get_certificate_compression_extension()
+                    .and_then(|offered| {
+                        compress::choose_compressor(&self.config.cert_compressors, offered)
+                    });
+                // Use a delegated credential if the client accepts both its
+                // signature and the scheme of the delegated key.
+                let delegated_credential = server_key
+                    .get_delegated_credential()
+                    .filter(|dc| {
+                        client_hello
+                            .get_delegated_credential_extension()
+                            .map_or(false, |schemes| schemes.contains(&dc.scheme()))
+                            && sigschemes_ext.contains(&dc.algorithm())
+                    });
                 emit_certificate_tls13(
                     &mut self.transcript,
                     cx.common,
                     server_key.get_cert(),
                     ocsp_response,
                     sct_list,
+                    delegated_credential.map(sign::DelegatedCredential::credential),
+                    compressor,
+                );
+
+                let signer = match delegated_credential {
+                    Some(dc) => dc.key().choose_scheme(&[dc.scheme()]),
+                    None => server_key
+                        .get_key()
+                        .choose_scheme(&sigschemes_ext),
+                }
+                .ok_or_else(|| hs::incompatible(cx.common, "no overlapping sigschemes"))?;
+                let message = verify::construct_tls13_server_verify_message(
+                    &self.transcript.get_current_hash(),
                 );
+
+                if self.config.defer_signing && !cx.common.is_dtls() {
+                    cx.data.pending_signature = Some(SigningRequest {
+                        scheme: signer.scheme(),
+                        message,
+                        cert_chain: server_key.get_cert().to_vec(),
+                        key_source: match delegated_credential {
+                            Some(dc) => SigningKeySource::DelegatedCredential(dc.encoded()),
+                            None => SigningKeySource::Certificate,
+                        },
+                    });
+                    return Ok(Box::new(ExpectSignature {
+                        handling: self,
+                        key_schedule,
+                        doing_early_data,
+                        doing_client_auth: client_auth,
+                        client_cert_type,
+                        scheme: signer.scheme(),
+                    }));
+                }
+
+                let signature = signer.sign(&message)?;
                 emit_certificate_verify_tls13(
                     &mut self.transcript,
                     cx.common,
-                    server_key.get_key(),
-                    &sigschemes_ext,
-                )?;
+                    signer.scheme(),
+                    signature,
+                );
                 client_auth
             } else {
                 false
             };
 
+            self.finish_server_flight(
+                cx,
+                key_schedule,
+                doing_early_data,
+                doing_client_auth,
+                client_cert_type,
+            )
+        }
+
+        /// Send the rest of our first flight, after `CertificateVerify`.
+        fn finish_server_flight(
+            mut self,
+            cx: &mut ServerContext<'_>,
+            key_schedule: KeyScheduleHandshake,
+            doing_early_data: EarlyDataDecision,
+            doing_client_auth: bool,
+            client_cert_type: CertificateType,
+        ) -> hs::NextStateOrError {
             // If we're not doing early data, then the next messages we receive
             // are encrypted with the handshake keys.
             match doing_early_data {
@@ -418,6 +666,7 @@ mod client_hello {
                     suite: self.suite,
                     key_schedule: key_schedule_traffic,
                     send_ticket: self.send_ticket,
+                    client_cert_type,
                 }))
             } else if doing_early_data == EarlyDataDecision::Accepted && !cx.common.is_quic() {
                 // Not used for QUIC: RFC 9001 §8.3: Clients MUST NOT send the EndOfEarlyData
@@ -442,45 +691,173 @@ mod client_hello {
         }
     }
 
+    /// The handshake is suspended until the application looks up the
+    /// session for the PSK identity at `psk_index`.
+    struct ExpectSessionLookup {
+        handling: CompleteClientHelloHandling,
+        server_key: Arc<sign::CertifiedKey>,
+        client_hello: PlainMessage,
+        sigschemes_ext: Vec<SignatureScheme>,
+        psk_index: usize,
+    }
+
+    impl State<ServerConnectionData> for ExpectSessionLookup {
+        fn handle(
+            self: Box<Self>,
+            _cx: &mut ServerContext<'_>,
+            m: Message,
+        ) -> hs::NextStateOrError {
+            Err(inappropriate_message(&m.payload, &[]))
+        }
+
+        fn is_suspended(&self) -> bool {
+            true
+        }
+
+        fn resume(self: Box<Self>, cx: &mut ServerContext<'_>) -> hs::NextStateOrError {
+            let Self {
+                handling,
+                server_key,
+                client_hello,
+                sigschemes_ext,
+                psk_index,
+            } = *self;
+            let chm = Message::try_from(client_hello)?;
+            let client_hello = require_handshake_msg!(
+                chm,
+                HandshakeType::ClientHello,
+                HandshakePayload::ClientHello
+            )?;
+            let stored = cx.data.session_lookup_result.take();
+            handling.handle_client_hello(
+                cx,
+                &server_key,
+                &chm,
+                client_hello,
+                sigschemes_ext,
+                Some((psk_index, stored)),
+            )
+        }
+    }
+
+    /// The handshake is suspended until the application supplies the
+    /// signature for our `CertificateVerify` message.
+    struct ExpectSignature {
+        handling: CompleteClientHelloHandling,
+        key_schedule: KeyScheduleHandshake,
+        doing_early_data: EarlyDataDecision,
+        doing_client_auth: bool,
+        client_cert_type: CertificateType,
+        scheme: SignatureScheme,
+    }
+
+    impl State<ServerConnectionData> for ExpectSignature {
+        fn handle(
+            self: Box<Self>,
+            _cx: &mut ServerContext<'_>,
+            m: Message,
+        ) -> hs::NextStateOrError {
+            Err(inappropriate_message(&m.payload, &[]))
+        }
+
+        fn is_suspended(&self) -> bool {
+            true
+        }
+
+        fn resume(self: Box<Self>, cx: &mut ServerContext<'_>) -> hs::NextStateOrError {
+            let mut handling = self.handling;
+            let signature = cx.data.take_signature()?;
+            emit_certificate_verify_tls13(
+                &mut handling.transcript,
+                cx.common,
+                self.scheme,
+                signature,
+            );
+            handling.finish_server_flight(
+                cx,
+                self.key_schedule,
+                self.doing_early_data,
+                self.doing_client_auth,
+                self.client_cert_type,
+            )
+        }
+    }
+
     fn emit_server_hello(
         transcript: &mut HandshakeHash,
-        randoms: &ConnectionRandoms,
+        randoms: &mut ConnectionRandoms,
         suite: &'static Tls13CipherSuite,
         cx: &mut ServerContext<'_>,
         session_id: &SessionID,
-        share: &KeyShareEntry,
+        share: Option<&KeyShareEntry>,
         chosen_psk_idx: Option<usize>,
         resuming_psk: Option<&[u8]>,
+        external_psk: Option<&ExternalPsk>,
         config: &ServerConfig,
     ) -> Result<KeyScheduleHandshake, Error> {
         let mut extensions = Vec::new();
 
-        // Prepare key exchange
-        let kx = kx::KeyExchange::choose(share.group, &config.kx_groups)
-            .and_then(kx::KeyExchange::start)
-            .ok_or(Error::FailedToGetRandomBytes)?;
+        // Do key exchange, unless we're doing psk_ke
+        let kx = share
+            .map(|share| {
+                crypto::find_kx_group(share.group, &config.kx_groups)
+                    .ok_or(Error::FailedToGetRandomBytes)
+                    .and_then(|group| group.start_and_complete(&share.payload.0))
+            })
+            .transpose()?;
 
-        let kse = KeyShareEntry::new(share.group, kx.pubkey.as_ref());
-        extensions.push(ServerExtension::KeyShare(kse));
-        extensions.push(ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_3));
+        if let Some(kx) = &kx {
+            let kse = KeyShareEntry::new(kx.group, &kx.pub_key);
+            extensions.push(ServerExtension::KeyShare(kse));
+        }
+        extensions.push(ServerExtension::SupportedVersions(
+            cx.common
+                .wire_version(ProtocolVersion::TLSv1_3),
+        ));
 
         if let Some(psk_idx) = chosen_psk_idx {
             extensions.push(ServerExtension::PresharedKey(psk_idx as u16));
         }
 
+        let mut sh = HandshakeMessagePayload {
+            typ: HandshakeType::ServerHello,
+            payload: HandshakePayload::ServerHello(ServerHelloPayload {
+                legacy_version: cx
+                    .common
+                    .wire_version(ProtocolVersion::TLSv1_2),
+                random: Random::from(randoms.server),
+                session_id: *session_id,
+                cipher_suite: suite.common.suite,
+                compression_method: Compression::Null,
+                extensions,
+            }),
+        };
+
+        if cx.data.ech_accepted {
+            // Signal acceptance in the last bytes of our random, computed
+            // over the ServerHello with those bytes zeroed.
+            let confirmation_start = randoms.server.len() - ech::CONFIRMATION_LEN;
+            randoms.server[confirmation_start..].fill(0);
+            if let HandshakePayload::ServerHello(payload) = &mut sh.payload {
+                payload.random = Random::from(randoms.server);
+            }
+
+            let hash = transcript.get_hash_given(&sh.get_encoding());
+            let confirmation = ech::confirmation(
+                suite,
+                &Random::from(randoms.client),
+                ech::SERVER_HELLO_CONFIRMATION_LABEL,
+                &hash,
+            );
+            randoms.server[confirmation_start..].copy_from_slice(&confirmation.0);
+            if let HandshakePayload::ServerHello(payload) = &mut sh.payload {
+                payload.random = Random::from(randoms.server);
+            }
+        }
+
         let sh = Message {
             version: ProtocolVersion::TLSv1_2,
-            payload: MessagePayload::handshake(HandshakeMessagePayload {
-                typ: HandshakeType::ServerHello,
-                payload: HandshakePayload::ServerHello(ServerHelloPayload {
-                    legacy_version: ProtocolVersion::TLSv1_2,
-                    random: Random::from(randoms.server),
-                    session_id: *session_id,
-                    cipher_suite: suite.common.suite,
-                    compression_method: Compression::Null,
-                    extensions,
-                }),
-            }),
+            payload: MessagePayload::handshake(sh),
         };
 
         cx.common.check_aligned_handshake()?;
@@ -493,7 +870,7 @@ mod client_hello {
 
         // Start key schedule
         let key_schedule_pre_handshake = if let Some(psk) = resuming_psk {
-            let early_key_schedule = KeyScheduleEarly::new(suite, psk);
+            let early_key_schedule = KeyScheduleEarly::new(cx.common.protocol, suite, psk);
             early_key_schedule.client_early_traffic_secret(
                 &client_hello_hash,
                 &*config.key_log,
@@ -502,14 +879,20 @@ mod client_hello {
             );
 
             KeySchedulePreHandshake::from(early_key_schedule)
+        } else if let Some(psk) = external_psk {
+            KeySchedulePreHandshake::from(KeyScheduleEarly::new(
+                cx.common.protocol,
+                suite,
+                psk.secret(),
+            ))
         } else {
-            KeySchedulePreHandshake::new(suite)
+            KeySchedulePreHandshake::new(cx.common.protocol, suite)
         };
 
-        // Do key exchange
-        let key_schedule = kx.complete(&share.payload.0, |secret| {
-            Ok(key_schedule_pre_handshake.into_handshake(secret))
-        })?;
+        let key_schedule = match kx {
+            Some(kx) => key_schedule_pre_handshake.into_handshake(kx.secret.secret_bytes()),
+            None => key_schedule_pre_handshake.into_handshake_without_kx(),
+        };
 
         let handshake_hash = transcript.get_current_hash();
         let key_schedule = key_schedule.derive_server_handshake_secrets(
@@ -523,7 +906,7 @@ mod client_hello {
     }
 
     fn emit_fake_ccs(common: &mut CommonState) {
-        if common.is_quic() {
+        if common.is_quic() || common.is_dtls() {
             return;
         }
         let m = Message {
@@ -536,11 +919,14 @@ mod client_hello {
     fn emit_hello_retry_request(
         transcript: &mut HandshakeHash,
         suite: &'static Tls13CipherSuite,
-        common: &mut CommonState,
+        cx: &mut ServerContext<'_>,
         group: NamedGroup,
+        randoms: &ConnectionRandoms,
     ) {
         let mut req = HelloRetryRequest {
-            legacy_version: ProtocolVersion::TLSv1_2,
+            legacy_version: cx
+                .common
+                .wire_version(ProtocolVersion::TLSv1_2),
             session_id: SessionID::empty(),
             cipher_suite: suite.common.suite,
             extensions: Vec::new(),
@@ -550,9 +936,41 @@ mod client_hello {
             .push(HelloRetryExtension::KeyShare(group));
         req.extensions
             .push(HelloRetryExtension::SupportedVersions(
-                ProtocolVersion::TLSv1_3,
+                cx.common
+                    .wire_version(ProtocolVersion::TLSv1_3),
             ));
 
+        transcript.rollup_for_hrr();
+
+        if cx.data.ech_accepted {
+            // As for ServerHello, the signal is computed over the
+            // HelloRetryRequest with the signal zeroed.
+            let zeroed = HelloRetryExtension::EncryptedClientHello(Payload::new(vec![
+                0u8;
+                ech::CONFIRMATION_LEN
+            ]));
+            req.extensions.push(zeroed);
+            let hash = transcript.get_hash_given(
+                &HandshakeMessagePayload {
+                    typ: HandshakeType::HelloRetryRequest,
+                    payload: HandshakePayload::HelloRetryRequest(req.clone()),
+                }
+                .get_encoding(),
+            );
+
+            let confirmation = ech::confirmation(
+                suite,
+                &Random::from(randoms.client),
+                ech::HRR_CONFIRMATION_LABEL,
+                &hash,
+            );
+            req.extensions.pop();
+            req.extensions
+                .push(HelloRetryExtension::EncryptedClientHello(Payload::new(
+                    confirmation.0,
+                )));
+        }
+
         let m = Message {
             version: ProtocolVersion::TLSv1_2,
             payload: MessagePayload::handshake(HandshakeMessagePayload {
@@ -562,9 +980,8 @@ mod client_hello {
         };
 
         trace!("Requesting retry {:?}", m);
-        transcript.rollup_for_hrr();
         transcript.add_message(&m);
-        common.send_msg(m, false);
+        cx.common.send_msg(m, false);
     }
 
     fn decide_if_early_data_allowed(
@@ -589,8 +1006,9 @@ mod client_hello {
         };
 
         /* Non-zero max_early_data_size controls whether early_data is allowed at all.
-         * We also require stateful resumption. */
-        let early_data_configured = config.max_early_data_size > 0 && !config.ticketer.enabled();
+         * We also require stateful resumption, and never accept early data over DTLS. */
+        let early_data_configured =
+            config.max_early_data_size > 0 && !config.ticketer.enabled() && !cx.common.is_dtls();
 
         /* "For PSKs provisioned via NewSessionTicket, a server MUST validate
          *  that the ticket age for the selected PSK identity (computed by
@@ -672,37 +1090,26 @@ mod client_hello {
         transcript: &mut HandshakeHash,
         cx: &mut ServerContext<'_>,
         config: &ServerConfig,
+        client_cert_type: CertificateType,
     ) -> Result<bool, Error> {
-        if !config.verifier.offer_client_auth() {
+        // A raw public key is requested whenever we agreed to accept one.
+        let raw_public_key = client_cert_type == CertificateType::RawPublicKey;
+        if !raw_public_key && !config.offer_client_auth_in_handshake() {
             return Ok(false);
         }
 
-        let mut cr = CertificateRequestPayloadTLS13 {
+        let extensions = certificate_req_extensions(config, raw_public_key).ok_or_else(|| {
+            debug!("could not determine root subjects based on SNI");
+            cx.common
+                .send_fatal_alert(AlertDescription::AccessDenied);
+            Error::General("client rejected by client_auth_root_subjects".into())
+        })?;
+
+        let cr = CertificateRequestPayloadTLS13 {
             context: PayloadU8::empty(),
-            extensions: Vec::new(),
+            extensions,
         };
 
-        let schemes = config
-            .verifier
-            .supported_verify_schemes();
-        cr.extensions
-            .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
-
-        let names = config
-            .verifier
-            .client_auth_root_subjects()
-            .ok_or_else(|| {
-                debug!("could not determine root subjects based on SNI");
-                cx.common
-                    .send_fatal_alert(AlertDescription::AccessDenied);
-                Error::General("client rejected by client_auth_root_subjects".into())
-            })?;
-
-        if !names.is_empty() {
-            cr.extensions
-                .push(CertReqExtension::AuthorityNames(names));
-        }
-
         let m = Message {
             version: ProtocolVersion::TLSv1_3,
             payload: MessagePayload::handshake(HandshakeMessagePayload {
@@ -723,6 +1130,8 @@ mod client_hello {
         cert_chain: &[Certificate],
         ocsp_response: Option<&[u8]>,
         sct_list: Option<&[u8]>,
+        delegated_credential: Option<&DelegatedCredential>,
+        compressor: Option<&dyn CertCompressor>,
     ) {
         let mut cert_entries = vec![];
         for cert in cert_chain {
@@ -750,15 +1159,28 @@ mod client_hello {
                     .exts
                     .push(CertificateExtension::make_sct(sct_list.to_owned()));
             }
+
+            if let Some(dc) = delegated_credential {
+                end_entity_cert
+                    .exts
+                    .push(CertificateExtension::DelegatedCredential(dc.clone()));
+            }
         }
 
         let cert_body = CertificatePayloadTLS13::new(cert_entries);
-        let c = Message {
-            version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::handshake(HandshakeMessagePayload {
+        let payload = match compressor.and_then(|c| compress::compress_certificate(c, &cert_body)) {
+            Some(compressed) => HandshakeMessagePayload {
+                typ: HandshakeType::CompressedCertificate,
+                payload: HandshakePayload::CompressedCertificate(compressed),
+            },
+            None => HandshakeMessagePayload {
                 typ: HandshakeType::Certificate,
                 payload: HandshakePayload::CertificateTLS13(cert_body),
-            }),
+            },
+        };
+        let c = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::handshake(payload),
         };
 
         trace!("sending certificate {:?}", c);
@@ -769,19 +1191,10 @@ mod client_hello {
     fn emit_certificate_verify_tls13(
         transcript: &mut HandshakeHash,
         common: &mut CommonState,
-        signing_key: &dyn sign::SigningKey,
-        schemes: &[SignatureScheme],
-    ) -> Result<(), Error> {
-        let message = verify::construct_tls13_server_verify_message(&transcript.get_current_hash());
-
-        let signer = signing_key
-            .choose_scheme(schemes)
-            .ok_or_else(|| hs::incompatible(common, "no overlapping sigschemes"))?;
-
-        let scheme = signer.scheme();
-        let sig = signer.sign(&message)?;
-
-        let cv = DigitallySignedStruct::new(scheme, sig);
+        scheme: SignatureScheme,
+        signature: Vec<u8>,
+    ) {
+        let cv = DigitallySignedStruct::new(scheme, signature);
 
         let m = Message {
             version: ProtocolVersion::TLSv1_3,
@@ -794,7 +1207,6 @@ mod client_hello {
         trace!("sending certificate-verify {:?}", m);
         transcript.add_message(&m);
         common.send_msg(m, true);
-        Ok(())
     }
 
     fn emit_finished_tls13(
@@ -832,6 +1244,47 @@ mod client_hello {
     }
 }
 
+/// The extensions of a `CertificateRequest`, during or after the handshake.
+///
+/// Returns `None` if the verifier cannot name the root subjects it accepts.
+fn certificate_req_extensions(
+    config: &ServerConfig,
+    raw_public_key: bool,
+) -> Option<Vec<CertReqExtension>> {
+    let mut extensions = Vec::new();
+
+    let schemes = if raw_public_key {
+        verify::RAW_KEY_SCHEMES.to_vec()
+    } else {
+        config
+            .verifier
+            .supported_verify_schemes()
+    };
+    extensions.push(CertReqExtension::SignatureAlgorithms(schemes));
+
+    if !raw_public_key {
+        let names = config
+            .verifier
+            .client_auth_root_subjects()?;
+
+        if !names.is_empty() {
+            extensions.push(CertReqExtension::AuthorityNames(names));
+        }
+    }
+
+    if !config.cert_decompressors.is_empty() {
+        extensions.push(CertReqExtension::CertificateCompressionAlgorithms(
+            config
+                .cert_decompressors
+                .iter()
+                .map(|dec| dec.algorithm())
+                .collect(),
+        ));
+    }
+
+    Some(extensions)
+}
+
 struct ExpectAndSkipRejectedEarlyData {
     skip_data_left: usize,
     next: Box<hs::ExpectClientHello>,
@@ -860,15 +1313,34 @@ struct ExpectCertificate {
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     send_ticket: bool,
+    client_cert_type: CertificateType,
 }
 
 impl State<ServerConnectionData> for ExpectCertificate {
     fn handle(mut self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> hs::NextStateOrError {
-        let certp = require_handshake_msg!(
-            m,
-            HandshakeType::Certificate,
-            HandshakePayload::CertificateTLS13
-        )?;
+        let decompressed;
+        let certp = match &m.payload {
+            MessagePayload::Handshake {
+                parsed:
+                    HandshakeMessagePayload {
+                        payload: HandshakePayload::CompressedCertificate(compressed),
+                        ..
+                    },
+                ..
+            } => {
+                decompressed = compress::decompress_certificate(
+                    cx.common,
+                    &self.config.cert_decompressors,
+                    compressed,
+                )?;
+                &decompressed
+            }
+            _ => require_handshake_msg!(
+                m,
+                HandshakeType::Certificate,
+                HandshakePayload::CertificateTLS13
+            )?,
+        };
         self.transcript.add_message(&m);
 
         // We don't send any CertificateRequest extensions, so any extensions
@@ -880,17 +1352,26 @@ impl State<ServerConnectionData> for ExpectCertificate {
         }
 
         let client_cert = certp.convert();
+        let raw_public_key = self.client_cert_type == CertificateType::RawPublicKey;
 
-        let mandatory = self
-            .config
-            .verifier
-            .client_auth_mandatory()
-            .ok_or_else(|| {
-                debug!("could not determine if client auth is mandatory based on SNI");
-                cx.common
-                    .send_fatal_alert(AlertDescription::AccessDenied);
-                Error::General("client rejected by client_auth_mandatory".into())
-            })?;
+        if raw_public_key && client_cert.len() > 1 {
+            return Err(cx
+                .common
+                .illegal_param("client sent more than one raw public key"));
+        }
+
+        // A client which offered a raw public key must use it.
+        let mandatory = raw_public_key
+            || self
+                .config
+                .verifier
+                .client_auth_mandatory()
+                .ok_or_else(|| {
+                    debug!("could not determine if client auth is mandatory based on SNI");
+                    cx.common
+                        .send_fatal_alert(AlertDescription::AccessDenied);
+                    Error::General("client rejected by client_auth_mandatory".into())
+                })?;
 
         let (end_entity, intermediates) = match client_cert.split_first() {
             None => {
@@ -914,22 +1395,38 @@ impl State<ServerConnectionData> for ExpectCertificate {
         };
 
         let now = std::time::SystemTime::now();
-        self.config
-            .verifier
-            .verify_client_cert(end_entity, intermediates, now)
-            .map_err(|err| {
-                hs::incompatible(cx.common, "certificate invalid");
-                err
-            })?;
+        let mut deferred = None;
+        match (raw_public_key, &self.config.client_raw_key_verifier) {
+            (true, Some(raw_key_verifier)) => raw_key_verifier
+                .verify_client_raw_key(&SubjectPublicKeyInfo(end_entity.0.clone()), now),
+            _ if self.config.defer_cert_verification && !cx.common.is_dtls() => {
+                deferred = Some(client_cert.clone());
+                Ok(())
+            }
+            _ => self
+                .config
+                .verifier
+                .verify_client_cert(end_entity, intermediates, now)
+                .map(|_| ()),
+        }
+        .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
 
-        Ok(Box::new(ExpectCertificateVerify {
+        let next = Box::new(ExpectCertificateVerify {
             config: self.config,
             suite: self.suite,
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             client_cert,
+            client_cert_type: self.client_cert_type,
             send_ticket: self.send_ticket,
-        }))
+        });
+
+        Ok(match deferred {
+            Some(cert_chain) => {
+                ExpectCertVerification::new(cx.common, cert_chain, next, hs::send_cert_error_alert)
+            }
+            None => next,
+        })
     }
 }
 
@@ -939,6 +1436,7 @@ struct ExpectCertificateVerify {
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     client_cert: Vec<Certificate>,
+    client_cert_type: CertificateType,
     send_ticket: bool,
 }
 
@@ -955,9 +1453,15 @@ impl State<ServerConnectionData> for ExpectCertificateVerify {
             let certs = &self.client_cert;
             let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
 
-            self.config
-                .verifier
-                .verify_tls13_signature(&msg, &certs[0], sig)
+            match self.client_cert_type {
+                CertificateType::RawPublicKey => {
+                    verify::verify_tls13_raw_key(self.config.provider, &msg, certs[0].as_ref(), sig)
+                }
+                _ => self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &certs[0], sig),
+            }
         };
 
         if let Err(e) = rc {
@@ -1083,9 +1587,9 @@ impl ExpectFinished {
         key_schedule: &KeyScheduleTraffic,
         config: &ServerConfig,
     ) -> Result<(), Error> {
-        let nonce = rand::random_vec(32)?;
+        let nonce = rand::random_vec(config.provider, 32)?;
         let now = ticketer::TimeBase::now()?;
-        let age_add = rand::random_u32()?;
+        let age_add = rand::random_u32(config.provider)?;
         let plain =
             get_server_session_value(transcript, suite, key_schedule, cx, &nonce, now, age_add)
                 .get_encoding();
@@ -1098,7 +1602,7 @@ impl ExpectFinished {
             };
             (ticket, config.ticketer.lifetime())
         } else {
-            let id = rand::random_vec(32)?;
+            let id = rand::random_vec(config.provider, 32)?;
             let stored = config
                 .session_storage
                 .put(id.clone(), plain);
@@ -1164,6 +1668,10 @@ impl State<ServerConnectionData> for ExpectFinished {
         // main application data keying.
         self.transcript.add_message(&m);
 
+        // Post-handshake authentication continues from here.
+        let mut transcript = self.transcript.clone();
+        transcript.abandon_client_auth();
+
         cx.common.check_aligned_handshake()?;
         if self.send_ticket {
             Self::emit_ticket(
@@ -1189,8 +1697,11 @@ impl State<ServerConnectionData> for ExpectFinished {
         }
 
         Ok(Box::new(ExpectTraffic {
+            config: self.config,
+            transcript,
             key_schedule: key_schedule_traffic,
             want_write_key_update: false,
+            post_handshake_auth: None,
             _fin_verified: fin,
         }))
     }
@@ -1198,12 +1709,171 @@ impl State<ServerConnectionData> for ExpectFinished {
 
 // --- Process traffic ---
 struct ExpectTraffic {
+    config: Arc<ServerConfig>,
+    transcript: HandshakeHash,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    post_handshake_auth: Option<PostHandshakeAuth>,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
+// An outstanding post-handshake CertificateRequest.
+struct PostHandshakeAuth {
+    context: Vec<u8>,
+    transcript: HandshakeHash,
+    stage: PostHandshakeAuthStage,
+}
+
+// The client message we expect next.
+enum PostHandshakeAuthStage {
+    Certificate,
+    CertificateVerify(Vec<Certificate>),
+    Finished(Option<Vec<Certificate>>),
+}
+
 impl ExpectTraffic {
+    fn handle_post_handshake_auth(
+        &mut self,
+        cx: &mut ServerContext<'_>,
+        m: &Message,
+    ) -> Result<(), Error> {
+        let mut auth = match self.post_handshake_auth.take() {
+            Some(auth) => auth,
+            None => {
+                return Err(inappropriate_handshake_message(
+                    &m.payload,
+                    &[ContentType::ApplicationData, ContentType::Handshake],
+                    &[HandshakeType::KeyUpdate],
+                ));
+            }
+        };
+
+        auth.stage = match auth.stage {
+            PostHandshakeAuthStage::Certificate => {
+                let decompressed;
+                let certp = match &m.payload {
+                    MessagePayload::Handshake {
+                        parsed:
+                            HandshakeMessagePayload {
+                                payload: HandshakePayload::CompressedCertificate(compressed),
+                                ..
+                            },
+                        ..
+                    } => {
+                        decompressed = compress::decompress_certificate(
+                            cx.common,
+                            &self.config.cert_decompressors,
+                            compressed,
+                        )?;
+                        &decompressed
+                    }
+                    _ => require_handshake_msg!(
+                        m,
+                        HandshakeType::Certificate,
+                        HandshakePayload::CertificateTLS13
+                    )?,
+                };
+
+                if certp.context.0 != auth.context {
+                    return Err(cx
+                        .common
+                        .illegal_param("client sent wrong certificate request context"));
+                }
+
+                if certp.any_entry_has_extension() {
+                    return Err(Error::PeerMisbehavedError(
+                        "client sent unsolicited cert extension".to_string(),
+                    ));
+                }
+
+                auth.transcript.add_message(m);
+                let client_cert = certp.convert();
+                match client_cert.split_first() {
+                    None => {
+                        let mandatory = self
+                            .config
+                            .verifier
+                            .client_auth_mandatory()
+                            .ok_or_else(|| {
+                                debug!("could not determine if client auth is mandatory");
+                                cx.common
+                                    .send_fatal_alert(AlertDescription::AccessDenied);
+                                Error::General("client rejected by client_auth_mandatory".into())
+                            })?;
+
+                        if mandatory {
+                            cx.common
+                                .send_fatal_alert(AlertDescription::CertificateRequired);
+                            return Err(Error::NoCertificatesPresented);
+                        }
+
+                        debug!("post-handshake client auth requested but no certificate supplied");
+                        PostHandshakeAuthStage::Finished(None)
+                    }
+                    Some((end_entity, intermediates)) => {
+                        let now = std::time::SystemTime::now();
+                        self.config
+                            .verifier
+                            .verify_client_cert(end_entity, intermediates, now)
+                            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                        PostHandshakeAuthStage::CertificateVerify(client_cert)
+                    }
+                }
+            }
+            PostHandshakeAuthStage::CertificateVerify(client_cert) => {
+                let sig = require_handshake_msg!(
+                    m,
+                    HandshakeType::CertificateVerify,
+                    HandshakePayload::CertificateVerify
+                )?;
+                let msg = verify::construct_tls13_client_verify_message(
+                    &auth.transcript.get_current_hash(),
+                );
+                if let Err(e) = self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &client_cert[0], sig)
+                {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::AccessDenied);
+                    return Err(e);
+                }
+
+                trace!("client post-handshake CertificateVerify OK");
+                auth.transcript.add_message(m);
+                PostHandshakeAuthStage::Finished(Some(client_cert))
+            }
+            PostHandshakeAuthStage::Finished(client_cert) => {
+                let finished =
+                    require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
+                cx.common.check_aligned_handshake()?;
+
+                let expect_verify_data = self
+                    .key_schedule
+                    .sign_post_handshake_client_finish(&auth.transcript.get_current_hash());
+                constant_time::verify_slices_are_equal(expect_verify_data.as_ref(), &finished.0)
+                    .map_err(|_| {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::DecryptError);
+                        warn!("post-handshake Finished wrong");
+                        Error::DecryptError
+                    })?;
+
+                cx.data.post_handshake_auth = match client_cert {
+                    Some(client_cert) => {
+                        cx.common.peer_certificates = Some(client_cert);
+                        PostHandshakeAuthStatus::Verified
+                    }
+                    None => PostHandshakeAuthStatus::Declined,
+                };
+                return Ok(());
+            }
+        };
+
+        self.post_handshake_auth = Some(auth);
+        Ok(())
+    }
+
     fn handle_key_update(
         &mut self,
         common: &mut CommonState,
@@ -1219,6 +1889,13 @@ impl ExpectTraffic {
             }
         }
 
+        if common.is_dtls() {
+            common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+            let msg = "KeyUpdate received in DTLS connection".to_string();
+            warn!("{}", msg);
+            return Err(Error::PeerMisbehavedError(msg));
+        }
+
         common.check_aligned_handshake()?;
 
         match kur {
@@ -1253,6 +1930,7 @@ impl State<ServerConnectionData> for ExpectTraffic {
                     },
                 ..
             } => self.handle_key_update(cx.common, &key_update)?,
+            MessagePayload::Handshake { .. } => self.handle_post_handshake_auth(cx, &m)?,
             payload => {
                 return Err(inappropriate_handshake_message(
                     &payload,
@@ -1265,6 +1943,44 @@ impl State<ServerConnectionData> for ExpectTraffic {
         Ok(self)
     }
 
+    fn request_client_certificate(&mut self, cx: &mut ServerContext<'_>) -> Result<(), Error> {
+        if !self.config.verifier.offer_client_auth() {
+            return Err(Error::General(
+                "client authentication is not configured".into(),
+            ));
+        }
+
+        let extensions = certificate_req_extensions(&self.config, false)
+            .ok_or_else(|| Error::General("client rejected by client_auth_root_subjects".into()))?;
+
+        let context = rand::random_vec(self.config.provider, 32)?;
+        let m = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::handshake(HandshakeMessagePayload {
+                typ: HandshakeType::CertificateRequest,
+                payload: HandshakePayload::CertificateRequestTLS13(
+                    CertificateRequestPayloadTLS13 {
+                        context: PayloadU8::new(context.clone()),
+                        extensions,
+                    },
+                ),
+            }),
+        };
+
+        let mut transcript = self.transcript.clone();
+        transcript.add_message(&m);
+        trace!("Sending post-handshake CertificateRequest {:?}", m);
+        cx.common.send_msg(m, true);
+
+        self.post_handshake_auth = Some(PostHandshakeAuth {
+            context,
+            transcript,
+            stage: PostHandshakeAuthStage::Certificate,
+        });
+        cx.data.post_handshake_auth = PostHandshakeAuthStatus::Pending;
+        Ok(())
+    }
+
     fn export_keying_material(
         &self,
         output: &mut [u8],
@@ -1276,13 +1992,31 @@ impl State<ServerConnectionData> for ExpectTraffic {
     }
 
     fn perhaps_write_key_update(&mut self, common: &mut CommonState) {
-        if self.want_write_key_update {
+        if self.want_write_key_update || common.record_layer.wants_key_update() {
             self.want_write_key_update = false;
             self.key_schedule
                 .update_encrypter_and_notify(common);
         }
     }
 
+    fn refresh_traffic_keys(
+        &mut self,
+        common: &mut CommonState,
+        request_peer: bool,
+    ) -> Result<(), Error> {
+        // This also answers any outstanding request from the peer.
+        self.want_write_key_update = false;
+        match request_peer {
+            true => self
+                .key_schedule
+                .update_encrypter_and_request(common),
+            false => self
+                .key_schedule
+                .update_encrypter_and_notify(common),
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "secret_extraction")]
     fn extract_secrets(&self) -> Result<PartiallyExtractedSecrets, Error> {
         self.key_schedule
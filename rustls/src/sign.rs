@@ -1,15 +1,20 @@
 use crate::enums::SignatureScheme;
 use crate::error::Error;
 use crate::key;
-use crate::msgs::enums::SignatureAlgorithm;
-use crate::x509::{wrap_in_asn1_len, wrap_in_sequence};
+use crate::msgs::base::{PayloadU16, PayloadU24};
+use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::enums::{CertificateType, SignatureAlgorithm};
+use crate::msgs::handshake;
+use crate::verify;
+use crate::x509::{self, wrap_in_asn1_len, wrap_in_sequence};
 
 use ring::io::der;
-use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair};
+use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
 
 use std::error::Error as StdError;
 use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// An abstract signing key.
 pub trait SigningKey: Send + Sync {
@@ -21,6 +26,14 @@ pub trait SigningKey: Send + Sync {
 
     /// What kind of key we have.
     fn algorithm(&self) -> SignatureAlgorithm;
+
+    /// The DER-encoded `SubjectPublicKeyInfo` for this key, if known.
+    ///
+    /// This is needed to use the key with raw public key authentication
+    /// (RFC7250) via [`CertifiedKey::from_signing_key`].
+    fn public_key(&self) -> Option<key::SubjectPublicKeyInfo> {
+        None
+    }
 }
 
 /// A thing that can sign a message.
@@ -50,6 +63,20 @@ pub struct CertifiedKey {
     /// certificate is included on those logs.  This must be
     /// a `SignedCertificateTimestampList` encoding; see RFC6962.
     pub sct_list: Option<Vec<u8>>,
+
+    /// What `cert` contains.
+    ///
+    /// For [`CertificateType::X509`] this is a certificate chain.  For
+    /// [`CertificateType::RawPublicKey`] `cert` holds exactly one item:
+    /// the DER-encoded `SubjectPublicKeyInfo` for `key`.  Raw public keys
+    /// can only be used in TLS1.3.
+    pub cert_type: CertificateType,
+
+    /// An optional delegated credential for the end-entity certificate.
+    ///
+    /// This is used instead of `key` to sign TLS1.3 handshakes with
+    /// clients that support it; see [`DelegatedCredential`].
+    pub delegated_credential: Option<DelegatedCredential>,
 }
 
 impl CertifiedKey {
@@ -63,9 +90,33 @@ impl CertifiedKey {
             key,
             ocsp: None,
             sct_list: None,
+            cert_type: CertificateType::X509,
+            delegated_credential: None,
         }
     }
 
+    /// Make a new CertifiedKey which authenticates with the bare public key
+    /// `spki`, rather than a certificate chain (see RFC7250).
+    pub fn new_raw_public_key(spki: key::SubjectPublicKeyInfo, key: Arc<dyn SigningKey>) -> Self {
+        Self {
+            cert: vec![key::Certificate(spki.0)],
+            key,
+            ocsp: None,
+            sct_list: None,
+            cert_type: CertificateType::RawPublicKey,
+            delegated_credential: None,
+        }
+    }
+
+    /// Make a new CertifiedKey which authenticates with the bare public key
+    /// of `key`.
+    ///
+    /// This fails if `key` cannot report its public key.
+    pub fn from_signing_key(key: Arc<dyn SigningKey>) -> Result<Self, SignError> {
+        let spki = key.public_key().ok_or(SignError(()))?;
+        Ok(Self::new_raw_public_key(spki, key))
+    }
+
     /// The end-entity certificate.
     pub fn end_entity_cert(&self) -> Result<&key::Certificate, SignError> {
         self.cert.get(0).ok_or(SignError(()))
@@ -122,8 +173,138 @@ impl CertifiedKey {
     }
 }
 
+/// A delegated credential (RFC9345), and the key it delegates to.
+///
+/// This lets a server sign TLS1.3 handshakes with a short-lived key,
+/// rather than the key of its end-entity certificate.  The certificate
+/// must carry the `DelegationUsage` extension for clients to accept
+/// the credential.
+#[derive(Clone)]
+pub struct DelegatedCredential {
+    credential: handshake::DelegatedCredential,
+    key: Arc<dyn SigningKey>,
+}
+
+impl DelegatedCredential {
+    /// Issue a delegated credential for `key`, valid for `lifetime`
+    /// from `now`.
+    ///
+    /// The credential is signed by `cert_key`, the key of the end-entity
+    /// certificate `end_entity`.  `lifetime` may not exceed seven days,
+    /// and `key` must be able to report its public key.
+    pub fn new(
+        end_entity: &key::Certificate,
+        cert_key: &dyn SigningKey,
+        key: Arc<dyn SigningKey>,
+        lifetime: Duration,
+        now: SystemTime,
+    ) -> Result<Self, Error> {
+        if lifetime.as_secs() > verify::MAX_DELEGATED_CREDENTIAL_VALIDITY {
+            return Err(Error::General(
+                "delegated credential lifetime too long".to_string(),
+            ));
+        }
+
+        let not_before = x509::cert_not_before(&end_entity.0)
+            .ok_or_else(|| Error::General("cannot parse end-entity certificate".to_string()))?;
+        let now = now
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| Error::FailedToGetCurrentTime)?
+            .as_secs();
+        let valid_time = now
+            .checked_sub(not_before)
+            .and_then(|age| u32::try_from(age + lifetime.as_secs()).ok())
+            .ok_or_else(|| {
+                Error::General("end-entity certificate not valid at this time".to_string())
+            })?;
+
+        let public_key = key
+            .public_key()
+            .ok_or_else(|| Error::General("delegated key has no public key".to_string()))?;
+        let dc_cert_verify_algorithm = key
+            .choose_scheme(verify::RAW_KEY_SCHEMES)
+            .ok_or_else(|| Error::General("unsupported delegated key type".to_string()))?
+            .scheme();
+        let cred = handshake::Credential {
+            valid_time,
+            dc_cert_verify_algorithm,
+            public_key: PayloadU24::new(public_key.0),
+        };
+
+        let signer = cert_key
+            .choose_scheme(supported_sign_tls13())
+            .ok_or_else(|| Error::General("unsupported certificate key type".to_string()))?;
+        let algorithm = signer.scheme();
+        let message = verify::construct_delegated_credential_message(end_entity, &cred, algorithm);
+        let signature = signer.sign(&message)?;
+
+        Ok(Self {
+            credential: handshake::DelegatedCredential {
+                cred,
+                algorithm,
+                signature: PayloadU16::new(signature),
+            },
+            key,
+        })
+    }
+
+    /// Use a delegated credential issued elsewhere, in its TLS
+    /// encoding (as produced by [`DelegatedCredential::encoded`]),
+    /// with its corresponding private `key`.
+    ///
+    /// This allows a server to use delegated credentials without access
+    /// to the key of its end-entity certificate.
+    pub fn from_encoded(encoded: &[u8], key: Arc<dyn SigningKey>) -> Result<Self, Error> {
+        let mut rd = Reader::init(encoded);
+        let credential = match handshake::DelegatedCredential::read(&mut rd) {
+            Some(credential) if !rd.any_left() => credential,
+            _ => return Err(Error::General("invalid delegated credential".to_string())),
+        };
+
+        if key
+            .choose_scheme(&[credential.cred.dc_cert_verify_algorithm])
+            .is_none()
+        {
+            return Err(Error::General(
+                "delegated key cannot be used with delegated credential".to_string(),
+            ));
+        }
+
+        Ok(Self { credential, key })
+    }
+
+    /// The TLS encoding of this delegated credential.
+    pub fn encoded(&self) -> Vec<u8> {
+        self.credential.get_encoding()
+    }
+
+    /// The signature scheme the delegated key uses.
+    pub fn scheme(&self) -> SignatureScheme {
+        self.credential
+            .cred
+            .dc_cert_verify_algorithm
+    }
+
+    /// The signature scheme with which the credential itself was signed.
+    pub(crate) fn algorithm(&self) -> SignatureScheme {
+        self.credential.algorithm
+    }
+
+    pub(crate) fn credential(&self) -> &handshake::DelegatedCredential {
+        &self.credential
+    }
+
+    pub(crate) fn key(&self) -> &dyn SigningKey {
+        &*self.key
+    }
+}
+
 /// Parse `der` as any supported key encoding/type, returning
 /// the first which works.
+///
+/// The keys are implemented with *ring*, whatever `CryptoProvider` is
+/// configured; use `CryptoProvider::load_private_key()` to load keys
+/// through a provider.
 pub fn any_supported_type(der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, SignError> {
     if let Ok(rsa) = RsaSigningKey::new(der) {
         Ok(Arc::new(rsa))
@@ -209,6 +390,13 @@ impl SigningKey for RsaSigningKey {
     fn algorithm(&self) -> SignatureAlgorithm {
         SignatureAlgorithm::RSA
     }
+
+    fn public_key(&self) -> Option<key::SubjectPublicKeyInfo> {
+        Some(key::SubjectPublicKeyInfo(x509::make_spki(
+            x509::ALG_ID_RSA_ENCRYPTION,
+            self.key.public_key().as_ref(),
+        )))
+    }
 }
 
 #[allow(clippy::upper_case_acronyms)]
@@ -357,6 +545,18 @@ impl SigningKey for EcdsaSigningKey {
         use crate::msgs::handshake::DecomposedSignatureScheme;
         self.scheme.sign()
     }
+
+    fn public_key(&self) -> Option<key::SubjectPublicKeyInfo> {
+        let alg_id = match self.scheme {
+            SignatureScheme::ECDSA_NISTP256_SHA256 => x509::ALG_ID_ECDSA_P256,
+            SignatureScheme::ECDSA_NISTP384_SHA384 => x509::ALG_ID_ECDSA_P384,
+            _ => unreachable!(), // all constructors are in this file
+        };
+        Some(key::SubjectPublicKeyInfo(x509::make_spki(
+            alg_id,
+            self.key.public_key().as_ref(),
+        )))
+    }
 }
 
 struct EcdsaSigner {
@@ -423,6 +623,13 @@ impl SigningKey for Ed25519SigningKey {
         use crate::msgs::handshake::DecomposedSignatureScheme;
         self.scheme.sign()
     }
+
+    fn public_key(&self) -> Option<key::SubjectPublicKeyInfo> {
+        Some(key::SubjectPublicKeyInfo(x509::make_spki(
+            x509::ALG_ID_ED25519,
+            self.key.public_key().as_ref(),
+        )))
+    }
 }
 
 struct Ed25519Signer {
@@ -1,10 +1,13 @@
 use std::fmt;
 
+use crate::crypto::{aead, hash, SupportedKxGroup};
 use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::msgs::enums::SignatureAlgorithm;
 use crate::msgs::handshake::DecomposedSignatureScheme;
 #[cfg(feature = "tls12")]
 use crate::tls12::Tls12CipherSuite;
+#[cfg(all(feature = "tls12", feature = "aes_ccm"))]
+use crate::tls12::{TLS_ECDHE_ECDSA_WITH_AES_128_CCM, TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8};
 #[cfg(feature = "tls12")]
 use crate::tls12::{
     TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
@@ -16,6 +19,8 @@ use crate::tls12::{
     TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
 };
 use crate::tls13::Tls13CipherSuite;
+#[cfg(feature = "aes_ccm")]
+use crate::tls13::TLS13_AES_128_CCM_SHA256;
 use crate::tls13::{
     TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256,
 };
@@ -35,6 +40,13 @@ pub enum BulkAlgorithm {
 
     /// Chacha20 for confidentiality with poly1305 for authenticity.
     Chacha20Poly1305,
+
+    /// AES with 128-bit keys in counter with CBC-MAC mode.
+    Aes128Ccm,
+
+    /// AES with 128-bit keys in counter with CBC-MAC mode, with a
+    /// truncated 8-byte tag.
+    Aes128Ccm8,
 }
 
 /// Common state for cipher suites (both for TLS 1.2 and TLS 1.3)
@@ -46,7 +58,8 @@ pub struct CipherSuiteCommon {
     /// How to do bulk encryption.
     pub bulk: BulkAlgorithm,
 
-    pub(crate) aead_algorithm: &'static ring::aead::Algorithm,
+    /// The implementation of the AEAD named by `bulk`.
+    pub aead_algorithm: &'static dyn aead::Algorithm,
 }
 
 /// A cipher suite supported by rustls.
@@ -70,7 +83,7 @@ impl fmt::Debug for SupportedCipherSuite {
 
 impl SupportedCipherSuite {
     /// Which hash function to use with this suite.
-    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
+    pub fn hash_algorithm(&self) -> &'static dyn hash::Hash {
         match self {
             #[cfg(feature = "tls12")]
             Self::Tls12(inner) => inner.hash_algorithm(),
@@ -108,6 +121,21 @@ impl SupportedCipherSuite {
         }
     }
 
+    /// Return true if this suite's key exchange can be done with one of
+    /// `kx_groups`.  This resolves to true for all TLS1.3 suites.
+    pub(crate) fn usable_for_kx_groups(
+        &self,
+        _kx_groups: &[&'static dyn SupportedKxGroup],
+    ) -> bool {
+        match self {
+            Self::Tls13(_) => true,
+            #[cfg(feature = "tls12")]
+            Self::Tls12(inner) => _kx_groups
+                .iter()
+                .any(|group| inner.usable_for_kx_group(*group)),
+        }
+    }
+
     /// Return true if this suite is usable for a key only offering `sig_alg`
     /// signatures.  This resolves to true for all TLS1.3 suites.
     pub fn usable_for_signature_algorithm(&self, _sig_alg: SignatureAlgorithm) -> bool {
@@ -123,11 +151,18 @@ impl SupportedCipherSuite {
 }
 
 /// A list of all the cipher suites supported by rustls.
+///
+/// This includes the AES-CCM suites when the `aes_ccm` feature is
+/// enabled.  It does not include the TLS1.2 DHE suites, such as
+/// `TLS_DHE_RSA_WITH_AES_128_GCM_SHA256`, which need a finite-field group
+/// from the `ffdhe` feature; add them explicitly to use them.
 pub static ALL_CIPHER_SUITES: &[SupportedCipherSuite] = &[
     // TLS1.3 suites
     TLS13_AES_256_GCM_SHA384,
     TLS13_AES_128_GCM_SHA256,
     TLS13_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "aes_ccm")]
+    TLS13_AES_128_CCM_SHA256,
     // TLS1.2 suites
     #[cfg(feature = "tls12")]
     TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
@@ -141,13 +176,36 @@ pub static ALL_CIPHER_SUITES: &[SupportedCipherSuite] = &[
     TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
     #[cfg(feature = "tls12")]
     TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(all(feature = "tls12", feature = "aes_ccm"))]
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+    #[cfg(all(feature = "tls12", feature = "aes_ccm"))]
+    TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
 ];
 
 /// The cipher suite configuration that an application should use by default.
 ///
 /// This will be [`ALL_CIPHER_SUITES`] sans any supported cipher suites that
-/// shouldn't be enabled by most applications.
-pub static DEFAULT_CIPHER_SUITES: &[SupportedCipherSuite] = ALL_CIPHER_SUITES;
+/// shouldn't be enabled by most applications.  The AES-CCM suites are left
+/// out: they are only needed by constrained peers.
+pub static DEFAULT_CIPHER_SUITES: &[SupportedCipherSuite] = &[
+    // TLS1.3 suites
+    TLS13_AES_256_GCM_SHA384,
+    TLS13_AES_128_GCM_SHA256,
+    TLS13_CHACHA20_POLY1305_SHA256,
+    // TLS1.2 suites
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    #[cfg(feature = "tls12")]
+    TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
 
 // These both O(N^2)!
 pub(crate) fn choose_ciphersuite_preferring_client(
@@ -319,6 +377,40 @@ mod test {
         .is_none());
     }
 
+    #[test]
+    fn test_default_suites_exclude_ccm() {
+        let is_ccm = |scs: &SupportedCipherSuite| {
+            matches!(
+                scs.common().bulk,
+                BulkAlgorithm::Aes128Ccm | BulkAlgorithm::Aes128Ccm8
+            )
+        };
+
+        for scs in DEFAULT_CIPHER_SUITES {
+            assert!(ALL_CIPHER_SUITES.contains(scs));
+            assert!(!is_ccm(scs));
+        }
+
+        let ccm_suites = ALL_CIPHER_SUITES
+            .iter()
+            .filter(|scs| is_ccm(scs))
+            .count();
+        assert_eq!(
+            ALL_CIPHER_SUITES.len(),
+            DEFAULT_CIPHER_SUITES.len() + ccm_suites
+        );
+
+        #[cfg(feature = "aes_ccm")]
+        assert!(ALL_CIPHER_SUITES.contains(&TLS13_AES_128_CCM_SHA256));
+        #[cfg(all(feature = "tls12", feature = "aes_ccm"))]
+        {
+            assert!(ALL_CIPHER_SUITES.contains(&TLS_ECDHE_ECDSA_WITH_AES_128_CCM));
+            assert!(ALL_CIPHER_SUITES.contains(&TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8));
+        }
+        #[cfg(not(feature = "aes_ccm"))]
+        assert_eq!(ccm_suites, 0);
+    }
+
     #[test]
     fn test_scs_is_debug() {
         println!("{:?}", ALL_CIPHER_SUITES);
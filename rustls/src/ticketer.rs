@@ -1,10 +1,11 @@
-use crate::rand;
+use crate::crypto::aead::{self, NONCE_LEN};
+use crate::crypto::ring::RING;
+use crate::crypto::CryptoProvider;
 use crate::server::ProducesTickets;
 use crate::Error;
 
-use ring::aead;
 use std::mem;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::time;
 
 /// The timebase for expiring and rolling tickets and ticketing
@@ -29,27 +30,29 @@ impl TimeBase {
 }
 
 /// This is a `ProducesTickets` implementation which uses
-/// any *ring* `aead::Algorithm` to encrypt and authentication
+/// any `aead::Algorithm` to encrypt and authentication
 /// the ticket payload.  It does not enforce any lifetime
 /// constraint.
 struct AeadTicketer {
-    alg: &'static aead::Algorithm,
-    key: aead::LessSafeKey,
+    provider: &'static dyn CryptoProvider,
+    alg: &'static dyn aead::Algorithm,
+    key: Box<dyn aead::Key>,
     lifetime: u32,
 }
 
 impl AeadTicketer {
     /// Make a ticketer with recommended configuration and a random key.
-    fn new() -> Result<Self, rand::GetRandomFailed> {
+    fn new(provider: &'static dyn CryptoProvider) -> Result<Self, Error> {
         let mut key = [0u8; 32];
-        rand::fill_random(&mut key)?;
+        provider.fill_random(&mut key)?;
 
-        let alg = &aead::CHACHA20_POLY1305;
-        let key = aead::UnboundKey::new(alg, &key).unwrap();
+        let alg = provider.ticket_aead();
+        let key = alg.new_key(&key)?;
 
         Ok(Self {
+            provider,
             alg,
-            key: aead::LessSafeKey::new(key),
+            key,
             lifetime: 60 * 60 * 12,
         })
     }
@@ -66,17 +69,17 @@ impl ProducesTickets for AeadTicketer {
     /// Encrypt `message` and return the ciphertext.
     fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
         // Random nonce, because a counter is a privacy leak.
-        let mut nonce_buf = [0u8; 12];
-        rand::fill_random(&mut nonce_buf).ok()?;
-        let nonce = ring::aead::Nonce::assume_unique_for_key(nonce_buf);
-        let aad = ring::aead::Aad::empty();
+        let mut nonce_buf = [0u8; NONCE_LEN];
+        self.provider
+            .fill_random(&mut nonce_buf)
+            .ok()?;
 
         let mut ciphertext =
-            Vec::with_capacity(nonce_buf.len() + message.len() + self.key.algorithm().tag_len());
+            Vec::with_capacity(nonce_buf.len() + message.len() + self.alg.tag_len());
         ciphertext.extend(nonce_buf);
         ciphertext.extend(message);
         self.key
-            .seal_in_place_separate_tag(nonce, aad, &mut ciphertext[nonce_buf.len()..])
+            .seal_in_place_separate_tag(nonce_buf, &[], &mut ciphertext[nonce_buf.len()..])
             .map(|tag| {
                 ciphertext.extend(tag.as_ref());
                 ciphertext
@@ -87,19 +90,18 @@ impl ProducesTickets for AeadTicketer {
     /// Decrypt `ciphertext` and recover the original message.
     fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
         // Non-panicking `let (nonce, ciphertext) = ciphertext.split_at(...)`.
-        let nonce = ciphertext.get(..self.alg.nonce_len())?;
+        let nonce = ciphertext.get(..NONCE_LEN)?;
         let ciphertext = ciphertext.get(nonce.len()..)?;
 
         // This won't fail since `nonce` has the required length.
-        let nonce = ring::aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let nonce = nonce.try_into().ok()?;
 
         let mut out = Vec::from(ciphertext);
 
         let plain_len = self
             .key
-            .open_in_place(nonce, aead::Aad::empty(), &mut out)
-            .ok()?
-            .len();
+            .open_in_place(nonce, &[], &mut out)
+            .ok()?;
         out.truncate(plain_len);
 
         Some(out)
@@ -113,11 +115,14 @@ struct TicketSwitcherState {
     next_switch_time: u64,
 }
 
+type Generator = fn(&'static dyn CryptoProvider) -> Result<Box<dyn ProducesTickets>, Error>;
+
 /// A ticketer that has a 'current' sub-ticketer and a single
 /// 'previous' ticketer.  It creates a new ticketer every so
 /// often, demoting the current ticketer.
 struct TicketSwitcher {
-    generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+    provider: &'static dyn CryptoProvider,
+    generator: Generator,
     lifetime: u32,
     state: Mutex<TicketSwitcherState>,
 }
@@ -126,18 +131,20 @@ impl TicketSwitcher {
     /// `lifetime` is in seconds, and is how long the current ticketer
     /// is used to generate new tickets.  Tickets are accepted for no
     /// longer than twice this duration.  `generator` produces a new
-    /// `ProducesTickets` implementation.
+    /// `ProducesTickets` implementation, using `provider`.
     fn new(
         lifetime: u32,
-        generator: fn() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed>,
+        provider: &'static dyn CryptoProvider,
+        generator: Generator,
     ) -> Result<Self, Error> {
         let now = TimeBase::now()?;
         Ok(Self {
+            provider,
             generator,
             lifetime,
             state: Mutex::new(TicketSwitcherState {
-                next: Some(generator()?),
-                current: generator()?,
+                next: Some(generator(provider)?),
+                current: generator(provider)?,
                 previous: None,
                 next_switch_time: now
                     .as_secs()
@@ -205,7 +212,7 @@ impl TicketSwitcher {
         }
 
         // We always need a next, so generate it now
-        let next = (self.generator)().ok()?;
+        let next = (self.generator)(self.provider).ok()?;
         if !are_recovering {
             // Normal path, generate new next and place it in the state
             let mut state = self.state.lock().ok()?;
@@ -215,7 +222,7 @@ impl TicketSwitcher {
             // Recovering, generate also a new current ticketer, and modify state
             // as needed. (we need to redo the time check, otherwise this might
             // result in very rapid switching of ticketers)
-            let new_current = (self.generator)().ok()?;
+            let new_current = (self.generator)(self.provider).ok()?;
             let mut state = self.state.lock().ok()?;
             state.next = Some(next);
             if now > state.next_switch_time {
@@ -261,8 +268,10 @@ impl ProducesTickets for TicketSwitcher {
 /// A concrete, safe ticket creation mechanism.
 pub struct Ticketer {}
 
-fn generate_inner() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
-    Ok(Box::new(AeadTicketer::new()?))
+fn generate_inner(
+    provider: &'static dyn CryptoProvider,
+) -> Result<Box<dyn ProducesTickets>, Error> {
+    Ok(Box::new(AeadTicketer::new(provider)?))
 }
 
 impl Ticketer {
@@ -271,7 +280,208 @@ impl Ticketer {
     ///
     /// The encryption mechanism used in Chacha20Poly1305.
     pub fn new() -> Result<Arc<dyn ProducesTickets>, Error> {
-        Ok(Arc::new(TicketSwitcher::new(6 * 60 * 60, generate_inner)?))
+        Self::new_with_provider(RING)
+    }
+
+    /// Like [`Ticketer::new`], but taking randomness and the ticket
+    /// encryption algorithm from `provider`.
+    pub fn new_with_provider(
+        provider: &'static dyn CryptoProvider,
+    ) -> Result<Arc<dyn ProducesTickets>, Error> {
+        Ok(Arc::new(TicketSwitcher::new(
+            6 * 60 * 60,
+            provider,
+            generate_inner,
+        )?))
+    }
+}
+
+/// A session ticket key, which may be shared by several servers so that
+/// each accepts tickets issued by the others.
+///
+/// Tickets start with the key's 16-byte name, which selects the key used to
+/// decrypt them.  Tickets are encrypted with ChaCha20Poly1305, or with
+/// the provider's [`CryptoProvider::ticket_aead`].
+pub struct TicketKey {
+    provider: &'static dyn CryptoProvider,
+    name: [u8; TICKET_KEY_NAME_LEN],
+    key: Box<dyn aead::Key>,
+}
+
+const TICKET_KEY_NAME_LEN: usize = 16;
+
+impl TicketKey {
+    /// Make a key named `name` from the 32-byte `secret`.
+    ///
+    /// `secret` must be random, and kept secret by all servers sharing
+    /// the key.  `name` must be unique among the keys in use.
+    pub fn new(name: [u8; 16], secret: &[u8; 32]) -> Result<Self, Error> {
+        Self::new_with_provider(RING, name, secret)
+    }
+
+    /// Like [`TicketKey::new`], but taking randomness and the ticket
+    /// encryption algorithm from `provider`.
+    pub fn new_with_provider(
+        provider: &'static dyn CryptoProvider,
+        name: [u8; 16],
+        secret: &[u8; 32],
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            provider,
+            name,
+            key: provider.ticket_aead().new_key(secret)?,
+        })
+    }
+
+    /// Make a key from the contents of an 80-byte key file, in the format
+    /// used by nginx's `ssl_session_ticket_key` and OpenSSL: a 16-byte
+    /// name followed by 64 bytes of secret material.  The last 32 bytes,
+    /// which nginx uses as its AES key, become the ticket encryption key.
+    ///
+    /// Only the key file format is shared: the tickets themselves are
+    /// specific to rustls.  A key file can be generated with
+    /// `openssl rand 80 > ticket.key`.
+    pub fn from_key_file(contents: &[u8]) -> Result<Self, Error> {
+        Self::from_key_file_with_provider(RING, contents)
+    }
+
+    /// Like [`TicketKey::from_key_file`], but taking randomness and the
+    /// ticket encryption algorithm from `provider`.
+    pub fn from_key_file_with_provider(
+        provider: &'static dyn CryptoProvider,
+        contents: &[u8],
+    ) -> Result<Self, Error> {
+        let bad_length =
+            || Error::General("session ticket key file must be 80 bytes long".to_string());
+        if contents.len() != 80 {
+            return Err(bad_length());
+        }
+        let (name, secret) = contents.split_at(TICKET_KEY_NAME_LEN);
+
+        Self::new_with_provider(
+            provider,
+            name.try_into()
+                .map_err(|_| bad_length())?,
+            secret[32..]
+                .try_into()
+                .map_err(|_| bad_length())?,
+        )
+    }
+
+    /// The name identifying this key in tickets.
+    pub fn name(&self) -> &[u8; 16] {
+        &self.name
+    }
+
+    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
+        // Random nonce, because a counter is a privacy leak.
+        let mut nonce_buf = [0u8; NONCE_LEN];
+        self.provider
+            .fill_random(&mut nonce_buf)
+            .ok()?;
+
+        let header_len = self.name.len() + nonce_buf.len();
+        let mut ticket = Vec::with_capacity(header_len + message.len() + aead::Tag::MAX_LEN);
+        ticket.extend(self.name);
+        ticket.extend(nonce_buf);
+        ticket.extend(message);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce_buf, &self.name, &mut ticket[header_len..])
+            .ok()?;
+        ticket.extend(tag.as_ref());
+        Some(ticket)
+    }
+
+    /// Decrypt `ticket`, which must have been encrypted with this key.
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let nonce = ticket.get(self.name.len()..self.name.len() + NONCE_LEN)?;
+        let mut out = Vec::from(ticket.get(self.name.len() + NONCE_LEN..)?);
+
+        let plain_len = self
+            .key
+            .open_in_place(nonce.try_into().ok()?, &self.name, &mut out)
+            .ok()?;
+        out.truncate(plain_len);
+
+        Some(out)
+    }
+}
+
+/// A `ProducesTickets` implementation using externally supplied
+/// [`TicketKey`]s, for servers which share session ticket keys.
+///
+/// New tickets are encrypted with the current key.  Tickets are accepted
+/// if they were encrypted with the current key or with one of the other
+/// keys given.  Unlike [`Ticketer`], this never changes keys by itself:
+/// the keys should be replaced regularly with [`SharedTicketer::rotate`].
+pub struct SharedTicketer {
+    lifetime: u32,
+    keys: Mutex<SharedTicketerKeys>,
+}
+
+struct SharedTicketerKeys {
+    current: TicketKey,
+    decrypt_only: Vec<TicketKey>,
+}
+
+impl SharedTicketer {
+    /// Make a ticketer which encrypts new tickets with `current`, and
+    /// also accepts tickets encrypted with any of `decrypt_only`.
+    ///
+    /// `lifetime` is how long, in seconds, clients are told tickets remain
+    /// valid.  It should not exceed how long each key remains in use.
+    pub fn new(current: TicketKey, decrypt_only: Vec<TicketKey>, lifetime: u32) -> Arc<Self> {
+        Arc::new(Self {
+            lifetime,
+            keys: Mutex::new(SharedTicketerKeys {
+                current,
+                decrypt_only,
+            }),
+        })
+    }
+
+    /// Replace all keys.  Typically, the previous current key is kept as
+    /// one of `decrypt_only`, so recently issued tickets stay valid.
+    pub fn rotate(&self, current: TicketKey, decrypt_only: Vec<TicketKey>) {
+        *self
+            .keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = SharedTicketerKeys {
+            current,
+            decrypt_only,
+        };
+    }
+}
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
+        self.keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .current
+            .encrypt(message)
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let name = ciphertext.get(..TICKET_KEY_NAME_LEN)?;
+        let keys = self
+            .keys
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        std::iter::once(&keys.current)
+            .chain(keys.decrypt_only.iter())
+            .find(|key| key.name == name)?
+            .decrypt(ciphertext)
     }
 }
 
@@ -286,7 +496,7 @@ fn basic_pairwise_test() {
 
 #[test]
 fn ticketswitcher_switching_test() {
-    let t = Arc::new(TicketSwitcher::new(1, generate_inner).unwrap());
+    let t = Arc::new(TicketSwitcher::new(1, RING, generate_inner).unwrap());
     let now = TimeBase::now().unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
@@ -308,13 +518,15 @@ fn ticketswitcher_switching_test() {
 }
 
 #[cfg(test)]
-fn fail_generator() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
-    Err(rand::GetRandomFailed)
+fn fail_generator(
+    _provider: &'static dyn CryptoProvider,
+) -> Result<Box<dyn ProducesTickets>, Error> {
+    Err(Error::FailedToGetRandomBytes)
 }
 
 #[test]
 fn ticketswitcher_recover_test() {
-    let mut t = TicketSwitcher::new(1, generate_inner).unwrap();
+    let mut t = TicketSwitcher::new(1, RING, generate_inner).unwrap();
     let now = TimeBase::now().unwrap();
     let cipher1 = t.encrypt(b"ticket 1").unwrap();
     assert_eq!(t.decrypt(&cipher1).unwrap(), b"ticket 1");
@@ -336,3 +548,91 @@ fn ticketswitcher_recover_test() {
     assert_eq!(t.decrypt(&cipher2).unwrap(), b"ticket 2");
     assert_eq!(t.decrypt(&cipher3).unwrap(), b"ticket 3");
 }
+
+#[test]
+fn shared_ticketer_test() {
+    let key = |name: u8| TicketKey::new([name; 16], &[name; 32]).unwrap();
+
+    // Servers sharing a key accept each other's tickets.
+    let t1 = SharedTicketer::new(key(1), vec![], 3600);
+    let t2 = SharedTicketer::new(key(1), vec![key(0)], 3600);
+    assert!(t1.enabled());
+    assert_eq!(t1.lifetime(), 3600);
+    let cipher1 = t1.encrypt(b"ticket 1").unwrap();
+    assert_eq!(&cipher1[..16], &[1; 16]);
+    assert_eq!(t2.decrypt(&cipher1).unwrap(), b"ticket 1");
+
+    // After rotation, tickets from the previous key are still accepted.
+    t1.rotate(key(2), vec![key(1)]);
+    let cipher2 = t1.encrypt(b"ticket 2").unwrap();
+    assert_eq!(&cipher2[..16], &[2; 16]);
+    assert_eq!(t1.decrypt(&cipher1).unwrap(), b"ticket 1");
+    assert_eq!(t1.decrypt(&cipher2).unwrap(), b"ticket 2");
+    assert!(t2.decrypt(&cipher2).is_none());
+
+    t1.rotate(key(3), vec![key(2)]);
+    assert!(t1.decrypt(&cipher1).is_none());
+    assert_eq!(t1.decrypt(&cipher2).unwrap(), b"ticket 2");
+}
+
+#[test]
+fn shared_ticketer_rejects_bad_tickets() {
+    let t = SharedTicketer::new(TicketKey::new([1; 16], &[1; 32]).unwrap(), vec![], 3600);
+    let cipher = t.encrypt(b"ticket").unwrap();
+
+    // The key name is authenticated.
+    let other = SharedTicketer::new(TicketKey::new([2; 16], &[1; 32]).unwrap(), vec![], 3600);
+    let mut renamed = cipher.clone();
+    renamed[..16].copy_from_slice(&[2; 16]);
+    assert!(other.decrypt(&renamed).is_none());
+
+    let mut tampered = cipher.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(t.decrypt(&tampered).is_none());
+
+    for len in 0..cipher.len() {
+        assert!(t.decrypt(&cipher[..len]).is_none());
+    }
+}
+
+#[test]
+fn shared_ticketer_survives_poisoning() {
+    let t = SharedTicketer::new(TicketKey::new([1; 16], &[1; 32]).unwrap(), vec![], 3600);
+    let cipher = t.encrypt(b"ticket").unwrap();
+
+    let poisoner = Arc::clone(&t);
+    std::thread::spawn(move || {
+        let _keys = poisoner.keys.lock().unwrap();
+        panic!("poison the lock");
+    })
+    .join()
+    .unwrap_err();
+    assert!(t.keys.is_poisoned());
+
+    assert_eq!(t.decrypt(&cipher).unwrap(), b"ticket");
+    assert!(t.encrypt(b"ticket").is_some());
+}
+
+#[test]
+fn ticket_key_file_test() {
+    let mut contents = [0u8; 80];
+    contents[..16].copy_from_slice(b"0123456789abcdef");
+    contents[16..].copy_from_slice(&[0x42; 64]);
+
+    let key = TicketKey::from_key_file(&contents).unwrap();
+    assert_eq!(key.name(), b"0123456789abcdef");
+
+    // Another server reading the same file can decrypt tickets.
+    let t1 = SharedTicketer::new(key, vec![], 3600);
+    let t2 = SharedTicketer::new(TicketKey::from_key_file(&contents).unwrap(), vec![], 3600);
+    let cipher = t1.encrypt(b"ticket").unwrap();
+    assert_eq!(t2.decrypt(&cipher).unwrap(), b"ticket");
+
+    // ... but not if the secret differs.
+    contents[79] ^= 1;
+    let t3 = SharedTicketer::new(TicketKey::from_key_file(&contents).unwrap(), vec![], 3600);
+    assert!(t3.decrypt(&cipher).is_none());
+
+    assert!(TicketKey::from_key_file(&contents[..48]).is_err());
+    assert!(TicketKey::from_key_file(&[0; 81]).is_err());
+}
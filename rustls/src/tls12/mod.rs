@@ -1,7 +1,7 @@
 use crate::cipher::{MessageDecrypter, MessageEncrypter};
 use crate::conn::{CommonState, ConnectionRandoms, Side};
-use crate::enums::{CipherSuite, SignatureScheme};
-use crate::kx;
+use crate::crypto::{aead, hash, hmac, ring, ActiveKeyExchange, SupportedKxGroup};
+use crate::enums::{CipherSuite, ProtocolVersion, SignatureScheme};
 use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{AlertDescription, ContentType};
 use crate::msgs::handshake::KeyExchangeAlgorithm;
@@ -10,13 +10,9 @@ use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
 use crate::suites::{ConnectionTrafficSecrets, PartiallyExtractedSecrets};
 use crate::Error;
 
-use ring::aead;
-use ring::digest::Digest;
-
 use std::fmt;
 
 mod cipher;
-pub(crate) use cipher::{AesGcm, ChaCha20Poly1305, Tls12AeadAlgorithm};
 
 mod prf;
 
@@ -32,8 +28,7 @@ pub static TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         sign: TLS12_ECDSA_SCHEMES,
         fixed_iv_len: 12,
         explicit_nonce_len: 0,
-        aead_alg: &ChaCha20Poly1305,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hmac: &ring::hmac::HMAC_SHA256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
@@ -48,8 +43,7 @@ pub static TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256: SupportedCipherSuite =
         sign: TLS12_RSA_SCHEMES,
         fixed_iv_len: 12,
         explicit_nonce_len: 0,
-        aead_alg: &ChaCha20Poly1305,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hmac: &ring::hmac::HMAC_SHA256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
@@ -64,8 +58,7 @@ pub static TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
         sign: TLS12_RSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
-        aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hmac: &ring::hmac::HMAC_SHA256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
@@ -80,8 +73,7 @@ pub static TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         sign: TLS12_RSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
-        aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA384,
+        hmac: &ring::hmac::HMAC_SHA384,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
@@ -96,8 +88,7 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
         sign: TLS12_ECDSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
-        aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA256,
+        hmac: &ring::hmac::HMAC_SHA256,
     });
 
 /// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
@@ -112,8 +103,75 @@ pub static TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
         sign: TLS12_ECDSA_SCHEMES,
         fixed_iv_len: 4,
         explicit_nonce_len: 8,
-        aead_alg: &AesGcm,
-        hmac_algorithm: ring::hmac::HMAC_SHA384,
+        hmac: &ring::hmac::HMAC_SHA384,
+    });
+
+/// The TLS1.2 ciphersuite TLS_DHE_RSA_WITH_AES_128_GCM_SHA256
+///
+/// This needs one of the finite-field groups, such as `kx_group::FFDHE2048`
+/// from the `ffdhe` feature, to be configured.
+pub static TLS_DHE_RSA_WITH_AES_128_GCM_SHA256: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256,
+            bulk: BulkAlgorithm::Aes128Gcm,
+            aead_algorithm: &ring::aead::AES_128_GCM,
+        },
+        kx: KeyExchangeAlgorithm::DHE,
+        sign: TLS12_RSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        hmac: &ring::hmac::HMAC_SHA256,
+    });
+
+/// The TLS1.2 ciphersuite TLS_DHE_RSA_WITH_AES_256_GCM_SHA384
+///
+/// This needs one of the finite-field groups, such as `kx_group::FFDHE2048`
+/// from the `ffdhe` feature, to be configured.
+pub static TLS_DHE_RSA_WITH_AES_256_GCM_SHA384: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_DHE_RSA_WITH_AES_256_GCM_SHA384,
+            bulk: BulkAlgorithm::Aes256Gcm,
+            aead_algorithm: &ring::aead::AES_256_GCM,
+        },
+        kx: KeyExchangeAlgorithm::DHE,
+        sign: TLS12_RSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        hmac: &ring::hmac::HMAC_SHA384,
+    });
+
+/// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_CCM
+#[cfg(feature = "aes_ccm")]
+pub static TLS_ECDHE_ECDSA_WITH_AES_128_CCM: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+            bulk: BulkAlgorithm::Aes128Ccm,
+            aead_algorithm: &crate::crypto::aes_ccm::AES_128_CCM,
+        },
+        kx: KeyExchangeAlgorithm::ECDHE,
+        sign: TLS12_ECDSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        hmac: &ring::hmac::HMAC_SHA256,
+    });
+
+/// The TLS1.2 ciphersuite TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8
+#[cfg(feature = "aes_ccm")]
+pub static TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8: SupportedCipherSuite =
+    SupportedCipherSuite::Tls12(&Tls12CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
+            bulk: BulkAlgorithm::Aes128Ccm8,
+            aead_algorithm: &crate::crypto::aes_ccm::AES_128_CCM_8,
+        },
+        kx: KeyExchangeAlgorithm::ECDHE,
+        sign: TLS12_ECDSA_SCHEMES,
+        fixed_iv_len: 4,
+        explicit_nonce_len: 8,
+        hmac: &ring::hmac::HMAC_SHA256,
     });
 
 static TLS12_ECDSA_SCHEMES: &[SignatureScheme] = &[
@@ -136,7 +194,10 @@ static TLS12_RSA_SCHEMES: &[SignatureScheme] = &[
 pub struct Tls12CipherSuite {
     /// Common cipher suite fields.
     pub common: CipherSuiteCommon,
-    pub(crate) hmac_algorithm: ring::hmac::Algorithm,
+
+    /// The HMAC underlying this suite's PRF.
+    pub hmac: &'static dyn hmac::Hmac,
+
     /// How to exchange/agree keys.
     pub kx: KeyExchangeAlgorithm,
 
@@ -153,9 +214,12 @@ pub struct Tls12CipherSuite {
     /// key block to provide an initial explicit nonce offset,
     /// in a deterministic and safe way.  GCM needs this,
     /// chacha20poly1305 works this way by design.
+    ///
+    /// This also decides the record protection: suites with an explicit
+    /// nonce send 8 bytes of it with each record, and must have a
+    /// `fixed_iv_len` of 4.  Otherwise, `fixed_iv_len` must be 12, and
+    /// the nonce is derived from it and the sequence number.
     pub explicit_nonce_len: usize,
-
-    pub(crate) aead_alg: &'static dyn Tls12AeadAlgorithm,
 }
 
 impl Tls12CipherSuite {
@@ -171,8 +235,14 @@ impl Tls12CipherSuite {
     }
 
     /// Which hash function to use with this suite.
-    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        self.hmac_algorithm.digest_algorithm()
+    pub fn hash_algorithm(&self) -> &'static dyn hash::Hash {
+        self.hmac.hash()
+    }
+
+    /// Whether `group` can provide this suite's key exchange.
+    pub(crate) fn usable_for_kx_group(&self, group: &dyn SupportedKxGroup) -> bool {
+        group.usable_for_version(ProtocolVersion::TLSv1_2)
+            && group.name().key_exchange_algorithm() == self.kx
     }
 }
 
@@ -206,9 +276,9 @@ pub(crate) struct ConnectionSecrets {
 
 impl ConnectionSecrets {
     pub(crate) fn from_key_exchange(
-        kx: kx::KeyExchange,
+        kx: Box<dyn ActiveKeyExchange>,
         peer_pub_key: &[u8],
-        ems_seed: Option<Digest>,
+        ems_seed: Option<hash::Output>,
         randoms: ConnectionRandoms,
         suite: &'static Tls12CipherSuite,
     ) -> Result<Self, Error> {
@@ -226,16 +296,22 @@ impl ConnectionSecrets {
             ),
         };
 
-        kx.complete(peer_pub_key, |secret| {
-            prf::prf(
-                &mut ret.master_secret,
-                suite.hmac_algorithm,
-                secret,
-                label.as_bytes(),
-                seed.as_ref(),
-            );
-            Ok(())
-        })?;
+        let secret = kx.complete(peer_pub_key)?;
+        let mut secret = secret.secret_bytes();
+        if suite.kx == KeyExchangeAlgorithm::DHE {
+            // RFC5246 section 8.1.2: leading zero bytes of a DH secret are
+            // stripped before use (unlike in TLS1.3).
+            while let Some((0, rest)) = secret.split_first() {
+                secret = rest;
+            }
+        }
+        prf::prf(
+            &mut ret.master_secret,
+            suite.hmac,
+            secret,
+            label.as_bytes(),
+            seed.as_ref(),
+        );
 
         Ok(ret)
     }
@@ -260,13 +336,12 @@ impl ConnectionSecrets {
     pub(crate) fn make_cipher_pair(&self, side: Side) -> MessageCipherPair {
         fn split_key<'a>(
             key_block: &'a [u8],
-            alg: &'static aead::Algorithm,
-        ) -> (aead::LessSafeKey, &'a [u8]) {
+            alg: &'static dyn aead::Algorithm,
+        ) -> (Box<dyn aead::Key>, &'a [u8]) {
             // Might panic if the key block is too small.
             let (key, rest) = key_block.split_at(alg.key_len());
             // Won't panic because its only prerequisite is that `key` is `alg.key_len()` bytes long.
-            let key = aead::UnboundKey::new(alg, key).unwrap();
-            (aead::LessSafeKey::new(key), rest)
+            (alg.new_key(key).unwrap(), rest)
         }
 
         // Make a key block, and chop it up.
@@ -297,12 +372,8 @@ impl ConnectionSecrets {
         };
 
         (
-            suite
-                .aead_alg
-                .decrypter(read_key, read_iv),
-            suite
-                .aead_alg
-                .encrypter(write_key, write_iv, extra),
+            cipher::decrypter(suite, read_key, read_iv),
+            cipher::encrypter(suite, write_key, write_iv, extra),
         )
     }
 
@@ -321,7 +392,7 @@ impl ConnectionSecrets {
         let randoms = join_randoms(&self.randoms.server, &self.randoms.client);
         prf::prf(
             &mut out,
-            self.suite.hmac_algorithm,
+            self.suite.hmac,
             &self.master_secret,
             b"key expansion",
             &randoms,
@@ -340,13 +411,13 @@ impl ConnectionSecrets {
         ret
     }
 
-    fn make_verify_data(&self, handshake_hash: &Digest, label: &[u8]) -> Vec<u8> {
+    fn make_verify_data(&self, handshake_hash: &hash::Output, label: &[u8]) -> Vec<u8> {
         let mut out = Vec::new();
         out.resize(12, 0u8);
 
         prf::prf(
             &mut out,
-            self.suite.hmac_algorithm,
+            self.suite.hmac,
             &self.master_secret,
             label,
             handshake_hash.as_ref(),
@@ -354,11 +425,11 @@ impl ConnectionSecrets {
         out
     }
 
-    pub(crate) fn client_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn client_verify_data(&self, handshake_hash: &hash::Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"client finished")
     }
 
-    pub(crate) fn server_verify_data(&self, handshake_hash: &Digest) -> Vec<u8> {
+    pub(crate) fn server_verify_data(&self, handshake_hash: &hash::Output) -> Vec<u8> {
         self.make_verify_data(handshake_hash, b"server finished")
     }
 
@@ -379,7 +450,7 @@ impl ConnectionSecrets {
 
         prf::prf(
             output,
-            self.suite.hmac_algorithm,
+            self.suite.hmac,
             &self.master_secret,
             label,
             &randoms,
@@ -393,6 +464,7 @@ impl ConnectionSecrets {
 
         let suite = self.suite;
         let algo = suite.common.aead_algorithm;
+        let bulk = &suite.common.bulk;
 
         let (client_key, key_block) = key_block.split_at(algo.key_len());
         let (server_key, key_block) = key_block.split_at(algo.key_len());
@@ -414,7 +486,7 @@ impl ConnectionSecrets {
             iv: server_iv,
         };
 
-        let (client_secrets, server_secrets) = if algo == &ring::aead::AES_128_GCM {
+        let (client_secrets, server_secrets) = if bulk == &BulkAlgorithm::Aes128Gcm {
             let extract = |pair: Pair| -> ConnectionTrafficSecrets {
                 let mut key = [0u8; 16];
                 key.copy_from_slice(pair.key);
@@ -429,7 +501,7 @@ impl ConnectionSecrets {
             };
 
             (extract(client_pair), extract(server_pair))
-        } else if algo == &ring::aead::AES_256_GCM {
+        } else if bulk == &BulkAlgorithm::Aes256Gcm {
             let extract = |pair: Pair| -> ConnectionTrafficSecrets {
                 let mut key = [0u8; 32];
                 key.copy_from_slice(pair.key);
@@ -444,7 +516,7 @@ impl ConnectionSecrets {
             };
 
             (extract(client_pair), extract(server_pair))
-        } else if algo == &ring::aead::CHACHA20_POLY1305 {
+        } else if bulk == &BulkAlgorithm::Chacha20Poly1305 {
             let extract = |pair: Pair| -> ConnectionTrafficSecrets {
                 let mut key = [0u8; 32];
                 key.copy_from_slice(pair.key);
@@ -472,7 +544,7 @@ impl ConnectionSecrets {
 }
 
 enum Seed {
-    Ems(Digest),
+    Ems(hash::Output),
     Randoms([u8; 64]),
 }
 
@@ -494,17 +566,17 @@ fn join_randoms(first: &[u8; 32], second: &[u8; 32]) -> [u8; 64] {
 
 type MessageCipherPair = (Box<dyn MessageDecrypter>, Box<dyn MessageEncrypter>);
 
-pub(crate) fn decode_ecdh_params<T: Codec>(
+pub(crate) fn decode_kx_params<T: Codec>(
     common: &mut CommonState,
     kx_params: &[u8],
 ) -> Result<T, Error> {
-    decode_ecdh_params_::<T>(kx_params).ok_or_else(|| {
+    decode_kx_params_::<T>(kx_params).ok_or_else(|| {
         common.send_fatal_alert(AlertDescription::DecodeError);
         Error::CorruptMessagePayload(ContentType::Handshake)
     })
 }
 
-fn decode_ecdh_params_<T: Codec>(kx_params: &[u8]) -> Option<T> {
+fn decode_kx_params_<T: Codec>(kx_params: &[u8]) -> Option<T> {
     let mut rd = Reader::init(kx_params);
     let ecdh_params = T::read(&mut rd)?;
     match rd.any_left() {
@@ -518,20 +590,21 @@ pub(crate) const DOWNGRADE_SENTINEL: [u8; 8] = [0x44, 0x4f, 0x57, 0x4e, 0x47, 0x
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::crypto::SupportedKxGroup;
     use crate::msgs::handshake::{ClientECDHParams, ServerECDHParams};
 
     #[test]
     fn server_ecdhe_remaining_bytes() {
-        let key = kx::KeyExchange::start(&kx::X25519).unwrap();
-        let server_params = ServerECDHParams::new(key.group(), key.pubkey.as_ref());
+        let key = ring::kx::X25519.start().unwrap();
+        let server_params = ServerECDHParams::new(key.group(), key.pub_key());
         let mut server_buf = Vec::new();
         server_params.encode(&mut server_buf);
         server_buf.push(34);
-        assert!(decode_ecdh_params_::<ServerECDHParams>(&server_buf).is_none());
+        assert!(decode_kx_params_::<ServerECDHParams>(&server_buf).is_none());
     }
 
     #[test]
     fn client_ecdhe_invalid() {
-        assert!(decode_ecdh_params_::<ClientECDHParams>(&[34]).is_none());
+        assert!(decode_kx_params_::<ClientECDHParams>(&[34]).is_none());
     }
 }
@@ -1,28 +1,23 @@
-use crate::cipher::{Iv, IvLen, MessageDecrypter};
-use crate::conn::{CommonState, Side};
+use crate::cipher::{Iv, MessageDecrypter};
+use crate::conn::{CommonState, Protocol, Side};
+use crate::crypto::aead::{self, NONCE_LEN};
+use crate::crypto::hkdf::{OutputLengthError, Prk};
+use crate::crypto::{hash, hmac};
 use crate::error::Error;
-use crate::msgs::base::PayloadU8;
 use crate::msgs::message::Message;
 #[cfg(feature = "quic")]
 use crate::quic;
 #[cfg(feature = "secret_extraction")]
-use crate::suites::{ConnectionTrafficSecrets, PartiallyExtractedSecrets};
+use crate::suites::{BulkAlgorithm, ConnectionTrafficSecrets, PartiallyExtractedSecrets};
 use crate::{KeyLog, Tls13CipherSuite};
 
-/// Key schedule maintenance for TLS1.3
-use ring::{
-    aead,
-    digest::{self, Digest},
-    hkdf::{self, KeyType as _},
-    hmac,
-};
-
 use super::{Tls13MessageDecrypter, Tls13MessageEncrypter};
 
 /// The kinds of secret we can extract from `KeySchedule`.
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum SecretKind {
     ResumptionPskBinderKey,
+    ExternalPskBinderKey,
     ClientEarlyTrafficSecret,
     ClientHandshakeTrafficSecret,
     ServerHandshakeTrafficSecret,
@@ -38,6 +33,7 @@ impl SecretKind {
         use self::SecretKind::*;
         match self {
             ResumptionPskBinderKey => b"res binder",
+            ExternalPskBinderKey => b"ext binder",
             ClientEarlyTrafficSecret => b"c e traffic",
             ClientHandshakeTrafficSecret => b"c hs traffic",
             ServerHandshakeTrafficSecret => b"s hs traffic",
@@ -69,8 +65,9 @@ impl SecretKind {
 /// the type of hash.  This isn't used directly; but only through the
 /// typestates.
 struct KeySchedule {
-    current: hkdf::Prk,
+    current: Prk,
     suite: &'static Tls13CipherSuite,
+    label_prefix: &'static [u8],
 }
 
 // We express the state of a contained KeySchedule using these
@@ -85,15 +82,15 @@ pub(crate) struct KeyScheduleEarly {
 }
 
 impl KeyScheduleEarly {
-    pub(crate) fn new(suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
+    pub(crate) fn new(protocol: Protocol, suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
         Self {
-            ks: KeySchedule::new(suite, secret),
+            ks: KeySchedule::new(protocol, suite, secret),
         }
     }
 
     pub(crate) fn client_early_traffic_secret(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -105,6 +102,12 @@ impl KeyScheduleEarly {
             client_random,
         );
 
+        // DTLS never accepts early data, so nothing is sent in its epoch.
+        #[cfg(feature = "dtls")]
+        if common.is_dtls() {
+            return;
+        }
+
         match common.side {
             Side::Client => self
                 .ks
@@ -124,7 +127,7 @@ impl KeyScheduleEarly {
 
     pub(crate) fn resumption_psk_binder_key_and_sign_verify_data(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
     ) -> hmac::Tag {
         let resumption_psk_binder_key = self
             .ks
@@ -132,6 +135,17 @@ impl KeyScheduleEarly {
         self.ks
             .sign_verify_data(&resumption_psk_binder_key, hs_hash)
     }
+
+    pub(crate) fn external_psk_binder_key_and_sign_verify_data(
+        &self,
+        hs_hash: &hash::Output,
+    ) -> hmac::Tag {
+        let external_psk_binder_key = self
+            .ks
+            .derive_for_empty_hash(SecretKind::ExternalPskBinderKey);
+        self.ks
+            .sign_verify_data(&external_psk_binder_key, hs_hash)
+    }
 }
 
 /// Pre-handshake key schedule
@@ -143,9 +157,9 @@ pub(crate) struct KeySchedulePreHandshake {
 }
 
 impl KeySchedulePreHandshake {
-    pub(crate) fn new(suite: &'static Tls13CipherSuite) -> Self {
+    pub(crate) fn new(protocol: Protocol, suite: &'static Tls13CipherSuite) -> Self {
         Self {
-            ks: KeySchedule::new_with_empty_secret(suite),
+            ks: KeySchedule::new_with_empty_secret(protocol, suite),
         }
     }
 
@@ -153,6 +167,13 @@ impl KeySchedulePreHandshake {
         self.ks.input_secret(secret);
         KeyScheduleHandshakeStart { ks: self.ks }
     }
+
+    /// Move to the handshake stage without a key exchange, as
+    /// happens in psk_ke mode.
+    pub(crate) fn into_handshake_without_kx(mut self) -> KeyScheduleHandshakeStart {
+        self.ks.input_empty();
+        KeyScheduleHandshakeStart { ks: self.ks }
+    }
 }
 
 impl From<KeyScheduleEarly> for KeySchedulePreHandshake {
@@ -170,7 +191,7 @@ impl KeyScheduleHandshakeStart {
     pub(crate) fn derive_client_handshake_secrets(
         mut self,
         early_data_enabled: bool,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         suite: &'static Tls13CipherSuite,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
@@ -196,7 +217,7 @@ impl KeyScheduleHandshakeStart {
 
     pub(crate) fn derive_server_handshake_secrets(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -214,7 +235,7 @@ impl KeyScheduleHandshakeStart {
 
     fn into_handshake(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         _common: &mut CommonState,
@@ -254,12 +275,12 @@ impl KeyScheduleHandshakeStart {
 
 pub(crate) struct KeyScheduleHandshake {
     ks: KeySchedule,
-    client_handshake_traffic_secret: hkdf::Prk,
-    server_handshake_traffic_secret: hkdf::Prk,
+    client_handshake_traffic_secret: Prk,
+    server_handshake_traffic_secret: Prk,
 }
 
 impl KeyScheduleHandshake {
-    pub(crate) fn sign_server_finish(&self, hs_hash: &Digest) -> hmac::Tag {
+    pub(crate) fn sign_server_finish(&self, hs_hash: &hash::Output) -> hmac::Tag {
         self.ks
             .sign_finish(&self.server_handshake_traffic_secret, hs_hash)
     }
@@ -279,6 +300,9 @@ impl KeyScheduleHandshake {
         let secret = &self.client_handshake_traffic_secret;
         match skip_requested {
             None => self.ks.set_decrypter(secret, common),
+            // Rejected DTLS early data has its own epoch, and is simply
+            // dropped by the record layer.
+            Some(_) if common.is_dtls() => self.ks.set_decrypter(secret, common),
             Some(max_early_data_size) => common
                 .record_layer
                 .set_message_decrypter_with_trial_decryption(
@@ -291,7 +315,7 @@ impl KeyScheduleHandshake {
 
     pub(crate) fn into_traffic_with_client_finished_pending(
         self,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
         common: &mut CommonState,
@@ -326,8 +350,8 @@ impl KeyScheduleHandshake {
 
     pub(crate) fn into_pre_finished_client_traffic(
         self,
-        pre_finished_hash: Digest,
-        handshake_hash: Digest,
+        pre_finished_hash: hash::Output,
+        handshake_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> (KeyScheduleClientBeforeFinished, hmac::Tag) {
@@ -380,7 +404,7 @@ impl KeyScheduleClientBeforeFinished {
 /// finished verify_data. The traffic stage key schedule can be extracted from it
 /// through signing the client finished hash.
 pub(crate) struct KeyScheduleTrafficWithClientFinishedPending {
-    handshake_client_traffic_secret: hkdf::Prk,
+    handshake_client_traffic_secret: Prk,
     traffic: KeyScheduleTraffic,
 }
 
@@ -394,7 +418,7 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 
     pub(crate) fn sign_client_finish(
         self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         common: &mut CommonState,
     ) -> (KeyScheduleTraffic, hmac::Tag) {
         debug_assert_eq!(common.side, Side::Server);
@@ -419,15 +443,15 @@ impl KeyScheduleTrafficWithClientFinishedPending {
 /// to be available.
 pub(crate) struct KeyScheduleTraffic {
     ks: KeySchedule,
-    current_client_traffic_secret: hkdf::Prk,
-    current_server_traffic_secret: hkdf::Prk,
-    current_exporter_secret: hkdf::Prk,
+    current_client_traffic_secret: Prk,
+    current_server_traffic_secret: Prk,
+    current_exporter_secret: Prk,
 }
 
 impl KeyScheduleTraffic {
     fn new(
         mut ks: KeySchedule,
-        hs_hash: Digest,
+        hs_hash: hash::Output,
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
     ) -> Self {
@@ -463,8 +487,18 @@ impl KeyScheduleTraffic {
     }
 
     pub(crate) fn update_encrypter_and_notify(&mut self, common: &mut CommonState) {
+        self.update_encrypter(common, Message::build_key_update_notify());
+    }
+
+    /// Like `update_encrypter_and_notify`, but also asks the peer to
+    /// update its keys.
+    pub(crate) fn update_encrypter_and_request(&mut self, common: &mut CommonState) {
+        self.update_encrypter(common, Message::build_key_update_request());
+    }
+
+    fn update_encrypter(&mut self, common: &mut CommonState, key_update: Message) {
         let secret = self.next_application_traffic_secret(common.side);
-        common.send_msg_encrypt(Message::build_key_update_notify().into());
+        common.send_msg_encrypt(key_update.into());
         self.ks.set_encrypter(&secret, common);
     }
 
@@ -473,7 +507,7 @@ impl KeyScheduleTraffic {
         self.ks.set_decrypter(&secret, common);
     }
 
-    pub(crate) fn next_application_traffic_secret(&mut self, side: Side) -> hkdf::Prk {
+    pub(crate) fn next_application_traffic_secret(&mut self, side: Side) -> Prk {
         let current = match side {
             Side::Client => &mut self.current_client_traffic_secret,
             Side::Server => &mut self.current_server_traffic_secret,
@@ -484,16 +518,21 @@ impl KeyScheduleTraffic {
         secret
     }
 
+    /// Sign the client's Finished message for post-handshake authentication,
+    /// which uses the current client application traffic secret as its base key.
+    pub(crate) fn sign_post_handshake_client_finish(&self, hs_hash: &hash::Output) -> hmac::Tag {
+        self.ks
+            .sign_finish(&self.current_client_traffic_secret, hs_hash)
+    }
+
     pub(crate) fn resumption_master_secret_and_derive_ticket_psk(
         &self,
-        hs_hash: &Digest,
+        hs_hash: &hash::Output,
         nonce: &[u8],
     ) -> Vec<u8> {
-        let resumption_master_secret = self.ks.derive(
-            self.ks.algorithm(),
-            SecretKind::ResumptionMasterSecret,
-            hs_hash.as_ref(),
-        );
+        let resumption_master_secret = self
+            .ks
+            .derive(SecretKind::ResumptionMasterSecret, hs_hash.as_ref());
         self.ks
             .derive_ticket_psk(&resumption_master_secret, nonce)
     }
@@ -511,72 +550,59 @@ impl KeyScheduleTraffic {
     #[cfg(feature = "secret_extraction")]
     pub(crate) fn extract_secrets(&self, side: Side) -> Result<PartiallyExtractedSecrets, Error> {
         fn expand<const KEY_LEN: usize, const IV_LEN: usize>(
-            secret: &hkdf::Prk,
+            secret: &Prk,
         ) -> Result<([u8; KEY_LEN], [u8; IV_LEN]), Error> {
             let mut key = [0u8; KEY_LEN];
             let mut iv = [0u8; IV_LEN];
 
-            hkdf_expand_info(secret, PayloadU8Len(key.len()), b"key", &[], |okm| {
-                okm.fill(&mut key)
-            })
-            .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
+            hkdf_expand_info(secret, TLS13_LABEL_PREFIX, b"key", &[], &mut key)
+                .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
 
-            hkdf_expand_info(secret, PayloadU8Len(iv.len()), b"iv", &[], |okm| {
-                okm.fill(&mut iv)
-            })
-            .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
+            hkdf_expand_info(secret, TLS13_LABEL_PREFIX, b"iv", &[], &mut iv)
+                .map_err(|_| Error::General("hkdf_expand_info failed".to_string()))?;
 
             Ok((key, iv))
         }
 
-        let client_secrets;
-        let server_secrets;
-
-        let algo = self.ks.suite.common.aead_algorithm;
-        if algo == &ring::aead::AES_128_GCM {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
-                let (key, iv_in) = expand::<16, 12>(secret)?;
-
-                let mut salt = [0u8; 4];
-                salt.copy_from_slice(&iv_in[..4]);
-
-                let mut iv = [0u8; 8];
-                iv.copy_from_slice(&iv_in[4..]);
-
-                Ok(ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv })
-            };
-
-            client_secrets = extract(&self.current_client_traffic_secret)?;
-            server_secrets = extract(&self.current_server_traffic_secret)?;
-        } else if algo == &ring::aead::AES_256_GCM {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
-                let (key, iv_in) = expand::<32, 12>(secret)?;
-
-                let mut salt = [0u8; 4];
-                salt.copy_from_slice(&iv_in[..4]);
-
-                let mut iv = [0u8; 8];
-                iv.copy_from_slice(&iv_in[4..]);
-
-                Ok(ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv })
-            };
-
-            client_secrets = extract(&self.current_client_traffic_secret)?;
-            server_secrets = extract(&self.current_server_traffic_secret)?;
-        } else if algo == &ring::aead::CHACHA20_POLY1305 {
-            let extract = |secret: &hkdf::Prk| -> Result<ConnectionTrafficSecrets, Error> {
-                let (key, iv) = expand::<32, 12>(secret)?;
-                Ok(ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv })
-            };
-
-            client_secrets = extract(&self.current_client_traffic_secret)?;
-            server_secrets = extract(&self.current_server_traffic_secret)?;
-        } else {
-            return Err(Error::General(format!(
-                "exporting secrets for {:?}: unimplemented",
-                algo
-            )));
-        }
+        let extract = |secret: &Prk| -> Result<ConnectionTrafficSecrets, Error> {
+            Ok(match self.ks.suite.common.bulk {
+                BulkAlgorithm::Aes128Gcm => {
+                    let (key, iv_in) = expand::<16, 12>(secret)?;
+
+                    let mut salt = [0u8; 4];
+                    salt.copy_from_slice(&iv_in[..4]);
+
+                    let mut iv = [0u8; 8];
+                    iv.copy_from_slice(&iv_in[4..]);
+
+                    ConnectionTrafficSecrets::Aes128Gcm { key, salt, iv }
+                }
+                BulkAlgorithm::Aes256Gcm => {
+                    let (key, iv_in) = expand::<32, 12>(secret)?;
+
+                    let mut salt = [0u8; 4];
+                    salt.copy_from_slice(&iv_in[..4]);
+
+                    let mut iv = [0u8; 8];
+                    iv.copy_from_slice(&iv_in[4..]);
+
+                    ConnectionTrafficSecrets::Aes256Gcm { key, salt, iv }
+                }
+                BulkAlgorithm::Chacha20Poly1305 => {
+                    let (key, iv) = expand::<32, 12>(secret)?;
+                    ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv }
+                }
+                BulkAlgorithm::Aes128Ccm | BulkAlgorithm::Aes128Ccm8 => {
+                    return Err(Error::General(format!(
+                        "exporting secrets for {:?}: unimplemented",
+                        self.ks.suite.common.bulk
+                    )));
+                }
+            })
+        };
+
+        let client_secrets = extract(&self.current_client_traffic_secret)?;
+        let server_secrets = extract(&self.current_server_traffic_secret)?;
 
         let (tx, rx) = match side {
             crate::conn::Side::Client => (client_secrets, server_secrets),
@@ -587,71 +613,111 @@ impl KeyScheduleTraffic {
 }
 
 impl KeySchedule {
-    fn new(suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        let salt = hkdf::Salt::new(suite.hkdf_algorithm, &zeroes[..suite.hkdf_algorithm.len()]);
+    fn new(protocol: Protocol, suite: &'static Tls13CipherSuite, secret: &[u8]) -> Self {
+        let zeroes = [0u8; hash::MAX_LEN];
+        let salt = &zeroes[..suite.hmac.hash_output_len()];
         Self {
-            current: salt.extract(secret),
+            current: Prk::extract(suite.hmac, salt, secret),
             suite,
+            label_prefix: label_prefix(protocol),
         }
     }
 
-    fn set_encrypter(&self, secret: &hkdf::Prk, common: &mut CommonState) {
-        let key = derive_traffic_key(secret, self.suite.common.aead_algorithm);
-        let iv = derive_traffic_iv(secret);
+    fn set_encrypter(&self, secret: &Prk, common: &mut CommonState) {
+        #[cfg(feature = "dtls")]
+        if common.is_dtls() {
+            common
+                .dtls
+                .set_write_secret(self.suite, secret.clone());
+            return;
+        }
 
+        let aead_alg = self.suite.common.aead_algorithm;
         common
             .record_layer
             .set_message_encrypter(Box::new(Tls13MessageEncrypter {
-                enc_key: aead::LessSafeKey::new(key),
-                iv,
-            }))
+                enc_key: derive_traffic_key(secret, aead_alg),
+                tag_len: aead_alg.tag_len(),
+                iv: derive_traffic_iv(secret),
+            }));
+        common
+            .record_layer
+            .set_confidentiality_limit(self.suite.confidentiality_limit);
     }
 
-    fn set_decrypter(&self, secret: &hkdf::Prk, common: &mut CommonState) {
+    fn set_decrypter(&self, secret: &Prk, common: &mut CommonState) {
+        #[cfg(feature = "dtls")]
+        if common.is_dtls() {
+            common
+                .dtls
+                .set_read_secret(self.suite, secret.clone());
+            return;
+        }
+
         common
             .record_layer
             .set_message_decrypter(self.derive_decrypter(secret))
     }
 
-    fn derive_decrypter(&self, secret: &hkdf::Prk) -> Box<dyn MessageDecrypter> {
-        let key = derive_traffic_key(secret, self.suite.common.aead_algorithm);
-        let iv = derive_traffic_iv(secret);
+    fn derive_decrypter(&self, secret: &Prk) -> Box<dyn MessageDecrypter> {
+        let aead_alg = self.suite.common.aead_algorithm;
         Box::new(Tls13MessageDecrypter {
-            dec_key: aead::LessSafeKey::new(key),
-            iv,
+            dec_key: derive_traffic_key(secret, aead_alg),
+            tag_len: aead_alg.tag_len(),
+            iv: derive_traffic_iv(secret),
         })
     }
 
     #[inline]
-    fn algorithm(&self) -> hkdf::Algorithm {
-        self.suite.hkdf_algorithm
+    fn hash_len(&self) -> usize {
+        self.suite.hmac.hash_output_len()
     }
 
-    fn new_with_empty_secret(suite: &'static Tls13CipherSuite) -> Self {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        Self::new(suite, &zeroes[..suite.hkdf_algorithm.len()])
+    fn new_with_empty_secret(protocol: Protocol, suite: &'static Tls13CipherSuite) -> Self {
+        let zeroes = [0u8; hash::MAX_LEN];
+        Self::new(protocol, suite, &zeroes[..suite.hmac.hash_output_len()])
     }
 
     /// Input the empty secret.
     fn input_empty(&mut self) {
-        let zeroes = [0u8; digest::MAX_OUTPUT_LEN];
-        self.input_secret(&zeroes[..self.suite.hkdf_algorithm.len()]);
+        let zeroes = [0u8; hash::MAX_LEN];
+        self.input_secret(&zeroes[..self.hash_len()]);
     }
 
     /// Input the given secret.
     fn input_secret(&mut self, secret: &[u8]) {
-        let salt: hkdf::Salt = self.derive_for_empty_hash(SecretKind::DerivedSecret);
-        self.current = salt.extract(secret);
+        let empty_hash = self.suite.hash_algorithm().hash(&[]);
+        let salt = self.derive_bytes(SecretKind::DerivedSecret, empty_hash.as_ref());
+        self.current = Prk::extract(self.suite.hmac, &salt, secret);
     }
 
     /// Derive a secret of given `kind`, using current handshake hash `hs_hash`.
-    fn derive<T, L>(&self, key_type: L, kind: SecretKind, hs_hash: &[u8]) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, L>>,
-        L: hkdf::KeyType,
-    {
-        hkdf_expand(&self.current, key_type, kind.to_bytes(), hs_hash)
+    fn derive(&self, kind: SecretKind, hs_hash: &[u8]) -> Prk {
+        self.expand_prk(&self.current, kind.to_bytes(), hs_hash)
+    }
+
+    /// Like `derive`, but returning the secret's bytes.
+    fn derive_bytes(&self, kind: SecretKind, hs_hash: &[u8]) -> Vec<u8> {
+        let mut secret = vec![0u8; self.hash_len()];
+        self.expand(&self.current, kind.to_bytes(), hs_hash, &mut secret)
+            .unwrap();
+        secret
+    }
+
+    /// `hkdf_expand`, with this schedule's label prefix.
+    fn expand(
+        &self,
+        secret: &Prk,
+        label: &[u8],
+        context: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), OutputLengthError> {
+        hkdf_expand_info(secret, self.label_prefix, label, context, out)
+    }
+
+    /// `hkdf_expand_prk`, with this schedule's label prefix.
+    fn expand_prk(&self, secret: &Prk, label: &[u8], context: &[u8]) -> Prk {
+        hkdf_expand_info_prk(secret, self.label_prefix, label, context)
     }
 
     fn derive_logged_secret(
@@ -660,179 +726,172 @@ impl KeySchedule {
         hs_hash: &[u8],
         key_log: &dyn KeyLog,
         client_random: &[u8; 32],
-    ) -> hkdf::Prk {
+    ) -> Prk {
         let log_label = kind
             .log_label()
             .expect("not a loggable secret");
         if key_log.will_log(log_label) {
-            let secret = self
-                .derive::<PayloadU8, _>(
-                    PayloadU8Len(self.suite.hkdf_algorithm.len()),
-                    kind,
-                    hs_hash,
-                )
-                .into_inner();
+            let secret = self.derive_bytes(kind, hs_hash);
             key_log.log(log_label, client_random, &secret);
         }
-        self.derive(self.suite.hkdf_algorithm, kind, hs_hash)
+        self.derive(kind, hs_hash)
     }
 
     /// Derive a secret of given `kind` using the hash of the empty string
     /// for the handshake hash.  Useful only for
     /// `SecretKind::ResumptionPSKBinderKey` and
-    /// `SecretKind::DerivedSecret`.
-    fn derive_for_empty_hash<T>(&self, kind: SecretKind) -> T
-    where
-        T: for<'a> From<hkdf::Okm<'a, hkdf::Algorithm>>,
-    {
-        let digest_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-        let empty_hash = digest::digest(digest_alg, &[]);
-        self.derive(self.suite.hkdf_algorithm, kind, empty_hash.as_ref())
+    /// `SecretKind::ExternalPskBinderKey`.
+    fn derive_for_empty_hash(&self, kind: SecretKind) -> Prk {
+        let empty_hash = self.suite.hash_algorithm().hash(&[]);
+        self.derive(kind, empty_hash.as_ref())
     }
 
     /// Sign the finished message consisting of `hs_hash` using a current
     /// traffic secret.
-    fn sign_finish(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
+    fn sign_finish(&self, base_key: &Prk, hs_hash: &hash::Output) -> hmac::Tag {
         self.sign_verify_data(base_key, hs_hash)
     }
 
     /// Sign the finished message consisting of `hs_hash` using the key material
     /// `base_key`.
-    fn sign_verify_data(&self, base_key: &hkdf::Prk, hs_hash: &Digest) -> hmac::Tag {
-        let hmac_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm();
-        let hmac_key = hkdf_expand(base_key, hmac_alg, b"finished", &[]);
-        hmac::sign(&hmac_key, hs_hash.as_ref())
+    fn sign_verify_data(&self, base_key: &Prk, hs_hash: &hash::Output) -> hmac::Tag {
+        let mut hmac_key = [0u8; hmac::Tag::MAX_LEN];
+        let hmac_key = &mut hmac_key[..self.hash_len()];
+        self.expand(base_key, b"finished", &[], hmac_key)
+            .unwrap();
+        self.suite
+            .hmac
+            .with_key(hmac_key)
+            .sign(&[hs_hash.as_ref()])
     }
 
     /// Derive the next application traffic secret, returning it.
-    fn derive_next(&self, base_key: &hkdf::Prk) -> hkdf::Prk {
-        hkdf_expand(base_key, self.suite.hkdf_algorithm, b"traffic upd", &[])
+    fn derive_next(&self, base_key: &Prk) -> Prk {
+        self.expand_prk(base_key, b"traffic upd", &[])
     }
 
     /// Derive the PSK to use given a resumption_master_secret and
     /// ticket_nonce.
-    fn derive_ticket_psk(&self, rms: &hkdf::Prk, nonce: &[u8]) -> Vec<u8> {
-        let payload: PayloadU8 = hkdf_expand(
-            rms,
-            PayloadU8Len(self.suite.hkdf_algorithm.len()),
-            b"resumption",
-            nonce,
-        );
-        payload.into_inner()
+    fn derive_ticket_psk(&self, rms: &Prk, nonce: &[u8]) -> Vec<u8> {
+        let mut psk = vec![0u8; self.hash_len()];
+        self.expand(rms, b"resumption", nonce, &mut psk)
+            .unwrap();
+        psk
     }
 
     fn export_keying_material(
         &self,
-        current_exporter_secret: &hkdf::Prk,
+        current_exporter_secret: &Prk,
         out: &mut [u8],
         label: &[u8],
         context: Option<&[u8]>,
     ) -> Result<(), Error> {
-        let digest_alg = self
-            .suite
-            .hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm();
-
-        let h_empty = digest::digest(digest_alg, &[]);
-        let secret: hkdf::Prk = hkdf_expand(
-            current_exporter_secret,
-            self.suite.hkdf_algorithm,
-            label,
-            h_empty.as_ref(),
-        );
+        let hash = self.suite.hash_algorithm();
+
+        let h_empty = hash.hash(&[]);
+        let secret = self.expand_prk(current_exporter_secret, label, h_empty.as_ref());
 
-        let h_context = digest::digest(digest_alg, context.unwrap_or(&[]));
+        let h_context = hash.hash(context.unwrap_or(&[]));
 
         // TODO: Test what happens when this fails
-        hkdf_expand_info(
-            &secret,
-            PayloadU8Len(out.len()),
-            b"exporter",
-            h_context.as_ref(),
-            |okm| okm.fill(out),
-        )
-        .map_err(|_| Error::General("exporting too much".to_string()))
+        self.expand(&secret, b"exporter", h_context.as_ref(), out)
+            .map_err(|_| Error::General("exporting too much".to_string()))
     }
 }
 
-pub(crate) fn hkdf_expand<T, L>(secret: &hkdf::Prk, key_type: L, label: &[u8], context: &[u8]) -> T
-where
-    T: for<'a> From<hkdf::Okm<'a, L>>,
-    L: hkdf::KeyType,
-{
-    hkdf_expand_info(secret, key_type, label, context, |okm| okm.into())
+/// HKDF-Expand-Label from RFC8446, filling `out`.
+pub(crate) fn hkdf_expand(secret: &Prk, label: &[u8], context: &[u8], out: &mut [u8]) {
+    hkdf_expand_info(secret, TLS13_LABEL_PREFIX, label, context, out).unwrap()
+}
+
+/// HKDF-Expand-Label from RFC8446, producing a new secret of the
+/// hash output length.
+#[cfg(feature = "quic")]
+pub(crate) fn hkdf_expand_prk(secret: &Prk, label: &[u8], context: &[u8]) -> Prk {
+    hkdf_expand_info_prk(secret, TLS13_LABEL_PREFIX, label, context)
+}
+
+/// Like `hkdf_expand`, for the DTLS1.3 key schedule.
+#[cfg(feature = "dtls")]
+pub(crate) fn dtls_hkdf_expand(secret: &Prk, label: &[u8], context: &[u8], out: &mut [u8]) {
+    hkdf_expand_info(secret, DTLS13_LABEL_PREFIX, label, context, out).unwrap()
 }
 
-fn hkdf_expand_info<F, T, L>(
-    secret: &hkdf::Prk,
-    key_type: L,
+const TLS13_LABEL_PREFIX: &[u8] = b"tls13 ";
+
+/// RFC9147 section 5.9: note this has no trailing space.
+#[cfg(feature = "dtls")]
+const DTLS13_LABEL_PREFIX: &[u8] = b"dtls13";
+
+fn label_prefix(_protocol: Protocol) -> &'static [u8] {
+    #[cfg(feature = "dtls")]
+    if _protocol == Protocol::Dtls {
+        return DTLS13_LABEL_PREFIX;
+    }
+    TLS13_LABEL_PREFIX
+}
+
+fn hkdf_expand_info(
+    secret: &Prk,
+    label_prefix: &[u8],
     label: &[u8],
     context: &[u8],
-    f: F,
-) -> T
-where
-    F: for<'b> FnOnce(hkdf::Okm<'b, L>) -> T,
-    L: hkdf::KeyType,
-{
-    const LABEL_PREFIX: &[u8] = b"tls13 ";
-
-    let output_len = u16::to_be_bytes(key_type.len() as u16);
-    let label_len = u8::to_be_bytes((LABEL_PREFIX.len() + label.len()) as u8);
+    out: &mut [u8],
+) -> Result<(), OutputLengthError> {
+    let output_len = u16::to_be_bytes(out.len() as u16);
+    let label_len = u8::to_be_bytes((label_prefix.len() + label.len()) as u8);
     let context_len = u8::to_be_bytes(context.len() as u8);
 
     let info = &[
         &output_len[..],
         &label_len[..],
-        LABEL_PREFIX,
+        label_prefix,
         label,
         &context_len[..],
         context,
     ];
-    let okm = secret.expand(info, key_type).unwrap();
-
-    f(okm)
+    secret.expand(info, out)
 }
 
-pub(crate) struct PayloadU8Len(pub(crate) usize);
-impl hkdf::KeyType for PayloadU8Len {
-    fn len(&self) -> usize {
-        self.0
-    }
-}
+fn hkdf_expand_info_prk(secret: &Prk, label_prefix: &[u8], label: &[u8], context: &[u8]) -> Prk {
+    let output_len = u16::to_be_bytes(secret.hmac().hash_output_len() as u16);
+    let label_len = u8::to_be_bytes((label_prefix.len() + label.len()) as u8);
+    let context_len = u8::to_be_bytes(context.len() as u8);
 
-impl From<hkdf::Okm<'_, PayloadU8Len>> for PayloadU8 {
-    fn from(okm: hkdf::Okm<PayloadU8Len>) -> Self {
-        let mut r = vec![0u8; okm.len().0];
-        okm.fill(&mut r[..]).unwrap();
-        Self::new(r)
-    }
+    secret.expand_prk(&[
+        &output_len[..],
+        &label_len[..],
+        label_prefix,
+        label,
+        &context_len[..],
+        context,
+    ])
 }
 
+/// Derive the traffic key for `aead_algorithm` from `secret`.
 pub(crate) fn derive_traffic_key(
-    secret: &hkdf::Prk,
-    aead_algorithm: &'static aead::Algorithm,
-) -> aead::UnboundKey {
-    hkdf_expand(secret, aead_algorithm, b"key", &[])
+    secret: &Prk,
+    aead_algorithm: &'static dyn aead::Algorithm,
+) -> Box<dyn aead::Key> {
+    let mut key = vec![0u8; aead_algorithm.key_len()];
+    hkdf_expand(secret, b"key", &[], &mut key);
+    aead_algorithm.new_key(&key).unwrap()
 }
 
-pub(crate) fn derive_traffic_iv(secret: &hkdf::Prk) -> Iv {
-    hkdf_expand(secret, IvLen, b"iv", &[])
+pub(crate) fn derive_traffic_iv(secret: &Prk) -> Iv {
+    let mut iv = Iv::default();
+    hkdf_expand(secret, b"iv", &[], &mut iv.0[..NONCE_LEN]);
+    iv
 }
 
 #[cfg(test)]
 mod test {
     use super::{derive_traffic_iv, derive_traffic_key, KeySchedule, SecretKind};
+    use crate::conn::Protocol;
+    use crate::crypto::aead;
+    use crate::crypto::ring::aead::AES_128_GCM;
     use crate::tls13::TLS13_CHACHA20_POLY1305_SHA256_INTERNAL;
     use crate::KeyLog;
-    use ring::aead;
 
     #[test]
     fn test_vectors() {
@@ -915,7 +974,10 @@ mod test {
             0x0d, 0xb2, 0x8f, 0x98, 0x85, 0x86, 0xa1, 0xb7, 0xe4, 0xd5, 0xc6, 0x9c,
         ];
 
-        let mut ks = KeySchedule::new_with_empty_secret(TLS13_CHACHA20_POLY1305_SHA256_INTERNAL);
+        let mut ks = KeySchedule::new_with_empty_secret(
+            Protocol::Tcp,
+            TLS13_CHACHA20_POLY1305_SHA256_INTERNAL,
+        );
         ks.input_secret(&ecdhe_secret);
 
         assert_traffic_secret(
@@ -975,10 +1037,9 @@ mod test {
         let traffic_secret = ks.derive_logged_secret(kind, hash, &log, &[0; 32]);
 
         // Since we can't test key equality, we test the output of sealing with the key instead.
-        let aead_alg = &aead::AES_128_GCM;
-        let key = derive_traffic_key(&traffic_secret, aead_alg);
+        let key = derive_traffic_key(&traffic_secret, &AES_128_GCM);
         let seal_output = seal_zeroes(key);
-        let expected_key = aead::UnboundKey::new(aead_alg, expected_key).unwrap();
+        let expected_key = aead::Algorithm::new_key(&AES_128_GCM, expected_key).unwrap();
         let expected_seal_output = seal_zeroes(expected_key);
         assert_eq!(seal_output, expected_seal_output);
         assert!(seal_output.len() >= 48); // Sanity check.
@@ -987,15 +1048,12 @@ mod test {
         assert_eq!(iv.value(), expected_iv);
     }
 
-    fn seal_zeroes(key: aead::UnboundKey) -> Vec<u8> {
-        let key = aead::LessSafeKey::new(key);
+    fn seal_zeroes(key: Box<dyn aead::Key>) -> Vec<u8> {
         let mut seal_output = vec![0; 32];
-        key.seal_in_place_append_tag(
-            aead::Nonce::assume_unique_for_key([0; aead::NONCE_LEN]),
-            aead::Aad::empty(),
-            &mut seal_output,
-        )
-        .unwrap();
+        let tag = key
+            .seal_in_place_separate_tag([0; aead::NONCE_LEN], &[], &mut seal_output)
+            .unwrap();
+        seal_output.extend_from_slice(tag.as_ref());
         seal_output
     }
 }
@@ -1,4 +1,5 @@
 use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::crypto::{aead, hash, hmac, ring};
 use crate::enums::{CipherSuite, ProtocolVersion};
 use crate::error::Error;
 use crate::msgs::base::Payload;
@@ -8,8 +9,6 @@ use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
 use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
 use crate::suites::{BulkAlgorithm, CipherSuiteCommon, SupportedCipherSuite};
 
-use ring::aead;
-
 use std::fmt;
 
 pub(crate) mod key_schedule;
@@ -24,10 +23,8 @@ pub(crate) static TLS13_CHACHA20_POLY1305_SHA256_INTERNAL: &Tls13CipherSuite = &
         bulk: BulkAlgorithm::Chacha20Poly1305,
         aead_algorithm: &ring::aead::CHACHA20_POLY1305,
     },
-    hkdf_algorithm: ring::hkdf::HKDF_SHA256,
-    #[cfg(feature = "quic")]
+    hmac: &ring::hmac::HMAC_SHA256,
     confidentiality_limit: u64::MAX,
-    #[cfg(feature = "quic")]
     integrity_limit: 1 << 36,
 };
 
@@ -39,10 +36,8 @@ pub static TLS13_AES_256_GCM_SHA384: SupportedCipherSuite =
             bulk: BulkAlgorithm::Aes256Gcm,
             aead_algorithm: &ring::aead::AES_256_GCM,
         },
-        hkdf_algorithm: ring::hkdf::HKDF_SHA384,
-        #[cfg(feature = "quic")]
+        hmac: &ring::hmac::HMAC_SHA384,
         confidentiality_limit: 1 << 23,
-        #[cfg(feature = "quic")]
         integrity_limit: 1 << 52,
     });
 
@@ -56,35 +51,52 @@ pub(crate) static TLS13_AES_128_GCM_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13C
         bulk: BulkAlgorithm::Aes128Gcm,
         aead_algorithm: &ring::aead::AES_128_GCM,
     },
-    hkdf_algorithm: ring::hkdf::HKDF_SHA256,
-    #[cfg(feature = "quic")]
+    hmac: &ring::hmac::HMAC_SHA256,
     confidentiality_limit: 1 << 23,
-    #[cfg(feature = "quic")]
     integrity_limit: 1 << 52,
 };
 
+/// The TLS1.3 ciphersuite TLS_AES_128_CCM_SHA256
+#[cfg(feature = "aes_ccm")]
+pub static TLS13_AES_128_CCM_SHA256: SupportedCipherSuite =
+    SupportedCipherSuite::Tls13(&Tls13CipherSuite {
+        common: CipherSuiteCommon {
+            suite: CipherSuite::TLS13_AES_128_CCM_SHA256,
+            bulk: BulkAlgorithm::Aes128Ccm,
+            aead_algorithm: &crate::crypto::aes_ccm::AES_128_CCM,
+        },
+        hmac: &ring::hmac::HMAC_SHA256,
+        // RFC9001 section 6.6: 2^21.5 records, for both.
+        confidentiality_limit: 2_965_820,
+        integrity_limit: 2_965_820,
+    });
+
 /// A TLS 1.3 cipher suite supported by rustls.
 pub struct Tls13CipherSuite {
     /// Common cipher suite fields.
     pub common: CipherSuiteCommon,
-    pub(crate) hkdf_algorithm: ring::hkdf::Algorithm,
-    #[cfg(feature = "quic")]
-    pub(crate) confidentiality_limit: u64,
-    #[cfg(feature = "quic")]
-    pub(crate) integrity_limit: u64,
+
+    /// The HMAC underlying this suite's HKDF, and so its key schedule.
+    pub hmac: &'static dyn hmac::Hmac,
+
+    /// How many records may be encrypted with one key.  TLS1.3
+    /// connections update their keys once this many have been sent.
+    pub confidentiality_limit: u64,
+
+    /// How many records may fail decryption before the connection must
+    /// be closed.  This is only enforced by QUIC.
+    pub integrity_limit: u64,
 }
 
 impl Tls13CipherSuite {
     /// Which hash function to use with this suite.
-    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
-        self.hkdf_algorithm
-            .hmac_algorithm()
-            .digest_algorithm()
+    pub fn hash_algorithm(&self) -> &'static dyn hash::Hash {
+        self.hmac.hash()
     }
 
     /// Can a session using suite self resume from suite prev?
     pub fn can_resume_from(&self, prev: &'static Self) -> Option<&'static Self> {
-        (prev.hash_algorithm() == self.hash_algorithm()).then(|| prev)
+        (prev.hash_algorithm().algorithm() == self.hash_algorithm().algorithm()).then(|| prev)
     }
 }
 
@@ -110,12 +122,14 @@ impl fmt::Debug for Tls13CipherSuite {
 }
 
 struct Tls13MessageEncrypter {
-    enc_key: aead::LessSafeKey,
+    enc_key: Box<dyn aead::Key>,
+    tag_len: usize,
     iv: Iv,
 }
 
 struct Tls13MessageDecrypter {
-    dec_key: aead::LessSafeKey,
+    dec_key: Box<dyn aead::Key>,
+    tag_len: usize,
     iv: Iv,
 }
 
@@ -129,14 +143,14 @@ fn unpad_tls13(v: &mut Vec<u8>) -> ContentType {
     }
 }
 
-fn make_tls13_aad(len: usize) -> ring::aead::Aad<[u8; TLS13_AAD_SIZE]> {
-    ring::aead::Aad::from([
+fn make_tls13_aad(len: usize) -> [u8; TLS13_AAD_SIZE] {
+    [
         0x17, // ContentType::ApplicationData
         0x3,  // ProtocolVersion (major)
         0x3,  // ProtocolVersion (minor)
         (len >> 8) as u8,
         len as u8,
-    ])
+    ]
 }
 
 // https://datatracker.ietf.org/doc/html/rfc8446#section-5.2
@@ -144,7 +158,7 @@ const TLS13_AAD_SIZE: usize = 1 + 2 + 2;
 
 impl MessageEncrypter for Tls13MessageEncrypter {
     fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
-        let total_len = msg.payload.len() + 1 + self.enc_key.algorithm().tag_len();
+        let total_len = msg.payload.len() + 1 + self.tag_len;
         let mut payload = Vec::with_capacity(total_len);
         payload.extend_from_slice(msg.payload);
         msg.typ.encode(&mut payload);
@@ -152,9 +166,11 @@ impl MessageEncrypter for Tls13MessageEncrypter {
         let nonce = make_nonce(&self.iv, seq);
         let aad = make_tls13_aad(total_len);
 
-        self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut payload)
+        let tag = self
+            .enc_key
+            .seal_in_place_separate_tag(nonce, &aad, &mut payload)
             .map_err(|_| Error::General("encrypt failed".to_string()))?;
+        payload.extend_from_slice(tag.as_ref());
 
         Ok(OpaqueMessage {
             typ: ContentType::ApplicationData,
@@ -167,7 +183,7 @@ impl MessageEncrypter for Tls13MessageEncrypter {
 impl MessageDecrypter for Tls13MessageDecrypter {
     fn decrypt(&self, mut msg: OpaqueMessage, seq: u64) -> Result<PlainMessage, Error> {
         let payload = &mut msg.payload.0;
-        if payload.len() < self.dec_key.algorithm().tag_len() {
+        if payload.len() < self.tag_len {
             return Err(Error::DecryptError);
         }
 
@@ -175,9 +191,8 @@ impl MessageDecrypter for Tls13MessageDecrypter {
         let aad = make_tls13_aad(payload.len());
         let plain_len = self
             .dec_key
-            .open_in_place(nonce, aad, payload)
-            .map_err(|_| Error::DecryptError)?
-            .len();
+            .open_in_place(nonce, &aad, payload)
+            .map_err(|_| Error::DecryptError)?;
 
         payload.truncate(plain_len);
 
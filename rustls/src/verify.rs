@@ -2,14 +2,18 @@ use std::fmt;
 
 use crate::anchors::{OwnedTrustAnchor, RootCertStore};
 use crate::client::ServerName;
+use crate::crl::CertRevocationList;
+use crate::crypto::{hash, CryptoProvider};
+use crate::ct::CertificateTransparencyPolicy;
 use crate::enums::SignatureScheme;
 use crate::error::Error;
-use crate::key::Certificate;
+use crate::key::{Certificate, SubjectPublicKeyInfo};
 #[cfg(feature = "logging")]
-use crate::log::{debug, trace, warn};
-use crate::msgs::handshake::{DigitallySignedStruct, DistinguishedNames};
-
-use ring::digest::Digest;
+use crate::log::{trace, warn};
+use crate::msgs::codec::Codec;
+use crate::msgs::handshake::{Credential, DigitallySignedStruct, DistinguishedNames};
+use crate::ocsp::{self, OcspError};
+use crate::x509::{self, Issuer, ParsedCert};
 
 use std::sync::Arc;
 use std::time::SystemTime;
@@ -193,6 +197,44 @@ pub trait ServerCertVerifier: Send + Sync {
     fn request_scts(&self) -> bool {
         true
     }
+
+    /// Return the subjects of the trust anchors this verifier accepts,
+    /// for the client to send in the `certificate_authorities` extension.
+    ///
+    /// This is only used if [`ClientConfig::send_certificate_authorities`]
+    /// is set.  The default implementation returns `None`, and no extension
+    /// is sent.
+    ///
+    /// [`ClientConfig::send_certificate_authorities`]: crate::ClientConfig::send_certificate_authorities
+    fn root_hint_subjects(&self) -> Option<DistinguishedNames> {
+        None
+    }
+
+    /// Verify a delegated credential (RFC9345) sent by the server, which
+    /// delegates authority from `end_entity` to another key.
+    ///
+    /// `message` is the content that must have been signed by the key of
+    /// `end_entity`, and `dss` holds that signature.  rustls separately
+    /// checks the credential's validity period, and then uses the delegated
+    /// key to verify the server's `CertificateVerify` message.
+    ///
+    /// `end_entity` has already been validated by [`ServerCertVerifier::verify_server_cert`].
+    ///
+    /// This is only called if [`ClientConfig::enable_delegated_credentials`]
+    /// is set.  The default implementation rejects all delegated credentials.
+    ///
+    /// [`ClientConfig::enable_delegated_credentials`]: crate::ClientConfig::enable_delegated_credentials
+    fn verify_delegated_credential(
+        &self,
+        message: &[u8],
+        end_entity: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        let _ = (message, end_entity, dss);
+        Err(Error::General(
+            "verifier does not support delegated credentials".to_string(),
+        ))
+    }
 }
 
 impl fmt::Debug for dyn ServerCertVerifier {
@@ -332,6 +374,8 @@ impl ServerCertVerifier for WebPkiVerifier {
     /// Will verify the certificate is valid in the following ways:
     /// - Signed by a  trusted `RootCertStore` CA
     /// - Not Expired
+    /// - Not revoked, according to a stapled OCSP response (see `OcspPolicy`)
+    /// - Logged, according to the `CertificateTransparencyPolicy` (if any)
     /// - Valid for DNS entry
     fn verify_server_cert(
         &self,
@@ -356,12 +400,21 @@ impl ServerCertVerifier for WebPkiVerifier {
             .map(|_| cert)?;
 
         if let Some(policy) = &self.ct_policy {
-            policy.verify(end_entity, now, scts)?;
+            let parsed =
+                ParsedCert::parse(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+            let issuer = find_issuer(&parsed, intermediates, &self.roots);
+            policy.verify(
+                end_entity,
+                issuer
+                    .as_ref()
+                    .map(|(issuer, _)| issuer),
+                scts,
+                ocsp_response,
+                now,
+            )?;
         }
 
-        if !ocsp_response.is_empty() {
-            trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
-        }
+        self.verify_ocsp_response(end_entity, intermediates, ocsp_response, now)?;
 
         match server_name {
             ServerName::DnsName(dns_name) => {
@@ -380,6 +433,30 @@ impl ServerCertVerifier for WebPkiVerifier {
             }
         }
     }
+
+    /// Returns the subjects of the `RootCertStore`.
+    #[allow(deprecated)]
+    fn root_hint_subjects(&self) -> Option<DistinguishedNames> {
+        Some(self.roots.subjects())
+    }
+
+    /// Checks `end_entity` carries the `DelegationUsage` extension and
+    /// the `digitalSignature` key usage, before verifying the signature
+    /// like `verify_tls13_signature`.
+    fn verify_delegated_credential(
+        &self,
+        message: &[u8],
+        end_entity: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        if !x509::cert_allows_delegation(&end_entity.0) {
+            return Err(Error::InvalidCertificateData(
+                "certificate does not permit delegated credentials".to_string(),
+            ));
+        }
+
+        verify_tls13(message, end_entity, dss)
+    }
 }
 
 /// Default `ServerCertVerifier`, see the trait impl for more information.
@@ -388,6 +465,7 @@ impl ServerCertVerifier for WebPkiVerifier {
 pub struct WebPkiVerifier {
     roots: RootCertStore,
     ct_policy: Option<CertificateTransparencyPolicy>,
+    ocsp_policy: OcspPolicy,
 }
 
 #[allow(unreachable_pub)]
@@ -396,11 +474,78 @@ impl WebPkiVerifier {
     ///
     /// `roots` is the set of trust anchors to trust for issuing server certs.
     ///
-    /// `ct_logs` is the list of logs that are trusted for Certificate
-    /// Transparency. Currently CT log enforcement is opportunistic; see
-    /// <https://github.com/rustls/rustls/issues/479>.
+    /// `ct_policy` is the policy for Certificate Transparency, if any;
+    /// see [`CertificateTransparencyPolicy`].
     pub fn new(roots: RootCertStore, ct_policy: Option<CertificateTransparencyPolicy>) -> Self {
-        Self { roots, ct_policy }
+        Self {
+            roots,
+            ct_policy,
+            ocsp_policy: OcspPolicy::default(),
+        }
+    }
+
+    /// Sets how stapled OCSP responses are checked.
+    ///
+    /// The default is to ignore them; see [`OcspPolicy`].
+    pub fn with_ocsp_policy(mut self, ocsp_policy: OcspPolicy) -> Self {
+        self.ocsp_policy = ocsp_policy;
+        self
+    }
+
+    fn verify_ocsp_response(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let mode = match self.ocsp_policy.mode {
+            _ if self.ocsp_policy.enforce_must_staple
+                && x509::cert_requires_ocsp_staple(&end_entity.0) =>
+            {
+                OcspMode::HardFail
+            }
+            OcspMode::Ignore => {
+                if !ocsp_response.is_empty() {
+                    trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
+                }
+                return Ok(());
+            }
+            mode => mode,
+        };
+
+        if ocsp_response.is_empty() {
+            return match mode {
+                OcspMode::HardFail => Err(Error::InvalidCertificateData(
+                    "no OCSP response stapled".to_string(),
+                )),
+                _ => Ok(()),
+            };
+        }
+
+        let end_entity =
+            ParsedCert::parse(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+        let now = now
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| Error::FailedToGetCurrentTime)?
+            .as_secs();
+
+        let result = match find_issuer(&end_entity, intermediates, &self.roots) {
+            Some((issuer, _)) => ocsp::verify_response(ocsp_response, &end_entity, &issuer, now),
+            None => Err(OcspError::UnknownIssuer),
+        };
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(OcspError::Revoked) => Err(Error::CertificateRevoked),
+            Err(err) if mode == OcspMode::HardFail => {
+                Err(Error::InvalidCertificateData(err.to_string()))
+            }
+            Err(_err) => {
+                warn!("Ignoring invalid OCSP response: {}", _err);
+                Ok(())
+            }
+        }
     }
 
     /// Returns the signature verification methods supported by
@@ -420,80 +565,41 @@ impl WebPkiVerifier {
     }
 }
 
-/// Policy for enforcing Certificate Transparency.
+/// Policy for checking OCSP responses stapled by the server.
 ///
-/// Because Certificate Transparency logs are sharded on a per-year basis and can be trusted or
-/// distrusted relatively quickly, rustls stores a validation deadline. Server certificates will
-/// be validated against the configured CT logs until the deadline expires. After the deadline,
-/// certificates will no longer be validated, and a warning message will be logged. The deadline
-/// may vary depending on how often you deploy builds with updated dependencies.
-#[allow(unreachable_pub)]
-#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
-pub struct CertificateTransparencyPolicy {
-    logs: &'static [&'static sct::Log<'static>],
-    validation_deadline: SystemTime,
-}
-
-impl CertificateTransparencyPolicy {
-    /// Create a new policy.
-    #[allow(unreachable_pub)]
-    pub fn new(
-        logs: &'static [&'static sct::Log<'static>],
-        validation_deadline: SystemTime,
-    ) -> Self {
-        Self {
-            logs,
-            validation_deadline,
-        }
-    }
+/// A response is only accepted if it is signed by the issuer of the
+/// server's certificate (or by a responder the issuer authorized),
+/// identifies that certificate, is current, and gives it a `good` status.
+/// A response saying the certificate is revoked is always fatal, unless
+/// `mode` is [`OcspMode::Ignore`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OcspPolicy {
+    /// What to do with stapled responses.
+    pub mode: OcspMode,
+
+    /// If true, a server certificate carrying the TLS feature extension
+    /// with `status_request` ("OCSP must-staple", RFC7633) is checked as if
+    /// `mode` were [`OcspMode::HardFail`].
+    pub enforce_must_staple: bool,
+}
 
-    fn verify(
-        &self,
-        cert: &Certificate,
-        now: SystemTime,
-        scts: &mut dyn Iterator<Item = &[u8]>,
-    ) -> Result<(), Error> {
-        if self.logs.is_empty() {
-            return Ok(());
-        } else if self
-            .validation_deadline
-            .duration_since(now)
-            .is_err()
-        {
-            warn!("certificate transparency logs have expired, validation disabled");
-            return Ok(());
-        }
+/// How strictly to check stapled OCSP responses; see [`OcspPolicy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OcspMode {
+    /// Do not check stapled responses at all.  This is the default.
+    Ignore,
 
-        let now = unix_time_millis(now)?;
-        let mut last_sct_error = None;
-        for sct in scts {
-            #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
-            match sct::verify_sct(&cert.0, sct, now, self.logs) {
-                Ok(index) => {
-                    debug!(
-                        "Valid SCT signed by {} on {}",
-                        self.logs[index].operated_by, self.logs[index].description
-                    );
-                    return Ok(());
-                }
-                Err(e) => {
-                    if e.should_be_fatal() {
-                        return Err(Error::InvalidSct(e));
-                    }
-                    debug!("SCT ignored because {:?}", e);
-                    last_sct_error = Some(e);
-                }
-            }
-        }
+    /// Check stapled responses, and fail if one says the certificate is
+    /// revoked.  A missing or invalid response is not an error.
+    SoftFail,
 
-        /* If we were supplied with some logs, and some SCTs,
-         * but couldn't verify any of them, fail the handshake. */
-        if let Some(last_sct_error) = last_sct_error {
-            warn!("No valid SCTs provided");
-            return Err(Error::InvalidSct(last_sct_error));
-        }
+    /// Require a valid response saying the certificate is good.
+    HardFail,
+}
 
-        Ok(())
+impl Default for OcspMode {
+    fn default() -> Self {
+        Self::Ignore
     }
 }
 
@@ -525,10 +631,48 @@ fn prepare<'a, 'b>(
     Ok((cert, intermediates, trustroots))
 }
 
+/// Find the issuer of `cert` among `intermediates` and `roots`, returning
+/// it and, if it is one of `intermediates`, its parsed certificate.
+///
+/// `cert` has already been validated, so this merely has to identify
+/// which of the candidates signed it.
+fn find_issuer<'a>(
+    cert: &ParsedCert,
+    intermediates: &'a [Certificate],
+    roots: &'a RootCertStore,
+) -> Option<(Issuer<'a>, Option<ParsedCert<'a>>)> {
+    for candidate in intermediates
+        .iter()
+        .filter_map(|cert| ParsedCert::parse(&cert.0))
+    {
+        let issuer = Issuer {
+            subject: candidate.subject,
+            spki: candidate.spki,
+        };
+        if cert.is_issued_by(&issuer) {
+            return Some((issuer, Some(candidate)));
+        }
+    }
+
+    roots
+        .roots
+        .iter()
+        .map(|root| {
+            let anchor = root.to_trust_anchor();
+            Issuer {
+                subject: anchor.subject,
+                spki: anchor.spki,
+            }
+        })
+        .find(|issuer| cert.is_issued_by(issuer))
+        .map(|issuer| (issuer, None))
+}
+
 /// A `ClientCertVerifier` that will ensure that every client provides a trusted
 /// certificate, without any name checking.
 pub struct AllowAnyAuthenticatedClient {
     roots: RootCertStore,
+    crls: Vec<CertRevocationList>,
 }
 
 impl AllowAnyAuthenticatedClient {
@@ -536,7 +680,62 @@ impl AllowAnyAuthenticatedClient {
     ///
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
-        Arc::new(Self { roots })
+        Self::new_with_crls(roots, Vec::new())
+    }
+
+    /// Construct a new `AllowAnyAuthenticatedClient` which also checks
+    /// client certificates for revocation.
+    ///
+    /// `roots` is the list of trust anchors to use for certificate validation.
+    ///
+    /// Each certificate in the client's chain (other than the trust anchor)
+    /// is looked up in the CRLs from its issuer in `crls`.  A revoked
+    /// certificate is rejected with [`Error::CertificateRevoked`].  A CRL
+    /// with an invalid signature, or which has expired, causes all
+    /// certificates from its issuer to be rejected.  Certificates whose
+    /// issuer has no CRL in `crls` are not checked.
+    pub fn new_with_crls(
+        roots: RootCertStore,
+        crls: Vec<CertRevocationList>,
+    ) -> Arc<dyn ClientCertVerifier> {
+        Arc::new(Self { roots, crls })
+    }
+
+    fn check_crls(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        if self.crls.is_empty() {
+            return Ok(());
+        }
+
+        let now = now
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| Error::FailedToGetCurrentTime)?
+            .as_secs();
+        let mut cert = ParsedCert::parse(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+
+        // The chain can't be longer than this, and this also stops us
+        // going round in circles.
+        for _ in 0..=intermediates.len() {
+            let (issuer, issuer_cert) = match find_issuer(&cert, intermediates, &self.roots) {
+                Some(found) => found,
+                None => break,
+            };
+
+            for crl in &self.crls {
+                crl.check(&cert, &issuer, now)?;
+            }
+
+            match issuer_cert {
+                Some(issuer_cert) => cert = issuer_cert,
+                None => break,
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -557,15 +756,17 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
         now: SystemTime,
     ) -> Result<ClientCertVerified, Error> {
         let (cert, chain, trustroots) = prepare(end_entity, intermediates, &self.roots)?;
-        let now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
+        let webpki_now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
         cert.verify_is_valid_tls_client_cert(
             SUPPORTED_SIG_ALGS,
             &webpki::TlsClientTrustAnchors(&trustroots),
             &chain,
-            now,
+            webpki_now,
         )
-        .map_err(pki_error)
-        .map(|_| ClientCertVerified::assertion())
+        .map_err(pki_error)?;
+
+        self.check_crls(end_entity, intermediates, now)?;
+        Ok(ClientCertVerified::assertion())
     }
 }
 
@@ -584,8 +785,21 @@ impl AllowAnyAnonymousOrAuthenticatedClient {
     ///
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
+        Self::new_with_crls(roots, Vec::new())
+    }
+
+    /// Construct a new `AllowAnyAnonymousOrAuthenticatedClient` which also
+    /// checks client certificates for revocation.
+    ///
+    /// `roots` is the list of trust anchors to use for certificate validation,
+    /// and `crls` is used as described in
+    /// [`AllowAnyAuthenticatedClient::new_with_crls`].
+    pub fn new_with_crls(
+        roots: RootCertStore,
+        crls: Vec<CertRevocationList>,
+    ) -> Arc<dyn ClientCertVerifier> {
         Arc::new(Self {
-            inner: AllowAnyAuthenticatedClient { roots },
+            inner: AllowAnyAuthenticatedClient { roots, crls },
         })
     }
 }
@@ -655,6 +869,112 @@ impl ClientCertVerifier for NoClientAuth {
     }
 }
 
+/// Something that can decide whether to trust a server which
+/// authenticated with a raw public key (RFC7250), rather than a
+/// certificate chain.
+///
+/// Offering raw public keys is controlled by
+/// [`ClientConfig::server_raw_key_verifier`](crate::ClientConfig::server_raw_key_verifier).
+pub trait ServerRawKeyVerifier: Send + Sync {
+    /// Return `Ok(())` if `spki` is a key which may be used to
+    /// authenticate as `server_name`.
+    ///
+    /// `spki` is a DER-encoded `SubjectPublicKeyInfo`.  The server's
+    /// possession of the corresponding private key is checked
+    /// separately by rustls.
+    fn verify_server_raw_key(
+        &self,
+        spki: &SubjectPublicKeyInfo,
+        server_name: &ServerName,
+        now: SystemTime,
+    ) -> Result<(), Error>;
+}
+
+impl fmt::Debug for dyn ServerRawKeyVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dyn ServerRawKeyVerifier")
+    }
+}
+
+/// Something that can decide whether to trust a client which
+/// authenticated with a raw public key (RFC7250), rather than a
+/// certificate chain.
+///
+/// Accepting raw public keys is controlled by
+/// [`ServerConfig::client_raw_key_verifier`](crate::ServerConfig::client_raw_key_verifier).
+pub trait ClientRawKeyVerifier: Send + Sync {
+    /// Return `Ok(())` if `spki` is a key which may be used to
+    /// authenticate as a client.
+    ///
+    /// `spki` is a DER-encoded `SubjectPublicKeyInfo`.  The client's
+    /// possession of the corresponding private key is checked
+    /// separately by rustls.
+    fn verify_client_raw_key(
+        &self,
+        spki: &SubjectPublicKeyInfo,
+        now: SystemTime,
+    ) -> Result<(), Error>;
+}
+
+impl fmt::Debug for dyn ClientRawKeyVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dyn ClientRawKeyVerifier")
+    }
+}
+
+/// A [`ServerRawKeyVerifier`] and [`ClientRawKeyVerifier`] which
+/// trusts exactly the given set of public keys.
+///
+/// The server name is not considered: any trusted key is accepted
+/// for any server.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedRawKeys {
+    keys: Vec<SubjectPublicKeyInfo>,
+}
+
+impl TrustedRawKeys {
+    /// Make a new `TrustedRawKeys` which trusts `keys`.
+    pub fn new(keys: Vec<SubjectPublicKeyInfo>) -> Self {
+        Self { keys }
+    }
+
+    /// Add `key` to the set of trusted keys.
+    pub fn add(&mut self, key: SubjectPublicKeyInfo) {
+        self.keys.push(key);
+    }
+
+    fn check(&self, spki: &SubjectPublicKeyInfo) -> Result<(), Error> {
+        if self.keys.contains(spki) {
+            Ok(())
+        } else {
+            Err(Error::InvalidCertificateData(
+                "raw public key is not trusted".to_string(),
+            ))
+        }
+    }
+}
+
+impl ServerRawKeyVerifier for TrustedRawKeys {
+    fn verify_server_raw_key(
+        &self,
+        spki: &SubjectPublicKeyInfo,
+        _server_name: &ServerName,
+        _now: SystemTime,
+    ) -> Result<(), Error> {
+        self.check(spki)
+    }
+}
+
+impl ClientRawKeyVerifier for TrustedRawKeys {
+    fn verify_client_raw_key(
+        &self,
+        spki: &SubjectPublicKeyInfo,
+        _now: SystemTime,
+    ) -> Result<(), Error> {
+        self.check(spki)
+    }
+}
+
 static ECDSA_SHA256: SignatureAlgorithms =
     &[&webpki::ECDSA_P256_SHA256, &webpki::ECDSA_P384_SHA256];
 
@@ -744,17 +1064,17 @@ fn convert_alg_tls13(
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_client_verify_message(handshake_hash: &hash::Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, client CertificateVerify\x00")
 }
 
 /// Constructs the signature message specified in section 4.4.3 of RFC8446.
-pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &Digest) -> Vec<u8> {
+pub(crate) fn construct_tls13_server_verify_message(handshake_hash: &hash::Output) -> Vec<u8> {
     construct_tls13_verify_message(handshake_hash, b"TLS 1.3, server CertificateVerify\x00")
 }
 
 fn construct_tls13_verify_message(
-    handshake_hash: &Digest,
+    handshake_hash: &hash::Output,
     context_string_with_0: &[u8],
 ) -> Vec<u8> {
     let mut msg = Vec::new();
@@ -764,6 +1084,54 @@ fn construct_tls13_verify_message(
     msg
 }
 
+/// The longest validity period a delegated credential may have,
+/// in seconds (see section 4.1.3 of RFC9345).
+pub(crate) const MAX_DELEGATED_CREDENTIAL_VALIDITY: u64 = 7 * 24 * 60 * 60;
+
+/// Constructs the message signed by a delegated credential's issuer,
+/// specified in section 4 of RFC9345.
+pub(crate) fn construct_delegated_credential_message(
+    end_entity: &Certificate,
+    cred: &Credential,
+    algorithm: SignatureScheme,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.resize(64, 0x20u8);
+    msg.extend_from_slice(b"TLS, server delegated credentials\x00");
+    msg.extend_from_slice(&end_entity.0);
+    cred.encode(&mut msg);
+    algorithm.encode(&mut msg);
+    msg
+}
+
+/// Check the delegated credential `cred`, issued for `end_entity`, is
+/// valid at `now` and does not claim too long a validity period.
+pub(crate) fn check_delegated_credential_time(
+    end_entity: &Certificate,
+    cred: &Credential,
+    now: SystemTime,
+) -> Result<(), Error> {
+    let not_before =
+        x509::cert_not_before(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+    let now = now
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|_| Error::FailedToGetCurrentTime)?
+        .as_secs();
+    let valid_until = not_before + u64::from(cred.valid_time);
+
+    if valid_until <= now {
+        Err(Error::InvalidCertificateData(
+            "delegated credential expired".to_string(),
+        ))
+    } else if valid_until - now > MAX_DELEGATED_CREDENTIAL_VALIDITY {
+        Err(Error::InvalidCertificateData(
+            "delegated credential validity period too long".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
 fn verify_tls13(
     msg: &[u8],
     cert: &Certificate,
@@ -778,14 +1146,27 @@ fn verify_tls13(
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
-fn unix_time_millis(now: SystemTime) -> Result<u64, Error> {
-    now.duration_since(std::time::UNIX_EPOCH)
-        .map(|dur| dur.as_secs())
-        .map_err(|_| Error::FailedToGetCurrentTime)
-        .and_then(|secs| {
-            secs.checked_mul(1000)
-                .ok_or(Error::FailedToGetCurrentTime)
-        })
+/// The signature schemes supported by `verify_tls13_raw_key`.
+pub(crate) static RAW_KEY_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// Verify a TLS1.3 `CertificateVerify` signature made by the key
+/// in the DER-encoded `SubjectPublicKeyInfo` `spki`.
+pub(crate) fn verify_tls13_raw_key(
+    provider: &dyn CryptoProvider,
+    msg: &[u8],
+    spki: &[u8],
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, Error> {
+    provider
+        .verify_signature(dss.scheme, spki, msg, dss.signature())
+        .map(|_| HandshakeSignatureValid::assertion())
 }
 
 #[cfg(test)]
@@ -811,4 +1192,84 @@ mod tests {
             "ServerCertVerified(())"
         );
     }
+
+    #[test]
+    fn raw_key_signatures() {
+        use crate::key::PrivateKey;
+        use crate::sign;
+
+        let keys: &[&[u8]] = &[
+            include_bytes!("testdata/nistp256key.pkcs8.der"),
+            include_bytes!("testdata/nistp384key.pkcs8.der"),
+            include_bytes!("testdata/eddsakey.der"),
+            include_bytes!("testdata/rsa2048key.pkcs8.der"),
+        ];
+
+        let msg = b"hello world";
+        for key in keys {
+            let key = sign::any_supported_type(&PrivateKey(key.to_vec())).unwrap();
+            let spki = key.public_key().unwrap();
+            let signer = key
+                .choose_scheme(sign::supported_sign_tls13())
+                .unwrap();
+            let dss = DigitallySignedStruct::new(signer.scheme(), signer.sign(msg).unwrap());
+
+            assert!(
+                verify_tls13_raw_key(crate::crypto::ring::RING, msg, spki.as_ref(), &dss).is_ok()
+            );
+            assert_eq!(
+                verify_tls13_raw_key(
+                    crate::crypto::ring::RING,
+                    b"goodbye world",
+                    spki.as_ref(),
+                    &dss
+                )
+                .unwrap_err(),
+                Error::InvalidCertificateSignature
+            );
+        }
+    }
+
+    #[test]
+    fn raw_key_signature_must_match_key_type() {
+        use crate::key::PrivateKey;
+        use crate::sign;
+
+        let ecdsa = sign::any_supported_type(&PrivateKey(
+            include_bytes!("testdata/nistp256key.pkcs8.der").to_vec(),
+        ))
+        .unwrap();
+        let eddsa = sign::any_supported_type(&PrivateKey(
+            include_bytes!("testdata/eddsakey.der").to_vec(),
+        ))
+        .unwrap();
+
+        let signer = eddsa
+            .choose_scheme(&[SignatureScheme::ED25519])
+            .unwrap();
+        let dss = DigitallySignedStruct::new(signer.scheme(), signer.sign(b"msg").unwrap());
+        assert_eq!(
+            verify_tls13_raw_key(
+                crate::crypto::ring::RING,
+                b"msg",
+                ecdsa.public_key().unwrap().as_ref(),
+                &dss
+            )
+            .unwrap_err(),
+            Error::InvalidCertificateSignatureType
+        );
+    }
+
+    #[test]
+    fn trusted_raw_keys() {
+        let trusted = SubjectPublicKeyInfo(vec![1, 2, 3]);
+        let verifier = TrustedRawKeys::new(vec![trusted.clone()]);
+        let now = SystemTime::now();
+        assert!(verifier
+            .verify_client_raw_key(&trusted, now)
+            .is_ok());
+        assert!(verifier
+            .verify_client_raw_key(&SubjectPublicKeyInfo(vec![1, 2]), now)
+            .is_err());
+    }
 }
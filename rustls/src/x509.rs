@@ -1,6 +1,7 @@
 // Additional x509/asn1 functions to those provided in webpki/ring.
 
 use ring::io::der;
+use ring::signature;
 
 pub(crate) fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
@@ -25,6 +26,605 @@ pub(crate) fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     bytes.insert(0, der::Tag::Sequence as u8);
 }
 
+/// Contents of the `AlgorithmIdentifier` for an ECDSA P-256 public key.
+pub(crate) const ALG_ID_ECDSA_P256: &[u8] = &[
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d,
+    0x03, 0x01, 0x07,
+];
+
+/// Contents of the `AlgorithmIdentifier` for an ECDSA P-384 public key.
+pub(crate) const ALG_ID_ECDSA_P384: &[u8] = &[
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22,
+];
+
+/// Contents of the `AlgorithmIdentifier` for an Ed25519 public key.
+pub(crate) const ALG_ID_ED25519: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+/// Contents of the `AlgorithmIdentifier` for an RSA public key.
+pub(crate) const ALG_ID_RSA_ENCRYPTION: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+
+/// Make a DER `SubjectPublicKeyInfo` from the contents of its
+/// `AlgorithmIdentifier`, and the public key itself.
+pub(crate) fn make_spki(alg_id: &[u8], public_key: &[u8]) -> Vec<u8> {
+    let mut bits = vec![0u8];
+    bits.extend_from_slice(public_key);
+    wrap_in_asn1_len(&mut bits);
+    bits.insert(0, der::Tag::BitString as u8);
+
+    let mut spki = alg_id.to_vec();
+    wrap_in_sequence(&mut spki);
+    spki.extend_from_slice(&bits);
+    wrap_in_sequence(&mut spki);
+    spki
+}
+
+/// Split a DER `SubjectPublicKeyInfo` into the contents of its
+/// `AlgorithmIdentifier`, and the public key itself.
+pub(crate) fn split_spki(spki: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (contents, rest) = read_der(spki, der::Tag::Sequence)?;
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let (alg_id, rest) = read_der(contents, der::Tag::Sequence)?;
+    let (bits, rest) = read_der(rest, der::Tag::BitString)?;
+    if !rest.is_empty() {
+        return None;
+    }
+
+    // We don't expect any unused bits in a public key.
+    match bits.split_first() {
+        Some((0, public_key)) => Some((alg_id, public_key)),
+        _ => None,
+    }
+}
+
+/// OID of the `DelegationUsage` certificate extension (RFC9345).
+const OID_DELEGATION_USAGE: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xda, 0x4b, 0x2c];
+
+/// OID of the `KeyUsage` certificate extension (RFC5280).
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+
+/// OID of the `ExtendedKeyUsage` certificate extension (RFC5280).
+const OID_EXTENDED_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+
+/// OID of the `TLSFeature` certificate extension (RFC7633).
+const OID_TLS_FEATURE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18];
+
+/// The TLS feature for the `status_request` extension.
+const TLS_FEATURE_STATUS_REQUEST: &[u8] = &[0x05];
+
+/// Return the `notBefore` time of the DER-encoded certificate `cert`,
+/// in seconds since the UNIX epoch.
+pub(crate) fn cert_not_before(cert: &[u8]) -> Option<u64> {
+    ParsedCert::parse(cert)?.not_before()
+}
+
+/// Return true if the DER-encoded certificate `cert` permits its key to
+/// issue delegated credentials.
+///
+/// This requires the `DelegationUsage` extension and the `digitalSignature`
+/// key usage; see section 4.2 of RFC9345.
+pub(crate) fn cert_allows_delegation(cert: &[u8]) -> bool {
+    let cert = match ParsedCert::parse(cert) {
+        Some(cert) => cert,
+        None => return false,
+    };
+
+    let digital_signature = match cert
+        .find_extension(OID_KEY_USAGE)
+        .and_then(|value| read_der(value, der::Tag::BitString))
+    {
+        Some((bits, [])) => bits
+            .get(1)
+            .map_or(false, |b| b & 0x80 != 0),
+        _ => false,
+    };
+
+    digital_signature
+        && cert
+            .find_extension(OID_DELEGATION_USAGE)
+            .is_some()
+}
+
+/// Return true if the DER-encoded certificate `cert` requires an OCSP
+/// response to be stapled, by way of the `TLSFeature` extension with
+/// `status_request` ("must-staple"; see RFC7633).
+pub(crate) fn cert_requires_ocsp_staple(cert: &[u8]) -> bool {
+    let features = match ParsedCert::parse(cert)
+        .and_then(|cert| cert.find_extension(OID_TLS_FEATURE))
+        .and_then(|value| read_der(value, der::Tag::Sequence))
+    {
+        Some((features, [])) => features,
+        // A malformed extension is treated as requiring a staple.
+        Some(_) => return true,
+        None => return false,
+    };
+
+    let mut features = features;
+    while let Some((feature, rest)) = read_der(features, der::Tag::Integer) {
+        if feature == TLS_FEATURE_STATUS_REQUEST {
+            return true;
+        }
+        features = rest;
+    }
+
+    !features.is_empty()
+}
+
+/// The fields of a DER-encoded X.509 certificate used by rustls itself.
+///
+/// Unless noted otherwise, each field holds the contents of the
+/// corresponding DER item, without its tag and length.
+pub(crate) struct ParsedCert<'a> {
+    /// The complete `TBSCertificate`, which is the signed data.
+    pub(crate) tbs: &'a [u8],
+    pub(crate) signature_alg: &'a [u8],
+    /// The signature, without the leading unused bits octet.
+    pub(crate) signature: &'a [u8],
+    pub(crate) serial: &'a [u8],
+    pub(crate) issuer: &'a [u8],
+    validity: &'a [u8],
+    pub(crate) subject: &'a [u8],
+    pub(crate) spki: &'a [u8],
+    /// Empty if the certificate has no extensions.
+    extensions: &'a [u8],
+}
+
+impl<'a> ParsedCert<'a> {
+    pub(crate) fn parse(cert: &'a [u8]) -> Option<Self> {
+        let (cert, _) = read_der(cert, der::Tag::Sequence)?;
+        let (tbs, rest) = read_der_element(cert, der::Tag::Sequence)?;
+        let (signature_alg, rest) = read_der(rest, der::Tag::Sequence)?;
+        let signature = match read_der(rest, der::Tag::BitString)? {
+            ([0, signature @ ..], []) => signature,
+            _ => return None,
+        };
+
+        let (tbs_contents, _) = read_der(tbs, der::Tag::Sequence)?;
+        // version is optional.
+        let fields = match read_der(tbs_contents, der::Tag::ContextSpecificConstructed0) {
+            Some((_, rest)) => rest,
+            None => tbs_contents,
+        };
+        let (serial, fields) = read_der(fields, der::Tag::Integer)?;
+        let (_signature, fields) = read_der(fields, der::Tag::Sequence)?;
+        let (issuer, fields) = read_der(fields, der::Tag::Sequence)?;
+        let (validity, fields) = read_der(fields, der::Tag::Sequence)?;
+        let (subject, fields) = read_der(fields, der::Tag::Sequence)?;
+        let (spki, mut fields) = read_der(fields, der::Tag::Sequence)?;
+
+        // Skip issuerUniqueID and subjectUniqueID, if present.
+        let mut extensions: &[u8] = &[];
+        while !fields.is_empty() {
+            let (tag, contents, rest) = read_any_der(fields)?;
+            if tag == der::Tag::ContextSpecificConstructed3 as u8 {
+                extensions = read_der(contents, der::Tag::Sequence)?.0;
+                break;
+            }
+            fields = rest;
+        }
+
+        Some(Self {
+            tbs,
+            signature_alg,
+            signature,
+            serial,
+            issuer,
+            validity,
+            subject,
+            spki,
+            extensions,
+        })
+    }
+
+    /// The `notBefore` time, in seconds since the UNIX epoch.
+    pub(crate) fn not_before(&self) -> Option<u64> {
+        read_time(self.validity).map(|(time, _)| time)
+    }
+
+    /// The `notAfter` time, in seconds since the UNIX epoch.
+    pub(crate) fn not_after(&self) -> Option<u64> {
+        let (_, rest) = read_time(self.validity)?;
+        read_time(rest).map(|(time, _)| time)
+    }
+
+    /// Return true if this certificate was issued by `issuer`.
+    pub(crate) fn is_issued_by(&self, issuer: &Issuer) -> bool {
+        self.issuer == issuer.subject
+            && verify_signature(issuer.spki, self.signature_alg, self.tbs, self.signature)
+    }
+
+    /// Return true if the `ExtendedKeyUsage` extension is present and
+    /// includes the purpose `oid`.
+    pub(crate) fn has_extended_key_usage(&self, oid: &[u8]) -> bool {
+        let mut purposes = match self
+            .find_extension(OID_EXTENDED_KEY_USAGE)
+            .and_then(|value| read_der(value, der::Tag::Sequence))
+        {
+            Some((purposes, _)) => purposes,
+            None => return false,
+        };
+
+        while let Some((purpose, rest)) = read_der(purposes, der::Tag::OID) {
+            if purpose == oid {
+                return true;
+            }
+            purposes = rest;
+        }
+
+        false
+    }
+
+    /// Find the value of the extension with the given `oid`.
+    pub(crate) fn find_extension(&self, oid: &[u8]) -> Option<&'a [u8]> {
+        find_extension(self.extensions, oid)
+    }
+
+    /// Return the complete `TBSCertificate`, re-encoded without the
+    /// extension `oid`, or `None` if there is no such extension.
+    ///
+    /// The `extensions` field is omitted entirely if that leaves it empty.
+    pub(crate) fn tbs_without_extension(&self, oid: &[u8]) -> Option<Vec<u8>> {
+        let (tbs_contents, _) = read_der(self.tbs, der::Tag::Sequence)?;
+
+        // Find where the extensions field starts.
+        let mut fields = tbs_contents;
+        while !fields.is_empty() {
+            let (tag, _, rest) = read_any_der(fields)?;
+            if tag == der::Tag::ContextSpecificConstructed3 as u8 {
+                break;
+            }
+            fields = rest;
+        }
+
+        let mut found = false;
+        let mut kept = Vec::new();
+        let mut extensions = self.extensions;
+        while !extensions.is_empty() {
+            let (extension, rest) = read_der_element(extensions, der::Tag::Sequence)?;
+            extensions = rest;
+
+            let (contents, _) = read_der(extension, der::Tag::Sequence)?;
+            let (id, _) = read_der(contents, der::Tag::OID)?;
+            if id == oid {
+                found = true;
+            } else {
+                kept.extend_from_slice(extension);
+            }
+        }
+
+        if !found {
+            return None;
+        }
+
+        let mut tbs = tbs_contents[..tbs_contents.len() - fields.len()].to_vec();
+        if !kept.is_empty() {
+            wrap_in_sequence(&mut kept);
+            wrap_in_asn1_len(&mut kept);
+            kept.insert(0, der::Tag::ContextSpecificConstructed3 as u8);
+            tbs.extend_from_slice(&kept);
+        }
+        wrap_in_sequence(&mut tbs);
+        Some(tbs)
+    }
+}
+
+/// Find the value of the extension with the given `oid` in the contents
+/// of the DER `Extensions` SEQUENCE `extensions`.
+pub(crate) fn find_extension<'a>(extensions: &'a [u8], oid: &[u8]) -> Option<&'a [u8]> {
+    let mut extensions = extensions;
+
+    while !extensions.is_empty() {
+        let (extension, rest) = read_der(extensions, der::Tag::Sequence)?;
+        extensions = rest;
+
+        let (id, extension) = read_der(extension, der::Tag::OID)?;
+        // critical is optional.
+        let extension = match read_der(extension, der::Tag::Boolean) {
+            Some((_, rest)) => rest,
+            None => extension,
+        };
+        let (value, _) = read_der(extension, der::Tag::OctetString)?;
+
+        if id == oid {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/// The issuer of a certificate, CRL or OCSP response.
+///
+/// Both fields are the contents of the corresponding DER items, as found
+/// in `ParsedCert` or a `webpki::TrustAnchor`.
+pub(crate) struct Issuer<'a> {
+    pub(crate) subject: &'a [u8],
+    pub(crate) spki: &'a [u8],
+}
+
+/// Verify `signature` over `message`, made with the algorithm described by
+/// the contents of the `AlgorithmIdentifier` `signature_alg`, using the key
+/// in the contents of the `SubjectPublicKeyInfo` `spki`.
+pub(crate) fn verify_signature(
+    spki: &[u8],
+    signature_alg: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> bool {
+    let mut spki = spki.to_vec();
+    wrap_in_sequence(&mut spki);
+    let (key_alg, public_key) = match split_spki(&spki) {
+        Some(split) => split,
+        None => return false,
+    };
+
+    // Parameters are ignored: they are absent or NULL for the algorithms we support.
+    let signature_alg = match read_der(signature_alg, der::Tag::OID) {
+        Some((oid, _)) => oid,
+        None => return false,
+    };
+
+    let alg: &dyn signature::VerificationAlgorithm = match (signature_alg, key_alg) {
+        (OID_SHA256_WITH_RSA, ALG_ID_RSA_ENCRYPTION) => &signature::RSA_PKCS1_2048_8192_SHA256,
+        (OID_SHA384_WITH_RSA, ALG_ID_RSA_ENCRYPTION) => &signature::RSA_PKCS1_2048_8192_SHA384,
+        (OID_SHA512_WITH_RSA, ALG_ID_RSA_ENCRYPTION) => &signature::RSA_PKCS1_2048_8192_SHA512,
+        (OID_ECDSA_WITH_SHA256, ALG_ID_ECDSA_P256) => &signature::ECDSA_P256_SHA256_ASN1,
+        (OID_ECDSA_WITH_SHA256, ALG_ID_ECDSA_P384) => &signature::ECDSA_P384_SHA256_ASN1,
+        (OID_ECDSA_WITH_SHA384, ALG_ID_ECDSA_P256) => &signature::ECDSA_P256_SHA384_ASN1,
+        (OID_ECDSA_WITH_SHA384, ALG_ID_ECDSA_P384) => &signature::ECDSA_P384_SHA384_ASN1,
+        (OID_ED25519, ALG_ID_ED25519) => &signature::ED25519,
+        _ => return false,
+    };
+
+    signature::UnparsedPublicKey::new(alg, public_key)
+        .verify(message, signature)
+        .is_ok()
+}
+
+const OID_SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// Read a `UTCTime` or `GeneralizedTime` from the front of `input`,
+/// returning it in seconds since the UNIX epoch, and whatever follows it.
+pub(crate) fn read_time(input: &[u8]) -> Option<(u64, &[u8])> {
+    let (tag, time, rest) = read_any_der(input)?;
+    let time = if tag == der::Tag::UTCTime as u8 {
+        parse_time(time, false)?
+    } else if tag == der::Tag::GeneralizedTime as u8 {
+        parse_time(time, true)?
+    } else {
+        return None;
+    };
+    Some((time, rest))
+}
+
+/// Parse the contents of a `UTCTime` (or, if `generalized`, a
+/// `GeneralizedTime`) into seconds since the UNIX epoch.
+///
+/// Only the forms allowed by RFC5280 are accepted: these are in UTC and
+/// have no fractional seconds.
+fn parse_time(time: &[u8], generalized: bool) -> Option<u64> {
+    let expected_len = if generalized { 15 } else { 13 };
+    if time.len() != expected_len || time.last() != Some(&b'Z') {
+        return None;
+    }
+
+    let mut digits = time[..expected_len - 1]
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi @ b'0'..=b'9', lo @ b'0'..=b'9'] => Some(u64::from((hi - b'0') * 10 + (lo - b'0'))),
+            _ => None,
+        });
+    let mut next = || digits.next().flatten();
+
+    let year = if generalized {
+        next()? * 100 + next()?
+    } else {
+        // RFC5280 section 4.1.2.5.1.
+        match next()? {
+            yy @ 0..=49 => 2000 + yy,
+            yy => 1900 + yy,
+        }
+    };
+    let (month, day) = (next()?, next()?);
+    let (hour, minute, second) = (next()?, next()?, next()?);
+
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    Some(((days_since_epoch(year, month, day) * 24 + hour) * 60 + minute) * 60 + second)
+}
+
+/// Number of days from 1970-01-01 until the given (proleptic Gregorian) date.
+pub(crate) fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // This is `days_from_civil` from http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Read one DER item with the given `tag` from the front of `input`,
+/// returning its contents and whatever follows it.
+pub(crate) fn read_der(input: &[u8], tag: der::Tag) -> Option<(&[u8], &[u8])> {
+    match read_any_der(input)? {
+        (actual, contents, rest) if actual == tag as u8 => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Like `read_der`, but return the whole item (including its tag and
+/// length) rather than its contents.
+pub(crate) fn read_der_element(input: &[u8], tag: der::Tag) -> Option<(&[u8], &[u8])> {
+    let (_, rest) = read_der(input, tag)?;
+    Some(input.split_at(input.len() - rest.len()))
+}
+
+/// Read one DER item from the front of `input`, returning its tag,
+/// its contents and whatever follows it.
+pub(crate) fn read_any_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+
+    let (&first, mut input) = input.split_first()?;
+    let len = match first {
+        0x00..=0x7f => first as usize,
+        0x81 | 0x82 => {
+            let octets = (first & 0x7f) as usize;
+            if input.len() < octets {
+                return None;
+            }
+            let (len_bytes, rest) = input.split_at(octets);
+            input = rest;
+            let len = len_bytes
+                .iter()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            // Non-minimal encodings are not DER.
+            if len < 0x80 || (octets == 2 && len < 0x100) {
+                return None;
+            }
+            len
+        }
+        _ => return None,
+    };
+
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+#[test]
+fn test_spki_round_trip() {
+    let spki = make_spki(ALG_ID_ED25519, &[0x42; 32]);
+    assert_eq!(&spki[..4], &[0x30, 0x2a, 0x30, 0x05]);
+    assert_eq!(split_spki(&spki), Some((ALG_ID_ED25519, &[0x42u8; 32][..])));
+
+    let spki = make_spki(ALG_ID_RSA_ENCRYPTION, &[0x42; 270]);
+    assert_eq!(
+        split_spki(&spki),
+        Some((ALG_ID_RSA_ENCRYPTION, &[0x42u8; 270][..]))
+    );
+}
+
+#[test]
+fn test_spki_rejects_malformed() {
+    let spki = make_spki(ALG_ID_ED25519, &[0x42; 32]);
+    assert!(split_spki(&spki[..spki.len() - 1]).is_none());
+
+    let mut trailing = spki.clone();
+    trailing.push(0);
+    assert!(split_spki(&trailing).is_none());
+
+    let mut unused_bits = spki;
+    unused_bits[11] = 1;
+    assert!(split_spki(&unused_bits).is_none());
+
+    assert!(split_spki(&[0x30, 0x81, 0x01, 0x00]).is_none());
+}
+
+#[test]
+fn test_cert_not_before() {
+    let cert = include_bytes!("testdata/cert-github.0.der");
+    assert_eq!(cert_not_before(cert), Some(1_616_630_400));
+
+    let cert = include_bytes!("testdata/cert-delegation-usage.der");
+    assert_eq!(cert_not_before(cert), Some(1_792_206_475));
+
+    assert_eq!(cert_not_before(&cert[..100]), None);
+}
+
+#[test]
+fn test_cert_allows_delegation() {
+    assert!(cert_allows_delegation(include_bytes!(
+        "testdata/cert-delegation-usage.der"
+    )));
+    assert!(!cert_allows_delegation(include_bytes!(
+        "testdata/cert-github.0.der"
+    )));
+    assert!(!cert_allows_delegation(&[]));
+}
+
+#[test]
+fn test_cert_requires_ocsp_staple() {
+    assert!(cert_requires_ocsp_staple(include_bytes!(
+        "testdata/cert-must-staple.der"
+    )));
+    assert!(!cert_requires_ocsp_staple(include_bytes!(
+        "testdata/ocsp-end.der"
+    )));
+    assert!(!cert_requires_ocsp_staple(&[]));
+}
+
+#[test]
+fn test_parsed_cert() {
+    let cert = ParsedCert::parse(include_bytes!("testdata/ocsp-end.der")).unwrap();
+    assert_eq!(cert.serial, &[0x01, 0xc8]);
+    assert!(cert.not_before() < cert.not_after());
+    assert!(!cert.has_extended_key_usage(&[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x09]));
+
+    let issuer = ParsedCert::parse(include_bytes!("testdata/ocsp-issuer.der")).unwrap();
+    assert_eq!(cert.issuer, issuer.subject);
+
+    assert!(ParsedCert::parse(&[]).is_none());
+}
+
+#[test]
+fn test_verify_signature() {
+    let cert = ParsedCert::parse(include_bytes!("testdata/ocsp-end.der")).unwrap();
+    let issuer = ParsedCert::parse(include_bytes!("testdata/ocsp-issuer.der")).unwrap();
+    assert!(verify_signature(
+        issuer.spki,
+        cert.signature_alg,
+        cert.tbs,
+        cert.signature
+    ));
+    assert!(!verify_signature(
+        cert.spki,
+        cert.signature_alg,
+        cert.tbs,
+        cert.signature
+    ));
+    assert!(!verify_signature(
+        issuer.spki,
+        cert.signature_alg,
+        &cert.tbs[1..],
+        cert.signature
+    ));
+}
+
+#[test]
+fn test_parse_time() {
+    assert_eq!(parse_time(b"700101000000Z", false), Some(0));
+    assert_eq!(parse_time(b"491231235959Z", false), Some(2_524_607_999));
+    assert_eq!(parse_time(b"20000229120000Z", true), Some(951_825_600));
+    assert_eq!(parse_time(b"21060207062816Z", true), Some(4_294_967_296));
+
+    assert_eq!(parse_time(b"19691231235959Z", true), None);
+    assert_eq!(parse_time(b"700101000000", false), None);
+    assert_eq!(parse_time(b"701301000000Z", false), None);
+    assert_eq!(parse_time(b"7001010000a0Z", false), None);
+    assert_eq!(parse_time(b"20000229120000.5Z", true), None);
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
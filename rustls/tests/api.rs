@@ -7,23 +7,33 @@ use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
 use log;
 
-use rustls::client::ResolvesClientCert;
+use rustls::client::{EchConfig, EchStatus, OcspMode, OcspPolicy, ResolvesClientCert};
+use rustls::compress::{CertCompressor, CertDecompressor, CompressionFailed, DecompressionFailed};
 use rustls::internal::msgs::base::Payload;
 use rustls::internal::msgs::codec::Codec;
 #[cfg(feature = "quic")]
 use rustls::quic::{self, ClientQuicExt, QuicExt, ServerQuicExt};
-use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
+#[cfg(feature = "ech_server")]
+use rustls::server::EchKeyPair;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    PostHandshakeAuthStatus, ResolvesServerCert, ResolvesServerPsk, SessionLookup,
+    SigningKeySource, SigningRequest,
+};
 #[cfg(feature = "secret_extraction")]
 use rustls::ConnectionTrafficSecrets;
-use rustls::{sign, ConnectionCommon, Error, KeyLog, SideData};
-use rustls::{CipherSuite, ProtocolVersion, SignatureScheme};
+use rustls::SignatureAlgorithm;
+use rustls::{sign, Certificate, ConnectionCommon, Error, KeyLog, SideData, TrustedRawKeys};
+use rustls::{CertificateCompressionAlgorithm, CipherSuite, ProtocolVersion, SignatureScheme};
 use rustls::{ClientConfig, ClientConnection};
+use rustls::{ExternalPsk, MaxFragmentLength, PSKKeyExchangeMode};
+use rustls::{RootCertStore, Stream, StreamOwned};
 use rustls::{ServerConfig, ServerConnection};
-use rustls::{Stream, StreamOwned};
-use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES};
+use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES};
 
 mod common;
 use crate::common::*;
@@ -409,36 +419,238 @@ fn server_can_get_client_cert_after_resumption() {
     }
 }
 
+#[test]
+fn server_accepts_client_cert_not_in_crl() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_client_crls(
+            *kt,
+            &["inter.crl"],
+            true,
+        ));
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake_until_error(&mut client, &mut server).unwrap();
+        }
+    }
+}
+
+#[test]
+fn server_rejects_revoked_client_cert() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for crl in ["client.revoked.crl", "inter.revoked.crl"] {
+            for client_auth_mandatory in [true, false] {
+                let server_config = Arc::new(make_server_config_with_client_crls(
+                    *kt,
+                    &["inter.crl", crl],
+                    client_auth_mandatory,
+                ));
+
+                for version in rustls::ALL_VERSIONS {
+                    let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+                    let (mut client, mut server) =
+                        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+                    assert_eq!(
+                        do_handshake_until_both_error(&mut client, &mut server),
+                        Err(vec![
+                            ErrorFromPeer::Server(Error::CertificateRevoked),
+                            ErrorFromPeer::Client(Error::AlertReceived(
+                                rustls::AlertDescription::CertificateRevoked
+                            )),
+                        ])
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn server_with_crls_accepts_anonymous_client() {
+    let server_config =
+        make_server_config_with_client_crls(KeyType::Ecdsa, &["client.revoked.crl"], false);
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Ecdsa), server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
 #[test]
 fn test_config_builders_debug() {
     let b = ServerConfig::builder();
     assert_eq!(
-        "ConfigBuilder<ServerConfig, _> { state: WantsCipherSuites(()) }",
+        "ConfigBuilder<ServerConfig, _> { state: WantsCipherSuites(Ring) }",
         format!("{:?}", b)
     );
     let b = b.with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256]);
-    assert_eq!("ConfigBuilder<ServerConfig, _> { state: WantsKxGroups { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256] } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ServerConfig, _> { state: WantsKxGroups { provider: Ring, cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256] } }", format!("{:?}", b));
     let b = b.with_kx_groups(&[&rustls::kx_group::X25519]);
-    assert_eq!("ConfigBuilder<ServerConfig, _> { state: WantsVersions { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519] } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ServerConfig, _> { state: WantsVersions { provider: Ring, cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519] } }", format!("{:?}", b));
     let b = b
         .with_protocol_versions(&[&rustls::version::TLS13])
         .unwrap();
     let b = b.with_no_client_auth();
-    assert_eq!("ConfigBuilder<ServerConfig, _> { state: WantsServerCert { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3], verifier: dyn ClientCertVerifier } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ServerConfig, _> { state: WantsServerCert { provider: Ring, cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3], verifier: dyn ClientCertVerifier } }", format!("{:?}", b));
 
     let b = ClientConfig::builder();
     assert_eq!(
-        "ConfigBuilder<ClientConfig, _> { state: WantsCipherSuites(()) }",
+        "ConfigBuilder<ClientConfig, _> { state: WantsCipherSuites(Ring) }",
         format!("{:?}", b)
     );
     let b = b.with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256]);
-    assert_eq!("ConfigBuilder<ClientConfig, _> { state: WantsKxGroups { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256] } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ClientConfig, _> { state: WantsKxGroups { provider: Ring, cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256] } }", format!("{:?}", b));
     let b = b.with_kx_groups(&[&rustls::kx_group::X25519]);
-    assert_eq!("ConfigBuilder<ClientConfig, _> { state: WantsVersions { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519] } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ClientConfig, _> { state: WantsVersions { provider: Ring, cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519] } }", format!("{:?}", b));
     let b = b
         .with_protocol_versions(&[&rustls::version::TLS13])
         .unwrap();
-    assert_eq!("ConfigBuilder<ClientConfig, _> { state: WantsVerifier { cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3] } }", format!("{:?}", b));
+    assert_eq!("ConfigBuilder<ClientConfig, _> { state: WantsVerifier { provider: Ring, cipher_suites: [TLS13_CHACHA20_POLY1305_SHA256], kx_groups: [X25519], versions: [TLSv1_3] } }", format!("{:?}", b));
+}
+
+#[derive(Debug)]
+struct CountingProvider {
+    random_calls: AtomicUsize,
+    verify_calls: AtomicUsize,
+}
+
+impl rustls::CryptoProvider for CountingProvider {
+    fn fill_random(&self, buf: &mut [u8]) -> Result<(), rustls::crypto::GetRandomFailed> {
+        self.random_calls
+            .fetch_add(1, Ordering::SeqCst);
+        rustls::crypto::ring::RING.fill_random(buf)
+    }
+
+    fn default_cipher_suites(&self) -> &'static [SupportedCipherSuite] {
+        static SUITES: &[SupportedCipherSuite] =
+            &[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256];
+        SUITES
+    }
+
+    fn default_kx_groups(&self) -> &'static [&'static dyn rustls::SupportedKxGroup] {
+        rustls::crypto::ring::RING.default_kx_groups()
+    }
+
+    fn verify_signature(
+        &self,
+        scheme: SignatureScheme,
+        spki: &[u8],
+        message: &[u8],
+        signature: &[u8],
+    ) -> Result<(), Error> {
+        self.verify_calls
+            .fetch_add(1, Ordering::SeqCst);
+        rustls::crypto::ring::RING.verify_signature(scheme, spki, message, signature)
+    }
+
+    fn load_private_key(
+        &self,
+        key_der: &rustls::PrivateKey,
+    ) -> Result<Arc<dyn sign::SigningKey>, Error> {
+        rustls::crypto::ring::RING.load_private_key(key_der)
+    }
+
+    fn ticket_aead(&self) -> &'static dyn rustls::crypto::aead::Algorithm {
+        rustls::crypto::ring::RING.ticket_aead()
+    }
+}
+
+#[test]
+fn test_config_with_custom_crypto_provider() {
+    static CLIENT_PROVIDER: CountingProvider = CountingProvider {
+        random_calls: AtomicUsize::new(0),
+        verify_calls: AtomicUsize::new(0),
+    };
+    static SERVER_PROVIDER: CountingProvider = CountingProvider {
+        random_calls: AtomicUsize::new(0),
+        verify_calls: AtomicUsize::new(0),
+    };
+
+    // Raw public keys are verified by the provider, rather than by webpki.
+    let kt = KeyType::Ecdsa;
+    let mut client_config = finish_client_config(
+        kt,
+        ClientConfig::builder_with_provider(&CLIENT_PROVIDER).with_safe_defaults(),
+    );
+    client_config.server_raw_key_verifier =
+        Some(Arc::new(TrustedRawKeys::new(vec![kt.get_spki()])));
+    let server_config = ServerConfig::builder_with_provider(&SERVER_PROVIDER)
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_raw_public_key(kt.get_key())
+        .unwrap();
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(
+        client.negotiated_cipher_suite(),
+        Some(rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256)
+    );
+    assert!(
+        CLIENT_PROVIDER
+            .random_calls
+            .load(Ordering::SeqCst)
+            > 0
+    );
+    assert!(
+        SERVER_PROVIDER
+            .random_calls
+            .load(Ordering::SeqCst)
+            > 0
+    );
+    assert_eq!(
+        CLIENT_PROVIDER
+            .verify_calls
+            .load(Ordering::SeqCst),
+        1
+    );
+}
+
+#[test]
+fn test_ticketers_use_crypto_provider() {
+    use rustls::server::ProducesTickets;
+
+    static PROVIDER: CountingProvider = CountingProvider {
+        random_calls: AtomicUsize::new(0),
+        verify_calls: AtomicUsize::new(0),
+    };
+
+    let ticketer = rustls::Ticketer::new_with_provider(&PROVIDER).unwrap();
+    let before = PROVIDER
+        .random_calls
+        .load(Ordering::SeqCst);
+    let ticket = ticketer.encrypt(b"ticket").unwrap();
+    assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"ticket");
+    assert!(
+        PROVIDER
+            .random_calls
+            .load(Ordering::SeqCst)
+            > before
+    );
+
+    let key = rustls::TicketKey::new_with_provider(&PROVIDER, [1; 16], &[2; 32]).unwrap();
+    let ticketer = rustls::SharedTicketer::new(key, vec![], 3600);
+    let before = PROVIDER
+        .random_calls
+        .load(Ordering::SeqCst);
+    ticketer.encrypt(b"ticket").unwrap();
+    assert_eq!(
+        PROVIDER
+            .random_calls
+            .load(Ordering::SeqCst),
+        before + 1
+    );
+
+    // Initial packets need TLS13_AES_128_GCM_SHA256, which this provider lacks.
+    #[cfg(feature = "quic")]
+    assert!(rustls::quic::Keys::initial_with_provider(
+        rustls::quic::Version::V1,
+        &PROVIDER,
+        &[0; 8],
+        rustls::Side::Client
+    )
+    .is_err());
 }
 
 /// Test that the server handles combination of `offer_client_auth()` returning true
@@ -858,1970 +1070,4259 @@ fn client_with_sni_disabled_does_not_send_sni() {
     }
 }
 
+struct ResolvesByCertificateAuthorities {
+    chains: Vec<(Vec<u8>, Arc<sign::CertifiedKey>)>,
+}
+
+impl ResolvesByCertificateAuthorities {
+    fn new() -> Self {
+        let chains = ALL_KEY_TYPES
+            .iter()
+            .map(|kt| {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(kt.get_chain().last().unwrap())
+                    .unwrap();
+                #[allow(deprecated)]
+                let subjects = roots.subjects();
+                let key = sign::any_supported_type(&kt.get_key()).unwrap();
+                (
+                    subjects[0].0.clone(),
+                    Arc::new(sign::CertifiedKey::new(kt.get_chain(), key)),
+                )
+            })
+            .collect();
+        Self { chains }
+    }
+}
+
+impl ResolvesServerCert for ResolvesByCertificateAuthorities {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        let authorities = client_hello.certificate_authorities()?;
+        self.chains
+            .iter()
+            .find(|(subject, _)| {
+                authorities
+                    .iter()
+                    .any(|name| &name.0 == subject)
+            })
+            .map(|(_, key)| Arc::clone(key))
+    }
+}
+
 #[test]
-fn client_checks_server_certificate_with_given_name() {
+fn server_cert_resolve_with_certificate_authorities() {
+    let resolver = Arc::new(ResolvesByCertificateAuthorities::new());
+
     for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config(*kt));
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config.send_certificate_authorities = true;
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>;
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let mut client = ClientConnection::new(
-                Arc::new(client_config),
-                dns_name("not-the-right-hostname.com"),
-            )
-            .unwrap();
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+    }
+}
+
+#[test]
+fn client_sends_certificate_authorities_only_if_enabled_for_tls13() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(ResolvesByCertificateAuthorities::new());
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        for enabled in [false, true] {
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.send_certificate_authorities = enabled;
+
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
             let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-            let err = do_handshake_until_error(&mut client, &mut server);
+            let result = do_handshake_until_error(&mut client, &mut server);
             assert_eq!(
-                err,
-                Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
-                    "invalid peer certificate: CertNotValidForName".into(),
-                )))
+                result.is_ok(),
+                enabled && version.version == ProtocolVersion::TLSv1_3
             );
         }
     }
 }
 
-struct ClientCheckCertResolve {
-    query_count: AtomicUsize,
-    expect_queries: usize,
-}
+/// A `SigningKey` which never signs anything, to show a delegated key
+/// was used instead.
+struct RefusesToSign;
 
-impl ClientCheckCertResolve {
-    fn new(expect_queries: usize) -> Self {
-        ClientCheckCertResolve {
-            query_count: AtomicUsize::new(0),
-            expect_queries,
-        }
+impl sign::SigningKey for RefusesToSign {
+    fn choose_scheme(&self, _offered: &[SignatureScheme]) -> Option<Box<dyn sign::Signer>> {
+        None
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ECDSA
     }
 }
 
-impl Drop for ClientCheckCertResolve {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            let count = self.query_count.load(Ordering::SeqCst);
-            assert_eq!(count, self.expect_queries);
-        }
+struct AlwaysResolvesKey(Arc<sign::CertifiedKey>);
+
+impl ResolvesServerCert for AlwaysResolvesKey {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        Some(Arc::clone(&self.0))
     }
 }
 
-impl ResolvesClientCert for ClientCheckCertResolve {
-    fn resolve(
-        &self,
-        acceptable_issuers: &[&[u8]],
-        sigschemes: &[SignatureScheme],
-    ) -> Option<Arc<sign::CertifiedKey>> {
-        self.query_count
-            .fetch_add(1, Ordering::SeqCst);
+fn make_delegated_key() -> Arc<dyn sign::SigningKey> {
+    let pkcs8 =
+        ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+    sign::any_eddsa_type(&rustls::PrivateKey(pkcs8.as_ref().to_vec())).unwrap()
+}
 
-        if acceptable_issuers.is_empty() {
-            panic!("no issuers offered by server");
-        }
+fn make_delegated_credential(chain: &[Certificate], kt: KeyType) -> sign::DelegatedCredential {
+    sign::DelegatedCredential::new(
+        &chain[0],
+        &*sign::any_supported_type(&kt.get_key()).unwrap(),
+        make_delegated_key(),
+        Duration::from_secs(3600),
+        SystemTime::now(),
+    )
+    .unwrap()
+}
 
-        if sigschemes.is_empty() {
-            panic!("no signature schemes shared by server");
-        }
+/// Make a server config whose only usable key is that of `dc`.
+fn make_server_config_with_delegated_credential(
+    kt: KeyType,
+    chain: Vec<Certificate>,
+    dc: sign::DelegatedCredential,
+) -> ServerConfig {
+    let mut certified_key = sign::CertifiedKey::new(chain, Arc::new(RefusesToSign));
+    certified_key.delegated_credential = Some(dc);
 
-        None
-    }
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(AlwaysResolvesKey(Arc::new(certified_key)));
+    server_config
+}
 
-    fn has_certs(&self) -> bool {
-        true
+#[test]
+fn server_signs_with_delegated_credential() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_delegation_chain();
+        let dc = make_delegated_credential(&chain, *kt);
+        let server_config = make_server_config_with_delegated_credential(*kt, chain.clone(), dc);
+
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config.enable_delegated_credentials = true;
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.peer_certificates(), Some(&chain[..]));
     }
 }
 
 #[test]
-fn client_cert_resolve() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn server_uses_delegated_credential_only_if_client_supports_it() {
+    let chain = KeyType::Ecdsa.get_delegation_chain();
+    let dc = make_delegated_credential(&chain, KeyType::Ecdsa);
+    let server_config = Arc::new(make_server_config_with_delegated_credential(
+        KeyType::Ecdsa,
+        chain,
+        dc,
+    ));
 
-        for version in rustls::ALL_VERSIONS {
-            let mut client_config = make_client_config_with_versions(*kt, &[version]);
-            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+    for version in rustls::ALL_VERSIONS {
+        for enabled in [false, true] {
+            let mut client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+            client_config.enable_delegated_credentials = enabled;
 
             let (mut client, mut server) =
                 make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-
+            let result = do_handshake_until_error(&mut client, &mut server);
             assert_eq!(
-                do_handshake_until_error(&mut client, &mut server),
-                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+                result.is_ok(),
+                enabled && version.version == ProtocolVersion::TLSv1_3
             );
         }
     }
 }
 
 #[test]
-fn client_auth_works() {
+fn client_rejects_delegated_credential_without_delegation_usage() {
     for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+        let chain = kt.get_chain();
+        let dc = make_delegated_credential(&chain, *kt);
+        let server_config = make_server_config_with_delegated_credential(*kt, chain, dc);
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            do_handshake(&mut client, &mut server);
-        }
+        let mut client_config = make_client_config(*kt);
+        client_config.enable_delegated_credentials = true;
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
+                "certificate does not permit delegated credentials".into()
+            )))
+        );
     }
 }
 
 #[test]
-fn client_error_is_sticky() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    client
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = client.process_new_packets();
-    assert!(err.is_err());
-    err = client.process_new_packets();
-    assert!(err.is_err());
+fn client_checks_delegated_credential_validity_period() {
+    let chain = KeyType::Ecdsa.get_delegation_chain();
+    let encoded = make_delegated_credential(&chain, KeyType::Ecdsa).encoded();
+
+    // The credential starts with its validity period, relative to the
+    // certificate's notBefore.
+    for (valid_time, expected_error) in [
+        (0u32, "delegated credential expired"),
+        (u32::MAX, "delegated credential validity period too long"),
+    ] {
+        let mut encoded = encoded.clone();
+        encoded[..4].copy_from_slice(&valid_time.to_be_bytes());
+        let dc = sign::DelegatedCredential::from_encoded(&encoded, make_delegated_key()).unwrap();
+        let server_config =
+            make_server_config_with_delegated_credential(KeyType::Ecdsa, chain.clone(), dc);
+
+        let mut client_config = make_client_config(KeyType::Ecdsa);
+        client_config.enable_delegated_credentials = true;
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
+                expected_error.into()
+            )))
+        );
+    }
 }
 
 #[test]
-fn server_error_is_sticky() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    server
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = server.process_new_packets();
-    assert!(err.is_err());
-    err = server.process_new_packets();
-    assert!(err.is_err());
+fn delegated_credential_construction() {
+    let chain = KeyType::Rsa.get_delegation_chain();
+    let cert_key = sign::any_supported_type(&KeyType::Rsa.get_key()).unwrap();
+
+    assert!(sign::DelegatedCredential::new(
+        &chain[0],
+        &*cert_key,
+        make_delegated_key(),
+        Duration::from_secs(8 * 24 * 60 * 60),
+        SystemTime::now(),
+    )
+    .is_err());
+
+    let dc = make_delegated_credential(&chain, KeyType::Rsa);
+    assert_eq!(dc.scheme(), SignatureScheme::ED25519);
+
+    let key = make_delegated_key();
+    let decoded = sign::DelegatedCredential::from_encoded(&dc.encoded(), key).unwrap();
+    assert_eq!(decoded.encoded(), dc.encoded());
+
+    let mut trailing = dc.encoded();
+    trailing.push(0);
+    assert!(sign::DelegatedCredential::from_encoded(&trailing, make_delegated_key()).is_err());
+
+    // The delegated key must be able to sign using the credential's scheme.
+    assert!(sign::DelegatedCredential::from_encoded(&dc.encoded(), cert_key).is_err());
+}
+
+fn check_ocsp_handshake(
+    server_config: ServerConfig,
+    ocsp_policy: OcspPolicy,
+    expected: Result<(), Error>,
+) {
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config =
+            make_client_config_with_ocsp_policy(KeyType::Ecdsa, &[version], ocsp_policy);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            expected
+                .clone()
+                .map_err(ErrorFromPeer::Client)
+        );
+    }
 }
 
+const OCSP_SOFT_FAIL: OcspPolicy = OcspPolicy {
+    mode: OcspMode::SoftFail,
+    enforce_must_staple: false,
+};
+
+const OCSP_HARD_FAIL: OcspPolicy = OcspPolicy {
+    mode: OcspMode::HardFail,
+    enforce_must_staple: false,
+};
+
 #[test]
-fn server_flush_does_nothing() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.writer().flush(), Ok(())));
+fn client_accepts_good_ocsp_response() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config =
+            make_server_config_with_ocsp(*kt, kt.get_chain(), kt.get_ocsp_response());
+        let server_config = Arc::new(server_config);
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config =
+                make_client_config_with_ocsp_policy(*kt, &[version], OCSP_HARD_FAIL);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake_until_error(&mut client, &mut server).unwrap();
+        }
+    }
 }
 
 #[test]
-fn client_flush_does_nothing() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.writer().flush(), Ok(())));
+fn client_rejects_revoked_ocsp_response() {
+    let kt = KeyType::Ecdsa;
+    let revoked =
+        || make_server_config_with_ocsp(kt, kt.get_chain(), kt.get_revoked_ocsp_response());
+
+    check_ocsp_handshake(revoked(), OCSP_SOFT_FAIL, Err(Error::CertificateRevoked));
+    check_ocsp_handshake(revoked(), OCSP_HARD_FAIL, Err(Error::CertificateRevoked));
+    check_ocsp_handshake(revoked(), OcspPolicy::default(), Ok(()));
 }
 
 #[test]
-fn server_is_send_and_sync() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    &server as &dyn Send;
-    &server as &dyn Sync;
+fn client_checks_missing_ocsp_response() {
+    let kt = KeyType::Ecdsa;
+
+    check_ocsp_handshake(make_server_config(kt), OCSP_SOFT_FAIL, Ok(()));
+    check_ocsp_handshake(
+        make_server_config(kt),
+        OCSP_HARD_FAIL,
+        Err(Error::InvalidCertificateData(
+            "no OCSP response stapled".into(),
+        )),
+    );
 }
 
 #[test]
-fn client_is_send_and_sync() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    &client as &dyn Send;
-    &client as &dyn Sync;
+fn client_checks_invalid_ocsp_response() {
+    let kt = KeyType::Ecdsa;
+    let garbage = || make_server_config_with_ocsp(kt, kt.get_chain(), vec![0x30, 0x00]);
+    // A response for a different certificate.
+    let mismatched =
+        || make_server_config_with_ocsp(kt, kt.get_chain(), kt.get_must_staple_ocsp_response());
+
+    check_ocsp_handshake(garbage(), OCSP_SOFT_FAIL, Ok(()));
+    check_ocsp_handshake(
+        garbage(),
+        OCSP_HARD_FAIL,
+        Err(Error::InvalidCertificateData(
+            "malformed OCSP response".into(),
+        )),
+    );
+    check_ocsp_handshake(mismatched(), OCSP_SOFT_FAIL, Ok(()));
+    check_ocsp_handshake(
+        mismatched(),
+        OCSP_HARD_FAIL,
+        Err(Error::InvalidCertificateData(
+            "OCSP response does not cover certificate".into(),
+        )),
+    );
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    server.set_buffer_limit(Some(32));
+fn client_enforces_ocsp_must_staple() {
+    let kt = KeyType::Ecdsa;
+    let must_staple = OcspPolicy {
+        enforce_must_staple: true,
+        ..OcspPolicy::default()
+    };
 
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
+    check_ocsp_handshake(
+        make_server_config_with_ocsp(kt, kt.get_must_staple_chain(), vec![]),
+        must_staple,
+        Err(Error::InvalidCertificateData(
+            "no OCSP response stapled".into(),
+        )),
     );
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
+    check_ocsp_handshake(
+        make_server_config_with_ocsp(kt, kt.get_must_staple_chain(), vec![]),
+        OCSP_SOFT_FAIL,
+        Ok(()),
+    );
+    check_ocsp_handshake(
+        make_server_config_with_ocsp(
+            kt,
+            kt.get_must_staple_chain(),
+            kt.get_must_staple_ocsp_response(),
+        ),
+        must_staple,
+        Ok(()),
     );
+    check_ocsp_handshake(make_server_config(kt), must_staple, Ok(()));
+}
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+/// A Certificate Transparency log, for testing.
+struct TestCtLog {
+    operator: &'static str,
+    key: ring::signature::EcdsaKeyPair,
+}
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+impl TestCtLog {
+    fn new(operator: &'static str) -> Self {
+        let rng = ring::rand::SystemRandom::new();
+        let alg = &ring::signature::ECDSA_P256_SHA256_ASN1_SIGNING;
+        let pkcs8 = ring::signature::EcdsaKeyPair::generate_pkcs8(alg, &rng).unwrap();
+        Self {
+            operator,
+            key: ring::signature::EcdsaKeyPair::from_pkcs8(alg, pkcs8.as_ref()).unwrap(),
+        }
+    }
+
+    fn spki(&self) -> Vec<u8> {
+        use ring::signature::KeyPair;
+        // SubjectPublicKeyInfo header for a P-256 public key.
+        let mut spki = b"\x30\x59\x30\x13\x06\x07\x2a\x86\x48\xce\x3d\x02\x01\x06\x08\x2a\x86\x48\xce\x3d\x03\x01\x07\x03\x42\x00".to_vec();
+        spki.extend_from_slice(self.key.public_key().as_ref());
+        spki
+    }
+
+    fn id(&self) -> Vec<u8> {
+        ring::digest::digest(&ring::digest::SHA256, &self.spki())
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Issue an SCT for `cert`, an hour ago.
+    fn sct(&self, cert: &Certificate) -> Vec<u8> {
+        let timestamp = (SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            - 3_600_000)
+            .to_be_bytes();
+
+        // version, signature_type, timestamp, entry_type (x509_entry), certificate
+        let mut signed = vec![0, 0];
+        signed.extend_from_slice(&timestamp);
+        signed.extend_from_slice(&[0, 0]);
+        signed.extend_from_slice(&(cert.0.len() as u32).to_be_bytes()[1..]);
+        signed.extend_from_slice(&cert.0);
+        signed.extend_from_slice(&[0, 0]);
+
+        let signature = self
+            .key
+            .sign(&ring::rand::SystemRandom::new(), &signed)
+            .unwrap();
+
+        // version, log_id, timestamp, extensions, signature (ecdsa_secp256r1_sha256)
+        let mut sct = vec![0];
+        sct.extend_from_slice(&self.id());
+        sct.extend_from_slice(&timestamp);
+        sct.extend_from_slice(&[0, 0, 0x04, 0x03]);
+        sct.extend_from_slice(&(signature.as_ref().len() as u16).to_be_bytes());
+        sct.extend_from_slice(signature.as_ref());
+        sct
+    }
+}
+
+fn ct_log_list(logs: &[&TestCtLog]) -> rustls::client::CtLogList {
+    let keys = logs
+        .iter()
+        .map(|log| {
+            use ring::signature::KeyPair;
+            log.key.public_key().as_ref().to_vec()
+        })
+        .collect::<Vec<_>>();
+    let sct_logs = logs
+        .iter()
+        .zip(&keys)
+        .map(|(log, key)| sct::Log {
+            description: "test log",
+            url: "https://ct.example/",
+            operated_by: log.operator,
+            key,
+            id: log.id().try_into().unwrap(),
+            max_merge_delay: 86400,
+        })
+        .collect::<Vec<_>>();
+
+    rustls::client::CtLogList::new(&sct_logs.iter().collect::<Vec<_>>())
+}
+
+fn check_ct_handshake(
+    logs: &[&TestCtLog],
+    server_scts: &[&TestCtLog],
+    expected: Result<(), Error>,
+) {
+    let kt = KeyType::Ecdsa;
+    let end_entity = &kt.get_chain()[0];
+
+    let mut scts = Vec::new();
+    for log in server_scts {
+        let sct = log.sct(end_entity);
+        scts.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+        scts.extend_from_slice(&sct);
+    }
+    let mut sct_list = (scts.len() as u16)
+        .to_be_bytes()
+        .to_vec();
+    sct_list.extend_from_slice(&scts);
+
+    let server_config = Arc::new(make_server_config_with_scts(kt, sct_list));
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_ct_policy(
+            kt,
+            &[version],
+            ct_log_list(logs),
+            rustls::client::CtRequirements::default(),
+        );
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            expected
+                .clone()
+                .map_err(ErrorFromPeer::Client)
+        );
+    }
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_enforces_certificate_transparency_policy() {
+    let alpha = TestCtLog::new("Alpha");
+    let alpha2 = TestCtLog::new("Alpha");
+    let beta = TestCtLog::new("Beta");
+    let logs = [&alpha, &alpha2, &beta];
+    let not_compliant = || {
+        Err(Error::InvalidCertificateData(
+            "certificate does not comply with Certificate Transparency policy".into(),
+        ))
+    };
 
-    server.set_buffer_limit(Some(32));
+    check_ct_handshake(&logs, &[&alpha, &beta], Ok(()));
+    check_ct_handshake(&logs, &[&beta, &alpha2, &alpha], Ok(()));
 
-    assert_eq!(
-        server
-            .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+    // Not enough logs, or log operators.
+    check_ct_handshake(&logs, &[&beta], not_compliant());
+    check_ct_handshake(&logs, &[&beta, &beta], not_compliant());
+    check_ct_handshake(&logs, &[&alpha, &alpha2], not_compliant());
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+    // SCTs from unknown logs do not count.
+    check_ct_handshake(&[&alpha, &alpha2], &[&alpha, &beta], not_compliant());
+}
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+#[test]
+fn client_checks_server_certificate_with_given_name() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut client = ClientConnection::new(
+                Arc::new(client_config),
+                dns_name("not-the-right-hostname.com"),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
+                    "invalid peer certificate: CertNotValidForName".into(),
+                )))
+            );
+        }
+    }
+}
+
+fn ech_config_list(public_name: &str) -> Vec<u8> {
+    use rustls::internal::msgs::base::{PayloadU16, PayloadU8};
+    use rustls::internal::msgs::enums::{HpkeAead, HpkeKdf, HpkeKem};
+    use rustls::internal::msgs::handshake::{
+        EchConfigContents, EchConfigPayload, HpkeKeyConfig, HpkeSymmetricCipherSuite,
+    };
+
+    let config = EchConfigPayload::V18(EchConfigContents {
+        key_config: HpkeKeyConfig {
+            config_id: 1,
+            kem_id: HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            public_key: PayloadU16::new(vec![9; 32]),
+            symmetric_cipher_suites: vec![HpkeSymmetricCipherSuite {
+                kdf_id: HpkeKdf::HKDF_SHA256,
+                aead_id: HpkeAead::AES_128_GCM,
+            }],
+        },
+        maximum_name_length: 0,
+        public_name: PayloadU8::new(public_name.as_bytes().to_vec()),
+        extensions: PayloadU16::empty(),
+    });
+    vec![config].get_encoding()
 }
 
 #[test]
-fn server_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_ech_rejected_by_server_without_ech() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config(*kt);
+            client_config.ech_config =
+                Some(EchConfig::new(&ech_config_list("testserver.com")).unwrap());
+            let server_config = make_server_config_with_versions(*kt, &[version]);
 
-    // this test will vary in behaviour depending on the default suites
-    do_handshake(&mut client, &mut server);
-    server.set_buffer_limit(Some(48));
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name("secret.example")).unwrap();
+            let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+            assert_eq!(client.ech_status(), EchStatus::Offered);
 
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        6
-    );
+            // The server only sees the public name, and proves its identity for it.
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(server.sni_hostname(), Some("testserver.com"));
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Client(Error::EncryptedClientHelloRejected {
+                    retry_configs: None
+                }))
+            );
+            assert_eq!(client.ech_status(), EchStatus::Rejected);
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+            transfer(&mut client, &mut server);
+            assert_eq!(
+                server.process_new_packets(),
+                Err(Error::AlertReceived(
+                    rustls::AlertDescription::EncryptedClientHelloRequired
+                ))
+            );
+        }
+    }
+}
 
-    check_read(&mut client.reader(), b"01234567890123456789012345");
+#[cfg(feature = "tls12")]
+#[test]
+fn client_ech_not_offered_without_tls13() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.ech_config = Some(EchConfig::new(&ech_config_list("testserver.com")).unwrap());
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    assert_eq!(client.ech_status(), EchStatus::NotOffered);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.sni_hostname(), Some("localhost"));
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn client_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_withholds_certificate_when_ech_rejected_in_tls12() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_auth(kt);
+    client_config.ech_config = Some(EchConfig::new(&ech_config_list("testserver.com")).unwrap());
+
+    let client_auth = AllowAnyAnonymousOrAuthenticatedClient::new(get_client_root_store(kt));
+    let server_config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS12])
+        .unwrap()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
 
-    client.set_buffer_limit(Some(32));
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), dns_name("secret.example")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
+    let err = do_handshake_until_error(&mut client, &mut server);
     assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
+        err,
+        Err(ErrorFromPeer::Client(Error::EncryptedClientHelloRejected {
+            retry_configs: None
+        }))
     );
+    assert_eq!(client.ech_status(), EchStatus::Rejected);
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+    // The public name server asked for a certificate, but got an empty one.
+    assert_eq!(server.protocol_version(), Some(ProtocolVersion::TLSv1_2));
+    assert!(server.peer_certificates().is_none());
 }
 
+#[cfg(feature = "ech_server")]
 #[test]
-fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn server_accepts_ech() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config.ech_keys = vec![EchKeyPair::generate(1, "testserver.com").unwrap()];
+        let list = server_config.ech_config_list();
 
-    client.set_buffer_limit(Some(32));
+        let mut client_config = make_client_config(*kt);
+        client_config.ech_config = Some(EchConfig::new(&list).unwrap());
+
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.ech_status(), EchStatus::Accepted);
+        assert!(server.ech_accepted());
+        assert_eq!(server.sni_hostname(), Some("second.testserver.com"));
 
-    assert_eq!(
         client
             .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"hello");
+    }
+}
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+#[cfg(feature = "ech_server")]
+#[test]
+fn server_shows_inner_hello_to_cert_resolver() {
+    let key = EchKeyPair::generate(1, "testserver.com").unwrap();
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.ech_keys = vec![key];
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    let list = server_config.ech_config_list();
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+        expected_sni: Some("secret.example".into()),
+        expected_alpn: Some(vec![b"h2".to_vec()]),
+        ..Default::default()
+    });
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.ech_config = Some(EchConfig::new(&list).unwrap());
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), dns_name("secret.example")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert!(matches!(err, Err(ErrorFromPeer::Server(_))));
+    assert_eq!(server.sni_hostname(), Some("secret.example"));
 }
 
+#[cfg(feature = "ech_server")]
 #[test]
-fn client_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    do_handshake(&mut client, &mut server);
-    client.set_buffer_limit(Some(48));
+fn server_rejects_ech_with_unknown_key() {
+    let published = EchKeyPair::generate(1, "testserver.com").unwrap();
+    let current = EchKeyPair::generate(2, "testserver.com").unwrap();
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.ech_keys = vec![published];
+    let stale_list = server_config.ech_config_list();
+    server_config.ech_keys = vec![current];
+    let current_list = server_config.ech_config_list();
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.ech_config = Some(EchConfig::new(&stale_list).unwrap());
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
+    // The handshake continues with the outer hello, and the client
+    // is given the current configuration to retry with.
+    let err = do_handshake_until_error(&mut client, &mut server);
     assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
+        err,
+        Err(ErrorFromPeer::Client(Error::EncryptedClientHelloRejected {
+            retry_configs: Some(current_list)
+        }))
     );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
+    assert!(!server.ech_accepted());
+    assert_eq!(server.sni_hostname(), Some("testserver.com"));
+}
+
+#[cfg(feature = "ech_server")]
+#[test]
+fn server_accepts_ech_after_hello_retry_request() {
+    let key = EchKeyPair::generate(1, "testserver.com").unwrap();
+    let mut server_config = finish_server_config(
+        KeyType::Ecdsa,
+        ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(&[&rustls::kx_group::SECP384R1])
+            .with_safe_default_protocol_versions()
             .unwrap(),
-        6
     );
+    server_config.ech_keys = vec![key];
+    let list = server_config.ech_config_list();
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    check_read(&mut server.reader(), b"01234567890123456789012345");
-}
+    // The client's first key share is for a group the server doesn't
+    // support, so the server sends a HelloRetryRequest.
+    let mut client_config = make_client_config(KeyType::Ecdsa);
+    client_config.ech_config = Some(EchConfig::new(&list).unwrap());
 
-struct OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    sess: &'a mut C,
-    pub reads: usize,
-    pub writevs: Vec<Vec<usize>>,
-    fail_ok: bool,
-    pub short_writes: bool,
-    pub last_error: Option<rustls::Error>,
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.ech_status(), EchStatus::Accepted);
+    assert!(server.ech_accepted());
+    assert_eq!(server.sni_hostname(), Some("second.testserver.com"));
 }
 
-impl<'a, C, S> OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        OtherSession {
-            sess,
-            reads: 0,
-            writevs: vec![],
-            fail_ok: false,
-            short_writes: false,
-            last_error: None,
-        }
+#[cfg(feature = "ech_server")]
+#[test]
+fn acceptor_decrypts_ech_with_config() {
+    use rustls::server::Acceptor;
+
+    let key = EchKeyPair::generate(1, "testserver.com").unwrap();
+    let mut server_config = make_server_config(KeyType::Ed25519);
+    server_config.ech_keys = vec![key];
+    let list = server_config.ech_config_list();
+
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.ech_config = Some(EchConfig::new(&list).unwrap());
+    let mut client =
+        ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com")).unwrap();
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+
+    let mut acceptor = Acceptor::default();
+    acceptor
+        .read_tls(&mut buf.as_slice())
+        .unwrap();
+    let accepted = acceptor.accept().unwrap().unwrap();
+    assert_eq!(
+        accepted.client_hello().server_name(),
+        Some("testserver.com")
+    );
+
+    let mut server = accepted
+        .into_connection(Arc::new(server_config))
+        .unwrap();
+    assert_eq!(server.sni_hostname(), Some("second.testserver.com"));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.ech_status(), EchStatus::Accepted);
+}
+
+#[derive(Debug)]
+struct FailingCompressor;
+
+impl CertCompressor for FailingCompressor {
+    fn compress(&self, _input: Vec<u8>) -> Result<Vec<u8>, CompressionFailed> {
+        Err(CompressionFailed)
     }
 
-    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        let mut os = OtherSession::new(sess);
-        os.fail_ok = true;
-        os
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Brotli
     }
 }
 
-impl<'a, C, S> io::Read for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
-        self.reads += 1;
-        self.sess.write_tls(b.by_ref())
+#[derive(Debug)]
+struct UnusedDecompressor;
+
+impl CertDecompressor for UnusedDecompressor {
+    fn decompress(&self, _input: &[u8], _output: &mut [u8]) -> Result<(), DecompressionFailed> {
+        panic!("peer should not have compressed its certificate");
+    }
+
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Brotli
     }
 }
 
-impl<'a, C, S> io::Write for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-        unreachable!()
+#[test]
+fn cert_compression_falls_back_when_compressor_fails() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config_with_mandatory_client_auth(*kt);
+        server_config.cert_compressors = vec![&FailingCompressor];
+        server_config.cert_decompressors = vec![&UnusedDecompressor];
+        let mut client_config = make_client_config_with_auth(*kt);
+        client_config.cert_compressors = vec![&FailingCompressor];
+        client_config.cert_decompressors = vec![&UnusedDecompressor];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(server.peer_certificates().is_some());
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+#[cfg(feature = "zlib")]
+#[derive(Debug)]
+struct CountingDecompressor(AtomicUsize);
+
+#[cfg(feature = "zlib")]
+impl CertDecompressor for CountingDecompressor {
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> Result<(), DecompressionFailed> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        rustls::compress::ZLIB_DECOMPRESSOR.decompress(input, output)
     }
 
-    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-        let mut total = 0;
-        let mut lengths = vec![];
-        for bytes in b {
-            let write_len = if self.short_writes {
-                if bytes.len() > 5 {
-                    bytes.len() / 2
-                } else {
-                    bytes.len()
-                }
-            } else {
-                bytes.len()
-            };
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        CertificateCompressionAlgorithm::Zlib
+    }
+}
 
-            let l = self
-                .sess
-                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
-            lengths.push(l);
-            total += l;
-            if bytes.len() != l {
-                break;
-            }
-        }
+#[cfg(feature = "zlib")]
+#[test]
+fn server_cert_compression_with_zlib() {
+    static DECOMPRESSOR: CountingDecompressor = CountingDecompressor(AtomicUsize::new(0));
 
-        let rc = self.sess.process_new_packets();
-        if !self.fail_ok {
-            rc.unwrap();
-        } else if rc.is_err() {
-            self.last_error = rc.err();
-        }
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.cert_decompressors = vec![&DECOMPRESSOR];
 
-        self.writevs.push(lengths);
-        Ok(total)
+        let before = DECOMPRESSOR.0.load(Ordering::SeqCst);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(*kt));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(DECOMPRESSOR.0.load(Ordering::SeqCst), before + 1);
+        assert_eq!(
+            client
+                .peer_certificates()
+                .map(|c| c.to_vec()),
+            Some(kt.get_chain())
+        );
     }
 }
 
+#[cfg(feature = "zlib")]
 #[test]
-fn server_read_returns_wouldblock_when_no_data() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+fn client_cert_compression_with_zlib() {
+    static DECOMPRESSOR: CountingDecompressor = CountingDecompressor(AtomicUsize::new(0));
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config_with_mandatory_client_auth(*kt);
+        server_config.cert_decompressors = vec![&DECOMPRESSOR];
+
+        let before = DECOMPRESSOR.0.load(Ordering::SeqCst);
+        let (mut client, mut server) =
+            make_pair_for_configs(make_client_config_with_auth(*kt), server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(DECOMPRESSOR.0.load(Ordering::SeqCst), before + 1);
+        assert_eq!(
+            server
+                .peer_certificates()
+                .map(|c| c.to_vec()),
+            Some(kt.get_client_chain())
+        );
+    }
 }
 
+#[cfg(all(feature = "zlib", feature = "tls12"))]
 #[test]
-fn client_read_returns_wouldblock_when_no_data() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+fn cert_compression_not_used_with_tls12() {
+    static DECOMPRESSOR: CountingDecompressor = CountingDecompressor(AtomicUsize::new(0));
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.cert_decompressors = vec![&DECOMPRESSOR];
+
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(DECOMPRESSOR.0.load(Ordering::SeqCst), 0);
 }
 
-#[test]
-fn new_server_returns_initial_io_state() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let io_state = server.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert_eq!(io_state.tls_bytes_to_write(), 0);
+fn make_server_config_with_raw_public_key(kt: KeyType) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_raw_public_key(kt.get_key())
+        .unwrap()
 }
 
 #[test]
-fn new_client_returns_initial_io_state() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let io_state = client.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert!(io_state.tls_bytes_to_write() > 200);
+fn server_raw_public_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.server_raw_key_verifier =
+            Some(Arc::new(TrustedRawKeys::new(vec![kt.get_spki()])));
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config_with_raw_public_key(*kt));
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&[Certificate(kt.get_spki().0)][..])
+        );
+    }
 }
 
 #[test]
-fn client_complete_io_for_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    assert!(client.is_handshaking());
-    let (rdlen, wrlen) = client
-        .complete_io(&mut OtherSession::new(&mut server))
-        .unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert!(!client.is_handshaking());
+fn server_raw_public_key_untrusted() {
+    let mut client_config = make_client_config(KeyType::Ecdsa);
+    client_config.server_raw_key_verifier = Some(Arc::new(TrustedRawKeys::new(vec![
+        KeyType::Ed25519.get_spki(),
+    ])));
+
+    let (mut client, mut server) = make_pair_for_configs(
+        client_config,
+        make_server_config_with_raw_public_key(KeyType::Ecdsa),
+    );
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Client(Error::InvalidCertificateData(
+                "raw public key is not trusted".into()
+            )),
+            ErrorFromPeer::Server(Error::AlertReceived(
+                rustls::AlertDescription::BadCertificate
+            )),
+        ])
+    );
 }
 
 #[test]
-fn client_complete_io_for_handshake_eof() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
+fn server_raw_public_key_needs_client_support() {
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config(KeyType::Ecdsa),
+        make_server_config_with_raw_public_key(KeyType::Ecdsa),
+    );
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Server(Error::PeerIncompatibleError(
+                "client does not accept our certificate type".into()
+            )),
+            ErrorFromPeer::Client(Error::AlertReceived(
+                rustls::AlertDescription::UnsupportedCertificate
+            )),
+        ])
+    );
+}
 
-    assert!(client.is_handshaking());
-    let err = client
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+#[cfg(feature = "tls12")]
+#[test]
+fn server_raw_public_key_not_used_with_tls12() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Ecdsa, &[&rustls::version::TLS12]);
+    client_config.server_raw_key_verifier = Some(Arc::new(TrustedRawKeys::new(vec![
+        KeyType::Ecdsa.get_spki(),
+    ])));
+
+    let (mut client, mut server) = make_pair_for_configs(
+        client_config,
+        make_server_config_with_raw_public_key(KeyType::Ecdsa),
+    );
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Server(Error::PeerIncompatibleError(
+                "raw public key resolved for non-TLS1.3 handshake".into()
+            )),
+            ErrorFromPeer::Client(Error::AlertReceived(
+                rustls::AlertDescription::HandshakeFailure
+            )),
+        ])
+    );
 }
 
 #[test]
-fn client_complete_io_for_write() {
+fn client_offering_raw_public_keys_accepts_certificates() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let mut client_config = make_client_config(*kt);
+        client_config.server_raw_key_verifier = Some(Arc::new(TrustedRawKeys::default()));
 
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(*kt));
         do_handshake(&mut client, &mut server);
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            println!("{:?}", pipe.writevs);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-        }
-        check_read(
-            &mut server.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
+        assert_eq!(client.peer_certificates(), Some(kt.get_chain().as_slice()));
     }
 }
 
 #[test]
-fn client_complete_io_for_read() {
+fn client_raw_public_key() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let mut server_config = make_server_config(*kt);
+        server_config.client_raw_key_verifier =
+            Some(Arc::new(TrustedRawKeys::new(vec![kt.get_client_spki()])));
 
+        let (mut client, mut server) =
+            make_pair_for_configs(make_client_config_with_raw_public_key(*kt), server_config);
         do_handshake(&mut client, &mut server);
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
-        }
-        check_read(&mut client.reader(), b"01234567890123456789");
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&[Certificate(kt.get_client_spki().0)][..])
+        );
     }
 }
 
 #[test]
-fn server_complete_io_for_handshake() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn client_raw_public_key_untrusted() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.client_raw_key_verifier = Some(Arc::new(TrustedRawKeys::new(vec![
+        KeyType::Ed25519.get_client_spki(),
+    ])));
 
-        assert!(server.is_handshaking());
-        let (rdlen, wrlen) = server
-            .complete_io(&mut OtherSession::new(&mut client))
-            .unwrap();
-        assert!(rdlen > 0 && wrlen > 0);
-        assert!(!server.is_handshaking());
-    }
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_raw_public_key(KeyType::Rsa),
+        server_config,
+    );
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Server(Error::InvalidCertificateData(
+                "raw public key is not trusted".into()
+            )),
+            ErrorFromPeer::Client(Error::AlertReceived(
+                rustls::AlertDescription::HandshakeFailure
+            )),
+        ])
+    );
 }
 
 #[test]
-fn server_complete_io_for_handshake_eof() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
-
-    assert!(server.is_handshaking());
-    let err = server
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+fn client_raw_public_key_not_requested_without_server_support() {
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_raw_public_key(KeyType::Rsa),
+        make_server_config(KeyType::Rsa),
+    );
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.peer_certificates(), None);
 }
 
-#[test]
-fn server_complete_io_for_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+struct ExternalPskResolver(Vec<Arc<ExternalPsk>>);
 
-        do_handshake(&mut client, &mut server);
-
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-        }
-        check_read(
-            &mut client.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
+impl ResolvesServerPsk for ExternalPskResolver {
+    fn resolve(&self, identity: &[u8]) -> Option<Arc<ExternalPsk>> {
+        self.0
+            .iter()
+            .find(|psk| psk.identity() == identity)
+            .cloned()
     }
 }
 
+fn make_external_psk(
+    identity: &[u8],
+    hash: &'static dyn rustls::crypto::hash::Hash,
+) -> Arc<ExternalPsk> {
+    Arc::new(ExternalPsk::new(
+        identity.to_vec(),
+        b"0123456789abcdef0123456789abcdef".to_vec(),
+        hash,
+    ))
+}
+
+fn make_external_psk_pair(
+    client_psk: Arc<ExternalPsk>,
+    server_psk: Arc<ExternalPsk>,
+    psk_kx_modes: &[PSKKeyExchangeMode],
+) -> (ClientConnection, ServerConnection) {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.external_psks = vec![client_psk];
+    client_config.psk_kx_modes = psk_kx_modes.to_vec();
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.psk_resolver = Some(Arc::new(ExternalPskResolver(vec![server_psk])));
+    server_config.psk_kx_modes = psk_kx_modes.to_vec();
+
+    make_pair_for_configs(client_config, server_config)
+}
+
 #[test]
-fn server_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn external_psk_with_dhe() {
+    let psk = make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384);
+    let (mut client, mut server) =
+        make_external_psk_pair(psk.clone(), psk, &[PSKKeyExchangeMode::PSK_DHE_KE]);
+    do_handshake(&mut client, &mut server);
 
-        do_handshake(&mut client, &mut server);
+    assert_eq!(server.external_psk_identity(), Some(&b"device-1"[..]));
+    assert_eq!(client.peer_certificates(), None);
+    assert_eq!(server.peer_certificates(), None);
+}
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
-        }
-        check_read(&mut server.reader(), b"01234567890123456789");
-    }
+#[test]
+fn external_psk_without_dhe() {
+    let psk = make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384);
+    let (mut client, mut server) =
+        make_external_psk_pair(psk.clone(), psk, &[PSKKeyExchangeMode::PSK_KE]);
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(server.external_psk_identity(), Some(&b"device-1"[..]));
+    assert_eq!(client.peer_certificates(), None);
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
 }
 
 #[test]
-fn client_stream_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn external_psk_without_dhe_needs_no_key_share_overlap() {
+    let psk = make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384);
+    let mut client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    client_config.external_psks = vec![psk.clone()];
+    client_config.psk_kx_modes = vec![PSKKeyExchangeMode::PSK_KE];
 
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream = Stream::new(&mut client, &mut pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
-        }
-        check_read(&mut server.reader(), b"hello");
-    }
+    let mut server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    server_config.psk_resolver = Some(Arc::new(ExternalPskResolver(vec![psk])));
+    server_config.psk_kx_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE, PSKKeyExchangeMode::PSK_KE];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.external_psk_identity(), Some(&b"device-1"[..]));
 }
 
 #[test]
-fn client_streamowned_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (client, mut server) = make_pair(*kt);
+fn external_psk_unknown_identity_uses_certificates() {
+    let (mut client, mut server) = make_external_psk_pair(
+        make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384),
+        make_external_psk(b"device-2", &rustls::crypto::ring::hash::SHA384),
+        &[PSKKeyExchangeMode::PSK_DHE_KE],
+    );
+    do_handshake(&mut client, &mut server);
 
-        {
-            let pipe = OtherSession::new(&mut server);
-            let mut stream = StreamOwned::new(client, pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
-        }
-        check_read(&mut server.reader(), b"hello");
-    }
+    assert_eq!(server.external_psk_identity(), None);
+    assert_eq!(
+        client.peer_certificates(),
+        Some(KeyType::Rsa.get_chain().as_slice())
+    );
 }
 
 #[test]
-fn client_stream_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn external_psk_for_other_hash_uses_certificates() {
+    let psk = make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384);
+    let mut client_config = finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::builder()
+            .with_cipher_suites(&[
+                rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+                rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+            ])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    client_config.external_psks = vec![psk.clone()];
 
-        server
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.psk_resolver = Some(Arc::new(ExternalPskResolver(vec![psk])));
 
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream = Stream::new(&mut client, &mut pipe);
-            check_read(&mut stream, b"world");
-        }
-    }
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(
+        client
+            .negotiated_cipher_suite()
+            .unwrap()
+            .suite(),
+        CipherSuite::TLS13_AES_128_GCM_SHA256
+    );
+    assert_eq!(server.external_psk_identity(), None);
+    assert!(client.peer_certificates().is_some());
 }
 
 #[test]
-fn client_streamowned_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (client, mut server) = make_pair(*kt);
+fn external_psk_wrong_secret() {
+    let server_psk = Arc::new(ExternalPsk::new(
+        b"device-1".to_vec(),
+        b"not the secret".to_vec(),
+        &rustls::crypto::ring::hash::SHA384,
+    ));
+    let (mut client, mut server) = make_external_psk_pair(
+        make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384),
+        server_psk,
+        &[PSKKeyExchangeMode::PSK_DHE_KE],
+    );
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Server(Error::PeerMisbehavedError(
+                "client sent wrong binder".into()
+            )),
+            ErrorFromPeer::Client(Error::AlertReceived(rustls::AlertDescription::DecryptError)),
+        ])
+    );
+}
 
-        server
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+#[test]
+fn external_psk_offered_after_resumption_ticket() {
+    let psk = make_external_psk(b"device-1", &rustls::crypto::ring::hash::SHA384);
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.external_psks = vec![psk.clone()];
+    let client_config = Arc::new(client_config);
 
-        {
-            let pipe = OtherSession::new(&mut server);
-            let mut stream = StreamOwned::new(client, pipe);
-            check_read(&mut stream, b"world");
+    // Get a ticket from a server which doesn't know our PSK.
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &Arc::new(make_server_config(KeyType::Rsa)));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.external_psk_identity(), None);
+
+    // Another server cannot resume, so should choose our PSK instead.
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.psk_resolver = Some(Arc::new(ExternalPskResolver(vec![psk])));
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&client_config, &Arc::new(server_config));
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.external_psk_identity(), Some(&b"device-1"[..]));
+    assert_eq!(server.received_resumption_data(), None);
+}
+
+struct ClientCheckCertResolve {
+    query_count: AtomicUsize,
+    expect_queries: usize,
+}
+
+impl ClientCheckCertResolve {
+    fn new(expect_queries: usize) -> Self {
+        ClientCheckCertResolve {
+            query_count: AtomicUsize::new(0),
+            expect_queries,
         }
     }
 }
 
-#[test]
-fn server_stream_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream = Stream::new(&mut server, &mut pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+impl Drop for ClientCheckCertResolve {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let count = self.query_count.load(Ordering::SeqCst);
+            assert_eq!(count, self.expect_queries);
         }
-        check_read(&mut client.reader(), b"hello");
     }
 }
 
-#[test]
-fn server_streamowned_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, server) = make_pair(*kt);
+impl ResolvesClientCert for ClientCheckCertResolve {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        self.query_count
+            .fetch_add(1, Ordering::SeqCst);
 
-        {
-            let pipe = OtherSession::new(&mut client);
-            let mut stream = StreamOwned::new(server, pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        if acceptable_issuers.is_empty() {
+            panic!("no issuers offered by server");
         }
-        check_read(&mut client.reader(), b"hello");
+
+        if sigschemes.is_empty() {
+            panic!("no signature schemes shared by server");
+        }
+
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
     }
 }
 
 #[test]
-fn server_stream_read() {
+fn client_cert_resolve() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        client
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
 
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream = Stream::new(&mut server, &mut pipe);
-            check_read(&mut stream, b"world");
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+            );
         }
     }
 }
 
 #[test]
-fn server_streamowned_read() {
+fn client_auth_works() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, server) = make_pair(*kt);
-
-        client
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        {
-            let pipe = OtherSession::new(&mut client);
-            let mut stream = StreamOwned::new(server, pipe);
-            check_read(&mut stream, b"world");
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
         }
     }
 }
 
-struct FailsWrites {
-    errkind: io::ErrorKind,
-    after: usize,
+fn make_server_config_with_deferred_client_auth(kt: KeyType, mandatory: bool) -> ServerConfig {
+    let client_auth_roots = get_client_root_store(kt);
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match mandatory {
+        true => {
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(client_auth_roots))
+        }
+        false => builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(
+            client_auth_roots,
+        )),
+    };
+    let mut server_config = builder
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
+    server_config.defer_client_auth = true;
+    server_config
 }
 
-impl io::Read for FailsWrites {
-    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
-        Ok(0)
-    }
+fn do_post_handshake_auth(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+) -> Result<(), ErrorFromPeer> {
+    transfer(server, client);
+    client
+        .process_new_packets()
+        .map_err(ErrorFromPeer::Client)?;
+    transfer(client, server);
+    server
+        .process_new_packets()
+        .map_err(ErrorFromPeer::Server)?;
+    Ok(())
 }
 
-impl io::Write for FailsWrites {
-    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
-        if self.after > 0 {
-            self.after -= 1;
-            Ok(b.len())
-        } else {
-            Err(io::Error::new(self.errkind, "oops"))
-        }
-    }
+#[test]
+fn post_handshake_auth_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config_with_deferred_client_auth(*kt, true);
+        let client_config = make_client_config_with_auth(*kt);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        assert_eq!(server.peer_certificates(), None);
+        assert_eq!(
+            server.post_handshake_auth_status(),
+            PostHandshakeAuthStatus::NotRequested
+        );
+
+        server
+            .request_client_certificate()
+            .unwrap();
+        assert_eq!(
+            server.post_handshake_auth_status(),
+            PostHandshakeAuthStatus::Pending
+        );
+        assert!(server
+            .request_client_certificate()
+            .is_err());
+
+        do_post_handshake_auth(&mut client, &mut server).unwrap();
+        assert_eq!(
+            server.post_handshake_auth_status(),
+            PostHandshakeAuthStatus::Verified
+        );
+        assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
+
+        // Keying is unaffected, and further requests are possible.
+        client
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"hello");
+
+        server
+            .request_client_certificate()
+            .unwrap();
+        do_post_handshake_auth(&mut client, &mut server).unwrap();
+        assert_eq!(
+            server.post_handshake_auth_status(),
+            PostHandshakeAuthStatus::Verified
+        );
     }
 }
 
 #[test]
-fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn post_handshake_auth_declined() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+
+    let server_config = make_server_config_with_deferred_client_auth(kt, false);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
     do_handshake(&mut client, &mut server);
 
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 0,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
+    server
+        .request_client_certificate()
         .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert!(rc.is_err());
-    let err = rc.err().unwrap();
-    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    do_post_handshake_auth(&mut client, &mut server).unwrap();
+    assert_eq!(
+        server.post_handshake_auth_status(),
+        PostHandshakeAuthStatus::Declined
+    );
+    assert_eq!(server.peer_certificates(), None);
 }
 
 #[test]
-fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn post_handshake_auth_declined_when_mandatory() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+
+    let server_config = make_server_config_with_deferred_client_auth(kt, true);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
     do_handshake(&mut client, &mut server);
 
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 1,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
+    server
+        .request_client_certificate()
         .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert_eq!(format!("{:?}", rc), "Ok(5)");
+    assert_eq!(
+        do_post_handshake_auth(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+    );
 }
 
-fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
+#[test]
+fn post_handshake_auth_requires_client_offer() {
     let kt = KeyType::Rsa;
-    let server_config = finish_server_config(
-        kt,
-        ServerConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
-
-    let client_config = finish_client_config(
-        kt,
-        ClientConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
-
-    (client_config, server_config)
+    let server_config = make_server_config_with_deferred_client_auth(kt, true);
+    let client_config = make_client_config(kt);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(matches!(
+        server.request_client_certificate(),
+        Err(Error::PeerIncompatibleError(_))
+    ));
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn client_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
+fn post_handshake_auth_requires_tls13() {
+    let kt = KeyType::Rsa;
+    let server_config = make_server_config_with_deferred_client_auth(kt, true);
+    let client_config = make_client_config_with_versions_with_auth(kt, &[&rustls::version::TLS12]);
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-
-    {
-        let mut pipe = OtherSession::new_fails(&mut server);
-        let mut client_stream = Stream::new(&mut client, &mut pipe);
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-        );
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-        );
-    }
+    do_handshake(&mut client, &mut server);
+    assert!(matches!(
+        server.request_client_certificate(),
+        Err(Error::PeerIncompatibleError(_))
+    ));
+    assert_eq!(server.peer_certificates(), None);
 }
 
 #[test]
-fn client_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (client, mut server) = make_pair_for_configs(client_config, server_config);
+fn post_handshake_auth_needs_completed_handshake() {
+    let kt = KeyType::Rsa;
+    let server_config = make_server_config_with_deferred_client_auth(kt, true);
+    let client_config = make_client_config_with_auth(kt);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    let pipe = OtherSession::new_fails(&mut server);
-    let mut client_stream = StreamOwned::new(client, pipe);
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-    );
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
     assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        server.request_client_certificate(),
+        Err(Error::HandshakeNotComplete)
     );
 }
 
 #[test]
-fn server_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-
+fn client_error_is_sticky() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
     client
-        .writer()
-        .write_all(b"world")
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
         .unwrap();
-
-    {
-        let mut pipe = OtherSession::new_fails(&mut client);
-        let mut server_stream = Stream::new(&mut server, &mut pipe);
-        let mut bytes = [0u8; 5];
-        let rc = server_stream.read(&mut bytes);
-        assert!(rc.is_err());
-        assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
-        );
-    }
+    let mut err = client.process_new_packets();
+    assert!(err.is_err());
+    err = client.process_new_packets();
+    assert!(err.is_err());
 }
 
 #[test]
-fn server_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, server) = make_pair_for_configs(client_config, server_config);
-
-    client
-        .writer()
-        .write_all(b"world")
+fn server_error_is_sticky() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    server
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
         .unwrap();
-
-    let pipe = OtherSession::new_fails(&mut client);
-    let mut server_stream = StreamOwned::new(server, pipe);
-    let mut bytes = [0u8; 5];
-    let rc = server_stream.read(&mut bytes);
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
-    );
+    let mut err = server.process_new_packets();
+    assert!(err.is_err());
+    err = server.process_new_packets();
+    assert!(err.is_err());
 }
 
 #[test]
-fn server_config_is_clone() {
-    let _ = make_server_config(KeyType::Rsa);
+fn server_flush_does_nothing() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.writer().flush(), Ok(())));
 }
 
 #[test]
-fn client_config_is_clone() {
-    let _ = make_client_config(KeyType::Rsa);
+fn client_flush_does_nothing() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.writer().flush(), Ok(())));
 }
 
 #[test]
-fn client_connection_is_debug() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    println!("{:?}", client);
+fn server_is_send_and_sync() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    &server as &dyn Send;
+    &server as &dyn Sync;
 }
 
 #[test]
-fn server_connection_is_debug() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    println!("{:?}", server);
+fn client_is_send_and_sync() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    &client as &dyn Send;
+    &client as &dyn Sync;
 }
 
 #[test]
-fn server_complete_io_for_handshake_ending_with_alert() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+fn server_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    assert!(server.is_handshaking());
+    server.set_buffer_limit(Some(32));
 
-    let mut pipe = OtherSession::new_fails(&mut client);
-    let rc = server.complete_io(&mut pipe);
-    assert!(rc.is_err(), "server io failed due to handshake failure");
-    assert!(!server.wants_write(), "but server did send its alert");
     assert_eq!(
-        format!("{:?}", pipe.last_error),
-        "Some(AlertReceived(HandshakeFailure))",
-        "which was received by client"
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
     );
-}
 
-#[test]
-fn server_exposes_offered_sni() {
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-        assert_eq!(None, server.sni_hostname());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
-    }
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn server_exposes_offered_sni_smashed_to_lowercase() {
-    // webpki actually does this for us in its DnsName type
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("SECOND.TESTServer.com"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
-
-        assert_eq!(None, server.sni_hostname());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
-    }
-}
+fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-#[test]
-fn server_exposes_offered_sni_even_if_resolver_fails() {
-    let kt = KeyType::Rsa;
-    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    server.set_buffer_limit(Some(32));
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+    assert_eq!(
+        server
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
+    );
 
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("thisdoesNOTexist.com"))
-                .unwrap();
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-        assert_eq!(None, server.sni_hostname());
-        transfer(&mut client, &mut server);
-        assert_eq!(
-            server.process_new_packets(),
-            Err(Error::General(
-                "no server certificate chain resolved".to_string()
-            ))
-        );
-        assert_eq!(Some("thisdoesnotexist.com"), server.sni_hostname());
-    }
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn sni_resolver_works() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
-    resolver
-        .add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
-        )
-        .unwrap();
-
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
-
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
-
-    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client2 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("notlocalhost")).unwrap();
-    let err = do_handshake_until_error(&mut client2, &mut server2);
-    assert_eq!(
-        err,
-        Err(ErrorFromPeer::Server(Error::General(
-            "no server certificate chain resolved".into()
-        )))
-    );
-}
+fn server_respects_buffer_limit_post_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-#[test]
-fn sni_resolver_rejects_wrong_names() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    // this test will vary in behaviour depending on the default suites
+    do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(Some(48));
 
     assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
-    assert_eq!(
-        Err(Error::General(
-            "The server certificate is not valid for the given name".into()
-        )),
-        resolver.add(
-            "not-localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
     );
     assert_eq!(
-        Err(Error::General("Bad DNS name".into())),
-        resolver.add(
-            "not ascii 🦀",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
     );
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client.reader(), b"01234567890123456789012345");
 }
 
 #[test]
-fn sni_resolver_lower_cases_configured_names() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn client_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client.set_buffer_limit(Some(32));
 
     assert_eq!(
-        Ok(()),
-        resolver.add(
-            "LOCALHOST",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
     );
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn sni_resolver_lower_cases_queried_names() {
-    // actually, the handshake parser does this, but the effect is the same.
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client.set_buffer_limit(Some(32));
 
     assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
+        client
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
     );
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("LOCALHOST")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn sni_resolver_rejects_bad_certs() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn client_respects_buffer_limit_post_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    do_handshake(&mut client, &mut server);
+    client.set_buffer_limit(Some(48));
 
     assert_eq!(
-        Err(Error::General(
-            "No end-entity certificate in certificate chain".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(vec![], signing_key.clone())
-        )
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
     );
-
-    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
     assert_eq!(
-        Err(Error::General(
-            "End-entity certificate in certificate chain is syntactically invalid".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(bad_chain, signing_key.clone())
-        )
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
     );
-}
 
-fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
-    let mut client_secret = [0u8; 64];
-    let mut server_secret = [0u8; 64];
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    check_read(&mut server.reader(), b"01234567890123456789012345");
+}
 
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+struct OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    sess: &'a mut C,
+    pub reads: usize,
+    pub writevs: Vec<Vec<usize>>,
+    fail_ok: bool,
+    pub short_writes: bool,
+    pub last_error: Option<rustls::Error>,
+}
+
+impl<'a, C, S> OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        OtherSession {
+            sess,
+            reads: 0,
+            writevs: vec![],
+            fail_ok: false,
+            short_writes: false,
+            last_error: None,
+        }
+    }
+
+    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        let mut os = OtherSession::new(sess);
+        os.fail_ok = true;
+        os
+    }
+}
+
+impl<'a, C, S> io::Read for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.sess.write_tls(b.by_ref())
+    }
+}
+
+impl<'a, C, S> io::Write for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        unreachable!()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+        let mut total = 0;
+        let mut lengths = vec![];
+        for bytes in b {
+            let write_len = if self.short_writes {
+                if bytes.len() > 5 {
+                    bytes.len() / 2
+                } else {
+                    bytes.len()
+                }
+            } else {
+                bytes.len()
+            };
+
+            let l = self
+                .sess
+                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
+            lengths.push(l);
+            total += l;
+            if bytes.len() != l {
+                break;
+            }
+        }
+
+        let rc = self.sess.process_new_packets();
+        if !self.fail_ok {
+            rc.unwrap();
+        } else if rc.is_err() {
+            self.last_error = rc.err();
+        }
+
+        self.writevs.push(lengths);
+        Ok(total)
+    }
+}
+
+#[test]
+fn server_read_returns_wouldblock_when_no_data() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+}
+
+#[test]
+fn client_read_returns_wouldblock_when_no_data() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+}
+
+#[test]
+fn new_server_returns_initial_io_state() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let io_state = server.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert_eq!(io_state.tls_bytes_to_write(), 0);
+}
+
+#[test]
+fn new_client_returns_initial_io_state() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let io_state = client.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert!(io_state.tls_bytes_to_write() > 200);
+}
+
+#[test]
+fn client_complete_io_for_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    assert!(client.is_handshaking());
+    let (rdlen, wrlen) = client
+        .complete_io(&mut OtherSession::new(&mut server))
+        .unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert!(!client.is_handshaking());
+}
+
+#[test]
+fn client_complete_io_for_handshake_eof() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
+
+    assert!(client.is_handshaking());
+    let err = client
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
+
+#[test]
+fn client_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            println!("{:?}", pipe.writevs);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut server.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
+    }
+}
+
+#[test]
+fn client_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut client.reader(), b"01234567890123456789");
+    }
+}
+
+#[test]
+fn server_complete_io_for_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        assert!(server.is_handshaking());
+        let (rdlen, wrlen) = server
+            .complete_io(&mut OtherSession::new(&mut client))
+            .unwrap();
+        assert!(rdlen > 0 && wrlen > 0);
+        assert!(!server.is_handshaking());
+    }
+}
+
+#[test]
+fn server_complete_io_for_handshake_eof() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
+
+    assert!(server.is_handshaking());
+    let err = server
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+}
+
+#[test]
+fn server_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut client.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
+    }
+}
+
+#[test]
+fn server_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        client
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut server.reader(), b"01234567890123456789");
+    }
+}
+
+#[test]
+fn client_stream_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut server.reader(), b"hello");
+    }
+}
+
+#[test]
+fn client_streamowned_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
+
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut server.reader(), b"hello");
+    }
+}
+
+#[test]
+fn client_stream_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        server
+            .writer()
+            .write_all(b"world")
+            .unwrap();
+
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
+
+#[test]
+fn client_streamowned_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
+
+        server
+            .writer()
+            .write_all(b"world")
+            .unwrap();
+
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
+
+#[test]
+fn server_stream_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream = Stream::new(&mut server, &mut pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut client.reader(), b"hello");
+    }
+}
+
+#[test]
+fn server_streamowned_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
+
+        {
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut client.reader(), b"hello");
+    }
+}
+
+#[test]
+fn server_stream_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        client
+            .writer()
+            .write_all(b"world")
+            .unwrap();
+
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream = Stream::new(&mut server, &mut pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
+
+#[test]
+fn server_streamowned_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
+
+        client
+            .writer()
+            .write_all(b"world")
+            .unwrap();
+
+        {
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
+
+struct FailsWrites {
+    errkind: io::ErrorKind,
+    after: usize,
+}
+
+impl io::Read for FailsWrites {
+    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl io::Write for FailsWrites {
+    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+        if self.after > 0 {
+            self.after -= 1;
+            Ok(b.len())
+        } else {
+            Err(io::Error::new(self.errkind, "oops"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 0,
+    };
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert!(rc.is_err());
+    let err = rc.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
+
+#[test]
+fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 1,
+    };
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert_eq!(format!("{:?}", rc), "Ok(5)");
+}
+
+fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
+    let kt = KeyType::Rsa;
+    let server_config = finish_server_config(
+        kt,
+        ServerConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+
+    let client_config = finish_client_config(
+        kt,
+        ClientConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+
+    (client_config, server_config)
+}
+
+#[test]
+fn client_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    {
+        let mut pipe = OtherSession::new_fails(&mut server);
+        let mut client_stream = Stream::new(&mut client, &mut pipe);
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
+    }
+}
+
+#[test]
+fn client_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let pipe = OtherSession::new_fails(&mut server);
+    let mut client_stream = StreamOwned::new(client, pipe);
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+}
+
+#[test]
+fn server_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+
+    {
+        let mut pipe = OtherSession::new_fails(&mut client);
+        let mut server_stream = Stream::new(&mut server, &mut pipe);
+        let mut bytes = [0u8; 5];
+        let rc = server_stream.read(&mut bytes);
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+        );
+    }
+}
+
+#[test]
+fn server_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, server) = make_pair_for_configs(client_config, server_config);
+
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+
+    let pipe = OtherSession::new_fails(&mut client);
+    let mut server_stream = StreamOwned::new(server, pipe);
+    let mut bytes = [0u8; 5];
+    let rc = server_stream.read(&mut bytes);
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+    );
+}
+
+#[test]
+fn server_config_is_clone() {
+    let _ = make_server_config(KeyType::Rsa);
+}
+
+#[test]
+fn client_config_is_clone() {
+    let _ = make_client_config(KeyType::Rsa);
+}
+
+#[test]
+fn client_connection_is_debug() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    println!("{:?}", client);
+}
+
+#[test]
+fn server_connection_is_debug() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    println!("{:?}", server);
+}
+
+#[test]
+fn server_complete_io_for_handshake_ending_with_alert() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert!(server.is_handshaking());
+
+    let mut pipe = OtherSession::new_fails(&mut client);
+    let rc = server.complete_io(&mut pipe);
+    assert!(rc.is_err(), "server io failed due to handshake failure");
+    assert!(!server.wants_write(), "but server did send its alert");
+    assert_eq!(
+        format!("{:?}", pipe.last_error),
+        "Some(AlertReceived(HandshakeFailure))",
+        "which was received by client"
+    );
+}
+
+#[test]
+fn server_exposes_offered_sni() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn server_exposes_offered_sni_smashed_to_lowercase() {
+    // webpki actually does this for us in its DnsName type
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("SECOND.TESTServer.com"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn server_exposes_offered_sni_even_if_resolver_fails() {
+    let kt = KeyType::Rsa;
+    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("thisdoesNOTexist.com"))
+                .unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets(),
+            Err(Error::General(
+                "no server certificate chain resolved".to_string()
+            ))
+        );
+        assert_eq!(Some("thisdoesnotexist.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn sni_resolver_works() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    resolver
+        .add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
+        )
+        .unwrap();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+
+    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client2 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("notlocalhost")).unwrap();
+    let err = do_handshake_until_error(&mut client2, &mut server2);
+    assert_eq!(
+        err,
+        Err(ErrorFromPeer::Server(Error::General(
+            "no server certificate chain resolved".into()
+        )))
+    );
+}
+
+#[test]
+fn sni_resolver_rejects_wrong_names() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General(
+            "The server certificate is not valid for the given name".into()
+        )),
+        resolver.add(
+            "not-localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General("Bad DNS name".into())),
+        resolver.add(
+            "not ascii 🦀",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+}
+
+#[test]
+fn sni_resolver_lower_cases_configured_names() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "LOCALHOST",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+}
+
+#[test]
+fn sni_resolver_lower_cases_queried_names() {
+    // actually, the handshake parser does this, but the effect is the same.
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("LOCALHOST")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+}
+
+#[test]
+fn sni_resolver_rejects_bad_certs() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Err(Error::General(
+            "No end-entity certificate in certificate chain".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(vec![], signing_key.clone())
+        )
+    );
+
+    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
+    assert_eq!(
+        Err(Error::General(
+            "End-entity certificate in certificate chain is syntactically invalid".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(bad_chain, signing_key.clone())
+        )
+    );
+}
+
+fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
+    let mut client_secret = [0u8; 64];
+    let mut server_secret = [0u8; 64];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(
+        Err(Error::HandshakeNotComplete),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(
+        Err(Error::HandshakeNotComplete),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(
+        Ok(()),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut client_secret, b"label", None)
+    );
+    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
+    assert_eq!(
+        Ok(()),
+        server.export_keying_material(&mut server_secret, b"label", None)
+    );
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        let server_config = make_server_config(*kt);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+#[test]
+fn test_tls13_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        let server_config = make_server_config(*kt);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+fn check_data_flows(client: &mut ClientConnection, server: &mut ServerConnection) {
+    client
+        .writer()
+        .write_all(b"to server")
+        .unwrap();
+    transfer(client, server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"to server");
+
+    server
+        .writer()
+        .write_all(b"to client")
+        .unwrap();
+    transfer(server, client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"to client");
+}
+
+#[test]
+fn refresh_traffic_keys_works() {
+    for request_peer in [false, true] {
+        let (mut client, mut server) = make_pair(KeyType::Rsa);
+        do_handshake(&mut client, &mut server);
+
+        client
+            .refresh_traffic_keys(request_peer)
+            .unwrap();
+        assert!(client.wants_write());
+        check_data_flows(&mut client, &mut server);
+
+        server
+            .refresh_traffic_keys(request_peer)
+            .unwrap();
+        assert!(server.wants_write());
+        check_data_flows(&mut client, &mut server);
+        check_data_flows(&mut client, &mut server);
+    }
+}
+
+/// AES-128-GCM, but with keys good for only four records.
+static TLS13_AES_128_GCM_SHA256_TINY_LIMIT: rustls::Tls13CipherSuite = rustls::Tls13CipherSuite {
+    common: rustls::cipher_suite::CipherSuiteCommon {
+        suite: CipherSuite::TLS13_AES_128_GCM_SHA256,
+        bulk: rustls::BulkAlgorithm::Aes128Gcm,
+        aead_algorithm: &rustls::crypto::ring::aead::AES_128_GCM,
+    },
+    hmac: &rustls::crypto::ring::hmac::HMAC_SHA256,
+    confidentiality_limit: 4,
+    integrity_limit: 1 << 52,
+};
+
+#[test]
+fn keys_are_updated_within_a_single_write() {
+    let suites = [SupportedCipherSuite::Tls13(
+        &TLS13_AES_128_GCM_SHA256_TINY_LIMIT,
+    )];
+    let client_config = finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::builder()
+            .with_cipher_suites(&suites)
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    let server_config = finish_server_config(
+        KeyType::Rsa,
+        ServerConfig::builder()
+            .with_cipher_suites(&suites)
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    // Ten full records, in one write.
+    let data = vec![0x5a; 10 * 16384];
+    client.set_buffer_limit(None);
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+
+    let mut tls = Vec::new();
+    client.write_tls(&mut tls).unwrap();
+    let mut records = 0;
+    let mut rest = &tls[..];
+    while !rest.is_empty() {
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        rest = &rest[5 + len..];
+        records += 1;
+    }
+
+    // Each key protects three records of data, and then a KeyUpdate.
+    assert_eq!(records, 10 + 3);
+
+    let mut received = Vec::new();
+    let mut tls = &tls[..];
+    while !tls.is_empty() {
+        server.read_tls(&mut tls).unwrap();
+        server.process_new_packets().unwrap();
+        server
+            .reader()
+            .read_to_end(&mut received)
+            .unwrap_or_default();
+    }
+    assert_eq!(received, data);
+}
+
+#[test]
+fn refresh_traffic_keys_needs_completed_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    assert_eq!(
+        client.refresh_traffic_keys(false),
+        Err(Error::HandshakeNotComplete)
+    );
+    assert_eq!(
+        server.refresh_traffic_keys(false),
+        Err(Error::HandshakeNotComplete)
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn refresh_traffic_keys_requires_tls13() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+    assert!(client
+        .refresh_traffic_keys(false)
+        .is_err());
+    assert!(server
+        .refresh_traffic_keys(true)
+        .is_err());
+}
+
+fn do_suite_test(
+    client_config: ClientConfig,
+    server_config: ServerConfig,
+    expect_suite: SupportedCipherSuite,
+    expect_version: ProtocolVersion,
+) {
+    println!(
+        "do_suite_test {:?} {:?}",
+        expect_version,
+        expect_suite.suite()
     );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(None, server.negotiated_cipher_suite());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(None, server.protocol_version());
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+    assert_eq!(Some(expect_version), client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+}
+
+fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
+    for scs in ALL_CIPHER_SUITES.iter().copied() {
+        if scs.suite() == suite {
+            return scs;
+        }
+    }
+
+    panic!("find_suite given unsupported suite");
+}
+
+static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ),
+];
+
+#[test]
+fn negotiated_ciphersuite_default() {
+    for kt in ALL_KEY_TYPES.iter() {
+        do_suite_test(
+            make_client_config(*kt),
+            make_server_config(*kt),
+            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+            ProtocolVersion::TLSv1_3,
+        );
+    }
+}
+
+#[test]
+fn all_suites_covered() {
+    // The AES-CCM suites are not in the defaults, so are tested separately.
+    assert_eq!(DEFAULT_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+}
+
+#[test]
+fn negotiated_ciphersuite_client() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let client_config = finish_client_config(
+            kt,
+            ClientConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        do_suite_test(client_config, make_server_config(kt), scs, version.version);
+    }
+}
+
+#[test]
+fn negotiated_ciphersuite_server() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let server_config = finish_server_config(
+            kt,
+            ServerConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+    }
+}
+
+#[cfg(feature = "aes_ccm")]
+#[test]
+fn negotiated_ciphersuite_aes_ccm() {
+    let suites = [
+        (
+            &rustls::version::TLS13,
+            rustls::cipher_suite::TLS13_AES_128_CCM_SHA256,
+        ),
+        #[cfg(feature = "tls12")]
+        (
+            &rustls::version::TLS12,
+            rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM,
+        ),
+        #[cfg(feature = "tls12")]
+        (
+            &rustls::version::TLS12,
+            rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8,
+        ),
+    ];
+
+    for (version, scs) in suites.iter().copied() {
+        let server_config = finish_server_config(
+            KeyType::Ecdsa,
+            ServerConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        let client_config = finish_client_config(
+            KeyType::Ecdsa,
+            ClientConfig::builder()
+                .with_cipher_suites(ALL_CIPHER_SUITES)
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .unwrap(),
+        );
+
+        do_suite_test(client_config, server_config, scs, version.version);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct KeyLogItem {
+    label: String,
+    client_random: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+struct KeyLogToVec {
+    label: &'static str,
+    items: Mutex<Vec<KeyLogItem>>,
+}
+
+impl KeyLogToVec {
+    fn new(who: &'static str) -> Self {
+        KeyLogToVec {
+            label: who,
+            items: Mutex::new(vec![]),
+        }
+    }
+
+    fn take(&self) -> Vec<KeyLogItem> {
+        std::mem::take(&mut self.items.lock().unwrap())
+    }
+}
+
+impl KeyLog for KeyLogToVec {
+    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
+        let value = KeyLogItem {
+            label: label.into(),
+            client_random: client.into(),
+            secret: secret.into(),
+        };
+
+        println!("key log {:?}: {:?}", self.label, value);
+
+        self.items.lock().unwrap().push(value);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn key_log_for_tls12() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+    assert_eq!(client_full_log, server_full_log);
+    assert_eq!(1, client_full_log.len());
+    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+    assert_eq!(client_resume_log, server_resume_log);
+    assert_eq!(1, client_resume_log.len());
+    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
+    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+}
+
+#[test]
+fn key_log_for_tls13() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+
+    assert_eq!(5, client_full_log.len());
+    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
+    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
+
+    assert_eq!(client_full_log[0], server_full_log[0]);
+    assert_eq!(client_full_log[1], server_full_log[1]);
+    assert_eq!(client_full_log[2], server_full_log[2]);
+    assert_eq!(client_full_log[3], server_full_log[3]);
+    assert_eq!(client_full_log[4], server_full_log[4]);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     do_handshake(&mut client, &mut server);
 
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+
+    assert_eq!(5, client_resume_log.len());
     assert_eq!(
-        Ok(()),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[0].label
     );
     assert_eq!(
-        Ok(()),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[1].label
     );
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
+
+    assert_eq!(6, server_resume_log.len());
+    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[1].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[2].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
+    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
+
+    assert_eq!(client_resume_log[0], server_resume_log[1]);
+    assert_eq!(client_resume_log[1], server_resume_log[2]);
+    assert_eq!(client_resume_log[2], server_resume_log[3]);
+    assert_eq!(client_resume_log[3], server_resume_log[4]);
+    assert_eq!(client_resume_log[4], server_resume_log[5]);
+}
+
+#[test]
+fn vectored_write_for_server_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut client.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_client_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut server.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_server_handshake_with_half_rtt_data() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.send_half_rtt_data = true;
+    // keep the cert chain uncompressed, so the first flight is big
+    server_config.cert_compressors = vec![];
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 103);
+        assert_eq!(pipe.writevs, vec![vec![103]]);
+    }
 
-    assert_eq!(
-        Ok(()),
-        client.export_keying_material(&mut client_secret, b"label", None)
-    );
-    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
-    assert_eq!(
-        Ok(()),
-        server.export_keying_material(&mut server_secret, b"label", None)
-    );
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
 }
 
-#[cfg(feature = "tls12")]
-#[test]
-fn test_tls12_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
-        let server_config = make_server_config(*kt);
+fn check_half_rtt_does_not_work(mut server_config: ServerConfig) {
+    // keep the cert chain uncompressed, so the first flight is big
+    server_config.cert_compressors = vec![];
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
 
-        do_exporter_test(client_config, server_config);
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() >= 6); // at least a server hello/ccs/cert/serverkx data
     }
-}
 
-#[test]
-fn test_tls13_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
-        let server_config = make_server_config(*kt);
+    // client second flight
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
 
-        do_exporter_test(client_config, server_config);
+    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
+    // it to an unauthenticated peer. so it happens here, in the server's second
+    // flight (42 and 32 are lengths of appdata sent above).
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 177);
+        assert_eq!(pipe.writevs, vec![vec![103, 42, 32]]);
     }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
 }
 
-fn do_suite_test(
-    client_config: ClientConfig,
-    server_config: ServerConfig,
-    expect_suite: SupportedCipherSuite,
-    expect_version: ProtocolVersion,
-) {
-    println!(
-        "do_suite_test {:?} {:?}",
-        expect_version,
-        expect_suite.suite()
-    );
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+#[test]
+fn vectored_write_for_server_handshake_no_half_rtt_with_client_auth() {
+    let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+    server_config.send_half_rtt_data = true; // ask even though it will be ignored
+    check_half_rtt_does_not_work(server_config);
+}
 
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(None, server.negotiated_cipher_suite());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(None, server.protocol_version());
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
+#[test]
+fn vectored_write_for_server_handshake_no_half_rtt_by_default() {
+    let server_config = make_server_config(KeyType::Rsa);
+    assert!(!server_config.send_half_rtt_data);
+    check_half_rtt_does_not_work(server_config);
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+#[test]
+fn vectored_write_for_client_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 200); // just the client hello
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 1); // only a client hello
+    }
 
     transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
 
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
-
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 154);
+        // CCS, finished, then two application datas
+        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
+    }
 
-    assert!(!client.is_handshaking());
     assert!(!server.is_handshaking());
-    assert_eq!(Some(expect_version), client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+    assert!(!client.is_handshaking());
+    check_read(&mut server.reader(), b"012345678901234567890123456789");
 }
 
-fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
-    for scs in ALL_CIPHER_SUITES.iter().copied() {
-        if scs.suite() == suite {
-            return scs;
-        }
-    }
+#[test]
+fn vectored_write_with_slow_client() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    panic!("find_suite given unsupported suite");
-}
+    client.set_buffer_limit(Some(32));
 
-static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_256_GCM_SHA384,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-    ),
-];
+    do_handshake(&mut client, &mut server);
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
 
-#[test]
-fn negotiated_ciphersuite_default() {
-    for kt in ALL_KEY_TYPES.iter() {
-        do_suite_test(
-            make_client_config(*kt),
-            make_server_config(*kt),
-            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
-            ProtocolVersion::TLSv1_3,
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        pipe.short_writes = true;
+        let wrlen = server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap();
+        assert_eq!(42, wrlen);
+        assert_eq!(
+            pipe.writevs,
+            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
         );
     }
+    check_read(&mut client.reader(), b"01234567890123456789");
+}
+
+struct ServerStorage {
+    storage: Arc<dyn rustls::server::StoresServerSessions>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+    take_count: AtomicUsize,
+}
+
+impl ServerStorage {
+    fn new() -> Self {
+        ServerStorage {
+            storage: rustls::server::ServerSessionMemoryCache::new(1024),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+            take_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.put_count.load(Ordering::SeqCst)
+    }
+    fn gets(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+    fn takes(&self) -> usize {
+        self.take_count.load(Ordering::SeqCst)
+    }
 }
 
-#[test]
-fn all_suites_covered() {
-    assert_eq!(ALL_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+impl fmt::Debug for ServerStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(put: {:?}, get: {:?}, take: {:?})",
+            self.put_count, self.get_count, self.take_count
+        )
+    }
 }
 
-#[test]
-fn negotiated_ciphersuite_client() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let client_config = finish_client_config(
-            kt,
-            ClientConfig::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+impl rustls::server::StoresServerSessions for ServerStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.put(key, value)
+    }
 
-        do_suite_test(client_config, make_server_config(kt), scs, version.version);
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.get(key)
     }
-}
 
-#[test]
-fn negotiated_ciphersuite_server() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let server_config = finish_server_config(
-            kt,
-            ServerConfig::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.take_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.take(key)
+    }
 
-        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+    fn can_cache(&self) -> bool {
+        true
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct KeyLogItem {
-    label: String,
-    client_random: Vec<u8>,
-    secret: Vec<u8>,
+#[derive(Debug, Clone, PartialEq)]
+enum ClientStorageOp {
+    SetKxHint(rustls::ServerName, rustls::NamedGroup),
+    GetKxHint(rustls::ServerName, Option<rustls::NamedGroup>),
+    #[cfg(feature = "tls12")]
+    SetTls12Session(rustls::ServerName),
+    #[cfg(feature = "tls12")]
+    GetTls12Session(rustls::ServerName, bool),
+    InsertTls13Ticket(rustls::ServerName),
+    TakeTls13Ticket(rustls::ServerName, bool),
 }
 
-struct KeyLogToVec {
-    label: &'static str,
-    items: Mutex<Vec<KeyLogItem>>,
+struct ClientStorage {
+    storage: Arc<dyn rustls::client::StoresClientSessions>,
+    ops: Mutex<Vec<ClientStorageOp>>,
 }
 
-impl KeyLogToVec {
-    fn new(who: &'static str) -> Self {
-        KeyLogToVec {
-            label: who,
-            items: Mutex::new(vec![]),
+impl ClientStorage {
+    fn new() -> Self {
+        ClientStorage {
+            storage: rustls::client::ClientSessionMemoryCache::new(1024),
+            ops: Mutex::new(Vec::new()),
         }
     }
 
-    fn take(&self) -> Vec<KeyLogItem> {
-        std::mem::take(&mut self.items.lock().unwrap())
+    fn ops(&self) -> Vec<ClientStorageOp> {
+        self.ops.lock().unwrap().clone()
+    }
+
+    #[cfg(feature = "tls12")]
+    fn puts(&self) -> usize {
+        self.ops()
+            .iter()
+            .filter(|op| {
+                matches!(
+                    op,
+                    ClientStorageOp::SetKxHint(..)
+                        | ClientStorageOp::SetTls12Session(..)
+                        | ClientStorageOp::InsertTls13Ticket(..)
+                )
+            })
+            .count()
+    }
+
+    fn tls13_tickets_taken(&self) -> usize {
+        self.ops()
+            .iter()
+            .filter(|op| matches!(op, ClientStorageOp::TakeTls13Ticket(_, true)))
+            .count()
+    }
+
+    fn record(&self, op: ClientStorageOp) {
+        self.ops.lock().unwrap().push(op);
     }
 }
 
-impl KeyLog for KeyLogToVec {
-    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
-        let value = KeyLogItem {
-            label: label.into(),
-            client_random: client.into(),
-            secret: secret.into(),
-        };
+impl fmt::Debug for ClientStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(ops: {:?})", self.ops.lock().unwrap())
+    }
+}
 
-        println!("key log {:?}: {:?}", self.label, value);
+impl rustls::client::StoresClientSessions for ClientStorage {
+    fn set_kx_hint(&self, server_name: &rustls::ServerName, group: rustls::NamedGroup) {
+        self.record(ClientStorageOp::SetKxHint(server_name.clone(), group));
+        self.storage
+            .set_kx_hint(server_name, group)
+    }
 
-        self.items.lock().unwrap().push(value);
+    fn kx_hint(&self, server_name: &rustls::ServerName) -> Option<rustls::NamedGroup> {
+        let group = self.storage.kx_hint(server_name);
+        self.record(ClientStorageOp::GetKxHint(server_name.clone(), group));
+        group
+    }
+
+    #[cfg(feature = "tls12")]
+    fn set_tls12_session(
+        &self,
+        server_name: &rustls::ServerName,
+        value: rustls::client::Tls12ClientSessionValue,
+    ) {
+        self.record(ClientStorageOp::SetTls12Session(server_name.clone()));
+        self.storage
+            .set_tls12_session(server_name, value)
+    }
+
+    #[cfg(feature = "tls12")]
+    fn tls12_session(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls12ClientSessionValue> {
+        let value = self.storage.tls12_session(server_name);
+        self.record(ClientStorageOp::GetTls12Session(
+            server_name.clone(),
+            value.is_some(),
+        ));
+        value
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+        value: rustls::client::Tls13ClientSessionValue,
+    ) {
+        self.record(ClientStorageOp::InsertTls13Ticket(server_name.clone()));
+        self.storage
+            .insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &rustls::ServerName,
+    ) -> Option<rustls::client::Tls13ClientSessionValue> {
+        let value = self
+            .storage
+            .take_tls13_ticket(server_name);
+        self.record(ClientStorageOp::TakeTls13Ticket(
+            server_name.clone(),
+            value.is_some(),
+        ));
+        value
     }
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn key_log_for_tls12() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
-
+fn tls13_stateful_resumption() {
     let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
-    client_config.key_log = client_key_log.clone();
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
     let client_config = Arc::new(client_config);
 
     let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
     let server_config = Arc::new(server_config);
 
     // full handshake
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
-
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
-    assert_eq!(client_full_log, server_full_log);
-    assert_eq!(1, client_full_log.len());
-    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 1);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
 
     // resumed
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 2);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 1);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
-    assert_eq!(client_resume_log, server_resume_log);
-    assert_eq!(1, client_resume_log.len());
-    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
-    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 3);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 2);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
 }
 
 #[test]
-fn key_log_for_tls13() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
-
+fn tls13_stateless_resumption() {
     let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    client_config.key_log = client_key_log.clone();
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
     let client_config = Arc::new(client_config);
 
     let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
     let server_config = Arc::new(server_config);
 
     // full handshake
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
-
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
-
-    assert_eq!(5, client_full_log.len());
-    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
-    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
-
-    assert_eq!(client_full_log[0], server_full_log[0]);
-    assert_eq!(client_full_log[1], server_full_log[1]);
-    assert_eq!(client_full_log[2], server_full_log[2]);
-    assert_eq!(client_full_log[3], server_full_log[3]);
-    assert_eq!(client_full_log[4], server_full_log[4]);
-
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
-
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
-
-    assert_eq!(5, client_resume_log.len());
-    assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[0].label
-    );
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
     assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[1].label
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
     );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
 
-    assert_eq!(6, server_resume_log.len());
-    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
     assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[1].label
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
     );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
     assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[2].label
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
     );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
-    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
-
-    assert_eq!(client_resume_log[0], server_resume_log[1]);
-    assert_eq!(client_resume_log[1], server_resume_log[2]);
-    assert_eq!(client_resume_log[2], server_resume_log[3]);
-    assert_eq!(client_resume_log[3], server_resume_log[4]);
-    assert_eq!(client_resume_log[4], server_resume_log[5]);
 }
 
-#[test]
-fn vectored_write_for_server_appdata() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
-
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+/// Like `do_handshake`, but doing the server's deferred session lookups
+/// in `storage`.  Returns how many there were.
+fn do_handshake_with_deferred_lookups(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+    storage: &dyn rustls::server::StoresServerSessions,
+) -> (usize, usize, usize) {
+    let (mut to_client, mut to_server, mut lookups) = (0, 0, 0);
+    while server.is_handshaking() || client.is_handshaking() {
+        to_server += transfer(client, server);
+        server.process_new_packets().unwrap();
+        while let Some(lookup) = server.pending_session_lookup().cloned() {
+            let value = match lookup {
+                SessionLookup::Get(key) => storage.get(&key),
+                SessionLookup::Take(key) => storage.take(&key),
+            };
+            server
+                .complete_session_lookup(value)
+                .unwrap();
+            lookups += 1;
+        }
+        to_client += transfer(server, client);
+        client.process_new_packets().unwrap();
     }
-    check_read(
-        &mut client.reader(),
-        b"0123456789012345678901234567890123456789",
-    );
+    (to_server, to_client, lookups)
 }
 
 #[test]
-fn vectored_write_for_client_appdata() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
+fn deferred_session_lookups() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
 
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        let mut server_config = make_server_config(kt);
+        let storage = Arc::new(ServerStorage::new());
+        server_config.session_storage = storage.clone();
+        server_config.defer_session_lookups = true;
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        let (_, full_s2c, mut lookups) =
+            do_handshake_with_deferred_lookups(&mut client, &mut server, &*storage);
+
+        for _ in 0..2 {
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            let (_, resume_s2c, resume_lookups) =
+                do_handshake_with_deferred_lookups(&mut client, &mut server, &*storage);
+            assert!(resume_s2c < full_s2c, "{:?}", version);
+            assert_eq!(resume_lookups, 1);
+            lookups += resume_lookups;
+        }
+
+        // Every lookup was left to the application.
+        assert_eq!(storage.gets() + storage.takes(), lookups);
+        match version.version {
+            ProtocolVersion::TLSv1_3 => assert_eq!(storage.takes(), 2),
+            _ => assert_eq!(storage.takes(), 0),
+        }
     }
-    check_read(
-        &mut server.reader(),
-        b"0123456789012345678901234567890123456789",
-    );
 }
 
 #[test]
-fn vectored_write_for_server_handshake_with_half_rtt_data() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.send_half_rtt_data = true;
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+fn deferred_session_lookup_suspends_handshake() {
+    let kt = KeyType::Rsa;
+    let client_config = Arc::new(make_client_config_with_versions(
+        kt,
+        &[&rustls::version::TLS13],
+    ));
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    let mut server_config = make_server_config(kt);
+    let storage: Arc<dyn rustls::server::StoresServerSessions> =
+        rustls::server::ServerSessionMemoryCache::new(32);
+    server_config.session_storage = storage.clone();
+    server_config.defer_session_lookups = true;
+    let server_config = Arc::new(server_config);
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
-    }
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, full_s2c, lookups) =
+        do_handshake_with_deferred_lookups(&mut client, &mut server, &*storage);
+    assert_eq!(lookups, 0);
+    assert!(server
+        .complete_session_lookup(None)
+        .is_err());
 
-    client.process_new_packets().unwrap();
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     transfer(&mut client, &mut server);
     server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 103);
-        assert_eq!(pipe.writevs, vec![vec![103]]);
-    }
-
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
-}
+    let key = match server.pending_session_lookup() {
+        Some(SessionLookup::Take(key)) => key.clone(),
+        lookup => panic!("unexpected lookup {:?}", lookup),
+    };
+    assert!(storage.get(&key).is_some());
 
-fn check_half_rtt_does_not_work(server_config: ServerConfig) {
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+    // Nothing happens until the lookup is completed.
+    server.process_new_packets().unwrap();
+    assert!(server
+        .pending_session_lookup()
+        .is_some());
+    assert!(!server.wants_write());
 
+    // Without the session, the handshake is a full one.
     server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
+        .complete_session_lookup(None)
         .unwrap();
+    assert!(server
+        .pending_session_lookup()
+        .is_none());
+    let s2c = transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    let (_, rest_s2c, lookups) =
+        do_handshake_with_deferred_lookups(&mut client, &mut server, &*storage);
+    assert_eq!(s2c + rest_s2c, full_s2c);
+    assert_eq!(lookups, 0);
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() >= 6); // at least a server hello/ccs/cert/serverkx data
+/// A stand-in for a remote signing service, which answers requests
+/// only when asked to.
+struct FakeRemoteSigner {
+    key: Arc<dyn sign::SigningKey>,
+    requests: Vec<SigningRequest>,
+    answered: Vec<SigningRequest>,
+}
+
+impl FakeRemoteSigner {
+    fn new(kt: KeyType) -> Self {
+        Self::with_key(sign::any_supported_type(&kt.get_key()).unwrap())
     }
 
-    // client second flight
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
+    fn with_key(key: Arc<dyn sign::SigningKey>) -> Self {
+        Self {
+            key,
+            requests: Vec::new(),
+            answered: Vec::new(),
+        }
+    }
 
-    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
-    // it to an unauthenticated peer. so it happens here, in the server's second
-    // flight (42 and 32 are lengths of appdata sent above).
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 177);
-        assert_eq!(pipe.writevs, vec![vec![103, 42, 32]]);
+    fn submit(&mut self, request: SigningRequest) {
+        self.requests.push(request);
     }
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
+    fn answer(&mut self) -> Option<Result<Vec<u8>, Error>> {
+        let request = self.requests.pop()?;
+        let signer = self
+            .key
+            .choose_scheme(&[request.scheme()])
+            .unwrap();
+        let signature = signer.sign(request.message());
+        self.answered.push(request);
+        Some(signature)
+    }
 }
 
-#[test]
-fn vectored_write_for_server_handshake_no_half_rtt_with_client_auth() {
-    let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
-    server_config.send_half_rtt_data = true; // ask even though it will be ignored
-    check_half_rtt_does_not_work(server_config);
+/// Like `do_handshake`, but with the server's signatures made by
+/// `signer`, each answered a round trip after it is requested.
+fn do_handshake_with_deferred_signing(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+    signer: &mut FakeRemoteSigner,
+) -> usize {
+    let mut signatures = 0;
+    while server.is_handshaking() || client.is_handshaking() {
+        if let Some(signature) = signer.answer() {
+            server
+                .complete_signature(signature)
+                .unwrap();
+            signatures += 1;
+        }
+        transfer(client, server);
+        server.process_new_packets().unwrap();
+        if let Some(request) = server.pending_signature() {
+            signer.submit(request.clone());
+        }
+        transfer(server, client);
+        client.process_new_packets().unwrap();
+    }
+    signatures
 }
 
 #[test]
-fn vectored_write_for_server_handshake_no_half_rtt_by_default() {
-    let server_config = make_server_config(KeyType::Rsa);
-    assert!(!server_config.send_half_rtt_data);
-    check_half_rtt_does_not_work(server_config);
+fn deferred_signing() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config.defer_signing = true;
+        let server_config = Arc::new(server_config);
+
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            let mut signer = FakeRemoteSigner::new(*kt);
+            let signatures =
+                do_handshake_with_deferred_signing(&mut client, &mut server, &mut signer);
+            assert_eq!(signatures, 1, "{:?}", version);
+
+            let request = &signer.answered[0];
+            assert_eq!(request.cert_chain(), &kt.get_chain()[..]);
+            assert_eq!(request.key_source(), &SigningKeySource::Certificate);
+        }
+    }
 }
 
 #[test]
-fn vectored_write_for_client_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"0123456789")
+fn deferred_signing_with_delegated_credential() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let chain = kt.get_delegation_chain();
+        let dc_key = make_delegated_key();
+        let dc = sign::DelegatedCredential::new(
+            &chain[0],
+            &*sign::any_supported_type(&kt.get_key()).unwrap(),
+            Arc::clone(&dc_key),
+            Duration::from_secs(3600),
+            SystemTime::now(),
+        )
         .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 200); // just the client hello
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 1); // only a client hello
-    }
+        let encoded = dc.encoded();
+        let mut server_config =
+            make_server_config_with_delegated_credential(*kt, chain.clone(), dc);
+        server_config.defer_signing = true;
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config.enable_delegated_credentials = true;
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 154);
-        // CCS, finished, then two application datas
-        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
-    }
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let mut signer = FakeRemoteSigner::with_key(dc_key);
+        let signatures = do_handshake_with_deferred_signing(&mut client, &mut server, &mut signer);
+        assert_eq!(signatures, 1);
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut server.reader(), b"012345678901234567890123456789");
+        let request = &signer.answered[0];
+        assert_eq!(request.cert_chain(), &chain[..]);
+        assert_eq!(
+            request.key_source(),
+            &SigningKeySource::DelegatedCredential(encoded)
+        );
+        assert_eq!(client.peer_certificates(), Some(&chain[..]));
+    }
 }
 
 #[test]
-fn vectored_write_with_slow_client() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn deferred_signing_suspends_handshake() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config(kt);
+    server_config.defer_signing = true;
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert!(server
+            .complete_signature(Ok(vec![]))
+            .is_err());
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let mut signer = FakeRemoteSigner::new(kt);
+        signer.submit(
+            server
+                .pending_signature()
+                .unwrap()
+                .clone(),
+        );
 
-    client.set_buffer_limit(Some(32));
+        // The server's first flight is not finished until the signature arrives.
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        server.process_new_packets().unwrap();
+        assert!(server.pending_signature().is_some());
+        assert!(!server.wants_write());
+        assert!(client.is_handshaking());
 
-    do_handshake(&mut client, &mut server);
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
+        server
+            .complete_signature(signer.answer().unwrap())
+            .unwrap();
+        assert!(server.pending_signature().is_none());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(5, client.writer().write(b"hello").unwrap());
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"hello");
 
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        pipe.short_writes = true;
-        let wrlen = server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap();
-        assert_eq!(42, wrlen);
+        // A failure to sign ends the handshake.  (A new client config, so
+        // there is no session to resume.)
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        let err = Error::General("signing service unavailable".into());
         assert_eq!(
-            pipe.writevs,
-            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
+            server.complete_signature(Err(err.clone())),
+            Err(err.clone())
         );
+        assert_eq!(server.process_new_packets().err(), Some(err));
     }
-    check_read(&mut client.reader(), b"01234567890123456789");
 }
 
-struct ServerStorage {
-    storage: Arc<dyn rustls::server::StoresServerSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    take_count: AtomicUsize,
-}
+/// Like `do_handshake_until_error`, but with certificates verified by
+/// `verify` for peers which defer that.  Returns how many chains were
+/// verified.
+fn do_handshake_with_deferred_cert_verification(
+    client: &mut ClientConnection,
+    server: &mut ServerConnection,
+    verify: impl Fn(&[Certificate]) -> Result<(), Error>,
+) -> Result<usize, ErrorFromPeer> {
+    let mut verified = 0;
+    while server.is_handshaking() || client.is_handshaking() {
+        transfer(client, server);
+        server
+            .process_new_packets()
+            .map_err(ErrorFromPeer::Server)?;
+        if let Some(cert_chain) = server.pending_cert_verification() {
+            let result = verify(cert_chain);
+            server
+                .complete_cert_verification(result)
+                .map_err(ErrorFromPeer::Server)?;
+            verified += 1;
+        }
 
-impl ServerStorage {
-    fn new() -> Self {
-        ServerStorage {
-            storage: rustls::server::ServerSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            take_count: AtomicUsize::new(0),
+        transfer(server, client);
+        client
+            .process_new_packets()
+            .map_err(ErrorFromPeer::Client)?;
+        if let Some(cert_chain) = client.pending_cert_verification() {
+            let result = verify(cert_chain);
+            client
+                .complete_cert_verification(result)
+                .map_err(ErrorFromPeer::Client)?;
+            verified += 1;
         }
     }
+    Ok(verified)
+}
 
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
-    }
-    fn takes(&self) -> usize {
-        self.take_count.load(Ordering::SeqCst)
+#[test]
+fn deferred_cert_verification() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config_with_mandatory_client_auth(*kt);
+        server_config.defer_cert_verification = true;
+        let server_config = Arc::new(server_config);
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            client_config.defer_cert_verification = true;
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            let verified =
+                do_handshake_with_deferred_cert_verification(&mut client, &mut server, |chain| {
+                    assert!(chain == kt.get_chain() || chain == kt.get_client_chain());
+                    Ok(())
+                });
+            assert_eq!(verified.ok(), Some(2), "{:?}", version);
+            assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+            assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
+        }
     }
 }
 
-impl fmt::Debug for ServerStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(put: {:?}, get: {:?}, take: {:?})",
-            self.put_count, self.get_count, self.take_count
-        )
+#[test]
+fn deferred_cert_verification_replaces_verifier() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+    for version in rustls::ALL_VERSIONS {
+        // This client doesn't trust the server's issuer...
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config.clone()), &server_config);
+        assert!(matches!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Client(Error::InvalidCertificateData(_)))
+        ));
+
+        // ... which is not consulted once the application does the verification.
+        let mut client_config = client_config;
+        client_config.defer_cert_verification = true;
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        let verified =
+            do_handshake_with_deferred_cert_verification(&mut client, &mut server, |_| Ok(()));
+        assert_eq!(verified.ok(), Some(1), "{:?}", version);
     }
 }
 
-impl rustls::server::StoresServerSessions for ServerStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.put(key, value)
-    }
+#[test]
+fn deferred_cert_verification_rejects() {
+    let kt = KeyType::Rsa;
+    let mut server_config = make_server_config_with_mandatory_client_auth(kt);
+    server_config.defer_cert_verification = true;
+    let server_config = Arc::new(server_config);
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
-    }
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        client_config.defer_cert_verification = true;
+        let client_config = Arc::new(client_config);
 
-    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.take_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.take(key)
-    }
+        // The client rejects the server's certificate.
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        let err = Error::InvalidCertificateData("rejected by policy".into());
+        assert_eq!(
+            do_handshake_with_deferred_cert_verification(&mut client, &mut server, |_| Err(
+                err.clone()
+            )),
+            Err(ErrorFromPeer::Client(err.clone()))
+        );
+        transfer(&mut client, &mut server);
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::AlertReceived(
+                rustls::AlertDescription::BadCertificate
+            ))
+        );
 
-    fn can_cache(&self) -> bool {
-        true
+        // The server rejects the client's certificate.
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        let verified =
+            do_handshake_with_deferred_cert_verification(&mut client, &mut server, |chain| {
+                if chain == kt.get_client_chain() {
+                    Err(Error::CertificateRevoked)
+                } else {
+                    Ok(())
+                }
+            });
+        assert_eq!(
+            verified,
+            Err(ErrorFromPeer::Server(Error::CertificateRevoked))
+        );
+        transfer(&mut server, &mut client);
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::AlertReceived(
+                rustls::AlertDescription::CertificateRevoked
+            ))
+        );
     }
 }
 
-struct ClientStorage {
-    storage: Arc<dyn rustls::client::StoresClientSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    last_put_key: Mutex<Option<Vec<u8>>>,
-}
+#[test]
+fn deferred_cert_verification_suspends_handshake() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config(kt));
 
-impl ClientStorage {
-    fn new() -> Self {
-        ClientStorage {
-            storage: rustls::client::ClientSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            last_put_key: Mutex::new(None),
-        }
-    }
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.defer_cert_verification = true;
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert!(client
+            .complete_cert_verification(Ok(()))
+            .is_err());
 
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert_eq!(
+            client.pending_cert_verification(),
+            Some(&kt.get_chain()[..])
+        );
+
+        // Nothing happens until the verification is completed.
+        client.process_new_packets().unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert!(client
+            .pending_cert_verification()
+            .is_some());
+        assert!(client.is_handshaking());
+        assert!(server.is_handshaking());
+        assert_eq!(client.peer_certificates(), None);
+
+        client
+            .complete_cert_verification(Ok(()))
+            .unwrap();
+        assert!(client
+            .pending_cert_verification()
+            .is_none());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
     }
 }
 
-impl fmt::Debug for ClientStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(puts: {:?}, gets: {:?} )",
-            self.put_count, self.get_count
-        )
+#[test]
+fn stateless_resumption_with_shared_ticket_keys() {
+    let kt = KeyType::Rsa;
+    let key = || rustls::TicketKey::new([1; 16], &[2; 32]).unwrap();
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(kt, &[version]));
+        let make_server_config = |ticketer: Arc<rustls::SharedTicketer>| {
+            let mut server_config = make_server_config(kt);
+            server_config.ticketer = ticketer;
+            server_config.session_storage = Arc::new(rustls::server::NoServerSessionStorage {});
+            Arc::new(server_config)
+        };
+
+        // A full handshake with one server...
+        let first = make_server_config(rustls::SharedTicketer::new(key(), vec![], 3600));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &first);
+        let (_, full_s2c) = do_handshake(&mut client, &mut server);
+
+        // ... allows resumption with another server sharing its key.
+        let ticketer = rustls::SharedTicketer::new(key(), vec![], 3600);
+        let second = make_server_config(ticketer.clone());
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &second);
+        let (_, resume_s2c) = do_handshake(&mut client, &mut server);
+        assert!(resume_s2c < full_s2c);
+
+        // After rotating to a new key, without keeping the old one, resumption fails.
+        ticketer.rotate(rustls::TicketKey::new([3; 16], &[4; 32]).unwrap(), vec![]);
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &second);
+        let (_, rotated_s2c) = do_handshake(&mut client, &mut server);
+        assert!(rotated_s2c > resume_s2c);
     }
 }
 
-impl rustls::client::StoresClientSessions for ClientStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        *self.last_put_key.lock().unwrap() = Some(key.clone());
-        self.storage.put(key, value)
-    }
+#[test]
+fn tls13_tickets_are_used_once() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let storage = Arc::new(ClientStorage::new());
+    client_config.session_storage = storage.clone();
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(kt));
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (_, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.tls13_tickets_taken(), 0);
+
+    // Of two parallel connections, only one can resume with the ticket.
+    let (mut client_1, mut server_1) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (mut client_2, mut server_2) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(storage.tls13_tickets_taken(), 1);
+    let (_, s2c_1) = do_handshake(&mut client_1, &mut server_1);
+    let (_, s2c_2) = do_handshake(&mut client_2, &mut server_2);
+    assert!(s2c_1 < full_s2c);
+    assert_eq!(s2c_2, full_s2c);
+
+    // Both received a ticket, so two more connections can resume.
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        let (_, s2c) = do_handshake(&mut client, &mut server);
+        assert!(s2c < full_s2c);
     }
+    assert_eq!(storage.tls13_tickets_taken(), 3);
 }
 
 #[test]
-fn tls13_stateful_resumption() {
+fn tls13_ticket_with_zero_lifetime_is_not_stored() {
     let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let storage = Arc::new(ClientStorage::new());
+    client_config.session_storage = storage.clone();
     let client_config = Arc::new(client_config);
 
     let mut server_config = make_server_config(kt);
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
-
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 1);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
-
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 2);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 1);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
+    server_config.ticketer = rustls::SharedTicketer::new(
+        rustls::TicketKey::new([1; 16], &[2; 32]).unwrap(),
+        vec![],
+        0,
     );
+    let server_config = Arc::new(server_config);
 
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 3);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 2);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+    assert!(!storage
+        .ops()
+        .iter()
+        .any(|op| matches!(op, ClientStorageOp::InsertTls13Ticket(_))));
+    assert_eq!(storage.tls13_tickets_taken(), 0);
 }
 
 #[test]
-fn tls13_stateless_resumption() {
+fn file_session_storage_resumes_across_client_configs() {
     let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(kt));
 
-    let mut server_config = make_server_config(kt);
-    server_config.ticketer = rustls::Ticketer::new().unwrap();
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
+    for version in rustls::ALL_VERSIONS {
+        let dir = std::env::temp_dir().join(format!(
+            "rustls-api-file-cache-{}-{:?}",
+            std::process::id(),
+            version.version
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        // Each config stands in for a separate process sharing the directory.
+        let make_client_config = || {
+            let mut client_config = make_client_config_with_versions(kt, &[version]);
+            client_config.session_storage =
+                rustls::client::ClientSessionFileCache::new(&dir, &[0x42; 32]).unwrap();
+            Arc::new(client_config)
+        };
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&make_client_config(), &server_config);
+        let (_, full_s2c) = do_handshake(&mut client, &mut server);
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&make_client_config(), &server_config);
+        let (_, resumed_s2c) = do_handshake(&mut client, &mut server);
+        assert!(resumed_s2c < full_s2c, "{:?}", version);
 
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
 
 #[test]
@@ -2884,6 +5385,42 @@ fn early_data_is_available_on_resumption() {
     assert_eq!(&received_early_data[..], b"hello");
 }
 
+#[test]
+fn early_data_is_available_after_deferred_session_lookup() {
+    let (client_config, server_config) = early_data_configs();
+    let mut server_config = (*server_config).clone();
+    server_config.defer_session_lookups = true;
+    let storage = server_config.session_storage.clone();
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake_with_deferred_lookups(&mut client, &mut server, &*storage);
+
+    // The early data arrives while the handshake is suspended.
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    let (_, _, lookups) = do_handshake_with_deferred_lookups(&mut client, &mut server, &*storage);
+    assert_eq!(lookups, 1);
+
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
+}
+
 #[test]
 fn early_data_not_available_on_server_before_client_hello() {
     let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
@@ -3087,6 +5624,14 @@ mod test_quic {
             .unwrap()
             .unwrap();
         assert!(client.is_early_data_accepted());
+        // Finish the handshake, which delivers a new ticket: the one used
+        // above cannot be used again.
+        step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(step(&mut server, &mut client)
+            .unwrap()
+            .is_none());
 
         // 0-RTT rejection
         {
@@ -3099,8 +5644,11 @@ mod test_quic {
             )
             .unwrap();
 
+            // This server has not seen the client's ticket.
+            let mut server_config = (*server_config).clone();
+            server_config.session_storage = rustls::server::ServerSessionMemoryCache::new(32);
             let mut server = ServerConnection::new_quic(
-                Arc::clone(&server_config),
+                Arc::new(server_config),
                 quic::Version::V1,
                 server_params.into(),
             )
@@ -3297,7 +5845,7 @@ mod test_quic {
                 payload: HandshakePayload::ClientHello(ClientHelloPayload {
                     client_version: ProtocolVersion::TLSv1_3,
                     random,
-                    session_id: SessionID::random().unwrap(),
+                    session_id: SessionID::random(rustls::crypto::ring::RING).unwrap(),
                     cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
                     compression_methods: vec![Compression::Null],
                     extensions: vec![
@@ -3364,7 +5912,7 @@ mod test_quic {
                 payload: HandshakePayload::ClientHello(ClientHelloPayload {
                     client_version: ProtocolVersion::TLSv1_2,
                     random: random.clone(),
-                    session_id: SessionID::random().unwrap(),
+                    session_id: SessionID::random(rustls::crypto::ring::RING).unwrap(),
                     cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
                     compression_methods: vec![Compression::Null],
                     extensions: vec![
@@ -3563,7 +6111,14 @@ mod test_quic {
         let (first, rest) = header.split_at_mut(1);
         let sample = &payload[..sample_len];
 
-        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, Side::Server);
+        // The server's keys come from the provider's implementation of the suite.
+        let server_keys = Keys::initial_with_provider(
+            Version::V1,
+            rustls::crypto::ring::RING,
+            &CONNECTION_ID,
+            Side::Server,
+        )
+        .unwrap();
         server_keys
             .remote
             .header
@@ -3619,95 +6174,501 @@ fn test_client_does_not_offer_sha1() {
                 _ => unreachable!(),
             };
 
-            let sigalgs = client_hello
-                .get_sigalgs_extension()
-                .unwrap();
-            assert!(
-                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
-                "sha1 unexpectedly offered"
-            );
-        }
+            let sigalgs = client_hello
+                .get_sigalgs_extension()
+                .unwrap();
+            assert!(
+                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
+                "sha1 unexpectedly offered"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_client_config_keyshare() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_config_keyshare_mismatch() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn test_client_sends_helloretryrequest() {
+    // client sends a secp384r1 key share
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let storage = Arc::new(ClientStorage::new());
+    client_config.session_storage = storage.clone();
+
+    // but server only accepts x25519, so a HRR is required
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // client sends hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 1);
+    }
+
+    // server sends HRR
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    }
+
+    // client sends fixed hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200); // just the client hello retry
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    }
+
+    // server completes handshake
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    }
+
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    // client looked for a ticket, then a TLS1.2 session, then a kx hint;
+    // and afterwards stored the kx group it ended up using and a ticket.
+    let server_name = dns_name("localhost");
+    let mut expected = vec![ClientStorageOp::TakeTls13Ticket(server_name.clone(), false)];
+    #[cfg(feature = "tls12")]
+    expected.push(ClientStorageOp::GetTls12Session(server_name.clone(), false));
+    expected.extend([
+        ClientStorageOp::GetKxHint(server_name.clone(), None),
+        ClientStorageOp::SetKxHint(server_name.clone(), rustls::NamedGroup::X25519),
+        ClientStorageOp::InsertTls13Ticket(server_name),
+    ]);
+    assert_eq!(storage.ops(), expected);
+}
+
+/// A key exchange group shaped like a key encapsulation mechanism, as a
+/// post-quantum group would be: the server replies to the client's public
+/// key with a "ciphertext", here just an X25519 public key followed by
+/// padding, so that the server's share differs in size from the client's.
+#[derive(Debug)]
+struct ToyKem;
+
+static TOY_KEM: ToyKem = ToyKem;
+
+const TOY_KEM_GROUP: rustls::NamedGroup = rustls::NamedGroup::Unknown(0xfe00);
+const TOY_KEM_PADDING: usize = 1024;
+
+impl rustls::SupportedKxGroup for ToyKem {
+    fn start(
+        &self,
+    ) -> Result<Box<dyn rustls::crypto::ActiveKeyExchange>, rustls::crypto::GetRandomFailed> {
+        Ok(Box::new(ToyKemClient(rustls::kx_group::X25519.start()?)))
+    }
+
+    fn start_and_complete(
+        &self,
+        peer_pub_key: &[u8],
+    ) -> Result<rustls::crypto::CompletedKeyExchange, Error> {
+        let kx = rustls::kx_group::X25519.start()?;
+        let mut pub_key = kx.pub_key().to_vec();
+        pub_key.resize(pub_key.len() + TOY_KEM_PADDING, 0);
+        Ok(rustls::crypto::CompletedKeyExchange {
+            group: TOY_KEM_GROUP,
+            pub_key,
+            secret: kx.complete(peer_pub_key)?,
+        })
+    }
+
+    fn name(&self) -> rustls::NamedGroup {
+        TOY_KEM_GROUP
+    }
+
+    fn usable_for_version(&self, version: ProtocolVersion) -> bool {
+        version == ProtocolVersion::TLSv1_3
+    }
+}
+
+struct ToyKemClient(Box<dyn rustls::crypto::ActiveKeyExchange>);
+
+impl rustls::crypto::ActiveKeyExchange for ToyKemClient {
+    fn complete(
+        self: Box<Self>,
+        peer_pub_key: &[u8],
+    ) -> Result<rustls::crypto::SharedSecret, Error> {
+        let ciphertext = peer_pub_key
+            .strip_suffix(&[0; TOY_KEM_PADDING][..])
+            .ok_or_else(|| Error::General("bad toy KEM ciphertext".into()))?;
+        self.0.complete(ciphertext)
+    }
+
+    fn pub_key(&self) -> &[u8] {
+        self.0.pub_key()
+    }
+
+    fn group(&self) -> rustls::NamedGroup {
+        TOY_KEM_GROUP
+    }
+}
+
+#[test]
+fn test_kem_kx_group() {
+    let client_config = make_client_config_with_kx_groups(KeyType::Rsa, &[&TOY_KEM]);
+    let server_config = make_server_config_with_kx_groups(KeyType::Rsa, &[&TOY_KEM]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    // server replies with its KEM ciphertext
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 32 + TOY_KEM_PADDING);
+    }
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[cfg(feature = "kyber")]
+#[test]
+fn test_hybrid_kx_group() {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519_KYBER768_DRAFT00],
+    );
+    let server_config = make_server_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519_KYBER768_DRAFT00],
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // client sends a key share with both an X25519 and a Kyber768 public key
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 32 + 1184);
+    }
+    server.process_new_packets().unwrap();
+
+    // server replies with its X25519 public key and a Kyber768 ciphertext
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 32 + 1088);
+    }
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[cfg(feature = "kyber")]
+#[test]
+fn test_hybrid_kx_group_falls_back_with_helloretryrequest() {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[
+            &rustls::kx_group::X25519_KYBER768_DRAFT00,
+            &rustls::kx_group::X25519,
+        ],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
     }
+
+    do_handshake(&mut client, &mut server);
 }
 
 #[test]
-fn test_client_config_keyshare() {
+fn test_kem_kx_group_falls_back_with_helloretryrequest() {
+    // client prefers the KEM group, and sends a key share for it
     let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&TOY_KEM, &rustls::kx_group::X25519]);
+
+    // but the server doesn't support it, so asks for X25519 instead
     let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
+    }
+
+    do_handshake(&mut client, &mut server);
 }
 
 #[test]
-fn test_client_config_keyshare_mismatch() {
+fn test_server_prefers_kem_kx_group_but_uses_offered_share() {
     let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519, &TOY_KEM]);
     let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&TOY_KEM, &rustls::kx_group::X25519]);
+
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    // the client's X25519 share is acceptable, so no HelloRetryRequest
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+    }
+
+    do_handshake(&mut client, &mut server);
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_client_sends_helloretryrequest() {
-    // client sends a secp384r1 key share
-    let mut client_config = make_client_config_with_kx_groups(
+fn test_kem_kx_group_is_not_used_for_tls12() {
+    let kx_groups: &[&'static dyn rustls::SupportedKxGroup] =
+        &[&TOY_KEM, &rustls::kx_group::SECP384R1];
+    let client_config = finish_client_config(
         KeyType::Rsa,
-        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+        ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(kx_groups)
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    let server_config = finish_server_config(
+        KeyType::Rsa,
+        ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(kx_groups)
+            .with_safe_default_protocol_versions()
+            .unwrap(),
     );
 
-    let storage = Arc::new(ClientStorage::new());
-    client_config.session_storage = storage.clone();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_2));
 
-    // but server only accepts x25519, so a HRR is required
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    // a TLS1.2 server with nothing but the KEM group has nothing to offer
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let server_config = make_server_config_with_kx_groups(KeyType::Rsa, &[&TOY_KEM]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(
+            "no supported group".to_string()
+        )))
+    );
+}
+
+#[cfg(feature = "ffdhe")]
+#[test]
+fn test_ffdhe_kx_group_for_tls13() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::FFDHE2048]);
+    let server_config = make_server_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519, &rustls::kx_group::FFDHE2048],
+    );
 
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
 
-    // client sends hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 1);
-    }
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
 
-    // server sends HRR
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen < 100); // just the hello retry request
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+#[cfg(all(feature = "tls12", feature = "ffdhe"))]
+fn make_dhe_configs(
+    suite: SupportedCipherSuite,
+    kx_group: &'static dyn rustls::SupportedKxGroup,
+) -> (ClientConfig, ServerConfig) {
+    let client_config = finish_client_config(
+        KeyType::Rsa,
+        ClientConfig::builder()
+            .with_cipher_suites(&[suite])
+            .with_kx_groups(&[kx_group])
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap(),
+    );
+    let server_config = finish_server_config(
+        KeyType::Rsa,
+        ServerConfig::builder()
+            .with_cipher_suites(&[ALL_CIPHER_SUITES, &[suite]].concat())
+            .with_kx_groups(&[&rustls::kx_group::X25519, kx_group])
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    (client_config, server_config)
+}
+
+#[cfg(all(feature = "tls12", feature = "ffdhe"))]
+#[test]
+fn test_tls12_dhe_suites_with_each_ffdhe_group() {
+    let suites = [
+        rustls::cipher_suite::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256,
+        rustls::cipher_suite::TLS_DHE_RSA_WITH_AES_256_GCM_SHA384,
+    ];
+    let kx_groups: [&'static dyn rustls::SupportedKxGroup; 3] = [
+        &rustls::kx_group::FFDHE2048,
+        &rustls::kx_group::FFDHE3072,
+        &rustls::kx_group::FFDHE4096,
+    ];
+
+    for suite in suites {
+        for kx_group in kx_groups {
+            println!("{:?} with {:?}", suite, kx_group.name());
+            let (client_config, server_config) = make_dhe_configs(suite, kx_group);
+            do_suite_test(
+                client_config,
+                server_config,
+                suite,
+                ProtocolVersion::TLSv1_2,
+            );
+        }
     }
+}
 
-    // client sends fixed hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200); // just the client hello retry
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+#[cfg(all(feature = "tls12", feature = "ffdhe"))]
+#[test]
+fn test_tls12_dhe_server_chooses_group_for_client_without_ffdhe_groups() {
+    // Clients predating RFC7919 offer DHE suites, but don't list any
+    // finite-field groups.
+    fn remove_named_groups(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake { parsed, encoded } = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut parsed.payload {
+                ch.extensions
+                    .retain(|ext| !matches!(ext, ClientExtension::NamedGroups(_)));
+            }
+
+            *encoded = Payload::new(parsed.get_encoding());
+        }
+        Altered::InPlace
     }
 
-    // server completes handshake
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    let (client_config, server_config) = make_dhe_configs(
+        rustls::cipher_suite::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256,
+        &rustls::kx_group::FFDHE2048,
+    );
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, remove_named_groups, &mut server);
+    server.process_new_packets().unwrap();
+
+    // The client accepts the server's choice of group.  (The handshake
+    // can't complete, because the transcripts now differ.)
+    transfer_altered(&mut server, |_| Altered::InPlace, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert_eq!(
+        server.negotiated_cipher_suite(),
+        Some(rustls::cipher_suite::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256)
+    );
+}
+
+#[cfg(all(feature = "tls12", feature = "ffdhe"))]
+#[test]
+fn test_tls12_dhe_client_rejects_non_standard_group() {
+    /// FFDHE2048, but claiming to use a small, unnamed group.
+    #[derive(Debug)]
+    struct SmallGroup;
+
+    impl rustls::SupportedKxGroup for SmallGroup {
+        fn start(
+            &self,
+        ) -> Result<Box<dyn rustls::crypto::ActiveKeyExchange>, rustls::crypto::GetRandomFailed>
+        {
+            rustls::kx_group::FFDHE2048.start()
+        }
+
+        fn name(&self) -> rustls::NamedGroup {
+            rustls::NamedGroup::FFDHE2048
+        }
+
+        fn ffdhe_group(&self) -> Option<rustls::crypto::FfdheGroup<'static>> {
+            Some(rustls::crypto::FfdheGroup {
+                p: &[0xff; 128],
+                g: &[2],
+            })
+        }
     }
 
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+    static SMALL_GROUP: SmallGroup = SmallGroup;
 
-    // client only did two storage queries: one for a session, another for a kx type
-    assert_eq!(storage.gets(), 2);
-    assert_eq!(storage.puts(), 2);
+    let (client_config, _) = make_dhe_configs(
+        rustls::cipher_suite::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256,
+        &rustls::kx_group::FFDHE2048,
+    );
+    let (_, server_config) = make_dhe_configs(
+        rustls::cipher_suite::TLS_DHE_RSA_WITH_AES_128_GCM_SHA256,
+        &SMALL_GROUP,
+    );
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Client(Error::PeerIncompatibleError(
+            "server chose a non-standard DHE group".to_string()
+        )))
+    );
 }
 
 #[test]
@@ -3855,6 +6816,151 @@ fn bad_client_max_fragment_sizes() {
     );
 }
 
+fn check_client_record_size_limit(limit: usize) -> Option<Error> {
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.record_size_limit = Some(limit);
+    ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err()
+}
+
+#[test]
+fn bad_client_record_size_limits() {
+    assert_eq!(
+        check_client_record_size_limit(63),
+        Some(Error::BadRecordSizeLimit)
+    );
+    assert_eq!(check_client_record_size_limit(64), None);
+    assert_eq!(check_client_record_size_limit(0x4001), None);
+    assert_eq!(
+        check_client_record_size_limit(0x4002),
+        Some(Error::BadRecordSizeLimit)
+    );
+}
+
+fn largest_record_sent(
+    sender: &mut impl DerefMut<Target = ConnectionCommon<impl SideData>>,
+    receiver: &mut impl DerefMut<Target = ConnectionCommon<impl SideData>>,
+) -> usize {
+    let mut pipe = OtherSession::new(receiver);
+    sender.write_tls(&mut pipe).unwrap();
+    pipe.writevs
+        .iter()
+        .flatten()
+        .copied()
+        .max()
+        .unwrap()
+}
+
+#[test]
+fn test_record_size_limit() {
+    // record header, plus the largest explicit nonce and tag we use
+    let overhead = 5 + 8 + 16;
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.record_size_limit = Some(512);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = Some(256);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let big_data = [0u8; 2048];
+        server
+            .writer()
+            .write_all(&big_data)
+            .unwrap();
+        assert!(largest_record_sent(&mut server, &mut client) <= 512 + overhead);
+        check_read(&mut client.reader(), &big_data);
+
+        client
+            .writer()
+            .write_all(&big_data)
+            .unwrap();
+        assert!(largest_record_sent(&mut client, &mut server) <= 256 + overhead);
+        check_read(&mut server.reader(), &big_data);
+    }
+}
+
+#[test]
+fn test_record_size_limit_requires_client_offer() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = Some(256);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let big_data = [0u8; 2048];
+        client
+            .writer()
+            .write_all(&big_data)
+            .unwrap();
+        assert!(largest_record_sent(&mut client, &mut server) > 2048);
+        check_read(&mut server.reader(), &big_data);
+    }
+}
+
+#[test]
+fn bad_client_max_fragment_length() {
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.max_fragment_length = Some(MaxFragmentLength::Unknown(5));
+    assert_eq!(
+        ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+#[test]
+fn test_max_fragment_length() {
+    let overhead = 5 + 8 + 16;
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_fragment_length = Some(MaxFragmentLength::Len512);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+
+        let big_data = [0u8; 2048];
+        server
+            .writer()
+            .write_all(&big_data)
+            .unwrap();
+        assert!(largest_record_sent(&mut server, &mut client) <= 512 + overhead);
+        check_read(&mut client.reader(), &big_data);
+
+        client
+            .writer()
+            .write_all(&big_data)
+            .unwrap();
+        assert!(largest_record_sent(&mut client, &mut server) <= 512 + overhead);
+        check_read(&mut server.reader(), &big_data);
+    }
+}
+
+#[test]
+fn test_record_size_limit_supersedes_max_fragment_length() {
+    let overhead = 5 + 8 + 16;
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_fragment_length = Some(MaxFragmentLength::Len512);
+        client_config.record_size_limit = Some(1024);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+
+        let big_data = [0u8; 2048];
+        server
+            .writer()
+            .write_all(&big_data)
+            .unwrap();
+        let largest = largest_record_sent(&mut server, &mut client);
+        assert!(largest > 512 + overhead);
+        assert!(largest <= 1024 + overhead);
+        check_read(&mut client.reader(), &big_data);
+    }
+}
+
 fn assert_lt(left: usize, right: usize) {
     if left >= right {
         panic!("expected {} < {}", left, right);
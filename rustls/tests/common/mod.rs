@@ -4,13 +4,16 @@ use std::io;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 
+use rustls::client::{CtLogList, CtRequirements, OcspPolicy};
 use rustls::internal::msgs::codec::Reader;
 use rustls::internal::msgs::message::{Message, OpaqueMessage, PlainMessage};
-use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, CertRevocationList,
+};
 use rustls::Connection;
 use rustls::Error;
 use rustls::RootCertStore;
-use rustls::{Certificate, PrivateKey};
+use rustls::{Certificate, PrivateKey, SubjectPublicKeyInfo};
 use rustls::{ClientConfig, ClientConnection};
 use rustls::{ConnectionCommon, ServerConfig, ServerConnection, SideData};
 
@@ -45,14 +48,23 @@ embed_files! {
     (ECDSA_CLIENT_FULLCHAIN, "ecdsa", "client.fullchain");
     (ECDSA_CLIENT_KEY, "ecdsa", "client.key");
     (ECDSA_CLIENT_REQ, "ecdsa", "client.req");
+    (ECDSA_CLIENT_REVOKED_CRL, "ecdsa", "client.revoked.crl");
     (ECDSA_END_CERT, "ecdsa", "end.cert");
     (ECDSA_END_CHAIN, "ecdsa", "end.chain");
+    (ECDSA_END_DC_CERT, "ecdsa", "end-dc.cert");
+    (ECDSA_END_DC_FULLCHAIN, "ecdsa", "end-dc.fullchain");
     (ECDSA_END_FULLCHAIN, "ecdsa", "end.fullchain");
     (ECDSA_END_KEY, "ecdsa", "end.key");
+    (ECDSA_END_MUST_STAPLE_FULLCHAIN, "ecdsa", "end-must-staple.fullchain");
+    (ECDSA_END_MUST_STAPLE_OCSP, "ecdsa", "end-must-staple.ocsp");
+    (ECDSA_END_OCSP, "ecdsa", "end.ocsp");
     (ECDSA_END_REQ, "ecdsa", "end.req");
+    (ECDSA_END_REVOKED_OCSP, "ecdsa", "end.revoked.ocsp");
     (ECDSA_INTER_CERT, "ecdsa", "inter.cert");
+    (ECDSA_INTER_CRL, "ecdsa", "inter.crl");
     (ECDSA_INTER_KEY, "ecdsa", "inter.key");
     (ECDSA_INTER_REQ, "ecdsa", "inter.req");
+    (ECDSA_INTER_REVOKED_CRL, "ecdsa", "inter.revoked.crl");
     (ECDSA_NISTP256_PEM, "ecdsa", "nistp256.pem");
     (ECDSA_NISTP384_PEM, "ecdsa", "nistp384.pem");
 
@@ -64,14 +76,23 @@ embed_files! {
     (EDDSA_CLIENT_FULLCHAIN, "eddsa", "client.fullchain");
     (EDDSA_CLIENT_KEY, "eddsa", "client.key");
     (EDDSA_CLIENT_REQ, "eddsa", "client.req");
+    (EDDSA_CLIENT_REVOKED_CRL, "eddsa", "client.revoked.crl");
     (EDDSA_END_CERT, "eddsa", "end.cert");
     (EDDSA_END_CHAIN, "eddsa", "end.chain");
+    (EDDSA_END_DC_CERT, "eddsa", "end-dc.cert");
+    (EDDSA_END_DC_FULLCHAIN, "eddsa", "end-dc.fullchain");
     (EDDSA_END_FULLCHAIN, "eddsa", "end.fullchain");
     (EDDSA_END_KEY, "eddsa", "end.key");
+    (EDDSA_END_MUST_STAPLE_FULLCHAIN, "eddsa", "end-must-staple.fullchain");
+    (EDDSA_END_MUST_STAPLE_OCSP, "eddsa", "end-must-staple.ocsp");
+    (EDDSA_END_OCSP, "eddsa", "end.ocsp");
     (EDDSA_END_REQ, "eddsa", "end.req");
+    (EDDSA_END_REVOKED_OCSP, "eddsa", "end.revoked.ocsp");
     (EDDSA_INTER_CERT, "eddsa", "inter.cert");
+    (EDDSA_INTER_CRL, "eddsa", "inter.crl");
     (EDDSA_INTER_KEY, "eddsa", "inter.key");
     (EDDSA_INTER_REQ, "eddsa", "inter.req");
+    (EDDSA_INTER_REVOKED_CRL, "eddsa", "inter.revoked.crl");
 
     (RSA_CA_CERT, "rsa", "ca.cert");
     (RSA_CA_DER, "rsa", "ca.der");
@@ -81,16 +102,25 @@ embed_files! {
     (RSA_CLIENT_FULLCHAIN, "rsa", "client.fullchain");
     (RSA_CLIENT_KEY, "rsa", "client.key");
     (RSA_CLIENT_REQ, "rsa", "client.req");
+    (RSA_CLIENT_REVOKED_CRL, "rsa", "client.revoked.crl");
     (RSA_CLIENT_RSA, "rsa", "client.rsa");
     (RSA_END_CERT, "rsa", "end.cert");
     (RSA_END_CHAIN, "rsa", "end.chain");
+    (RSA_END_DC_CERT, "rsa", "end-dc.cert");
+    (RSA_END_DC_FULLCHAIN, "rsa", "end-dc.fullchain");
     (RSA_END_FULLCHAIN, "rsa", "end.fullchain");
     (RSA_END_KEY, "rsa", "end.key");
+    (RSA_END_MUST_STAPLE_FULLCHAIN, "rsa", "end-must-staple.fullchain");
+    (RSA_END_MUST_STAPLE_OCSP, "rsa", "end-must-staple.ocsp");
+    (RSA_END_OCSP, "rsa", "end.ocsp");
     (RSA_END_REQ, "rsa", "end.req");
+    (RSA_END_REVOKED_OCSP, "rsa", "end.revoked.ocsp");
     (RSA_END_RSA, "rsa", "end.rsa");
     (RSA_INTER_CERT, "rsa", "inter.cert");
+    (RSA_INTER_CRL, "rsa", "inter.crl");
     (RSA_INTER_KEY, "rsa", "inter.key");
     (RSA_INTER_REQ, "rsa", "inter.req");
+    (RSA_INTER_REVOKED_CRL, "rsa", "inter.revoked.crl");
 }
 
 pub fn transfer(
@@ -202,6 +232,48 @@ impl KeyType {
             .collect()
     }
 
+    /// A chain for `get_key()` whose end-entity certificate permits
+    /// delegated credentials.
+    pub fn get_delegation_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(self.bytes_for("end-dc.fullchain")))
+            .unwrap()
+            .iter()
+            .map(|v| Certificate(v.clone()))
+            .collect()
+    }
+
+    /// A chain for `get_key()` whose end-entity certificate requires an
+    /// OCSP response to be stapled.
+    pub fn get_must_staple_chain(&self) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut io::BufReader::new(
+            self.bytes_for("end-must-staple.fullchain"),
+        ))
+        .unwrap()
+        .iter()
+        .map(|v| Certificate(v.clone()))
+        .collect()
+    }
+
+    /// An OCSP response saying the end-entity certificate of
+    /// `get_chain()` is good.
+    pub fn get_ocsp_response(&self) -> Vec<u8> {
+        self.bytes_for("end.ocsp").to_vec()
+    }
+
+    /// An OCSP response saying the end-entity certificate of
+    /// `get_chain()` is revoked.
+    pub fn get_revoked_ocsp_response(&self) -> Vec<u8> {
+        self.bytes_for("end.revoked.ocsp")
+            .to_vec()
+    }
+
+    /// An OCSP response saying the end-entity certificate of
+    /// `get_must_staple_chain()` is good.
+    pub fn get_must_staple_ocsp_response(&self) -> Vec<u8> {
+        self.bytes_for("end-must-staple.ocsp")
+            .to_vec()
+    }
+
     pub fn get_key(&self) -> PrivateKey {
         PrivateKey(
             rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(self.bytes_for("end.key")))
@@ -227,6 +299,28 @@ impl KeyType {
                 .clone(),
         )
     }
+
+    /// Load a CRL: `inter.crl` revokes the end-entity certificate,
+    /// `client.revoked.crl` the client certificate, and `inter.revoked.crl`
+    /// the intermediate.
+    pub fn get_crl(&self, part: &str) -> CertRevocationList {
+        let crls = rustls_pemfile::crls(&mut io::BufReader::new(self.bytes_for(part))).unwrap();
+        CertRevocationList::new(&crls[0]).unwrap()
+    }
+
+    pub fn get_spki(&self) -> SubjectPublicKeyInfo {
+        rustls::sign::any_supported_type(&self.get_key())
+            .unwrap()
+            .public_key()
+            .unwrap()
+    }
+
+    pub fn get_client_spki(&self) -> SubjectPublicKeyInfo {
+        rustls::sign::any_supported_type(&self.get_client_key())
+            .unwrap()
+            .public_key()
+            .unwrap()
+    }
 }
 
 pub fn finish_server_config(
@@ -242,6 +336,26 @@ pub fn make_server_config(kt: KeyType) -> ServerConfig {
     finish_server_config(kt, ServerConfig::builder().with_safe_defaults())
 }
 
+pub fn make_server_config_with_ocsp(
+    kt: KeyType,
+    chain: Vec<Certificate>,
+    ocsp: Vec<u8>,
+) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp_and_sct(chain, kt.get_key(), ocsp, vec![])
+        .unwrap()
+}
+
+pub fn make_server_config_with_scts(kt: KeyType, scts: Vec<u8>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(), vec![], scts)
+        .unwrap()
+}
+
 pub fn make_server_config_with_versions(
     kt: KeyType,
     versions: &[&'static rustls::SupportedProtocolVersion],
@@ -258,7 +372,7 @@ pub fn make_server_config_with_versions(
 
 pub fn make_server_config_with_kx_groups(
     kt: KeyType,
-    kx_groups: &[&'static rustls::SupportedKxGroup],
+    kx_groups: &[&'static dyn rustls::SupportedKxGroup],
 ) -> ServerConfig {
     finish_server_config(
         kt,
@@ -291,6 +405,30 @@ pub fn make_server_config_with_mandatory_client_auth(kt: KeyType) -> ServerConfi
         .unwrap()
 }
 
+pub fn make_server_config_with_client_crls(
+    kt: KeyType,
+    crls: &[&str],
+    client_auth_mandatory: bool,
+) -> ServerConfig {
+    let client_auth_roots = get_client_root_store(kt);
+    let crls = crls
+        .iter()
+        .map(|part| kt.get_crl(part))
+        .collect();
+
+    let client_auth = if client_auth_mandatory {
+        AllowAnyAuthenticatedClient::new_with_crls(client_auth_roots, crls)
+    } else {
+        AllowAnyAnonymousOrAuthenticatedClient::new_with_crls(client_auth_roots, crls)
+    };
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap()
+}
+
 pub fn finish_client_config(
     kt: KeyType,
     config: rustls::ConfigBuilder<ClientConfig, rustls::WantsVerifier>,
@@ -304,6 +442,49 @@ pub fn finish_client_config(
         .with_no_client_auth()
 }
 
+pub fn make_client_config_with_ocsp_policy(
+    kt: KeyType,
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    ocsp_policy: OcspPolicy,
+) -> ClientConfig {
+    let mut root_store = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(kt.bytes_for("ca.cert"));
+    root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut rootbuf).unwrap());
+
+    ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_ocsp_policy(ocsp_policy)
+        .with_no_client_auth()
+}
+
+pub fn make_client_config_with_ct_policy(
+    kt: KeyType,
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    logs: CtLogList,
+    requirements: CtRequirements,
+) -> ClientConfig {
+    let mut root_store = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(kt.bytes_for("ca.cert"));
+    root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut rootbuf).unwrap());
+
+    ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(versions)
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_certificate_transparency_policy(
+            logs,
+            requirements,
+            std::time::SystemTime::now() + std::time::Duration::from_secs(3600),
+        )
+        .with_no_client_auth()
+}
+
 pub fn finish_client_config_with_creds(
     kt: KeyType,
     config: rustls::ConfigBuilder<ClientConfig, rustls::WantsVerifier>,
@@ -318,13 +499,25 @@ pub fn finish_client_config_with_creds(
         .unwrap()
 }
 
+pub fn make_client_config_with_raw_public_key(kt: KeyType) -> ClientConfig {
+    let mut root_store = RootCertStore::empty();
+    let mut rootbuf = io::BufReader::new(kt.bytes_for("ca.cert"));
+    root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut rootbuf).unwrap());
+
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_client_raw_public_key(kt.get_client_key())
+        .unwrap()
+}
+
 pub fn make_client_config(kt: KeyType) -> ClientConfig {
     finish_client_config(kt, ClientConfig::builder().with_safe_defaults())
 }
 
 pub fn make_client_config_with_kx_groups(
     kt: KeyType,
-    kx_groups: &[&'static rustls::SupportedKxGroup],
+    kx_groups: &[&'static dyn rustls::SupportedKxGroup],
 ) -> ClientConfig {
     let builder = ClientConfig::builder()
         .with_safe_default_cipher_suites()
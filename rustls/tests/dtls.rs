@@ -0,0 +1,420 @@
+//! Tests for DTLS1.3 connections.
+
+#![cfg(feature = "dtls")]
+
+mod common;
+
+use std::net::UdpSocket;
+use std::ops::DerefMut;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::common::{
+    dns_name, finish_client_config, finish_server_config, make_client_config,
+    make_client_config_with_auth, make_server_config,
+    make_server_config_with_mandatory_client_auth, KeyType, ALL_KEY_TYPES,
+};
+use rustls::dtls::{ClientConnection, ConnectionCommon, ServerConnection};
+use rustls::{ClientConfig, Error, ServerConfig, SideData};
+
+fn make_pair(
+    client_config: ClientConfig,
+    server_config: ServerConfig,
+) -> (ClientConnection, ServerConnection) {
+    let client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+    let server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    (client, server)
+}
+
+/// Moves all datagrams from `left` to `right`, returning how many there were.
+fn transfer(
+    left: &mut impl DerefMut<Target = ConnectionCommon<impl SideData>>,
+    right: &mut impl DerefMut<Target = ConnectionCommon<impl SideData>>,
+    now: Instant,
+) -> usize {
+    let mut count = 0;
+    while let Some(datagram) = left.write_datagram(now) {
+        right.read_datagram(&datagram).unwrap();
+        count += 1;
+    }
+    count
+}
+
+fn do_handshake(client: &mut ClientConnection, server: &mut ServerConnection, now: Instant) {
+    while client.is_handshaking() || server.is_handshaking() {
+        let moved = transfer(client, server, now) + transfer(server, client, now);
+        assert_ne!(moved, 0, "handshake stalled");
+    }
+
+    // Deliver the client's final ACK.
+    transfer(client, server, now);
+}
+
+fn send(socket: &UdpSocket, conn: &mut ConnectionCommon<impl SideData>, now: Instant) {
+    while let Some(datagram) = conn.write_datagram(now) {
+        socket.send(&datagram).unwrap();
+    }
+}
+
+fn receive(socket: &UdpSocket, conn: &mut ConnectionCommon<impl SideData>) {
+    let mut buf = [0u8; 2048];
+    let len = socket.recv(&mut buf).unwrap();
+    conn.read_datagram(&buf[..len]).unwrap();
+}
+
+#[test]
+fn udp_loopback() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_socket
+            .connect(server_socket.local_addr().unwrap())
+            .unwrap();
+        server_socket
+            .connect(client_socket.local_addr().unwrap())
+            .unwrap();
+        for socket in [&client_socket, &server_socket] {
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+
+        let (mut client, mut server) = make_pair(make_client_config(*kt), make_server_config(*kt));
+        let now = Instant::now();
+
+        // ClientHello
+        send(&client_socket, &mut client, now);
+        receive(&server_socket, &mut server);
+
+        // ServerHello ... Finished
+        send(&server_socket, &mut server, now);
+        while client.is_handshaking() {
+            receive(&client_socket, &mut client);
+        }
+
+        // Finished
+        send(&client_socket, &mut client, now);
+        while server.is_handshaking() {
+            receive(&server_socket, &mut server);
+        }
+
+        client
+            .send_application_data(b"ping")
+            .unwrap();
+        send(&client_socket, &mut client, now);
+        while server
+            .received_application_data()
+            .is_none()
+        {
+            receive(&server_socket, &mut server);
+            server
+                .send_application_data(b"pong")
+                .unwrap();
+        }
+
+        send(&server_socket, &mut server, now);
+        while client.timeout().is_some() {
+            // The server's ACK of our Finished, possibly after the data.
+            receive(&client_socket, &mut client);
+        }
+        assert_eq!(client.received_application_data(), Some(b"pong".to_vec()));
+
+        assert_eq!(
+            client.negotiated_cipher_suite(),
+            server.negotiated_cipher_suite()
+        );
+        assert_eq!(
+            client
+                .peer_certificates()
+                .map(|certs| certs.len()),
+            Some(3)
+        );
+
+        client.send_close_notify();
+        send(&client_socket, &mut client, now);
+        receive(&server_socket, &mut server);
+        assert!(server.peer_has_closed());
+    }
+}
+
+#[test]
+fn client_auth() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(
+            make_client_config_with_auth(*kt),
+            make_server_config_with_mandatory_client_auth(*kt),
+        );
+        do_handshake(&mut client, &mut server, Instant::now());
+        assert!(server.peer_certificates().is_some());
+    }
+}
+
+#[test]
+fn handshake_messages_are_fragmented() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Rsa),
+        make_server_config(KeyType::Rsa),
+    );
+    client.set_mtu(256);
+    server.set_mtu(256);
+    let now = Instant::now();
+
+    transfer(&mut client, &mut server, now);
+    let flight = transfer(&mut server, &mut client, now);
+    assert!(flight > 10);
+    assert!(!client.is_handshaking());
+    do_handshake(&mut client, &mut server, now);
+}
+
+#[test]
+fn lost_datagrams_are_retransmitted() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Ecdsa),
+        make_server_config(KeyType::Ecdsa),
+    );
+    let mut now = Instant::now();
+
+    // Lose the ClientHello.
+    assert!(client.write_datagram(now).is_some());
+    assert!(client.write_datagram(now).is_none());
+    let timeout = client.timeout().unwrap();
+    assert_eq!(timeout, now + Duration::from_secs(1));
+
+    client.handle_timeout(now);
+    assert!(client.write_datagram(now).is_none());
+
+    now = timeout;
+    client.handle_timeout(now);
+    assert_eq!(transfer(&mut client, &mut server, now), 1);
+    assert_eq!(client.timeout(), Some(now + Duration::from_secs(2)));
+
+    // Lose the server's flight.  The retransmitted ClientHello is in the
+    // clear, so the server ignores it and resends on its own timer.
+    while server.write_datagram(now).is_some() {}
+    now = client.timeout().unwrap();
+    client.handle_timeout(now);
+    assert_eq!(transfer(&mut client, &mut server, now), 1);
+    assert!(server.write_datagram(now).is_none());
+    server.handle_timeout(now);
+    transfer(&mut server, &mut client, now);
+    assert!(!client.is_handshaking());
+
+    // Lose the client's Finished, and the server's ACK of the retransmission.
+    while client.write_datagram(now).is_some() {}
+    now = client.timeout().unwrap();
+    client.handle_timeout(now);
+    transfer(&mut client, &mut server, now);
+    assert!(!server.is_handshaking());
+    while server.write_datagram(now).is_some() {}
+
+    now = client.timeout().unwrap();
+    client.handle_timeout(now);
+    transfer(&mut client, &mut server, now);
+    transfer(&mut server, &mut client, now);
+    assert_eq!(client.timeout(), None);
+
+    // That also lost the server's NewSessionTicket messages.
+    now = server.timeout().unwrap();
+    server.handle_timeout(now);
+    transfer(&mut server, &mut client, now);
+    transfer(&mut client, &mut server, now);
+    assert_eq!(server.timeout(), None);
+}
+
+#[test]
+fn reordered_and_duplicated_datagrams() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Rsa),
+        make_server_config(KeyType::Rsa),
+    );
+    client.set_mtu(500);
+    server.set_mtu(500);
+    let now = Instant::now();
+
+    transfer(&mut client, &mut server, now);
+    let mut flight = Vec::new();
+    while let Some(datagram) = server.write_datagram(now) {
+        flight.push(datagram);
+    }
+    assert!(flight.len() > 2);
+
+    for datagram in flight.iter().rev().chain(flight.iter()) {
+        client.read_datagram(datagram).unwrap();
+    }
+    assert!(!client.is_handshaking());
+    do_handshake(&mut client, &mut server, now);
+
+    server
+        .send_application_data(b"hello")
+        .unwrap();
+    let datagram = server.write_datagram(now).unwrap();
+    client.read_datagram(&datagram).unwrap();
+    client.read_datagram(&datagram).unwrap();
+    assert_eq!(client.received_application_data(), Some(b"hello".to_vec()));
+    assert_eq!(client.received_application_data(), None);
+}
+
+#[test]
+fn application_data_waits_for_handshake() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Ed25519),
+        make_server_config(KeyType::Ed25519),
+    );
+    let now = Instant::now();
+
+    client
+        .send_application_data(b"early")
+        .unwrap();
+    server
+        .send_application_data(b"late")
+        .unwrap();
+    do_handshake(&mut client, &mut server, now);
+    transfer(&mut server, &mut client, now);
+
+    assert_eq!(server.received_application_data(), Some(b"early".to_vec()));
+    assert_eq!(client.received_application_data(), Some(b"late".to_vec()));
+
+    let too_big = vec![0u8; client.max_application_data_len() + 1];
+    assert!(client
+        .send_application_data(&too_big)
+        .is_err());
+}
+
+#[test]
+fn tampered_records_are_discarded() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Ecdsa),
+        make_server_config(KeyType::Ecdsa),
+    );
+    let now = Instant::now();
+    do_handshake(&mut client, &mut server, now);
+
+    client
+        .send_application_data(b"hello")
+        .unwrap();
+    let mut datagram = client.write_datagram(now).unwrap();
+    let last = datagram.len() - 1;
+    datagram[last] ^= 1;
+    server.read_datagram(&datagram).unwrap();
+    assert_eq!(server.received_application_data(), None);
+}
+
+#[test]
+fn plaintext_alert_after_handshake_is_ignored() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Ecdsa),
+        make_server_config(KeyType::Ecdsa),
+    );
+    let now = Instant::now();
+    do_handshake(&mut client, &mut server, now);
+
+    // A DTLSPlaintext fatal unexpected_message alert in epoch 0, as an
+    // off-path attacker could send.
+    let forged = [
+        0x15, 0xfe, 0xfd, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x02, 0x02, 0x0a,
+    ];
+    server.read_datagram(&forged).unwrap();
+    client.read_datagram(&forged).unwrap();
+
+    client
+        .send_application_data(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server, now);
+    assert_eq!(server.received_application_data(), Some(b"hello".to_vec()));
+
+    server
+        .send_application_data(b"world")
+        .unwrap();
+    transfer(&mut server, &mut client, now);
+    assert_eq!(client.received_application_data(), Some(b"world".to_vec()));
+}
+
+#[cfg(feature = "aes_ccm")]
+#[test]
+fn aes_ccm_suite() {
+    let suite = rustls::cipher_suite::TLS13_AES_128_CCM_SHA256;
+    let client_config = finish_client_config(
+        KeyType::Ecdsa,
+        ClientConfig::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    let server_config = finish_server_config(
+        KeyType::Ecdsa,
+        ServerConfig::builder()
+            .with_cipher_suites(&[suite])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
+    let (mut client, mut server) = make_pair(client_config, server_config);
+    let now = Instant::now();
+    do_handshake(&mut client, &mut server, now);
+    assert_eq!(client.negotiated_cipher_suite(), Some(suite));
+
+    // Record numbers are encrypted with AES, as for AES-GCM.
+    client
+        .send_application_data(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server, now);
+    assert_eq!(server.received_application_data(), Some(b"hello".to_vec()));
+}
+
+#[test]
+fn exporter_agrees() {
+    let (mut client, mut server) = make_pair(
+        make_client_config(KeyType::Rsa),
+        make_server_config(KeyType::Rsa),
+    );
+    do_handshake(&mut client, &mut server, Instant::now());
+
+    let mut client_secret = [0u8; 32];
+    let mut server_secret = [0u8; 32];
+    client
+        .export_keying_material(&mut client_secret, b"label", None)
+        .unwrap();
+    server
+        .export_keying_material(&mut server_secret, b"label", None)
+        .unwrap();
+    assert_eq!(client_secret, server_secret);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_only_client_is_refused() {
+    let config = common::make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let err = ClientConnection::new(Arc::new(config), dns_name("localhost")).unwrap_err();
+    assert_eq!(
+        err,
+        Error::General("TLS 1.3 support is required for DTLS".into())
+    );
+}
+
+#[test]
+fn resumption() {
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let now = Instant::now();
+
+    let mut flight_sizes = Vec::new();
+    for _ in 0..2 {
+        let mut client =
+            ClientConnection::new(Arc::clone(&client_config), dns_name("localhost")).unwrap();
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        transfer(&mut client, &mut server, now);
+
+        let mut size = 0;
+        while let Some(datagram) = server.write_datagram(now) {
+            size += datagram.len();
+            client.read_datagram(&datagram).unwrap();
+        }
+        flight_sizes.push(size);
+        do_handshake(&mut client, &mut server, now);
+        transfer(&mut server, &mut client, now);
+    }
+
+    // The resumed handshake doesn't send the certificate chain.
+    assert!(flight_sizes[1] < flight_sizes[0] / 2);
+}
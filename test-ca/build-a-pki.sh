@@ -156,6 +156,26 @@ for kt in rsa ecdsa eddsa ; do
             -set_serial 456 \
             -extensions v3_end -extfile openssl.cnf
 
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end-dc.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 457 \
+            -extensions v3_end_dc -extfile openssl.cnf
+
+  openssl x509 -req \
+            -in $kt/end.req \
+            -out $kt/end-must-staple.cert \
+            -CA $kt/inter.cert \
+            -CAkey $kt/inter.key \
+            -sha256 \
+            -days 2000 \
+            -set_serial 458 \
+            -extensions v3_end_must_staple -extfile openssl.cnf
+
   openssl x509 -req \
             -in $kt/client.req \
             -out $kt/client.cert \
@@ -168,9 +188,58 @@ for kt in rsa ecdsa eddsa ; do
 
   cat $kt/inter.cert $kt/ca.cert > $kt/end.chain
   cat $kt/end.cert $kt/inter.cert $kt/ca.cert > $kt/end.fullchain
+  cat $kt/end-dc.cert $kt/inter.cert $kt/ca.cert > $kt/end-dc.fullchain
+  cat $kt/end-must-staple.cert $kt/inter.cert $kt/ca.cert > $kt/end-must-staple.fullchain
 
   cat $kt/inter.cert $kt/ca.cert > $kt/client.chain
   cat $kt/client.cert $kt/inter.cert $kt/ca.cert > $kt/client.fullchain
 
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
+
+  # OCSP responses from the issuer, valid as long as the certificates.
+  expiry=$(date -u -d "+2000 days" +%y%m%d%H%M%SZ)
+  printf 'V\t%s\t\t01C8\tunknown\t/CN=testserver.com\n' $expiry > $kt/ocsp.index
+  printf 'V\t%s\t\t01CA\tunknown\t/CN=testserver.com\n' $expiry >> $kt/ocsp.index
+  printf 'R\t%s\t%s\t01C8\tunknown\t/CN=testserver.com\n' $expiry $(date -u +%y%m%d%H%M%SZ) > $kt/ocsp-revoked.index
+  echo 'unique_subject = no' > $kt/ocsp.index.attr
+
+  for cert in end end-must-staple ; do
+    openssl ocsp \
+              -issuer $kt/inter.cert \
+              -cert $kt/$cert.cert \
+              -no_nonce \
+              -reqout $kt/$cert.ocspreq
+    openssl ocsp \
+              -index $kt/ocsp.index \
+              -rsigner $kt/inter.cert \
+              -rkey $kt/inter.key \
+              -CA $kt/inter.cert \
+              -reqin $kt/$cert.ocspreq \
+              -respout $kt/$cert.ocsp \
+              -ndays 2000
+  done
+
+  openssl ocsp \
+            -index $kt/ocsp-revoked.index \
+            -rsigner $kt/inter.cert \
+            -rkey $kt/inter.key \
+            -CA $kt/inter.cert \
+            -reqin $kt/end.ocspreq \
+            -respout $kt/end.revoked.ocsp \
+            -ndays 2000
+
+  rm $kt/*.ocspreq $kt/*.index*
+
+  # CRLs from the intermediate, one revoking the server certificate and one
+  # revoking the client certificate, and one from the CA revoking the intermediate.
+  printf 'R\t%s\t%s\t01C8\tunknown\t/CN=testserver.com\n' $expiry $(date -u +%y%m%d%H%M%SZ) > crl.index
+  openssl ca -config openssl.cnf -name crl_ca -gencrl -crldays 2000 \
+            -cert $kt/inter.cert -keyfile $kt/inter.key -out $kt/inter.crl
+  printf 'R\t%s\t%s\t0315\tunknown\t/CN=ponytown client\n' $expiry $(date -u +%y%m%d%H%M%SZ) > crl.index
+  openssl ca -config openssl.cnf -name crl_ca -gencrl -crldays 2000 \
+            -cert $kt/inter.cert -keyfile $kt/inter.key -out $kt/client.revoked.crl
+  printf 'R\t%s\t%s\t7B\tunknown\t/CN=ponytown level 2 intermediate\n' $expiry $(date -u +%y%m%d%H%M%SZ) > crl.index
+  openssl ca -config openssl.cnf -name crl_ca -gencrl -crldays 2000 \
+            -cert $kt/ca.cert -keyfile $kt/ca.key -out $kt/inter.revoked.crl
+  rm crl.index*
 done